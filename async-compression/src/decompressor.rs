@@ -23,7 +23,7 @@ where
     inner: Box<RawDecoder<R> + 'a + Send>,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum DecompressorType {
     Bzip2,
     Gzip,
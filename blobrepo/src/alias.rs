@@ -0,0 +1,60 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Aliases of file contents by another hash than the one they are stored under.
+//!
+//! A content is stored once under the SHA-1 of the content, whichever paths and filenodes refer to
+//! it, so identical files share their content already. Some clients name contents by their
+//! SHA-256 instead, as LFS does, so `alias.sha256-<hash>` maps the SHA-256 of each file content
+//! to the SHA-1 it's stored under.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use rust_crypto::digest::Digest;
+use rust_crypto::sha2::Sha256;
+
+use blobstore::Blobstore;
+use mercurial_types::BlobHash;
+
+use errors::*;
+
+/// The hex SHA-256 of `content`
+pub fn get_sha256(content: &[u8]) -> String {
+    let mut sha256 = Sha256::new();
+    sha256.input(content);
+    sha256.result_str()
+}
+
+fn get_sha256_alias_key(sha256: &str) -> String {
+    format!("alias.sha256-{}", sha256)
+}
+
+/// Alias `content`, stored under `blob`, by its SHA-256
+pub fn store_sha256_alias(
+    blobstore: &Arc<Blobstore>,
+    blob: BlobHash,
+    content: &[u8],
+) -> BoxFuture<(), Error> {
+    let key = get_sha256_alias_key(&get_sha256(content));
+    blobstore.put(key, Bytes::from(blob.sha1().as_ref()))
+}
+
+/// The hash a content is stored under, given its hex SHA-256, if it has been aliased
+pub fn get_sha256_alias(
+    blobstore: &Arc<Blobstore>,
+    sha256: &str,
+) -> BoxFuture<Option<BlobHash>, Error> {
+    blobstore
+        .get(get_sha256_alias_key(sha256))
+        .and_then(|alias| match alias {
+            Some(alias) => Ok(Some(BlobHash::from_bytes(alias.as_ref())?)),
+            None => Ok(None),
+        })
+        .boxify()
+}
@@ -0,0 +1,347 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bonsai changesets, stored alongside the Mercurial changesets they're converted from.
+//!
+//! A bonsai changeset (see `mononoke_types::BonsaiChangeset`) lists the files it changes, with
+//! their copy information, and names contents and changesets by BLAKE2 hashes of their own. So
+//! it doesn't depend on Mercurial hashing, which makes it the changeset format for protocols
+//! other than Mercurial. It's stored as `bonsai_changeset-<id>.bincode`, and:
+//!
+//! - `alias.content-<id>` maps the content id of each file to the SHA-1 the content is stored
+//!   under, as `alias.sha256-` does for SHA-256
+//! - `hg_to_bonsai-<hg id>` and `bonsai_to_hg-<bonsai id>` map the changesets both ways
+//!
+//! A Mercurial changeset is converted once its parents have been.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+use mercurial_types::{Blob, BlobHash, Changeset, ChangesetId, MPath, Manifest, NodeHash};
+use mercurial_types::manifest::Type;
+use mercurial_types::nodehash::ManifestId;
+use mononoke_types::{BonsaiChangeset, BonsaiChangesetMut, ContentId, DateTime, FileChange,
+                     FileType};
+use mononoke_types::ChangesetId as BonsaiChangesetId;
+
+use changeset::BlobChangeset;
+use delta::{get_content_key, store_content};
+use errors::*;
+use file::fetch_file_content_and_renames_from_blobstore;
+use manifest::BlobManifest;
+
+fn get_bonsai_key(id: &BonsaiChangesetId) -> String {
+    format!("bonsai_changeset-{}.bincode", id)
+}
+
+fn get_content_alias_key(id: &ContentId) -> String {
+    format!("alias.content-{}", id)
+}
+
+fn get_hg_to_bonsai_key(id: &ChangesetId) -> String {
+    format!("hg_to_bonsai-{}", id)
+}
+
+fn get_bonsai_to_hg_key(id: &BonsaiChangesetId) -> String {
+    format!("bonsai_to_hg-{}", id)
+}
+
+pub fn save_bonsai_changeset(
+    blobstore: &Arc<Blobstore>,
+    cs: &BonsaiChangeset,
+) -> BoxFuture<BonsaiChangesetId, Error> {
+    let id = cs.get_changeset_id();
+    blobstore
+        .put(get_bonsai_key(&id), Bytes::from(cs.to_blob()))
+        .map(move |()| id)
+        .boxify()
+}
+
+pub fn load_bonsai_changeset(
+    blobstore: &Arc<Blobstore>,
+    id: &BonsaiChangesetId,
+) -> BoxFuture<Option<BonsaiChangeset>, Error> {
+    blobstore
+        .get(get_bonsai_key(id))
+        .and_then(|blob| match blob {
+            Some(blob) => Ok(Some(BonsaiChangeset::from_blob(blob)?)),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+/// The SHA-1 the content `id` is stored under, if a file with that content has been converted
+pub fn get_content_alias(
+    blobstore: &Arc<Blobstore>,
+    id: &ContentId,
+) -> BoxFuture<Option<BlobHash>, Error> {
+    blobstore
+        .get(get_content_alias_key(id))
+        .and_then(|alias| match alias {
+            Some(alias) => Ok(Some(BlobHash::from_bytes(alias.as_ref())?)),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+/// Map `hg` to `bonsai` and back
+pub fn store_bonsai_mapping(
+    blobstore: &Arc<Blobstore>,
+    hg: ChangesetId,
+    bonsai: BonsaiChangesetId,
+) -> BoxFuture<(), Error> {
+    let to_bonsai = blobstore.put(
+        get_hg_to_bonsai_key(&hg),
+        Bytes::from(bonsai.blake2().as_ref()),
+    );
+    let to_hg = blobstore.put(
+        get_bonsai_to_hg_key(&bonsai),
+        Bytes::from(hg.into_nodehash().as_ref()),
+    );
+    to_bonsai.join(to_hg).map(|_| ()).boxify()
+}
+
+pub fn get_bonsai_from_hg(
+    blobstore: &Arc<Blobstore>,
+    hg: &ChangesetId,
+) -> BoxFuture<Option<BonsaiChangesetId>, Error> {
+    blobstore
+        .get(get_hg_to_bonsai_key(hg))
+        .and_then(|id| match id {
+            Some(id) => Ok(Some(BonsaiChangesetId::from_bytes(id)?)),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+pub fn get_hg_from_bonsai(
+    blobstore: &Arc<Blobstore>,
+    bonsai: &BonsaiChangesetId,
+) -> BoxFuture<Option<ChangesetId>, Error> {
+    blobstore
+        .get(get_bonsai_to_hg_key(bonsai))
+        .and_then(|id| match id {
+            Some(id) => Ok(Some(ChangesetId::new(NodeHash::from_bytes(id.as_ref())?))),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+/// Convert the Mercurial changeset `hg` to a bonsai changeset, store it and map the two, and
+/// return the id of the bonsai changeset. The parents of `hg` have to be converted already.
+pub fn convert_to_bonsai(
+    blobstore: Arc<Blobstore>,
+    hg: ChangesetId,
+) -> BoxFuture<BonsaiChangesetId, Error> {
+    BlobChangeset::load(&blobstore, &hg)
+        .and_then(move |cs| {
+            let cs = try_boxfuture!(cs.ok_or(ErrorKind::ChangesetMissing(hg)));
+            let hg_parents: Vec<_> = cs.parents()
+                .into_iter()
+                .map(ChangesetId::new)
+                .collect();
+
+            let parents: Vec<_> = hg_parents
+                .iter()
+                .map(|parent| {
+                    let parent = *parent;
+                    get_bonsai_from_hg(&blobstore, &parent).and_then(move |id| {
+                        id.ok_or(ErrorKind::BonsaiMappingMissing(parent).into())
+                    })
+                })
+                .collect();
+            let parent_manifests: Vec<_> = hg_parents
+                .iter()
+                .map(|parent| load_manifest_of(&blobstore, *parent))
+                .collect();
+            let manifest = load_manifest(&blobstore, *cs.manifestid());
+
+            let blobstore = blobstore.clone();
+            future::join_all(parents)
+                .join3(future::join_all(parent_manifests), manifest)
+                .and_then(move |(parents, parent_manifests, manifest)| {
+                    let parent_manifests: Vec<_> =
+                        parents.iter().cloned().zip(parent_manifests).collect();
+                    let parent_manifests = Arc::new(parent_manifests);
+                    let manifest = Arc::new(manifest);
+                    let file_changes: Vec<_> = cs.files()
+                        .iter()
+                        .map(|path| {
+                            convert_file_change(
+                                blobstore.clone(),
+                                manifest.clone(),
+                                parent_manifests.clone(),
+                                path.clone(),
+                            ).map({
+                                let path = path.clone();
+                                move |change| (path, change)
+                            })
+                        })
+                        .collect();
+                    future::join_all(file_changes).and_then(move |file_changes| {
+                        let bonsai = try_boxfuture!(bonsai_from_hg(&cs, parents, file_changes));
+                        save_bonsai_changeset(&blobstore, &bonsai)
+                            .and_then(move |id| {
+                                store_bonsai_mapping(&blobstore, hg, id).map(move |()| id)
+                            })
+                            .boxify()
+                    })
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn bonsai_from_hg(
+    cs: &BlobChangeset,
+    parents: Vec<BonsaiChangesetId>,
+    file_changes: Vec<(MPath, Option<FileChange>)>,
+) -> Result<BonsaiChangeset> {
+    let hg = cs.get_changeset_id();
+    let text = |field: &'static str, bytes: &[u8]| {
+        String::from_utf8(bytes.to_vec()).map_err(|_| ErrorKind::NotUtf8(hg, field))
+    };
+
+    let mut extra = BTreeMap::new();
+    for (key, value) in cs.extra() {
+        extra.insert(text("extra", key)?, value.clone());
+    }
+    // Mercurial records the timezone in seconds west of UTC
+    let time = cs.time();
+    let author_date = DateTime::new(time.time as i64, -time.tz)?;
+
+    BonsaiChangesetMut {
+        parents,
+        author: text("user", cs.user())?,
+        author_date,
+        committer: None,
+        committer_date: None,
+        message: text("comments", cs.comments())?,
+        extra,
+        file_changes: file_changes.into_iter().collect(),
+    }.freeze()
+}
+
+/// The change to the file at `path`, given the manifest of the changeset and those of its
+/// parents, with their bonsai ids. `None` if the file was deleted, or replaced by a directory.
+fn convert_file_change(
+    blobstore: Arc<Blobstore>,
+    manifest: Arc<BlobManifest>,
+    parent_manifests: Arc<Vec<(BonsaiChangesetId, BlobManifest)>>,
+    path: MPath,
+) -> BoxFuture<Option<FileChange>, Error> {
+    manifest
+        .lookup(&path)
+        .and_then(move |entry| {
+            let entry = match entry {
+                Some(entry) => entry,
+                None => return future::ok(None).boxify(),
+            };
+            let file_type = match entry.get_type() {
+                Type::File => FileType::Regular,
+                Type::Executable => FileType::Executable,
+                Type::Symlink => FileType::Symlink,
+                Type::Tree => return future::ok(None).boxify(),
+            };
+            let node = entry.get_hash().into_nodehash();
+            fetch_file_content_and_renames_from_blobstore(&blobstore, node)
+                .and_then(move |(content, copy_from)| {
+                    let copy_from = match copy_from {
+                        Some((from_path, from_node)) => {
+                            find_copy_source(&parent_manifests, from_path, from_node)
+                        }
+                        None => future::ok(None).boxify(),
+                    };
+                    let content_id = store_content_alias(&blobstore, content.clone());
+                    content_id.join(copy_from).map(move |(content_id, copy_from)| {
+                        let size = content.len() as u64;
+                        Some(FileChange::new(content_id, file_type, size, copy_from))
+                    })
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// The parent a file was copied from: the one which has the file node at `from_path`, else the
+/// first parent, where Mercurial copies from. `None` for a root changeset.
+fn find_copy_source(
+    parent_manifests: &Arc<Vec<(BonsaiChangesetId, BlobManifest)>>,
+    from_path: MPath,
+    from_node: NodeHash,
+) -> BoxFuture<Option<(MPath, BonsaiChangesetId)>, Error> {
+    let lookups: Vec<_> = parent_manifests
+        .iter()
+        .map(|&(parent, ref manifest)| {
+            manifest.lookup(&from_path).map(move |entry| {
+                let found = entry.map_or(false, |e| e.get_hash().into_nodehash() == from_node);
+                (parent, found)
+            })
+        })
+        .collect();
+    future::join_all(lookups)
+        .map(move |parents| {
+            let source = parents
+                .iter()
+                .find(|&&(_, found)| found)
+                .or_else(|| parents.first())
+                .map(|&(parent, _)| parent);
+            source.map(|parent| (from_path, parent))
+        })
+        .boxify()
+}
+
+/// Alias `content` by its content id, first storing it under its own SHA-1 if the file node
+/// stores it together with its copy metadata
+fn store_content_alias(
+    blobstore: &Arc<Blobstore>,
+    content: Bytes,
+) -> BoxFuture<ContentId, Error> {
+    let content_id = ContentId::from_data(&content);
+    let blob = Blob::from(content.clone())
+        .hash()
+        .expect("a blob with content has a hash");
+    let alias_key = get_content_alias_key(&content_id);
+    let blobstore = blobstore.clone();
+    blobstore
+        .is_present(get_content_key(&blob))
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |present| {
+                if present {
+                    future::ok(()).boxify()
+                } else {
+                    store_content(&blobstore, blob, content, None, None)
+                }
+            }
+        })
+        .and_then(move |()| blobstore.put(alias_key, Bytes::from(blob.sha1().as_ref())))
+        .map(move |()| content_id)
+        .boxify()
+}
+
+fn load_manifest(blobstore: &Arc<Blobstore>, id: ManifestId) -> BoxFuture<BlobManifest, Error> {
+    BlobManifest::load(blobstore, &id)
+        .and_then(move |manifest| {
+            manifest.ok_or(ErrorKind::ManifestMissing(id.into_nodehash()).into())
+        })
+        .boxify()
+}
+
+fn load_manifest_of(blobstore: &Arc<Blobstore>, cs: ChangesetId) -> BoxFuture<BlobManifest, Error> {
+    let blobstore = blobstore.clone();
+    BlobChangeset::load(&blobstore, &cs)
+        .and_then(move |found| {
+            let found = try_boxfuture!(found.ok_or(ErrorKind::ChangesetMissing(cs)));
+            load_manifest(&blobstore, *found.manifestid())
+        })
+        .boxify()
+}
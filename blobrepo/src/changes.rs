@@ -0,0 +1,320 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Changesets created from the changes to their files, for writers which don't speak Mercurial.
+//!
+//! `create_changeset` takes the manifests and filenodes of a changeset already built, as a push
+//! sends them. Here they're built from the trees of the first parent along the changed paths,
+//! the other trees being shared with it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{self, loop_fn, Future, Loop};
+use futures::stream;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+
+use mercurial::manifest::revlog::{Details, ManifestContent};
+use mercurial_types::{Blob, Changeset, ChangesetId, MPath, MPathElement, NodeHash, RepoPath,
+                      Time};
+use mercurial_types::manifest::Type;
+use mercurial_types::nodehash::EntryId;
+
+use errors::*;
+use file::BlobEntry;
+use repo::BlobRepo;
+use repo_commit::ChangesetHandle;
+
+type BlobFuture = BoxFuture<(BlobEntry, RepoPath), Error>;
+/// Trees of a manifest by path, with their nodes
+type Trees = HashMap<MPath, (NodeHash, ManifestContent)>;
+
+/// Marks the metadata at the start of the text of a filenode, which is escaped by doubling it
+/// for content which starts with it.
+const META_MARKER: &[u8] = b"\x01\n";
+
+/// A change to the file at a path
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum FileChange {
+    /// The file now has this content, and this type, which mustn't be `Type::Tree`
+    Change(Bytes, Type),
+    Remove,
+}
+
+/// What a changeset records besides its parents and the changes to its files
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ChangesetMetadata {
+    pub user: String,
+    pub time: Time,
+    pub extra: BTreeMap<Vec<u8>, Vec<u8>>,
+    pub comments: String,
+}
+
+/// Create a changeset with up to two `parents`, whose files are those of the first parent once
+/// `changes` are applied, and return its id once it's complete. The parents of the changed
+/// filenodes are those of the first parent.
+pub fn create_changeset_from_changes(
+    repo: BlobRepo,
+    parents: Vec<ChangesetId>,
+    changes: BTreeMap<MPath, FileChange>,
+    metadata: ChangesetMetadata,
+) -> BoxFuture<ChangesetId, Error> {
+    if parents.len() > 2 {
+        return future::err(ErrorKind::TooManyParents(parents.len()).into()).boxify();
+    }
+    let paths: Vec<_> = changes.keys().cloned().collect();
+    let dirs = Arc::new(parent_dirs(&paths));
+    for (path, change) in &changes {
+        // A file can't also be the directory of other changed files, nor be a directory itself
+        let valid = match change {
+            &FileChange::Change(_, file_type) => {
+                file_type != Type::Tree && !dirs.contains(path)
+            }
+            &FileChange::Remove => !path.is_empty(),
+        };
+        if !valid {
+            return future::err(ErrorKind::InvalidFileChange(path.clone()).into()).boxify();
+        }
+    }
+    let parents = future::join_all(
+        parents
+            .iter()
+            .map(|parent| repo.get_changeset_by_changesetid(parent))
+            .collect::<Vec<_>>(),
+    );
+
+    parents
+        .and_then({
+            let repo = repo.clone();
+            move |parents| {
+                let trees = match parents.first() {
+                    Some(p1) => {
+                        load_trees(repo, p1.manifestid().into_nodehash(), dirs).boxify()
+                    }
+                    None => future::ok(HashMap::new()).boxify(),
+                };
+                trees.map(move |trees| (parents, trees))
+            }
+        })
+        .and_then(move |(parents, trees)| {
+            let mut entries = Vec::new();
+            let mut tree_changes = Vec::new();
+            for (path, change) in changes {
+                let details = match change {
+                    FileChange::Change(content, file_type) => {
+                        let p1 = lookup(&trees, &path).and_then(|details| {
+                            if details.is_tree() {
+                                None
+                            } else {
+                                Some(details.entryid().into_nodehash())
+                            }
+                        });
+                        let (node, upload) = repo.upload_entry(
+                            Blob::from(escape_content(content)),
+                            file_type,
+                            p1,
+                            None,
+                            RepoPath::file(path.clone())?,
+                        )?;
+                        entries.push(upload);
+                        Some(Details::new(EntryId::new(node), file_type))
+                    }
+                    FileChange::Remove => None,
+                };
+                tree_changes.push((path, details));
+            }
+            let (_, root, mut trees) = rebuild_trees(&repo, trees, tree_changes)?;
+            entries.append(&mut trees);
+
+            let mut parents = parents.into_iter().map(ChangesetHandle::from);
+            let handle = repo.create_changeset(
+                parents.next(),
+                parents.next(),
+                root,
+                stream::futures_unordered(entries).boxify(),
+                metadata.user,
+                metadata.time,
+                metadata.extra,
+                metadata.comments,
+            );
+            Ok(handle)
+        })
+        .and_then(|handle| {
+            handle
+                .get_completed_changeset()
+                .map_err(Error::from)
+                .map(|cs| cs.get_changeset_id())
+        })
+        .boxify()
+}
+
+/// The text of a filenode with `content`, which can't be mistaken for copy metadata
+fn escape_content(content: Bytes) -> Bytes {
+    if content.starts_with(META_MARKER) {
+        let mut escaped = Vec::with_capacity(2 * META_MARKER.len() + content.len());
+        escaped.extend_from_slice(META_MARKER);
+        escaped.extend_from_slice(META_MARKER);
+        escaped.extend_from_slice(&content);
+        Bytes::from(escaped)
+    } else {
+        content
+    }
+}
+
+/// The directories containing the files, including the root
+fn parent_dirs(files: &[MPath]) -> BTreeSet<MPath> {
+    let mut dirs = BTreeSet::new();
+    dirs.insert(MPath::empty());
+    for file in files {
+        let elements: Vec<&MPathElement> = file.into_iter().collect();
+        for len in 1..elements.len() {
+            dirs.insert(MPath::empty().join(elements[..len].iter().cloned()));
+        }
+    }
+    dirs
+}
+
+/// The directory of a file or directory, and its name in it
+fn split_path(path: &MPath) -> (MPath, MPath) {
+    let elements: Vec<&MPathElement> = path.into_iter().collect();
+    match elements.split_last() {
+        Some((name, dir)) => (
+            MPath::empty().join(dir.iter().cloned()),
+            MPath::empty().join(Some(*name)),
+        ),
+        None => (MPath::empty(), MPath::empty()),
+    }
+}
+
+fn tree_repo_path(path: &MPath) -> Result<RepoPath> {
+    if path.is_empty() {
+        Ok(RepoPath::root())
+    } else {
+        RepoPath::dir(path.clone())
+    }
+}
+
+/// Loads the trees of the manifest `root` which are in `dirs`
+fn load_trees(
+    repo: BlobRepo,
+    root: NodeHash,
+    dirs: Arc<BTreeSet<MPath>>,
+) -> BoxFuture<Trees, Error> {
+    loop_fn(
+        (vec![(MPath::empty(), root)], HashMap::new()),
+        move |(mut pending, mut trees): (Vec<(MPath, NodeHash)>, Trees)| {
+            let (path, node) = match pending.pop() {
+                Some(next) => next,
+                None => return future::ok(Loop::Break(trees)).boxify(),
+            };
+
+            let dirs = dirs.clone();
+            repo.get_raw_content(&node)
+                .and_then(|bytes| ManifestContent::parse(&bytes))
+                .map(move |content| {
+                    for (name, details) in &content.files {
+                        let child = path.join(name);
+                        if details.is_tree() && dirs.contains(&child) {
+                            pending.push((child, details.entryid().into_nodehash()));
+                        }
+                    }
+                    trees.insert(path, (node, content));
+                    Loop::Continue((pending, trees))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+/// The entry of a file in a manifest, given the trees of the manifest containing it
+fn lookup(trees: &Trees, path: &MPath) -> Option<Details> {
+    let (dir, name) = split_path(path);
+    trees
+        .get(&dir)
+        .and_then(|&(_, ref content)| content.files.get(&name).cloned())
+}
+
+/// Applies the changes to the files to the manifest whose trees containing them are given,
+/// uploading the trees which changed. Returns the node of the new root tree and its upload,
+/// along with the uploads of the other trees.
+fn rebuild_trees(
+    repo: &BlobRepo,
+    mut trees: Trees,
+    changes: Vec<(MPath, Option<Details>)>,
+) -> Result<(NodeHash, BlobFuture, Vec<BlobFuture>)> {
+    // The changes to each directory, by name in the directory, with every directory containing
+    // changed files so that it is rebuilt
+    let mut dir_changes: BTreeMap<MPath, Vec<(MPath, Option<Details>)>> = BTreeMap::new();
+    let paths: Vec<_> = changes.iter().map(|&(ref path, _)| path.clone()).collect();
+    for dir in parent_dirs(&paths) {
+        dir_changes.insert(dir, Vec::new());
+    }
+    for (path, details) in changes {
+        let (dir, name) = split_path(&path);
+        dir_changes
+            .entry(dir)
+            .or_insert_with(Vec::new)
+            .push((name, details));
+    }
+
+    // The longest paths first, so that directories are rebuilt before their parents, which get
+    // their new nodes
+    let mut dirs: Vec<MPath> = dir_changes.keys().cloned().collect();
+    dirs.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    let mut uploads = Vec::new();
+    for dir in dirs {
+        let (p1, mut content) = match trees.remove(&dir) {
+            Some((node, content)) => (Some(node), content),
+            None => (None, ManifestContent::new_empty()),
+        };
+        for (name, details) in dir_changes.remove(&dir).unwrap_or_else(Vec::new) {
+            match details {
+                Some(details) => content.files.insert(name, details),
+                None => content.files.remove(&name),
+            };
+        }
+
+        if dir.is_empty() {
+            let (node, upload) = upload_tree(repo, &dir, &content, p1)?;
+            return Ok((node, upload, uploads));
+        }
+
+        // Directories without any file left are removed from their parent
+        let details = if content.files.is_empty() {
+            None
+        } else {
+            let (node, upload) = upload_tree(repo, &dir, &content, p1)?;
+            uploads.push(upload);
+            Some(Details::new(EntryId::new(node), Type::Tree))
+        };
+        let (parent, name) = split_path(&dir);
+        dir_changes
+            .entry(parent)
+            .or_insert_with(Vec::new)
+            .push((name, details));
+    }
+
+    bail_msg!("Logic error: the root tree was not rebuilt")
+}
+
+fn upload_tree(
+    repo: &BlobRepo,
+    path: &MPath,
+    content: &ManifestContent,
+    p1: Option<NodeHash>,
+) -> Result<(NodeHash, BlobFuture)> {
+    let mut data = Vec::new();
+    content.generate(&mut data)?;
+    repo.upload_entry(
+        Blob::from(Bytes::from(data)),
+        Type::Tree,
+        p1,
+        None,
+        tree_repo_path(path)?,
+    )
+}
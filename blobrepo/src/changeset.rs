@@ -5,13 +5,15 @@
 // GNU General Public License version 2 or any later version.
 
 use std::borrow::Cow;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 
 use bincode;
 use bytes::Bytes;
 use failure;
 use futures::future::{Either, Future, IntoFuture};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
 
 use blobstore::Blobstore;
 
@@ -20,6 +22,7 @@ use mercurial_types::{Blob, BlobNode, Changeset, MPath, Parents, Time};
 use mercurial_types::nodehash::{ChangesetId, ManifestId, NULL_HASH};
 
 use errors::*;
+use utils::topological_order;
 
 // In stock mercurial, the revlog acts as an envelope which holds (primarily) the parents
 // for each entry. The changelog itself is encoded as a blob within the entry. This structure
@@ -39,7 +42,7 @@ pub struct BlobChangeset {
     revlogcs: RevlogChangeset,
 }
 
-fn cskey(changesetid: &ChangesetId) -> String {
+pub fn cskey(changesetid: &ChangesetId) -> String {
     format!("changeset-{}.bincode", changesetid)
 }
 
@@ -104,23 +107,57 @@ impl BlobChangeset {
         &self,
         blobstore: Arc<Blobstore>,
     ) -> impl Future<Item = (), Error = Error> + Send + 'static {
-        let key = cskey(&self.changesetid);
-
-        self.revlogcs.get_node() // FIXME: generate from scratch
-            .map_err(Error::from)
-            .and_then(|node| {
-                let data = node
-                    .as_blob()
-                    .as_slice()
-                    .ok_or(failure::err_msg("missing changeset blob"))?;
-                let blob = RawCSBlob {
-                    parents: *self.revlogcs.parents(),
-                    blob: Cow::Borrowed(data),
-                };
-                bincode::serialize(&blob).map_err(Error::from)
-            })
+        self.to_blob()
             .into_future()
-            .and_then(move |blob| blobstore.put(key, blob.into()))
+            .and_then(move |(key, blob)| blobstore.put(key, blob))
+    }
+
+    /// Save `changesets` with one `put_many` per batch of up to `batch_size` of them, ordered so
+    /// that parents come before their children. A batch is only put once the previous one is
+    /// stored, but the changesets of a batch can land in any order, so until it's stored one of
+    /// them can be there without its parents. Changesets are only visible from the heads and the
+    /// changesets table, which have to be updated once `save_many` is done.
+    pub fn save_many(
+        blobstore: Arc<Blobstore>,
+        changesets: Vec<BlobChangeset>,
+        batch_size: usize,
+    ) -> BoxFuture<(), Error> {
+        let parents: HashMap<_, _> = changesets
+            .iter()
+            .map(|cs| {
+                let parents: Vec<_> = cs.parents().into_iter().collect();
+                (cs.changesetid.into_nodehash(), parents)
+            })
+            .collect();
+        let mut changesets: HashMap<_, _> = changesets
+            .into_iter()
+            .map(|cs| (cs.changesetid.into_nodehash(), cs))
+            .collect();
+        let blobs: Result<Vec<_>> = topological_order(&parents)
+            .into_iter()
+            .filter_map(|node| changesets.remove(&node))
+            .map(|cs| cs.to_blob())
+            .collect();
+        let blobs = try_boxfuture!(blobs);
+
+        stream::iter_ok(blobs)
+            .chunks(batch_size)
+            .for_each(move |batch| blobstore.put_many(batch))
+            .boxify()
+    }
+
+    /// The key and the blob the changeset is stored as
+    fn to_blob(&self) -> Result<(String, Bytes)> {
+        let node = self.revlogcs.get_node()?; // FIXME: generate from scratch
+        let data = node.as_blob()
+            .as_slice()
+            .ok_or(failure::err_msg("missing changeset blob"))?;
+        let blob = RawCSBlob {
+            parents: *self.revlogcs.parents(),
+            blob: Cow::Borrowed(data),
+        };
+        let blob = bincode::serialize(&blob)?;
+        Ok((cskey(&self.changesetid), Bytes::from(blob)))
     }
 }
 
@@ -0,0 +1,245 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Storage of blob contents, either as fulltexts or as deltas against another blob.
+//!
+//! Fulltexts are stored under `sha1-<hash>`, deltas under `delta-sha1-<hash>`. Readers always try
+//! the fulltext first, so repos written without delta storage read exactly as before.
+
+use std::cmp;
+use std::sync::Arc;
+
+use bincode;
+use bytes::Bytes;
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+use mercurial_types::{BlobHash, NodeHash};
+use mercurial_types::delta::{self, Delta, Fragment};
+
+use errors::*;
+use utils::get_node;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RawFragment {
+    start: usize,
+    end: usize,
+    content: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RawDeltaBlob {
+    base: BlobHash,
+    // Number of deltas which need applying to get this blob, including this one
+    chain_len: usize,
+    frags: Vec<RawFragment>,
+}
+
+pub fn get_content_key(blob: &BlobHash) -> String {
+    format!("sha1-{}", blob.sha1())
+}
+
+fn get_delta_key(blob: &BlobHash) -> String {
+    format!("delta-sha1-{}", blob.sha1())
+}
+
+// Describe `text` as a single replacement of whatever lies between the prefix and suffix it has
+// in common with `base`. This is cheap and covers the common cases of appends and local edits.
+fn compute_delta(base: &[u8], text: &[u8]) -> Vec<RawFragment> {
+    let prefix = base.iter()
+        .zip(text.iter())
+        .take_while(|&(b, t)| b == t)
+        .count();
+    let max_suffix = cmp::min(base.len(), text.len()) - prefix;
+    let suffix = base.iter()
+        .rev()
+        .zip(text.iter().rev())
+        .take(max_suffix)
+        .take_while(|&(b, t)| b == t)
+        .count();
+
+    let end = base.len() - suffix;
+    let content = &text[prefix..text.len() - suffix];
+    if prefix == end && content.is_empty() {
+        vec![]
+    } else {
+        vec![
+            RawFragment {
+                start: prefix,
+                end,
+                content: content.to_vec(),
+            },
+        ]
+    }
+}
+
+fn apply_delta(base: &[u8], raw: RawDeltaBlob) -> Result<Bytes> {
+    let frags = raw.frags
+        .into_iter()
+        .map(|frag| Fragment {
+            start: frag.start,
+            end: frag.end,
+            content: frag.content,
+        })
+        .collect();
+    let delta = Delta::new(frags)?;
+    Ok(Bytes::from(delta::apply(base, &delta)))
+}
+
+/// Fetch the content of a blob, reconstructing it from deltas if needed. Returns `None` if the
+/// blob isn't stored in any form.
+pub fn fetch_content(
+    blobstore: &Arc<Blobstore>,
+    blob: BlobHash,
+) -> BoxFuture<Option<Bytes>, Error> {
+    fetch_content_and_chain_len(blobstore.clone(), blob)
+        .map(|content| content.map(|(content, _)| content))
+        .boxify()
+}
+
+/// The keys under which the content of a blob is stored: its fulltext, or its delta and the keys
+/// of the content of the delta base. Returns `None` if the blob isn't stored in any form.
+pub fn get_content_keys(
+    blobstore: Arc<Blobstore>,
+    blob: BlobHash,
+) -> BoxFuture<Option<Vec<String>>, Error> {
+    let key = get_content_key(&blob);
+    blobstore
+        .is_present(key.clone())
+        .and_then(move |present| {
+            if present {
+                return future::ok(Some(vec![key])).boxify();
+            }
+            let delta_key = get_delta_key(&blob);
+            blobstore
+                .get(delta_key.clone())
+                .and_then(move |raw| match raw {
+                    Some(raw) => {
+                        let raw: RawDeltaBlob =
+                            try_boxfuture!(bincode::deserialize(raw.as_ref()));
+                        get_content_keys(blobstore, raw.base)
+                            .and_then(move |base_keys| {
+                                let mut keys =
+                                    base_keys.ok_or(ErrorKind::DeltaBaseMissing(blob, raw.base))?;
+                                keys.push(delta_key);
+                                Ok(Some(keys))
+                            })
+                            .boxify()
+                    }
+                    None => future::ok(None).boxify(),
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn fetch_content_and_chain_len(
+    blobstore: Arc<Blobstore>,
+    blob: BlobHash,
+) -> BoxFuture<Option<(Bytes, usize)>, Error> {
+    blobstore
+        .get(get_content_key(&blob))
+        .and_then(move |fulltext| match fulltext {
+            Some(fulltext) => future::ok(Some((fulltext, 0))).boxify(),
+            None => blobstore
+                .get(get_delta_key(&blob))
+                .and_then(move |raw| {
+                    let raw: RawDeltaBlob = match raw {
+                        Some(raw) => bincode::deserialize(raw.as_ref())?,
+                        None => return Ok(None),
+                    };
+                    Ok(Some((blobstore, raw)))
+                })
+                .and_then(move |raw| match raw {
+                    Some((blobstore, raw)) => fetch_content_and_chain_len(blobstore, raw.base)
+                        .and_then(move |base| {
+                            let (base, _) =
+                                base.ok_or(ErrorKind::DeltaBaseMissing(blob, raw.base))?;
+                            let chain_len = raw.chain_len;
+                            Ok(Some((apply_delta(&base, raw)?, chain_len)))
+                        })
+                        .boxify(),
+                    None => future::ok(None).boxify(),
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+/// Store the content of a blob.
+///
+/// If `max_chain_len` is set and the content of the blob `base` refers to can be fetched, the
+/// content is stored as a delta against it, as long as that's smaller than the fulltext and
+/// doesn't make reconstructing the content take more than `max_chain_len` deltas. Otherwise (for
+/// instance if `base` hasn't been uploaded yet) the fulltext is stored.
+pub fn store_content(
+    blobstore: &Arc<Blobstore>,
+    blob: BlobHash,
+    content: Bytes,
+    base: Option<NodeHash>,
+    max_chain_len: Option<usize>,
+) -> BoxFuture<(), Error> {
+    let (base, max_chain_len) = match (base, max_chain_len) {
+        (Some(base), Some(max_chain_len)) if max_chain_len > 0 => (base, max_chain_len),
+        _ => return blobstore.put(get_content_key(&blob), content),
+    };
+    let blobstore = blobstore.clone();
+
+    // Content must never be stored in more than one form: if a delta overwrote an earlier one,
+    // two blobs could end up as deltas against each other.
+    let present = blobstore
+        .is_present(get_content_key(&blob))
+        .join(blobstore.is_present(get_delta_key(&blob)))
+        .map(|(fulltext, delta)| fulltext || delta);
+
+    present
+        .and_then(move |present| {
+            if present {
+                return future::ok(()).boxify();
+            }
+
+            let base_content = get_node(&blobstore, base).and_then({
+                let blobstore = blobstore.clone();
+                move |node| {
+                    fetch_content_and_chain_len(blobstore, node.blob)
+                        .map(move |content| content.map(|(content, len)| (node.blob, content, len)))
+                }
+            });
+
+            base_content
+                .then(move |res| {
+                    let delta = match res {
+                        Ok(Some((base, ref base_content, base_chain_len)))
+                            if base != blob && base_chain_len < max_chain_len =>
+                        {
+                            let raw = RawDeltaBlob {
+                                base,
+                                chain_len: base_chain_len + 1,
+                                frags: compute_delta(base_content, &content),
+                            };
+                            bincode::serialize(&raw)
+                                .ok()
+                                .and_then(|encoded| {
+                                    if encoded.len() < content.len() {
+                                        Some(encoded)
+                                    } else {
+                                        None
+                                    }
+                                })
+                        }
+                        _ => None,
+                    };
+
+                    match delta {
+                        Some(delta) => blobstore.put(get_delta_key(&blob), Bytes::from(delta)),
+                        None => blobstore.put(get_content_key(&blob), content),
+                    }
+                })
+                .boxify()
+        })
+        .boxify()
+}
@@ -10,7 +10,7 @@ use bincode;
 
 pub use failure::Error;
 
-use mercurial_types::{Blob, BlobHash, ChangesetId, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::{Blob, BlobHash, ChangesetId, MPath, NodeHash, Parents, RepoPath, Type};
 
 #[derive(Debug)]
 pub enum StateOpenError {
@@ -19,6 +19,8 @@ pub enum StateOpenError {
     Blobstore,
     Changesets,
     Linknodes,
+    ObsMarkers,
+    Phases,
 }
 
 impl fmt::Display for StateOpenError {
@@ -31,6 +33,8 @@ impl fmt::Display for StateOpenError {
             Blobstore => write!(f, "blob store"),
             Changesets => write!(f, "changesets"),
             Linknodes => write!(f, "linknodes"),
+            ObsMarkers => write!(f, "obsolescence markers"),
+            Phases => write!(f, "phases"),
         }
     }
 }
@@ -45,6 +49,8 @@ pub enum ErrorKind {
     #[fail(display = "Node id {} is missing", _0)] NodeMissing(NodeHash),
     #[fail(display = "Content missing nodeid {} (blob hash {:?})", _0, _1)]
     ContentMissing(NodeHash, BlobHash),
+    #[fail(display = "Delta base {:?} of blob {:?} is missing", _1, _0)]
+    DeltaBaseMissing(BlobHash, BlobHash),
     #[fail(display = "Uploaded blob is incomplete {:?}", _0)] BadUploadBlob(Blob),
     #[fail(display = "Parents are not in blob store {:?}", _0)] ParentsUnknown(Parents),
     #[fail(display = "Serialization of node failed {} ({})", _0, _1)]
@@ -60,4 +66,16 @@ pub enum ErrorKind {
     #[fail(display = "Parents failed to complete")] ParentsFailed,
     #[fail(display = "Expected {} to be a manifest, found a {} instead", _0, _1)]
     NotAManifest(NodeHash, Type),
+    #[fail(display = "A changeset has at most two parents, not {}", _0)] TooManyParents(usize),
+    #[fail(display = "Invalid change to file {}", _0)] InvalidFileChange(MPath),
+    #[fail(display = "Blob {} is missing", _0)] BlobMissing(String),
+    #[fail(display = "Changeset {} isn't converted to a bonsai changeset", _0)]
+    BonsaiMappingMissing(ChangesetId),
+    #[fail(display = "The {} of changeset {} isn't valid UTF-8", _1, _0)]
+    NotUtf8(ChangesetId, &'static str),
+    #[fail(display = "Bookmark {:?} isn't valid UTF-8", _0)] BookmarkNotUtf8(Vec<u8>),
+    #[fail(display = "Protected bookmark {} can't be {}", _0, _1)]
+    ProtectedBookmark(String, &'static str),
+    #[fail(display = "Garbage collection is deleting blobs, retry the push once it's done")]
+    SweepInProgress,
 }
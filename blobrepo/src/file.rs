@@ -19,6 +19,7 @@ use mercurial_types::nodehash::EntryId;
 
 use blobstore::Blobstore;
 
+use delta::fetch_content;
 use errors::*;
 
 use manifest::BlobManifest;
@@ -41,19 +42,24 @@ pub fn fetch_file_content_and_renames_from_blobstore(
         .and_then({
             let blobstore = blobstore.clone();
             move |node| {
-                let key = format!("sha1-{}", node.blob.sha1());
                 let parents = node.parents;
+                let blobhash = node.blob;
 
-                blobstore.get(key).and_then(move |blob| {
-                    blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
-                        .and_then(|blob| {
+                fetch_content(&blobstore, blobhash).and_then(move |blob| {
+                    blob.ok_or(ErrorKind::ContentMissing(nodeid, blobhash).into())
+                        .and_then(move |blob| {
+                            if node.copy_from.is_some() {
+                                return Ok((blob, node.copy_from));
+                            }
+
+                            // Files whose metadata is stored along with their content
                             let (p1, p2) = parents.get_nodes();
                             let blobnode = BlobNode::new(blob, p1, p2);
                             let file = file::File::new(blobnode);
 
                             file.copied_from().and_then(|from| {
                                 file.content()
-                                    .ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                                    .ok_or(ErrorKind::ContentMissing(nodeid, blobhash).into())
                                     .map(|content| (Bytes::from(content), from))
                             })
                         })
@@ -63,6 +69,24 @@ pub fn fetch_file_content_and_renames_from_blobstore(
         .boxify()
 }
 
+/// The content of a node as Mercurial hashes it, so including the copy metadata of files.
+pub fn fetch_raw_content_from_blobstore(
+    blobstore: &Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxFuture<Bytes, Error> {
+    get_node(blobstore, nodeid)
+        .and_then({
+            let blobstore = blobstore.clone();
+            move |node| {
+                fetch_content(&blobstore, node.blob).and_then(move |blob| {
+                    blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
+                        .map(|blob| node.raw_text(blob))
+                })
+            }
+        })
+        .boxify()
+}
+
 impl BlobEntry {
     pub fn new(
         blobstore: Arc<Blobstore>,
@@ -92,21 +116,7 @@ impl BlobEntry {
     }
 
     fn get_raw_content_inner(&self) -> BoxFuture<Bytes, Error> {
-        let nodeid = self.id.into_nodehash();
-        let blobstore = self.blobstore.clone();
-
-        self.get_node()
-            .and_then({
-                let blobstore = blobstore.clone();
-                move |node| {
-                    let key = format!("sha1-{}", node.blob.sha1());
-
-                    blobstore.get(key).and_then(move |blob| {
-                        blob.ok_or(ErrorKind::ContentMissing(nodeid, node.blob).into())
-                    })
-                }
-            })
-            .boxify()
+        fetch_raw_content_from_blobstore(&self.blobstore, self.id.into_nodehash())
     }
 }
 
@@ -0,0 +1,80 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The history of a file, as getfiles and getpack serve it.
+//!
+//! Nothing is stored for it beyond what landing a changeset stores already: the parents of each
+//! filenode are in its node blob, its copy source in its content, and the changeset which
+//! introduced it in the linknodes, which both blobimport and pushes populate.
+
+use std::collections::{HashSet, VecDeque};
+
+use futures::future::{Future, IntoFuture};
+use futures::stream;
+use futures_ext::{BoxStream, StreamExt};
+
+use mercurial_types::{MPath, NodeHash, Parents, RepoPath};
+use mercurial_types::nodehash::NULL_HASH;
+
+use errors::*;
+use repo::BlobRepo;
+
+/// A revision of a file, along with what its history needs to know of it.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FileNodeInfo {
+    pub node: NodeHash,
+    pub parents: Parents,
+    /// The changeset which introduced this revision of the file
+    pub linknode: NodeHash,
+    /// The path and filenode this revision was copied or renamed from
+    pub copy_from: Option<(MPath, NodeHash)>,
+}
+
+/// `startnode` of the file at `path`, then its ancestors breadth first, each once.
+pub fn file_history_stream(
+    repo: BlobRepo,
+    path: MPath,
+    startnode: NodeHash,
+) -> BoxStream<FileNodeInfo, Error> {
+    if startnode == NULL_HASH {
+        return stream::empty().boxify();
+    }
+    let mut startstate = VecDeque::new();
+    startstate.push_back(startnode);
+    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
+
+    stream::unfold(
+        (startstate, seen_nodes),
+        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
+            let (mut nodes, mut seen_nodes) = cur_data;
+            let node = nodes.pop_front()?;
+
+            let parents = repo.get_parents(&node);
+            let copy = repo.get_file_copy(&node);
+
+            let linknode = RepoPath::file(path.clone()).into_future().and_then({
+                let repo = repo.clone();
+                move |path| repo.get_linknode(path, &node)
+            });
+
+            let joined = parents
+                .join(linknode)
+                .join(copy)
+                .map(|(pl, c)| (pl.0, pl.1, c));
+
+            Some(joined.map(move |(parents, linknode, copy_from)| {
+                nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
+                let info = FileNodeInfo {
+                    node,
+                    parents,
+                    linknode,
+                    copy_from,
+                };
+                (info, (nodes, seen_nodes))
+            }))
+        },
+    ).boxify()
+}
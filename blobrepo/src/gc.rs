@@ -0,0 +1,389 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Garbage collection of the blobs nothing refers to anymore.
+//!
+//! Collection is mark and sweep. Marking walks everything reachable from the given roots
+//! (changesets, their manifests and the files of those) and records the keys of the blobs it
+//! reads. Everything else the blobstore enumerates is unreachable, but isn't deleted right away:
+//! a push in flight can have uploaded blobs which nothing refers to yet. Only the blobs which
+//! were already unreachable in the previous collection, at least a safety window ago, are
+//! deleted. The state of a collection has to be kept between runs for that, see `GcState`.
+//!
+//! A push also skips uploading the blobs which are already stored, even unreachable ones, so
+//! deleting anything while a push is in flight could leave it referring to deleted blobs. Pushes
+//! record themselves before uploading anything (see `uploads`), and a sweep happens between
+//! `begin_sweep` and `end_sweep`, which stop new pushes from starting, and only once
+//! `find_pushes_in_flight` found none. The pushes which completed since marking are taken into
+//! account by `recheck_unreachable`, which has to run after that.
+//!
+//! Only the blobs whose format is known are ever deleted. The rest (aliases and large files, for
+//! instance) are reported as unmanaged and kept.
+
+use std::collections::{BTreeSet, HashSet};
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{self, Future, Loop};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::{Blobstore, Deletable, Enumerable};
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial_types::{Changeset, ChangesetId, NodeHash, NULL_HASH};
+
+use changeset::{cskey, BlobChangeset};
+use delta::{fetch_content, get_content_keys};
+use errors::*;
+use uploads::{get_push_uploads_status, parse_push_uploads_key, SWEEP_KEY};
+use utils::{get_node, get_node_key, RawNodeBlob};
+
+/// The prefixes of the keys of blobs which marking knows about, and so which can be deleted.
+const MANAGED_PREFIXES: &[&str] = &["changeset-", "node-", "sha1-", "delta-sha1-"];
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+enum Node {
+    Changeset(NodeHash),
+    Manifest(NodeHash),
+    File(NodeHash),
+}
+
+/// What visiting a node found: the keys of the blobs it's made of, and the nodes it refers to.
+type Visited = (Vec<String>, Vec<Node>);
+
+/// Mark all the blobs reachable from the changesets `roots`, visiting up to `concurrency` nodes
+/// at once. Returns their keys.
+///
+/// A blob which should be there but isn't fails marking, as the repo is corrupt and sweeping
+/// anything could make it worse.
+pub fn mark_reachable(
+    blobstore: Arc<Blobstore>,
+    roots: Vec<NodeHash>,
+    concurrency: usize,
+) -> BoxFuture<HashSet<String>, Error> {
+    mark_more_reachable(blobstore, roots, Arc::new(HashSet::new()), concurrency)
+}
+
+/// Like `mark_reachable`, but the nodes whose blobs are in `known` aren't visited, nor what they
+/// refer to. Returns the keys of the blobs marked which weren't known.
+fn mark_more_reachable(
+    blobstore: Arc<Blobstore>,
+    roots: Vec<NodeHash>,
+    known: Arc<HashSet<String>>,
+    concurrency: usize,
+) -> BoxFuture<HashSet<String>, Error> {
+    let roots: Vec<_> = roots
+        .into_iter()
+        .filter(|root| *root != NULL_HASH)
+        .map(Node::Changeset)
+        .filter(|node| !known.contains(&node_key(node)))
+        .collect();
+    let seen: HashSet<_> = roots.iter().cloned().collect();
+
+    future::loop_fn(
+        (roots, seen, HashSet::new()),
+        move |(frontier, mut seen, mut marked)| {
+            let blobstore = blobstore.clone();
+            let known = known.clone();
+            stream::iter_ok(frontier)
+                .map(move |node| visit(blobstore.clone(), node))
+                .buffer_unordered(concurrency)
+                .collect()
+                .map(move |visited: Vec<Visited>| {
+                    let mut frontier = Vec::new();
+                    for (keys, refs) in visited {
+                        marked.extend(keys);
+                        for node in refs {
+                            if !known.contains(&node_key(&node)) && seen.insert(node) {
+                                frontier.push(node);
+                            }
+                        }
+                    }
+                    if frontier.is_empty() {
+                        Loop::Break(marked)
+                    } else {
+                        Loop::Continue((frontier, seen, marked))
+                    }
+                })
+        },
+    ).boxify()
+}
+
+/// Of the `keys` a collection found unreachable from the blobs `marked`, those which still are
+/// from the changesets `roots`, as read again right before sweeping: pushes which completed since
+/// marking can refer to blobs which were unreachable, as they aren't uploaded again. Only what
+/// isn't `marked` is visited, so this is quick.
+pub fn recheck_unreachable(
+    blobstore: Arc<Blobstore>,
+    roots: Vec<NodeHash>,
+    marked: Arc<HashSet<String>>,
+    keys: Vec<String>,
+    concurrency: usize,
+) -> BoxFuture<Vec<String>, Error> {
+    mark_more_reachable(blobstore, roots, marked.clone(), concurrency)
+        .map(move |more| {
+            keys.into_iter()
+                .filter(|key| {
+                    let owner = owner_key(key);
+                    !marked.contains(owner) && !more.contains(owner)
+                })
+                .collect()
+        })
+        .boxify()
+}
+
+// The key of the blob visiting `node` marks first
+fn node_key(node: &Node) -> String {
+    match *node {
+        Node::Changeset(nodeid) => cskey(&ChangesetId::new(nodeid)),
+        Node::Manifest(nodeid) | Node::File(nodeid) => get_node_key(nodeid),
+    }
+}
+
+fn visit(blobstore: Arc<Blobstore>, node: Node) -> BoxFuture<Visited, Error> {
+    match node {
+        Node::Changeset(nodeid) => visit_changeset(blobstore, nodeid),
+        Node::Manifest(nodeid) => visit_manifest(blobstore, nodeid),
+        Node::File(nodeid) => visit_file(blobstore, nodeid)
+            .map(|(keys, node)| {
+                let mut refs: Vec<_> = node.parents.into_iter().map(Node::File).collect();
+                refs.extend(node.copy_from.map(|(_, copied)| Node::File(copied)));
+                (keys, refs)
+            })
+            .boxify(),
+    }
+}
+
+fn visit_changeset(blobstore: Arc<Blobstore>, nodeid: NodeHash) -> BoxFuture<Visited, Error> {
+    let changesetid = ChangesetId::new(nodeid);
+    BlobChangeset::load(&blobstore, &changesetid)
+        .and_then(move |cs| {
+            let cs = cs.ok_or(ErrorKind::ChangesetMissing(changesetid))?;
+            let mut refs: Vec<_> = cs.parents().into_iter().map(Node::Changeset).collect();
+            let manifest = cs.manifestid().into_nodehash();
+            if manifest != NULL_HASH {
+                refs.push(Node::Manifest(manifest));
+            }
+            Ok((vec![cskey(&changesetid)], refs))
+        })
+        .boxify()
+}
+
+fn visit_manifest(blobstore: Arc<Blobstore>, nodeid: NodeHash) -> BoxFuture<Visited, Error> {
+    visit_file(blobstore.clone(), nodeid)
+        .and_then(move |(keys, node)| {
+            fetch_content(&blobstore, node.blob).and_then(move |content| {
+                let content = content.ok_or(ErrorKind::ContentMissing(nodeid, node.blob))?;
+                let content = ManifestContent::parse(content.as_ref())?;
+                let mut refs: Vec<_> = node.parents.into_iter().map(Node::Manifest).collect();
+                refs.extend(content.files.values().map(|details| {
+                    let entry = details.entryid().into_nodehash();
+                    if details.is_tree() {
+                        Node::Manifest(entry)
+                    } else {
+                        Node::File(entry)
+                    }
+                }));
+                Ok((keys, refs))
+            })
+        })
+        .boxify()
+}
+
+// Manifests are stored like files, so this is the part of visiting either which doesn't depend
+// on the content.
+fn visit_file(
+    blobstore: Arc<Blobstore>,
+    nodeid: NodeHash,
+) -> BoxFuture<(Vec<String>, RawNodeBlob), Error> {
+    get_node(&blobstore, nodeid)
+        .and_then(move |node| {
+            get_content_keys(blobstore, node.blob).and_then(move |keys| {
+                let mut keys = keys.ok_or(ErrorKind::ContentMissing(nodeid, node.blob))?;
+                keys.push(get_node_key(nodeid));
+                Ok((keys, node))
+            })
+        })
+        .boxify()
+}
+
+/// The key of the blob `key` belongs to: itself, unless it's a chunk of a larger blob.
+fn owner_key(key: &str) -> &str {
+    match key.rfind(".chunk") {
+        Some(pos) if key[pos + ".chunk".len()..].parse::<u64>().is_ok() => &key[..pos],
+        _ => key,
+    }
+}
+
+fn is_managed(key: &str) -> bool {
+    MANAGED_PREFIXES.iter().any(|prefix| key.starts_with(prefix))
+}
+
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Unreachable {
+    /// The keys which can be deleted
+    pub keys: BTreeSet<String>,
+    /// How many keys the blobstore enumerated
+    pub enumerated: usize,
+    /// How many keys were kept because the format of their blobs is unknown
+    pub unmanaged: usize,
+}
+
+/// Find the keys of the blobstore which aren't in `marked`. Chunks are reachable whenever the
+/// blob they're part of is, so a key ending in `.chunk<N>` counts as the key before it.
+pub fn find_unreachable<B>(
+    blobstore: &B,
+    marked: Arc<HashSet<String>>,
+) -> BoxFuture<Unreachable, Error>
+where
+    B: Enumerable,
+{
+    blobstore
+        .enumerate()
+        .fold(Unreachable::default(), move |mut unreachable, key| {
+            unreachable.enumerated += 1;
+            let reachable = marked.contains(owner_key(&key));
+            if !reachable {
+                if is_managed(&key) {
+                    unreachable.keys.insert(key);
+                } else {
+                    unreachable.unmanaged += 1;
+                }
+            }
+            Ok::<_, Error>(unreachable)
+        })
+        .boxify()
+}
+
+/// What a collection found unreachable, which the next collection needs.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GcState {
+    /// Incremented by each collection which deletes blobs
+    pub generation: u64,
+    /// When the keys were first found unreachable, in seconds since the epoch
+    pub time: u64,
+    pub unreachable: BTreeSet<String>,
+}
+
+/// Decide which of the `unreachable` keys a collection running at `now` can delete, given the
+/// state `previous` left by the previous collection. Returns them and the state to keep for the
+/// next collection.
+///
+/// Only the keys which were unreachable in the previous collection, at least `window` seconds
+/// ago, are deleted. The previous state is kept until then, less the keys which became reachable
+/// again. The first collection deletes nothing.
+pub fn plan_sweep(
+    previous: Option<GcState>,
+    mut unreachable: BTreeSet<String>,
+    now: u64,
+    window: u64,
+) -> (Vec<String>, GcState) {
+    match previous {
+        None => (
+            vec![],
+            GcState {
+                generation: 1,
+                time: now,
+                unreachable,
+            },
+        ),
+        Some(previous) => {
+            if now < previous.time + window {
+                return (vec![], postpone_sweep(previous, &unreachable));
+            }
+            let mut deleted = Vec::new();
+            for key in previous.unreachable {
+                if unreachable.remove(&key) {
+                    deleted.push(key);
+                }
+            }
+            let next = GcState {
+                generation: previous.generation + 1,
+                time: now,
+                unreachable,
+            };
+            (deleted, next)
+        }
+    }
+}
+
+/// The state to keep when a collection can't sweep: the state `previous` left, less the keys
+/// which aren't `unreachable` anymore.
+pub fn postpone_sweep(previous: GcState, unreachable: &BTreeSet<String>) -> GcState {
+    let unreachable = previous
+        .unreachable
+        .intersection(unreachable)
+        .cloned()
+        .collect();
+    GcState {
+        unreachable,
+        ..previous
+    }
+}
+
+/// Stop new pushes from starting until `end_sweep`, so that none can start relying on the blobs
+/// which are about to be deleted. Look for the pushes already in flight with
+/// `find_pushes_in_flight` afterwards.
+pub fn begin_sweep<B>(blobstore: &B) -> BoxFuture<(), Error>
+where
+    B: Deletable,
+{
+    blobstore.put(SWEEP_KEY.to_string(), Bytes::new())
+}
+
+pub fn end_sweep<B>(blobstore: &B) -> BoxFuture<(), Error>
+where
+    B: Deletable,
+{
+    blobstore.delete(SWEEP_KEY.to_string())
+}
+
+/// The ids of the push uploads records of the pushes in flight at `now`: those which aren't
+/// complete, and were recorded less than `window` seconds ago. A push left incomplete for longer
+/// failed, and the blobs it uploaded are left to be collected.
+pub fn find_pushes_in_flight<B>(
+    blobstore: Arc<B>,
+    now: u64,
+    window: u64,
+    concurrency: usize,
+) -> BoxFuture<Vec<String>, Error>
+where
+    B: Enumerable,
+{
+    blobstore
+        .enumerate()
+        .filter_map(|key| parse_push_uploads_key(&key).map(|id| id.to_string()))
+        .map({
+            let blobstore = blobstore.clone();
+            move |id| {
+                get_push_uploads_status(&*blobstore, &id).map(move |status| match status {
+                    Some((time, false)) if now < time + window => Some(id),
+                    _ => None,
+                })
+            }
+        })
+        .buffer_unordered(concurrency)
+        .filter_map(|id| id)
+        .collect()
+        .boxify()
+}
+
+/// Delete `keys` from the blobstore, up to `concurrency` at once. Returns how many were deleted.
+pub fn sweep<B>(
+    blobstore: Arc<B>,
+    keys: Vec<String>,
+    concurrency: usize,
+) -> BoxFuture<usize, Error>
+where
+    B: Deletable + Send + Sync + 'static,
+{
+    stream::iter_ok(keys)
+        .map(move |key| blobstore.delete(key))
+        .buffer_unordered(concurrency)
+        .fold(0, |deleted, ()| Ok::<_, Error>(deleted + 1))
+        .boxify()
+}
+
@@ -17,6 +17,7 @@ extern crate bytes;
 extern crate serde;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate tokio_core;
@@ -34,6 +35,8 @@ extern crate fileblob;
 extern crate filebookmarks;
 extern crate fileheads;
 extern crate filelinknodes;
+extern crate fileobsmarkers;
+extern crate filephases;
 #[macro_use]
 extern crate futures_ext;
 extern crate heads;
@@ -43,29 +46,60 @@ extern crate memblob;
 extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
+extern crate memobsmarkers;
+extern crate memphases;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate mononoke_types;
+extern crate obsmarkers;
+extern crate phases;
 extern crate rocksblob;
+extern crate rust_crypto;
+extern crate statsblob;
 extern crate storage_types;
+extern crate throttledblob;
 
 mod repo;
+mod alias;
+mod bonsai;
 mod changeset;
+mod changes;
+mod delta;
 mod manifest;
+mod manifest_diff;
 mod file;
+mod file_history;
 mod errors;
+mod gc;
+mod oplog;
+mod protected;
 mod utils;
 mod repo_commit;
+mod replication;
+mod uploads;
 
 pub use errors::*;
 
+pub use alias::get_sha256;
+pub use changes::{ChangesetMetadata, FileChange};
 pub use changeset::BlobChangeset;
 pub use file::BlobEntry;
+pub use file_history::FileNodeInfo;
+pub use gc::{begin_sweep, end_sweep, find_pushes_in_flight, find_unreachable, mark_reachable,
+             plan_sweep, postpone_sweep, recheck_unreachable, sweep, GcState, Unreachable};
 pub use manifest::BlobManifest;
+pub use manifest_diff::ManifestDiffEntry;
+pub use oplog::{read_oplog, BookmarkMove, LoggedOperation, OpLog, Operation, Outcome};
+pub use protected::ProtectedBookmarks;
+pub use replication::{find_missing_changesets, replicate_changesets};
 pub use repo::BlobRepo;
 pub use repo_commit::ChangesetHandle;
+pub use uploads::PushUploads;
 // TODO: This is exported for testing - is this the right place for it?
 pub use repo_commit::compute_changed_files;
 //
 // TODO: (jsgf) T21597565 This is exposed here for blobimport -- don't use it for anything else.
 
 pub use utils::RawNodeBlob;
+// Also for tools reading blobs directly, as contents can be stored as deltas
+pub use delta::fetch_content;
@@ -0,0 +1,72 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The files which differ between two manifests.
+
+use mercurial_types::{MPath, NodeHash};
+use mercurial_types::manifest::Type;
+use mercurial_types::manifest_utils::{ChangedEntry, EntryStatus};
+
+/// A file which differs between two manifests, with its filenodes on either side.
+///
+/// A file whose type changes, say from a regular file to an executable or a directory, is both
+/// removed and added.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ManifestDiffEntry {
+    Added { path: MPath, new: NodeHash },
+    Removed { path: MPath, old: NodeHash },
+    Modified {
+        path: MPath,
+        old: NodeHash,
+        new: NodeHash,
+    },
+}
+
+impl ManifestDiffEntry {
+    pub fn path(&self) -> &MPath {
+        match self {
+            &ManifestDiffEntry::Added { ref path, .. } => path,
+            &ManifestDiffEntry::Removed { ref path, .. } => path,
+            &ManifestDiffEntry::Modified { ref path, .. } => path,
+        }
+    }
+
+    /// The diff entry of a file, or None for a tree, whose changed files come as entries of
+    /// their own.
+    pub(crate) fn from_changed_entry(changed: ChangedEntry) -> Option<Self> {
+        let ChangedEntry { path, status } = changed;
+        match status {
+            EntryStatus::Added(entry) => {
+                if entry.get_type() == Type::Tree {
+                    return None;
+                }
+                Some(ManifestDiffEntry::Added {
+                    path: path.join_element(entry.get_name()),
+                    new: entry.get_hash().into_nodehash(),
+                })
+            }
+            EntryStatus::Deleted(entry) => {
+                if entry.get_type() == Type::Tree {
+                    return None;
+                }
+                Some(ManifestDiffEntry::Removed {
+                    path: path.join_element(entry.get_name()),
+                    old: entry.get_hash().into_nodehash(),
+                })
+            }
+            EntryStatus::Modified(new, old) => {
+                if new.get_type() == Type::Tree {
+                    return None;
+                }
+                Some(ManifestDiffEntry::Modified {
+                    path: path.join_element(new.get_name()),
+                    old: old.get_hash().into_nodehash(),
+                    new: new.get_hash().into_nodehash(),
+                })
+            }
+        }
+    }
+}
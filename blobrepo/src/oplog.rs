@@ -0,0 +1,240 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Write-ahead log of the operations changing what a repo serves.
+//!
+//! Every push and every move of bookmarks is appended to the log, and synced to disk, before it
+//! is applied, and its outcome is appended once it's known. An operation without an outcome was
+//! in flight when the server stopped, so after a crash the log tells what may have been applied
+//! only in part, and in any case who changed what and when. `oplog_replay` lists the log, and
+//! applies it again to a repo, for instance to rebuild the bookmarks of a repo restored from a
+//! backup, or up to a botched push.
+//!
+//! The log of a repo is a file of JSON records, one per line, which is only ever appended to.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use failure::ResultExt;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use serde_json;
+use slog::Logger;
+
+use bookmarks::timestamp_now;
+use mercurial_types::{ChangesetId, NodeHash};
+
+use errors::*;
+
+/// A change to the state of a repo, as logged before it's applied.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Changesets pushed to the repo, whose blobs are listed by the push uploads record `uploads`.
+    /// The changesets a pushrebase creates are only logged as the bookmark moved onto them.
+    Push {
+        changesets: Vec<NodeHash>,
+        uploads: String,
+    },
+    /// Bookmarks moved together, as with `BlobRepo::update_bookmarks`
+    Bookmarks {
+        moves: Vec<BookmarkMove>,
+        reason: String,
+    },
+}
+
+/// A move of the bookmark `name` from `old` to `new`, where None means that it doesn't exist.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BookmarkMove {
+    pub name: String,
+    pub old: Option<ChangesetId>,
+    pub new: Option<ChangesetId>,
+}
+
+/// How an operation ended.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    Applied,
+    /// Nothing was changed, as the bookmarks weren't where the operation expected them
+    Rejected,
+    Failed(String),
+}
+
+/// An operation read back from the log, with its outcome if one was logged.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LoggedOperation {
+    pub seq: u64,
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub author: String,
+    pub operation: Operation,
+    pub outcome: Option<Outcome>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Record {
+    Begin {
+        seq: u64,
+        timestamp: u64,
+        author: String,
+        operation: Operation,
+    },
+    End { seq: u64, outcome: Outcome },
+}
+
+struct LogFile {
+    file: File,
+    next_seq: u64,
+}
+
+/// The log of a repo, open for appending.
+pub struct OpLog {
+    inner: Arc<Mutex<LogFile>>,
+}
+
+impl OpLog {
+    /// Open the log at `path`, creating it if it doesn't exist. Operations are numbered on from
+    /// the last one already logged, and a record cut short by a crash is dropped.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let (records, len) = read_records(path)?;
+        let next_seq = records
+            .iter()
+            .map(|record| match *record {
+                Record::Begin { seq, .. } | Record::End { seq, .. } => seq + 1,
+            })
+            .max()
+            .unwrap_or(0);
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|_| format!("failed to open operation log {}", path.display()))?;
+        file.set_len(len)?;
+        Ok(OpLog {
+            inner: Arc::new(Mutex::new(LogFile { file, next_seq })),
+        })
+    }
+
+    /// Log `operation`, made by `author`, resolving to its number in the log once it's on disk.
+    pub fn begin(&self, author: &str, operation: Operation) -> Result<u64> {
+        let mut inner = self.inner.lock().expect("lock poisoned");
+        let seq = inner.next_seq;
+        let record = Record::Begin {
+            seq,
+            timestamp: timestamp_now(),
+            author: author.to_string(),
+            operation,
+        };
+        write_record(&mut inner.file, &record)?;
+        inner.next_seq += 1;
+        Ok(seq)
+    }
+
+    /// Log `operation` before running `apply`, which applies it, and its outcome once `apply`
+    /// is done: `Rejected` if `applied` says nothing was changed. Failing to log the outcome
+    /// doesn't fail the operation, which has been applied by then, and is only warned about.
+    pub fn run<F>(
+        &self,
+        logger: &Logger,
+        author: &str,
+        operation: Operation,
+        apply: F,
+        applied: fn(&F::Item) -> bool,
+    ) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let seq = try_boxfuture!(self.begin(author, operation));
+        let inner = self.inner.clone();
+        let logger = logger.clone();
+        apply
+            .then(move |res| {
+                let outcome = match res {
+                    Ok(ref item) if applied(item) => Outcome::Applied,
+                    Ok(_) => Outcome::Rejected,
+                    Err(ref err) => Outcome::Failed(format!("{}", err)),
+                };
+                let mut inner = inner.lock().expect("lock poisoned");
+                if let Err(err) = write_record(&mut inner.file, &Record::End { seq, outcome }) {
+                    warn!(logger, "failed to log the outcome of operation {}: {}", seq, err);
+                }
+                res
+            })
+            .boxify()
+    }
+}
+
+fn write_record(file: &mut File, record: &Record) -> Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+    // A single write, so that records don't interleave, synced before the operation goes ahead
+    file.write_all(&line)?;
+    file.sync_data()?;
+    Ok(())
+}
+
+/// The records of the log at `path`, and the length of the log up to the end of the last one.
+fn read_records(path: &Path) -> Result<(Vec<Record>, u64)> {
+    let mut content = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut content).map(|_| ())?,
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok((Vec::new(), 0)),
+        Err(err) => Err(err)
+            .with_context(|_| format!("failed to open operation log {}", path.display()))?,
+    };
+
+    let mut lines: Vec<_> = content.split(|byte| *byte == b'\n').collect();
+    // What follows the last newline is empty, unless a crash cut a record short. The operation
+    // of such a record wasn't applied, as records are synced before their operations go ahead,
+    // or if it was an outcome, the operation shows as in flight.
+    lines.pop();
+    let mut records = Vec::with_capacity(lines.len());
+    let mut len = 0;
+    for (idx, line) in lines.into_iter().enumerate() {
+        let record = serde_json::from_slice(line).with_context(|_| {
+            format!("invalid record {} of operation log {}", idx + 1, path.display())
+        })?;
+        records.push(record);
+        len += line.len() as u64 + 1;
+    }
+    Ok((records, len))
+}
+
+/// Read the operations of the log at `path`, in the order they were logged.
+pub fn read_oplog<P: AsRef<Path>>(path: P) -> Result<Vec<LoggedOperation>> {
+    let mut operations: Vec<LoggedOperation> = Vec::new();
+    let (records, _) = read_records(path.as_ref())?;
+    for record in records {
+        match record {
+            Record::Begin {
+                seq,
+                timestamp,
+                author,
+                operation,
+            } => operations.push(LoggedOperation {
+                seq,
+                timestamp,
+                author,
+                operation,
+                outcome: None,
+            }),
+            Record::End { seq, outcome } => {
+                // Outcomes are logged after their operations, and usually soon after
+                match operations.iter_mut().rev().find(|op| op.seq == seq) {
+                    Some(op) => op.outcome = Some(outcome),
+                    None => bail_msg!("outcome of unknown operation {} in operation log", seq),
+                }
+            }
+        }
+    }
+    Ok(operations)
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Bookmarks which can't be deleted, nor moved to a changeset which doesn't descend from the one
+//! they're at, whoever moves them. The rules can be replaced while the repo is in use, as the
+//! server does when it reloads its config.
+
+use std::collections::HashSet;
+use std::sync::RwLock;
+
+pub struct ProtectedBookmarks {
+    names: RwLock<HashSet<Vec<u8>>>,
+}
+
+impl ProtectedBookmarks {
+    pub fn new<I, S>(names: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        ProtectedBookmarks {
+            names: RwLock::new(Self::collect(names)),
+        }
+    }
+
+    /// Protect `names` instead of the bookmarks protected so far.
+    pub fn replace<I, S>(&self, names: I)
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        *self.names.write().expect("lock poisoned") = Self::collect(names);
+    }
+
+    pub fn is_protected(&self, name: &[u8]) -> bool {
+        self.names.read().expect("lock poisoned").contains(name)
+    }
+
+    fn collect<I, S>(names: I) -> HashSet<Vec<u8>>
+    where
+        I: IntoIterator<Item = S>,
+        S: AsRef<[u8]>,
+    {
+        names
+            .into_iter()
+            .map(|name| name.as_ref().to_vec())
+            .collect()
+    }
+}
@@ -0,0 +1,279 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Replication of the blobs of changesets from one blobstore to another.
+//!
+//! Blobs are copied before the blobs which refer to them: the contents of a node before the node,
+//! the entries of a manifest before the manifest, and a changeset last, once its parents and root
+//! manifest have been copied. So whenever the destination has a changeset or node, it has
+//! everything these refer to, and replication can stop there. That's also what makes it safe to
+//! interrupt: the next replication carries on where the previous one stopped.
+//!
+//! The parents of manifest and file nodes are replicated with the changesets they belong to.
+//!
+//! The walks fan out at every level of the history and of the manifests, so the number of blobs
+//! they ask for at once is bounded for the whole walk, by throttling the requests to each
+//! blobstore, rather than at each level.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use futures::future::{self, Future, Loop};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::Blobstore;
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial_types::{BlobHash, Changeset, ChangesetId, NodeHash, NULL_HASH};
+use throttledblob::{ThrottleLimits, ThrottledBlobstore};
+
+use changeset::{cskey, BlobChangeset};
+use delta::{fetch_content, get_content_keys};
+use errors::*;
+use utils::{get_node, get_node_key, topological_order};
+
+// Up to `concurrency` requests in flight to `blobstore` at once, however they're made
+fn limit_inflight(blobstore: Arc<Blobstore>, concurrency: usize) -> Result<Arc<Blobstore>> {
+    let limits = ThrottleLimits {
+        max_inflight: Some(concurrency),
+        max_qps: None,
+    };
+    Ok(Arc::new(ThrottledBlobstore::new(blobstore, limits)?))
+}
+
+/// The changesets reachable from `heads` which `dest` doesn't have yet, parents first. Walking
+/// the history stops at the changesets in `known`, which `dest` is known to have. There are up to
+/// `concurrency` requests in flight to each blobstore.
+pub fn find_missing_changesets(
+    source: Arc<Blobstore>,
+    dest: Arc<Blobstore>,
+    heads: Vec<NodeHash>,
+    known: HashSet<NodeHash>,
+    concurrency: usize,
+) -> BoxFuture<Vec<NodeHash>, Error> {
+    let heads: Vec<_> = heads
+        .into_iter()
+        .filter(|head| *head != NULL_HASH && !known.contains(head))
+        .collect();
+    let seen: HashSet<_> = heads.iter().cloned().collect();
+    let known = Arc::new(known);
+    let source = try_boxfuture!(limit_inflight(source, concurrency));
+    let dest = try_boxfuture!(limit_inflight(dest, concurrency));
+
+    // Walk the history one generation at a time, recording the parents of missing changesets
+    future::loop_fn(
+        (heads, seen, HashMap::new()),
+        move |(frontier, mut seen, mut missing): (Vec<_>, HashSet<_>, HashMap<_, _>)| {
+            let source = source.clone();
+            let dest = dest.clone();
+            stream::iter_ok(frontier)
+                .map(move |node| {
+                    let csid = ChangesetId::new(node);
+                    let source = source.clone();
+                    dest.is_present(cskey(&csid)).and_then(move |present| {
+                        if present {
+                            return future::ok(None).boxify();
+                        }
+                        BlobChangeset::load(&source, &csid)
+                            .and_then(move |cs| {
+                                let cs = cs.ok_or(ErrorKind::ChangesetMissing(csid))?;
+                                let parents: Vec<_> = cs.parents().into_iter().collect();
+                                Ok(Some((node, parents)))
+                            })
+                            .boxify()
+                    })
+                })
+                .buffer_unordered(concurrency)
+                .collect()
+                .map({
+                    let known = known.clone();
+                    move |batch| {
+                        let mut frontier = Vec::new();
+                        for (node, parents) in batch.into_iter().filter_map(|found| found) {
+                            for parent in &parents {
+                                if !known.contains(parent) && seen.insert(*parent) {
+                                    frontier.push(*parent);
+                                }
+                            }
+                            missing.insert(node, parents);
+                        }
+                        if frontier.is_empty() {
+                            Loop::Break(missing)
+                        } else {
+                            Loop::Continue((frontier, seen, missing))
+                        }
+                    }
+                })
+        },
+    ).map(|missing| topological_order(&missing))
+        .boxify()
+}
+
+/// Copy the blobs of `changesets`, which have to be ordered parents first, from `source` to
+/// `dest`. The contents of up to `batch_size` changesets are copied at once, with up to
+/// `concurrency` requests in flight to each blobstore for all of them, and the changeset blobs
+/// themselves in order once their batch is done. Yields each changeset once it's replicated, with the number of blobs copied for
+/// it.
+///
+/// A `batch_size` larger than one is meant to catch up on a long history: the changesets of a
+/// batch can share manifests and files, which may then be copied more than once.
+pub fn replicate_changesets(
+    source: Arc<Blobstore>,
+    dest: Arc<Blobstore>,
+    changesets: Vec<NodeHash>,
+    batch_size: usize,
+    concurrency: usize,
+) -> BoxStream<(NodeHash, usize), Error> {
+    let source = match limit_inflight(source, concurrency) {
+        Ok(source) => source,
+        Err(err) => return stream::once(Err(err)).boxify(),
+    };
+    let dest = match limit_inflight(dest, concurrency) {
+        Ok(dest) => dest,
+        Err(err) => return stream::once(Err(err)).boxify(),
+    };
+    stream::iter_ok(changesets)
+        .chunks(batch_size)
+        .and_then(move |batch| {
+            let contents: Vec<_> = batch
+                .iter()
+                .map(|node| replicate_changeset_contents(&source, &dest, *node, concurrency))
+                .collect();
+            let source = source.clone();
+            let dest = dest.clone();
+            future::join_all(contents).map(move |copied| {
+                // Changesets are copied one at a time, so that none lands before its parents
+                stream::iter_ok(batch.into_iter().zip(copied)).and_then(move |(node, copied)| {
+                    let key = cskey(&ChangesetId::new(node));
+                    copy_blob(&source, &dest, key).map(move |()| (node, copied + 1))
+                })
+            })
+        })
+        .flatten()
+        .boxify()
+}
+
+/// Copy everything a changeset refers to but its parents: its root manifest, and recursively
+/// the manifests and files the destination doesn't have.
+fn replicate_changeset_contents(
+    source: &Arc<Blobstore>,
+    dest: &Arc<Blobstore>,
+    node: NodeHash,
+    concurrency: usize,
+) -> BoxFuture<usize, Error> {
+    let csid = ChangesetId::new(node);
+    let source = source.clone();
+    let dest = dest.clone();
+    BlobChangeset::load(&source, &csid)
+        .and_then(move |cs| {
+            let cs = try_boxfuture!(cs.ok_or(ErrorKind::ChangesetMissing(csid)));
+            let manifest = cs.manifestid().into_nodehash();
+            if manifest == NULL_HASH {
+                future::ok(0).boxify()
+            } else {
+                replicate_node(source, dest, manifest, true, concurrency)
+            }
+        })
+        .boxify()
+}
+
+/// Copy a manifest or file node, unless the destination has it already. The content is copied
+/// before the node, and so are the entries of a manifest.
+fn replicate_node(
+    source: Arc<Blobstore>,
+    dest: Arc<Blobstore>,
+    node: NodeHash,
+    is_manifest: bool,
+    concurrency: usize,
+) -> BoxFuture<usize, Error> {
+    let node_key = get_node_key(node);
+    dest.is_present(node_key.clone())
+        .and_then(move |present| {
+            if present {
+                return future::ok(0).boxify();
+            }
+            get_node(&source, node)
+                .and_then({
+                    let source = source.clone();
+                    let dest = dest.clone();
+                    move |nodeblob| {
+                        let blob = nodeblob.blob;
+                        let entries = if is_manifest {
+                            replicate_entries(source.clone(), dest.clone(), node, blob, concurrency)
+                        } else {
+                            future::ok(0).boxify()
+                        };
+                        let content = get_content_keys(source.clone(), blob)
+                            .and_then(move |keys| {
+                                let keys = keys.ok_or(ErrorKind::ContentMissing(node, blob))?;
+                                Ok(keys)
+                            })
+                            .and_then(move |keys| copy_blobs(&source, &dest, keys));
+                        entries.join(content).map(|(entries, content)| entries + content)
+                    }
+                })
+                .and_then(move |copied| {
+                    copy_blob(&source, &dest, node_key).map(move |()| copied + 1)
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn replicate_entries(
+    source: Arc<Blobstore>,
+    dest: Arc<Blobstore>,
+    node: NodeHash,
+    blob: BlobHash,
+    concurrency: usize,
+) -> BoxFuture<usize, Error> {
+    fetch_content(&source, blob)
+        .and_then(move |content| {
+            let content = content.ok_or(ErrorKind::ContentMissing(node, blob))?;
+            ManifestContent::parse(content.as_ref())
+        })
+        .and_then(move |manifest| {
+            let entries: Vec<_> = manifest
+                .files
+                .values()
+                .map(|details| (details.entryid().into_nodehash(), details.is_tree()))
+                .collect();
+            stream::iter_ok(entries)
+                .map(move |(entry, is_tree)| {
+                    replicate_node(source.clone(), dest.clone(), entry, is_tree, concurrency)
+                })
+                .buffer_unordered(concurrency)
+                .fold(0, |total, copied| Ok::<_, Error>(total + copied))
+        })
+        .boxify()
+}
+
+fn copy_blobs(
+    source: &Arc<Blobstore>,
+    dest: &Arc<Blobstore>,
+    keys: Vec<String>,
+) -> BoxFuture<usize, Error> {
+    let count = keys.len();
+    let copies: Vec<_> = keys.into_iter()
+        .map(|key| copy_blob(source, dest, key))
+        .collect();
+    future::join_all(copies).map(move |_| count).boxify()
+}
+
+fn copy_blob(
+    source: &Arc<Blobstore>,
+    dest: &Arc<Blobstore>,
+    key: String,
+) -> BoxFuture<(), Error> {
+    let dest = dest.clone();
+    source
+        .get(key.clone())
+        .and_then(move |blob| match blob {
+            Some(blob) => dest.put(key, blob),
+            None => future::err(ErrorKind::BlobMissing(key).into()).boxify(),
+        })
+        .boxify()
+}
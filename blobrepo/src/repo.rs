@@ -7,63 +7,98 @@
 use std::collections::{BTreeMap, HashSet};
 use std::mem;
 use std::path::Path;
+use std::str::FromStr;
 use std::sync::Arc;
 
 use bincode;
 use bytes::Bytes;
 use failure::{Fail, ResultExt};
 use futures::{Async, Poll};
-use futures::future::Future;
+use futures::future::{self, loop_fn, Future, Loop};
 use futures::stream::{self, Stream};
 use futures::sync::oneshot;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use futures_stats::{Stats, Timed};
 use slog::{Discard, Drain, Logger};
 
-use blobstore::Blobstore;
-use bookmarks::Bookmarks;
-use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
+use blobstore::{Blobstore, CachingBlobstore, PrefixBlobstore, ReadOnlyBlobstore};
+use bookmarks::{BookmarkEvent, BookmarkLogEntry, Bookmarks, BookmarksMut, BookmarksTransaction};
+use changesets::{ChangesetEntry, ChangesetInsert, Changesets, SqliteChangesets};
 use fileblob::Fileblob;
 use filebookmarks::FileBookmarks;
 use fileheads::FileHeads;
 use filelinknodes::FileLinknodes;
+use fileobsmarkers::FileObsMarkers;
+use filephases::FilePhases;
 use heads::Heads;
 use linknodes::Linknodes;
 use manifoldblob::ManifoldBlob;
-use memblob::{EagerMemblob, LazyMemblob};
+use memblob::{BoundedMemblob, EagerMemblob, LazyMemblob};
 use membookmarks::MemBookmarks;
 use memheads::MemHeads;
 use memlinknodes::MemLinknodes;
+use memobsmarkers::MemObsMarkers;
+use memphases::MemPhases;
+use mercurial::file::File;
 use mercurial_types::{Blob, BlobNode, Changeset, ChangesetId, Entry, MPath, Manifest, NodeHash,
-                      Parents, RepoPath, RepositoryId, Time};
+                      ObsMarker, Parents, RepoPath, RepositoryId, Time};
 use mercurial_types::manifest;
+use mercurial_types::manifest_utils::changed_entry_stream;
 use mercurial_types::nodehash::ManifestId;
+use mononoke_types::{BonsaiChangeset, ContentId};
+use mononoke_types::ChangesetId as BonsaiChangesetId;
+use obsmarkers::ObsMarkers;
+use phases::{Phase, Phases};
 use rocksblob::Rocksblob;
+use statsblob::StatsBlobstore;
 use storage_types::Version;
 use tokio_core::reactor::Remote;
 
 use BlobChangeset;
 use BlobManifest;
+use alias::{get_sha256_alias, store_sha256_alias};
+use bonsai::{convert_to_bonsai, get_bonsai_from_hg, get_content_alias, get_hg_from_bonsai,
+             load_bonsai_changeset};
+use changes::{create_changeset_from_changes, ChangesetMetadata, FileChange};
+use delta::{fetch_content, get_content_key, get_content_keys, store_content};
 use errors::*;
-use file::{fetch_file_content_and_renames_from_blobstore, BlobEntry};
+use file::{fetch_file_content_and_renames_from_blobstore, fetch_raw_content_from_blobstore,
+           BlobEntry};
+use file_history::{file_history_stream, FileNodeInfo};
+use manifest_diff::ManifestDiffEntry;
+use oplog::{BookmarkMove, OpLog, Operation};
+use protected::ProtectedBookmarks;
 use repo_commit::*;
+use uploads::{complete_push_uploads, get_push_uploads, record_push_uploads, PushUploads};
 use utils::{get_node, get_node_key, RawNodeBlob};
 
 pub struct BlobRepo {
     logger: Logger,
     blobstore: Arc<Blobstore>,
-    bookmarks: Arc<Bookmarks>,
+    bookmarks: Arc<BookmarksMut>,
+    phases: Arc<Phases>,
+    obsmarkers: Arc<ObsMarkers>,
     heads: Arc<Heads>,
     linknodes: Arc<Linknodes>,
     changesets: Arc<Changesets>,
     repoid: RepositoryId,
+    max_delta_chain: Option<usize>,
+    // Whether blobs are cached in process, which prefetching fills
+    blob_cache: bool,
+    oplog: Option<Arc<OpLog>>,
+    protected_bookmarks: Option<Arc<ProtectedBookmarks>>,
 }
 
+/// Most blobs cached by `BlobRepo::with_blob_cache`, whatever their size
+const BLOB_CACHE_MAX_ENTRIES: usize = 1_000_000;
+
 impl BlobRepo {
     pub fn new(
         logger: Logger,
         heads: Arc<Heads>,
-        bookmarks: Arc<Bookmarks>,
+        bookmarks: Arc<BookmarksMut>,
+        phases: Arc<Phases>,
+        obsmarkers: Arc<ObsMarkers>,
         blobstore: Arc<Blobstore>,
         linknodes: Arc<Linknodes>,
         changesets: Arc<Changesets>,
@@ -73,20 +108,38 @@ impl BlobRepo {
             logger,
             heads,
             bookmarks,
+            phases,
+            obsmarkers,
             blobstore,
             linknodes,
             changesets,
             repoid,
+            max_delta_chain: None,
+            blob_cache: false,
+            oplog: None,
+            protected_bookmarks: None,
         }
     }
 
-    pub fn new_files(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
+    pub fn new_files(
+        logger: Logger,
+        path: &Path,
+        repoid: RepositoryId,
+        blob_prefix: Option<String>,
+    ) -> Result<Self> {
         let heads = FileHeads::open(path.join("heads"))
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
         let bookmarks = FileBookmarks::open(path.join("books"))
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        // Repos from before phases and obsolescence markers were tracked have nowhere to store
+        // them yet
+        let phases = FilePhases::create(path.join("phases"))
+            .context(ErrorKind::StateOpen(StateOpenError::Phases))?;
+        let obsmarkers = FileObsMarkers::create(path.join("obsmarkers"))
+            .context(ErrorKind::StateOpen(StateOpenError::ObsMarkers))?;
         let blobstore = Fileblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let blobstore = StatsBlobstore::new(blobstore, "fileblob");
         let linknodes = FileLinknodes::open(path.join("linknodes"))
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
@@ -96,20 +149,34 @@ impl BlobRepo {
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
-            Arc::new(blobstore),
+            Arc::new(phases),
+            Arc::new(obsmarkers),
+            with_prefix(blobstore, blob_prefix),
             Arc::new(linknodes),
             Arc::new(changesets),
             repoid,
         ))
     }
 
-    pub fn new_rocksdb(logger: Logger, path: &Path, repoid: RepositoryId) -> Result<Self> {
+    pub fn new_rocksdb(
+        logger: Logger,
+        path: &Path,
+        repoid: RepositoryId,
+        blob_prefix: Option<String>,
+    ) -> Result<Self> {
         let heads = FileHeads::open(path.join("heads"))
             .context(ErrorKind::StateOpen(StateOpenError::Heads))?;
         let bookmarks = FileBookmarks::open(path.join("books"))
             .context(ErrorKind::StateOpen(StateOpenError::Bookmarks))?;
+        // Repos from before phases and obsolescence markers were tracked have nowhere to store
+        // them yet
+        let phases = FilePhases::create(path.join("phases"))
+            .context(ErrorKind::StateOpen(StateOpenError::Phases))?;
+        let obsmarkers = FileObsMarkers::create(path.join("obsmarkers"))
+            .context(ErrorKind::StateOpen(StateOpenError::ObsMarkers))?;
         let blobstore = Rocksblob::open(path.join("blobs"))
             .context(ErrorKind::StateOpen(StateOpenError::Blobstore))?;
+        let blobstore = StatsBlobstore::new(blobstore, "rocksblob");
         let linknodes = FileLinknodes::open(path.join("linknodes"))
             .context(ErrorKind::StateOpen(StateOpenError::Linknodes))?;
         let changesets = SqliteChangesets::open(path.join("changesets").to_string_lossy())
@@ -119,7 +186,9 @@ impl BlobRepo {
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
-            Arc::new(blobstore),
+            Arc::new(phases),
+            Arc::new(obsmarkers),
+            with_prefix(blobstore, blob_prefix),
             Arc::new(linknodes),
             Arc::new(changesets),
             repoid,
@@ -141,6 +210,8 @@ impl BlobRepo {
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(MemPhases::new()),
+            Arc::new(MemObsMarkers::new()),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
@@ -161,6 +232,8 @@ impl BlobRepo {
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(MemPhases::new()),
+            Arc::new(MemObsMarkers::new()),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
@@ -173,6 +246,8 @@ impl BlobRepo {
             logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
             Arc::new(MemHeads::new()),
             Arc::new(MemBookmarks::new()),
+            Arc::new(MemPhases::new()),
+            Arc::new(MemObsMarkers::new()),
             Arc::new(EagerMemblob::new()),
             Arc::new(MemLinknodes::new()),
             Arc::new(SqliteChangesets::in_memory()
@@ -181,6 +256,27 @@ impl BlobRepo {
         ))
     }
 
+    /// As `new_memblob_empty`, but with blobs bounded in size and number, the least recently
+    /// used being evicted. Only suitable for tests which don't read back what they evicted.
+    pub fn new_bounded_memblob_empty(
+        logger: Option<Logger>,
+        max_size: usize,
+        max_entries: usize,
+    ) -> Result<Self> {
+        Ok(Self::new(
+            logger.unwrap_or(Logger::root(Discard {}.ignore_res(), o!())),
+            Arc::new(MemHeads::new()),
+            Arc::new(MemBookmarks::new()),
+            Arc::new(MemPhases::new()),
+            Arc::new(MemObsMarkers::new()),
+            Arc::new(BoundedMemblob::new(max_size, max_entries)),
+            Arc::new(MemLinknodes::new()),
+            Arc::new(SqliteChangesets::in_memory()
+                .context(ErrorKind::StateOpen(StateOpenError::Changesets))?),
+            RepositoryId::new(0),
+        ))
+    }
+
     pub fn new_test_manifold<T: ToString>(
         logger: Logger,
         bucket: T,
@@ -191,6 +287,7 @@ impl BlobRepo {
         let heads = MemHeads::new();
         let bookmarks = MemBookmarks::new();
         let blobstore = ManifoldBlob::new_with_prefix(bucket.to_string(), prefix, remote);
+        let blobstore = StatsBlobstore::new(blobstore, "manifoldblob");
         let linknodes = MemLinknodes::new();
         let changesets = SqliteChangesets::in_memory()
             .context(ErrorKind::StateOpen(StateOpenError::Changesets))?;
@@ -199,6 +296,8 @@ impl BlobRepo {
             logger,
             Arc::new(heads),
             Arc::new(bookmarks),
+            Arc::new(MemPhases::new()),
+            Arc::new(MemObsMarkers::new()),
             Arc::new(blobstore),
             Arc::new(linknodes),
             Arc::new(changesets),
@@ -206,12 +305,144 @@ impl BlobRepo {
         ))
     }
 
+    /// Make every blobstore write through this repo fail, for serving read-only mirrors.
+    /// Only the blobstore is protected: heads, bookmarks and linknodes are left as they are.
+    pub fn into_readonly(self) -> Self {
+        Self {
+            blobstore: Arc::new(ReadOnlyBlobstore::new(self.blobstore)),
+            ..self
+        }
+    }
+
+    /// Store uploaded file contents as deltas against their first parent where that saves space.
+    /// At most `max_chain_len` deltas are applied when reading a file back. Contents are read
+    /// back the same way whether or not this is enabled.
+    pub fn with_delta_storage(self, max_chain_len: usize) -> Self {
+        Self {
+            max_delta_chain: Some(max_chain_len),
+            ..self
+        }
+    }
+
+    /// Keep up to `max_size` bytes of the blobs of this repo in process, so that blobs read over
+    /// and over, and prefetched ones, aren't fetched from the blobstore every time.
+    pub fn with_blob_cache(self, max_size: usize) -> Self {
+        let blobstore = CachingBlobstore::new(self.blobstore, max_size, BLOB_CACHE_MAX_ENTRIES);
+        Self {
+            blobstore: Arc::new(blobstore),
+            blob_cache: true,
+            ..self
+        }
+    }
+
+    /// Write every push and move of bookmarks through this repo to `oplog` before applying it,
+    /// see the oplog module.
+    pub fn with_oplog(self, oplog: Arc<OpLog>) -> Self {
+        Self {
+            oplog: Some(oplog),
+            ..self
+        }
+    }
+
+    /// Keep the heads and bookmarks of this repo in `heads` and `bookmarks` rather than where it
+    /// was opened with, e.g. in a database which all the servers sharing its blobstore use.
+    pub fn with_heads_and_bookmarks(
+        self,
+        heads: Arc<Heads>,
+        bookmarks: Arc<BookmarksMut>,
+    ) -> Self {
+        Self {
+            heads,
+            bookmarks,
+            ..self
+        }
+    }
+
+    /// Refuse to delete the bookmarks of `protected`, or to move them other than forward, to a
+    /// descendant of where they are.
+    pub fn with_protected_bookmarks(self, protected: Arc<ProtectedBookmarks>) -> Self {
+        Self {
+            protected_bookmarks: Some(protected),
+            ..self
+        }
+    }
+
+    /// Fetch the nodes of many files or manifests, and then their contents, each with a single
+    /// request to the blobstore, so that reading them afterwards is served from the blob cache
+    /// rather than by as many requests as there are blobs. Nodes which aren't stored are skipped.
+    /// This does nothing without a blob cache to fill.
+    pub fn prefetch_nodes(&self, nodes: Vec<NodeHash>) -> BoxFuture<(), Error> {
+        if !self.blob_cache || nodes.is_empty() {
+            return future::ok(()).boxify();
+        }
+        let blobstore = self.blobstore.clone();
+        let keys = nodes.into_iter().map(get_node_key).collect();
+        self.blobstore
+            .get_many(keys)
+            .and_then(|blobs| {
+                blobs
+                    .into_iter()
+                    .filter_map(|blob| blob)
+                    .map(|blob| RawNodeBlob::parse(blob.as_ref()).map(|node| node.blob))
+                    .collect::<Result<Vec<_>>>()
+            })
+            .and_then(move |blobs| {
+                let keys = blobs.iter().map(get_content_key).collect();
+                blobstore.get_many(keys).and_then(move |contents| {
+                    // The contents which aren't stored in full are stored as deltas, whose
+                    // chains are fetched, and so cached, one blob at a time
+                    let deltas = blobs
+                        .into_iter()
+                        .zip(contents)
+                        .filter(|&(_, ref content)| content.is_none())
+                        .map(|(blob, _)| fetch_content(&blobstore, blob));
+                    future::join_all(deltas).map(|_| ())
+                })
+            })
+            .boxify()
+    }
+
     pub fn get_file_content(&self, key: &NodeHash) -> BoxFuture<Bytes, Error> {
         fetch_file_content_and_renames_from_blobstore(&self.blobstore, *key)
             .map(|contentrename| contentrename.0)
             .boxify()
     }
 
+    /// The content of a file or manifest as hashed by Mercurial, with the copy metadata of files.
+    pub fn get_raw_content(&self, key: &NodeHash) -> BoxFuture<Bytes, Error> {
+        fetch_raw_content_from_blobstore(&self.blobstore, *key)
+    }
+
+    /// The content of a file given the hex SHA-256 of the content, as LFS names it, if a file
+    /// with that content has been uploaded, or its alias backfilled.
+    pub fn get_file_content_by_sha256(&self, sha256: &str) -> BoxFuture<Option<Bytes>, Error> {
+        let blobstore = self.blobstore.clone();
+        get_sha256_alias(&self.blobstore, sha256)
+            .and_then(move |blob| match blob {
+                Some(blob) => fetch_content(&blobstore, blob),
+                None => future::ok(None).boxify(),
+            })
+            .boxify()
+    }
+
+    /// Alias the content of the file `key` by its SHA-256, for files uploaded before contents
+    /// were aliased on upload.
+    pub fn backfill_sha256_alias(&self, key: &NodeHash) -> BoxFuture<(), Error> {
+        let key = *key;
+        let blobstore = self.blobstore.clone();
+        get_node(&self.blobstore, key)
+            .and_then(move |node| {
+                fetch_content(&blobstore, node.blob).and_then(move |content| {
+                    match content {
+                        Some(content) => store_sha256_alias(&blobstore, node.blob, &content),
+                        None => future::err(ErrorKind::ContentMissing(key, node.blob).into())
+                            .boxify(),
+                    }
+                })
+            })
+            .boxify()
+    }
+
     pub fn get_parents(&self, key: &NodeHash) -> BoxFuture<Parents, Error> {
         get_node(&self.blobstore, *key)
             .map(|rawnode| rawnode.parents)
@@ -224,6 +455,11 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// The revision `node` of the file at `path`, then its ancestors, each once.
+    pub fn get_file_history(&self, path: MPath, node: &NodeHash) -> BoxStream<FileNodeInfo, Error> {
+        file_history_stream(self.clone(), path, *node)
+    }
+
     pub fn get_changesets(&self) -> BoxStream<NodeHash, Error> {
         BlobChangesetStream {
             repo: self.clone(),
@@ -237,6 +473,23 @@ impl BlobRepo {
         self.heads.heads().boxify()
     }
 
+    pub fn is_head(&self, node: &NodeHash) -> BoxFuture<bool, Error> {
+        self.heads.is_head(node)
+    }
+
+    /// Check that the blobstore can be reached, by looking up a key which is never stored.
+    pub fn check_blobstore(&self) -> BoxFuture<(), Error> {
+        self.blobstore
+            .is_present("healthcheck".into())
+            .map(|_| ())
+            .boxify()
+    }
+
+    /// The blobstore of the repo, for replicating it elsewhere.
+    pub fn get_blobstore(&self) -> Arc<Blobstore> {
+        self.blobstore.clone()
+    }
+
     pub fn changeset_exists(&self, changesetid: &ChangesetId) -> BoxFuture<bool, Error> {
         self.changesets
             .get(self.repoid, *changesetid)
@@ -244,6 +497,30 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Whether each of `changesetids` exists, looking them all up at once.
+    pub fn changesets_exist(&self, changesetids: &[ChangesetId]) -> BoxFuture<Vec<bool>, Error> {
+        self.changesets.exist(self.repoid, changesetids)
+    }
+
+    /// The first `limit` changesets, in hash order, whose hash starts with the hex `prefix`. They
+    /// are looked up as a range of the changesets table, without going through the others.
+    pub fn get_changesets_by_prefix(
+        &self,
+        prefix: &str,
+        limit: usize,
+    ) -> BoxFuture<Vec<ChangesetId>, Error> {
+        let bound = |fill| {
+            let mut hex = prefix.to_lowercase();
+            while hex.len() < 40 {
+                hex.push(fill);
+            }
+            NodeHash::from_str(&hex).map(ChangesetId::new)
+        };
+        let min = try_boxfuture!(bound('0'));
+        let max = try_boxfuture!(bound('f'));
+        self.changesets.get_range(self.repoid, min, max, limit)
+    }
+
     pub fn get_changeset_by_changesetid(
         &self,
         changesetid: &ChangesetId,
@@ -254,6 +531,52 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// Convert the changeset `changesetid` to a bonsai changeset, and map the two. Its parents
+    /// have to be converted already.
+    pub fn convert_to_bonsai(
+        &self,
+        changesetid: &ChangesetId,
+    ) -> BoxFuture<BonsaiChangesetId, Error> {
+        convert_to_bonsai(self.blobstore.clone(), *changesetid)
+    }
+
+    pub fn get_bonsai_changeset(
+        &self,
+        bonsai: &BonsaiChangesetId,
+    ) -> BoxFuture<Option<BonsaiChangeset>, Error> {
+        load_bonsai_changeset(&self.blobstore, bonsai)
+    }
+
+    /// The bonsai changeset `changesetid` was converted to, if it has been
+    pub fn get_bonsai_from_hg(
+        &self,
+        changesetid: &ChangesetId,
+    ) -> BoxFuture<Option<BonsaiChangesetId>, Error> {
+        get_bonsai_from_hg(&self.blobstore, changesetid)
+    }
+
+    /// The changeset the bonsai changeset `bonsai` was converted from
+    pub fn get_hg_from_bonsai(
+        &self,
+        bonsai: &BonsaiChangesetId,
+    ) -> BoxFuture<Option<ChangesetId>, Error> {
+        get_hg_from_bonsai(&self.blobstore, bonsai)
+    }
+
+    /// The content of a file given its content id, as bonsai changesets name it
+    pub fn get_file_content_by_content_id(
+        &self,
+        content_id: &ContentId,
+    ) -> BoxFuture<Option<Bytes>, Error> {
+        let blobstore = self.blobstore.clone();
+        get_content_alias(&self.blobstore, content_id)
+            .and_then(move |blob| match blob {
+                Some(blob) => fetch_content(&blobstore, blob),
+                None => future::ok(None).boxify(),
+            })
+            .boxify()
+    }
+
     pub fn get_manifest_by_nodeid(
         &self,
         nodeid: &NodeHash,
@@ -266,6 +589,26 @@ impl BlobRepo {
             .boxify()
     }
 
+    /// The files which differ between the changesets `from` and `to`, walking only the
+    /// directories which differ.
+    pub fn diff_manifests(
+        &self,
+        from: &ChangesetId,
+        to: &ChangesetId,
+    ) -> BoxStream<ManifestDiffEntry, Error> {
+        let repo = self.clone();
+        self.get_changeset_by_changesetid(from)
+            .join(self.get_changeset_by_changesetid(to))
+            .and_then(move |(from, to)| {
+                repo.get_manifest_by_nodeid(&from.manifestid().into_nodehash())
+                    .join(repo.get_manifest_by_nodeid(&to.manifestid().into_nodehash()))
+            })
+            .map(|(from, to)| changed_entry_stream(&to, &from, MPath::empty()))
+            .flatten_stream()
+            .filter_map(ManifestDiffEntry::from_changed_entry)
+            .boxify()
+    }
+
     pub fn get_root_entry(&self, manifestid: &ManifestId) -> Box<Entry + Sync> {
         Box::new(BlobEntry::new_root(self.blobstore.clone(), *manifestid))
     }
@@ -281,10 +624,262 @@ impl BlobRepo {
         self.bookmarks.get(key).boxify()
     }
 
+    /// The moves of a bookmark, most recent first
+    pub fn get_bookmark_history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        self.bookmarks.history(key)
+    }
+
+    /// The moves of all the bookmarks made from now on, as they're made
+    pub fn watch_bookmarks(&self) -> BoxStream<BookmarkEvent, Error> {
+        self.bookmarks.watch()
+    }
+
+    /// Move a bookmark from `old` to `new`, where None means that it doesn't exist, logging the
+    /// move as made by `author` for `reason`. Resolves to false, changing nothing, if the bookmark
+    /// isn't at `old` or moves concurrently.
+    pub fn update_bookmark(
+        &self,
+        key: &AsRef<[u8]>,
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+        author: &str,
+        reason: &str,
+    ) -> BoxFuture<bool, Error> {
+        self.update_bookmarks(vec![(key.as_ref().to_vec(), old, new)], author, reason)
+    }
+
+    /// Move several bookmarks together, each as in `update_bookmark`. Resolves to false, leaving
+    /// them all as they were, if any of them isn't at its `old` value or moves concurrently.
+    pub fn update_bookmarks(
+        &self,
+        updates: Vec<(Vec<u8>, Option<ChangesetId>, Option<ChangesetId>)>,
+        author: &str,
+        reason: &str,
+    ) -> BoxFuture<bool, Error> {
+        let operation = match self.oplog {
+            Some(_) => {
+                let moves = updates
+                    .iter()
+                    .map(|&(ref key, old, new)| -> Result<_> {
+                        let name = String::from_utf8(key.clone())
+                            .map_err(|_| ErrorKind::BookmarkNotUtf8(key.clone()))?;
+                        Ok(BookmarkMove { name, old, new })
+                    })
+                    .collect::<Result<_>>();
+                Some(Operation::Bookmarks {
+                    moves: try_boxfuture!(moves),
+                    reason: reason.to_string(),
+                })
+            }
+            None => None,
+        };
+
+        let bookmarks = self.bookmarks.clone();
+        let current: Vec<_> = updates
+            .iter()
+            .map(|&(ref key, _, _)| self.bookmarks.get(key))
+            .collect();
+        let mut txn = BookmarksTransaction::new();
+        txn.logged_as(author, reason);
+
+        // Checked as part of the operation, so that the oplog records refused moves as failed
+        let protected: Vec<_> = match self.protected_bookmarks {
+            Some(ref protected) => updates
+                .iter()
+                .filter(|&&(ref key, _, _)| protected.is_protected(key))
+                .map(|&(ref key, old, new)| self.check_protected_move(key, old, new))
+                .collect(),
+            None => Vec::new(),
+        };
+
+        let apply = future::join_all(protected)
+            .and_then(move |_| future::join_all(current))
+            .and_then(move |current| {
+                for ((key, old, new), current) in updates.into_iter().zip(current) {
+                    let (current, version) = match current {
+                        Some((csid, version)) => (Some(csid), version),
+                        None => (None, Version::absent()),
+                    };
+                    if current != old {
+                        return future::ok(false).boxify();
+                    }
+                    match new {
+                        Some(new) => txn.set(&key, &new, &version),
+                        None => txn.delete(&key, &version),
+                    };
+                }
+                bookmarks.commit(txn)
+            });
+        match operation {
+            Some(operation) => self.log_operation(author, operation, apply, |moved| *moved),
+            None => apply.boxify(),
+        }
+    }
+
+    // Fail unless moving the protected bookmark `key` from `old` to `new` moves it forward
+    fn check_protected_move(
+        &self,
+        key: &[u8],
+        old: Option<ChangesetId>,
+        new: Option<ChangesetId>,
+    ) -> BoxFuture<(), Error> {
+        let name = String::from_utf8_lossy(key).into_owned();
+        match (old, new) {
+            (_, None) => future::err(ErrorKind::ProtectedBookmark(name, "deleted").into()).boxify(),
+            (None, Some(_)) => future::ok(()).boxify(),
+            (Some(old), Some(new)) => self.is_ancestor(&old, &new)
+                .and_then(move |forward| {
+                    if forward {
+                        Ok(())
+                    } else {
+                        Err(ErrorKind::ProtectedBookmark(name, "moved backward or sideways").into())
+                    }
+                })
+                .boxify(),
+        }
+    }
+
+    /// Whether `ancestor` is `descendant` or one of its ancestors, walking the parents recorded
+    /// in the changesets store. Only changesets with a greater generation number than `ancestor`
+    /// can lead to it, so the walk stops there.
+    pub fn is_ancestor(
+        &self,
+        ancestor: &ChangesetId,
+        descendant: &ChangesetId,
+    ) -> BoxFuture<bool, Error> {
+        if ancestor == descendant {
+            return future::ok(true).boxify();
+        }
+
+        let ancestor = *ancestor;
+        let descendant = *descendant;
+        let repo = self.clone();
+        self.get_generation_number(&ancestor)
+            .and_then(move |gen| gen.ok_or(ErrorKind::ChangesetMissing(ancestor).into()))
+            .and_then(move |ancestor_gen| {
+                let mut seen = HashSet::new();
+                seen.insert(descendant);
+                loop_fn((vec![descendant], seen), move |(mut pending, mut seen)| {
+                    let cs = match pending.pop() {
+                        Some(cs) => cs,
+                        None => return future::ok(Loop::Break(false)).boxify(),
+                    };
+                    repo.get_changeset_entry(&cs)
+                        .and_then(move |entry| {
+                            let entry = entry.ok_or(ErrorKind::ChangesetMissing(cs))?;
+                            if entry.gen <= ancestor_gen {
+                                return Ok(Loop::Continue((pending, seen)));
+                            }
+                            for parent in entry.parents {
+                                if parent == ancestor {
+                                    return Ok(Loop::Break(true));
+                                }
+                                if seen.insert(parent) {
+                                    pending.push(parent);
+                                }
+                            }
+                            Ok(Loop::Continue((pending, seen)))
+                        })
+                        .boxify()
+                })
+            })
+            .boxify()
+    }
+
+    pub fn get_phase(&self, node: &NodeHash) -> BoxFuture<Phase, Error> {
+        self.phases.get(node)
+    }
+
+    /// All draft changesets, in no particular order.
+    pub fn get_draft_changesets(&self) -> BoxStream<NodeHash, Error> {
+        self.phases.drafts()
+    }
+
+    /// Record a changeset which was pushed without being published, like a scratch commit, as
+    /// draft. Public changesets must never be made draft again.
+    pub fn mark_draft(&self, node: &NodeHash) -> BoxFuture<(), Error> {
+        self.phases.set(node, Phase::Draft)
+    }
+
+    /// Make `node` public, along with its draft ancestors. Ancestors are made public first, so
+    /// that the ancestors of a public changeset are public even if this is interrupted.
+    pub fn mark_public(&self, node: &NodeHash) -> BoxFuture<(), Error> {
+        let phases = self.phases.clone();
+        self.get_draft_ancestors(node)
+            .and_then(move |drafts| {
+                stream::iter_ok(drafts.into_iter().rev())
+                    .for_each(move |node| phases.set(&node, Phase::Public))
+            })
+            .boxify()
+    }
+
+    /// Store obsolescence markers, resolving to how many of them weren't already stored.
+    pub fn add_obsmarkers(&self, markers: Vec<ObsMarker>) -> BoxFuture<usize, Error> {
+        self.obsmarkers.add(markers)
+    }
+
+    /// All obsolescence markers, in the order they were stored.
+    pub fn get_obsmarkers(&self) -> BoxStream<ObsMarker, Error> {
+        self.obsmarkers.markers()
+    }
+
+    /// Draft changesets among `node` and its ancestors, descendants before ancestors. Public
+    /// changesets only have public ancestors, so the walk stops at them.
+    fn get_draft_ancestors(&self, node: &NodeHash) -> BoxFuture<Vec<NodeHash>, Error> {
+        let repo = self.clone();
+        loop_fn(
+            (vec![*node], Vec::new(), HashSet::new()),
+            move |(mut queue, mut drafts, mut seen)| {
+                let node = match queue.pop() {
+                    Some(node) => node,
+                    None => return future::ok(Loop::Break(drafts)).boxify(),
+                };
+                if !seen.insert(node) {
+                    return future::ok(Loop::Continue((queue, drafts, seen))).boxify();
+                }
+
+                let repo = repo.clone();
+                repo.get_phase(&node)
+                    .and_then(move |phase| match phase {
+                        Phase::Public => future::ok(None).boxify(),
+                        Phase::Draft => repo.get_changeset_parents(node).map(Some).boxify(),
+                    })
+                    .map(move |parents| {
+                        if let Some(parents) = parents {
+                            queue.extend(parents);
+                            drafts.push(node);
+                        }
+                        Loop::Continue((queue, drafts, seen))
+                    })
+                    .boxify()
+            },
+        ).boxify()
+    }
+
+    fn get_changeset_parents(&self, node: NodeHash) -> BoxFuture<Vec<NodeHash>, Error> {
+        let csid = ChangesetId::new(node);
+        self.changesets
+            .get(self.repoid, csid)
+            .and_then(move |entry| {
+                let entry = entry.ok_or(ErrorKind::ChangesetMissing(csid))?;
+                Ok(entry.parents.into_iter().map(|p| p.into_nodehash()).collect())
+            })
+            .boxify()
+    }
+
     pub fn get_linknode(&self, path: RepoPath, node: &NodeHash) -> BoxFuture<NodeHash, Error> {
         self.linknodes.get(path, node)
     }
 
+    /// The parents and generation number of a changeset, as recorded when it was completed.
+    /// This is much cheaper than fetching the changeset.
+    pub fn get_changeset_entry(
+        &self,
+        cs: &ChangesetId,
+    ) -> BoxFuture<Option<ChangesetEntry>, Error> {
+        self.changesets.get(self.repoid, *cs)
+    }
+
     pub fn get_generation_number(&self, cs: &ChangesetId) -> BoxFuture<Option<u64>, Error> {
         self.changesets
             .get(self.repoid, *cs)
@@ -312,6 +907,24 @@ impl BlobRepo {
         let raw_content = raw_content.clean();
         let parents = Parents::new(p1, p2);
 
+        let nodeid = BlobNode::new(raw_content.clone(), p1, p2)
+            .nodeid()
+            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
+
+        // Where a file was copied from is kept in its node, and its content stored without it
+        let (raw_content, copy_from) = if content_type == manifest::Type::Tree {
+            (raw_content, None)
+        } else {
+            let file = File::new(BlobNode::new(raw_content.clone(), p1, p2));
+            match file.split_copied_from()? {
+                Some((copy_from, content)) => (
+                    Blob::from(Bytes::from(content)).clean(),
+                    Some(copy_from),
+                ),
+                None => (raw_content, None),
+            }
+        };
+
         let blob_hash = raw_content
             .hash()
             .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
@@ -319,12 +932,9 @@ impl BlobRepo {
         let raw_node = RawNodeBlob {
             parents,
             blob: blob_hash,
+            copy_from,
         };
 
-        let nodeid = BlobNode::new(raw_content.clone(), p1, p2)
-            .nodeid()
-            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
-
         let blob_entry = BlobEntry::new(
             self.blobstore.clone(),
             path.mpath()
@@ -353,37 +963,63 @@ impl BlobRepo {
             );
         }
 
-        // Ensure that content is in the blobstore
-        let content_upload = self.blobstore
-            .put(
-                format!("sha1-{}", blob_hash.sha1()),
-                raw_content
-                    .clone()
-                    .into_inner()
-                    .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?,
-            )
-            .timed({
+        // Ensure that content is in the blobstore. Only files are delta-compressed, and aliased.
+        let is_file = content_type != manifest::Type::Tree;
+        let delta_base = if content_type == manifest::Type::Tree {
+            None
+        } else {
+            p1.cloned()
+        };
+        let content = raw_content
+            .clone()
+            .into_inner()
+            .ok_or_else(|| Error::from(ErrorKind::BadUploadBlob(raw_content.clone())))?;
+        let node_blob: Bytes = bincode::serialize(&raw_node)
+            .map_err(|err| Error::from(ErrorKind::SerializationFailed(nodeid, err)))?
+            .into();
+
+        // The node is only put once its content is stored, so that a node left by an earlier
+        // attempt at the same upload (e.g. a push which failed part way and is retried) means
+        // there's nothing left to upload, and content already stored isn't uploaded again.
+        // Content stored as a delta counts as present.
+        let node_key = get_node_key(nodeid);
+        let upload = self.blobstore
+            .is_present(node_key.clone())
+            .join(get_content_keys(self.blobstore.clone(), blob_hash).map(|keys| keys.is_some()))
+            .and_then({
+                let blobstore = self.blobstore.clone();
+                let max_delta_chain = self.max_delta_chain;
                 let logger = self.logger.clone();
                 let path = path.clone();
-                let nodeid = nodeid.clone();
-                move |stats, result| {
-                    if result.is_ok() {
-                        log_upload_stats(logger, path, nodeid, "content_uploaded", stats)
+                move |(node_present, content_present)| {
+                    if node_present {
+                        return future::ok(()).boxify();
                     }
+                    let content_upload = if content_present {
+                        future::ok(()).boxify()
+                    } else if is_file {
+                        let alias = store_sha256_alias(&blobstore, blob_hash, &content);
+                        store_content(&blobstore, blob_hash, content, delta_base, max_delta_chain)
+                            .join(alias)
+                            .map(|_| ())
+                            .boxify()
+                    } else {
+                        store_content(&blobstore, blob_hash, content, delta_base, max_delta_chain)
+                    };
+                    content_upload
+                        .timed(move |stats, result| {
+                            if result.is_ok() {
+                                log_upload_stats(logger, path, nodeid, "content_uploaded", stats)
+                            }
+                        })
+                        .and_then(move |()| blobstore.put(node_key, node_blob))
+                        .boxify()
                 }
             });
-        // Upload the new node
-        let node_upload = self.blobstore.put(
-            get_node_key(nodeid),
-            bincode::serialize(&raw_node)
-                .map_err(|err| Error::from(ErrorKind::SerializationFailed(nodeid, err)))?
-                .into(),
-        );
 
         Ok((
             nodeid,
-            content_upload
-                .join(node_upload)
+            upload
                 .map({
                     let path = path.clone();
                     |_| (blob_entry, path)
@@ -402,6 +1038,73 @@ impl BlobRepo {
         ))
     }
 
+    /// Record the blobs a push is about to upload, resolving to the id of the record. Recording
+    /// the same uploads again, as a retry of a failed push does, gives the same id. Fails while
+    /// a garbage collection is deleting blobs, as the push could rely on some of them.
+    pub fn record_push_uploads(&self, uploads: &PushUploads) -> BoxFuture<String, Error> {
+        record_push_uploads(&self.blobstore, uploads)
+    }
+
+    /// Mark the push recorded under `id` as complete, its blobs no longer being orphan candidates
+    pub fn complete_push_uploads(&self, id: &str) -> BoxFuture<(), Error> {
+        complete_push_uploads(&self.blobstore, id)
+    }
+
+    /// The uploads recorded under `id`, with whether their push completed
+    pub fn get_push_uploads(&self, id: &str) -> BoxFuture<Option<(PushUploads, bool)>, Error> {
+        get_push_uploads(&self.blobstore, id)
+    }
+
+    /// Run `upload`, which uploads the `changesets` of a push, whose blobs are recorded under the
+    /// push uploads record `uploads`. The push is written to the operation log of the repo
+    /// first, if it has one.
+    pub fn log_push<F>(
+        &self,
+        author: &str,
+        changesets: Vec<NodeHash>,
+        uploads: String,
+        upload: F,
+    ) -> BoxFuture<(), Error>
+    where
+        F: Future<Item = (), Error = Error> + Send + 'static,
+    {
+        let operation = Operation::Push {
+            changesets,
+            uploads,
+        };
+        self.log_operation(author, operation, upload, |&()| true)
+    }
+
+    // Write `operation` to the operation log, if any, before running `apply`, see `OpLog::run`
+    fn log_operation<F>(
+        &self,
+        author: &str,
+        operation: Operation,
+        apply: F,
+        applied: fn(&F::Item) -> bool,
+    ) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        match self.oplog {
+            Some(ref oplog) => oplog.run(&self.logger, author, operation, apply, applied),
+            None => apply.boxify(),
+        }
+    }
+
+    /// Create a changeset from the changes to the files of its first parent, building and
+    /// uploading its manifests and filenodes, for writers which don't have them already. Returns
+    /// the id of the changeset once it's complete, and a head.
+    pub fn create_changeset_from_changes(
+        &self,
+        parents: Vec<ChangesetId>,
+        changes: BTreeMap<MPath, FileChange>,
+        metadata: ChangesetMetadata,
+    ) -> BoxFuture<ChangesetId, Error> {
+        create_changeset_from_changes(self.clone(), parents, changes, metadata)
+    }
+
     /// Create a changeset in this repo. This will upload all the blobs to the underlying Blobstore
     /// and ensure that the changeset is marked as "complete".
     /// No attempt is made to clean up the Blobstore if the changeset creation fails
@@ -441,6 +1144,7 @@ impl BlobRepo {
                         p2_manifest.as_ref(),
                     ).and_then({
                         move |files| {
+                            let parent_nodes: Vec<_> = parents.into_iter().collect();
                             let blobcs = try_boxfuture!(make_new_changeset(
                                 parents,
                                 root_hash,
@@ -456,8 +1160,8 @@ impl BlobRepo {
 
                             blobcs
                                 .save(blobstore)
-                                .join(heads.add(&cs_id))
                                 .join(entry_processor.finalize(linknodes, cs_id))
+                                .and_then(move |_| heads.replace(parent_nodes, vec![cs_id]))
                                 .map(move |_| {
                                     // We deliberately eat this error - this is only so that
                                     // another changeset can start uploading to the blob store
@@ -499,16 +1203,30 @@ impl BlobRepo {
     }
 }
 
+// Namespace the keys of a blobstore, so that several repos can share it
+fn with_prefix<B: Blobstore>(blobstore: B, prefix: Option<String>) -> Arc<Blobstore> {
+    match prefix {
+        Some(prefix) => Arc::new(PrefixBlobstore::new(blobstore, prefix)),
+        None => Arc::new(blobstore),
+    }
+}
+
 impl Clone for BlobRepo {
     fn clone(&self) -> Self {
         Self {
             logger: self.logger.clone(),
             heads: self.heads.clone(),
             bookmarks: self.bookmarks.clone(),
+            phases: self.phases.clone(),
+            obsmarkers: self.obsmarkers.clone(),
             blobstore: self.blobstore.clone(),
             linknodes: self.linknodes.clone(),
             changesets: self.changesets.clone(),
             repoid: self.repoid.clone(),
+            max_delta_chain: self.max_delta_chain,
+            blob_cache: self.blob_cache,
+            oplog: self.oplog.clone(),
+            protected_bookmarks: self.protected_bookmarks.clone(),
         }
     }
 }
@@ -0,0 +1,160 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Records of the blobs uploaded by pushes.
+//!
+//! A push records what it's about to upload before uploading anything, and marks the record done
+//! once all of its changesets are complete. A record never marked done belongs either to a push in
+//! flight, which garbage collection must not sweep under, or to a push which failed part way and
+//! left orphans for it to collect: see `gc::find_pushes_in_flight`.
+//!
+//! Recording a push fails while a sweep is in progress. Uploads skip blobs which are already
+//! stored, and a push started during a sweep could rely on blobs which are about to be deleted.
+
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bincode;
+use bytes::Bytes;
+use futures::future::Future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+use mercurial_types::NodeHash;
+use mercurial_types::hash::Context;
+
+use errors::*;
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct PushUploads {
+    pub changesets: Vec<NodeHash>,
+    /// The nodes of the Filelogs and Manifests uploaded along with the changesets
+    pub entries: Vec<NodeHash>,
+}
+
+impl PushUploads {
+    pub fn new(changesets: Vec<NodeHash>, mut entries: Vec<NodeHash>) -> Self {
+        entries.sort();
+        entries.dedup();
+        PushUploads {
+            changesets,
+            entries,
+        }
+    }
+
+    /// The id of the record, which is derived from its content, so that a retried push records
+    /// the same one again rather than leaving a record per attempt
+    pub fn id(&self) -> String {
+        let mut ctxt = Context::new();
+        for node in self.changesets.iter().chain(self.entries.iter()) {
+            ctxt.update(node.sha1());
+        }
+        format!("{}", ctxt.finish())
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct RawPushUploads {
+    uploads: PushUploads,
+    /// When the push was recorded, in seconds since the epoch
+    time: u64,
+}
+
+/// The key a garbage collection puts while it deletes blobs, see `gc::begin_sweep`
+pub const SWEEP_KEY: &str = "gc-sweep";
+
+const PUSH_UPLOADS_PREFIX: &str = "pushuploads-";
+const PUSH_UPLOADS_SUFFIX: &str = ".bincode";
+
+fn get_push_uploads_key(id: &str) -> String {
+    format!("{}{}{}", PUSH_UPLOADS_PREFIX, id, PUSH_UPLOADS_SUFFIX)
+}
+
+/// The id of the push uploads record stored under `key`, if that's what it is
+pub fn parse_push_uploads_key(key: &str) -> Option<&str> {
+    if key.starts_with(PUSH_UPLOADS_PREFIX) && key.ends_with(PUSH_UPLOADS_SUFFIX) {
+        Some(&key[PUSH_UPLOADS_PREFIX.len()..key.len() - PUSH_UPLOADS_SUFFIX.len()])
+    } else {
+        None
+    }
+}
+
+// Blobs are never overwritten, so the completion of a push is recorded under a key of its own
+fn get_push_uploads_done_key(id: &str) -> String {
+    format!("pushuploads-{}.done", id)
+}
+
+/// Store `uploads`, resolving to its id. A retried push records the same id again, with a new
+/// time. Fails if a garbage collection is sweeping.
+pub fn record_push_uploads(
+    blobstore: &Arc<Blobstore>,
+    uploads: &PushUploads,
+) -> BoxFuture<String, Error> {
+    let id = uploads.id();
+    let time = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs())
+        .unwrap_or(0);
+    let raw = RawPushUploads {
+        uploads: uploads.clone(),
+        time,
+    };
+    let blob = try_boxfuture!(bincode::serialize(&raw).map_err(Error::from));
+    let blobstore = blobstore.clone();
+    // The record is put before the sweep key is checked, and a sweep puts its key before looking
+    // for pushes in flight, so that either the sweep sees this push, or this push sees the sweep
+    blobstore
+        .put(get_push_uploads_key(&id), Bytes::from(blob))
+        .and_then(move |()| blobstore.is_present(SWEEP_KEY.to_string()))
+        .and_then(move |sweeping| {
+            if sweeping {
+                Err(ErrorKind::SweepInProgress.into())
+            } else {
+                Ok(id)
+            }
+        })
+        .boxify()
+}
+
+pub fn complete_push_uploads(blobstore: &Blobstore, id: &str) -> BoxFuture<(), Error> {
+    blobstore.put(get_push_uploads_done_key(id), Bytes::new())
+}
+
+fn get_raw_push_uploads(
+    blobstore: &Blobstore,
+    id: &str,
+) -> BoxFuture<Option<(RawPushUploads, bool)>, Error> {
+    blobstore
+        .get(get_push_uploads_key(id))
+        .join(blobstore.is_present(get_push_uploads_done_key(id)))
+        .and_then(|(blob, done)| match blob {
+            Some(blob) => Ok(Some((bincode::deserialize(blob.as_ref())?, done))),
+            None => Ok(None),
+        })
+        .boxify()
+}
+
+/// The record stored under `id`, with whether its push completed
+pub fn get_push_uploads(
+    blobstore: &Blobstore,
+    id: &str,
+) -> BoxFuture<Option<(PushUploads, bool)>, Error> {
+    get_raw_push_uploads(blobstore, id)
+        .map(|raw| raw.map(|(raw, done)| (raw.uploads, done)))
+        .boxify()
+}
+
+/// When the push recorded under `id` was last recorded, in seconds since the epoch, with whether
+/// it completed
+pub fn get_push_uploads_status(
+    blobstore: &Blobstore,
+    id: &str,
+) -> BoxFuture<Option<(u64, bool)>, Error> {
+    get_raw_push_uploads(blobstore, id)
+        .map(|raw| raw.map(|(raw, done)| (raw.time, done)))
+        .boxify()
+}
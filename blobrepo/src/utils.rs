@@ -4,21 +4,59 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use futures::future::{Future, IntoFuture};
+use std::collections::{HashMap, HashSet};
+
+use futures::future::Future;
 use futures_ext::{BoxFuture, FutureExt};
 
 use bincode;
+use bytes::Bytes;
 
 use blobstore::Blobstore;
-use mercurial_types::{BlobHash, NodeHash, Parents};
+use mercurial::file::File;
+use mercurial_types::{BlobHash, MPath, NodeHash, Parents};
 
 use errors::*;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct RawNodeBlob {
     pub parents: Parents,
     pub blob: BlobHash,
+    /// Where a file was copied from. The content of such a file is stored without the metadata
+    /// recording it, which `raw_text` adds back.
+    pub copy_from: Option<(MPath, NodeHash)>,
+}
+
+// Nodes stored before the copies of files were kept apart from their content
+#[derive(Deserialize)]
+struct LegacyRawNodeBlob {
+    parents: Parents,
+    blob: BlobHash,
+}
+
+impl RawNodeBlob {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        match bincode::deserialize(bytes) {
+            Ok(node) => Ok(node),
+            Err(_) => {
+                let legacy: LegacyRawNodeBlob = bincode::deserialize(bytes)?;
+                Ok(RawNodeBlob {
+                    parents: legacy.parents,
+                    blob: legacy.blob,
+                    copy_from: None,
+                })
+            }
+        }
+    }
+
+    /// The text Mercurial hashes for a node with this content.
+    pub fn raw_text(&self, content: Bytes) -> Bytes {
+        match self.copy_from {
+            Some(ref copy_from) => Bytes::from(File::generate_copied_from(copy_from, &content)),
+            None => content,
+        }
+    }
 }
 
 pub fn get_node_key(nodeid: NodeHash) -> String {
@@ -31,6 +69,37 @@ pub fn get_node(blobstore: &Blobstore, nodeid: NodeHash) -> BoxFuture<RawNodeBlo
     blobstore
         .get(key)
         .and_then(move |got| got.ok_or(ErrorKind::NodeMissing(nodeid).into()))
-        .and_then(move |blob| bincode::deserialize(blob.as_ref()).into_future().from_err())
+        .and_then(move |blob| RawNodeBlob::parse(blob.as_ref()))
         .boxify()
 }
+
+/// Order `changesets` so that parents always come before their children. Parents which aren't
+/// in `changesets` are ignored.
+pub fn topological_order(changesets: &HashMap<NodeHash, Vec<NodeHash>>) -> Vec<NodeHash> {
+    let mut nodes: Vec<_> = changesets.keys().cloned().collect();
+    // Any order would do, but sorting keeps it deterministic
+    nodes.sort();
+
+    let mut order = Vec::with_capacity(changesets.len());
+    let mut visited = HashSet::new();
+    for node in nodes {
+        // Depth first, emitting a changeset once all its parents have been emitted
+        let mut stack = vec![(node, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            for parent in changesets[&node].iter().rev() {
+                if changesets.contains_key(parent) && !visited.contains(parent) {
+                    stack.push((*parent, false));
+                }
+            }
+        }
+    }
+    order
+}
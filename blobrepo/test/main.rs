@@ -8,6 +8,7 @@
 
 extern crate ascii;
 extern crate bytes;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
@@ -17,20 +18,40 @@ extern crate maplit;
 extern crate slog;
 
 extern crate blobrepo;
+extern crate blobstore;
 extern crate changesets;
+extern crate linear;
 extern crate many_files_dirs;
 extern crate memblob;
 extern crate membookmarks;
 extern crate memheads;
 extern crate memlinknodes;
 extern crate mercurial_types;
+extern crate mononoke_types;
+extern crate tempdir;
 
-use bytes::Bytes;
-use futures::Future;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
-use blobrepo::{compute_changed_files, BlobRepo};
-use mercurial_types::{manifest, Blob, Changeset, ChangesetId, Entry, EntryId, MPath, MPathElement,
-                      ManifestId, RepoPath};
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+
+use blobrepo::{begin_sweep, compute_changed_files, end_sweep, find_missing_changesets,
+               find_pushes_in_flight, find_unreachable, mark_reachable, plan_sweep, read_oplog,
+               recheck_unreachable, replicate_changesets, sweep, BlobChangeset, BlobRepo, BookmarkMove,
+               ChangesetMetadata, FileChange, FileNodeInfo, ManifestDiffEntry, OpLog, Operation,
+               Outcome, ProtectedBookmarks, PushUploads};
+use blobstore::Blobstore;
+use changesets::SqliteChangesets;
+use memblob::EagerMemblob;
+use membookmarks::MemBookmarks;
+use memheads::MemHeads;
+use memlinknodes::MemLinknodes;
+use mercurial_types::{manifest, Blob, BlobHash, Changeset, ChangesetId, Entry, EntryId, MPath,
+                      MPathElement, Manifest, ManifestId, Parents, RepoPath, RepositoryId, Time};
+use mononoke_types::{ContentId, FileType};
+use tempdir::TempDir;
 
 mod stats_units;
 #[macro_use]
@@ -298,6 +319,59 @@ test_both_repotypes!(
     create_double_linknode_eager
 );
 
+fn file_history(repo: BlobRepo) {
+    let fake_file_path = RepoPath::file("file").expect("Can't generate fake RepoPath");
+
+    let (p1, parent_commit) = {
+        let (filehash, file_future) = upload_file_no_parents(&repo, "blob", &fake_file_path);
+        let (_, root_manifest_future) =
+            upload_manifest_no_parents(&repo, format!("file\0{}\n", filehash), &RepoPath::root());
+        (
+            filehash,
+            create_changeset_no_parents(&repo, root_manifest_future, vec![file_future]),
+        )
+    };
+
+    let (filehash, child_commit) = {
+        let (filehash, file_future) = upload_file_one_parent(&repo, "blob2", &fake_file_path, p1);
+        let (_, root_manifest_future) =
+            upload_manifest_no_parents(&repo, format!("file\0{}\n", filehash), &RepoPath::root());
+        (
+            filehash,
+            create_changeset_one_parent(
+                &repo,
+                root_manifest_future,
+                vec![file_future],
+                parent_commit.clone(),
+            ),
+        )
+    };
+    let child = run_future(child_commit.get_completed_changeset()).unwrap();
+    let parent = run_future(parent_commit.get_completed_changeset()).unwrap();
+
+    let history = run_future(
+        repo.get_file_history(MPath::new("file").unwrap(), &filehash)
+            .collect(),
+    ).unwrap();
+    let expected = vec![
+        FileNodeInfo {
+            node: filehash,
+            parents: Parents::One(p1),
+            linknode: child.get_changeset_id().into_nodehash(),
+            copy_from: None,
+        },
+        FileNodeInfo {
+            node: p1,
+            parents: Parents::None,
+            linknode: parent.get_changeset_id().into_nodehash(),
+            copy_from: None,
+        },
+    ];
+    assert_eq!(history, expected);
+}
+
+test_both_repotypes!(file_history, file_history_lazy, file_history_eager);
+
 fn check_linknode_creation(repo: BlobRepo) {
     let fake_dir_path = RepoPath::dir("dir").expect("Can't generate fake RepoPath");
     let author: String = "author <author@fb.com>".into();
@@ -356,6 +430,299 @@ test_both_repotypes!(
     check_linknode_creation_eager
 );
 
+#[test]
+fn delta_storage() {
+    let blobs = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        blobs.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    ).with_delta_storage(2);
+    let fake_path = RepoPath::file("fake/file").expect("Can't generate fake RepoPath");
+
+    let base: String = (0..200).map(|i| format!("line {}\n", i)).collect();
+    let versions: Vec<String> = (0..4)
+        .map(|v| format!("{}edited in version {}\n", base, v))
+        .collect();
+
+    let mut p1 = None;
+    let mut hashes = Vec::new();
+    for version in &versions {
+        let (hash, future) = match p1 {
+            None => upload_file_no_parents(&repo, version.clone(), &fake_path),
+            Some(p1) => upload_file_one_parent(&repo, version.clone(), &fake_path, p1),
+        };
+        run_future(future).unwrap();
+        p1 = Some(hash);
+        hashes.push(hash);
+    }
+
+    // The first version has no parent and the last one would exceed the max chain length, so
+    // those two are stored in full and the others as deltas
+    let stored_as = |version: &String| {
+        let sha1 = BlobHash::from(version.as_bytes()).sha1().clone();
+        let fulltext = run_future(blobs.get(format!("sha1-{}", sha1))).unwrap();
+        let delta = run_future(blobs.get(format!("delta-sha1-{}", sha1))).unwrap();
+        (fulltext.is_some(), delta.is_some())
+    };
+    assert_eq!(stored_as(&versions[0]), (true, false));
+    assert_eq!(stored_as(&versions[1]), (false, true));
+    assert_eq!(stored_as(&versions[2]), (false, true));
+    assert_eq!(stored_as(&versions[3]), (true, false));
+
+    // Another node with content already stored as a delta doesn't store it again
+    let (hash, future) = upload_file_no_parents(&repo, versions[1].clone(), &fake_path);
+    run_future(future).unwrap();
+    assert_eq!(stored_as(&versions[1]), (false, true));
+    let bytes = run_future(repo.get_file_content(&hash)).unwrap();
+    assert!(&bytes == versions[1].as_bytes());
+
+    for (hash, version) in hashes.iter().zip(versions.iter()) {
+        let bytes = run_future(repo.get_file_content(hash)).unwrap();
+        assert!(&bytes == version.as_bytes());
+    }
+}
+
+#[test]
+fn copied_file() {
+    let blobs = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        blobs.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let fake_path = RepoPath::file("fake/copy").expect("Can't generate fake RepoPath");
+    let copied_from = (
+        MPath::new("fake/file").unwrap(),
+        string_to_nodehash("c3127cdbf2eae0f09653f9237d85c8436425b246"),
+    );
+    let text = "\x01\ncopy: fake/file\n\
+                copyrev: c3127cdbf2eae0f09653f9237d85c8436425b246\n\x01\nblob";
+
+    let (hash, future) = upload_file_no_parents(&repo, text, &fake_path);
+    run_future(future).unwrap();
+
+    // The content is stored without the metadata...
+    let sha1 = BlobHash::from(&b"blob"[..]).sha1().clone();
+    assert!(run_future(blobs.get(format!("sha1-{}", sha1))).unwrap().is_some());
+
+    // ... which is added back to the raw content
+    let copy = run_future(repo.get_file_copy(&hash)).unwrap();
+    assert_eq!(copy, Some(copied_from));
+    let bytes = run_future(repo.get_file_content(&hash)).unwrap();
+    assert!(&bytes == &b"blob"[..]);
+    let bytes = run_future(repo.get_raw_content(&hash)).unwrap();
+    assert!(&bytes == text.as_bytes());
+}
+
+#[test]
+fn prefetch_nodes() {
+    let repo = get_empty_eager_repo().with_blob_cache(1024 * 1024);
+    let fake_path = RepoPath::file("fake/file").expect("Can't generate fake RepoPath");
+    let (hash, future) = upload_file_no_parents(&repo, "blob", &fake_path);
+    run_future(future).unwrap();
+
+    // Nodes which aren't stored are skipped
+    let missing = string_to_nodehash("1111111111111111111111111111111111111111");
+    run_future(repo.prefetch_nodes(vec![hash, missing])).unwrap();
+    let bytes = run_future(repo.get_file_content(&hash)).unwrap();
+    assert!(&bytes == &b"blob"[..]);
+}
+
+#[test]
+fn sha256_alias() {
+    let repo = get_empty_eager_repo();
+    let fake_path = RepoPath::file("fake/file").expect("Can't generate fake RepoPath");
+    let sha256 = "fa2c8cc4f28176bbeed4b736df569a34c79cd3723e9ec42f9674b4d46ac6b8b8";
+
+    assert_eq!(
+        run_future(repo.get_file_content_by_sha256(sha256)).unwrap(),
+        None
+    );
+
+    let (hash, future) = upload_file_no_parents(&repo, "blob", &fake_path);
+    run_future(future).unwrap();
+    assert_eq!(
+        run_future(repo.get_file_content_by_sha256(sha256)).unwrap(),
+        Some(Bytes::from(&b"blob"[..]))
+    );
+
+    // Backfilling an alias which exists already changes nothing
+    run_future(repo.backfill_sha256_alias(&hash)).unwrap();
+    assert_eq!(
+        run_future(repo.get_file_content_by_sha256(sha256)).unwrap(),
+        Some(Bytes::from(&b"blob"[..]))
+    );
+}
+
+#[test]
+fn update_bookmarks() {
+    let repo = get_empty_eager_repo();
+    let one = ChangesetId::new(string_to_nodehash("1111111111111111111111111111111111111111"));
+    let two = ChangesetId::new(string_to_nodehash("2222222222222222222222222222222222222222"));
+    let get = |key| run_future(repo.get_bookmark_value(&key)).unwrap().map(|(cs, _)| cs);
+    assert!(run_future(repo.update_bookmark(&"a", None, Some(one), "alice", "test")).unwrap());
+
+    // "b" doesn't exist, so neither bookmark moves
+    let updates = vec![
+        (b"a".to_vec(), Some(one), Some(two)),
+        (b"b".to_vec(), Some(one), Some(two)),
+    ];
+    assert!(!run_future(repo.update_bookmarks(updates, "alice", "test")).unwrap());
+    assert_eq!(get("a"), Some(one));
+    assert_eq!(get("b"), None);
+
+    let updates = vec![
+        (b"a".to_vec(), Some(one), None),
+        (b"b".to_vec(), None, Some(two)),
+    ];
+    assert!(run_future(repo.update_bookmarks(updates, "bob", "push")).unwrap());
+    assert_eq!(get("a"), None);
+    assert_eq!(get("b"), Some(two));
+
+    let history: Vec<_> = run_future(repo.get_bookmark_history(&"a").collect())
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.from, entry.to, entry.author))
+        .collect();
+    assert_eq!(
+        history,
+        vec![
+            (Some(one), None, "bob".to_string()),
+            (None, Some(one), "alice".to_string()),
+        ]
+    );
+}
+
+#[test]
+fn push_uploads() {
+    let repo = get_empty_eager_repo();
+    let uploads = PushUploads::new(
+        vec![string_to_nodehash("1111111111111111111111111111111111111111")],
+        vec![
+            string_to_nodehash("3333333333333333333333333333333333333333"),
+            string_to_nodehash("2222222222222222222222222222222222222222"),
+            string_to_nodehash("3333333333333333333333333333333333333333"),
+        ],
+    );
+    assert_eq!(uploads.entries.len(), 2);
+
+    let id = run_future(repo.record_push_uploads(&uploads)).unwrap();
+    assert_eq!(
+        run_future(repo.get_push_uploads(&id)).unwrap(),
+        Some((uploads.clone(), false))
+    );
+
+    // Recording the same uploads again, as a retried push does, reuses the record
+    assert_eq!(run_future(repo.record_push_uploads(&uploads)).unwrap(), id);
+
+    run_future(repo.complete_push_uploads(&id)).unwrap();
+    assert_eq!(
+        run_future(repo.get_push_uploads(&id)).unwrap(),
+        Some((uploads, true))
+    );
+    assert_eq!(run_future(repo.get_push_uploads("missing")).unwrap(), None);
+}
+
+#[test]
+fn oplog() {
+    let dir = TempDir::new("oplog").unwrap();
+    let path = dir.path().join("oplog");
+    let repo = get_empty_eager_repo().with_oplog(Arc::new(OpLog::open(&path).unwrap()));
+    let one = ChangesetId::new(string_to_nodehash("1111111111111111111111111111111111111111"));
+    let two = ChangesetId::new(string_to_nodehash("2222222222222222222222222222222222222222"));
+
+    let pushed = vec![one.into_nodehash()];
+    run_future(repo.log_push("alice", pushed, "id".into(), future::ok(()))).unwrap();
+    assert!(run_future(repo.update_bookmark(&"a", None, Some(one), "alice", "push")).unwrap());
+    // "a" isn't at two, so this is rejected, but still logged
+    assert!(!run_future(repo.update_bookmark(&"a", Some(two), None, "bob", "pushkey")).unwrap());
+
+    // Numbering carries on where the log stopped
+    let reopened = get_empty_eager_repo().with_oplog(Arc::new(OpLog::open(&path).unwrap()));
+    let failed = future::err(format_err!("upload failed"));
+    assert!(run_future(reopened.log_push("bob", vec![], "id".into(), failed)).is_err());
+
+    let logged: Vec<_> = read_oplog(&path)
+        .unwrap()
+        .into_iter()
+        .map(|op| (op.seq, op.author, op.operation, op.outcome))
+        .collect();
+    let push = |changesets| Operation::Push {
+        changesets,
+        uploads: "id".into(),
+    };
+    let bookmark = |old, new, reason: &str| Operation::Bookmarks {
+        moves: vec![
+            BookmarkMove {
+                name: "a".into(),
+                old,
+                new,
+            },
+        ],
+        reason: reason.into(),
+    };
+    assert_eq!(
+        logged,
+        vec![
+            (0, "alice".into(), push(vec![one.into_nodehash()]), Some(Outcome::Applied)),
+            (1, "alice".into(), bookmark(None, Some(one), "push"), Some(Outcome::Applied)),
+            (2, "bob".into(), bookmark(Some(two), None, "pushkey"), Some(Outcome::Rejected)),
+            (
+                3,
+                "bob".into(),
+                push(vec![]),
+                Some(Outcome::Failed("upload failed".into())),
+            ),
+        ]
+    );
+}
+
+#[test]
+fn protected_bookmarks() {
+    let protected = Arc::new(ProtectedBookmarks::new(vec!["master"]));
+    let repo = linear::getrepo(None).with_protected_bookmarks(protected.clone());
+    let head = ChangesetId::new(string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"));
+    let middle = ChangesetId::new(string_to_nodehash("cb15ca4a43a59acff5388cea9648c162afde8372"));
+    assert!(run_future(repo.is_ancestor(&middle, &head)).unwrap());
+    assert!(!run_future(repo.is_ancestor(&head, &middle)).unwrap());
+
+    let update = |key, old, new| run_future(repo.update_bookmark(&key, old, new, "alice", "test"));
+    assert!(update("master", None, Some(middle)).unwrap());
+    assert!(update("master", Some(middle), Some(head)).unwrap());
+    // Neither moved backward nor deleted
+    assert!(update("master", Some(head), Some(middle)).is_err());
+    assert!(update("master", Some(head), None).is_err());
+    assert!(update("other", None, Some(head)).unwrap());
+    assert!(update("other", Some(head), None).unwrap());
+
+    protected.replace(Vec::<String>::new());
+    assert!(update("master", Some(head), None).unwrap());
+}
+
+#[test]
+fn get_changesets_by_prefix() {
+    let repo = linear::getrepo(None);
+    let head = ChangesetId::new(string_to_nodehash("a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157"));
+    let by_prefix =
+        |prefix: &str, limit| run_future(repo.get_changesets_by_prefix(prefix, limit));
+
+    assert_eq!(by_prefix("a9473", 2).unwrap(), vec![head]);
+    assert_eq!(by_prefix("A9473BEB", 2).unwrap(), vec![head]);
+    assert_eq!(by_prefix(head.to_hex().as_str(), 2).unwrap(), vec![head]);
+    assert_eq!(by_prefix("", 2).unwrap().len(), 2);
+    assert!(by_prefix("a9473c", 2).unwrap().is_empty());
+    assert!(by_prefix("xyz", 2).is_err());
+}
+
 #[test]
 fn test_compute_changed_files_no_parents() {
     let repo = many_files_dirs::getrepo(None);
@@ -413,3 +780,492 @@ fn test_compute_changed_files_one_parent() {
         expected,
     );
 }
+
+#[test]
+fn test_diff_manifests() {
+    let repo = many_files_dirs::getrepo(None);
+    let nodehash = ChangesetId::new(string_to_nodehash(
+        "a6cb7dddec32acaf9a28db46cdb3061682155531",
+    ));
+    let parenthash = ChangesetId::new(string_to_nodehash(
+        "473b2e715e0df6b2316010908879a3c78e275dd9",
+    ));
+
+    let diff = run_future(repo.diff_manifests(&parenthash, &nodehash).collect()).unwrap();
+    let mut diff: Vec<_> = diff.into_iter()
+        .map(|entry| {
+            let kind = match entry {
+                ManifestDiffEntry::Added { .. } => "added",
+                ManifestDiffEntry::Removed { .. } => "removed",
+                ManifestDiffEntry::Modified { .. } => "modified",
+            };
+            (entry.path().clone(), kind)
+        })
+        .collect();
+    diff.sort();
+
+    // dir1 went from being a file to being a directory
+    let expected = vec![
+        (MPath::new(b"dir1").unwrap(), "removed"),
+        (MPath::new(b"dir1/file_1_in_dir1").unwrap(), "added"),
+        (MPath::new(b"dir1/file_2_in_dir1").unwrap(), "added"),
+        (MPath::new(b"dir1/subdir1/file_1").unwrap(), "added"),
+        (MPath::new(b"dir1/subdir1/subsubdir1/file_1").unwrap(), "added"),
+        (MPath::new(b"dir1/subdir1/subsubdir2/file_1").unwrap(), "added"),
+        (MPath::new(b"dir1/subdir1/subsubdir2/file_2").unwrap(), "added"),
+    ];
+    assert_eq!(diff, expected);
+
+    let diff = run_future(repo.diff_manifests(&nodehash, &nodehash).collect()).unwrap();
+    assert!(diff.is_empty());
+}
+
+#[test]
+fn create_changeset_from_changes() {
+    let repo = get_empty_eager_repo();
+    let metadata = ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: BTreeMap::new(),
+        comments: "Created from changes".into(),
+    };
+    let change = |content: &str| {
+        FileChange::Change(Bytes::from(content.as_bytes()), manifest::Type::File)
+    };
+
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("a").unwrap(), change("1"));
+    changes.insert(MPath::new("dir/b").unwrap(), change("2"));
+    let parent = run_future(repo.create_changeset_from_changes(
+        vec![],
+        changes,
+        metadata.clone(),
+    )).unwrap();
+
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("a").unwrap(), FileChange::Remove);
+    changes.insert(MPath::new("dir/b").unwrap(), change("3"));
+    let child = run_future(repo.create_changeset_from_changes(
+        vec![parent],
+        changes,
+        metadata.clone(),
+    )).unwrap();
+
+    let cs = run_future(repo.get_changeset_by_changesetid(&child)).unwrap();
+    assert_eq!(cs.parents().get_nodes(), (Some(&parent.into_nodehash()), None));
+    let files: Vec<_> = cs.files().into();
+    assert_eq!(files, vec![MPath::new("a").unwrap(), MPath::new("dir/b").unwrap()]);
+
+    let heads = run_future(repo.get_heads().collect()).unwrap();
+    assert_eq!(heads, vec![child.into_nodehash()]);
+
+    let diff = run_future(repo.diff_manifests(&parent, &child).collect()).unwrap();
+    assert_eq!(diff.len(), 2);
+    match diff.iter().find(|entry| entry.path() == &MPath::new("dir/b").unwrap()) {
+        Some(&ManifestDiffEntry::Modified { new, .. }) => {
+            let content = run_future(repo.get_file_content(&new)).unwrap();
+            assert_eq!(&content[..], b"3");
+        }
+        _ => panic!("dir/b should have been modified"),
+    }
+
+    // A file can't also be the directory of another
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("dir").unwrap(), change("4"));
+    changes.insert(MPath::new("dir/c").unwrap(), change("5"));
+    let res = run_future(repo.create_changeset_from_changes(vec![child], changes, metadata));
+    assert!(res.is_err());
+}
+
+#[test]
+fn garbage_collection() {
+    let blobs = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        blobs.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let metadata = ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: BTreeMap::new(),
+        comments: "Collected".into(),
+    };
+    let mut changes = BTreeMap::new();
+    changes.insert(
+        MPath::new("dir/a").unwrap(),
+        FileChange::Change(Bytes::from(&b"kept"[..]), manifest::Type::File),
+    );
+    let head = run_future(repo.create_changeset_from_changes(vec![], changes, metadata)).unwrap();
+
+    // A file which no changeset refers to, as a failed push would leave behind
+    let fake_path = RepoPath::file("fake/file").expect("Can't generate fake RepoPath");
+    let (stray, future) = upload_file_no_parents(&repo, "stray", &fake_path);
+    run_future(future).unwrap();
+    let stray_sha1 = BlobHash::from(&b"stray"[..]).sha1().clone();
+    let stray_keys = btreeset!{
+        format!("node-{}.bincode", stray),
+        format!("sha1-{}", stray_sha1),
+    };
+
+    let find = || {
+        let blobstore: Arc<Blobstore> = Arc::new(blobs.clone());
+        let marked = run_future(mark_reachable(blobstore, vec![head.into_nodehash()], 4)).unwrap();
+        run_future(find_unreachable(&blobs, Arc::new(marked))).unwrap()
+    };
+    let unreachable = find();
+    assert_eq!(unreachable.keys, stray_keys);
+    // The sha256 alias of the stray file isn't managed
+    assert!(unreachable.unmanaged > 0);
+
+    // The first collection deletes nothing, nor does one within the window
+    let (deleted, state) = plan_sweep(None, unreachable.keys, 100, 50);
+    assert!(deleted.is_empty());
+    assert_eq!(state.generation, 1);
+    let (deleted, state) = plan_sweep(Some(state), find().keys, 120, 50);
+    assert!(deleted.is_empty());
+    assert_eq!(state.time, 100);
+
+    // Once the window passed, what's still unreachable goes
+    let (deleted, state) = plan_sweep(Some(state), find().keys, 150, 50);
+    assert_eq!(deleted.iter().cloned().collect::<BTreeSet<_>>(), stray_keys);
+    assert_eq!(state.generation, 2);
+    assert!(state.unreachable.is_empty());
+    assert_eq!(run_future(sweep(Arc::new(blobs.clone()), deleted, 4)).unwrap(), 2);
+
+    assert!(find().keys.is_empty());
+    assert!(run_future(repo.get_file_content(&stray)).is_err());
+    let cs = run_future(repo.get_changeset_by_changesetid(&head)).unwrap();
+    let root = run_future(repo.get_manifest_by_nodeid(&cs.manifestid().into_nodehash())).unwrap();
+    assert!(run_future(root.list().collect()).unwrap().len() == 1);
+}
+
+#[test]
+fn recheck_unreachable_after_push() {
+    let blobs = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        blobs.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let blobstore: Arc<Blobstore> = Arc::new(blobs.clone());
+    let metadata = |comments: &str| ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: BTreeMap::new(),
+        comments: comments.into(),
+    };
+    let change = |content: &'static [u8]| {
+        FileChange::Change(Bytes::from(content), manifest::Type::File)
+    };
+
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("a").unwrap(), change(b"kept"));
+    let head = run_future(repo.create_changeset_from_changes(vec![], changes, metadata("A")))
+        .unwrap();
+
+    let fake_path = RepoPath::file("fake/file").expect("Can't generate fake RepoPath");
+    let (stray, future) = upload_file_no_parents(&repo, "stray", &fake_path);
+    run_future(future).unwrap();
+    let stray_node = format!("node-{}.bincode", stray);
+    let stray_content = format!("sha1-{}", BlobHash::from(&b"stray"[..]).sha1());
+
+    let roots = vec![head.into_nodehash()];
+    let marked =
+        Arc::new(run_future(mark_reachable(blobstore.clone(), roots.clone(), 4)).unwrap());
+    let keys: Vec<_> = run_future(find_unreachable(&blobs, marked.clone()))
+        .unwrap()
+        .keys
+        .into_iter()
+        .collect();
+    assert_eq!(keys, vec![stray_node.clone(), stray_content.clone()]);
+
+    // A push after marking reuses the stored content of the stray file, in a node of its own
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("a").unwrap(), change(b"stray"));
+    let pushed = run_future(repo.create_changeset_from_changes(
+        vec![head],
+        changes,
+        metadata("B"),
+    )).unwrap();
+
+    let recheck = |roots| {
+        run_future(recheck_unreachable(
+            blobstore.clone(),
+            roots,
+            marked.clone(),
+            keys.clone(),
+            4,
+        )).unwrap()
+    };
+    assert_eq!(recheck(roots), keys);
+    assert_eq!(recheck(vec![pushed.into_nodehash()]), vec![stray_node]);
+}
+
+#[test]
+fn sweep_waits_for_pushes() {
+    let blobs = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        blobs.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let uploads = PushUploads::new(
+        vec![string_to_nodehash("1111111111111111111111111111111111111111")],
+        vec![string_to_nodehash("2222222222222222222222222222222222222222")],
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let in_flight = |now| {
+        run_future(find_pushes_in_flight(Arc::new(blobs.clone()), now, 50, 4)).unwrap()
+    };
+
+    // No push starts during a sweep
+    run_future(begin_sweep(&blobs)).unwrap();
+    assert!(run_future(repo.record_push_uploads(&uploads)).is_err());
+    run_future(end_sweep(&blobs)).unwrap();
+
+    let id = run_future(repo.record_push_uploads(&uploads)).unwrap();
+    assert_eq!(in_flight(now), vec![id.clone()]);
+    // A push incomplete for longer than the window failed
+    assert!(in_flight(now + 100).is_empty());
+
+    run_future(repo.complete_push_uploads(&id)).unwrap();
+    assert!(in_flight(now).is_empty());
+}
+
+#[test]
+fn plan_sweep_forgets_reachable_keys() {
+    let keys = |keys: &[&str]| -> BTreeSet<String> {
+        keys.iter().map(|key| key.to_string()).collect()
+    };
+
+    let (_, state) = plan_sweep(None, keys(&["a", "b", "c"]), 100, 50);
+    // "b" is reachable again, say because a push completed
+    let (deleted, state) = plan_sweep(Some(state), keys(&["a", "c", "d"]), 120, 50);
+    assert!(deleted.is_empty());
+    assert_eq!(state.unreachable, keys(&["a", "c"]));
+
+    let (deleted, state) = plan_sweep(Some(state), keys(&["a", "d", "e"]), 150, 50);
+    assert_eq!(deleted, vec!["a".to_string()]);
+    assert_eq!(state.unreachable, keys(&["d", "e"]));
+}
+
+#[test]
+fn replicate_changesets_to_another_blobstore() {
+    let source = EagerMemblob::new();
+    let repo = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        source.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let metadata = ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: BTreeMap::new(),
+        comments: "Replicated".into(),
+    };
+    let change = |content: &str| {
+        FileChange::Change(Bytes::from(content.as_bytes()), manifest::Type::File)
+    };
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("dir/a").unwrap(), change("1"));
+    let parent = run_future(repo.create_changeset_from_changes(
+        vec![],
+        changes,
+        metadata.clone(),
+    )).unwrap();
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("dir/b").unwrap(), change("2"));
+    let child = run_future(repo.create_changeset_from_changes(
+        vec![parent],
+        changes,
+        metadata,
+    )).unwrap();
+
+    let dest = EagerMemblob::new();
+    let find_missing = || {
+        let source: Arc<Blobstore> = Arc::new(source.clone());
+        let dest: Arc<Blobstore> = Arc::new(dest.clone());
+        let heads = vec![child.into_nodehash()];
+        run_future(find_missing_changesets(source, dest, heads, HashSet::new(), 4)).unwrap()
+    };
+    let missing = find_missing();
+    assert_eq!(missing, vec![parent.into_nodehash(), child.into_nodehash()]);
+
+    let replicated = run_future(
+        replicate_changesets(
+            Arc::new(source.clone()),
+            Arc::new(dest.clone()),
+            missing,
+            2,
+            4,
+        ).collect(),
+    ).unwrap();
+    let replicated: Vec<_> = replicated.into_iter().map(|(node, _)| node).collect();
+    assert_eq!(replicated, vec![parent.into_nodehash(), child.into_nodehash()]);
+    assert!(find_missing().is_empty());
+
+    // Everything the child refers to can be read from the replica
+    let replica = BlobRepo::new_memblob(
+        None,
+        MemHeads::new(),
+        MemBookmarks::new(),
+        dest.clone(),
+        MemLinknodes::new(),
+        SqliteChangesets::in_memory().expect("cannot create in memory changesets"),
+        RepositoryId::new(0),
+    );
+    let diff = run_future(replica.diff_manifests(&parent, &child).collect()).unwrap();
+    assert_eq!(diff.len(), 1);
+    match diff[0] {
+        ManifestDiffEntry::Added { new, .. } => {
+            let content = run_future(replica.get_file_content(&new)).unwrap();
+            assert_eq!(&content[..], b"2");
+        }
+        _ => panic!("dir/b should have been added"),
+    }
+}
+
+#[test]
+fn convert_to_bonsai() {
+    let repo = get_empty_eager_repo();
+    let metadata = ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time {
+            time: 1000,
+            tz: -3600,
+        },
+        extra: btreemap! {b"key".to_vec() => b"value".to_vec()},
+        comments: "Converted".into(),
+    };
+    let mut changes = BTreeMap::new();
+    changes.insert(
+        MPath::new("dir/a").unwrap(),
+        FileChange::Change(Bytes::from("1"), manifest::Type::File),
+    );
+    let parent = run_future(repo.create_changeset_from_changes(
+        vec![],
+        changes,
+        metadata.clone(),
+    )).unwrap();
+    let mut changes = BTreeMap::new();
+    changes.insert(MPath::new("dir/a").unwrap(), FileChange::Remove);
+    changes.insert(
+        MPath::new("dir/b").unwrap(),
+        FileChange::Change(Bytes::from("2"), manifest::Type::Executable),
+    );
+    let child = run_future(repo.create_changeset_from_changes(
+        vec![parent],
+        changes,
+        metadata,
+    )).unwrap();
+
+    // The parents have to be converted first
+    assert!(run_future(repo.convert_to_bonsai(&child)).is_err());
+
+    let bonsai_parent = run_future(repo.convert_to_bonsai(&parent)).unwrap();
+    let bonsai_child = run_future(repo.convert_to_bonsai(&child)).unwrap();
+    assert_eq!(
+        run_future(repo.get_bonsai_from_hg(&child)).unwrap(),
+        Some(bonsai_child)
+    );
+    assert_eq!(
+        run_future(repo.get_hg_from_bonsai(&bonsai_parent)).unwrap(),
+        Some(parent)
+    );
+
+    let cs = run_future(repo.get_bonsai_changeset(&bonsai_child))
+        .unwrap()
+        .expect("bonsai changeset should be stored");
+    assert_eq!(cs.get_changeset_id(), bonsai_child);
+    assert_eq!(cs.parents(), &[bonsai_parent][..]);
+    assert_eq!(cs.author(), "author <author@fb.com>");
+    assert_eq!(cs.author_date().timestamp_secs(), 1000);
+    assert_eq!(cs.author_date().tz_offset_secs(), 3600);
+    assert_eq!(cs.message(), "Converted");
+    assert_eq!(cs.extra().get("key"), Some(&b"value".to_vec()));
+
+    let changes = cs.file_changes();
+    assert_eq!(changes.len(), 2);
+    assert_eq!(changes[&MPath::new("dir/a").unwrap()], None);
+    let change = changes[&MPath::new("dir/b").unwrap()]
+        .clone()
+        .expect("dir/b should be changed");
+    assert_eq!(*change.content_id(), ContentId::from_data(b"2"));
+    assert_eq!(change.file_type(), FileType::Executable);
+    assert_eq!(change.size(), 1);
+    assert_eq!(change.copy_from(), None);
+    assert_eq!(
+        run_future(repo.get_file_content_by_content_id(change.content_id())).unwrap(),
+        Some(Bytes::from("2"))
+    );
+
+    // Converting again gives the same changeset
+    assert_eq!(
+        run_future(repo.convert_to_bonsai(&child)).unwrap(),
+        bonsai_child
+    );
+}
+
+#[test]
+fn save_many_changesets() {
+    let repo = get_empty_eager_repo();
+    let metadata = ChangesetMetadata {
+        user: "author <author@fb.com>".into(),
+        time: Time { time: 0, tz: 0 },
+        extra: BTreeMap::new(),
+        comments: "Saved in batches".into(),
+    };
+    let mut parents = vec![];
+    let mut changesets = vec![];
+    for content in &["1", "2", "3", "4", "5"] {
+        let mut changes = BTreeMap::new();
+        changes.insert(
+            MPath::new("file").unwrap(),
+            FileChange::Change(Bytes::from(*content), manifest::Type::File),
+        );
+        let cs = run_future(repo.create_changeset_from_changes(
+            parents,
+            changes,
+            metadata.clone(),
+        )).unwrap();
+        changesets.push(cs);
+        parents = vec![cs];
+    }
+
+    // Children first: save_many puts their parents first itself
+    let loaded: Vec<_> = changesets
+        .iter()
+        .rev()
+        .map(|cs| run_future(repo.get_changeset_by_changesetid(cs)).unwrap())
+        .collect();
+    let dest = EagerMemblob::new();
+    run_future(BlobChangeset::save_many(Arc::new(dest.clone()), loaded, 2)).unwrap();
+
+    let dest: Arc<Blobstore> = Arc::new(dest);
+    for cs in &changesets {
+        let saved = run_future(BlobChangeset::load(&dest, cs))
+            .unwrap()
+            .expect("changeset should be saved");
+        assert_eq!(saved.get_changeset_id(), *cs);
+    }
+}
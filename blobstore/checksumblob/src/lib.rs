@@ -0,0 +1,84 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate blobstore;
+extern crate mononoke_types;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::{Blobstore, ErrorKind};
+use mononoke_types::hash::Blake2;
+
+const HASH_LEN: usize = 32;
+
+/// Blobstore adapter storing a checksum with every blob.
+///
+/// Each value is stored prefixed with the BLAKE2b hash of its content, and the hash is checked
+/// again on every `get`. A mismatch is reported as `ErrorKind::CorruptBlob` rather than silently
+/// returning bad data, so that corruption in the underlying store can be detected (and, with a
+/// multiplexed blobstore, routed around).
+///
+/// All blobs in the underlying blobstore must have been written through this adapter.
+#[derive(Clone, Debug)]
+pub struct ChecksumBlobstore<B> {
+    blobstore: B,
+}
+
+impl<B: Blobstore> ChecksumBlobstore<B> {
+    pub fn new(blobstore: B) -> Self {
+        Self { blobstore }
+    }
+}
+
+fn encode(value: &[u8]) -> Bytes {
+    let hash = Blake2::from(value);
+    let mut out = Vec::with_capacity(HASH_LEN + value.len());
+    out.extend_from_slice(hash.as_ref());
+    out.extend_from_slice(value);
+    Bytes::from(out)
+}
+
+fn decode(key: String, stored: Bytes) -> Result<Bytes, Error> {
+    if stored.len() < HASH_LEN {
+        return Err(ErrorKind::CorruptBlob(key).into());
+    }
+
+    let value = stored.slice_from(HASH_LEN);
+    if Blake2::from(value.as_ref()).as_ref() != &stored[..HASH_LEN] {
+        return Err(ErrorKind::CorruptBlob(key).into());
+    }
+    Ok(value)
+}
+
+impl<B: Blobstore> Blobstore for ChecksumBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore
+            .get(key.clone())
+            .and_then(move |stored| match stored {
+                Some(stored) => decode(key, stored).map(Some),
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.blobstore.put(key, encode(&value))
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(key)
+    }
+}
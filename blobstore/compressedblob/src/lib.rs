@@ -0,0 +1,164 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate flate2;
+extern crate futures;
+extern crate futures_ext;
+extern crate pylz4;
+extern crate zstd;
+
+extern crate blobstore;
+
+use std::io::{Read, Write};
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use flate2::Compression as FlateCompression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+// Every stored value starts with MAGIC followed by one byte identifying the codec.
+const MAGIC: &[u8] = b"\xc0mb";
+const HEADER_LEN: usize = 4;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Blob {} has no compression header", _0)] MissingHeader(String),
+    #[fail(display = "Blob {} is compressed with unknown codec {}", _0, _1)]
+    UnknownCodec(String, u8),
+    #[fail(display = "Failed to decompress blob {}", _0)] DecompressFailed(String),
+}
+
+/// Compression algorithm applied to values before they reach the underlying blobstore.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Values are stored as is (apart from the header).
+    Raw,
+    Zstd { level: i32 },
+    Lz4,
+    Gzip { level: u32 },
+}
+
+impl Codec {
+    fn id(&self) -> u8 {
+        match *self {
+            Codec::Raw => 0,
+            Codec::Zstd { .. } => 1,
+            Codec::Lz4 => 2,
+            Codec::Gzip { .. } => 3,
+        }
+    }
+
+    fn compress(&self, data: &[u8]) -> Result<Vec<u8>> {
+        match *self {
+            Codec::Raw => Ok(data.to_vec()),
+            Codec::Zstd { level } => Ok(zstd::encode_all(data, level)?),
+            Codec::Lz4 => pylz4::compress(data),
+            Codec::Gzip { level } => {
+                let mut encoder = GzEncoder::new(Vec::new(), FlateCompression::new(level));
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            }
+        }
+    }
+}
+
+fn decompress(key: &str, id: u8, data: &[u8]) -> Result<Vec<u8>> {
+    let res = match id {
+        0 => Ok(data.to_vec()),
+        1 => zstd::decode_all(data).map_err(Error::from),
+        2 => pylz4::decompress(data).map(|(decompressed, _)| decompressed),
+        3 => {
+            let mut decompressed = Vec::new();
+            GzDecoder::new(data)
+                .read_to_end(&mut decompressed)
+                .map(|_| decompressed)
+                .map_err(Error::from)
+        }
+        id => return Err(ErrorKind::UnknownCodec(key.to_string(), id).into()),
+    };
+
+    res.map_err(|err| err.context(ErrorKind::DecompressFailed(key.to_string())).into())
+}
+
+/// Blobstore adapter transparently compressing values.
+///
+/// Values are compressed with the configured codec on `put`, and a small header recording the
+/// codec is prepended, so that `get` can decompress blobs written with any codec. This means
+/// that the codec can be changed without rewriting existing blobs. Values which don't get any
+/// smaller are stored uncompressed.
+///
+/// All blobs in the underlying blobstore must have been written through this adapter.
+#[derive(Clone, Debug)]
+pub struct CompressingBlobstore<B> {
+    blobstore: B,
+    codec: Codec,
+}
+
+impl<B: Blobstore> CompressingBlobstore<B> {
+    pub fn new(blobstore: B, codec: Codec) -> Self {
+        Self { blobstore, codec }
+    }
+}
+
+fn encode(codec: Codec, value: &[u8]) -> Result<Bytes> {
+    let compressed = codec.compress(value)?;
+    let (codec, payload) = if compressed.len() < value.len() {
+        (codec, &compressed[..])
+    } else {
+        (Codec::Raw, value)
+    };
+
+    let mut out = Vec::with_capacity(HEADER_LEN + payload.len());
+    out.extend_from_slice(MAGIC);
+    out.push(codec.id());
+    out.extend_from_slice(payload);
+    Ok(Bytes::from(out))
+}
+
+fn decode(key: &str, value: Bytes) -> Result<Bytes> {
+    if value.len() < HEADER_LEN || &value[..MAGIC.len()] != MAGIC {
+        return Err(ErrorKind::MissingHeader(key.to_string()).into());
+    }
+
+    match value[MAGIC.len()] {
+        // Avoid a copy in the common uncompressed case
+        0 => Ok(value.slice_from(HEADER_LEN)),
+        id => decompress(key, id, &value[HEADER_LEN..]).map(Bytes::from),
+    }
+}
+
+impl<B: Blobstore> Blobstore for CompressingBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore
+            .get(key.clone())
+            .and_then(move |value| match value {
+                Some(value) => decode(&key, value).map(Some),
+                None => Ok(None),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        match encode(self.codec, &value) {
+            Ok(encoded) => self.blobstore.put(key, encoded),
+            Err(err) => future::err(err).boxify(),
+        }
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(key)
+    }
+}
@@ -10,12 +10,13 @@ extern crate bytes;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
+#[macro_use]
 extern crate url;
 
 extern crate blobstore;
 extern crate futures_ext;
 
-use std::fs::{create_dir_all, File};
+use std::fs::{create_dir_all, read_dir, remove_file, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 
@@ -23,13 +24,21 @@ use bytes::Bytes;
 use failure::{Error, Result};
 use futures::Async;
 use futures::future::{poll_fn, Future};
-use futures_ext::{BoxFuture, FutureExt};
-use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+use futures::stream::{self, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use url::percent_encoding::{percent_decode, percent_encode, DEFAULT_ENCODE_SET,
+                            PATH_SEGMENT_ENCODE_SET};
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, Deletable, Enumerable};
 
 const PREFIX: &str = "blob";
 
+define_encode_set! {
+    // Keys become a single file name, and enumerate() decodes them back, so both '/' and '%'
+    // must be escaped for the encoding to round-trip.
+    KEY_ENCODE_SET = [PATH_SEGMENT_ENCODE_SET] | {'/', '%'}
+}
+
 #[derive(Debug, Clone)]
 pub struct Fileblob {
     base: PathBuf,
@@ -55,24 +64,54 @@ impl Fileblob {
     }
 
     fn path(&self, key: &String) -> PathBuf {
-        let key = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET);
+        let key = percent_encode(key.as_bytes(), KEY_ENCODE_SET);
         self.base.join(format!("{}-{}", PREFIX, key))
     }
+
+    /// Blobs written before '/' and '%' were escaped live under a different name. Returns that
+    /// name if it differs from the current one, so such blobs can still be found.
+    fn legacy_path(&self, key: &String) -> Option<PathBuf> {
+        let legacy = percent_encode(key.as_bytes(), DEFAULT_ENCODE_SET).to_string();
+        let current = percent_encode(key.as_bytes(), KEY_ENCODE_SET).to_string();
+        if legacy == current {
+            None
+        } else {
+            Some(self.base.join(format!("{}-{}", PREFIX, legacy)))
+        }
+    }
+}
+
+fn read_blob(p: &Path) -> io::Result<Option<Bytes>> {
+    let mut v = Vec::new();
+    match File::open(p) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+        Ok(mut f) => {
+            f.read_to_end(&mut v)?;
+            Ok(Some(Bytes::from(v)))
+        }
+    }
+}
+
+fn remove_blob(p: &Path) -> io::Result<()> {
+    match remove_file(p) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        res => res,
+    }
 }
 
 impl Blobstore for Fileblob {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
         let p = self.path(&key);
+        let legacy = self.legacy_path(&key);
 
         poll_fn(move || {
-            let mut v = Vec::new();
-            let ret = match File::open(&p) {
-                Err(ref e) if e.kind() == io::ErrorKind::NotFound => None,
-                Err(e) => return Err(e),
-                Ok(mut f) => {
-                    f.read_to_end(&mut v)?;
-                    Some(Bytes::from(v))
-                }
+            let ret = match read_blob(&p)? {
+                Some(value) => Some(value),
+                None => match legacy {
+                    Some(ref legacy) => read_blob(legacy)?,
+                    None => None,
+                },
             };
             Ok(Async::Ready(ret))
         }).from_err()
@@ -81,10 +120,56 @@ impl Blobstore for Fileblob {
 
     fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
         let p = self.path(&key);
+        let legacy = self.legacy_path(&key);
 
         poll_fn::<_, Error, _>(move || {
             File::create(&p)?.write_all(value.as_ref())?;
+            // Drop any copy under the old name so enumerate() doesn't see the key twice.
+            if let Some(ref legacy) = legacy {
+                remove_blob(legacy)?;
+            }
             Ok(Async::Ready(()))
         }).boxify()
     }
 }
+
+impl Deletable for Fileblob {
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let p = self.path(&key);
+        let legacy = self.legacy_path(&key);
+
+        poll_fn::<_, Error, _>(move || {
+            remove_blob(&p)?;
+            if let Some(ref legacy) = legacy {
+                remove_blob(legacy)?;
+            }
+            Ok(Async::Ready(()))
+        }).boxify()
+    }
+}
+
+impl Enumerable for Fileblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let prefix = format!("{}-", PREFIX);
+
+        let entries = match read_dir(&self.base) {
+            Ok(entries) => entries,
+            Err(err) => return stream::once(Err(err.into())).boxify(),
+        };
+
+        stream::iter_result(entries)
+            .from_err()
+            .filter_map(move |entry| {
+                let name = entry.file_name();
+                let name = name.to_str()?;
+                if !name.starts_with(&prefix) {
+                    return None;
+                }
+                percent_decode(name[prefix.len()..].as_bytes())
+                    .decode_utf8()
+                    .ok()
+                    .map(|key| key.into_owned())
+            })
+            .boxify()
+    }
+}
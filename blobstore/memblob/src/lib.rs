@@ -18,10 +18,11 @@ use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use failure::Error;
-use futures::future::{lazy, IntoFuture};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::future::{lazy, Future, IntoFuture};
+use futures::stream;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, Deletable, Enumerable, LruBlobs, LruStats};
 
 /// In-memory "blob store"
 ///
@@ -37,6 +38,14 @@ pub struct LazyMemblob {
     hash: Arc<Mutex<HashMap<String, Bytes>>>,
 }
 
+/// As EagerMemblob, but bounded both by the total size of the blobs and by their number, evicting
+/// the least recently used. Blobs can go missing, so it's only suitable as a cache, or as a test
+/// double for repos too large to hold entirely.
+#[derive(Clone)]
+pub struct BoundedMemblob {
+    blobs: Arc<Mutex<LruBlobs>>,
+}
+
 impl EagerMemblob {
     pub fn new() -> Self {
         Self {
@@ -53,6 +62,19 @@ impl LazyMemblob {
     }
 }
 
+impl BoundedMemblob {
+    pub fn new(max_size: usize, max_entries: usize) -> Self {
+        Self {
+            blobs: Arc::new(Mutex::new(LruBlobs::new(max_size, max_entries))),
+        }
+    }
+
+    /// Hits, misses and evictions so far.
+    pub fn stats(&self) -> LruStats {
+        self.blobs.lock().expect("lock poison").stats()
+    }
+}
+
 impl Blobstore for EagerMemblob {
     fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
         let mut inner = self.hash.lock().expect("lock poison");
@@ -89,3 +111,74 @@ impl Blobstore for LazyMemblob {
         }).boxify()
     }
 }
+
+impl Blobstore for BoundedMemblob {
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let mut inner = self.blobs.lock().expect("lock poison");
+
+        inner.insert(key, value);
+        Ok(()).into_future().boxify()
+    }
+
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let mut inner = self.blobs.lock().expect("lock poison");
+
+        Ok(inner.get(&key)).into_future().boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        let inner = self.blobs.lock().expect("lock poison");
+
+        Ok(inner.contains_key(&key)).into_future().boxify()
+    }
+}
+
+impl Deletable for EagerMemblob {
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let mut inner = self.hash.lock().expect("lock poison");
+
+        inner.remove(&key);
+        Ok(()).into_future().boxify()
+    }
+}
+
+impl Deletable for LazyMemblob {
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let hash = self.hash.clone();
+
+        lazy(move || {
+            let mut inner = hash.lock().expect("lock poison");
+
+            inner.remove(&key);
+            Ok(()).into_future()
+        }).boxify()
+    }
+}
+
+impl Enumerable for EagerMemblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let inner = self.hash.lock().expect("lock poison");
+
+        stream::iter_ok(inner.keys().cloned().collect::<Vec<_>>()).boxify()
+    }
+}
+
+impl Enumerable for LazyMemblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let hash = self.hash.clone();
+
+        lazy(move || {
+            let inner = hash.lock().expect("lock poison");
+            Ok(stream::iter_ok(inner.keys().cloned().collect::<Vec<_>>()))
+        }).flatten_stream()
+            .boxify()
+    }
+}
+
+impl Enumerable for BoundedMemblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let inner = self.blobs.lock().expect("lock poison");
+
+        stream::iter_ok(inner.keys()).boxify()
+    }
+}
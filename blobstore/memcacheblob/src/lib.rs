@@ -0,0 +1,141 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate blobstore;
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::future::{self, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+// Memcache rejects keys longer than this
+const MAX_KEY_LEN: usize = 250;
+// Default memcache item size limit
+const MAX_VALUE_LEN: usize = 1024 * 1024;
+
+/// Minimal interface to a memcache client.
+///
+/// This only covers what's needed to use memcache as a blob cache, so that different client
+/// libraries (or an in-memory fake for tests) can be plugged into `MemcacheBlob`.
+pub trait Memcache: Send + Sync + 'static {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error>;
+    fn set(&self, key: String, value: Bytes) -> BoxFuture<(), Error>;
+}
+
+impl Memcache for Arc<Memcache> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.as_ref().get(key)
+    }
+    fn set(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.as_ref().set(key, value)
+    }
+}
+
+/// Read-through and write-through memcache layer in front of another blobstore.
+///
+/// The memcache is treated purely as a cache: any failure talking to it is ignored and the
+/// operation falls back to the underlying blobstore, which is always the source of truth. Blobs
+/// too large for memcache, or whose keys are too long, bypass the cache entirely.
+#[derive(Clone)]
+pub struct MemcacheBlob<B, M> {
+    blobstore: B,
+    memcache: Arc<M>,
+    prefix: String,
+}
+
+impl<B, M> MemcacheBlob<B, M>
+where
+    B: Blobstore,
+    M: Memcache,
+{
+    /// `prefix` is prepended to every memcache key, to keep blobs from different repos (or
+    /// different users of the same memcache) apart.
+    pub fn new<S: Into<String>>(blobstore: B, memcache: M, prefix: S) -> Self {
+        Self {
+            blobstore,
+            memcache: Arc::new(memcache),
+            prefix: prefix.into(),
+        }
+    }
+
+    fn cache_key(&self, key: &str) -> Option<String> {
+        let cache_key = format!("{}{}", self.prefix, key);
+        if cache_key.len() <= MAX_KEY_LEN {
+            Some(cache_key)
+        } else {
+            None
+        }
+    }
+}
+
+fn cache_set<M: Memcache>(
+    memcache: &M,
+    cache_key: Option<String>,
+    value: Bytes,
+) -> BoxFuture<(), Error> {
+    match cache_key {
+        Some(ref cache_key) if value.len() <= MAX_VALUE_LEN => memcache
+            .set(cache_key.clone(), value)
+            .or_else(|_| Ok(()))
+            .boxify(),
+        _ => future::ok(()).boxify(),
+    }
+}
+
+impl<B, M> Blobstore for MemcacheBlob<B, M>
+where
+    B: Blobstore + Clone,
+    M: Memcache,
+{
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let cache_key = self.cache_key(&key);
+        let cached = match cache_key {
+            Some(ref cache_key) => self.memcache
+                .get(cache_key.clone())
+                .or_else(|_| Ok(None))
+                .boxify(),
+            None => future::ok(None).boxify(),
+        };
+
+        let blobstore = self.blobstore.clone();
+        let memcache = self.memcache.clone();
+        cached
+            .and_then(move |cached| match cached {
+                Some(value) => future::ok(Some(value)).boxify(),
+                None => blobstore
+                    .get(key)
+                    .and_then(move |value| match value {
+                        Some(value) => cache_set(&*memcache, cache_key, value.clone())
+                            .map(move |()| Some(value))
+                            .boxify(),
+                        None => future::ok(None).boxify(),
+                    })
+                    .boxify(),
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let cache_key = self.cache_key(&key);
+        let memcache = self.memcache.clone();
+
+        self.blobstore
+            .put(key, value.clone())
+            .and_then(move |()| cache_set(&*memcache, cache_key, value))
+            .boxify()
+    }
+}
@@ -0,0 +1,148 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate blobstore;
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::future::{self, join_all, loop_fn, Future, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Multiplexed blobstore needs at least one underlying blobstore")]
+    NoBlobstores,
+    #[fail(display = "Write quorum of {} can never be met by {} blobstores", _0, _1)]
+    BadQuorum(usize, usize),
+    #[fail(display = "Put of {} succeeded on {} blobstores, needed {}: {:?}", _0, _1, _2, _3)]
+    QuorumNotReached(String, usize, usize, Vec<Error>),
+    #[fail(display = "Get of {} failed on all blobstores: {:?}", _0, _1)]
+    AllFailed(String, Vec<Error>),
+}
+
+/// How many of the underlying blobstores have to accept a `put` for it to be considered
+/// successful.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WriteQuorum {
+    /// Every blobstore must succeed.
+    All,
+    /// At least this many blobstores must succeed.
+    AtLeast(usize),
+}
+
+impl WriteQuorum {
+    fn required(&self, total: usize) -> usize {
+        match *self {
+            WriteQuorum::All => total,
+            WriteQuorum::AtLeast(n) => n,
+        }
+    }
+}
+
+/// Blobstore that writes to several underlying blobstores.
+///
+/// `put` is sent to every blobstore and succeeds once all of them have finished, as long as at
+/// least as many succeeded as the `WriteQuorum` requires. `get` tries the blobstores in order and
+/// returns the first blob found; a blobstore failing or not having the key just moves on to the
+/// next one. This means the cheapest/closest blobstore should be listed first.
+#[derive(Clone)]
+pub struct MultiplexedBlobstore {
+    blobstores: Arc<Vec<Arc<Blobstore>>>,
+    quorum: usize,
+}
+
+impl MultiplexedBlobstore {
+    pub fn new(blobstores: Vec<Arc<Blobstore>>, quorum: WriteQuorum) -> Result<Self, Error> {
+        if blobstores.is_empty() {
+            return Err(ErrorKind::NoBlobstores.into());
+        }
+
+        let required = quorum.required(blobstores.len());
+        if required == 0 || required > blobstores.len() {
+            return Err(ErrorKind::BadQuorum(required, blobstores.len()).into());
+        }
+
+        Ok(Self {
+            blobstores: Arc::new(blobstores),
+            quorum: required,
+        })
+    }
+}
+
+impl Blobstore for MultiplexedBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let blobstores = self.blobstores.clone();
+
+        loop_fn(
+            (0, Vec::new()),
+            move |(idx, mut errors): (usize, Vec<Error>)| match blobstores.get(idx) {
+                None => {
+                    let res = if errors.len() == blobstores.len() {
+                        // Nobody answered, so we can't claim the blob is missing
+                        Err(ErrorKind::AllFailed(key.clone(), errors).into())
+                    } else {
+                        Ok(Loop::Break(None))
+                    };
+                    future::result(res).boxify()
+                }
+                Some(blobstore) => blobstore
+                    .get(key.clone())
+                    .then(move |res| match res {
+                        Ok(Some(value)) => Ok(Loop::Break(Some(value))),
+                        Ok(None) => Ok(Loop::Continue((idx + 1, errors))),
+                        Err(err) => {
+                            errors.push(err);
+                            Ok(Loop::Continue((idx + 1, errors)))
+                        }
+                    })
+                    .boxify(),
+            },
+        ).boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let quorum = self.quorum;
+        let puts = self.blobstores.iter().map({
+            let key = key.clone();
+            move |blobstore| {
+                blobstore
+                    .put(key.clone(), value.clone())
+                    .then(|res| Ok::<_, Error>(res))
+            }
+        });
+
+        join_all(puts)
+            .and_then(move |results| {
+                let mut successes = 0;
+                let mut errors = Vec::new();
+                for res in results {
+                    match res {
+                        Ok(()) => successes += 1,
+                        Err(err) => errors.push(err),
+                    }
+                }
+
+                if successes >= quorum {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::QuorumNotReached(key, successes, quorum, errors).into())
+                }
+            })
+            .boxify()
+    }
+}
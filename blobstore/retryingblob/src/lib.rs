@@ -0,0 +1,159 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate rand;
+extern crate tokio_timer;
+
+extern crate blobstore;
+
+use std::fmt::{self, Debug};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::future::{loop_fn, Future, IntoFuture, Loop};
+use futures_ext::{BoxFuture, FutureExt};
+use rand::Rng;
+use tokio_timer::{wheel, Timer};
+
+use blobstore::Blobstore;
+
+/// Decides whether a failed operation is worth retrying.
+pub type RetryClassifier = Arc<Fn(&Error) -> bool + Send + Sync>;
+
+/// How `RetryingBlobstore` retries failed operations.
+///
+/// The delay before retry `n` (counting from 0) is `base_delay * factor^n`, randomly adjusted by
+/// up to `jitter` (as a fraction of the delay) in either direction, so that many clients failing
+/// at the same time don't all retry in lockstep.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    /// Total number of attempts, including the first one.
+    pub max_attempts: usize,
+    pub base_delay: Duration,
+    pub factor: f64,
+    /// Between 0.0 (no jitter) and 1.0.
+    pub jitter: f64,
+    pub retryable: RetryClassifier,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_millis(100),
+            factor: 2.0,
+            jitter: 0.1,
+            retryable: Arc::new(|_| true),
+        }
+    }
+}
+
+impl Debug for RetryPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.debug_struct("RetryPolicy")
+            .field("max_attempts", &self.max_attempts)
+            .field("base_delay", &self.base_delay)
+            .field("factor", &self.factor)
+            .field("jitter", &self.jitter)
+            .finish()
+    }
+}
+
+fn duration_from_secs_f64(secs: f64) -> Duration {
+    let secs = secs.max(0.0);
+    Duration::new(secs.trunc() as u64, (secs.fract() * 1e9) as u32)
+}
+
+impl RetryPolicy {
+    /// Delay before the retry following failed attempt `attempt` (counting from 0).
+    pub fn delay(&self, attempt: usize) -> Duration {
+        let base = self.base_delay.as_secs() as f64
+            + self.base_delay.subsec_nanos() as f64 / 1e9;
+        let delay = base * self.factor.powi(attempt as i32);
+        let jitter = if self.jitter > 0.0 {
+            rand::thread_rng().gen_range(-self.jitter, self.jitter)
+        } else {
+            0.0
+        };
+        duration_from_secs_f64(delay * (1.0 + jitter))
+    }
+}
+
+/// Blobstore wrapper retrying failed operations according to a `RetryPolicy`.
+///
+/// Gets, puts and presence checks are all retried. A put is safe to retry because a blobstore
+/// key always maps to the same value.
+#[derive(Clone)]
+pub struct RetryingBlobstore<B> {
+    blobstore: B,
+    policy: RetryPolicy,
+    timer: Timer,
+}
+
+impl<B: Blobstore + Clone> RetryingBlobstore<B> {
+    /// Fails if the policy allows no attempt at all.
+    pub fn new(blobstore: B, policy: RetryPolicy) -> Result<Self> {
+        if policy.max_attempts == 0 {
+            bail_msg!("max_attempts must be positive");
+        }
+        Ok(Self {
+            blobstore,
+            policy,
+            timer: wheel().tick_duration(Duration::from_millis(10)).build(),
+        })
+    }
+
+    fn retry<T, F>(&self, op: F) -> BoxFuture<T, Error>
+    where
+        T: Send + 'static,
+        F: Fn(&B) -> BoxFuture<T, Error> + Send + 'static,
+    {
+        let blobstore = self.blobstore.clone();
+        let policy = self.policy.clone();
+        let timer = self.timer.clone();
+
+        loop_fn(0, move |attempt| {
+            let policy = policy.clone();
+            let timer = timer.clone();
+            op(&blobstore).then(move |res| match res {
+                Ok(val) => Ok(Loop::Break(val)).into_future().boxify(),
+                Err(err) => {
+                    if attempt + 1 >= policy.max_attempts || !(policy.retryable)(&err) {
+                        return Err(err).into_future().boxify();
+                    }
+                    timer
+                        .sleep(policy.delay(attempt))
+                        .from_err()
+                        .map(move |()| Loop::Continue(attempt + 1))
+                        .boxify()
+                }
+            })
+        }).boxify()
+    }
+}
+
+impl<B: Blobstore + Clone> Blobstore for RetryingBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.retry(move |blobstore| blobstore.get(key.clone()))
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.retry(move |blobstore| blobstore.put(key.clone(), value.clone()))
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.retry(move |blobstore| blobstore.is_present(key.clone()))
+    }
+}
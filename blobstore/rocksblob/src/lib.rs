@@ -19,11 +19,13 @@ use std::path::Path;
 use bytes::Bytes;
 use failure::Error;
 use futures::{Async, Future, Poll};
-use futures_ext::{BoxFuture, FutureExt};
+use futures::future::lazy;
+use futures::stream;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 
 use rocksdb::{Db, ReadOptions, WriteOptions};
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, Deletable, Enumerable};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
@@ -60,6 +62,9 @@ pub struct GetBlob(Db, String);
 #[must_use = "futures do nothing unless polled"]
 pub struct PutBlob(Db, String, Bytes);
 
+#[must_use = "futures do nothing unless polled"]
+pub struct DeleteBlob(Db, String);
+
 impl Future for GetBlob {
     type Item = Option<Bytes>;
     type Error = Error;
@@ -82,6 +87,17 @@ impl Future for PutBlob {
     }
 }
 
+impl Future for DeleteBlob {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let wropts = WriteOptions::new().set_sync(false);
+        self.0.delete(&self.1, &wropts).map_err(Error::from)?;
+        Ok(Async::Ready(()))
+    }
+}
+
 impl Blobstore for Rocksblob where {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
         let db = self.db.clone();
@@ -95,3 +111,28 @@ impl Blobstore for Rocksblob where {
         PutBlob(db, key, value).boxify()
     }
 }
+
+impl Deletable for Rocksblob {
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let db = self.db.clone();
+
+        DeleteBlob(db, key).boxify()
+    }
+}
+
+impl Enumerable for Rocksblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let db = self.db.clone();
+
+        // The RocksDB iterator borrows the Db, so the keys are gathered up front rather than
+        // streamed.
+        lazy(move || {
+            let rdopts = ReadOptions::new();
+            let keys: Vec<_> = db.iter(&rdopts)
+                .filter_map(|(key, _value)| String::from_utf8(key.to_vec()).ok())
+                .collect();
+            Ok(stream::iter_ok(keys))
+        }).flatten_stream()
+            .boxify()
+    }
+}
@@ -0,0 +1,4 @@
+CREATE TABLE IF NOT EXISTS blobs (
+  key TEXT PRIMARY KEY NOT NULL,
+  value BLOB NOT NULL
+);
@@ -0,0 +1,151 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate diesel;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate blobstore;
+
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use diesel::{replace_into, select, Connection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::dsl::exists;
+use diesel::prelude::*;
+use failure::Error;
+use futures::future;
+use futures::stream;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobstore::{Blobstore, Deletable, Enumerable};
+
+mod schema;
+
+use schema::blobs;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+/// Blobstore keeping all blobs in a single SQLite database file.
+///
+/// This is meant for small and development deployments which want a self-contained repo store
+/// without the footprint of RocksDB. The database is used in WAL mode, so that reads aren't
+/// blocked by a concurrent writer from another process.
+#[derive(Clone)]
+pub struct Sqliteblob {
+    connection: Arc<Mutex<SqliteConnection>>,
+}
+
+impl Sqliteblob {
+    /// Open an existing SQLite blob database. This is synchronous because the SQLite backend
+    /// hits local disk.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = SqliteConnection::establish(&path.as_ref().to_string_lossy())?;
+        conn.batch_execute("PRAGMA journal_mode = WAL;")?;
+        Ok(Self {
+            connection: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    /// Open a SQLite blob database, creating it if it doesn't exist.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let blobstore = Self::open(path)?;
+
+        let up_query = include_str!("../schemas/sqlite-blobs.sql");
+        blobstore
+            .connection
+            .lock()
+            .expect("lock poisoned")
+            .batch_execute(&up_query)?;
+
+        Ok(blobstore)
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory() -> Result<Self> {
+        Self::create(":memory:")
+    }
+}
+
+// TODO: don't block -- send the queries to another thread
+impl Blobstore for Sqliteblob {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+        let value = blobs::table
+            .filter(blobs::key.eq(&key))
+            .select(blobs::value)
+            .first::<Vec<u8>>(&*connection)
+            .optional()
+            .map(|value| value.map(Bytes::from))
+            .map_err(Error::from);
+        future::result(value).boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        // Values never change for a given key, so replacing an existing row is harmless
+        let connection = self.connection.lock().expect("lock poisoned");
+        let res = replace_into(blobs::table)
+            .values((blobs::key.eq(&key), blobs::value.eq(value.as_ref())))
+            .execute(&*connection)
+            .map(|_| ())
+            .map_err(Error::from);
+        future::result(res).boxify()
+    }
+
+    // All the blobs are put in one transaction, which is much faster than one per blob
+    fn put_many(&self, blobs: Vec<(String, Bytes)>) -> BoxFuture<(), Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+        let res = connection
+            .transaction::<_, diesel::result::Error, _>(|| {
+                for (key, value) in blobs {
+                    replace_into(blobs::table)
+                        .values((blobs::key.eq(&key), blobs::value.eq(value.as_ref())))
+                        .execute(&*connection)?;
+                }
+                Ok(())
+            })
+            .map_err(Error::from);
+        future::result(res).boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+        let present = select(exists(blobs::table.filter(blobs::key.eq(&key))))
+            .get_result::<bool>(&*connection)
+            .map_err(Error::from);
+        future::result(present).boxify()
+    }
+}
+
+impl Deletable for Sqliteblob {
+    fn delete(&self, key: String) -> BoxFuture<(), Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+        let res = diesel::delete(blobs::table.filter(blobs::key.eq(&key)))
+            .execute(&*connection)
+            .map(|_| ())
+            .map_err(Error::from);
+        future::result(res).boxify()
+    }
+}
+
+impl Enumerable for Sqliteblob {
+    fn enumerate(&self) -> BoxStream<String, Error> {
+        let connection = self.connection.lock().expect("lock poisoned");
+        let keys = blobs::table
+            .select(blobs::key)
+            .load::<String>(&*connection)
+            .map(stream::iter_ok)
+            .map_err(Error::from);
+        future::result(keys).flatten_stream().boxify()
+    }
+}
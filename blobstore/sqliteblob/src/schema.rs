@@ -0,0 +1,15 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macro in this module describes the schema of the blobs table. It is *not* the
+//! source of truth, so if the schema ever changes it will need to be updated here as well.
+
+table! {
+    blobs (key) {
+        key -> Text,
+        value -> Binary,
+    }
+}
@@ -0,0 +1,168 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use failure::{Compat, Error};
+use futures::future::{self, Future, Shared};
+use futures_ext::{BoxFuture, FutureExt};
+
+use Blobstore;
+use lru::{LruBlobs, LruStats};
+
+type SharedGet = Shared<BoxFuture<Option<Bytes>, Compat<Error>>>;
+
+struct CacheState {
+    blobs: LruBlobs,
+    // Gets that are currently being fetched from the underlying blobstore, so that concurrent
+    // gets for the same key share a single fetch.
+    inflight: HashMap<String, SharedGet>,
+}
+
+/// Blobstore wrapper keeping an in-process LRU cache of blobs.
+///
+/// The cache is bounded both by the total size of the cached blobs and by the number of entries.
+/// Concurrent gets for a key which isn't cached yet are merged into a single get to the
+/// underlying blobstore. Only blobs which exist are cached, so a missing key will be looked up
+/// again next time.
+#[derive(Clone)]
+pub struct CachingBlobstore<B> {
+    blobstore: B,
+    state: Arc<Mutex<CacheState>>,
+}
+
+impl<B: Blobstore> CachingBlobstore<B> {
+    pub fn new(blobstore: B, max_size: usize, max_entries: usize) -> Self {
+        Self {
+            blobstore,
+            state: Arc::new(Mutex::new(CacheState {
+                blobs: LruBlobs::new(max_size, max_entries),
+                inflight: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Total size in bytes of the blobs currently cached.
+    pub fn cached_size(&self) -> usize {
+        self.state.lock().expect("lock poison").blobs.size()
+    }
+
+    /// Number of blobs currently cached.
+    pub fn cached_entries(&self) -> usize {
+        self.state.lock().expect("lock poison").blobs.len()
+    }
+
+    /// Hits, misses and evictions of the cache so far.
+    pub fn stats(&self) -> LruStats {
+        self.state.lock().expect("lock poison").blobs.stats()
+    }
+}
+
+impl<B: Blobstore> Blobstore for CachingBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let mut state = self.state.lock().expect("lock poison");
+
+        if let Some(value) = state.blobs.get(&key) {
+            return future::ok(Some(value)).boxify();
+        }
+
+        let inflight = state.inflight.get(&key).cloned();
+        let fetch = match inflight {
+            Some(fetch) => fetch,
+            None => {
+                let fetch = self.blobstore
+                    .get(key.clone())
+                    .then({
+                        let state = self.state.clone();
+                        let key = key.clone();
+                        move |res| {
+                            let mut state = state.lock().expect("lock poison");
+                            state.inflight.remove(&key);
+                            if let Ok(Some(ref value)) = res {
+                                state.blobs.insert(key, value.clone());
+                            }
+                            res
+                        }
+                    })
+                    .map_err(Error::compat)
+                    .boxify()
+                    .shared();
+                state.inflight.insert(key, fetch.clone());
+                fetch
+            }
+        };
+
+        fetch
+            .map(|value| (*value).clone())
+            .map_err(|err| Error::from(err))
+            .boxify()
+    }
+
+    // The keys which aren't cached are got from the underlying blobstore at once, without being
+    // merged with concurrent gets of them.
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        let (cached, missing): (Vec<_>, Vec<_>) = {
+            let mut state = self.state.lock().expect("lock poison");
+            let cached: Vec<_> = keys.iter()
+                .map(|key| state.blobs.get(key))
+                .collect();
+            let missing = keys.iter()
+                .zip(cached.iter())
+                .filter(|&(_, value)| value.is_none())
+                .map(|(key, _)| key.clone())
+                .collect();
+            (cached, missing)
+        };
+        if missing.is_empty() {
+            return future::ok(cached).boxify();
+        }
+
+        let state = self.state.clone();
+        self.blobstore
+            .get_many(missing.clone())
+            .map(move |fetched| {
+                let mut state = state.lock().expect("lock poison");
+                for (key, value) in missing.into_iter().zip(fetched.iter()) {
+                    if let Some(ref value) = *value {
+                        state.blobs.insert(key, value.clone());
+                    }
+                }
+                let mut fetched = fetched.into_iter();
+                cached
+                    .into_iter()
+                    .map(|value| match value {
+                        Some(value) => Some(value),
+                        None => fetched.next().and_then(|value| value),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let state = self.state.clone();
+
+        self.blobstore
+            .put(key.clone(), value.clone())
+            .map(move |()| state.lock().expect("lock poison").blobs.insert(key, value))
+            .boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        if self.state
+            .lock()
+            .expect("lock poison")
+            .blobs
+            .contains_key(&key)
+        {
+            return future::ok(true).boxify();
+        }
+
+        self.blobstore.is_present(key)
+    }
+}
@@ -0,0 +1,128 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::str;
+
+use bytes::{Bytes, BytesMut};
+use failure::Error;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use {Blobstore, ErrorKind};
+
+const MANIFEST_MAGIC: &str = "mononoke-chunked-v1";
+// How many chunks to fetch ahead of the consumer in `get_stream`
+const PREFETCH_CHUNKS: usize = 4;
+
+fn chunk_key(key: &str, idx: usize) -> String {
+    format!("{}.chunk{}", key, idx)
+}
+
+fn encode_manifest(chunks: usize, size: u64) -> Bytes {
+    Bytes::from(format!("{}\n{} {}\n", MANIFEST_MAGIC, chunks, size))
+}
+
+fn decode_manifest(key: &str, manifest: &[u8]) -> Result<(usize, u64), Error> {
+    let bad = || ErrorKind::BadChunkManifest(key.to_string());
+
+    let manifest = str::from_utf8(manifest).map_err(|_| bad())?;
+    let mut lines = manifest.lines();
+    if lines.next() != Some(MANIFEST_MAGIC) {
+        return Err(bad().into());
+    }
+    let mut fields = lines.next().ok_or_else(|| bad())?.split(' ');
+    let chunks = fields
+        .next()
+        .and_then(|chunks| chunks.parse().ok())
+        .ok_or_else(|| bad())?;
+    let size = fields
+        .next()
+        .and_then(|size| size.parse().ok())
+        .ok_or_else(|| bad())?;
+    Ok((chunks, size))
+}
+
+/// Streaming access to very large blobs.
+///
+/// `put_stream` splits the data into chunks of a fixed size, each stored as its own blob, and
+/// then stores a small manifest blob under the requested key describing the chunks. The manifest
+/// is only written once all the chunks have been, so a blob either appears complete or not at
+/// all. `get_stream` reads the manifest and streams the chunks back in order.
+///
+/// `put_stream` fails if the chunk size is 0.
+///
+/// Blobs stored with `put_stream` must be read with `get_stream`: a plain `get` on the key only
+/// returns the manifest.
+pub trait ChunkedBlobstore: Blobstore + Clone {
+    fn put_stream<S>(&self, key: String, chunk_size: usize, data: S) -> BoxFuture<(), Error>
+    where
+        S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        if chunk_size == 0 {
+            return future::err(ErrorKind::ZeroChunkSize(key).into()).boxify();
+        }
+        let blobstore = self.clone();
+
+        let chunks = data.fold((BytesMut::new(), 0, 0), {
+            let blobstore = blobstore.clone();
+            let key = key.clone();
+            move |(mut buf, mut chunks, size): (BytesMut, usize, u64), bytes| {
+                buf.extend_from_slice(&bytes);
+                let mut puts = Vec::new();
+                while buf.len() >= chunk_size {
+                    let chunk = buf.split_to(chunk_size).freeze();
+                    puts.push(blobstore.put(chunk_key(&key, chunks), chunk));
+                    chunks += 1;
+                }
+                let size = size + bytes.len() as u64;
+                future::join_all(puts).map(move |_| (buf, chunks, size))
+            }
+        });
+
+        chunks
+            .and_then(move |(buf, chunks, size)| {
+                // Store whatever is left over as a final, shorter chunk
+                let last = if buf.is_empty() {
+                    future::ok(chunks).boxify()
+                } else {
+                    blobstore
+                        .put(chunk_key(&key, chunks), buf.freeze())
+                        .map(move |()| chunks + 1)
+                        .boxify()
+                };
+                last.and_then(move |chunks| blobstore.put(key, encode_manifest(chunks, size)))
+            })
+            .boxify()
+    }
+
+    /// Returns `None` if there is no blob stored under `key`.
+    fn get_stream(&self, key: String) -> BoxFuture<Option<BoxStream<Bytes, Error>>, Error> {
+        let blobstore = self.clone();
+
+        self.get(key.clone())
+            .and_then(move |manifest| {
+                let manifest = match manifest {
+                    Some(manifest) => manifest,
+                    None => return Ok(None),
+                };
+                let (chunks, _size) = decode_manifest(&key, &manifest)?;
+
+                let chunks = stream::iter_ok(0..chunks)
+                    .map(move |idx| {
+                        let chunk_key = chunk_key(&key, idx);
+                        blobstore.get(chunk_key.clone()).and_then(move |chunk| {
+                            chunk.ok_or_else(|| ErrorKind::NotFound(chunk_key).into())
+                        })
+                    })
+                    .buffered(PREFETCH_CHUNKS)
+                    .boxify();
+                Ok(Some(chunks))
+            })
+            .boxify()
+    }
+}
+
+impl<B: Blobstore + Clone> ChunkedBlobstore for B {}
@@ -11,6 +11,7 @@ extern crate bytes;
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
+extern crate linked_hash_map;
 extern crate tokio_core;
 
 use std::sync::Arc;
@@ -19,11 +20,26 @@ use bytes::Bytes;
 
 use failure::Error;
 use futures::{future, Future};
-use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::{BoxFuture, BoxStream, FutureExt};
+
+mod caching;
+mod chunked;
+mod lru;
+mod prefix;
+mod readonly;
+pub use caching::CachingBlobstore;
+pub use chunked::ChunkedBlobstore;
+pub use lru::{LruBlobs, LruStats};
+pub use prefix::PrefixBlobstore;
+pub use readonly::ReadOnlyBlobstore;
 
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Blob {} not found in blobstore", _0)] NotFound(String),
+    #[fail(display = "Chunk manifest of blob {} is malformed", _0)] BadChunkManifest(String),
+    #[fail(display = "Blob {} is corrupt: checksum mismatch", _0)] CorruptBlob(String),
+    #[fail(display = "Cannot put blob {}: blobstore is read-only", _0)] ReadOnlyPut(String),
+    #[fail(display = "Cannot put blob {} in chunks of 0 bytes", _0)] ZeroChunkSize(String),
 }
 
 /// Basic trait for the Blob Store interface
@@ -99,6 +115,23 @@ pub trait Blobstore: Send + Sync + 'static {
     fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
         self.get(key).map(|opt| opt.is_some()).boxify()
     }
+    // Whether each of `keys` is present, in order. Backends which can probe many keys in one
+    // request should override this.
+    fn are_present(&self, keys: Vec<String>) -> BoxFuture<Vec<bool>, Error> {
+        future::join_all(keys.into_iter().map(|key| self.is_present(key))).boxify()
+    }
+    // The blobs of each of `keys`, in order. Backends which can get many keys in one request
+    // should override this.
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        future::join_all(keys.into_iter().map(|key| self.get(key))).boxify()
+    }
+    // Put each of `blobs`. They may land in any order, and some may land if the others fail.
+    // Backends which can put many blobs in one request should override this.
+    fn put_many(&self, blobs: Vec<(String, Bytes)>) -> BoxFuture<(), Error> {
+        future::join_all(blobs.into_iter().map(|(key, value)| self.put(key, value)))
+            .map(|_| ())
+            .boxify()
+    }
     fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
         self.is_present(key.clone())
             .and_then(|present| {
@@ -112,6 +145,25 @@ pub trait Blobstore: Send + Sync + 'static {
     }
 }
 
+/// Blobstores which can list every key they hold.
+///
+/// This is separate from `Blobstore` because not every backend can support it efficiently (or at
+/// all). It's meant for maintenance tooling such as scrubbing and garbage collection, which need
+/// to walk all stored blobs rather than only the ones reachable from known keys. No order is
+/// guaranteed, and keys put concurrently with an enumeration may or may not be returned.
+pub trait Enumerable: Blobstore {
+    fn enumerate(&self) -> BoxStream<String, Error>;
+}
+
+/// Blobstores which blobs can be deleted from.
+///
+/// Blobs are otherwise immutable, and everything else assumes that a blob which was put stays
+/// there, so only garbage collection deletes blobs, once nothing refers to them anymore. Deleting
+/// a key which isn't present succeeds.
+pub trait Deletable: Blobstore {
+    fn delete(&self, key: String) -> BoxFuture<(), Error>;
+}
+
 impl Blobstore for Arc<Blobstore> {
     fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
         self.as_ref().get(key)
@@ -122,6 +174,15 @@ impl Blobstore for Arc<Blobstore> {
     fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
         self.as_ref().is_present(key)
     }
+    fn are_present(&self, keys: Vec<String>) -> BoxFuture<Vec<bool>, Error> {
+        self.as_ref().are_present(keys)
+    }
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        self.as_ref().get_many(keys)
+    }
+    fn put_many(&self, blobs: Vec<(String, Bytes)>) -> BoxFuture<(), Error> {
+        self.as_ref().put_many(blobs)
+    }
     fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
         self.as_ref().assert_present(key)
     }
@@ -137,6 +198,15 @@ impl Blobstore for Box<Blobstore> {
     fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
         self.as_ref().is_present(key)
     }
+    fn are_present(&self, keys: Vec<String>) -> BoxFuture<Vec<bool>, Error> {
+        self.as_ref().are_present(keys)
+    }
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        self.as_ref().get_many(keys)
+    }
+    fn put_many(&self, blobs: Vec<(String, Bytes)>) -> BoxFuture<(), Error> {
+        self.as_ref().put_many(blobs)
+    }
     fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
         self.as_ref().assert_present(key)
     }
@@ -0,0 +1,104 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bytes::Bytes;
+use linked_hash_map::LinkedHashMap;
+
+/// Counters of an `LruBlobs`, along with its current size.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LruStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Total size in bytes of the blobs held
+    pub size: usize,
+    pub entries: usize,
+}
+
+/// Blobs held in memory, bounded both by their total size and by their number, the least
+/// recently used being evicted first. A blob larger than the size bound is never held, as it would
+/// just evict everything else and then itself.
+pub struct LruBlobs {
+    lru: LinkedHashMap<String, Bytes>,
+    size: usize,
+    max_size: usize,
+    max_entries: usize,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl LruBlobs {
+    pub fn new(max_size: usize, max_entries: usize) -> Self {
+        Self {
+            lru: LinkedHashMap::new(),
+            size: 0,
+            max_size,
+            max_entries,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// The blob under `key`, which becomes the most recently used.
+    pub fn get(&mut self, key: &str) -> Option<Bytes> {
+        let value = self.lru.get_refresh(key).cloned();
+        if value.is_some() {
+            self.hits += 1;
+        } else {
+            self.misses += 1;
+        }
+        value
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.lru.contains_key(key)
+    }
+
+    pub fn insert(&mut self, key: String, value: Bytes) {
+        if value.len() > self.max_size {
+            return;
+        }
+
+        self.size += value.len();
+        if let Some(old) = self.lru.insert(key, value) {
+            self.size -= old.len();
+        }
+
+        while self.size > self.max_size || self.lru.len() > self.max_entries {
+            match self.lru.pop_front() {
+                Some((_, evicted)) => {
+                    self.size -= evicted.len();
+                    self.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    pub fn keys(&self) -> Vec<String> {
+        self.lru.keys().cloned().collect()
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    pub fn len(&self) -> usize {
+        self.lru.len()
+    }
+
+    pub fn stats(&self) -> LruStats {
+        LruStats {
+            hits: self.hits,
+            misses: self.misses,
+            evictions: self.evictions,
+            size: self.size,
+            entries: self.lru.len(),
+        }
+    }
+}
@@ -0,0 +1,71 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bytes::Bytes;
+use failure::Error;
+use futures_ext::BoxFuture;
+
+use Blobstore;
+
+/// Blobstore wrapper that prepends a fixed prefix to every key.
+///
+/// This allows several repos to share a single underlying store (a Manifold bucket, a RocksDB
+/// instance) without their keys colliding, as long as each repo uses a distinct prefix.
+#[derive(Clone, Debug)]
+pub struct PrefixBlobstore<B> {
+    blobstore: B,
+    prefix: String,
+}
+
+impl<B: Blobstore> PrefixBlobstore<B> {
+    pub fn new<S: Into<String>>(blobstore: B, prefix: S) -> Self {
+        Self {
+            blobstore,
+            prefix: prefix.into(),
+        }
+    }
+
+    #[inline]
+    pub fn prepend(&self, key: String) -> String {
+        [&self.prefix, key.as_str()].concat()
+    }
+}
+
+impl<B: Blobstore> Blobstore for PrefixBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore.get(self.prepend(key))
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.blobstore.put(self.prepend(key), value)
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(self.prepend(key))
+    }
+
+    fn are_present(&self, keys: Vec<String>) -> BoxFuture<Vec<bool>, Error> {
+        let keys = keys.into_iter().map(|key| self.prepend(key)).collect();
+        self.blobstore.are_present(keys)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        let keys = keys.into_iter().map(|key| self.prepend(key)).collect();
+        self.blobstore.get_many(keys)
+    }
+
+    fn put_many(&self, blobs: Vec<(String, Bytes)>) -> BoxFuture<(), Error> {
+        let blobs = blobs
+            .into_iter()
+            .map(|(key, value)| (self.prepend(key), value))
+            .collect();
+        self.blobstore.put_many(blobs)
+    }
+
+    fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.assert_present(self.prepend(key))
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bytes::Bytes;
+use failure::Error;
+use futures::future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use {Blobstore, ErrorKind};
+
+/// Blobstore wrapper rejecting all writes.
+///
+/// Used for read-only mirrors of a repo, which must never modify the underlying store. Every
+/// `put` fails with `ErrorKind::ReadOnlyPut`, even if the blob is already present.
+#[derive(Clone, Debug)]
+pub struct ReadOnlyBlobstore<B> {
+    blobstore: B,
+}
+
+impl<B: Blobstore> ReadOnlyBlobstore<B> {
+    pub fn new(blobstore: B) -> Self {
+        Self { blobstore }
+    }
+}
+
+impl<B: Blobstore> Blobstore for ReadOnlyBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.blobstore.get(key)
+    }
+
+    fn put(&self, key: String, _value: Bytes) -> BoxFuture<(), Error> {
+        future::err(ErrorKind::ReadOnlyPut(key).into()).boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        self.blobstore.is_present(key)
+    }
+
+    fn are_present(&self, keys: Vec<String>) -> BoxFuture<Vec<bool>, Error> {
+        self.blobstore.are_present(keys)
+    }
+
+    fn get_many(&self, keys: Vec<String>) -> BoxFuture<Vec<Option<Bytes>>, Error> {
+        self.blobstore.get_many(keys)
+    }
+
+    fn assert_present(&self, key: String) -> BoxFuture<(), Error> {
+        self.blobstore.assert_present(key)
+    }
+}
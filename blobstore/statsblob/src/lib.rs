@@ -0,0 +1,126 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate futures_stats;
+#[macro_use]
+extern crate stats;
+
+extern crate blobstore;
+
+use bytes::Bytes;
+use failure::Error;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+use futures_stats::Timed;
+use stats::prelude::*;
+
+use blobstore::Blobstore;
+
+define_stats! {
+    prefix = "mononoke.blobstore";
+    get: dynamic_timeseries("{}.get", (backend: &'static str); RATE, SUM),
+    get_missing: dynamic_timeseries("{}.get.missing", (backend: &'static str); RATE, SUM),
+    get_err: dynamic_timeseries("{}.get.err", (backend: &'static str); RATE, SUM),
+    get_time_ms: dynamic_histogram(
+        "{}.get.time_ms", (backend: &'static str);
+        10, 0, 10_000, AVG; P 50; P 95; P 99
+    ),
+    get_size: dynamic_histogram(
+        "{}.get.size", (backend: &'static str);
+        400, 0, 100_000, AVG, SUM, COUNT; P 50; P 95; P 99
+    ),
+    put: dynamic_timeseries("{}.put", (backend: &'static str); RATE, SUM),
+    put_err: dynamic_timeseries("{}.put.err", (backend: &'static str); RATE, SUM),
+    put_time_ms: dynamic_histogram(
+        "{}.put.time_ms", (backend: &'static str);
+        10, 0, 10_000, AVG; P 50; P 95; P 99
+    ),
+    put_size: dynamic_histogram(
+        "{}.put.size", (backend: &'static str);
+        400, 0, 100_000, AVG, SUM, COUNT; P 50; P 95; P 99
+    ),
+    is_present: dynamic_timeseries("{}.is_present", (backend: &'static str); RATE, SUM),
+    is_present_err: dynamic_timeseries("{}.is_present.err", (backend: &'static str); RATE, SUM),
+    is_present_time_ms: dynamic_histogram(
+        "{}.is_present.time_ms", (backend: &'static str);
+        10, 0, 10_000, AVG; P 50; P 95; P 99
+    ),
+}
+
+/// Blobstore wrapper recording the latency, outcome and value size of every operation.
+///
+/// Stats are exported under `mononoke.blobstore.<backend>`, so that several backends (or
+/// several layers of the same stack) can be told apart.
+#[derive(Clone, Debug)]
+pub struct StatsBlobstore<B> {
+    blobstore: B,
+    backend: &'static str,
+}
+
+impl<B: Blobstore> StatsBlobstore<B> {
+    pub fn new(blobstore: B, backend: &'static str) -> Self {
+        Self { blobstore, backend }
+    }
+}
+
+impl<B: Blobstore> Blobstore for StatsBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let backend = self.backend;
+        STATS::get.add_value(1, (backend,));
+
+        self.blobstore
+            .get(key)
+            .timed(move |stats, result| {
+                STATS::get_time_ms.add_value(stats.completion_time.num_milliseconds(), (backend,));
+                match result {
+                    Ok(&Some(ref value)) => {
+                        STATS::get_size.add_value(value.len() as i64, (backend,))
+                    }
+                    Ok(&None) => STATS::get_missing.add_value(1, (backend,)),
+                    Err(_) => STATS::get_err.add_value(1, (backend,)),
+                }
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let backend = self.backend;
+        STATS::put.add_value(1, (backend,));
+        STATS::put_size.add_value(value.len() as i64, (backend,));
+
+        self.blobstore
+            .put(key, value)
+            .timed(move |stats, result| {
+                STATS::put_time_ms.add_value(stats.completion_time.num_milliseconds(), (backend,));
+                if result.is_err() {
+                    STATS::put_err.add_value(1, (backend,));
+                }
+            })
+            .boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        let backend = self.backend;
+        STATS::is_present.add_value(1, (backend,));
+
+        self.blobstore
+            .is_present(key)
+            .timed(move |stats, result| {
+                STATS::is_present_time_ms
+                    .add_value(stats.completion_time.num_milliseconds(), (backend,));
+                if result.is_err() {
+                    STATS::is_present_err.add_value(1, (backend,));
+                }
+            })
+            .boxify()
+    }
+}
@@ -10,6 +10,7 @@
 #![feature(never_type)]
 
 extern crate bytes;
+#[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_ext;
@@ -17,18 +18,44 @@ extern crate tempdir;
 extern crate tokio_core;
 
 extern crate blobstore;
+extern crate checksumblob;
+extern crate compressedblob;
 extern crate fileblob;
 extern crate memblob;
+extern crate memcacheblob;
+extern crate multiplexedblob;
+extern crate retryingblob;
 extern crate rocksblob;
+extern crate sqliteblob;
+extern crate statsblob;
+extern crate throttledblob;
+
+use std::fs::File;
+use std::io::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 use bytes::Bytes;
-use futures::Future;
+use failure::Error;
+use futures::{stream, Future, Stream};
+use futures::future::err;
+use futures_ext::{BoxFuture, FutureExt};
 use tempdir::TempDir;
 
-use blobstore::Blobstore;
+use blobstore::{Blobstore, CachingBlobstore, ChunkedBlobstore, Deletable, Enumerable, ErrorKind,
+                LruStats, PrefixBlobstore, ReadOnlyBlobstore};
+use checksumblob::ChecksumBlobstore;
+use compressedblob::{Codec, CompressingBlobstore};
 use fileblob::Fileblob;
-use memblob::EagerMemblob;
+use memblob::{BoundedMemblob, EagerMemblob};
+use memcacheblob::{Memcache, MemcacheBlob};
+use multiplexedblob::{MultiplexedBlobstore, WriteQuorum};
+use retryingblob::{RetryPolicy, RetryingBlobstore};
 use rocksblob::Rocksblob;
+use sqliteblob::Sqliteblob;
+use statsblob::StatsBlobstore;
+use throttledblob::{ThrottleLimits, ThrottledBlobstore};
 
 fn simple<B>(blobstore: B)
 where
@@ -53,6 +80,94 @@ where
     assert!(out.is_none());
 }
 
+fn present<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    blobstore
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put failed");
+
+    let keys = vec!["missing".to_string(), "foo".to_string(), "foo".to_string()];
+    let out = blobstore.are_present(keys).wait().expect("are_present failed");
+
+    assert_eq!(out, vec![false, true, true]);
+}
+
+fn many<B>(blobstore: B)
+where
+    B: Blobstore,
+{
+    blobstore
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put failed");
+
+    let keys = vec!["missing".to_string(), "foo".to_string(), "foo".to_string()];
+    let out = blobstore.get_many(keys).wait().expect("get_many failed");
+
+    let bar = Some(Bytes::from_static(b"bar"));
+    assert_eq!(out, vec![None, bar.clone(), bar]);
+
+    let blobs = vec![
+        ("one".to_string(), Bytes::from_static(b"1")),
+        ("two".to_string(), Bytes::from_static(b"2")),
+    ];
+    blobstore.put_many(blobs).wait().expect("put_many failed");
+
+    let keys = vec!["one".to_string(), "two".to_string()];
+    let out = blobstore.get_many(keys).wait().expect("get_many failed");
+    assert_eq!(
+        out,
+        vec![Some(Bytes::from_static(b"1")), Some(Bytes::from_static(b"2"))]
+    );
+}
+
+fn enumerable<B>(blobstore: B)
+where
+    B: Enumerable,
+{
+    let keys = vec!["foo".to_string(), "bar/baz".to_string(), "quux qu%x%2F".to_string()];
+    for key in &keys {
+        blobstore
+            .put(key.clone(), Bytes::from_static(b"value"))
+            .wait()
+            .expect("put failed");
+    }
+
+    let mut out = blobstore
+        .enumerate()
+        .collect()
+        .wait()
+        .expect("enumerate failed");
+    out.sort();
+    let mut expected = keys;
+    expected.sort();
+    assert_eq!(out, expected);
+}
+
+fn deletable<B>(blobstore: B)
+where
+    B: Enumerable + Deletable,
+{
+    for key in &["foo", "bar"] {
+        blobstore
+            .put(key.to_string(), Bytes::from_static(b"value"))
+            .wait()
+            .expect("put failed");
+    }
+
+    blobstore.delete("foo".into()).wait().expect("delete failed");
+    // Deleting a missing key is fine
+    blobstore.delete("foo".into()).wait().expect("delete failed");
+
+    assert!(!blobstore.is_present("foo".into()).wait().unwrap());
+    assert!(blobstore.is_present("bar".into()).wait().unwrap());
+    let out = blobstore.enumerate().collect().wait().expect("enumerate failed");
+    assert_eq!(out, vec!["bar".to_string()]);
+}
+
 fn boxable<B>(blobstore: B)
 where
     B: Blobstore,
@@ -89,6 +204,18 @@ macro_rules! blobstore_test_impl {
                 missing($new_cb(&state));
             }
 
+            #[test]
+            fn test_present() {
+                let state = $state;
+                present($new_cb(&state));
+            }
+
+            #[test]
+            fn test_many() {
+                let state = $state;
+                many($new_cb(&state));
+            }
+
             #[test]
             fn test_boxable() {
                 let state = $state;
@@ -98,6 +225,29 @@ macro_rules! blobstore_test_impl {
     }
 }
 
+macro_rules! enumerable_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_enumerable() {
+                let state = $state;
+                enumerable($new_cb(&state));
+            }
+
+            #[test]
+            fn test_deletable() {
+                let state = $state;
+                deletable($new_cb(&state));
+            }
+        }
+    }
+}
+
 blobstore_test_impl! {
     memblob_test => {
         state: (),
@@ -106,6 +256,46 @@ blobstore_test_impl! {
     }
 }
 
+blobstore_test_impl! {
+    boundedmemblob_test => {
+        state: (),
+        new: |_| BoundedMemblob::new(1024, 16),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_boundedmemblob_eviction() {
+    // Room for only two of the three blobs
+    let blobstore = BoundedMemblob::new(6, 16);
+    for key in &["foo", "bar", "baz"] {
+        blobstore
+            .put(key.to_string(), Bytes::from(key.as_bytes()))
+            .wait()
+            .unwrap();
+    }
+
+    assert_eq!(blobstore.get("foo".to_string()).wait().unwrap(), None);
+    assert_eq!(
+        blobstore.get("baz".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"baz"))
+    );
+    let mut keys = blobstore.enumerate().collect().wait().unwrap();
+    keys.sort();
+    assert_eq!(keys, vec!["bar".to_string(), "baz".to_string()]);
+
+    assert_eq!(
+        blobstore.stats(),
+        LruStats {
+            hits: 1,
+            misses: 1,
+            evictions: 1,
+            size: 6,
+            entries: 2,
+        }
+    );
+}
+
 blobstore_test_impl! {
     fileblob_test => {
         state: TempDir::new("fileblob_test").unwrap(),
@@ -114,6 +304,38 @@ blobstore_test_impl! {
     }
 }
 
+#[test]
+fn test_fileblob_legacy_key_encoding() {
+    let dir = TempDir::new("fileblob_legacy_test").unwrap();
+    // Before '/' and '%' were escaped, "foo%bar" was stored verbatim.
+    File::create(dir.path().join("blob-foo%bar"))
+        .unwrap()
+        .write_all(b"old")
+        .unwrap();
+    let blobstore = Fileblob::open(&dir).unwrap();
+    let key = "foo%bar".to_string();
+
+    assert_eq!(
+        blobstore.get(key.clone()).wait().unwrap(),
+        Some(Bytes::from_static(b"old"))
+    );
+    assert!(blobstore.is_present(key.clone()).wait().unwrap());
+
+    blobstore
+        .put(key.clone(), Bytes::from_static(b"new"))
+        .wait()
+        .unwrap();
+    assert_eq!(
+        blobstore.get(key.clone()).wait().unwrap(),
+        Some(Bytes::from_static(b"new"))
+    );
+    assert!(!dir.path().join("blob-foo%bar").exists());
+    assert_eq!(blobstore.enumerate().collect().wait().unwrap(), vec![key.clone()]);
+
+    blobstore.delete(key.clone()).wait().unwrap();
+    assert_eq!(blobstore.get(key).wait().unwrap(), None);
+}
+
 blobstore_test_impl! {
     rocksblob_test => {
         state: TempDir::new("rocksblob_test").unwrap(),
@@ -122,3 +344,654 @@ blobstore_test_impl! {
         persistent: true,
     }
 }
+
+blobstore_test_impl! {
+    sqliteblob_test => {
+        state: TempDir::new("sqliteblob_test").unwrap(),
+        new: |dir: &TempDir| Sqliteblob::create(dir.path().join("blobs.sqlite")).unwrap(),
+        persistent: true,
+    }
+}
+
+enumerable_test_impl! {
+    memblob_enumerable_test => {
+        state: (),
+        new: |_| EagerMemblob::new(),
+    }
+}
+
+enumerable_test_impl! {
+    fileblob_enumerable_test => {
+        state: TempDir::new("fileblob_enumerable_test").unwrap(),
+        new: |dir| Fileblob::open(dir).unwrap(),
+    }
+}
+
+enumerable_test_impl! {
+    rocksblob_enumerable_test => {
+        state: TempDir::new("rocksblob_enumerable_test").unwrap(),
+        new: |dir| Rocksblob::create(dir).unwrap(),
+    }
+}
+
+enumerable_test_impl! {
+    sqliteblob_enumerable_test => {
+        state: (),
+        new: |_| Sqliteblob::in_memory().unwrap(),
+    }
+}
+
+blobstore_test_impl! {
+    multiplexedblob_test => {
+        state: (),
+        new: |_| MultiplexedBlobstore::new(
+            vec![Arc::new(EagerMemblob::new()), Arc::new(EagerMemblob::new())],
+            WriteQuorum::All,
+        ).unwrap(),
+        persistent: false,
+    }
+}
+
+/// Blobstore that fails every operation, for testing error handling in wrappers.
+struct FailingBlobstore;
+
+impl Blobstore for FailingBlobstore {
+    fn get(&self, _key: String) -> BoxFuture<Option<Bytes>, Error> {
+        err(format_err!("get failed")).boxify()
+    }
+
+    fn put(&self, _key: String, _value: Bytes) -> BoxFuture<(), Error> {
+        err(format_err!("put failed")).boxify()
+    }
+}
+
+#[test]
+fn test_multiplexed_quorum() {
+    let good = EagerMemblob::new();
+    let blobstores: Vec<Arc<Blobstore>> = vec![Arc::new(FailingBlobstore), Arc::new(good.clone())];
+
+    let all = MultiplexedBlobstore::new(blobstores.clone(), WriteQuorum::All).unwrap();
+    assert!(
+        all.put("foo".to_string(), Bytes::from_static(b"bar"))
+            .wait()
+            .is_err()
+    );
+
+    let one = MultiplexedBlobstore::new(blobstores, WriteQuorum::AtLeast(1)).unwrap();
+    one.put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put with quorum of one failed");
+
+    // The failing blobstore is skipped, and the blob served from the working one
+    let out = one.get("foo".to_string()).wait().expect("get failed");
+    assert_eq!(out, Some(Bytes::from_static(b"bar")));
+    let out = good.get("foo".to_string()).wait().expect("get failed");
+    assert_eq!(out, Some(Bytes::from_static(b"bar")));
+
+    assert!(
+        MultiplexedBlobstore::new(vec![Arc::new(good)], WriteQuorum::AtLeast(2)).is_err()
+    );
+}
+
+#[test]
+fn test_multiplexed_get_all_failed() {
+    let blobstores: Vec<Arc<Blobstore>> = vec![Arc::new(FailingBlobstore)];
+    let blobstore = MultiplexedBlobstore::new(blobstores, WriteQuorum::All).unwrap();
+
+    assert!(blobstore.get("foo".to_string()).wait().is_err());
+}
+
+blobstore_test_impl! {
+    cachingblob_test => {
+        state: (),
+        new: |_| CachingBlobstore::new(EagerMemblob::new(), 1024, 16),
+        persistent: false,
+    }
+}
+
+/// Blobstore counting the gets that reach it.
+#[derive(Clone)]
+struct CountingBlobstore {
+    inner: EagerMemblob,
+    gets: Arc<AtomicUsize>,
+}
+
+impl Blobstore for CountingBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.gets.fetch_add(1, Ordering::Relaxed);
+        self.inner.get(key)
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.inner.put(key, value)
+    }
+}
+
+#[test]
+fn test_caching_hits_and_eviction() {
+    let inner = EagerMemblob::new();
+    inner
+        .put("foo".to_string(), Bytes::from_static(b"foo"))
+        .wait()
+        .unwrap();
+    inner
+        .put("bar".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+    let gets = Arc::new(AtomicUsize::new(0));
+    let counting = CountingBlobstore {
+        inner,
+        gets: gets.clone(),
+    };
+    // Room for only one of the two blobs
+    let blobstore = CachingBlobstore::new(counting, 4, 16);
+
+    // Concurrent gets are merged, and the second round is served from the cache
+    let first = blobstore.get("foo".to_string());
+    let second = blobstore.get("foo".to_string());
+    let (first, second) = first.join(second).wait().unwrap();
+    assert_eq!(first, Some(Bytes::from_static(b"foo")));
+    assert_eq!(second, Some(Bytes::from_static(b"foo")));
+    assert_eq!(gets.load(Ordering::Relaxed), 1);
+    blobstore.get("foo".to_string()).wait().unwrap();
+    assert_eq!(gets.load(Ordering::Relaxed), 1);
+
+    // Fetching "bar" evicts "foo"
+    blobstore.get("bar".to_string()).wait().unwrap();
+    assert_eq!(blobstore.cached_entries(), 1);
+    assert_eq!(blobstore.cached_size(), 3);
+    blobstore.get("foo".to_string()).wait().unwrap();
+    assert_eq!(gets.load(Ordering::Relaxed), 3);
+
+    // Missing blobs aren't cached
+    assert_eq!(blobstore.get("baz".to_string()).wait().unwrap(), None);
+    assert_eq!(blobstore.get("baz".to_string()).wait().unwrap(), None);
+    assert_eq!(gets.load(Ordering::Relaxed), 5);
+}
+
+#[test]
+fn test_caching_get_many() {
+    let inner = EagerMemblob::new();
+    inner
+        .put("foo".to_string(), Bytes::from_static(b"foo"))
+        .wait()
+        .unwrap();
+    inner
+        .put("bar".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+    let gets = Arc::new(AtomicUsize::new(0));
+    let counting = CountingBlobstore {
+        inner,
+        gets: gets.clone(),
+    };
+    let blobstore = CachingBlobstore::new(counting, 1024, 16);
+
+    blobstore.get("foo".to_string()).wait().unwrap();
+    assert_eq!(gets.load(Ordering::Relaxed), 1);
+
+    // Only the blobs which aren't cached are got, and they are cached in turn
+    let keys = vec!["bar".to_string(), "foo".to_string(), "baz".to_string()];
+    let out = blobstore.get_many(keys).wait().unwrap();
+    assert_eq!(
+        out,
+        vec![
+            Some(Bytes::from_static(b"bar")),
+            Some(Bytes::from_static(b"foo")),
+            None,
+        ]
+    );
+    assert_eq!(gets.load(Ordering::Relaxed), 3);
+    blobstore.get("bar".to_string()).wait().unwrap();
+    assert_eq!(gets.load(Ordering::Relaxed), 3);
+}
+
+/// Memcache fake backed by a memblob.
+struct FakeMemcache(EagerMemblob);
+
+impl Memcache for FakeMemcache {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        self.0.get(key)
+    }
+
+    fn set(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        self.0.put(key, value)
+    }
+}
+
+/// Memcache which is always down.
+struct BrokenMemcache;
+
+impl Memcache for BrokenMemcache {
+    fn get(&self, _key: String) -> BoxFuture<Option<Bytes>, Error> {
+        err(format_err!("memcache get failed")).boxify()
+    }
+
+    fn set(&self, _key: String, _value: Bytes) -> BoxFuture<(), Error> {
+        err(format_err!("memcache set failed")).boxify()
+    }
+}
+
+blobstore_test_impl! {
+    memcacheblob_test => {
+        state: (),
+        new: |_| MemcacheBlob::new(
+            EagerMemblob::new(),
+            FakeMemcache(EagerMemblob::new()),
+            "test.",
+        ),
+        persistent: false,
+    }
+}
+
+blobstore_test_impl! {
+    memcacheblob_broken_test => {
+        state: (),
+        new: |_| MemcacheBlob::new(EagerMemblob::new(), BrokenMemcache, "test."),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_memcache_read_through() {
+    let inner = EagerMemblob::new();
+    let cache = EagerMemblob::new();
+    inner
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+    let blobstore = MemcacheBlob::new(inner, FakeMemcache(cache.clone()), "test.");
+
+    assert_eq!(cache.get("test.foo".to_string()).wait().unwrap(), None);
+    let out = blobstore.get("foo".to_string()).wait().unwrap();
+    assert_eq!(out, Some(Bytes::from_static(b"bar")));
+    // The miss populated memcache
+    let out = cache.get("test.foo".to_string()).wait().unwrap();
+    assert_eq!(out, Some(Bytes::from_static(b"bar")));
+
+    // Puts are written through
+    blobstore
+        .put("baz".to_string(), Bytes::from_static(b"quux"))
+        .wait()
+        .unwrap();
+    let out = cache.get("test.baz".to_string()).wait().unwrap();
+    assert_eq!(out, Some(Bytes::from_static(b"quux")));
+}
+
+blobstore_test_impl! {
+    compressedblob_zstd_test => {
+        state: (),
+        new: |_| CompressingBlobstore::new(EagerMemblob::new(), Codec::Zstd { level: 0 }),
+        persistent: false,
+    }
+}
+
+blobstore_test_impl! {
+    compressedblob_lz4_test => {
+        state: (),
+        new: |_| CompressingBlobstore::new(EagerMemblob::new(), Codec::Lz4),
+        persistent: false,
+    }
+}
+
+blobstore_test_impl! {
+    compressedblob_gzip_test => {
+        state: (),
+        new: |_| CompressingBlobstore::new(EagerMemblob::new(), Codec::Gzip { level: 6 }),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_compressed_codecs() {
+    let inner = EagerMemblob::new();
+    let value = Bytes::from(vec![b'a'; 4096]);
+    let codecs = vec![
+        Codec::Raw,
+        Codec::Zstd { level: 0 },
+        Codec::Lz4,
+        Codec::Gzip { level: 6 },
+    ];
+
+    for (idx, codec) in codecs.iter().enumerate() {
+        let key = format!("key{}", idx);
+        CompressingBlobstore::new(inner.clone(), *codec)
+            .put(key.clone(), value.clone())
+            .wait()
+            .unwrap();
+        let stored = inner.get(key.clone()).wait().unwrap().unwrap();
+        if *codec != Codec::Raw {
+            assert!(stored.len() < value.len(), "{:?} didn't compress", codec);
+        }
+
+        // The header lets a store configured with any codec read the blob back
+        let reader = CompressingBlobstore::new(inner.clone(), Codec::Lz4);
+        assert_eq!(reader.get(key).wait().unwrap(), Some(value.clone()));
+    }
+
+    // Blobs not written through the adapter are rejected
+    inner
+        .put("plain".to_string(), Bytes::from_static(b"plain"))
+        .wait()
+        .unwrap();
+    let reader = CompressingBlobstore::new(inner, Codec::Raw);
+    assert!(reader.get("plain".to_string()).wait().is_err());
+}
+
+blobstore_test_impl! {
+    prefixblob_test => {
+        state: (),
+        new: |_| PrefixBlobstore::new(EagerMemblob::new(), "repo0000."),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_prefix_isolation() {
+    let inner = EagerMemblob::new();
+    let repo1 = PrefixBlobstore::new(inner.clone(), "repo1.");
+    let repo2 = PrefixBlobstore::new(inner.clone(), "repo2.");
+
+    repo1
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+
+    assert_eq!(
+        repo1.get("foo".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"bar"))
+    );
+    assert_eq!(repo2.get("foo".to_string()).wait().unwrap(), None);
+    assert_eq!(
+        inner.get("repo1.foo".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"bar"))
+    );
+}
+
+#[test]
+fn test_readonly_rejects_put() {
+    let inner = EagerMemblob::new();
+    inner
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+    let blobstore = ReadOnlyBlobstore::new(inner.clone());
+
+    assert_eq!(
+        blobstore.get("foo".to_string()).wait().unwrap(),
+        Some(Bytes::from_static(b"bar"))
+    );
+    assert!(blobstore.is_present("foo".to_string()).wait().unwrap());
+
+    let err = blobstore
+        .put("baz".to_string(), Bytes::from_static(b"quux"))
+        .wait()
+        .expect_err("put succeeded on read-only blobstore");
+    match err.downcast::<ErrorKind>() {
+        Ok(ErrorKind::ReadOnlyPut(key)) => assert_eq!(key, "baz"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(inner.get("baz".to_string()).wait().unwrap(), None);
+}
+
+#[test]
+fn test_chunked_roundtrip() {
+    let blobstore = EagerMemblob::new();
+    let data: Vec<u8> = (0..100u8).collect();
+    // Input pieces don't line up with chunk boundaries
+    let pieces: Vec<Result<Bytes, Error>> = data.chunks(7).map(|c| Ok(Bytes::from(c))).collect();
+
+    blobstore
+        .put_stream("big".to_string(), 16, stream::iter_result(pieces))
+        .wait()
+        .expect("put_stream failed");
+
+    // 100 bytes in chunks of 16 is 6 full chunks and one of 4 bytes
+    let last = blobstore.get("big.chunk6".to_string()).wait().unwrap();
+    assert_eq!(last.map(|c| c.len()), Some(4));
+    assert_eq!(blobstore.get("big.chunk7".to_string()).wait().unwrap(), None);
+
+    let chunks = blobstore
+        .get_stream("big".to_string())
+        .wait()
+        .expect("get_stream failed")
+        .expect("missing");
+    let out: Vec<u8> = chunks
+        .collect()
+        .wait()
+        .expect("reading chunks failed")
+        .into_iter()
+        .flat_map(|chunk| chunk.to_vec())
+        .collect();
+    assert_eq!(out, data);
+
+    assert!(
+        blobstore
+            .get_stream("missing".to_string())
+            .wait()
+            .unwrap()
+            .is_none()
+    );
+}
+
+#[test]
+fn test_chunked_zero_chunk_size() {
+    let blobstore = EagerMemblob::new();
+    let pieces: Vec<Result<Bytes, Error>> = vec![Ok(Bytes::from_static(b"data"))];
+
+    let err = blobstore
+        .put_stream("big".to_string(), 0, stream::iter_result(pieces))
+        .wait()
+        .expect_err("put_stream succeeded with a chunk size of 0");
+    match err.downcast::<ErrorKind>() {
+        Ok(ErrorKind::ZeroChunkSize(key)) => assert_eq!(key, "big"),
+        other => panic!("unexpected result {:?}", other),
+    }
+    assert_eq!(blobstore.get("big".to_string()).wait().unwrap(), None);
+}
+
+blobstore_test_impl! {
+    checksumblob_test => {
+        state: (),
+        new: |_| ChecksumBlobstore::new(EagerMemblob::new()),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_checksum_corruption() {
+    let inner = EagerMemblob::new();
+    let blobstore = ChecksumBlobstore::new(inner.clone());
+
+    blobstore
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .unwrap();
+    let mut stored = inner.get("foo".to_string()).wait().unwrap().unwrap().to_vec();
+    let last = stored.len() - 1;
+    stored[last] ^= 0xff;
+    inner
+        .put("foo".to_string(), Bytes::from(stored))
+        .wait()
+        .unwrap();
+
+    let err = blobstore
+        .get("foo".to_string())
+        .wait()
+        .expect_err("corruption not detected");
+    match err.downcast::<ErrorKind>() {
+        Ok(ErrorKind::CorruptBlob(key)) => assert_eq!(key, "foo"),
+        other => panic!("unexpected result {:?}", other),
+    }
+}
+
+blobstore_test_impl! {
+    throttledblob_test => {
+        state: (),
+        new: |_| ThrottledBlobstore::new(
+            EagerMemblob::new(),
+            ThrottleLimits {
+                max_inflight: Some(1),
+                max_qps: Some(1000),
+            },
+        ).unwrap(),
+        persistent: false,
+    }
+}
+
+#[test]
+fn test_throttled_qps() {
+    let blobstore = ThrottledBlobstore::new(
+        EagerMemblob::new(),
+        ThrottleLimits {
+            max_inflight: None,
+            max_qps: Some(20),
+        },
+    ).unwrap();
+
+    let start = Instant::now();
+    let gets: Vec<_> = (0..10)
+        .map(|i| blobstore.get(format!("key{}", i)))
+        .collect();
+    futures::future::join_all(gets).wait().unwrap();
+    // 10 requests at 20 per second can't all start within less than 450ms
+    assert!(start.elapsed() >= Duration::from_millis(400));
+}
+
+#[test]
+fn test_throttled_zero_limits() {
+    let zero_inflight = ThrottleLimits {
+        max_inflight: Some(0),
+        max_qps: None,
+    };
+    assert!(ThrottledBlobstore::new(EagerMemblob::new(), zero_inflight).is_err());
+    let zero_qps = ThrottleLimits {
+        max_inflight: None,
+        max_qps: Some(0),
+    };
+    assert!(ThrottledBlobstore::new(EagerMemblob::new(), zero_qps).is_err());
+}
+
+#[test]
+fn test_throttled_inflight() {
+    let blobstore = ThrottledBlobstore::new(
+        EagerMemblob::new(),
+        ThrottleLimits {
+            max_inflight: Some(2),
+            max_qps: None,
+        },
+    ).unwrap();
+
+    let puts: Vec<_> = (0..10)
+        .map(|i| blobstore.put(format!("key{}", i), Bytes::from_static(b"value")))
+        .collect();
+    futures::future::join_all(puts).wait().unwrap();
+
+    // Permits were all handed back, so further requests still go through
+    let out = blobstore.get("key9".to_string()).wait().unwrap();
+    assert_eq!(out, Some(Bytes::from_static(b"value")));
+}
+
+blobstore_test_impl! {
+    retryingblob_test => {
+        state: (),
+        new: |_| RetryingBlobstore::new(EagerMemblob::new(), RetryPolicy::default()).unwrap(),
+        persistent: false,
+    }
+}
+
+/// Blobstore whose first `failures` operations fail.
+#[derive(Clone)]
+struct FlakyBlobstore {
+    inner: EagerMemblob,
+    failures: Arc<AtomicUsize>,
+}
+
+impl FlakyBlobstore {
+    fn fail(&self) -> bool {
+        let failures = self.failures.load(Ordering::Relaxed);
+        if failures > 0 {
+            self.failures.store(failures - 1, Ordering::Relaxed);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl Blobstore for FlakyBlobstore {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        if self.fail() {
+            err(format_err!("flaky get")).boxify()
+        } else {
+            self.inner.get(key)
+        }
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        if self.fail() {
+            err(format_err!("flaky put")).boxify()
+        } else {
+            self.inner.put(key, value)
+        }
+    }
+}
+
+#[test]
+fn test_retrying_policy() {
+    let failures = Arc::new(AtomicUsize::new(0));
+    let flaky = FlakyBlobstore {
+        inner: EagerMemblob::new(),
+        failures: failures.clone(),
+    };
+    let policy = RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(1),
+        factor: 2.0,
+        jitter: 0.5,
+        retryable: Arc::new(|_| true),
+    };
+    let blobstore = RetryingBlobstore::new(flaky.clone(), policy.clone()).unwrap();
+
+    failures.store(2, Ordering::Relaxed);
+    blobstore
+        .put("foo".to_string(), Bytes::from_static(b"bar"))
+        .wait()
+        .expect("put should succeed on the third attempt");
+
+    failures.store(2, Ordering::Relaxed);
+    let out = blobstore.get("foo".to_string()).wait().expect("get failed");
+    assert_eq!(out, Some(Bytes::from_static(b"bar")));
+
+    failures.store(3, Ordering::Relaxed);
+    assert!(blobstore.get("foo".to_string()).wait().is_err());
+
+    // Errors the classifier rejects aren't retried
+    let blobstore = RetryingBlobstore::new(
+        flaky,
+        RetryPolicy {
+            retryable: Arc::new(|_| false),
+            ..policy
+        },
+    ).unwrap();
+    failures.store(1, Ordering::Relaxed);
+    assert!(blobstore.get("foo".to_string()).wait().is_err());
+    assert_eq!(failures.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_retrying_zero_attempts() {
+    let policy = RetryPolicy {
+        max_attempts: 0,
+        ..RetryPolicy::default()
+    };
+    assert!(RetryingBlobstore::new(EagerMemblob::new(), policy).is_err());
+}
+
+blobstore_test_impl! {
+    statsblob_test => {
+        state: (),
+        new: |_| StatsBlobstore::new(EagerMemblob::new(), "memblob"),
+        persistent: false,
+    }
+}
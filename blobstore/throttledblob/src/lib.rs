@@ -0,0 +1,206 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate bytes;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate tokio_timer;
+
+extern crate blobstore;
+
+use std::cmp;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use failure::{Error, Result};
+use futures::future::{self, Future};
+use futures::sync::oneshot;
+use futures_ext::{BoxFuture, FutureExt};
+use tokio_timer::{wheel, Timer};
+
+use blobstore::Blobstore;
+
+/// Limits applied by `ThrottledBlobstore`. `None` means unlimited.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ThrottleLimits {
+    /// Maximum number of requests to the underlying blobstore running at once.
+    pub max_inflight: Option<usize>,
+    /// Maximum number of requests started per second.
+    pub max_qps: Option<u32>,
+}
+
+struct InflightState {
+    inflight: usize,
+    max_inflight: usize,
+    waiters: VecDeque<oneshot::Sender<Permit>>,
+}
+
+/// Permission to have one request in flight. The slot is handed over to the next waiter (or
+/// freed) when the permit is dropped, which also covers requests that get cancelled.
+struct Permit(Option<Arc<Mutex<InflightState>>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let state = match self.0.take() {
+            Some(state) => state,
+            None => return,
+        };
+        let mut inner = state.lock().expect("lock poison");
+
+        while let Some(waiter) = inner.waiters.pop_front() {
+            match waiter.send(Permit(Some(state.clone()))) {
+                Ok(()) => return,
+                // The waiter went away. Disarm the permit so that dropping it doesn't try to
+                // release the slot again.
+                Err(mut permit) => {
+                    permit.0.take();
+                }
+            }
+        }
+        inner.inflight -= 1;
+    }
+}
+
+struct RateState {
+    interval: Duration,
+    next_slot: Instant,
+}
+
+/// Blobstore wrapper limiting the load put on the underlying blobstore.
+///
+/// Requests beyond the configured number in flight are queued and started in order as earlier
+/// ones finish. Requests are also spaced out so that no more than the configured number are
+/// started per second; the timer has a 10ms resolution, so over short periods requests may be
+/// started in small bursts while the average rate is still respected.
+#[derive(Clone)]
+pub struct ThrottledBlobstore<B> {
+    blobstore: B,
+    inflight: Option<Arc<Mutex<InflightState>>>,
+    rate: Option<Arc<Mutex<RateState>>>,
+    timer: Timer,
+}
+
+impl<B: Blobstore + Clone> ThrottledBlobstore<B> {
+    /// Fails if a limit is 0, which would never let any request through.
+    pub fn new(blobstore: B, limits: ThrottleLimits) -> Result<Self> {
+        if limits.max_inflight == Some(0) {
+            bail_msg!("max_inflight must be positive");
+        }
+        if limits.max_qps == Some(0) {
+            bail_msg!("max_qps must be positive");
+        }
+
+        let inflight = limits.max_inflight.map(|max_inflight| {
+            Arc::new(Mutex::new(InflightState {
+                inflight: 0,
+                max_inflight,
+                waiters: VecDeque::new(),
+            }))
+        });
+        let rate = limits.max_qps.map(|max_qps| {
+            Arc::new(Mutex::new(RateState {
+                interval: Duration::from_secs(1) / max_qps,
+                next_slot: Instant::now(),
+            }))
+        });
+
+        Ok(Self {
+            blobstore,
+            inflight,
+            rate,
+            timer: wheel().tick_duration(Duration::from_millis(10)).build(),
+        })
+    }
+
+    // Wait until the rate limit allows another request to start
+    fn wait_rate(&self) -> BoxFuture<(), Error> {
+        let rate = match self.rate {
+            Some(ref rate) => rate,
+            None => return future::ok(()).boxify(),
+        };
+
+        let now = Instant::now();
+        let slot = {
+            let mut rate = rate.lock().expect("lock poison");
+            let slot = cmp::max(now, rate.next_slot);
+            rate.next_slot = slot + rate.interval;
+            slot
+        };
+
+        if slot > now {
+            self.timer.sleep(slot - now).from_err().boxify()
+        } else {
+            future::ok(()).boxify()
+        }
+    }
+
+    // Wait until there's room for another request in flight
+    fn acquire(&self) -> BoxFuture<Option<Permit>, Error> {
+        let state = match self.inflight {
+            Some(ref state) => state,
+            None => return future::ok(None).boxify(),
+        };
+
+        let mut inner = state.lock().expect("lock poison");
+        if inner.inflight < inner.max_inflight {
+            inner.inflight += 1;
+            return future::ok(Some(Permit(Some(state.clone())))).boxify();
+        }
+
+        let (sender, receiver) = oneshot::channel();
+        inner.waiters.push_back(sender);
+        receiver.map(Some).from_err().boxify()
+    }
+
+    fn throttle(&self) -> BoxFuture<Option<Permit>, Error> {
+        let this = self.clone();
+        self.wait_rate().and_then(move |()| this.acquire()).boxify()
+    }
+}
+
+impl<B: Blobstore + Clone> Blobstore for ThrottledBlobstore<B> {
+    fn get(&self, key: String) -> BoxFuture<Option<Bytes>, Error> {
+        let blobstore = self.blobstore.clone();
+        self.throttle()
+            .and_then(move |permit| {
+                blobstore.get(key).then(move |res| {
+                    drop(permit);
+                    res
+                })
+            })
+            .boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        let blobstore = self.blobstore.clone();
+        self.throttle()
+            .and_then(move |permit| {
+                blobstore.put(key, value).then(move |res| {
+                    drop(permit);
+                    res
+                })
+            })
+            .boxify()
+    }
+
+    fn is_present(&self, key: String) -> BoxFuture<bool, Error> {
+        let blobstore = self.blobstore.clone();
+        self.throttle()
+            .and_then(move |permit| {
+                blobstore.is_present(key).then(move |res| {
+                    drop(permit);
+                    res
+                })
+            })
+            .boxify()
+    }
+}
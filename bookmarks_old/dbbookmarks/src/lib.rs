@@ -16,6 +16,7 @@ extern crate mysql;
 #[macro_use]
 extern crate mysql_async;
 extern crate tokio_core;
+extern crate tokio_timer;
 
 extern crate db;
 extern crate futures_ext;
@@ -25,23 +26,31 @@ extern crate storage_types;
 
 use std::convert::TryFrom;
 use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
 
 use ascii::AsciiStr;
 use failure::{Error, SyncFailure};
 use futures::{future, stream, Future, Stream};
-use mysql_async::{Opts, Pool, Row, TransactionOptions};
+use mysql_async::{Conn, Opts, Pool, Row, Transaction, TransactionOptions};
 use mysql_async::prelude::*;
 use tokio_core::reactor::Remote;
+use tokio_timer::Timer;
 
-use bookmarks::{Bookmarks, BookmarksMut};
+use bookmarks::{timestamp_now, BookmarkChange, BookmarkEvent, BookmarkLogEntry, Bookmarks,
+                BookmarksMut, BookmarksTransaction};
 use db::ConnectionParams;
 use futures_ext::{BoxFuture, BoxFutureNonSend, BoxStream, FutureExt, StreamExt};
 use mercurial_types::nodehash::ChangesetId;
 use sendwrapper::SendWrapper;
 use storage_types::Version;
 
+// How often watchers query the log for new moves
+const WATCH_INTERVAL_MS: u64 = 500;
+
 pub struct DbBookmarks {
-    wrapper: SendWrapper<Pool>,
+    wrapper: Arc<SendWrapper<Pool>>,
+    timer: Timer,
 }
 
 impl DbBookmarks {
@@ -50,7 +59,12 @@ impl DbBookmarks {
             Opts::try_from(params)
                 .and_then(|opts| Ok(Pool::new(opts, handle)))
                 .map_err(Into::into)
-        }).and_then(|wrapper| Ok(DbBookmarks { wrapper }))
+        }).and_then(|wrapper| {
+            Ok(DbBookmarks {
+                wrapper: Arc::new(wrapper),
+                timer: Timer::default(),
+            })
+        })
             .boxify()
     }
 }
@@ -71,6 +85,40 @@ impl Bookmarks for DbBookmarks {
             .map_err(|e| e.context("DbBookmarks keys failed").into())
             .boxify()
     }
+
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        let key = key.as_ref().to_vec();
+        self.wrapper
+            .with_inner(move |pool| get_history(pool, key))
+            .flatten_stream()
+            .map_err(|e| e.context("DbBookmarks history failed").into())
+            .boxify()
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        let wrapper = self.wrapper.clone();
+        let timer = self.timer.clone();
+        let since = timestamp_now();
+        // Each poll yields the moves logged since the previous one, going by the id of the last
+        // move it yielded, or by when they were made until there is one.
+        stream::unfold(None, move |last_id: Option<u64>| {
+            let wrapper = wrapper.clone();
+            let poll = timer
+                .sleep(Duration::from_millis(WATCH_INTERVAL_MS))
+                .from_err()
+                .and_then(move |()| {
+                    wrapper.with_inner(move |pool| get_log_since(pool, since, last_id))
+                })
+                .map(move |events| {
+                    let last_id = events.last().map(|&(id, _)| id).or(last_id);
+                    let events = events.into_iter().map(|(_, event)| event);
+                    (stream::iter_ok(events), last_id)
+                });
+            Some(poll)
+        }).flatten()
+            .map_err(|e| e.context("DbBookmarks watch failed").into())
+            .boxify()
+    }
 }
 
 impl BookmarksMut for DbBookmarks {
@@ -97,6 +145,13 @@ impl BookmarksMut for DbBookmarks {
             .map_err(|e| e.context("DbBookmarks delete failed").into())
             .boxify()
     }
+
+    fn commit(&self, txn: BookmarksTransaction) -> BoxFuture<bool, Error> {
+        self.wrapper
+            .with_inner(move |pool| commit_transaction(pool, txn))
+            .map_err(|e| e.context("DbBookmarks commit failed").into())
+            .boxify()
+    }
 }
 
 fn list_keys(pool: Rc<Pool>) -> BoxFutureNonSend<BoxStream<Vec<u8>, Error>, Error> {
@@ -220,6 +275,206 @@ fn delete_bookmark(
         .boxify_nonsend()
 }
 
+fn commit_transaction(
+    pool: Rc<Pool>,
+    bookmarks_txn: BookmarksTransaction,
+) -> BoxFutureNonSend<bool, Error> {
+    pool.get_conn()
+        .and_then(|conn| conn.start_transaction(TransactionOptions::new()))
+        .map_err(|e| SyncFailure::new(e).into())
+        .and_then(move |txn| {
+            // Lock the rows of all the bookmarks while checking their versions, so that none of
+            // them can change before all the changes are made. The changes are ordered by
+            // bookmark, so concurrent transactions lock the rows in the same order.
+            let versions = bookmarks_txn
+                .changes()
+                .iter()
+                .map(|(key, change)| (key.clone(), change.version().clone()))
+                .collect::<Vec<_>>();
+            stream::iter_ok(versions)
+                .fold(
+                    (txn, true, Vec::new()),
+                    |(txn, matched, mut old), (key, version)| {
+                        txn.prep_exec(
+                            "SELECT value, version FROM bookmarks WHERE name = ? FOR UPDATE",
+                            (key,),
+                        ).and_then(|res| res.collect_and_drop::<(String, u64)>())
+                            .map_err(|e| SyncFailure::new(e).into())
+                            .and_then(move |(txn, mut rows)| -> Result<_, Error> {
+                                let (value, current) = match rows.pop() {
+                                    Some((value, current)) => {
+                                        let value = AsciiStr::from_ascii(&value)?;
+                                        let value = ChangesetId::from_ascii_str(&value)?;
+                                        (Some(value), Version::from(current))
+                                    }
+                                    None => (None, Version::absent()),
+                                };
+                                old.push(value);
+                                Ok((txn, matched && current == version, old))
+                            })
+                    },
+                )
+                .and_then(move |(txn, matched, old)| {
+                    if !matched {
+                        return txn.rollback()
+                            .map(|_| false)
+                            .map_err(|e| SyncFailure::new(e).into())
+                            .boxify_nonsend();
+                    }
+                    let changes = bookmarks_txn
+                        .changes()
+                        .iter()
+                        .zip(old)
+                        .map(|((key, change), from)| {
+                            (key.clone(), change.clone(), bookmarks_txn.log_entry(change, from))
+                        })
+                        .collect::<Vec<_>>();
+                    stream::iter_ok(changes)
+                        .fold(txn, |txn, (key, change, entry)| {
+                            apply_change(txn, key.clone(), change)
+                                .and_then(move |txn| log_change(txn, key, entry))
+                        })
+                        .and_then(|txn| txn.commit().map_err(|e| SyncFailure::new(e).into()))
+                        .map(|_| true)
+                        .boxify_nonsend()
+                })
+        })
+        .boxify_nonsend()
+}
+
+fn apply_change(
+    txn: Transaction<Conn>,
+    key: Vec<u8>,
+    change: BookmarkChange,
+) -> BoxFutureNonSend<Transaction<Conn>, Error> {
+    match change {
+        BookmarkChange::Set(value, _) => {
+            let value: String = value.to_hex().into();
+            txn.prep_exec(
+                "INSERT INTO bookmarks (name, value, version) \
+                 VALUES (:key, :value, 0) \
+                 ON DUPLICATE KEY UPDATE \
+                 value = :value, version = version + 1",
+                params!(key, value),
+            ).and_then(|res| res.drop_result())
+                .map_err(|e| SyncFailure::new(e).into())
+                .boxify_nonsend()
+        }
+        BookmarkChange::Delete(_) => txn.prep_exec("DELETE FROM bookmarks WHERE name = ?", (key,))
+            .and_then(|res| res.drop_result())
+            .map_err(|e| SyncFailure::new(e).into())
+            .boxify_nonsend(),
+    }
+}
+
+fn log_change(
+    txn: Transaction<Conn>,
+    key: Vec<u8>,
+    entry: BookmarkLogEntry,
+) -> BoxFutureNonSend<Transaction<Conn>, Error> {
+    let from: Option<String> = entry.from.map(|value| value.to_hex().into());
+    let to: Option<String> = entry.to.map(|value| value.to_hex().into());
+    let timestamp = entry.timestamp;
+    let author = entry.author;
+    let reason = entry.reason;
+    txn.prep_exec(
+        "INSERT INTO bookmarks_log (name, from_value, to_value, timestamp, author, reason) \
+         VALUES (:key, :from, :to, :timestamp, :author, :reason)",
+        params!(key, from, to, timestamp, author, reason),
+    ).and_then(|res| res.drop_result())
+        .map_err(|e| SyncFailure::new(e).into())
+        .boxify_nonsend()
+}
+
+fn get_history(
+    pool: Rc<Pool>,
+    key: Vec<u8>,
+) -> BoxFutureNonSend<BoxStream<BookmarkLogEntry, Error>, Error> {
+    pool.get_conn()
+        .and_then(|conn| {
+            conn.prep_exec(
+                "SELECT from_value, to_value, timestamp, author, reason FROM bookmarks_log \
+                 WHERE name = ? ORDER BY id DESC",
+                (key,),
+            )
+        })
+        .and_then(|res| {
+            res.collect::<(Option<String>, Option<String>, u64, String, String)>()
+        })
+        .map_err(|e| SyncFailure::new(e).into())
+        .and_then(|(_, rows)| -> Result<_, Error> {
+            let entries = rows.into_iter()
+                .map(|(from, to, timestamp, author, reason)| {
+                    parse_log_entry(from, to, timestamp, author, reason)
+                })
+                .collect::<Result<Vec<_>, Error>>()?;
+            Ok(stream::iter_ok(entries).boxify())
+        })
+        .boxify_nonsend()
+}
+
+// The moves logged after the one with id `last_id`, or made from the second `since` if None,
+// along with their ids. Ids are allocated as moves are logged rather than committed, so a move
+// committed after one with a greater id may be missed.
+fn get_log_since(
+    pool: Rc<Pool>,
+    since: u64,
+    last_id: Option<u64>,
+) -> BoxFutureNonSend<Vec<(u64, BookmarkEvent)>, Error> {
+    pool.get_conn()
+        .and_then(move |conn| match last_id {
+            Some(last_id) => conn.prep_exec(
+                "SELECT id, name, from_value, to_value, timestamp, author, reason \
+                 FROM bookmarks_log WHERE id > ? ORDER BY id",
+                (last_id,),
+            ),
+            None => conn.prep_exec(
+                "SELECT id, name, from_value, to_value, timestamp, author, reason \
+                 FROM bookmarks_log WHERE timestamp >= ? ORDER BY id",
+                (since,),
+            ),
+        })
+        .and_then(|res| {
+            res.collect::<(u64, Vec<u8>, Option<String>, Option<String>, u64, String, String)>()
+        })
+        .map_err(|e| SyncFailure::new(e).into())
+        .and_then(|(_, rows)| {
+            rows.into_iter()
+                .map(|(id, key, from, to, timestamp, author, reason)| {
+                    let entry = parse_log_entry(from, to, timestamp, author, reason)?;
+                    Ok((id, BookmarkEvent { key, entry }))
+                })
+                .collect()
+        })
+        .boxify_nonsend()
+}
+
+fn parse_log_entry(
+    from: Option<String>,
+    to: Option<String>,
+    timestamp: u64,
+    author: String,
+    reason: String,
+) -> Result<BookmarkLogEntry, Error> {
+    Ok(BookmarkLogEntry {
+        from: parse_value(from)?,
+        to: parse_value(to)?,
+        timestamp,
+        author,
+        reason,
+    })
+}
+
+fn parse_value(value: Option<String>) -> Result<Option<ChangesetId>, Error> {
+    match value {
+        Some(value) => {
+            let value = AsciiStr::from_ascii(&value)?;
+            Ok(Some(ChangesetId::from_ascii_str(&value)?))
+        }
+        None => Ok(None),
+    }
+}
+
 pub fn init_test_db() -> ConnectionParams {
     let params = db::create_test_db("mononoke_dbbookmarks").unwrap();
     let pool = mysql::Pool::new(params.clone()).unwrap();
@@ -237,5 +492,19 @@ pub fn init_test_db() -> ConnectionParams {
         (),
     ).unwrap();
 
+    let _ = pool.prep_exec(
+        "CREATE TABLE bookmarks_log (
+            id INTEGER PRIMARY KEY AUTO_INCREMENT,
+            name VARBINARY(256) NOT NULL,
+            from_value VARCHAR(40),
+            to_value VARCHAR(40),
+            timestamp BIGINT UNSIGNED NOT NULL,
+            author VARCHAR(255) NOT NULL,
+            reason VARCHAR(255) NOT NULL,
+            KEY (name)
+        );",
+        (),
+    ).unwrap();
+
     params
 }
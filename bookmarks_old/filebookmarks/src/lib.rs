@@ -12,64 +12,91 @@ extern crate failure_ext as failure;
 extern crate futures;
 extern crate futures_cpupool;
 extern crate percent_encoding;
+extern crate tokio_timer;
 
 extern crate filekv;
 extern crate futures_ext;
 extern crate mercurial_types;
 extern crate storage_types;
 
+use std::collections::HashMap;
 use std::path::PathBuf;
 use std::str;
 use std::sync::Arc;
+use std::time::Duration;
 
 use failure::{Error, Result};
-use futures::{Future, Stream};
+use futures::{stream, Future, Stream};
+use futures::future::{self, loop_fn, Loop};
 use futures_cpupool::CpuPool;
 use percent_encoding::{percent_decode, percent_encode, DEFAULT_ENCODE_SET};
+use tokio_timer::Timer;
 
-use bookmarks::{Bookmarks, BookmarksMut};
+use bookmarks::{timestamp_now, BookmarkChange, BookmarkEvent, BookmarkLogEntry, Bookmarks,
+                BookmarksMut, BookmarksTransaction};
 use filekv::FileKV;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial_types::nodehash::ChangesetId;
 use storage_types::Version;
 
 static PREFIX: &'static str = "bookmark:";
+static LOG_PREFIX: &'static str = "bookmarklog:";
+
+// How often watchers read the logs for new moves
+const WATCH_INTERVAL_MS: u64 = 500;
 
 /// A basic file-based persistent bookmark store.
 ///
 /// Bookmarks are stored as files in the specified base directory. File operations are dispatched
 /// to a thread pool to avoid blocking the main thread. File accesses between these threads
 /// are synchronized by a global map of per-path locks.
+///
+/// The moves of each bookmark are logged in a file of their own, which is appended to once the
+/// move is made. Watchers poll the logs, as other processes may be moving the bookmarks too.
 pub struct FileBookmarks {
     kv: FileKV<ChangesetId>,
+    log: Arc<FileKV<Vec<BookmarkLogEntry>>>,
+    timer: Timer,
 }
 
 impl FileBookmarks {
     #[inline]
     pub fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
         Ok(FileBookmarks {
-            kv: FileKV::open(path, PREFIX)?,
+            kv: FileKV::open(path.clone(), PREFIX)?,
+            log: Arc::new(FileKV::open(path, LOG_PREFIX)?),
+            timer: Timer::default(),
         })
     }
 
     #[inline]
     pub fn open_with_pool<P: Into<PathBuf>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.into();
         Ok(FileBookmarks {
-            kv: FileKV::open_with_pool(path, PREFIX, pool)?,
+            kv: FileKV::open_with_pool(path.clone(), PREFIX, pool.clone())?,
+            log: Arc::new(FileKV::open_with_pool(path, LOG_PREFIX, pool)?),
+            timer: Timer::default(),
         })
     }
 
     #[inline]
     pub fn create<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
         Ok(FileBookmarks {
-            kv: FileKV::create(path, PREFIX)?,
+            kv: FileKV::create(path.clone(), PREFIX)?,
+            log: Arc::new(FileKV::open(path, LOG_PREFIX)?),
+            timer: Timer::default(),
         })
     }
 
     #[inline]
     pub fn create_with_pool<P: Into<PathBuf>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.into();
         Ok(FileBookmarks {
-            kv: FileKV::create_with_pool(path, PREFIX, pool)?,
+            kv: FileKV::create_with_pool(path.clone(), PREFIX, pool.clone())?,
+            log: Arc::new(FileKV::open_with_pool(path, LOG_PREFIX, pool)?),
+            timer: Timer::default(),
         })
     }
 }
@@ -95,6 +122,57 @@ impl Bookmarks for FileBookmarks {
             .map_err(|e| e.context("FileBookmarks keys failed").into())
             .boxify()
     }
+
+    fn history(&self, name: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        self.log
+            .get(encode_key(name))
+            .map(|log| {
+                let entries = log.map_or(Vec::new(), |(entries, _)| entries);
+                stream::iter_ok(entries.into_iter().rev())
+            })
+            .flatten_stream()
+            .map_err(|e| e.context("FileBookmarks history failed").into())
+            .boxify()
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        let log = self.log.clone();
+        let timer = self.timer.clone();
+        let since = timestamp_now();
+        // Each poll yields the entries appended to the logs since the previous one, going by how
+        // many entries of each log it has already gone through.
+        stream::unfold(HashMap::new(), move |mut seen: HashMap<String, usize>| {
+            let log = log.clone();
+            let poll = timer
+                .sleep(Duration::from_millis(WATCH_INTERVAL_MS))
+                .from_err()
+                .and_then(move |()| read_logs(&log))
+                .map(move |logs| {
+                    let mut events = Vec::new();
+                    for (key, entries) in logs {
+                        let start = seen.get(&key).cloned().unwrap_or(0);
+                        seen.insert(key.clone(), entries.len());
+                        let name: Vec<u8> = percent_decode(key.as_bytes()).collect();
+                        events.extend(
+                            entries
+                                .into_iter()
+                                .skip(start)
+                                .filter(|entry| entry.timestamp >= since)
+                                .map(|entry| BookmarkEvent {
+                                    key: name.clone(),
+                                    entry,
+                                }),
+                        );
+                    }
+                    // Moves of different bookmarks are only ordered by when they were made
+                    events.sort_by_key(|event| event.entry.timestamp);
+                    (stream::iter_ok(events), seen)
+                });
+            Some(poll)
+        }).flatten()
+            .map_err(|e| e.context("FileBookmarks watch failed").into())
+            .boxify()
+    }
 }
 
 impl BookmarksMut for FileBookmarks {
@@ -118,4 +196,73 @@ impl BookmarksMut for FileBookmarks {
             .map_err(|e| e.context("FileBookmarks delete failed").into())
             .boxify()
     }
+
+    fn commit(&self, txn: BookmarksTransaction) -> BoxFuture<bool, Error> {
+        let changes = txn.changes()
+            .iter()
+            .map(|(key, change)| match *change {
+                BookmarkChange::Set(value, version) => (encode_key(key), Some(value), version),
+                BookmarkChange::Delete(version) => (encode_key(key), None, version),
+            })
+            .collect();
+        let log = self.log.clone();
+        self.kv
+            .transaction(changes)
+            .and_then(move |old| {
+                let old: HashMap<_, _> = match old {
+                    Some(old) => old.into_iter().collect(),
+                    None => return future::ok(false).boxify(),
+                };
+                let entries: Vec<_> = txn.changes()
+                    .iter()
+                    .map(|(key, change)| {
+                        let key = encode_key(key);
+                        let entry = txn.log_entry(change, old[&key]);
+                        (key, entry)
+                    })
+                    .collect();
+                stream::iter_ok(entries)
+                    .for_each(move |(key, entry)| append_log(&log, key, entry))
+                    .map(|()| true)
+                    .boxify()
+            })
+            .map_err(|e| e.context("FileBookmarks commit failed").into())
+            .boxify()
+    }
+}
+
+// The logs of all the bookmarks, by encoded key
+fn read_logs(
+    log: &Arc<FileKV<Vec<BookmarkLogEntry>>>,
+) -> BoxFuture<Vec<(String, Vec<BookmarkLogEntry>)>, Error> {
+    let keys = log.keys();
+    let log = log.clone();
+    keys.and_then(move |key| {
+        log.get(key.clone())
+            .map(move |entries| (key, entries.map_or(Vec::new(), |(entries, _)| entries)))
+    }).collect()
+        .boxify()
+}
+
+// Append `entry` to the log of the bookmark `key`, retrying if another move of the bookmark
+// appends to it concurrently.
+fn append_log(
+    log: &Arc<FileKV<Vec<BookmarkLogEntry>>>,
+    key: String,
+    entry: BookmarkLogEntry,
+) -> BoxFuture<(), Error> {
+    let log = log.clone();
+    loop_fn((), move |()| {
+        let log = log.clone();
+        let key = key.clone();
+        let entry = entry.clone();
+        log.get(key.clone()).and_then(move |current| {
+            let (mut entries, version) = current.unwrap_or((Vec::new(), Version::absent()));
+            entries.push(entry);
+            log.set(key, &entries, &version, None).map(|version| match version {
+                Some(_) => Loop::Break(()),
+                None => Loop::Continue(()),
+            })
+        })
+    }).boxify()
 }
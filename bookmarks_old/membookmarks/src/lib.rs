@@ -19,10 +19,13 @@ use std::sync::Mutex;
 use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
 
 use failure::Error;
+use futures::Stream;
 use futures::future::ok;
 use futures::stream::iter_ok;
+use futures::sync::mpsc;
 
-use bookmarks::{Bookmarks, BookmarksMut};
+use bookmarks::{BookmarkChange, BookmarkEvent, BookmarkLogEntry, Bookmarks, BookmarksMut,
+                BookmarksTransaction};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use mercurial_types::nodehash::ChangesetId;
 use storage_types::Version;
@@ -36,12 +39,17 @@ fn version_next() -> Version {
 /// In-memory bookmark store backed by a HashMap, intended to be used in tests.
 pub struct MemBookmarks {
     bookmarks: Mutex<HashMap<Vec<u8>, (ChangesetId, Version)>>,
+    log: Mutex<HashMap<Vec<u8>, Vec<BookmarkLogEntry>>>,
+    // Committed moves are sent to every watcher, as they're made
+    watchers: Mutex<Vec<mpsc::UnboundedSender<BookmarkEvent>>>,
 }
 
 impl MemBookmarks {
     pub fn new() -> Self {
         MemBookmarks {
             bookmarks: Mutex::new(HashMap::new()),
+            log: Mutex::new(HashMap::new()),
+            watchers: Mutex::new(Vec::new()),
         }
     }
 }
@@ -62,6 +70,20 @@ impl Bookmarks for MemBookmarks {
         let keys = guard.keys().map(|k| k.clone()).collect::<Vec<_>>();
         iter_ok(keys.into_iter()).boxify()
     }
+
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        let log = self.log.lock().unwrap();
+        let entries = log.get(key.as_ref()).cloned().unwrap_or_default();
+        iter_ok(entries.into_iter().rev()).boxify()
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.watchers.lock().unwrap().push(sender);
+        receiver
+            .map_err(|()| -> Error { unreachable!("mpsc::UnboundedReceiver never fails") })
+            .boxify()
+    }
 }
 
 impl BookmarksMut for MemBookmarks {
@@ -108,4 +130,46 @@ impl BookmarksMut for MemBookmarks {
             },
         }.boxify()
     }
+
+    fn commit(&self, txn: BookmarksTransaction) -> BoxFuture<bool, Error> {
+        let mut bookmarks = self.bookmarks.lock().unwrap();
+
+        let matched = txn.changes().iter().all(|(key, change)| {
+            let current = bookmarks
+                .get(key)
+                .map_or(Version::absent(), |&(_, version)| version);
+            current == *change.version()
+        });
+        if !matched {
+            return ok(false).boxify();
+        }
+
+        let mut log = self.log.lock().unwrap();
+        let mut watchers = self.watchers.lock().unwrap();
+        for (key, change) in txn.changes() {
+            let from = bookmarks.get(key).map(|&(value, _)| value);
+            let entry = txn.log_entry(change, from);
+            // Forget the watchers which have dropped their streams
+            watchers.retain(|watcher| {
+                let event = BookmarkEvent {
+                    key: key.clone(),
+                    entry: entry.clone(),
+                };
+                watcher.unbounded_send(event).is_ok()
+            });
+            log.entry(key.clone()).or_insert_with(Vec::new).push(entry);
+        }
+
+        for (key, change) in txn.into_changes() {
+            match change {
+                BookmarkChange::Set(value, _) => {
+                    bookmarks.insert(key, (value, version_next()));
+                }
+                BookmarkChange::Delete(_) => {
+                    bookmarks.remove(&key);
+                }
+            }
+        }
+        ok(true).boxify()
+    }
 }
@@ -8,12 +8,17 @@
 
 extern crate failure;
 extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
 
 extern crate futures_ext;
 extern crate mercurial_types;
 extern crate storage_types;
 
+use std::collections::BTreeMap;
 use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use futures_ext::{BoxFuture, BoxStream};
 
@@ -28,6 +33,13 @@ pub trait Bookmarks: Sync + Send + 'static {
     // Basic operations.
     fn get(&self, key: &AsRef<[u8]>) -> BoxFuture<Option<(ChangesetId, Version)>, Error>;
     fn keys(&self) -> BoxStream<Vec<u8>, Error>;
+
+    // The moves of a bookmark, most recent first.
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error>;
+
+    // The moves of all the bookmarks logged from the second this is called on, as they're made.
+    // The stream only ends if the bookmarks can't move.
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error>;
 }
 
 // Implement Bookmarks for boxed Bookmarks trait object
@@ -39,6 +51,14 @@ impl Bookmarks for Box<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        (**self).history(key)
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        (**self).watch()
+    }
 }
 
 // Implement Bookmarks for Arced Bookmarks trait object
@@ -50,6 +70,14 @@ impl Bookmarks for Arc<Bookmarks> {
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        (**self).history(key)
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        (**self).watch()
+    }
 }
 
 // Implement Bookmarks for Arc-wrapped Bookmark type
@@ -64,6 +92,14 @@ where
     fn keys(&self) -> BoxStream<Vec<u8>, Error> {
         (**self).keys()
     }
+
+    fn history(&self, key: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        (**self).history(key)
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        (**self).watch()
+    }
 }
 
 /// Trait representing write operations on a bookmark store. Consistency is maintained using
@@ -79,4 +115,132 @@ pub trait BookmarksMut: Bookmarks {
     fn create(&self, key: &AsRef<[u8]>, value: &ChangesetId) -> BoxFuture<Option<Version>, Error> {
         self.set(key, value, &Version::absent())
     }
+
+    // Make all the changes of a transaction at once, logging each bookmark's move. Resolves to
+    // false, changing nothing, if any of the bookmarks isn't at the version its change expects.
+    fn commit(&self, txn: BookmarksTransaction) -> BoxFuture<bool, Error>;
+}
+
+/// A change to a bookmark, made only if the bookmark is at the version given.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum BookmarkChange {
+    Set(ChangesetId, Version),
+    Delete(Version),
+}
+
+impl BookmarkChange {
+    /// The version the bookmark must be at for the change to be made
+    pub fn version(&self) -> &Version {
+        match *self {
+            BookmarkChange::Set(_, ref version) => version,
+            BookmarkChange::Delete(ref version) => version,
+        }
+    }
+}
+
+/// Changes to several bookmarks which are committed together, or not at all. This lets a push
+/// move all of its bookmarks without a concurrent push seeing, or interleaving with, only some of
+/// them moved.
+///
+/// Changes are kept ordered by bookmark, which stores can rely on to lock the bookmarks of
+/// concurrent transactions in a consistent order.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BookmarksTransaction {
+    changes: BTreeMap<Vec<u8>, BookmarkChange>,
+    author: String,
+    reason: String,
+}
+
+impl BookmarksTransaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Who is making the changes and why, as recorded in the logs of the bookmarks
+    pub fn logged_as<A, R>(&mut self, author: A, reason: R) -> &mut Self
+    where
+        A: Into<String>,
+        R: Into<String>,
+    {
+        self.author = author.into();
+        self.reason = reason.into();
+        self
+    }
+
+    // A bookmark changed more than once in a transaction only gets its last change.
+    pub fn set(&mut self, key: &AsRef<[u8]>, value: &ChangesetId, version: &Version) -> &mut Self {
+        self.changes.insert(
+            key.as_ref().to_vec(),
+            BookmarkChange::Set(*value, *version),
+        );
+        self
+    }
+
+    pub fn create(&mut self, key: &AsRef<[u8]>, value: &ChangesetId) -> &mut Self {
+        self.set(key, value, &Version::absent())
+    }
+
+    pub fn delete(&mut self, key: &AsRef<[u8]>, version: &Version) -> &mut Self {
+        self.changes
+            .insert(key.as_ref().to_vec(), BookmarkChange::Delete(*version));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    pub fn changes(&self) -> &BTreeMap<Vec<u8>, BookmarkChange> {
+        &self.changes
+    }
+
+    pub fn into_changes(self) -> BTreeMap<Vec<u8>, BookmarkChange> {
+        self.changes
+    }
+
+    /// The log entry recording the move of a bookmark by this transaction, from `from` to
+    /// wherever its change sets it
+    pub fn log_entry(
+        &self,
+        change: &BookmarkChange,
+        from: Option<ChangesetId>,
+    ) -> BookmarkLogEntry {
+        let to = match *change {
+            BookmarkChange::Set(value, _) => Some(value),
+            BookmarkChange::Delete(_) => None,
+        };
+        BookmarkLogEntry {
+            from,
+            to,
+            timestamp: timestamp_now(),
+            author: self.author.clone(),
+            reason: self.reason.clone(),
+        }
+    }
+}
+
+/// A move of a bookmark, where None means that it didn't or doesn't exist any more.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct BookmarkLogEntry {
+    pub from: Option<ChangesetId>,
+    pub to: Option<ChangesetId>,
+    /// Seconds since the epoch
+    pub timestamp: u64,
+    pub author: String,
+    pub reason: String,
+}
+
+/// A move of the bookmark `key`, as followed by `Bookmarks::watch`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BookmarkEvent {
+    pub key: Vec<u8>,
+    pub entry: BookmarkLogEntry,
+}
+
+/// The current time in seconds since the epoch, as logged
+pub fn timestamp_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
 }
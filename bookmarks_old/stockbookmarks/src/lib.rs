@@ -32,7 +32,7 @@ use futures::future;
 use futures::stream::{self, Stream};
 use futures_ext::{BoxFuture, BoxStream, StreamExt};
 
-use bookmarks::Bookmarks;
+use bookmarks::{BookmarkEvent, BookmarkLogEntry, Bookmarks};
 use mercurial_types::nodehash::ChangesetId;
 use storage_types::Version;
 
@@ -127,6 +127,16 @@ impl Bookmarks for StockBookmarks {
         ).and_then(|x| x)
             .boxify()
     }
+
+    fn history(&self, _name: &AsRef<[u8]>) -> BoxStream<BookmarkLogEntry, Error> {
+        // Mercurial doesn't keep the history of bookmarks
+        stream::empty().boxify()
+    }
+
+    fn watch(&self) -> BoxStream<BookmarkEvent, Error> {
+        // Read-only, so no bookmark ever moves
+        stream::empty().boxify()
+    }
 }
 
 #[cfg(test)]
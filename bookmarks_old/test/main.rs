@@ -28,7 +28,7 @@ use futures::Stream;
 use tempdir::TempDir;
 use tokio_core::reactor::Core;
 
-use bookmarks::BookmarksMut;
+use bookmarks::{BookmarksMut, BookmarksTransaction};
 use dbbookmarks::DbBookmarks;
 use filebookmarks::FileBookmarks;
 use membookmarks::MemBookmarks;
@@ -82,6 +82,117 @@ where
     );
 }
 
+fn transaction<B>(bookmarks: B, core: &mut Core)
+where
+    B: BookmarksMut,
+{
+    let foo = b"foo";
+    let bar = b"bar";
+    let baz = b"baz";
+    let one = ChangesetId::new(nodehash::ONES_HASH);
+    let two = ChangesetId::new(nodehash::TWOS_HASH);
+
+    let absent = Version::absent();
+    let foo_v1 = core.run(bookmarks.create(&foo, &one)).unwrap().unwrap();
+
+    // Should fail as a whole due to the version mismatch on "bar".
+    let mut txn = BookmarksTransaction::new();
+    txn.set(&foo, &two, &foo_v1)
+        .set(&bar, &two, &foo_v1)
+        .create(&baz, &two);
+    assert!(!core.run(bookmarks.commit(txn)).unwrap());
+    assert_eq!(
+        core.run(bookmarks.get(&foo)).unwrap(),
+        Some((one.clone(), foo_v1))
+    );
+    assert_eq!(core.run(bookmarks.get(&baz)).unwrap(), None);
+
+    let mut txn = BookmarksTransaction::new();
+    txn.delete(&foo, &foo_v1)
+        .delete(&bar, &absent)
+        .create(&baz, &two)
+        .logged_as("alice", "testing");
+    assert!(core.run(bookmarks.commit(txn)).unwrap());
+    assert_eq!(core.run(bookmarks.get(&foo)).unwrap(), None);
+    assert_eq!(core.run(bookmarks.get(&bar)).unwrap(), None);
+    assert_eq!(
+        core.run(bookmarks.get(&baz)).unwrap().map(|(value, _)| value),
+        Some(two)
+    );
+}
+
+fn history<B>(bookmarks: B, core: &mut Core)
+where
+    B: BookmarksMut,
+{
+    let foo = b"foo";
+    let one = ChangesetId::new(nodehash::ONES_HASH);
+    let two = ChangesetId::new(nodehash::TWOS_HASH);
+
+    let mut txn = BookmarksTransaction::new();
+    txn.create(&foo, &one).logged_as("alice", "push");
+    assert!(core.run(bookmarks.commit(txn)).unwrap());
+    let (_, foo_v1) = core.run(bookmarks.get(&foo)).unwrap().unwrap();
+
+    let mut txn = BookmarksTransaction::new();
+    txn.set(&foo, &two, &foo_v1).logged_as("bob", "pushrebase");
+    assert!(core.run(bookmarks.commit(txn)).unwrap());
+
+    // Failed transactions aren't logged.
+    let mut txn = BookmarksTransaction::new();
+    txn.delete(&foo, &foo_v1).logged_as("carol", "pushkey");
+    assert!(!core.run(bookmarks.commit(txn)).unwrap());
+
+    let moves: Vec<_> = core.run(bookmarks.history(&foo).collect())
+        .unwrap()
+        .into_iter()
+        .map(|entry| (entry.from, entry.to, entry.author, entry.reason))
+        .collect();
+    assert_eq!(
+        moves,
+        vec![
+            (Some(one), Some(two), "bob".to_string(), "pushrebase".to_string()),
+            (None, Some(one), "alice".to_string(), "push".to_string()),
+        ]
+    );
+    assert!(core.run(bookmarks.history(&b"bar").collect()).unwrap().is_empty());
+}
+
+fn watch<B>(bookmarks: B, core: &mut Core)
+where
+    B: BookmarksMut,
+{
+    let foo = b"foo";
+    let bar = b"bar";
+    let one = ChangesetId::new(nodehash::ONES_HASH);
+    let two = ChangesetId::new(nodehash::TWOS_HASH);
+
+    let watch = bookmarks.watch();
+
+    let mut txn = BookmarksTransaction::new();
+    txn.create(&foo, &one).logged_as("alice", "push");
+    assert!(core.run(bookmarks.commit(txn)).unwrap());
+
+    let mut txn = BookmarksTransaction::new();
+    txn.create(&bar, &two).logged_as("bob", "pushrebase");
+    assert!(core.run(bookmarks.commit(txn)).unwrap());
+
+    // Moves made in the same second may be yielded in any order.
+    let mut moves: Vec<_> = core.run(watch.take(2).collect())
+        .unwrap()
+        .into_iter()
+        .map(|event| (event.key, event.entry.to, event.entry.author))
+        .collect();
+    moves.sort();
+    assert_eq!(
+        moves,
+        vec![
+            (bar.to_vec(), Some(two), "bob".to_string()),
+            (foo.to_vec(), Some(one), "alice".to_string()),
+        ]
+    );
+}
+
 fn list<B>(bookmarks: B, core: &mut Core)
 where
     B: BookmarksMut,
@@ -142,6 +253,30 @@ macro_rules! bookmarks_test_impl {
                 basic(bookmarks, &mut core);
             }
 
+            #[test]
+            fn test_transaction() {
+                let mut core = Core::new().unwrap();
+                let state = $state;
+                let bookmarks = $new_cb(&state, &mut core);
+                transaction(bookmarks, &mut core);
+            }
+
+            #[test]
+            fn test_history() {
+                let mut core = Core::new().unwrap();
+                let state = $state;
+                let bookmarks = $new_cb(&state, &mut core);
+                history(bookmarks, &mut core);
+            }
+
+            #[test]
+            fn test_watch() {
+                let mut core = Core::new().unwrap();
+                let state = $state;
+                let bookmarks = $new_cb(&state, &mut core);
+                watch(bookmarks, &mut core);
+            }
+
             #[test]
             fn test_list() {
                 let mut core = Core::new().unwrap();
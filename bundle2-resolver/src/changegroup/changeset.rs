@@ -60,6 +60,8 @@ mod tests {
     use futures::stream::iter_ok;
     use itertools::equal;
 
+    use mercurial_bundles::changegroup::RevFlags;
+
     enum CheckResult {
         ExpectedOk(bool),
         ExpectedErr(bool),
@@ -93,6 +95,7 @@ mod tests {
             base,
             linknode,
             delta,
+            flags: RevFlags::empty(),
         };
 
         let result = convert_to_revlog_changesets(iter_ok(vec![ChangesetDeltaed { chunk }]))
@@ -4,21 +4,24 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::mem;
 use std::sync::Arc;
 
 use bytes::Bytes;
 use failure::Compat;
 use futures::{Future, Stream};
-use futures::future::{ok, Shared};
+use futures::future::{err as err_future, join_all, lazy, ok, Shared};
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use heapsize::HeapSizeOf;
+use linked_hash_map::LinkedHashMap;
 use quickcheck::{Arbitrary, Gen};
 
 use blobrepo::{BlobEntry, BlobRepo};
-use mercurial_bundles::changegroup::CgDeltaChunk;
-use mercurial_types::{delta, manifest, Blob, Delta, MPath, NodeHash, RepoPath};
+use mercurial::file::File;
+use mercurial_bundles::changegroup::{CgDeltaChunk, RevFlags};
+use mercurial_types::{manifest, Blob, BlobNode, Delta, MPath, NodeHash, RepoPath};
+use mercurial_types::delta::Rope;
 use mercurial_types::nodehash::NULL_HASH;
 
 use errors::*;
@@ -39,12 +42,22 @@ pub struct Filelog {
     pub p2: Option<NodeHash>,
     pub linknode: NodeHash,
     pub blob: Blob,
+    pub flags: RevFlags,
 }
 
 impl UploadableBlob for Filelog {
     type Value = Shared<BoxFuture<(BlobEntry, RepoPath), Compat<Error>>>;
 
     fn upload(self, repo: &BlobRepo) -> Result<((NodeHash, RepoPath), Self::Value)> {
+        // Blobs have nowhere to keep the flags, and the content of censored or externally stored
+        // revisions isn't what their hash was computed from
+        if !self.flags.is_empty() {
+            bail_err!(ErrorKind::UnsupportedRevFlags(
+                self.path,
+                self.node,
+                self.flags,
+            ));
+        }
         let path = self.path;
         repo.upload_entry(
             self.blob,
@@ -56,11 +69,26 @@ impl UploadableBlob for Filelog {
     }
 }
 
+/// How many bytes of file contents the conversion of a changegroup keeps in memory to apply the
+/// deltas of the next files to.
+const DELTA_CACHE_BUDGET: usize = 512 * 1024 * 1024;
+
 pub fn convert_to_revlog_filelog<S>(repo: Arc<BlobRepo>, deltaed: S) -> BoxStream<Filelog, Error>
 where
     S: Stream<Item = FilelogDeltaed, Error = Error> + Send + 'static,
 {
-    let mut delta_cache = DeltaCache::new(repo);
+    convert_with_budget(repo, deltaed, DELTA_CACHE_BUDGET)
+}
+
+fn convert_with_budget<S>(
+    repo: Arc<BlobRepo>,
+    deltaed: S,
+    budget: usize,
+) -> BoxStream<Filelog, Error>
+where
+    S: Stream<Item = FilelogDeltaed, Error = Error> + Send + 'static,
+{
+    let mut delta_cache = DeltaCache::new(repo, budget);
     deltaed
         .and_then(move |FilelogDeltaed { path, chunk }| {
             let CgDeltaChunk {
@@ -70,18 +98,38 @@ where
                 p1,
                 p2,
                 linknode,
+                flags,
             } = chunk;
+            let path = try_boxfuture!(RepoPath::file(path));
+            let p1 = p1.into_option();
+            let p2 = p2.into_option();
 
             delta_cache
-                .decode(node.clone(), base.into_option(), delta)
+                .decode(node.clone(), base.into_option(), delta, &path, p1, p2)
                 .and_then(move |blob| {
+                    let blobnode = BlobNode::new(blob.clone(), p1.as_ref(), p2.as_ref());
+                    // The content of revisions with flags isn't what their hash was computed
+                    // from, and they are rejected on upload anyway
+                    if flags.is_empty() {
+                        let actual = blobnode
+                            .nodeid()
+                            .ok_or_else(|| format_err!("content of {} {} is missing", path, node))?;
+                        if actual != node {
+                            bail_err!(ErrorKind::FilelogNodeMismatch(path, node, actual));
+                        }
+                    }
+                    // The copy is kept apart from the content on upload, so it has to be valid
+                    if let Err(err) = File::new(blobnode).copied_from() {
+                        bail_err!(ErrorKind::InvalidCopyMetadata(path, node, err.to_string()));
+                    }
                     Ok(Filelog {
-                        path: RepoPath::file(path)?,
+                        path,
                         node,
-                        p1: p1.into_option(),
-                        p2: p2.into_option(),
+                        p1,
+                        p2,
                         linknode,
                         blob,
+                        flags,
                     })
                 })
                 .boxify()
@@ -89,16 +137,41 @@ where
         .boxify()
 }
 
+// What's needed to upload a file if its content is evicted from the cache
+struct FileRevision {
+    path: RepoPath,
+    p1: Option<NodeHash>,
+    p2: Option<NodeHash>,
+}
+
+/// Contents of the files of a changegroup, which the deltas of the next files apply to.
+///
+/// The contents are kept in memory up to a budget, past which the least recently used ones are
+/// evicted. As the files are decoded one after the other, the contents are all known by then.
+/// Evicted contents are uploaded to the repo first, and fetched back from there if a delta
+/// applies to them later, like the contents of files which were pushed before.
 struct DeltaCache {
     repo: Arc<BlobRepo>,
-    bytes_cache: HashMap<NodeHash, Shared<BoxFuture<Bytes, Compat<Error>>>>,
+    bytes_cache: HashMap<NodeHash, (Shared<BoxFuture<Rope, Compat<Error>>>, FileRevision)>,
+    // The contents counted in the budget, least recently used first
+    lru: LinkedHashMap<NodeHash, usize>,
+    // The last content decoded, which is counted once known
+    pending: Option<NodeHash>,
+    evicted: HashSet<NodeHash>,
+    size: usize,
+    budget: usize,
 }
 
 impl DeltaCache {
-    fn new(repo: Arc<BlobRepo>) -> Self {
+    fn new(repo: Arc<BlobRepo>, budget: usize) -> Self {
         Self {
             repo,
             bytes_cache: HashMap::new(),
+            lru: LinkedHashMap::new(),
+            pending: None,
+            evicted: HashSet::new(),
+            size: 0,
+            budget,
         }
     }
 
@@ -107,27 +180,48 @@ impl DeltaCache {
         node: NodeHash,
         base: Option<NodeHash>,
         delta: Delta,
+        path: &RepoPath,
+        p1: Option<NodeHash>,
+        p2: Option<NodeHash>,
     ) -> BoxFuture<Blob, Error> {
-        let bytes = match self.bytes_cache.get(&node).cloned() {
-            Some(bytes) => bytes,
+        let spills = self.evict();
+
+        let bytes = match self.bytes_cache.get(&node).map(|&(ref bytes, _)| bytes.clone()) {
+            Some(bytes) => {
+                self.lru.get_refresh(&node);
+                bytes
+            }
             None => {
                 let dsize = delta.heap_size_of_children() as i64;
                 STATS::deltacache_dsize.add_value(dsize);
                 STATS::deltacache_dsize_large.add_value(dsize);
 
-                let vec = match base {
-                    None => ok(delta::apply(b"", &delta)).boxify(),
+                // The contents are kept as ropes, so that applying a delta shares the regions it
+                // leaves unchanged with its base
+                let rope = match base {
+                    None => ok(Rope::default().apply(delta)).boxify(),
                     Some(base) => {
-                        let fut = match self.bytes_cache.get(&base) {
-                            Some(bytes) => bytes
-                                .clone()
-                                .map(move |bytes| delta::apply(&bytes, &delta))
-                                .map_err(Error::from)
-                                .boxify(),
-                            None => self.repo
-                                .get_file_content(&base)
-                                .map(move |bytes| delta::apply(bytes.as_ref(), &delta))
-                                .boxify(),
+                        let cached = self.bytes_cache
+                            .get(&base)
+                            .map(|&(ref bytes, _)| bytes.clone());
+                        let fut = match cached {
+                            Some(bytes) => {
+                                self.lru.get_refresh(&base);
+                                bytes
+                                    .map(move |rope| rope.apply(delta))
+                                    .map_err(Error::from)
+                                    .boxify()
+                            }
+                            None => {
+                                if self.evicted.contains(&base) {
+                                    STATS::deltacache_refetches.add_value(1);
+                                }
+                                // Not fetched before the evicted contents are uploaded
+                                let repo = self.repo.clone();
+                                lazy(move || repo.get_raw_content(&base))
+                                    .map(move |bytes| Rope::new(bytes).apply(delta))
+                                    .boxify()
+                            }
                         };
                         fut.map_err(move |err| {
                             Error::from(err.context(format_err!(
@@ -139,25 +233,97 @@ impl DeltaCache {
                     }
                 };
 
-                let bytes = vec.map(|vec| Bytes::from(vec)).boxify().shared();
+                let bytes = rope.boxify().shared();
 
-                if self.bytes_cache.insert(node, bytes.clone()).is_some() {
+                let revision = FileRevision {
+                    path: path.clone(),
+                    p1,
+                    p2,
+                };
+                if self.bytes_cache
+                    .insert(node, (bytes.clone(), revision))
+                    .is_some()
+                {
                     panic!("Logic error: byte cache returned None for HashMap::get with node");
                 }
+                self.pending = Some(node);
                 bytes
             }
         };
 
-        bytes
-            .inspect(|bytes| {
-                let fsize = (mem::size_of::<u8>() * bytes.as_ref().len()) as i64;
-                STATS::deltacache_fsize.add_value(fsize);
-                STATS::deltacache_fsize_large.add_value(fsize);
+        join_all(spills)
+            .and_then(move |_| {
+                bytes
+                    .inspect(|rope| {
+                        let fsize = (mem::size_of::<u8>() * rope.len()) as i64;
+                        STATS::deltacache_fsize.add_value(fsize);
+                        STATS::deltacache_fsize_large.add_value(fsize);
+                    })
+                    .map(|rope| Blob::from((*rope).clone().into_bytes()))
+                    .from_err()
             })
-            .map(|bytes| Blob::from((*bytes).clone()))
-            .from_err()
             .boxify()
     }
+
+    // Counts the last content decoded, then evicts the least recently used contents until the
+    // cache is within its budget. Returns the uploads of the evicted contents.
+    fn evict(&mut self) -> Vec<BoxFuture<(), Error>> {
+        if let Some(node) = self.pending.take() {
+            let size = match self.bytes_cache.get(&node) {
+                Some(&(ref bytes, _)) => match bytes.peek() {
+                    Some(Ok(bytes)) => bytes.len(),
+                    _ => 0,
+                },
+                None => 0,
+            };
+            self.size += size;
+            self.lru.insert(node, size);
+        }
+
+        let mut spills = Vec::new();
+        while self.size > self.budget {
+            let (node, size) = match self.lru.pop_front() {
+                Some(evicted) => evicted,
+                None => break,
+            };
+            self.size -= size;
+            STATS::deltacache_evictions.add_value(1);
+
+            let (bytes, revision) = match self.bytes_cache.remove(&node) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            let bytes = match bytes.peek() {
+                Some(Ok(rope)) => (*rope).clone().into_bytes(),
+                _ => continue,
+            };
+            // The file is uploaded again later along with the others, which is harmless
+            let upload = self.repo.upload_entry(
+                Blob::from(bytes),
+                manifest::Type::File,
+                revision.p1,
+                revision.p2,
+                revision.path,
+            );
+            let spill = match upload {
+                Ok((_, fut)) => fut.map(|_| ()).boxify(),
+                Err(err) => err_future(err).boxify(),
+            };
+            self.evicted.insert(node);
+            spills.push(spill);
+        }
+        spills
+    }
+}
+
+impl Filelog {
+    // Gives the file the node Mercurial would compute for it
+    fn with_hashed_node(mut self) -> Self {
+        self.node = BlobNode::new(self.blob.clone(), self.p1.as_ref(), self.p2.as_ref())
+            .nodeid()
+            .expect("blob has no content");
+        self
+    }
 }
 
 impl Arbitrary for Filelog {
@@ -165,12 +331,13 @@ impl Arbitrary for Filelog {
         Filelog {
             path: RepoPath::file(MPath::arbitrary(g))
                 .unwrap_or(RepoPath::file(MPath::new(b"test").unwrap()).unwrap()),
-            node: NodeHash::arbitrary(g),
+            node: NULL_HASH,
             p1: NodeHash::arbitrary(g).into_option(),
             p2: NodeHash::arbitrary(g).into_option(),
             linknode: NodeHash::arbitrary(g),
             blob: Blob::from(Bytes::from(Vec::<u8>::arbitrary(g))),
-        }
+            flags: RevFlags::empty(),
+        }.with_hashed_node()
     }
 
     fn shrink(&self) -> Box<Iterator<Item = Self>> {
@@ -187,22 +354,16 @@ impl Arbitrary for Filelog {
             append(&mut result, f);
         }
 
-        if self.node != NULL_HASH {
-            let mut f = self.clone();
-            f.node = NULL_HASH;
-            append(&mut result, f);
-        }
-
         if self.p1 != None {
             let mut f = self.clone();
             f.p1 = None;
-            append(&mut result, f);
+            append(&mut result, f.with_hashed_node());
         }
 
         if self.p2 != None {
             let mut f = self.clone();
             f.p2 = None;
-            append(&mut result, f);
+            append(&mut result, f.with_hashed_node());
         }
 
         if self.linknode != NULL_HASH {
@@ -214,7 +375,7 @@ impl Arbitrary for Filelog {
         if self.blob.size() != Some(0) {
             let mut f = self.clone();
             f.blob = Blob::from(Bytes::from(Vec::new()));
-            append(&mut result, f);
+            append(&mut result, f.with_hashed_node());
         }
 
         Box::new(result.into_iter())
@@ -231,32 +392,7 @@ mod tests {
     use futures::stream::iter_ok;
     use itertools::{assert_equal, EitherOrBoth, Itertools};
 
-    use mercurial_types::delta::Fragment;
-
-    struct NodeHashGen {
-        bytes: Vec<u8>,
-    }
-
-    impl NodeHashGen {
-        fn new() -> Self {
-            Self {
-                bytes: Vec::from(NULL_HASH.as_ref()),
-            }
-        }
-
-        fn next(&mut self) -> NodeHash {
-            for i in 0..self.bytes.len() {
-                if self.bytes[i] == 255 {
-                    self.bytes[i] = 0;
-                } else {
-                    self.bytes[i] = self.bytes[i] + 1;
-                    return NodeHash::from_bytes(self.bytes.as_slice()).unwrap();
-                }
-            }
-
-            panic!("NodeHashGen overflow");
-        }
-    }
+    use mercurial_types::delta::{self, Fragment};
 
     fn check_conversion<I, J>(inp: I, exp: J)
     where
@@ -283,6 +419,7 @@ mod tests {
                 base: NULL_HASH,
                 linknode: f.linknode.clone(),
                 delta: Delta::new_fulltext(f.blob.as_slice().unwrap()),
+                flags: f.flags,
             },
         }
     }
@@ -340,21 +477,23 @@ mod tests {
 
         let f1 = Filelog {
             path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
-            node: ONES_HASH,
+            node: NULL_HASH,
             p1: Some(TWOS_HASH),
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             blob: Blob::from(Bytes::from("test file content")),
-        };
+            flags: RevFlags::empty(),
+        }.with_hashed_node();
 
         let f2 = Filelog {
             path: RepoPath::file(MPath::new(b"test2").unwrap()).unwrap(),
-            node: FIVES_HASH,
+            node: NULL_HASH,
             p1: Some(SIXES_HASH),
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             blob: Blob::from(Bytes::from("test2 file content")),
-        };
+            flags: RevFlags::empty(),
+        }.with_hashed_node();
 
         check_conversion(
             vec![filelog_to_deltaed(&f1), filelog_to_deltaed(&f2)],
@@ -367,21 +506,23 @@ mod tests {
 
         let f1 = Filelog {
             path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
-            node: ONES_HASH,
+            node: NULL_HASH,
             p1: Some(TWOS_HASH),
             p2: Some(THREES_HASH),
             linknode: FOURS_HASH,
             blob: Blob::from(Bytes::from("test file content")),
-        };
+            flags: RevFlags::empty(),
+        }.with_hashed_node();
 
         let f2 = Filelog {
             path: RepoPath::file(MPath::new(b"test2").unwrap()).unwrap(),
-            node: FIVES_HASH,
+            node: NULL_HASH,
             p1: Some(SIXES_HASH),
             p2: Some(SEVENS_HASH),
             linknode: EIGHTS_HASH,
             blob: Blob::from(Bytes::from("test2 file content")),
-        };
+            flags: RevFlags::empty(),
+        }.with_hashed_node();
 
         let f1_deltaed = filelog_to_deltaed(&f1);
         let mut f2_deltaed = filelog_to_deltaed(&f2);
@@ -424,6 +565,129 @@ mod tests {
         files_check_order(false);
     }
 
+    #[test]
+    fn delta_against_evicted_base() {
+        let filelog = |content: &'static str, p1: Option<NodeHash>| {
+            Filelog {
+                path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
+                node: NULL_HASH,
+                p1,
+                p2: None,
+                linknode: NULL_HASH,
+                blob: Blob::from(Bytes::from(content)),
+                flags: RevFlags::empty(),
+            }.with_hashed_node()
+        };
+        let f1 = filelog("the first version of the file", None);
+        let f2 = filelog("the second version of the file", Some(f1.node));
+        let f3 = filelog("the third version of the file", Some(f2.node));
+
+        // The cache only fits one file, so f1 is evicted by the time f3 is deltaed against it
+        let mut f3_deltaed = filelog_to_deltaed(&f3);
+        f3_deltaed.chunk.base = f1.node.clone();
+        f3_deltaed.chunk.delta =
+            compute_delta(f1.blob.as_slice().unwrap(), f3.blob.as_slice().unwrap());
+        let inp = vec![filelog_to_deltaed(&f1), filelog_to_deltaed(&f2), f3_deltaed];
+
+        let result = convert_with_budget(
+            Arc::new(BlobRepo::new_memblob_empty(None).unwrap()),
+            iter_ok(inp),
+            40,
+        ).collect()
+            .wait()
+            .unwrap();
+
+        assert_equal(result, vec![f1, f2, f3]);
+    }
+
+    #[test]
+    fn node_mismatch_rejected() {
+        use mercurial_types_mocks::nodehash::*;
+
+        let f = Filelog {
+            path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
+            node: ONES_HASH,
+            p1: None,
+            p2: None,
+            linknode: TWOS_HASH,
+            blob: Blob::from(Bytes::from("test file content")),
+            flags: RevFlags::empty(),
+        };
+
+        let result = convert_to_revlog_filelog(
+            Arc::new(BlobRepo::new_memblob_empty(None).unwrap()),
+            iter_ok(vec![filelog_to_deltaed(&f)]),
+        ).collect()
+            .wait();
+
+        match result {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::FilelogNodeMismatch(path, node, _)) => {
+                    assert_eq!(path, f.path);
+                    assert_eq!(node, ONES_HASH);
+                }
+                other => panic!("unexpected error {:?}", other),
+            },
+            Ok(_) => panic!("file with a wrong node converted"),
+        }
+    }
+
+    #[test]
+    fn invalid_copy_metadata_rejected() {
+        let f = Filelog {
+            path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
+            node: NULL_HASH,
+            p1: None,
+            p2: None,
+            linknode: NULL_HASH,
+            blob: Blob::from(Bytes::from(
+                &b"\x01\ncopy: in\0valid\n\
+                   copyrev: 1111111111111111111111111111111111111111\n\x01\ncontent"[..],
+            )),
+            flags: RevFlags::empty(),
+        }.with_hashed_node();
+
+        let result = convert_to_revlog_filelog(
+            Arc::new(BlobRepo::new_memblob_empty(None).unwrap()),
+            iter_ok(vec![filelog_to_deltaed(&f)]),
+        ).collect()
+            .wait();
+
+        match result {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::InvalidCopyMetadata(path, node, _)) => {
+                    assert_eq!(path, f.path);
+                    assert_eq!(node, f.node);
+                }
+                other => panic!("unexpected error {:?}", other),
+            },
+            Ok(_) => panic!("file with invalid copy metadata converted"),
+        }
+    }
+
+    #[test]
+    fn flags_rejected() {
+        use mercurial_types_mocks::nodehash::*;
+
+        let f = Filelog {
+            path: RepoPath::file(MPath::new(b"test").unwrap()).unwrap(),
+            node: ONES_HASH,
+            p1: None,
+            p2: None,
+            linknode: TWOS_HASH,
+            blob: Blob::from(Bytes::from("censored")),
+            flags: RevFlags::CENSORED,
+        };
+        let repo = BlobRepo::new_memblob_empty(None).unwrap();
+        match f.upload(&repo) {
+            Err(err) => match err.downcast::<ErrorKind>() {
+                Ok(ErrorKind::UnsupportedRevFlags(..)) => (),
+                other => panic!("unexpected error {:?}", other),
+            },
+            Ok(_) => panic!("file with flags uploaded"),
+        }
+    }
+
     quickcheck! {
         fn sanitycheck_delta_computation(b1: Vec<u8>, b2: Vec<u8>) -> bool {
             assert_equal(&b2, &delta::apply(&b1, &compute_delta(&b1, &b2)));
@@ -440,16 +704,6 @@ mod tests {
         }
 
         fn correct_conversion_delta_against_first(f: Filelog, fs: Vec<Filelog>) -> bool {
-            let mut hash_gen = NodeHashGen::new();
-
-            let mut f = f.clone();
-            f.node = hash_gen.next();
-
-            let mut fs = fs.clone();
-            for el in fs.iter_mut() {
-                el.node = hash_gen.next();
-            }
-
             let mut deltas = vec![filelog_to_deltaed(&f)];
             for filelog in &fs {
                 let mut delta = filelog_to_deltaed(filelog);
@@ -465,13 +719,6 @@ mod tests {
         }
 
         fn correct_conversion_delta_against_next(fs: Vec<Filelog>) -> bool {
-            let mut hash_gen = NodeHashGen::new();
-
-            let mut fs = fs.clone();
-            for el in fs.iter_mut() {
-                el.node = hash_gen.next();
-            }
-
             let deltas = {
                 let mut it = fs.iter();
                 let mut deltas = match it.next() {
@@ -90,6 +90,13 @@ where
                     // Checking that there is exactly one Part::end is is covered by CheckEnd
                     // wrapper
                     Part::End if seen_path.is_none() => Ok(None),
+                    // Changegroups of version 3 can have tree manifests after the root manifests,
+                    // which are only accepted in treegroup parts
+                    ref bad @ Part::CgChunk(Section::Treemanifest(_), _)
+                    | ref bad @ Part::SectionEnd(Section::Treemanifest(_)) => bail_msg!(
+                        "Tree manifests must be pushed in a b2x:treegroup2 part, found: {:?}",
+                        bad
+                    ),
                     bad => if seen_path.is_some() {
                         bail_msg!(
                             "Expected Filelog chunk or end, seen_path was {:?}, found: {:?}",
@@ -6,7 +6,27 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use hooks::HookRejection;
+use mercurial_bundles::changegroup::RevFlags;
+use mercurial_types::{MPath, NodeHash, RepoPath};
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "Malformed treemanifest part: {}", _0)] MalformedTreemanifestPart(String),
+    #[fail(display = "Push rejected by hooks: {:?}", _0)] HooksRejected(Vec<HookRejection>),
+    #[fail(display = "Unsupported revlog flags for {} {}: {:?}", _0, _1, _2)]
+    UnsupportedRevFlags(RepoPath, NodeHash, RevFlags),
+    #[fail(display = "Content of {} does not match its node {}, it hashes to {}", _0, _1, _2)]
+    FilelogNodeMismatch(RepoPath, NodeHash, NodeHash),
+    #[fail(display = "Invalid copy metadata for {} {}: {}", _0, _1, _2)]
+    InvalidCopyMetadata(RepoPath, NodeHash, String),
+    #[fail(display = "Push failed, bookmarks moved while pushing: {:?}", _0)]
+    BookmarksMoved(Vec<String>),
+    #[fail(display = "Pushrebase onto {} failed, there is no such bookmark", _0)]
+    PushrebaseBookmarkMissing(String),
+    #[fail(display = "Pushrebase onto {} failed, the bookmark moved while rebasing", _0)]
+    PushrebaseBookmarkMoved(String),
+    #[fail(display = "Pushrebase failed, files changed on the server since the push's base: {:?}",
+           _0)]
+    PushrebaseConflicts(Vec<MPath>),
 }
@@ -19,6 +19,7 @@ extern crate heapsize;
 extern crate itertools;
 #[macro_use]
 extern crate lazy_static;
+extern crate linked_hash_map;
 #[cfg(test)]
 #[macro_use]
 extern crate maplit;
@@ -34,17 +35,21 @@ extern crate stats as stats_crate;
 extern crate tokio_io;
 
 extern crate blobrepo;
+extern crate hooks;
 extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
+extern crate phases;
 
 mod changegroup;
 pub mod errors;
+mod pushrebase;
+mod reply;
 mod resolver;
 mod stats;
 mod wirepackparser;
 mod upload_blobs;
 
-pub use resolver::resolve;
+pub use resolver::{apply_pushkey, resolve};
@@ -0,0 +1,492 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Pushrebase: the changesets of a push based on an old position of a bookmark are rebased onto
+//! its current position on the server, so that the pushes to a busy bookmark don't race.
+//!
+//! Only the changesets and the manifests are rewritten, the pushed file revisions are kept as
+//! they are. That's why a push is rejected if any of its files changed since the changeset it
+//! was based on.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use futures::{Future, Stream};
+use futures::future::{loop_fn, ok, Loop};
+use futures::stream;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+
+use blobrepo::{compute_changed_files, BlobEntry, BlobRepo, ChangesetHandle};
+use mercurial::manifest::revlog::{Details, ManifestContent};
+use mercurial_types::{Blob, Changeset, ChangesetId, MPath, MPathElement, NodeHash, ObsMarker,
+                      RepoPath};
+use mercurial_types::manifest::Type;
+use mercurial_types::nodehash::EntryId;
+
+use errors::*;
+use resolver::{Changesets, Filelogs, Manifests};
+
+type BlobFuture = BoxFuture<(BlobEntry, RepoPath), Error>;
+/// Trees of a manifest by path, with their nodes
+type Trees = HashMap<MPath, (NodeHash, ManifestContent)>;
+
+/// The changeset the pushed ones are based on, which is None if they start a new history. Only
+/// stacks of changesets without merges can be rebased.
+pub fn stack_base(changesets: &Changesets) -> Result<Option<NodeHash>> {
+    let mut base = None;
+    let mut previous = None;
+    for &(node, ref revlog_cs) in changesets {
+        let (p1, p2) = revlog_cs.parents().get_nodes();
+        ensure_msg!(
+            p2.is_none(),
+            "pushrebase of merge changeset {} is not supported",
+            node
+        );
+        match previous {
+            None => base = p1.cloned(),
+            Some(previous) => ensure_msg!(
+                p1 == Some(&previous),
+                "pushrebase of changesets which aren't a stack is not supported, {} is not a \
+                 child of {}",
+                node,
+                previous
+            ),
+        }
+        previous = Some(node);
+    }
+    Ok(base)
+}
+
+/// Rebases the pushed changesets from `base` onto `onto`, resolving to each of them paired with
+/// what it was rebased into.
+pub fn rebase(
+    repo: Arc<BlobRepo>,
+    base: Option<NodeHash>,
+    onto: ChangesetId,
+    changesets: Changesets,
+    filelogs: Filelogs,
+    manifests: Manifests,
+) -> BoxFuture<Vec<(NodeHash, NodeHash)>, Error> {
+    let pushed_files: BTreeSet<MPath> = changesets
+        .iter()
+        .flat_map(|&(_, ref revlog_cs)| revlog_cs.files().iter().cloned())
+        .collect();
+    let filelogs = Arc::new(filelogs);
+    let manifests = Arc::new(manifests);
+
+    let base_manifest = match base {
+        Some(base) => {
+            let repo = repo.clone();
+            repo.get_changeset_by_changesetid(&ChangesetId::new(base))
+                .and_then(move |cs| repo.get_manifest_by_nodeid(&cs.manifestid().into_nodehash()))
+                .map(Some)
+                .boxify()
+        }
+        None => ok(None).boxify(),
+    };
+
+    repo.get_changeset_by_changesetid(&onto)
+        .join(base_manifest)
+        .and_then({
+            let repo = repo.clone();
+            move |(onto_cs, base_manifest)| {
+                repo.get_manifest_by_nodeid(&onto_cs.manifestid().into_nodehash())
+                    .and_then(move |onto_manifest| {
+                        compute_changed_files(&onto_manifest, base_manifest.as_ref(), None)
+                    })
+                    .and_then(move |changed| {
+                        let conflicts: Vec<_> = changed
+                            .into_iter()
+                            .filter(|path| pushed_files.contains(path))
+                            .collect();
+                        if !conflicts.is_empty() {
+                            bail_err!(ErrorKind::PushrebaseConflicts(conflicts));
+                        }
+                        Ok(onto_cs)
+                    })
+            }
+        })
+        .and_then(move |onto_cs| {
+            let parent_manifest = onto_cs.manifestid().into_nodehash();
+            let parent = ChangesetHandle::from(onto_cs);
+
+            stream::iter_ok(changesets)
+                .fold(
+                    (parent, parent_manifest, Vec::new()),
+                    move |(parent, parent_manifest, mut rebased), (node, revlog_cs)| {
+                        let files = revlog_cs.files().to_vec();
+                        let dirs = Arc::new(parent_dirs(&files));
+                        let pushed_trees = load_trees(
+                            repo.clone(),
+                            Some(manifests.clone()),
+                            revlog_cs.manifestid().into_nodehash(),
+                            dirs.clone(),
+                        );
+                        let parent_trees =
+                            load_trees(repo.clone(), None, parent_manifest, dirs);
+
+                        let repo = repo.clone();
+                        let filelogs = filelogs.clone();
+                        pushed_trees
+                            .join(parent_trees)
+                            .and_then(move |(pushed_trees, parent_trees)| {
+                                let changes: Vec<_> = files
+                                    .into_iter()
+                                    .map(|path| {
+                                        let details = lookup(&pushed_trees, &path);
+                                        (path, details)
+                                    })
+                                    .collect();
+                                let mut entries = Vec::new();
+                                for &(ref path, ref details) in &changes {
+                                    if let &Some(ref details) = details {
+                                        let key = (
+                                            details.entryid().into_nodehash(),
+                                            RepoPath::file(path.clone())?,
+                                        );
+                                        if let Some(filelog) = filelogs.get(&key) {
+                                            entries.push(
+                                                filelog
+                                                    .clone()
+                                                    .map(|it| (*it).clone())
+                                                    .from_err()
+                                                    .boxify(),
+                                            );
+                                        }
+                                    }
+                                }
+                                let (root_node, root, mut trees) =
+                                    rebuild_trees(&repo, parent_trees, changes)?;
+                                entries.append(&mut trees);
+
+                                let handle = repo.create_changeset(
+                                    Some(parent),
+                                    None,
+                                    root,
+                                    stream::futures_unordered(entries).boxify(),
+                                    String::from_utf8(revlog_cs.user().into())?,
+                                    revlog_cs.time().clone(),
+                                    revlog_cs.extra().clone(),
+                                    String::from_utf8(revlog_cs.comments().into())?,
+                                );
+                                Ok((handle, root_node))
+                            })
+                            .and_then(move |(handle, root_node)| {
+                                handle
+                                    .clone()
+                                    .get_completed_changeset()
+                                    .map_err(Error::from)
+                                    .map(move |cs| {
+                                        let new = cs.get_changeset_id().into_nodehash();
+                                        rebased.push((node, new));
+                                        (handle, root_node, rebased)
+                                    })
+                            })
+                            .map_err(move |err| {
+                                err.context(format!("While rebasing changeset {}", node))
+                            })
+                    },
+                )
+                .map(|(_, _, rebased)| rebased)
+        })
+        .boxify()
+}
+
+/// The directories containing the files, including the root
+fn parent_dirs(files: &[MPath]) -> BTreeSet<MPath> {
+    let mut dirs = BTreeSet::new();
+    dirs.insert(MPath::empty());
+    for file in files {
+        let elements: Vec<&MPathElement> = file.into_iter().collect();
+        for len in 1..elements.len() {
+            dirs.insert(MPath::empty().join(elements[..len].iter().cloned()));
+        }
+    }
+    dirs
+}
+
+/// The directory of a file or directory, and its name in it
+fn split_path(path: &MPath) -> (MPath, MPath) {
+    let elements: Vec<&MPathElement> = path.into_iter().collect();
+    match elements.split_last() {
+        Some((name, dir)) => (
+            MPath::empty().join(dir.iter().cloned()),
+            MPath::empty().join(Some(*name)),
+        ),
+        None => (MPath::empty(), MPath::empty()),
+    }
+}
+
+fn tree_repo_path(path: &MPath) -> Result<RepoPath> {
+    if path.is_empty() {
+        Ok(RepoPath::root())
+    } else {
+        RepoPath::dir(path.clone())
+    }
+}
+
+/// Loads the trees of the manifest `root` which are in `dirs`, from the pushed manifests if
+/// they're among them or from the repo otherwise.
+fn load_trees(
+    repo: Arc<BlobRepo>,
+    pushed: Option<Arc<Manifests>>,
+    root: NodeHash,
+    dirs: Arc<BTreeSet<MPath>>,
+) -> BoxFuture<Trees, Error> {
+    loop_fn(
+        (vec![(MPath::empty(), root)], HashMap::new()),
+        move |(mut pending, mut trees): (Vec<(MPath, NodeHash)>, Trees)| {
+            let (path, node) = match pending.pop() {
+                Some(next) => next,
+                None => return ok(Loop::Break(trees)).boxify(),
+            };
+
+            let repo_path = try_boxfuture!(tree_repo_path(&path));
+            let pushed_content = pushed
+                .as_ref()
+                .and_then(|manifests| manifests.get(&(node, repo_path)))
+                .map(|&(ref content, _)| ManifestContent {
+                    files: content.files.clone(),
+                });
+            let content = match pushed_content {
+                Some(content) => ok(content).boxify(),
+                None => repo.get_raw_content(&node)
+                    .and_then(|bytes| ManifestContent::parse(&bytes))
+                    .boxify(),
+            };
+
+            let dirs = dirs.clone();
+            content
+                .map(move |content| {
+                    for (name, details) in &content.files {
+                        let child = path.join(name);
+                        if details.is_tree() && dirs.contains(&child) {
+                            pending.push((child, details.entryid().into_nodehash()));
+                        }
+                    }
+                    trees.insert(path, (node, content));
+                    Loop::Continue((pending, trees))
+                })
+                .boxify()
+        },
+    ).boxify()
+}
+
+/// The entry of a file in a manifest, given the trees of the manifest containing it
+fn lookup(trees: &Trees, path: &MPath) -> Option<Details> {
+    let (dir, name) = split_path(path);
+    trees
+        .get(&dir)
+        .and_then(|&(_, ref content)| content.files.get(&name).cloned())
+}
+
+/// Applies the changes to the files to the manifest whose trees containing them are given,
+/// uploading the trees which changed. Returns the node of the new root tree and its upload,
+/// along with the uploads of the other trees.
+fn rebuild_trees(
+    repo: &BlobRepo,
+    mut trees: Trees,
+    changes: Vec<(MPath, Option<Details>)>,
+) -> Result<(NodeHash, BlobFuture, Vec<BlobFuture>)> {
+    // The changes to each directory, by name in the directory, with every directory containing
+    // changed files so that it is rebuilt
+    let mut dir_changes: BTreeMap<MPath, Vec<(MPath, Option<Details>)>> = BTreeMap::new();
+    let paths: Vec<_> = changes.iter().map(|&(ref path, _)| path.clone()).collect();
+    for dir in parent_dirs(&paths) {
+        dir_changes.insert(dir, Vec::new());
+    }
+    for (path, details) in changes {
+        let (dir, name) = split_path(&path);
+        dir_changes
+            .entry(dir)
+            .or_insert_with(Vec::new)
+            .push((name, details));
+    }
+
+    // The longest paths first, so that directories are rebuilt before their parents, which get
+    // their new nodes
+    let mut dirs: Vec<MPath> = dir_changes.keys().cloned().collect();
+    dirs.sort_by(|a, b| b.len().cmp(&a.len()));
+
+    let mut uploads = Vec::new();
+    for dir in dirs {
+        let (p1, mut content) = match trees.remove(&dir) {
+            Some((node, content)) => (Some(node), content),
+            None => (None, ManifestContent::new_empty()),
+        };
+        for (name, details) in dir_changes.remove(&dir).unwrap_or_else(Vec::new) {
+            match details {
+                Some(details) => content.files.insert(name, details),
+                None => content.files.remove(&name),
+            };
+        }
+
+        if dir.is_empty() {
+            let (node, upload) = upload_tree(repo, &dir, &content, p1)?;
+            return Ok((node, upload, uploads));
+        }
+
+        // Directories without any file left are removed from their parent
+        let details = if content.files.is_empty() {
+            None
+        } else {
+            let (node, upload) = upload_tree(repo, &dir, &content, p1)?;
+            uploads.push(upload);
+            Some(Details::new(EntryId::new(node), Type::Tree))
+        };
+        let (parent, name) = split_path(&dir);
+        dir_changes
+            .entry(parent)
+            .or_insert_with(Vec::new)
+            .push((name, details));
+    }
+
+    bail_msg!("Logic error: the root tree was not rebuilt")
+}
+
+fn upload_tree(
+    repo: &BlobRepo,
+    path: &MPath,
+    content: &ManifestContent,
+    p1: Option<NodeHash>,
+) -> Result<(NodeHash, BlobFuture)> {
+    let mut data = Vec::new();
+    content.generate(&mut data)?;
+    repo.upload_entry(
+        Blob::from(Bytes::from(data)),
+        Type::Tree,
+        p1,
+        None,
+        tree_repo_path(path)?,
+    )
+}
+
+/// Phases of the pushed changesets, given to what they were rebased into
+pub fn rebased_phases<P>(
+    phases: Vec<(NodeHash, P)>,
+    rebased: &[(NodeHash, NodeHash)],
+) -> Vec<(NodeHash, P)> {
+    let rebased: HashMap<_, _> = rebased.iter().cloned().collect();
+    phases
+        .into_iter()
+        .map(|(node, phase)| (rebased.get(&node).cloned().unwrap_or(node), phase))
+        .collect()
+}
+
+/// Obsolescence markers telling the client what its changesets were rebased into
+pub fn rebased_markers(rebased: &[(NodeHash, NodeHash)]) -> Vec<ObsMarker> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() as f64)
+        .unwrap_or(0.0);
+    rebased
+        .iter()
+        .map(|&(old, new)| ObsMarker {
+            precursor: old,
+            successors: vec![new],
+            flags: 0,
+            parents: None,
+            date: (now, 0),
+            metadata: vec![(b"operation".to_vec(), b"pushrebase".to_vec())],
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::*;
+
+    fn file(node: NodeHash) -> Option<Details> {
+        Some(Details::new(EntryId::new(node), Type::File))
+    }
+
+    fn content(files: Vec<(&str, Option<Details>)>) -> ManifestContent {
+        ManifestContent {
+            files: files
+                .into_iter()
+                .filter_map(|(name, details)| details.map(|d| (MPath::new(name).unwrap(), d)))
+                .collect(),
+        }
+    }
+
+    fn tree_node(
+        repo: &BlobRepo,
+        path: MPath,
+        content: &ManifestContent,
+        p1: Option<NodeHash>,
+    ) -> NodeHash {
+        upload_tree(repo, &path, content, p1).unwrap().0
+    }
+
+    #[test]
+    fn rebuild() {
+        let repo = BlobRepo::new_memblob_empty(None).unwrap();
+        let dir = content(vec![("b", file(TWOS_HASH))]);
+        let gone = content(vec![("c", file(THREES_HASH))]);
+        let dir_node = tree_node(&repo, MPath::new("dir").unwrap(), &dir, None);
+        let gone_node = tree_node(&repo, MPath::new("gone").unwrap(), &gone, None);
+        let root = ManifestContent {
+            files: btreemap!{
+                MPath::new("a").unwrap() => file(ONES_HASH).unwrap(),
+                MPath::new("dir").unwrap() => Details::new(EntryId::new(dir_node), Type::Tree),
+                MPath::new("gone").unwrap() => Details::new(EntryId::new(gone_node), Type::Tree),
+            },
+        };
+        let root_node = tree_node(&repo, MPath::empty(), &root, None);
+
+        let trees = hashmap!{
+            MPath::empty() => (root_node, root),
+            MPath::new("dir").unwrap() => (dir_node, dir),
+            MPath::new("gone").unwrap() => (gone_node, gone),
+        };
+        let changes = vec![
+            (MPath::new("a").unwrap(), file(FOURS_HASH)),
+            (MPath::new("dir/b").unwrap(), None),
+            (MPath::new("dir/new/d").unwrap(), file(FIVES_HASH)),
+            (MPath::new("gone/c").unwrap(), None),
+        ];
+        let (node, _, uploads) = rebuild_trees(&repo, trees, changes).unwrap();
+
+        let new = content(vec![("d", file(FIVES_HASH))]);
+        let new_node = tree_node(&repo, MPath::new("dir/new").unwrap(), &new, None);
+        let dir = content(vec![
+            ("new", Some(Details::new(EntryId::new(new_node), Type::Tree))),
+        ]);
+        let dir_node = tree_node(&repo, MPath::new("dir").unwrap(), &dir, Some(dir_node));
+        let root = content(vec![
+            ("a", file(FOURS_HASH)),
+            ("dir", Some(Details::new(EntryId::new(dir_node), Type::Tree))),
+        ]);
+        assert_eq!(node, tree_node(&repo, MPath::empty(), &root, Some(root_node)));
+        assert_eq!(uploads.len(), 2);
+    }
+
+    #[test]
+    fn phases() {
+        let phases = vec![(ONES_HASH, 1), (TWOS_HASH, 2)];
+        let rebased = vec![(ONES_HASH, THREES_HASH)];
+        assert_eq!(
+            rebased_phases(phases, &rebased),
+            vec![(THREES_HASH, 1), (TWOS_HASH, 2)]
+        );
+    }
+
+    #[test]
+    fn uploads_complete() {
+        let repo = BlobRepo::new_memblob_empty(None).unwrap();
+        let changes = vec![(MPath::new("dir/a").unwrap(), file(ONES_HASH))];
+        let (node, root, uploads) = rebuild_trees(&repo, HashMap::new(), changes).unwrap();
+
+        root.join(::futures::future::join_all(uploads)).wait().unwrap();
+        let bytes = repo.get_raw_content(&node).wait().unwrap();
+        let root = ManifestContent::parse(&bytes).unwrap();
+        assert!(root.files[&MPath::new("dir").unwrap()].is_tree());
+    }
+}
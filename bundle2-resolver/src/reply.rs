@@ -0,0 +1,118 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::io::Cursor;
+
+use bytes::Bytes;
+use futures::Future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use mercurial_bundles::{parts, Bundle2EncodeBuilder};
+use mercurial_types::NodeHash;
+
+use errors::*;
+use pushrebase;
+
+pub type PartId = u32;
+
+/// Accumulates the outcomes of the parts of a push as they are resolved, and encodes them in the
+/// bundle2 replied to the client, each reply referring to the part it answers.
+#[derive(Debug, Default)]
+pub struct ReplyBuilder {
+    changegroup: Option<(PartId, i64)>,
+    obsmarkers: Option<(PartId, usize)>,
+    pushkeys: Vec<(PartId, bool)>,
+    rebased: Vec<(NodeHash, NodeHash)>,
+    output: Vec<String>,
+    abort: Option<String>,
+}
+
+impl ReplyBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The changegroup was applied, changing the number of heads of the repo by `heads_num_diff`
+    pub fn changegroup(&mut self, part_id: PartId, heads_num_diff: i64) -> &mut Self {
+        self.changegroup = Some((part_id, heads_num_diff));
+        self
+    }
+
+    /// The obsolescence markers were stored, `new` of them being new
+    pub fn obsmarkers(&mut self, part_id: PartId, new: usize) -> &mut Self {
+        self.obsmarkers = Some((part_id, new));
+        self
+    }
+
+    pub fn pushkey(&mut self, part_id: PartId, res: bool) -> &mut Self {
+        self.pushkeys.push((part_id, res));
+        self
+    }
+
+    /// A pushed changeset was rebased into `new`, which the client is told with an obsolescence
+    /// marker
+    pub fn rebased(&mut self, old: NodeHash, new: NodeHash) -> &mut Self {
+        self.rebased.push((old, new));
+        self
+    }
+
+    /// A line for the client to show to its user
+    pub fn output<S: Into<String>>(&mut self, line: S) -> &mut Self {
+        self.output.push(line.into());
+        self
+    }
+
+    /// Make the client abort the push with `message`, after showing the output
+    pub fn abort<S: Into<String>>(&mut self, message: S) -> &mut Self {
+        self.abort = Some(message.into());
+        self
+    }
+
+    /// Encode the reply bundle2
+    pub fn build(&self) -> BoxFuture<Bytes, Error> {
+        let writer = Cursor::new(Vec::new());
+        let mut bundle = Bundle2EncodeBuilder::new(writer);
+        // Mercurial currently hangs while trying to read compressed bundles over the wire:
+        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
+        // TODO: possibly enable compression support once this is fixed.
+        bundle.set_compressor_type(None);
+
+        if !self.output.is_empty() {
+            let mut output = String::new();
+            for line in &self.output {
+                output.push_str(line);
+                output.push('\n');
+            }
+            bundle.add_part(try_boxfuture!(parts::output_part(output)));
+        }
+        if let Some(ref message) = self.abort {
+            bundle.add_part(try_boxfuture!(parts::error_abort_part(message.clone(), None)));
+        } else {
+            if let Some((part_id, heads_num_diff)) = self.changegroup {
+                bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
+                    parts::ChangegroupApplyResult::Success { heads_num_diff },
+                    part_id,
+                )));
+            }
+            if let Some((part_id, new)) = self.obsmarkers {
+                bundle.add_part(try_boxfuture!(parts::replyobsmarkers_part(new, part_id)));
+            }
+            for &(part_id, res) in &self.pushkeys {
+                bundle.add_part(try_boxfuture!(parts::replypushkey_part(res, part_id)));
+            }
+            if !self.rebased.is_empty() {
+                let markers = pushrebase::rebased_markers(&self.rebased);
+                bundle.add_part(try_boxfuture!(parts::obsmarkers_part(markers)));
+            }
+        }
+
+        bundle
+            .build()
+            .map(|cursor| Bytes::from(cursor.into_inner()))
+            .map_err(|err| err.context("While preparing response").into())
+            .boxify()
+    }
+}
@@ -4,58 +4,88 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::collections::HashMap;
-use std::io::Cursor;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::mem;
+use std::str::{self, FromStr};
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
+use failure::Compat;
 use futures::{Future, IntoFuture, Stream};
-use futures::future::{err, ok};
+use futures::future::{err, loop_fn, ok, Loop, Shared};
 use futures::stream;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
 use slog::Logger;
 
-use blobrepo::{BlobEntry, BlobRepo, ChangesetHandle};
+use blobrepo::{BlobEntry, BlobRepo, ChangesetHandle, PushUploads};
+use hooks::{self, Hook, HookChangeset, HookFile};
 use mercurial::changeset::RevlogChangeset;
 use mercurial::manifest::revlog::ManifestContent;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{Changeset, ChangesetId, MPath, ManifestId, NodeHash, RepoPath};
+use mercurial_bundles::{Bundle2Item, PartHeader};
+use mercurial_types::{Changeset, ChangesetId, MPath, ManifestId, NodeHash, ObsMarker, RepoPath};
+use phases::Phase;
 
 use changegroup::{convert_to_revlog_changesets, convert_to_revlog_filelog, split_changegroup,
                   Filelog};
 use errors::*;
+use pushrebase;
+use reply::{PartId, ReplyBuilder};
 use upload_blobs::{upload_blobs, UploadBlobsType, UploadableBlob};
 use wirepackparser::{TreemanifestBundle2Parser, TreemanifestEntry};
 
-type PartId = u32;
-type Changesets = Vec<(NodeHash, RevlogChangeset)>;
-type Filelogs = HashMap<(NodeHash, RepoPath), <Filelog as UploadableBlob>::Value>;
-type Manifests = HashMap<(NodeHash, RepoPath), <TreemanifestEntry as UploadableBlob>::Value>;
+pub(crate) type Changesets = Vec<(NodeHash, RevlogChangeset)>;
+pub(crate) type Filelogs = HashMap<(NodeHash, RepoPath), <Filelog as UploadableBlob>::Value>;
+pub(crate) type Manifests =
+    HashMap<(NodeHash, RepoPath), <TreemanifestEntry as UploadableBlob>::Value>;
 type UploadedChangesets = HashMap<NodeHash, ChangesetHandle>;
+/// Sizes of the pushed files, by linknode and path
+type FileSizes = HashMap<(NodeHash, MPath), Option<usize>>;
 
 /// The resolve function takes a bundle2, interprets it's content as Changesets, Filelogs and
 /// Manifests and uploades all of them to the provided BlobRepo in the correct order.
+/// Heads and bookmarks are only updated once all the changesets are uploaded. The bookmarks are
+/// moved together, and the push fails without moving any of them if one of them isn't where the
+/// pusher saw it.
+/// The hooks are run against the pushed changesets before any of them is created, and the push
+/// is rejected if any hook fails.
+/// Changesets pushed through pushrebase are rebased onto the bookmark they target, which is moved
+/// to them, and the client is told what they were rebased into with obsolescence markers.
+/// The bookmarks moved are logged as moved by `author`.
 /// It returns a Future that contains the response that should be send back to the requester.
 pub fn resolve(
     repo: Arc<BlobRepo>,
     logger: Logger,
     heads: Vec<String>,
     bundle2: BoxStream<Bundle2Item, Error>,
+    hooks: Vec<Arc<Hook>>,
+    author: String,
 ) -> BoxFuture<Bytes, Error> {
     info!(logger, "unbundle heads {:?}", heads);
 
-    let resolver = Bundle2Resolver::new(repo, logger);
+    let resolver = Bundle2Resolver::new(repo, logger, hooks, author);
 
     let bundle2 = resolver.resolve_start_and_replycaps(bundle2);
 
     resolver
-        .resolve_changegroup(bundle2)
+        .maybe_resolve_check_bookmarks(bundle2)
+        .and_then({
+            let resolver = resolver.clone();
+            move |bundle2| resolver.maybe_resolve_check_heads(bundle2)
+        })
+        .and_then({
+            let resolver = resolver.clone();
+            move |bundle2| resolver.resolve_changegroup(bundle2)
+        })
         .and_then(move |(cg_push, bundle2)| {
             let changegroup_id = cg_push.part_id;
             let changesets = cg_push.changesets;
             let filelogs = cg_push.filelogs;
+            let pushed: Vec<_> = changesets.iter().map(|&(node, _)| node).collect();
+            let hook_changesets = hook_changesets(&changesets, &cg_push.file_sizes);
+            let draft = cg_push.draft;
+            let onto = cg_push.onto;
 
-            let bundle2 = resolver
+            resolver
                 .resolve_b2xtreegroup2(bundle2)
                 .and_then({
                     let resolver = resolver.clone();
@@ -71,43 +101,268 @@ pub fn resolve(
 
                     move |(manifests, bundle2)| {
                         resolver
-                            .upload_changesets(changesets, filelogs, manifests)
-                            .map(|()| bundle2)
+                            .maybe_resolve_obsmarkers(bundle2)
+                            .map(|(obsmarkers, bundle2)| (manifests, obsmarkers, bundle2))
                     }
                 })
-                .flatten_stream()
-                .boxify();
+                .and_then({
+                    let resolver = resolver.clone();
 
-            resolver
-                .ensure_stream_finished(bundle2)
-                .and_then(move |()| resolver.prepare_response(changegroup_id))
+                    move |(manifests, obsmarkers, bundle2)| {
+                        resolver
+                            .resolve_pushkeys(bundle2)
+                            .map(|(pushkeys, bundle2)| (manifests, obsmarkers, pushkeys, bundle2))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, obsmarkers, pushkeys, bundle2)| {
+                        resolver
+                            .ensure_stream_finished(bundle2)
+                            .map(|()| (manifests, obsmarkers, pushkeys))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, obsmarkers, pushkeys)| {
+                        resolver
+                            .run_hooks(hook_changesets)
+                            .map(|()| (manifests, obsmarkers, pushkeys))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, obsmarkers, pushkeys)| {
+                        resolver
+                            .check_pushkey_bookmarks(&pushkeys)
+                            .map(|()| (manifests, obsmarkers, pushkeys))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, obsmarkers, pushkeys)| {
+                        resolver
+                            .pushed_phases(pushed, draft)
+                            .map(|phases| (manifests, obsmarkers, pushkeys, phases))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(manifests, obsmarkers, pushkeys, phases)| {
+                        // The heads the push adds are the same once rebased, as only stacks are
+                        // rebased, and the ones it replaces are those it's based on
+                        let (added, bases) = push_heads(&changesets);
+                        let uploaded = match onto {
+                            Some(onto) => {
+                                resolver.pushrebase(onto, changesets, filelogs, manifests)
+                            }
+                            None => {
+                                let upload = {
+                                    let resolver = resolver.clone();
+                                    move |replaced| {
+                                        resolver
+                                            .upload_changesets(changesets, filelogs, manifests)
+                                            .map(move |()| (Vec::new(), replaced))
+                                    }
+                                };
+                                resolver.count_heads(bases).and_then(upload).boxify()
+                            }
+                        };
+                        uploaded.map({
+                            let resolver = resolver.clone();
+                            move |(rebased, replaced)| {
+                                let phases = pushrebase::rebased_phases(phases, &rebased);
+                                resolver.reply(|reply| {
+                                    for (old, new) in rebased {
+                                        reply.rebased(old, new);
+                                    }
+                                });
+                                let heads_num_diff = added as i64 - replaced;
+                                (obsmarkers, pushkeys, heads_num_diff, phases)
+                            }
+                        })
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(obsmarkers, pushkeys, heads_num_diff, phases)| {
+                        resolver
+                            .apply_phases(phases)
+                            .map(move |()| (obsmarkers, pushkeys, heads_num_diff))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(obsmarkers, pushkeys, heads_num_diff)| {
+                        resolver
+                            .apply_obsmarkers(obsmarkers)
+                            .map(move |()| (pushkeys, heads_num_diff))
+                    }
+                })
+                .and_then({
+                    let resolver = resolver.clone();
+
+                    move |(pushkeys, heads_num_diff)| {
+                        resolver
+                            .apply_pushkeys(pushkeys)
+                            .map(move |()| heads_num_diff)
+                    }
+                })
+                .and_then(move |heads_num_diff| {
+                    resolver.reply(|reply| {
+                        reply.changegroup(changegroup_id, heads_num_diff);
+                    });
+                    resolver.build_reply()
+                })
+                // A push rejected by the hooks isn't an error of the server, the client is told
+                // why instead
+                .or_else(move |error| match error.downcast::<ErrorKind>() {
+                    Ok(ErrorKind::HooksRejected(rejections)) => {
+                        let mut reply = ReplyBuilder::new();
+                        reply.output("push rejected by hooks:");
+                        for rejection in &rejections {
+                            reply.output(format!("  {}", rejection));
+                        }
+                        reply
+                            .abort(format!("{} hook failures", rejections.len()))
+                            .build()
+                    }
+                    Ok(kind) => err(kind.into()).boxify(),
+                    Err(error) => err(error).boxify(),
+                })
         })
         .map_err(|err| err.context("bundle2-resolver error").into())
         .boxify()
 }
 
+/// How many of the pushed changesets no other pushed changeset is based on, which become heads,
+/// and the changesets outside of the push the others are based on, which stop being heads if
+/// they were. Counting them instead of the heads of the whole repo before and after the push
+/// leaves out what concurrent pushes do.
+fn push_heads(changesets: &Changesets) -> (usize, Vec<NodeHash>) {
+    let pushed: HashSet<_> = changesets.iter().map(|&(node, _)| node).collect();
+    let mut parents = HashSet::new();
+    let mut bases = Vec::new();
+    for &(_, ref revlog_cs) in changesets {
+        let (p1, p2) = revlog_cs.parents().get_nodes();
+        for parent in p1.into_iter().chain(p2) {
+            if parents.insert(*parent) && !pushed.contains(parent) {
+                bases.push(*parent);
+            }
+        }
+    }
+    let added = pushed.iter().filter(|node| !parents.contains(*node)).count();
+    (added, bases)
+}
+
+/// What the hooks get to see of the pushed changesets
+fn hook_changesets(changesets: &Changesets, file_sizes: &FileSizes) -> Vec<HookChangeset> {
+    changesets
+        .iter()
+        .map(|&(node, ref revlog_cs)| {
+            let files = revlog_cs
+                .files()
+                .iter()
+                .map(|path| HookFile {
+                    path: path.clone(),
+                    size: file_sizes
+                        .get(&(node, path.clone()))
+                        .cloned()
+                        .unwrap_or(None),
+                })
+                .collect();
+            HookChangeset {
+                node,
+                user: String::from_utf8_lossy(revlog_cs.user()).into_owned(),
+                message: String::from_utf8_lossy(revlog_cs.comments()).into_owned(),
+                files,
+            }
+        })
+        .collect()
+}
+
+/// The record of what a push uploads, see `BlobRepo::record_push_uploads`
+fn push_uploads(
+    changesets: &Changesets,
+    filelogs: &Filelogs,
+    manifests: &Manifests,
+) -> PushUploads {
+    PushUploads::new(
+        changesets.iter().map(|&(node, _)| node).collect(),
+        filelogs
+            .keys()
+            .chain(manifests.keys())
+            .map(|&(node, _)| node)
+            .collect(),
+    )
+}
+
 fn next_item(
     bundle2: BoxStream<Bundle2Item, Error>,
 ) -> BoxFuture<(Option<Bundle2Item>, BoxStream<Bundle2Item, Error>), Error> {
     bundle2.into_future().map_err(|(err, _)| err).boxify()
 }
 
+/// Put an item which was read but not handled back in front of the stream
+fn push_back(
+    item: Option<Bundle2Item>,
+    bundle2: BoxStream<Bundle2Item, Error>,
+) -> BoxStream<Bundle2Item, Error> {
+    match item {
+        Some(item) => stream::once(Ok(item)).chain(bundle2).boxify(),
+        None => bundle2,
+    }
+}
+
 struct ChangegroupPush {
     part_id: PartId,
     changesets: Changesets,
     filelogs: Filelogs,
+    file_sizes: FileSizes,
+    /// Scratch commits pushed through infinitepush aren't published
+    draft: bool,
+    /// The bookmark changesets pushed through pushrebase are rebased onto
+    onto: Option<Bytes>,
 }
 
-/// Holds repo and logger for convienience access from it's methods
+/// Holds repo, logger and hooks for convienience access from it's methods, and the reply to the
+/// push, which records the outcomes of its parts as they are resolved
 #[derive(Clone)]
 struct Bundle2Resolver {
     repo: Arc<BlobRepo>,
     logger: Logger,
+    hooks: Arc<Vec<Arc<Hook>>>,
+    reply: Arc<Mutex<ReplyBuilder>>,
+    /// Who is pushing, as logged with the bookmarks the push moves
+    author: Arc<String>,
 }
 
 impl Bundle2Resolver {
-    fn new(repo: Arc<BlobRepo>, logger: Logger) -> Self {
-        Self { repo, logger }
+    fn new(repo: Arc<BlobRepo>, logger: Logger, hooks: Vec<Arc<Hook>>, author: String) -> Self {
+        Self {
+            repo,
+            logger,
+            hooks: Arc::new(hooks),
+            reply: Arc::new(Mutex::new(ReplyBuilder::new())),
+            author: Arc::new(author),
+        }
+    }
+
+    /// Record outcomes of the parts in the reply
+    fn reply<F: FnOnce(&mut ReplyBuilder)>(&self, record: F) {
+        let mut reply = self.reply.lock().expect("lock poisoned");
+        record(&mut reply);
+    }
+
+    fn build_reply(&self) -> BoxFuture<Bytes, Error> {
+        self.reply.lock().expect("lock poisoned").build()
     }
 
     /// Parse Start and Replycaps and ignore their content
@@ -128,6 +383,60 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Parse check:bookmarks, if it's there, and make sure that the bookmarks the pusher saw
+    /// didn't move
+    fn maybe_resolve_check_bookmarks(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<BoxStream<Bundle2Item, Error>, Error> {
+        let repo = self.repo.clone();
+
+        next_item(bundle2)
+            .and_then(move |(check_bookmarks, bundle2)| match check_bookmarks {
+                Some(Bundle2Item::CheckBookmarks(_, bookmarks)) => bookmarks
+                    .and_then(move |bookmarks| {
+                        let expected = bookmarks
+                            .into_iter()
+                            .map(|(key, node)| (key.to_vec(), node.map(ChangesetId::new)))
+                            .collect();
+                        check_bookmarks(repo, expected)
+                    })
+                    .map(|()| bundle2)
+                    .boxify(),
+                other => ok(push_back(other, bundle2)).boxify(),
+            })
+            .map_err(|err| err.context("While resolving CheckBookmarks").into())
+            .boxify()
+    }
+
+    /// Parse check:heads, if it's there, and make sure that the heads the pusher saw are still the
+    /// heads of the repo
+    fn maybe_resolve_check_heads(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<BoxStream<Bundle2Item, Error>, Error> {
+        let repo = self.repo.clone();
+
+        next_item(bundle2)
+            .and_then(move |(check_heads, bundle2)| match check_heads {
+                Some(Bundle2Item::CheckHeads(_, heads)) => heads
+                    .join(repo.get_heads().collect())
+                    .and_then(|(expected, actual)| {
+                        let expected: HashSet<_> = expected.into_iter().collect();
+                        let actual: HashSet<_> = actual.into_iter().collect();
+                        ensure_msg!(
+                            expected == actual,
+                            "push race: repository changed while pushing - please try again"
+                        );
+                        Ok(bundle2)
+                    })
+                    .boxify(),
+                other => ok(push_back(other, bundle2)).boxify(),
+            })
+            .map_err(|err| err.context("While resolving CheckHeads").into())
+            .boxify()
+    }
+
     /// Parse changegroup.
     /// The ChangegroupId will be used in the last step for preparing response
     /// The Changesets should be parsed as RevlogChangesets and used for uploading changesets
@@ -140,25 +449,64 @@ impl Bundle2Resolver {
         let repo = self.repo.clone();
 
         next_item(bundle2)
-            .and_then(move |(changegroup, bundle2)| match changegroup {
+            .map(|(changegroup, bundle2)| {
+                let draft = match changegroup {
+                    Some(Bundle2Item::B2xInfinitepush(..)) => true,
+                    _ => false,
+                };
+                let rebase = match changegroup {
+                    Some(Bundle2Item::B2xRebase(..)) => true,
+                    _ => false,
+                };
+                (changegroup, bundle2, draft, rebase)
+            })
+            .and_then(move |(changegroup, bundle2, draft, rebase)| match changegroup {
                 Some(Bundle2Item::Changegroup(header, parts))
-                | Some(Bundle2Item::B2xInfinitepush(header, parts)) => {
+                | Some(Bundle2Item::B2xInfinitepush(header, parts))
+                | Some(Bundle2Item::B2xRebase(header, parts)) => {
                     let part_id = header.part_id();
+                    let onto = if rebase {
+                        let onto = try_boxfuture!(
+                            header
+                                .mparams()
+                                .get("onto")
+                                .cloned()
+                                .ok_or_else(|| format_err!("Rebase part without onto"))
+                        );
+                        Some(onto)
+                    } else {
+                        None
+                    };
                     let (c, f) = split_changegroup(parts);
+                    // The sizes are recorded for the hooks as the files go by
+                    let file_sizes = Arc::new(Mutex::new(HashMap::new()));
+                    let filelogs = convert_to_revlog_filelog(repo.clone(), f).map({
+                        let file_sizes = file_sizes.clone();
+                        move |filelog| {
+                            if let Some(path) = filelog.path.mpath() {
+                                let key = (filelog.linknode, path.clone());
+                                let mut file_sizes = file_sizes.lock().expect("lock poisoned");
+                                file_sizes.insert(key, filelog.blob.size());
+                            }
+                            filelog
+                        }
+                    });
                     convert_to_revlog_changesets(c)
                         .collect()
                         .join(
-                            upload_blobs(
-                                repo.clone(),
-                                convert_to_revlog_filelog(repo, f),
-                                UploadBlobsType::EnsureNoDuplicates,
-                            ).map_err(|err| err.context("While uploading File Blobs").into()),
+                            upload_blobs(repo, filelogs, UploadBlobsType::EnsureNoDuplicates)
+                                .map_err(|err| err.context("While uploading File Blobs").into()),
                         )
                         .map(move |(changesets, filelogs)| {
+                            let mut sizes = file_sizes.lock().expect("lock poisoned");
+                            let file_sizes = mem::replace(&mut *sizes, HashMap::new());
                             let cg_push = ChangegroupPush {
                                 part_id,
                                 changesets,
                                 filelogs,
+                                file_sizes,
+                                draft,
+                                onto,
                             };
                             (cg_push, bundle2)
                         })
@@ -181,7 +529,8 @@ impl Bundle2Resolver {
 
         next_item(bundle2)
             .and_then(move |(b2xtreegroup2, bundle2)| match b2xtreegroup2 {
-                Some(Bundle2Item::B2xTreegroup2(_, parts)) => {
+                Some(Bundle2Item::B2xTreegroup2(_, parts))
+                | Some(Bundle2Item::B2xRebasePack(_, parts)) => {
                     upload_blobs(
                         repo,
                         TreemanifestBundle2Parser::new(parts),
@@ -208,10 +557,7 @@ impl Bundle2Resolver {
                     Some(Bundle2Item::B2xInfinitepushBookmarks(_, bookmarks)) => {
                         bookmarks.collect().map(|_| ((), bundle2)).boxify()
                     }
-                    None => Ok(((), bundle2)).into_future().boxify(),
-                    _ => err(format_err!(
-                        "Expected B2xInfinitepushBookmarks or end of the stream"
-                    )).boxify(),
+                    other => Ok(((), push_back(other, bundle2))).into_future().boxify(),
                 },
             )
             .map_err(|err| {
@@ -221,11 +567,56 @@ impl Bundle2Resolver {
             .boxify()
     }
 
+    /// Parse obsmarkers, if it's there. The markers are only stored once the changesets are
+    /// uploaded
+    fn maybe_resolve_obsmarkers(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(Option<(PartId, Vec<ObsMarker>)>, BoxStream<Bundle2Item, Error>), Error> {
+        next_item(bundle2)
+            .and_then(move |(obsmarkers, bundle2)| match obsmarkers {
+                Some(Bundle2Item::Obsmarkers(header, markers)) => {
+                    let part_id = header.part_id();
+                    markers
+                        .map(move |markers| (Some((part_id, markers)), bundle2))
+                        .boxify()
+                }
+                other => ok((None, push_back(other, bundle2))).boxify(),
+            })
+            .map_err(|err| err.context("While resolving Obsmarkers").into())
+            .boxify()
+    }
+
+    /// Parse the pushkey parts up to the end of the stream. They are only applied once the
+    /// changesets are uploaded, so that bookmarks never point to missing changesets
+    fn resolve_pushkeys(
+        &self,
+        bundle2: BoxStream<Bundle2Item, Error>,
+    ) -> BoxFuture<(Vec<Pushkey>, BoxStream<Bundle2Item, Error>), Error> {
+        loop_fn((Vec::new(), bundle2), |(mut pushkeys, bundle2)| {
+            next_item(bundle2).and_then(move |(pushkey, bundle2)| match pushkey {
+                Some(Bundle2Item::Pushkey(header, part)) => {
+                    let pushkey = try_boxfuture!(Pushkey::from_header(&header));
+                    part.map(move |()| {
+                        pushkeys.push(pushkey);
+                        Loop::Continue((pushkeys, bundle2))
+                    }).boxify()
+                }
+                other => ok(Loop::Break((pushkeys, push_back(other, bundle2)))).boxify(),
+            })
+        }).map_err(|err| err.context("While resolving Pushkey").into())
+        .boxify()
+    }
+
     /// Takes parsed Changesets and scheduled for upload Filelogs and Manifests. The content of
     /// Manifests is used to figure out DAG of dependencies between a given Changeset and the
     /// Manifests and Filelogs it adds.
     /// The Changesets are scheduled for uploading and a Future is returned, whose completion means
-    /// that the changesets were uploaded
+    /// that the changesets were uploaded. At most MAX_CHANGESETS_IN_FLIGHT of them are uploading
+    /// at once, and the Manifests and Filelogs are dropped as the changesets using them complete.
+    /// What is uploaded is recorded first, the record being marked done once everything is, so
+    /// that the blobs of a push failing part way can be garbage collected. The push goes to the
+    /// operation log of the repo before any of it is uploaded.
     fn upload_changesets(
         &self,
         changesets: Changesets,
@@ -236,21 +627,20 @@ impl Bundle2Resolver {
             repo: Arc<BlobRepo>,
             node: NodeHash,
             revlog_cs: RevlogChangeset,
-            mut uploaded_changesets: UploadedChangesets,
-            filelogs: &Filelogs,
-            manifests: &Manifests,
-        ) -> BoxFuture<UploadedChangesets, Error> {
+            mut state: UploadState,
+        ) -> BoxFuture<UploadState, Error> {
             let (p1, p2) = {
                 let (p1, p2) = revlog_cs.parents().get_nodes();
                 (
-                    get_parent(&repo, &uploaded_changesets, p1.cloned()),
-                    get_parent(&repo, &uploaded_changesets, p2.cloned()),
+                    get_parent(&repo, &state.uploaded, p1.cloned()),
+                    get_parent(&repo, &state.uploaded, p2.cloned()),
                 )
             };
-            let (root_manifest, entries) = try_boxfuture!(walk_manifests(
+            let (root_manifest, entries, walked) = try_boxfuture!(walk_manifests(
                 *revlog_cs.manifestid(),
-                &manifests,
-                &filelogs
+                &state.manifests,
+                &state.filelogs,
+                &mut state.roots,
             ));
 
             p1.join(p2)
@@ -266,8 +656,16 @@ impl Bundle2Resolver {
                         String::from_utf8(revlog_cs.comments().into())?,
                     );
 
-                    uploaded_changesets.insert(node, scheduled_uploading);
-                    Ok(uploaded_changesets)
+                    state.uploaded.insert(node, scheduled_uploading);
+                    state.in_flight.push_back((node, walked));
+                    Ok(state)
+                })
+                .and_then(|state| {
+                    if state.in_flight.len() > MAX_CHANGESETS_IN_FLIGHT {
+                        state.complete_oldest()
+                    } else {
+                        ok(state).boxify()
+                    }
                 })
                 .boxify()
         }
@@ -278,37 +676,192 @@ impl Bundle2Resolver {
         debug!(self.logger, "filelogs: {:?}", filelogs.keys());
         debug!(self.logger, "manifests: {:?}", manifests.keys());
 
-        stream::iter_ok(changesets)
-            .fold(
-                HashMap::new(),
-                move |uploaded_changesets, (node, revlog_cs)| {
-                    upload_changeset(
-                        repo.clone(),
-                        node.clone(),
-                        revlog_cs,
-                        uploaded_changesets,
-                        &filelogs,
-                        &manifests,
-                    ).map_err(move |err| {
+        // Recorded so that the blobs of a push which fails part way can be found and collected
+        let push_uploads = push_uploads(&changesets, &filelogs, &manifests);
+
+        let state = UploadState {
+            uploaded: HashMap::new(),
+            filelogs,
+            manifests,
+            roots: HashMap::new(),
+            in_flight: VecDeque::new(),
+        };
+
+        let pushed = push_uploads.changesets.clone();
+        let uploads_id = self.repo.record_push_uploads(&push_uploads);
+        let upload = stream::iter_ok(changesets)
+            .fold(state, move |state, (node, revlog_cs)| {
+                upload_changeset(repo.clone(), node.clone(), revlog_cs, state).map_err(
+                    move |err| {
                         err.context(format!(
                             "While trying to upload Changeset with id {:?}",
                             node
                         ))
-                    })
-                },
-            )
-            .and_then(|uploaded_changesets| {
+                    },
+                )
+            })
+            .and_then(|state| {
                 stream::futures_unordered(
-                    uploaded_changesets
+                    state
+                        .uploaded
                         .into_iter()
                         .map(|(_, cs)| cs.get_completed_changeset()),
                 ).map_err(Error::from)
                     .for_each(|_| Ok(()))
-            })
+            });
+
+        let upload = uploads_id
+            .and_then(move |id| upload.map(move |()| id))
+            .and_then({
+                let repo = self.repo.clone();
+                move |id| repo.complete_push_uploads(&id)
+            });
+
+        self.repo
+            .log_push(&self.author, pushed, push_uploads.id(), upload)
             .map_err(|err| err.context("While uploading Changesets to BlobRepo").into())
             .boxify()
     }
 
+    /// Uploads the changesets pushed through pushrebase onto the bookmark `onto`, rebasing them
+    /// if the bookmark moved since the changeset they're based on, and moves the bookmark to the
+    /// last of them. Resolves to the changesets which were rebased, with what they became, and to
+    /// whether the changeset they landed on was a head, as 1 or 0.
+    fn pushrebase(
+        &self,
+        onto: Bytes,
+        changesets: Changesets,
+        filelogs: Filelogs,
+        manifests: Manifests,
+    ) -> BoxFuture<(Vec<(NodeHash, NodeHash)>, i64), Error> {
+        let pushed_top = match changesets.last() {
+            Some(&(node, _)) => node,
+            None => return ok((Vec::new(), 0)).boxify(),
+        };
+        let base = try_boxfuture!(pushrebase::stack_base(&changesets));
+        let bookmark = String::from_utf8_lossy(&onto).into_owned();
+        let resolver = self.clone();
+        let repo = self.repo.clone();
+        let author = self.author.clone();
+
+        self.repo
+            .get_bookmark_value(&onto)
+            .and_then({
+                let bookmark = bookmark.clone();
+                move |head| match head {
+                    Some((head, _)) => Ok(head),
+                    None => Err(ErrorKind::PushrebaseBookmarkMissing(bookmark).into()),
+                }
+            })
+            .and_then({
+                let resolver = resolver.clone();
+                move |head| {
+                    resolver
+                        .count_heads(vec![head.into_nodehash()])
+                        .map(move |replaced| (head, replaced))
+                }
+            })
+            .and_then({
+                let repo = repo.clone();
+                move |(head, replaced)| if Some(head.into_nodehash()) == base {
+                    // The push is based on the bookmark, there is nothing to rebase
+                    resolver
+                        .upload_changesets(changesets, filelogs, manifests)
+                        .map(move |()| (head, pushed_top, Vec::new(), replaced))
+                        .boxify()
+                } else {
+                    // Rebasing uploads entries too, which garbage collection must know about
+                    let uploads = push_uploads(&changesets, &filelogs, &manifests);
+                    repo.record_push_uploads(&uploads)
+                        .and_then(move |id| {
+                            pushrebase::rebase(
+                                repo.clone(),
+                                base,
+                                head,
+                                changesets,
+                                filelogs,
+                                manifests,
+                            ).and_then(move |rebased| {
+                                repo.complete_push_uploads(&id).map(move |()| rebased)
+                            })
+                        })
+                        .map(move |rebased| {
+                            let top = rebased.last().map_or(pushed_top, |&(_, new)| new);
+                            (head, top, rebased, replaced)
+                        })
+                        .boxify()
+                }
+            })
+            .and_then(move |(head, top, rebased, replaced)| {
+                let top = Some(ChangesetId::new(top));
+                repo.update_bookmark(&onto, Some(head), top, &author, "pushrebase")
+                    .and_then(move |moved| {
+                        if moved {
+                            Ok((rebased, replaced))
+                        } else {
+                            Err(ErrorKind::PushrebaseBookmarkMoved(bookmark).into())
+                        }
+                    })
+            })
+            .map_err(|err| err.context("While pushrebasing").into())
+            .boxify()
+    }
+
+    /// Phases to give the pushed changesets once they're uploaded. Scratch commits stay draft,
+    /// except those which were already in the repo and keep their phase, while every other push
+    /// is published.
+    fn pushed_phases(
+        &self,
+        pushed: Vec<NodeHash>,
+        draft: bool,
+    ) -> BoxFuture<Vec<(NodeHash, Phase)>, Error> {
+        if !draft {
+            let phases = pushed.into_iter().map(|node| (node, Phase::Public)).collect();
+            return ok(phases).boxify();
+        }
+
+        let repo = self.repo.clone();
+        stream::iter_ok(pushed)
+            .and_then(move |node| {
+                repo.changeset_exists(&ChangesetId::new(node))
+                    .map(move |exists| (node, exists))
+            })
+            .filter_map(|(node, exists)| if exists { None } else { Some((node, Phase::Draft)) })
+            .collect()
+            .boxify()
+    }
+
+    fn apply_phases(&self, phases: Vec<(NodeHash, Phase)>) -> BoxFuture<(), Error> {
+        let repo = self.repo.clone();
+        stream::iter_ok(phases)
+            .for_each(move |(node, phase)| match phase {
+                Phase::Draft => repo.mark_draft(&node),
+                Phase::Public => repo.mark_public(&node),
+            })
+            .map_err(|err| err.context("While updating phases").into())
+            .boxify()
+    }
+
+    /// Store the pushed obsolescence markers, replying how many of them were new
+    fn apply_obsmarkers(
+        &self,
+        obsmarkers: Option<(PartId, Vec<ObsMarker>)>,
+    ) -> BoxFuture<(), Error> {
+        let resolver = self.clone();
+        match obsmarkers {
+            Some((part_id, markers)) => self.repo
+                .add_obsmarkers(markers)
+                .map(move |new| {
+                    resolver.reply(|reply| {
+                        reply.obsmarkers(part_id, new);
+                    })
+                })
+                .map_err(|err| err.context("While storing Obsmarkers").into())
+                .boxify(),
+            None => ok(()).boxify(),
+        }
+    }
+
     /// Ensures that the next item in stream is None
     fn ensure_stream_finished(
         &self,
@@ -322,23 +875,324 @@ impl Bundle2Resolver {
             .boxify()
     }
 
-    /// Takes a changegroup id and prepares a Bytes response containing Bundle2 with reply to
-    /// changegroup part saying that the push was successful
-    fn prepare_response(&self, changegroup_id: PartId) -> BoxFuture<Bytes, Error> {
-        let writer = Cursor::new(Vec::new());
-        let mut bundle = Bundle2EncodeBuilder::new(writer);
-        // Mercurial currently hangs while trying to read compressed bundles over the wire:
-        // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
-        bundle.add_part(try_boxfuture!(parts::replychangegroup_part(
-            parts::ChangegroupApplyResult::Success { heads_num_diff: 0 },
-            changegroup_id,
-        )));
-        bundle
-            .build()
-            .map(|cursor| Bytes::from(cursor.into_inner()))
-            .map_err(|err| err.context("While preparing response").into())
+    /// How many of `nodes` are heads of the repo
+    fn count_heads(&self, nodes: Vec<NodeHash>) -> BoxFuture<i64, Error> {
+        let repo = self.repo.clone();
+        stream::iter_ok(nodes)
+            .and_then(move |node| repo.is_head(&node))
+            .fold(0, |count, is_head| {
+                Ok::<_, Error>(if is_head { count + 1 } else { count })
+            })
+            .boxify()
+    }
+
+    /// Run the hooks against the pushed changesets, failing with all the rejections if any
+    fn run_hooks(&self, changesets: Vec<HookChangeset>) -> BoxFuture<(), Error> {
+        let logger = self.logger.clone();
+        hooks::run_hooks(&self.hooks, changesets)
+            .map_err(|err| err.context("While running hooks").into())
+            .and_then(move |rejections| {
+                if rejections.is_empty() {
+                    return Ok(());
+                }
+                for rejection in &rejections {
+                    info!(logger, "push rejected by hook: {}", rejection);
+                }
+                Err(ErrorKind::HooksRejected(rejections).into())
+            })
+            .boxify()
+    }
+
+    /// Apply the pushkeys one by one, returning for each of them its part id and whether it
+    /// succeeded
+    /// Make sure that the bookmarks the pushkeys move are still where the pusher saw them, before
+    /// anything is uploaded
+    fn check_pushkey_bookmarks(&self, pushkeys: &[Pushkey]) -> BoxFuture<(), Error> {
+        let expected = try_boxfuture!(bookmark_updates(pushkeys))
+            .into_iter()
+            .map(|(key, old, _)| (key, old))
+            .collect();
+        check_bookmarks(self.repo.clone(), expected)
+    }
+
+    /// Apply the pushkeys, moving all the bookmarks together. The push fails if any bookmark
+    /// can't be moved, while the other pushkeys get a failed reply.
+    fn apply_pushkeys(&self, pushkeys: Vec<Pushkey>) -> BoxFuture<(), Error> {
+        let resolver = self.clone();
+        let repo = self.repo.clone();
+        let logger = self.logger.clone();
+        let author = self.author.clone();
+
+        let (bookmarks, others): (Vec<_>, Vec<_>) = pushkeys
+            .into_iter()
+            .partition(|pushkey| pushkey.namespace.as_ref() == b"bookmarks");
+        let updates = try_boxfuture!(bookmark_updates(&bookmarks));
+        let names: Vec<_> = updates
+            .iter()
+            .map(|&(ref key, _, _)| String::from_utf8_lossy(key).into_owned())
+            .collect();
+        let bookmark_results: Vec<_> = bookmarks
+            .iter()
+            .map(|pushkey| (pushkey.part_id, true))
+            .collect();
+        let targets: Vec<_> = updates.iter().filter_map(|&(_, _, new)| new).collect();
+
+        let move_bookmarks = stream::iter_ok(targets)
+            .for_each({
+                let repo = repo.clone();
+                move |target| {
+                    repo.changeset_exists(&target).and_then(move |exists| {
+                        ensure_msg!(exists, "bookmark moved to unknown changeset {}", target);
+                        Ok(())
+                    })
+                }
+            })
+            .and_then({
+                let repo = repo.clone();
+                let author = author.clone();
+                move |()| {
+                    if updates.is_empty() {
+                        return ok(true).boxify();
+                    }
+                    repo.update_bookmarks(updates, &author, "push")
+                }
+            })
+            .and_then(move |moved| {
+                if !moved {
+                    bail_err!(ErrorKind::BookmarksMoved(names));
+                }
+                Ok(bookmark_results)
+            });
+
+        let apply_others = stream::iter_ok(others)
+            .and_then(move |pushkey| {
+                let part_id = pushkey.part_id;
+                let logger = logger.clone();
+                pushkey
+                    .apply(repo.clone(), &author)
+                    .map(move |res| {
+                        if !res {
+                            info!(logger, "pushkey {} failed", part_id);
+                        }
+                        (part_id, res)
+                    })
+            })
+            .collect();
+
+        move_bookmarks
+            .and_then(move |mut results| {
+                apply_others.map(move |others| {
+                    results.extend(others);
+                    results
+                })
+            })
+            .map(move |results| {
+                resolver.reply(|reply| {
+                    for (part_id, res) in results {
+                        reply.pushkey(part_id, res);
+                    }
+                })
+            })
+            .map_err(|err| err.context("While applying Pushkeys").into())
+            .boxify()
+    }
+}
+
+/// A key update sent with the push
+struct Pushkey {
+    part_id: PartId,
+    namespace: Bytes,
+    key: Bytes,
+    old: Bytes,
+    new: Bytes,
+}
+
+impl Pushkey {
+    fn from_header(header: &PartHeader) -> Result<Self> {
+        let mparam = |name: &str| -> Result<Bytes> {
+            header
+                .mparams()
+                .get(name)
+                .cloned()
+                .ok_or_else(|| format_err!("Pushkey part without {}", name))
+        };
+
+        Ok(Pushkey {
+            part_id: header.part_id(),
+            namespace: mparam("namespace")?,
+            key: mparam("key")?,
+            old: mparam("old")?,
+            new: mparam("new")?,
+        })
+    }
+
+    fn apply(self, repo: Arc<BlobRepo>, author: &str) -> BoxFuture<bool, Error> {
+        apply_pushkey(repo, &self.namespace, self.key, &self.old, &self.new, author)
+    }
+}
+
+/// Set `key` in `namespace` from `old` to `new`, as the pushkey part and wire command do, on
+/// behalf of `author`. Resolves to false, changing nothing, if the key isn't at `old` or if the
+/// namespace can't be pushed to.
+pub fn apply_pushkey(
+    repo: Arc<BlobRepo>,
+    namespace: &[u8],
+    key: Bytes,
+    old: &[u8],
+    new: &[u8],
+    author: &str,
+) -> BoxFuture<bool, Error> {
+    match namespace {
+        b"bookmarks" => {
+            let old = try_boxfuture!(parse_bookmark_value(old));
+            let new = try_boxfuture!(parse_bookmark_value(new));
+            let new_exists = match new {
+                Some(new) => repo.changeset_exists(&new),
+                None => ok(true).boxify(),
+            };
+            let author = author.to_string();
+            new_exists
+                .and_then(move |new_exists| {
+                    if new_exists {
+                        repo.update_bookmark(&key, old, new, &author, "pushkey")
+                    } else {
+                        ok(false).boxify()
+                    }
+                })
+                .boxify()
+        }
+        // Like in Mercurial, phases only ever move towards public, and moving a changeset to a
+        // phase it's already past succeeds without doing anything
+        b"phases" => {
+            let node = try_boxfuture!(parse_phase_key(&key));
+            let old = try_boxfuture!(parse_phase_value(old));
+            let new = try_boxfuture!(parse_phase_value(new));
+            repo.changeset_exists(&ChangesetId::new(node))
+                .and_then(move |exists| {
+                    if !exists {
+                        return ok(false).boxify();
+                    }
+                    repo.get_phase(&node)
+                        .and_then(move |current| {
+                            if current == old && new < old {
+                                repo.mark_public(&node).map(|()| true).boxify()
+                            } else {
+                                ok(current <= new).boxify()
+                            }
+                        })
+                        .boxify()
+                })
+                .boxify()
+        }
+        _ => ok(false).boxify(),
+    }
+}
+
+/// The moves of bookmarks among the pushkeys, from their old value to their new one
+fn bookmark_updates(
+    pushkeys: &[Pushkey],
+) -> Result<Vec<(Vec<u8>, Option<ChangesetId>, Option<ChangesetId>)>> {
+    pushkeys
+        .iter()
+        .filter(|pushkey| pushkey.namespace.as_ref() == b"bookmarks")
+        .map(|pushkey| {
+            Ok((
+                pushkey.key.to_vec(),
+                parse_bookmark_value(&pushkey.old)?,
+                parse_bookmark_value(&pushkey.new)?,
+            ))
+        })
+        .collect()
+}
+
+/// Fails with BookmarksMoved if any of the bookmarks isn't at the changeset expected, None
+/// meaning that it doesn't exist
+fn check_bookmarks(
+    repo: Arc<BlobRepo>,
+    expected: Vec<(Vec<u8>, Option<ChangesetId>)>,
+) -> BoxFuture<(), Error> {
+    stream::iter_ok(expected)
+        .and_then(move |(key, expected)| {
+            repo.get_bookmark_value(&key).map(move |current| {
+                let current = current.map(|(csid, _)| csid);
+                if current == expected {
+                    None
+                } else {
+                    Some(String::from_utf8_lossy(&key).into_owned())
+                }
+            })
+        })
+        .filter_map(|moved| moved)
+        .collect()
+        .and_then(|moved| {
+            if !moved.is_empty() {
+                bail_err!(ErrorKind::BookmarksMoved(moved));
+            }
+            Ok(())
+        })
+        .boxify()
+}
+
+/// Bookmark values are hex changeset ids, or empty when the bookmark doesn't exist
+fn parse_bookmark_value(value: &[u8]) -> Result<Option<ChangesetId>> {
+    if value.is_empty() {
+        return Ok(None);
+    }
+    let value = str::from_utf8(value)?;
+    let node = NodeHash::from_str(value)
+        .with_context(|_| format!("invalid bookmark value {}", value))?;
+    Ok(Some(ChangesetId::new(node)))
+}
+
+/// Phase keys are hex changeset ids
+fn parse_phase_key(key: &[u8]) -> Result<NodeHash> {
+    let key = str::from_utf8(key)?;
+    Ok(NodeHash::from_str(key).with_context(|_| format!("invalid phase key {}", key))?)
+}
+
+/// Phase values are Mercurial's phase numbers
+fn parse_phase_value(value: &[u8]) -> Result<Phase> {
+    str::from_utf8(value)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .and_then(Phase::from_u32)
+        .ok_or_else(|| format_err!("invalid phase {:?}", value))
+}
+
+/// How many changesets of a push can be uploading at once. The oldest one has to complete before
+/// more are scheduled, which bounds the state kept for huge pushes.
+const MAX_CHANGESETS_IN_FLIGHT: usize = 100;
+
+/// The state of the upload of the changesets of a push
+struct UploadState {
+    uploaded: UploadedChangesets,
+    /// The pushed Filelogs and Manifests which no completed changeset walked yet. Those walked by
+    /// a changeset are dropped once it completes, any later changeset finding them in the repo.
+    filelogs: Filelogs,
+    manifests: Manifests,
+    roots: HashMap<NodeHash, SharedBlobFuture>,
+    /// The changesets which may still be uploading, oldest first, with the keys of the Filelogs
+    /// and Manifests they walked
+    in_flight: VecDeque<(NodeHash, Vec<(NodeHash, RepoPath)>)>,
+}
+
+impl UploadState {
+    fn complete_oldest(mut self) -> BoxFuture<Self, Error> {
+        let (node, walked) = match self.in_flight.pop_front() {
+            Some(oldest) => oldest,
+            None => return ok(self).boxify(),
+        };
+        self.uploaded[&node]
+            .clone()
+            .get_completed_changeset()
+            .map_err(Error::from)
+            .map(move |_| {
+                for key in walked {
+                    self.filelogs.remove(&key);
+                    self.manifests.remove(&key);
+                }
+                self
+            })
             .boxify()
     }
 }
@@ -362,21 +1216,26 @@ fn get_parent(
 
 type BlobFuture = BoxFuture<(BlobEntry, RepoPath), Error>;
 type BlobStream = BoxStream<(BlobEntry, RepoPath), Error>;
+type SharedBlobFuture = Shared<BoxFuture<(BlobEntry, RepoPath), Compat<Error>>>;
 
 /// In order to generate the DAG of dependencies between Root Manifest and other Manifests and
 /// Filelogs we need to walk that DAG.
 /// This function starts with the Root Manifest Id and returns Future for Root Manifest and Stream
-/// of all dependent Manifests and Filelogs that were provided in this push.
+/// of all dependent Manifests and Filelogs that were provided in this push, along with the keys
+/// of those it walked. The uploads of Root Manifests are kept in `roots` once walked, since they
+/// are needed by any later changeset with the same Root Manifest.
 fn walk_manifests(
     manifest_root_id: ManifestId,
     manifests: &Manifests,
     filelogs: &Filelogs,
-) -> Result<(BlobFuture, BlobStream)> {
+    roots: &mut HashMap<NodeHash, SharedBlobFuture>,
+) -> Result<(BlobFuture, BlobStream, Vec<(NodeHash, RepoPath)>)> {
     fn walk_helper(
         path_taken: &MPath,
         manifest_content: &ManifestContent,
         manifests: &Manifests,
         filelogs: &Filelogs,
+        walked: &mut Vec<(NodeHash, RepoPath)>,
     ) -> Result<Vec<BlobFuture>> {
         if path_taken.len() > 4096 {
             bail_msg!(
@@ -406,10 +1265,13 @@ fn walk_manifests(
                         manifest_content,
                         manifests,
                         filelogs,
+                        walked,
                     )?);
+                    walked.push(key);
                 }
             } else {
-                if let Some(blobfuture) = filelogs.get(&(nodehash, RepoPath::file(next_path)?)) {
+                let key = (nodehash, RepoPath::file(next_path)?);
+                if let Some(blobfuture) = filelogs.get(&key) {
                     entries.push(
                         blobfuture
                             .clone()
@@ -417,6 +1279,7 @@ fn walk_manifests(
                             .from_err()
                             .boxify(),
                     );
+                    walked.push(key);
                 }
             }
         }
@@ -424,27 +1287,38 @@ fn walk_manifests(
         Ok(entries)
     }
 
-    let &(ref manifest_content, ref manifest_root) = manifests
-        .get(&(manifest_root_id.clone().into_nodehash(), RepoPath::root()))
-        .ok_or_else(|| format_err!("Missing root tree manifest"))?;
+    let root_key = (manifest_root_id.clone().into_nodehash(), RepoPath::root());
+    let mut walked = Vec::new();
+    let (manifest_root, entries) = match manifests.get(&root_key) {
+        Some(&(ref manifest_content, ref manifest_root)) => {
+            let entries = walk_helper(
+                &MPath::empty(),
+                &manifest_content,
+                manifests,
+                filelogs,
+                &mut walked,
+            )?;
+            roots.insert(root_key.0, manifest_root.clone());
+            walked.push(root_key);
+            (manifest_root.clone(), entries)
+        }
+        // The Root Manifest was walked by an earlier changeset, and so were its dependencies
+        None => match roots.get(&root_key.0) {
+            Some(manifest_root) => (manifest_root.clone(), Vec::new()),
+            None => bail_msg!("Missing root tree manifest"),
+        },
+    };
 
     Ok((
-        manifest_root
-            .clone()
-            .map(|it| (*it).clone())
-            .from_err()
-            .boxify(),
-        stream::futures_unordered(walk_helper(
-            &MPath::empty(),
-            &manifest_content,
-            manifests,
-            filelogs,
-        )?).map_err(move |err| {
-            err.context(format!(
-                "While walking dependencies of Root Manifest with id {:?}",
-                manifest_root_id
-            )).into()
-        })
+        manifest_root.map(|it| (*it).clone()).from_err().boxify(),
+        stream::futures_unordered(entries)
+            .map_err(move |err| {
+                err.context(format!(
+                    "While walking dependencies of Root Manifest with id {:?}",
+                    manifest_root_id
+                )).into()
+            })
             .boxify(),
+        walked,
     ))
 }
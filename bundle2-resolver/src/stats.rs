@@ -12,4 +12,6 @@ define_stats! {
     deltacache_dsize_large: histogram(400_000, 0, 100_000_000; P 50; P 95; P 99),
     deltacache_fsize: histogram(400, 0, 100_000, AVG, SUM, COUNT; P 50; P 95; P 99),
     deltacache_fsize_large: histogram(400_000, 0, 100_000_000; P 50; P 95; P 99),
+    deltacache_evictions: timeseries(RATE, SUM),
+    deltacache_refetches: timeseries(RATE, SUM),
 }
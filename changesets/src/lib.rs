@@ -41,6 +41,10 @@ mod schema;
 mod models;
 mod wrappers;
 
+/// How many changesets are looked up by a single query. SQLite doesn't allow more than 999
+/// parameters in a query.
+const MAX_QUERY_IDS: usize = 500;
+
 pub use errors::*;
 use models::{ChangesetInsertRow, ChangesetParentRow, ChangesetRow};
 use schema::{changesets, csparents};
@@ -71,6 +75,21 @@ pub trait Changesets: Send + Sync {
         repo_id: RepositoryId,
         cs_id: ChangesetId,
     ) -> BoxFuture<Option<ChangesetEntry>, Error>;
+
+    /// Check which of these commits are available, in the same order. This is much cheaper than
+    /// getting them one by one.
+    fn exist(&self, repo_id: RepositoryId, cs_ids: &[ChangesetId]) -> BoxFuture<Vec<bool>, Error>;
+
+    /// The first `limit` commits, in increasing order, whose ids are between `min` and `max`,
+    /// both included. Ids are compared as bytes, so the commits whose hash starts with a given
+    /// prefix make up a range, which is looked up with the index of the table.
+    fn get_range(
+        &self,
+        repo_id: RepositoryId,
+        min: ChangesetId,
+        max: ChangesetId,
+        limit: usize,
+    ) -> BoxFuture<Vec<ChangesetId>, Error>;
 }
 
 pub struct SqliteChangesets {
@@ -189,6 +208,50 @@ macro_rules! impl_changesets {
                 future::result(entry).boxify()
             }
 
+            /// Check which of these changesets are stored, with a query per batch of them.
+            fn exist(
+                &self,
+                repo_id: RepositoryId,
+                cs_ids: &[ChangesetId],
+            ) -> BoxFuture<Vec<bool>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let mut existing = HashSet::with_capacity(cs_ids.len());
+                for batch in cs_ids.chunks(MAX_QUERY_IDS) {
+                    let query = changesets::table
+                        .filter(changesets::repo_id.eq(repo_id))
+                        .filter(changesets::cs_id.eq_any(batch))
+                        .select(changesets::cs_id);
+                    match query.load::<ChangesetId>(&*connection) {
+                        Ok(rows) => existing.extend(rows),
+                        Err(err) => return future::err(err.into()).boxify(),
+                    }
+                }
+
+                let exist = cs_ids.iter().map(|cs_id| existing.contains(cs_id)).collect();
+                future::ok(exist).boxify()
+            }
+
+            fn get_range(
+                &self,
+                repo_id: RepositoryId,
+                min: ChangesetId,
+                max: ChangesetId,
+                limit: usize,
+            ) -> BoxFuture<Vec<ChangesetId>, Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connection.lock().expect("lock poisoned");
+                let query = changesets::table
+                    .filter(changesets::repo_id.eq(repo_id))
+                    .filter(changesets::cs_id.ge(min))
+                    .filter(changesets::cs_id.le(max))
+                    .order(changesets::cs_id.asc())
+                    .limit(limit as i64)
+                    .select(changesets::cs_id);
+                let rows = query.load::<ChangesetId>(&*connection);
+                future::result(rows.map_err(failure::Error::from)).boxify()
+            }
+
             /// Insert a new changeset into this table. Checks that all parents are already in
             /// storage.
             fn add(&self, cs: &ChangesetInsert) -> BoxFuture<(), Error> {
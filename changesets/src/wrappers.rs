@@ -26,4 +26,18 @@ impl Changesets for Arc<Changesets> {
     ) -> BoxFuture<Option<ChangesetEntry>, Error> {
         (**self).get(repo_id, cs_id)
     }
+
+    fn exist(&self, repo_id: RepositoryId, cs_ids: &[ChangesetId]) -> BoxFuture<Vec<bool>, Error> {
+        (**self).exist(repo_id, cs_ids)
+    }
+
+    fn get_range(
+        &self,
+        repo_id: RepositoryId,
+        min: ChangesetId,
+        max: ChangesetId,
+        limit: usize,
+    ) -> BoxFuture<Vec<ChangesetId>, Error> {
+        (**self).get_range(repo_id, min, max, limit)
+    }
 }
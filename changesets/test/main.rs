@@ -76,6 +76,62 @@ fn missing<C: Changesets>(changesets: C) {
     assert_eq!(result, None);
 }
 
+fn exist<C: Changesets>(changesets: C) {
+    let row = ChangesetInsert {
+        repo_id: REPO_ZERO,
+        cs_id: TWOS_CSID,
+        parents: vec![],
+    };
+    changesets
+        .add(&row)
+        .wait()
+        .expect("Adding new entry failed");
+
+    let result = changesets
+        .exist(REPO_ZERO, &[ONES_CSID, TWOS_CSID, THREES_CSID, TWOS_CSID])
+        .wait()
+        .expect("Checking existence failed");
+    assert_eq!(result, vec![false, true, false, true]);
+
+    let result = changesets
+        .exist(REPO_ZERO, &[])
+        .wait()
+        .expect("Checking existence of nothing failed");
+    assert_eq!(result, vec![]);
+}
+
+fn get_range<C: Changesets>(changesets: C) {
+    for cs_id in &[ONES_CSID, TWOS_CSID, THREES_CSID] {
+        let row = ChangesetInsert {
+            repo_id: REPO_ZERO,
+            cs_id: *cs_id,
+            parents: vec![],
+        };
+        changesets
+            .add(&row)
+            .wait()
+            .expect("Adding new entry failed");
+    }
+
+    let result = changesets
+        .get_range(REPO_ZERO, TWOS_CSID, FOURS_CSID, 10)
+        .wait()
+        .expect("Getting range failed");
+    assert_eq!(result, vec![TWOS_CSID, THREES_CSID]);
+
+    let result = changesets
+        .get_range(REPO_ZERO, ONES_CSID, THREES_CSID, 2)
+        .wait()
+        .expect("Getting limited range failed");
+    assert_eq!(result, vec![ONES_CSID, TWOS_CSID]);
+
+    let result = changesets
+        .get_range(REPO_ZERO, FOURS_CSID, FS_CSID, 10)
+        .wait()
+        .expect("Getting empty range failed");
+    assert_eq!(result, vec![]);
+}
+
 fn duplicate<C: Changesets>(changesets: C) {
     let row = ChangesetInsert {
         repo_id: REPO_ZERO,
@@ -221,6 +277,16 @@ macro_rules! changesets_test_impl {
                 missing($new_cb());
             }
 
+            #[test]
+            fn test_exist() {
+                exist($new_cb());
+            }
+
+            #[test]
+            fn test_get_range() {
+                get_range($new_cb());
+            }
+
             #[test]
             fn test_duplicate() {
                 duplicate($new_cb());
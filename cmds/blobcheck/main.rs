@@ -0,0 +1,344 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Consistency check of a repo: every changeset reachable from the heads, and every manifest and
+//! file node those refer to, is read back and its Mercurial node hash recomputed from the stored
+//! content and parents.
+//!
+//! The walk goes on past the nodes which are missing or corrupt, so that every problem is
+//! reported, together with what refers to the node. The exit status is 0 if the repo is
+//! consistent, 1 if problems were found and 2 if the check couldn't run at all.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate futures_ext;
+extern crate mercurial;
+extern crate mercurial_types;
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, SlogKVError};
+use futures::{future, stream, Future, Stream};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::BlobRepo;
+use futures_ext::{BoxFuture, FutureExt};
+use mercurial::changeset::serialize_cs;
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial_types::{BlobNode, Changeset, ChangesetId, MPath, NodeHash, Parents, RepositoryId,
+                      NULL_HASH};
+
+const EXIT_PROBLEMS: i32 = 1;
+const EXIT_FAILED: i32 = 2;
+
+#[derive(Clone, Debug)]
+enum Node {
+    Changeset(NodeHash),
+    /// The path is the directory of the manifest, empty for root manifests
+    Manifest(NodeHash, MPath),
+    File(NodeHash, MPath),
+}
+
+impl Node {
+    fn hash(&self) -> NodeHash {
+        match *self {
+            Node::Changeset(hash) | Node::Manifest(hash, _) | Node::File(hash, _) => hash,
+        }
+    }
+}
+
+impl fmt::Display for Node {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Node::Changeset(hash) => write!(fmt, "changeset {}", hash),
+            Node::Manifest(hash, ref path) if path.is_empty() => {
+                write!(fmt, "root manifest {}", hash)
+            }
+            Node::Manifest(hash, ref path) => write!(fmt, "manifest {} of {}/", hash, path),
+            Node::File(hash, ref path) => write!(fmt, "file {} of {}", hash, path),
+        }
+    }
+}
+
+/// Something wrong with a node. These are reported, but don't stop the check.
+#[derive(Debug, Fail)]
+enum Problem {
+    #[fail(display = "{} is missing", _0)]
+    Missing(String),
+    #[fail(display = "{} failed to read: {}", _0, _1)]
+    ReadFailed(String, Error),
+    #[fail(display = "{} is corrupt: {}", _0, _1)]
+    Corrupt(String, Error),
+    #[fail(display = "{} has the wrong hash {:?}", _0, _1)]
+    HashMismatch(String, Option<NodeHash>),
+}
+
+impl Problem {
+    fn from_read_error(node: &Node, err: Error) -> Self {
+        let missing = match err.downcast_ref::<blobrepo::ErrorKind>() {
+            Some(&blobrepo::ErrorKind::ChangesetMissing(_))
+            | Some(&blobrepo::ErrorKind::NodeMissing(_))
+            | Some(&blobrepo::ErrorKind::ContentMissing(..))
+            | Some(&blobrepo::ErrorKind::DeltaBaseMissing(..)) => true,
+            _ => false,
+        };
+        if missing {
+            Problem::Missing(node.to_string())
+        } else {
+            Problem::ReadFailed(node.to_string(), err)
+        }
+    }
+}
+
+/// What checking a node found: its problem if it has one, and the nodes it refers to, with what
+/// they're referred to by.
+type Checked = (Option<Problem>, Vec<(Node, String)>);
+
+fn run_blobcheck(repo: Arc<BlobRepo>, logger: &Logger, concurrency: usize) -> Result<usize> {
+    let mut core = Core::new()?;
+
+    let heads = core.run(repo.get_heads().collect())?;
+    info!(logger, "Checking the nodes reachable from {} heads", heads.len());
+    let roots: Vec<_> = heads
+        .into_iter()
+        .map(|head| (Node::Changeset(head), "heads".to_string()))
+        .collect();
+    let seen: HashSet<_> = roots.iter().map(|&(ref node, _)| node.hash()).collect();
+
+    let logger = logger.clone();
+    let check = future::loop_fn(
+        (roots, seen, 0, 0),
+        move |(frontier, mut seen, mut checked, mut problems)| {
+            let repo = repo.clone();
+            let logger = logger.clone();
+            stream::iter_ok(frontier)
+                .map(move |(node, referrer)| {
+                    check_node(repo.clone(), node)
+                        .map(move |(problem, refs)| ((problem, refs), referrer))
+                })
+                .buffer_unordered(concurrency)
+                .collect()
+                .map(move |batch: Vec<(Checked, String)>| {
+                    let mut frontier = Vec::new();
+                    for ((problem, refs), referrer) in batch {
+                        checked += 1;
+                        if let Some(problem) = problem {
+                            error!(logger, "{} (referenced by {})", problem, referrer);
+                            problems += 1;
+                        }
+                        for (node, referrer) in refs {
+                            if seen.insert(node.hash()) {
+                                frontier.push((node, referrer));
+                            }
+                        }
+                    }
+                    if frontier.is_empty() {
+                        future::Loop::Break((checked, problems))
+                    } else {
+                        future::Loop::Continue((frontier, seen, checked, problems))
+                    }
+                })
+        },
+    );
+
+    let (checked, problems) = core.run(check)?;
+    println!("Checked {} nodes, found {} problems", checked, problems);
+    Ok(problems)
+}
+
+fn check_node(repo: Arc<BlobRepo>, node: Node) -> BoxFuture<Checked, Error> {
+    match node {
+        Node::Changeset(hash) => check_changeset(repo, hash),
+        node => check_manifest_or_file(repo, node),
+    }
+}
+
+fn check_changeset(repo: Arc<BlobRepo>, hash: NodeHash) -> BoxFuture<Checked, Error> {
+    let node = Node::Changeset(hash);
+    repo.get_changeset_by_changesetid(&ChangesetId::new(hash))
+        .then(move |res| -> Result<Checked> {
+            let cs = match res {
+                Ok(cs) => cs,
+                Err(err) => return Ok((Some(Problem::from_read_error(&node, err)), vec![])),
+            };
+            let referrer = node.to_string();
+            let mut refs: Vec<_> = cs.parents()
+                .into_iter()
+                .map(|parent| (Node::Changeset(parent), referrer.clone()))
+                .collect();
+            let manifest = cs.manifestid().into_nodehash();
+            if manifest != NULL_HASH {
+                refs.push((Node::Manifest(manifest, MPath::empty()), referrer));
+            }
+
+            let mut data = Vec::new();
+            let problem = match serialize_cs(&cs, &mut data) {
+                Err(err) => Some(Problem::Corrupt(node.to_string(), err)),
+                Ok(()) => check_hash(&node, Bytes::from(data), cs.parents()),
+            };
+            Ok((problem, refs))
+        })
+        .boxify()
+}
+
+fn check_manifest_or_file(repo: Arc<BlobRepo>, node: Node) -> BoxFuture<Checked, Error> {
+    let hash = node.hash();
+    repo.get_raw_content(&hash)
+        .join(repo.get_parents(&hash))
+        .then(move |res| -> Result<Checked> {
+            let (content, parents) = match res {
+                Ok(found) => found,
+                Err(err) => return Ok((Some(Problem::from_read_error(&node, err)), vec![])),
+            };
+            let referrer = node.to_string();
+            let mut refs: Vec<_> = parents
+                .into_iter()
+                .map(|parent| {
+                    // Parents are the same kind of node at the same path
+                    let parent = match node {
+                        Node::Changeset(_) => Node::Changeset(parent),
+                        Node::Manifest(_, ref path) => Node::Manifest(parent, path.clone()),
+                        Node::File(_, ref path) => Node::File(parent, path.clone()),
+                    };
+                    (parent, referrer.clone())
+                })
+                .collect();
+
+            if let Node::Manifest(_, ref dir) = node {
+                match ManifestContent::parse(content.as_ref()) {
+                    Err(err) => {
+                        let problem = Problem::Corrupt(node.to_string(), err);
+                        return Ok((Some(problem), refs));
+                    }
+                    Ok(manifest) => refs.extend(manifest.files.iter().map(|(path, details)| {
+                        let path = dir.join(path);
+                        let entry = details.entryid().into_nodehash();
+                        let entry = if details.is_tree() {
+                            Node::Manifest(entry, path)
+                        } else {
+                            Node::File(entry, path)
+                        };
+                        (entry, referrer.clone())
+                    })),
+                }
+            }
+
+            let problem = check_hash(&node, content, &parents);
+            Ok((problem, refs))
+        })
+        .boxify()
+}
+
+fn check_hash(node: &Node, text: Bytes, parents: &Parents) -> Option<Problem> {
+    let (p1, p2) = parents.get_nodes();
+    let actual = BlobNode::new(text, p1, p2).nodeid();
+    if actual == Some(node.hash()) {
+        None
+    } else {
+        Some(Problem::HashMismatch(node.to_string(), actual))
+    }
+}
+
+fn open_repo(
+    logger: &Logger,
+    input: &Path,
+    blobtype: &str,
+    repoid: RepositoryId,
+    blob_prefix: Option<String>,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!("repo" => format!("{}", input.display())));
+    let repo = match blobtype {
+        "files" => BlobRepo::new_files(logger, input, repoid, blob_prefix)?,
+        "rocksdb" => BlobRepo::new_rocksdb(logger, input, repoid, blob_prefix)?,
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(repo)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("repo consistency checker")
+        .version("0.0.0")
+        .about("check that every node reachable from the heads is stored and hashes correctly")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input blobstore RepoCtx'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'blobstore type: files or rocksdb'
+            --repoid [ID]            'numerical id of the repo. Default: 0'
+            --blob-prefix [PREFIX]   'prefix of the blobstore keys of the repo'
+            --concurrency [LIMIT]    'max number of nodes checked at once. Default: 100'
+        "#,
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<usize> {
+        let input = Path::new(matches.value_of("INPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let repoid = matches
+            .value_of("repoid")
+            .map(|id| id.parse().expect("repoid must be an integer"))
+            .unwrap_or(0);
+        let blob_prefix = matches.value_of("blob-prefix").map(ToOwned::to_owned);
+        let concurrency: usize = match matches.value_of("concurrency") {
+            Some(limit) => match limit.parse() {
+                Ok(limit) if limit > 0 => limit,
+                _ => bail_msg!("concurrency must be positive integer"),
+            },
+            None => 100,
+        };
+
+        let repo = open_repo(
+            root_log,
+            input,
+            blobtype,
+            RepositoryId::new(repoid),
+            blob_prefix,
+        )?;
+        run_blobcheck(Arc::new(repo), root_log, concurrency)
+    }
+
+    match run(&root_log, matches) {
+        Ok(0) => {}
+        Ok(_) => std::process::exit(EXIT_PROBLEMS),
+        Err(e) => {
+            error!(root_log, "Blobcheck failed"; SlogKVError(e));
+            std::process::exit(EXIT_FAILED);
+        }
+    }
+}
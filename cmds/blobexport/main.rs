@@ -0,0 +1,436 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Export of a blobstore back into a Mercurial revlog repo, the reverse of blobimport.
+//!
+//! Every changeset reachable from the heads store is written to the changelog, parents first,
+//! together with its manifest and the filenodes it introduced. The result can be compared with
+//! the repo that was imported, or used to get data back out of Mononoke.
+
+#![deny(warnings)]
+
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_cpupool;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate bookmarks;
+extern crate fileblob;
+extern crate filebookmarks;
+extern crate fileheads;
+extern crate futures_ext;
+extern crate heads;
+extern crate mercurial;
+extern crate mercurial_types;
+extern crate rocksblob;
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{future, stream, Future, Stream};
+use futures_cpupool::CpuPool;
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::{fetch_content, BlobChangeset, RawNodeBlob};
+use blobstore::Blobstore;
+use bookmarks::Bookmarks;
+use fileblob::Fileblob;
+use filebookmarks::FileBookmarks;
+use fileheads::FileHeads;
+use futures_ext::{BoxFuture, FutureExt};
+use heads::Heads;
+use mercurial::changeset::serialize_cs;
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial::revlog::RevlogWriter;
+use mercurial_types::{fncache_fsencode, Changeset, MPath, MPathElement, NodeHash, Parents};
+use mercurial_types::nodehash::ChangesetId;
+use rocksblob::Rocksblob;
+
+type BBlobstore = Arc<Blobstore>;
+
+const REQUIREMENTS: &[&str] = &["dotencode", "fncache", "revlogv1", "store"];
+
+/// The revlogs of the exported repo, and the list of filelogs for the fncache.
+struct Store {
+    path: PathBuf,
+    changelog: RevlogWriter,
+    manifest: RevlogWriter,
+    filelogs: HashMap<MPath, RevlogWriter>,
+    fncache: Vec<Vec<u8>>,
+}
+
+impl Store {
+    fn create(path: PathBuf) -> Result<Self> {
+        let changelog = RevlogWriter::create(
+            path.join("00changelog.i"),
+            path.join("00changelog.d"),
+            false,
+        )?;
+        let manifest = RevlogWriter::create(
+            path.join("00manifest.i"),
+            path.join("00manifest.d"),
+            false,
+        )?;
+        Ok(Self {
+            path,
+            changelog,
+            manifest,
+            filelogs: HashMap::new(),
+            fncache: Vec::new(),
+        })
+    }
+
+    fn filelog(&mut self, path: &MPath) -> Result<&mut RevlogWriter> {
+        if !self.filelogs.contains_key(path) {
+            let filelog = RevlogWriter::create(
+                self.path.join(filelog_path(path, ".i")),
+                self.path.join(filelog_path(path, ".d")),
+                false,
+            )?;
+            for extension in &[".i", ".d"] {
+                let mut entry = b"data/".to_vec();
+                entry.extend_from_slice(&path.to_vec());
+                entry.extend_from_slice(extension.as_bytes());
+                self.fncache.push(entry);
+            }
+            self.filelogs.insert(path.clone(), filelog);
+        }
+        Ok(self.filelogs.get_mut(path).unwrap())
+    }
+
+    fn save_fncache(&self) -> Result<()> {
+        let mut file = fs::File::create(self.path.join("fncache"))?;
+        for entry in &self.fncache {
+            file.write_all(entry)?;
+            file.write_all(b"\n")?;
+        }
+        Ok(())
+    }
+}
+
+/// Path of a filelog relative to the store, as Mercurial encodes it with fncache and dotencode.
+fn filelog_path(path: &MPath, extension: &str) -> PathBuf {
+    let mut elements = vec![MPathElement::new(b"data".to_vec())];
+    elements.extend(path.into_iter().cloned());
+    if let Some(last) = elements.last_mut() {
+        last.extend(extension.as_bytes());
+    }
+    fncache_fsencode(&elements, true)
+}
+
+fn run_blobexport(
+    input: PathBuf,
+    output: PathBuf,
+    blobtype: &str,
+    logger: &Logger,
+    concurrency: usize,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let cpupool = Arc::new(CpuPool::new_num_cpus());
+
+    info!(logger, "Opening blobstore: {}", input.display());
+    let blobstore = open_blobstore(input.clone(), blobtype)?;
+    let headstore = FileHeads::open_with_pool(input.join("heads"), cpupool.clone())?;
+
+    let mut heads = core.run(headstore.heads().collect())?;
+    heads.sort();
+    info!(logger, "Loading changesets reachable from {} heads", heads.len());
+    let changesets = core.run(load_changesets(blobstore.clone(), heads.clone(), concurrency))?;
+    let order = topological_order(&changesets, &heads);
+
+    let hgdir = output.join(".hg");
+    fs::create_dir_all(hgdir.join("store"))
+        .with_context(|_| format!("cannot create {}", hgdir.display()))?;
+    let mut requires = fs::File::create(hgdir.join("requires"))?;
+    for requirement in REQUIREMENTS {
+        writeln!(requires, "{}", requirement)?;
+    }
+    let mut store = Store::create(hgdir.join("store"))?;
+
+    info!(logger, "Exporting {} changesets", order.len());
+    for (rev, csid) in order.iter().enumerate() {
+        debug!(logger, "{}: changeset {}", rev, csid);
+        let cs = &changesets[csid];
+        export_changeset(&mut core, &blobstore, &mut store, concurrency, rev, cs)
+            .with_context(|_| format!("cannot export changeset {}", csid))?;
+    }
+    store.save_fncache()?;
+
+    let books = input.join("books");
+    if books.exists() {
+        let bookmarks = Arc::new(FileBookmarks::open_with_pool(books, cpupool.clone())?);
+        let exported = core.run(export_bookmarks(bookmarks, &changesets))?;
+        let mut file = fs::File::create(hgdir.join("bookmarks"))?;
+        for (name, csid) in exported {
+            write!(file, "{} ", csid)?;
+            file.write_all(&name)?;
+            file.write_all(b"\n")?;
+        }
+    }
+
+    info!(logger, "Exported to {}", output.display());
+    Ok(())
+}
+
+/// Load every changeset reachable from `heads`.
+fn load_changesets(
+    blobstore: BBlobstore,
+    heads: Vec<NodeHash>,
+    concurrency: usize,
+) -> BoxFuture<HashMap<NodeHash, BlobChangeset>, Error> {
+    // Walk the history one generation at a time
+    future::loop_fn(
+        (heads, HashMap::new()),
+        move |(frontier, mut loaded): (Vec<NodeHash>, HashMap<_, _>)| {
+            stream::iter_ok(frontier)
+                .map({
+                    let blobstore = blobstore.clone();
+                    move |node| {
+                        BlobChangeset::load(&blobstore, &ChangesetId::new(node)).and_then(
+                            move |cs| match cs {
+                                Some(cs) => Ok((node, cs)),
+                                None => Err(format_err!("changeset {} is missing", node)),
+                            },
+                        )
+                    }
+                })
+                .buffer_unordered(concurrency)
+                .collect()
+                .map(move |batch| {
+                    let mut frontier = HashSet::new();
+                    for (node, cs) in batch {
+                        for parent in cs.parents() {
+                            if !loaded.contains_key(&parent) {
+                                frontier.insert(parent);
+                            }
+                        }
+                        loaded.insert(node, cs);
+                    }
+                    let frontier: Vec<_> = frontier
+                        .into_iter()
+                        .filter(|node| !loaded.contains_key(node))
+                        .collect();
+                    if frontier.is_empty() {
+                        future::Loop::Break(loaded)
+                    } else {
+                        future::Loop::Continue((frontier, loaded))
+                    }
+                })
+        },
+    ).boxify()
+}
+
+/// Order changesets so that parents always come before their children.
+fn topological_order(
+    changesets: &HashMap<NodeHash, BlobChangeset>,
+    heads: &[NodeHash],
+) -> Vec<NodeHash> {
+    let mut order = Vec::with_capacity(changesets.len());
+    let mut visited = HashSet::new();
+    for head in heads {
+        // Depth first, emitting a changeset once all its parents have been emitted
+        let mut stack = vec![(*head, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            let parents: Vec<_> = changesets[&node].parents().into_iter().collect();
+            for parent in parents.into_iter().rev() {
+                if !visited.contains(&parent) {
+                    stack.push((parent, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Write a changeset, its manifest and the filenodes the manifest introduced as revision `rev`.
+fn export_changeset(
+    core: &mut Core,
+    blobstore: &BBlobstore,
+    store: &mut Store,
+    concurrency: usize,
+    rev: usize,
+    cs: &BlobChangeset,
+) -> Result<()> {
+    let mfid = cs.manifestid().into_nodehash();
+    if !store.manifest.contains(&mfid) {
+        let (mfparents, manifest) = core.run(load_node(blobstore.clone(), mfid))?;
+        let content = ManifestContent::parse(&manifest)?;
+
+        let missing: Vec<_> = content
+            .files
+            .iter()
+            .map(|(path, details)| (path.clone(), details.entryid().into_nodehash()))
+            .filter(|&(ref path, ref node)| {
+                store
+                    .filelogs
+                    .get(path)
+                    .map_or(true, |filelog| !filelog.contains(node))
+            })
+            .collect();
+        let filenodes = core.run(
+            stream::iter_ok(missing)
+                .map(|(path, node)| {
+                    load_node(blobstore.clone(), node)
+                        .map(move |(parents, content)| (path, node, parents, content))
+                })
+                .buffer_unordered(concurrency)
+                .collect(),
+        )?;
+        for (path, node, parents, content) in filenodes {
+            let (p1, p2) = parents.get_nodes();
+            store
+                .filelog(&path)?
+                .add(node, &content, rev, p1, p2)
+                .with_context(|_| format!("cannot export {}", path))?;
+        }
+
+        let (p1, p2) = mfparents.get_nodes();
+        store.manifest.add(mfid, &manifest, rev, p1, p2)?;
+    }
+
+    let mut data = Vec::new();
+    serialize_cs(cs, &mut data)?;
+    let (p1, p2) = cs.parents().get_nodes();
+    store
+        .changelog
+        .add(cs.get_changeset_id().into_nodehash(), &data, rev, p1, p2)
+}
+
+/// Load the parents and content of a manifest or file node.
+fn load_node(blobstore: BBlobstore, node: NodeHash) -> BoxFuture<(Parents, Bytes), Error> {
+    let nodekey = format!("node-{}.bincode", node);
+    blobstore
+        .get(nodekey.clone())
+        .and_then(move |bytes| {
+            let bytes = bytes.ok_or_else(|| format_err!("{} is missing", nodekey))?;
+            let nodeblob = RawNodeBlob::parse(bytes.as_ref())?;
+            Ok(nodeblob)
+        })
+        .and_then(move |nodeblob| {
+            fetch_content(&blobstore, nodeblob.blob).and_then(move |content| {
+                let content = content
+                    .ok_or_else(|| format_err!("content of {} is missing", node))?;
+                Ok((nodeblob.parents, nodeblob.raw_text(content)))
+            })
+        })
+        .boxify()
+}
+
+/// List the bookmarks pointing to exported changesets.
+fn export_bookmarks(
+    bookmarks: Arc<FileBookmarks>,
+    changesets: &HashMap<NodeHash, BlobChangeset>,
+) -> BoxFuture<Vec<(Vec<u8>, ChangesetId)>, Error> {
+    let exported: HashSet<_> = changesets.keys().cloned().collect();
+    bookmarks
+        .keys()
+        .and_then({
+            let bookmarks = bookmarks.clone();
+            move |name| {
+                bookmarks
+                    .get(&name)
+                    .map(move |value| value.map(|(csid, _)| (name, csid)))
+            }
+        })
+        .filter_map(|bookmark| bookmark)
+        .filter(move |&(_, csid)| exported.contains(&csid.into_nodehash()))
+        .collect()
+        .boxify()
+}
+
+fn open_blobstore(input: PathBuf, blobtype: &str) -> Result<BBlobstore> {
+    let mut input = input;
+    input.push("blobs");
+    let blobstore: BBlobstore = match blobtype {
+        "files" => Arc::new(
+            Fileblob::open(input)
+                .map_err(Error::from)
+                .context("Failed to open file blob store")?,
+        ),
+        "rocksdb" => Arc::new(
+            Rocksblob::open(input)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?,
+        ),
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(blobstore)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob to revlog exporter")
+        .version("0.0.0")
+        .about("make a revlog repo out of blobs")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input blobstore RepoCtx'
+            <OUTPUT>                 'output revlog repo'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'blobstore type: files or rocksdb'
+            --concurrency [LIMIT]    'max number of blobstore reads in flight. Default: 100'
+        "#,
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = PathBuf::from(matches.value_of("INPUT").unwrap());
+        let output = PathBuf::from(matches.value_of("OUTPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let concurrency: usize = matches
+            .value_of("concurrency")
+            .map(|limit| limit.parse().expect("concurrency must be positive integer"))
+            .unwrap_or(100);
+        if concurrency == 0 {
+            bail_msg!("concurrency must be positive integer");
+        }
+
+        run_blobexport(input, output, blobtype, root_log, concurrency)
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Blobexport failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
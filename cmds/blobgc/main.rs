@@ -0,0 +1,348 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Garbage collection of the blobs of a repo which nothing reachable refers to.
+//!
+//! Every blob reachable from the heads and bookmarks of the repo is marked, and the unreachable
+//! blobs are recorded in the state file. A blob is only deleted by a later run, once it has been
+//! unreachable for the whole safety window: a push in flight uploads its blobs before the
+//! changesets referring to them, and the window has to be longer than any push takes.
+//!
+//! Pushes can't start while blobs are deleted, and nothing is deleted while a push recorded less
+//! than a window ago is still in flight: the run is postponed, and only updates the state file.
+//! Pushes which completed since marking can refer to unreachable blobs, which they didn't upload
+//! again, so the heads and bookmarks are read again right before deleting anything, and what's
+//! reachable from them is kept.
+//!
+//! With `--dry-run`, nothing is deleted and the state file isn't updated, only the report of what
+//! a run would do is printed.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate bookmarks;
+extern crate fileblob;
+extern crate filebookmarks;
+extern crate fileheads;
+extern crate futures_ext;
+extern crate heads;
+extern crate mercurial_types;
+extern crate rocksblob;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::{begin_sweep, end_sweep, find_pushes_in_flight, find_unreachable, mark_reachable,
+               plan_sweep, postpone_sweep, recheck_unreachable, GcState};
+use blobstore::{Blobstore, Deletable, Enumerable};
+use bookmarks::Bookmarks;
+use fileblob::Fileblob;
+use filebookmarks::FileBookmarks;
+use fileheads::FileHeads;
+use futures_ext::{BoxFuture, FutureExt};
+use heads::Heads;
+use mercurial_types::NodeHash;
+use rocksblob::Rocksblob;
+
+/// A day, which is much longer than any push should take
+const DEFAULT_WINDOW_SECS: u64 = 24 * 60 * 60;
+
+struct Options<'a> {
+    state: &'a Path,
+    window: u64,
+    dry_run: bool,
+    concurrency: usize,
+}
+
+fn run_blobgc(input: PathBuf, blobtype: &str, logger: &Logger, opts: Options) -> Result<()> {
+    let mut core = Core::new()?;
+    let cpupool = Arc::new(CpuPool::new_num_cpus());
+
+    let blobs = input.join("blobs");
+    info!(logger, "Opening blobstore: {}", blobs.display());
+    match blobtype {
+        "files" => {
+            let blobstore = Fileblob::open(blobs)
+                .map_err(Error::from)
+                .context("Failed to open file blob store")?;
+            collect(&mut core, blobstore, &input, &cpupool, logger, opts)
+        }
+        "rocksdb" => {
+            let blobstore = Rocksblob::open(blobs)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?;
+            collect(&mut core, blobstore, &input, &cpupool, logger, opts)
+        }
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    }
+}
+
+/// The changesets the heads and bookmarks of the repo point to.
+fn load_roots(input: &Path, cpupool: &Arc<CpuPool>) -> Result<BoxFuture<Vec<NodeHash>, Error>> {
+    let headstore = FileHeads::open_with_pool(input.join("heads"), cpupool.clone())?;
+    let heads = headstore.heads().collect();
+
+    let books = input.join("books");
+    let bookmarks = if books.exists() {
+        let bookmarks = Arc::new(FileBookmarks::open_with_pool(books, cpupool.clone())?);
+        bookmarks
+            .keys()
+            .and_then({
+                let bookmarks = bookmarks.clone();
+                move |name| bookmarks.get(&name)
+            })
+            .filter_map(|value| value.map(|(csid, _)| csid.into_nodehash()))
+            .collect()
+            .boxify()
+    } else {
+        futures::future::ok(vec![]).boxify()
+    };
+
+    Ok(heads
+        .join(bookmarks)
+        .map(|(mut roots, bookmarks)| {
+            roots.extend(bookmarks);
+            roots.sort();
+            roots.dedup();
+            roots
+        })
+        .boxify())
+}
+
+fn collect<B>(
+    core: &mut Core,
+    blobstore: B,
+    input: &Path,
+    cpupool: &Arc<CpuPool>,
+    logger: &Logger,
+    opts: Options,
+) -> Result<()>
+where
+    B: Enumerable + Deletable + Clone + Send + Sync + 'static,
+{
+    info!(logger, "Loading heads and bookmarks");
+    let roots = core.run(load_roots(input, cpupool)?)?;
+
+    info!(logger, "Marking blobs reachable from {} changesets", roots.len());
+    let reachable: Arc<Blobstore> = Arc::new(blobstore.clone());
+    let marked = Arc::new(core.run(mark_reachable(reachable, roots, opts.concurrency))?);
+    let reachable = marked.len();
+
+    info!(logger, "Enumerating blobs");
+    let unreachable = core.run(find_unreachable(&blobstore, marked.clone()))?;
+
+    let unreachable_count = unreachable.keys.len();
+    let previous = load_state(opts.state)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let (deleted, state) = plan_sweep(
+        previous.clone(),
+        unreachable.keys.clone(),
+        now,
+        opts.window,
+    );
+
+    println!("Enumerated blobs: {}", unreachable.enumerated);
+    println!("Reachable blobs: {}", reachable);
+    println!("Unreachable blobs: {}", unreachable_count);
+    println!("Unmanaged blobs kept: {}", unreachable.unmanaged);
+    println!("Generation: {}", state.generation);
+
+    if opts.dry_run {
+        println!("Blobs which would be deleted: {}", deleted.len());
+        for key in &deleted {
+            debug!(logger, "would delete {}", key);
+        }
+        return Ok(());
+    }
+
+    if deleted.is_empty() {
+        println!("Deleted blobs: 0");
+        return save_state(opts.state, &state);
+    }
+
+    let blobstore = Arc::new(blobstore);
+    core.run(begin_sweep(&*blobstore))?;
+    let sweep = Sweep {
+        blobstore: blobstore.clone(),
+        input,
+        cpupool,
+        marked,
+    };
+    let swept = sweep_unless_pushing(core, sweep, deleted, logger, &opts);
+    core.run(end_sweep(&*blobstore))?;
+
+    match swept? {
+        Some(deleted) => {
+            println!("Deleted blobs: {}", deleted);
+            save_state(opts.state, &state)
+        }
+        None => match previous {
+            Some(previous) => save_state(opts.state, &postpone_sweep(previous, &unreachable.keys)),
+            None => Ok(()),
+        },
+    }
+}
+
+struct Sweep<'a, B> {
+    blobstore: Arc<B>,
+    input: &'a Path,
+    cpupool: &'a Arc<CpuPool>,
+    /// The blobs marked reachable
+    marked: Arc<HashSet<String>>,
+}
+
+// Delete `keys`, unless a push is in flight, as it could rely on some of them, or they became
+// reachable since marking. New pushes can't start while this runs. Returns how many keys were
+// deleted, if any were.
+fn sweep_unless_pushing<B>(
+    core: &mut Core,
+    sweep: Sweep<B>,
+    keys: Vec<String>,
+    logger: &Logger,
+    opts: &Options,
+) -> Result<Option<usize>>
+where
+    B: Enumerable + Deletable,
+{
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+    let in_flight = core.run(find_pushes_in_flight(
+        sweep.blobstore.clone(),
+        now,
+        opts.window,
+        opts.concurrency,
+    ))?;
+    if !in_flight.is_empty() {
+        println!("Pushes in flight: {}, sweep postponed", in_flight.len());
+        for id in &in_flight {
+            debug!(logger, "push in flight {}", id);
+        }
+        return Ok(None);
+    }
+
+    // Pushes complete before their records are marked complete, so whatever pushes completed
+    // since marking are reachable from the heads and bookmarks read now
+    info!(logger, "Checking the heads and bookmarks again");
+    let roots = core.run(load_roots(sweep.input, sweep.cpupool)?)?;
+    let reachable: Arc<Blobstore> = sweep.blobstore.clone();
+    let keys = core.run(recheck_unreachable(
+        reachable,
+        roots,
+        sweep.marked,
+        keys,
+        opts.concurrency,
+    ))?;
+
+    let deleted = core.run(blobrepo::sweep(sweep.blobstore, keys, opts.concurrency))?;
+    Ok(Some(deleted))
+}
+
+fn load_state(path: &Path) -> Result<Option<GcState>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let file = fs::File::open(path).with_context(|_| format!("cannot open {}", path.display()))?;
+    let state = serde_json::from_reader(file)
+        .with_context(|_| format!("cannot parse {}", path.display()))?;
+    Ok(Some(state))
+}
+
+// The state is written to a temporary file first, so that it's never left half written
+fn save_state(path: &Path, state: &GcState) -> Result<()> {
+    let tmp = path.with_extension("tmp");
+    let file = fs::File::create(&tmp).with_context(|_| format!("cannot create {}", tmp.display()))?;
+    serde_json::to_writer_pretty(file, state)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blob garbage collector")
+        .version("0.0.0")
+        .about("delete the blobs which nothing reachable refers to")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input blobstore RepoCtx'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'blobstore type: files or rocksdb'
+            --state <FILE>           'state of the previous collection, updated by this one'
+            --window [SECS]          'safety window, in seconds. Default: 86400'
+            --dry-run                'only report what would be deleted'
+            --concurrency [LIMIT]    'max number of blobstore requests in flight. Default: 100'
+        "#,
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = PathBuf::from(matches.value_of("INPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let state = PathBuf::from(matches.value_of("state").unwrap());
+        let window = match matches.value_of("window") {
+            Some(secs) => match secs.parse() {
+                Ok(secs) => secs,
+                Err(_) => bail_msg!("window must be a number of seconds"),
+            },
+            None => DEFAULT_WINDOW_SECS,
+        };
+        let concurrency: usize = match matches.value_of("concurrency") {
+            Some(limit) => match limit.parse() {
+                Ok(limit) if limit > 0 => limit,
+                _ => bail_msg!("concurrency must be positive integer"),
+            },
+            None => 100,
+        };
+        let opts = Options {
+            state: &state,
+            window,
+            dry_run: matches.is_present("dry-run"),
+            concurrency,
+        };
+
+        run_blobgc(input, blobtype, root_log, opts)
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Blobgc failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,218 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checkpointing of blobimport progress, so that an interrupted import can be resumed.
+//!
+//! Changesets are converted concurrently and their blobs written asynchronously by the io
+//! thread, so "imported up to revision N" needs some care. The checkpoint records the revision
+//! below which every changeset has been converted (the watermark), together with the keys of all
+//! blobs which have been handed to the io thread but not yet written, and the revision each of
+//! them came from. When resuming, any of those blobs that didn't make it to the blobstore causes
+//! the import to restart from the earliest revision that produced one.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use failure::{Error, Result, ResultExt};
+use futures::{stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+
+use blobstore::Blobstore;
+
+use BlobstoreEntry;
+
+const MAGIC: &str = "mononoke-blobimport-checkpoint-v1";
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+// How many pending blobs to check at once when resuming
+const VERIFY_CONCURRENCY: usize = 100;
+
+/// The contents of a checkpoint file.
+#[derive(Debug, Default)]
+pub(crate) struct SavedCheckpoint {
+    rev: u64,
+    pending: HashMap<String, u64>,
+}
+
+impl SavedCheckpoint {
+    pub fn load(path: &PathBuf) -> Result<Self> {
+        let bad = || format_err!("malformed checkpoint file {}", path.display());
+        let file = File::open(path)
+            .with_context(|_| format!("Failed to open checkpoint file {}", path.display()))?;
+        let mut lines = BufReader::new(file).lines();
+
+        match lines.next() {
+            Some(Ok(ref magic)) if magic == MAGIC => {}
+            Some(Err(err)) => return Err(err.into()),
+            _ => return Err(bad()),
+        }
+
+        let mut saved = SavedCheckpoint::default();
+        for line in lines {
+            let line = line?;
+            let mut fields = line.splitn(3, ' ');
+            match (fields.next(), fields.next(), fields.next()) {
+                (Some("rev"), Some(rev), None) => saved.rev = rev.parse().map_err(|_| bad())?,
+                (Some("pending"), Some(rev), Some(key)) => {
+                    saved
+                        .pending
+                        .insert(key.to_string(), rev.parse().map_err(|_| bad())?);
+                }
+                _ => return Err(bad()),
+            }
+        }
+        Ok(saved)
+    }
+
+    /// Work out which revision to resume from, checking which of the pending blobs were
+    /// actually written.
+    pub fn resume_rev<B: Blobstore>(self, blobstore: &B) -> BoxFuture<u64, Error> {
+        let rev = self.rev;
+        let checks: Vec<_> = self.pending
+            .into_iter()
+            .map(|(key, rev)| blobstore.is_present(key).map(move |present| (present, rev)))
+            .collect();
+
+        stream::iter_ok(checks)
+            .buffer_unordered(VERIFY_CONCURRENCY)
+            .fold(rev, |resume, (present, rev)| -> Result<u64> {
+                if present {
+                    Ok(resume)
+                } else {
+                    Ok(resume.min(rev))
+                }
+            })
+            .boxify()
+    }
+}
+
+struct CheckpointState {
+    watermark: u64,
+    // Converted revisions at or above the watermark
+    converted: BTreeSet<u64>,
+    // Blobs handed to the io thread but not yet written: revision and number of copies in flight
+    pending: HashMap<String, (u64, usize)>,
+    last_save: Instant,
+}
+
+/// Tracks import progress and periodically saves it to a checkpoint file.
+pub(crate) struct Checkpoint {
+    // None if checkpointing is disabled, in which case nothing is tracked
+    path: Option<PathBuf>,
+    state: Mutex<CheckpointState>,
+}
+
+impl Checkpoint {
+    pub fn new(path: PathBuf) -> Self {
+        Self::with_path(Some(path))
+    }
+
+    pub fn disabled() -> Self {
+        Self::with_path(None)
+    }
+
+    fn with_path(path: Option<PathBuf>) -> Self {
+        Self {
+            path,
+            state: Mutex::new(CheckpointState {
+                watermark: 0,
+                converted: BTreeSet::new(),
+                pending: HashMap::new(),
+                last_save: Instant::now(),
+            }),
+        }
+    }
+
+    /// Set the first revision to be converted. Must be called before any progress is recorded.
+    pub fn start_from(&self, rev: u64) {
+        self.state.lock().expect("lock poison").watermark = rev;
+    }
+
+    /// Record that `entry`, produced by revision `rev`, is being sent to the io thread.
+    pub fn entry_sent(&self, entry: &BlobstoreEntry, rev: u64) {
+        if self.path.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().expect("lock poison");
+        let pending = state.pending.entry(entry.key()).or_insert((rev, 0));
+        pending.0 = pending.0.min(rev);
+        pending.1 += 1;
+    }
+
+    /// Record that the io thread is done with one copy of the blob `key`, either because it was
+    /// written or because it was a duplicate.
+    pub fn entry_written(&self, key: &str) {
+        if self.path.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().expect("lock poison");
+        let done = match state.pending.get_mut(key) {
+            Some(entry) => {
+                entry.1 -= 1;
+                entry.1 == 0
+            }
+            None => false,
+        };
+        if done {
+            state.pending.remove(key);
+        }
+    }
+
+    /// Record that all the blobs of revision `rev` have been sent to the io thread, saving the
+    /// checkpoint if it's been a while.
+    pub fn changeset_converted(&self, rev: u64) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().expect("lock poison");
+        state.converted.insert(rev);
+        loop {
+            let watermark = state.watermark;
+            if !state.converted.remove(&watermark) {
+                break;
+            }
+            state.watermark += 1;
+        }
+
+        if state.last_save.elapsed() >= SAVE_INTERVAL {
+            save_locked(path, &mut state)?;
+        }
+        Ok(())
+    }
+
+    pub fn save(&self) -> Result<()> {
+        match self.path {
+            Some(ref path) => save_locked(path, &mut self.state.lock().expect("lock poison")),
+            None => Ok(()),
+        }
+    }
+}
+
+fn save_locked(path: &PathBuf, state: &mut CheckpointState) -> Result<()> {
+    // Write to a temporary file first, so that a crash never leaves a truncated checkpoint
+    let mut tmp_path = path.clone().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+
+    {
+        let mut file = File::create(&tmp_path)?;
+        writeln!(file, "{}", MAGIC)?;
+        writeln!(file, "rev {}", state.watermark)?;
+        for (key, &(rev, _)) in &state.pending {
+            writeln!(file, "pending {} {}", rev, key)?;
+        }
+        file.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)
+        .with_context(|_| format!("Failed to save checkpoint {}", path.display()))?;
+
+    state.last_save = Instant::now();
+    Ok(())
+}
@@ -5,7 +5,6 @@
 // GNU General Public License version 2 or any later version.
 
 use std::sync::Arc;
-use std::sync::mpsc::SyncSender;
 
 use futures::{Future, IntoFuture, Stream};
 use futures_cpupool::CpuPool;
@@ -19,23 +18,35 @@ use heads::Heads;
 use linknodes::Linknodes;
 use mercurial::{self, RevlogManifest, RevlogRepo};
 use mercurial::revlog::RevIdx;
+use mercurial::revlogrepo::Required;
 use mercurial_types::{Changeset, MPath, Manifest, NodeHash, RepoPath};
 use mercurial_types::nodehash::{ChangesetId, EntryId};
 use stats::Timeseries;
 
-use BlobstoreEntry;
+use {BlobstoreEntry, EntrySender};
 use STATS;
+use error_manifest::ErrorManifest;
+use largefiles::LargefileStores;
 use manifest;
 
 pub(crate) struct ConvertContext<H> {
     pub repo: RevlogRepo,
-    pub sender: SyncSender<BlobstoreEntry>,
+    pub sender: EntrySender,
     pub headstore: H,
     pub core: Core,
     pub cpupool: Arc<CpuPool>,
     pub logger: Logger,
     pub skip: Option<u64>,
     pub commits_limit: Option<u64>,
+    /// Max number of changesets converted at once, and of entries copied at once within each
+    /// changeset.
+    pub concurrency: usize,
+    /// If set, entries which can't be read are recorded here instead of failing the import.
+    pub error_manifest: Option<Arc<ErrorManifest>>,
+    /// If set, largefiles and LFS pointers are resolved from these stores.
+    pub largefiles: Option<Arc<LargefileStores>>,
+    /// If set and the repo only has flat manifests, they're converted to tree manifests.
+    pub flat_to_tree: bool,
 }
 
 impl<H> ConvertContext<H>
@@ -50,6 +61,15 @@ where
         let headstore = self.headstore;
         let skip = self.skip;
         let commits_limit = self.commits_limit;
+        let concurrency = self.concurrency;
+        let error_manifest = self.error_manifest;
+        let largefiles = self.largefiles;
+        let flat_to_tree =
+            self.flat_to_tree && !self.repo.get_requirements().contains(&Required::Treemanifest);
+        if flat_to_tree {
+            info!(logger, "converting flat manifests to tree manifests");
+        }
+        let first_rev = skip.unwrap_or(0);
 
         let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(skip) = skip {
             self.repo.changesets().skip(skip).boxify()
@@ -72,14 +92,26 @@ where
             .map({
                 let repo = self.repo.clone();
                 let sender = self.sender.clone();
+                let cpupool = cpupool.clone();
                 move |(seq, csid)| {
                     debug!(logger, "{}: changeset {}", seq, csid);
                     STATS::changesets.add_value(1);
-                    copy_changeset(repo.clone(), sender.clone(), linknodes_store.clone(), ChangesetId::new(csid))
+                    copy_changeset(
+                        repo.clone(),
+                        sender.clone(),
+                        linknodes_store.clone(),
+                        cpupool.clone(),
+                        concurrency,
+                        error_manifest.clone(),
+                        largefiles.clone(),
+                        flat_to_tree,
+                        first_rev + seq as u64,
+                        ChangesetId::new(csid),
+                    )
                 }
             }) // Stream<Future<()>>
             .map(|copy| cpupool.spawn(copy))
-            .buffer_unordered(100);
+            .buffer_unordered(concurrency);
 
         let heads = self.repo
             .get_heads()
@@ -111,13 +143,23 @@ where
 /// The changeset and the manifest are straightforward - we just make literal copies of the
 /// blobs into the blobstore.
 ///
-/// The files are more complex. For each manifest, we generate a stream of entries, then flatten
-/// the entry streams from all changesets into a single stream. Then each entry is filtered
-/// against a set of entries that have already been copied, and any remaining are actually copied.
+/// The files are more complex. For each manifest, we generate a stream of entries introduced by
+/// this changeset. Each entry is then read from its revlog and copied as a separate task on the
+/// CpuPool, with up to `concurrency` in flight, so that parsing the revlogs of a changeset which
+/// touches many files is spread over all the workers. Entries are content-addressed, so they can
+/// be copied in any order.
+///
+/// Once everything has been handed to the io thread, `rev` is marked as converted.
 fn copy_changeset<L>(
     revlog_repo: RevlogRepo,
-    sender: SyncSender<BlobstoreEntry>,
+    sender: EntrySender,
     linknodes_store: L,
+    cpupool: Arc<CpuPool>,
+    concurrency: usize,
+    error_manifest: Option<Arc<ErrorManifest>>,
+    largefiles: Option<Arc<LargefileStores>>,
+    flat_to_tree: bool,
+    rev: u64,
     csid: ChangesetId,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
 where
@@ -133,9 +175,7 @@ where
             .from_err()
             .and_then(move |cs| {
                 let bcs = BlobChangeset::new_with_id(&csid, cs);
-                sender
-                    .send(BlobstoreEntry::Changeset(bcs))
-                    .map_err(Error::from)
+                sender.send(BlobstoreEntry::Changeset(bcs), rev)
             })
     };
 
@@ -145,16 +185,25 @@ where
         .get_changeset_by_changesetid(&csid)
         .join(revlog_repo.get_changelog_revlog_entry_by_id(&entryid))
         .from_err()
-        .and_then(move |(cs, entry)| {
-            let mfid = *cs.manifestid();
-            let linkrev = entry.linkrev;
-            put_blobs(
-                revlog_repo,
-                sender,
-                linknodes_store,
-                mfid.clone().into_nodehash(),
-                linkrev,
-            )
+        .and_then({
+            let sender = sender.clone();
+            move |(cs, entry)| {
+                let mfid = *cs.manifestid();
+                let linkrev = entry.linkrev;
+                put_blobs(
+                    revlog_repo,
+                    sender,
+                    linknodes_store,
+                    cpupool,
+                    concurrency,
+                    error_manifest,
+                    largefiles,
+                    flat_to_tree,
+                    rev,
+                    mfid.clone().into_nodehash(),
+                    linkrev,
+                )
+            }
         })
         .map_err(move |err| {
             err.context(format_err!("Can't copy manifest for cs {}", csid))
@@ -163,7 +212,8 @@ where
     _assert_sized(&put);
     _assert_sized(&manifest);
 
-    put.join(manifest).map(|_| ())
+    put.join(manifest)
+        .and_then(move |_| sender.changeset_converted(rev))
 }
 
 /// Copy manifest and filelog entries into the blob store.
@@ -171,8 +221,14 @@ where
 /// See the help for copy_changeset for a full description.
 fn put_blobs<L>(
     revlog_repo: RevlogRepo,
-    sender: SyncSender<BlobstoreEntry>,
+    sender: EntrySender,
     linknodes_store: L,
+    cpupool: Arc<CpuPool>,
+    concurrency: usize,
+    error_manifest: Option<Arc<ErrorManifest>>,
+    largefiles: Option<Arc<LargefileStores>>,
+    flat_to_tree: bool,
+    rev: u64,
     mfid: NodeHash,
     linkrev: RevIdx,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static
@@ -186,14 +242,26 @@ where
         .join(cs_entry_fut)
         .from_err()
         .and_then(move |(blob, cs_entry)| {
-            let putmf = manifest::put_entry(
-                sender.clone(),
-                mfid,
-                blob.as_blob().clone(),
-                blob.parents().clone(),
-            );
-
             let linknode = cs_entry.nodeid;
+            let putmf = if flat_to_tree {
+                manifest::put_flat_as_trees(
+                    sender.clone(),
+                    &linknodes_store,
+                    rev,
+                    mfid,
+                    &blob,
+                    linknode,
+                )
+            } else {
+                manifest::put_entry(
+                    sender.clone(),
+                    rev,
+                    mfid,
+                    blob.as_blob().clone(),
+                    blob.parents().clone(),
+                ).boxify()
+            };
+
             let put_root_linknode = linknodes_store.add(RepoPath::root(), &mfid, &linknode);
 
             // Get the listing of entries and fetch each of those
@@ -214,16 +282,50 @@ where
                             }
                         })
                         .flatten()
-                        .for_each(move |(entry, repopath)| {
-                            // All entries share the same linknode to the changelog.
-                            let linknode_future = linknodes_store.add(
-                                repopath,
-                                &entry.get_hash().into_nodehash(),
-                                &linknode,
+                        .then({
+                            let error_manifest = error_manifest.clone();
+                            move |res| match (res, error_manifest.as_ref()) {
+                                (Ok(entry), _) => Ok(Some(entry)),
+                                (Err(err), Some(error_manifest)) => {
+                                    error_manifest.skip(linknode, None, None, err);
+                                    Ok(None)
+                                }
+                                (Err(err), None) => Err(err),
+                            }
+                        })
+                        .filter_map(|entry| entry)
+                        .map(move |(entry, repopath)| {
+                            let hash = entry.get_hash().into_nodehash();
+                            let copy_future = manifest::copy_entry(
+                                entry,
+                                repopath.clone(),
+                                sender.clone(),
+                                rev,
+                                largefiles.clone(),
                             );
-                            let copy_future = manifest::copy_entry(entry, sender.clone());
-                            copy_future.join(linknode_future).map(|_| ())
+                            let copy_future = match error_manifest.clone() {
+                                Some(error_manifest) => {
+                                    let repopath = repopath.clone();
+                                    copy_future
+                                        .or_else(move |err| {
+                                            error_manifest.skip(
+                                                linknode,
+                                                Some(&repopath),
+                                                Some(hash),
+                                                err,
+                                            );
+                                            Ok::<_, Error>(())
+                                        })
+                                        .boxify()
+                                }
+                                None => copy_future.boxify(),
+                            };
+                            // All entries share the same linknode to the changelog.
+                            let linknode_future = linknodes_store.add(repopath, &hash, &linknode);
+                            cpupool.spawn(copy_future.join(linknode_future).map(|_| ()))
                         })
+                        .buffer_unordered(concurrency)
+                        .for_each(|()| Ok(()))
                 })
                 .into_future()
                 .flatten();
@@ -0,0 +1,92 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Record of the entries skipped by `--skip-bad-filelogs`, saved as JSON so that the broken
+//! paths can be triaged after the import.
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use failure::{Error, Result, ResultExt};
+use serde_json;
+use slog::Logger;
+
+use mercurial_types::{NodeHash, RepoPath};
+use stats::Timeseries;
+
+use STATS;
+
+#[derive(Debug, Serialize)]
+struct SkippedEntry {
+    changeset: String,
+    /// Not known if the entry couldn't even be listed
+    path: Option<String>,
+    node: Option<String>,
+    error: String,
+}
+
+pub(crate) struct ErrorManifest {
+    path: PathBuf,
+    logger: Logger,
+    skipped: Mutex<Vec<SkippedEntry>>,
+}
+
+impl ErrorManifest {
+    pub fn new(path: PathBuf, logger: Logger) -> Self {
+        Self {
+            path,
+            logger,
+            skipped: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Record that an entry of `changeset` couldn't be imported because of `err`.
+    pub fn skip(
+        &self,
+        changeset: NodeHash,
+        path: Option<&RepoPath>,
+        node: Option<NodeHash>,
+        err: Error,
+    ) {
+        let error = err.causes()
+            .map(|cause| cause.to_string())
+            .collect::<Vec<_>>()
+            .join(": ");
+        warn!(
+            self.logger,
+            "skipping bad entry {:?} in changeset {}: {}", path, changeset, error
+        );
+        STATS::skipped.add_value(1);
+
+        self.skipped
+            .lock()
+            .expect("lock poison")
+            .push(SkippedEntry {
+                changeset: changeset.to_string(),
+                path: path.map(|path| path.to_string()),
+                node: node.map(|node| node.to_string()),
+                error,
+            });
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let skipped = self.skipped.lock().expect("lock poison");
+        if !skipped.is_empty() {
+            warn!(
+                self.logger,
+                "skipped {} bad entries, see {}",
+                skipped.len(),
+                self.path.display()
+            );
+        }
+
+        let file = File::create(&self.path)
+            .with_context(|_| format!("Failed to create error manifest {}", self.path.display()))?;
+        serde_json::to_writer_pretty(file, &*skipped)?;
+        Ok(())
+    }
+}
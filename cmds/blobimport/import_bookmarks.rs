@@ -0,0 +1,101 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Copying of the source repo's `.hg/bookmarks` into the bookmarks store of the output repo.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use futures::{Future, Stream};
+use futures_cpupool::CpuPool;
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use bookmarks::{Bookmarks, BookmarksMut};
+use failure::{Error, Result};
+use filebookmarks::FileBookmarks;
+use mercurial::RevlogRepo;
+use storage_types::Version;
+
+/// Copy every bookmark pointing to a revision in `[first_rev, first_rev + limit)` into the
+/// bookmarks store in `output`, overwriting the bookmarks which are already there.
+pub(crate) fn import_bookmarks(
+    repo: &RevlogRepo,
+    output: PathBuf,
+    pool: &Arc<CpuPool>,
+    first_rev: u64,
+    limit: Option<u64>,
+    logger: &Logger,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let source = Arc::new(repo.bookmarks()?);
+    let dest = Arc::new(FileBookmarks::create_with_pool(
+        output.join("books"),
+        pool.clone(),
+    )?);
+    let changelog = repo.get_changelog();
+
+    let import = source
+        .keys()
+        .and_then({
+            let source = source.clone();
+            move |key| source.get(&key).map(move |value| (key, value))
+        })
+        .filter_map(|(key, value)| value.map(|(csid, _)| (key, csid)))
+        .filter(|&(ref key, ref csid)| {
+            let rev: u64 = match changelog.get_idx_by_nodeid(&csid.into_nodehash()) {
+                Ok(idx) => idx.into(),
+                Err(_) => {
+                    warn!(
+                        logger,
+                        "bookmark {} points to unknown changeset {}, skipping",
+                        String::from_utf8_lossy(key),
+                        csid
+                    );
+                    return false;
+                }
+            };
+            let selected = rev >= first_rev && limit.map_or(true, |limit| rev - first_rev < limit);
+            if !selected {
+                info!(
+                    logger,
+                    "bookmark {} points to revision {} which wasn't imported, skipping",
+                    String::from_utf8_lossy(key),
+                    rev
+                );
+            }
+            selected
+        })
+        .and_then(move |(key, csid)| {
+            let dest = dest.clone();
+            dest.get(&key)
+                .and_then(move |current| {
+                    let version = current
+                        .map(|(_, version)| version)
+                        .unwrap_or_else(Version::absent);
+                    dest.set(&key, &csid, &version).and_then(move |new_version| {
+                        match new_version {
+                            Some(_) => Ok((key, csid)),
+                            None => Err(format_err!(
+                                "bookmark {} was changed while being imported",
+                                String::from_utf8_lossy(&key)
+                            )),
+                        }
+                    })
+                })
+        })
+        .for_each(|(key, csid)| {
+            debug!(
+                logger,
+                "imported bookmark {} -> {}",
+                String::from_utf8_lossy(&key),
+                csid
+            );
+            Ok::<_, Error>(())
+        });
+
+    core.run(import)
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Resolution of the pointer files stored by the largefiles and LFS extensions.
+//!
+//! The revlogs of such repos only contain pointers, so the pointers are imported as they are to
+//! keep the filenode hashes valid, and the real content is imported next to them as a typed
+//! blob, `largefile-sha1-<hash>` or `lfs-sha256-<oid>`. The content is checked against the hash
+//! the pointer names it by before being imported.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::str;
+
+use bytes::Bytes;
+use failure::{Result, ResultExt};
+
+use blobrepo::get_sha256;
+use mercurial::file;
+use mercurial_types::{BlobHash, RepoPath};
+
+/// Largefiles standins are stored under this directory.
+const STANDIN_DIR: &[u8] = b".hglf/";
+const LFS_VERSION: &[u8] = b"version https://git-lfs.github.com/spec/v1\n";
+
+#[derive(Debug, Eq, PartialEq)]
+enum Pointer {
+    Largefile { sha1: String },
+    Lfs { oid: String, size: u64 },
+}
+
+impl Pointer {
+    /// Parse `content` as a pointer, if it is one.
+    fn parse(path: &RepoPath, content: &[u8]) -> Option<Pointer> {
+        let (_, metasz) = file::File::extract_meta(content);
+        let content = &content[metasz..];
+
+        if content.starts_with(LFS_VERSION) {
+            let mut oid = None;
+            let mut size = None;
+            for line in str::from_utf8(content).ok()?.lines() {
+                if line.starts_with("oid sha256:") {
+                    oid = Some(line["oid sha256:".len()..].to_string());
+                } else if line.starts_with("size ") {
+                    size = line["size ".len()..].parse().ok();
+                }
+            }
+            let oid = oid?;
+            if !is_hex(&oid, 64) {
+                return None;
+            }
+            return Some(Pointer::Lfs { oid, size: size? });
+        }
+
+        let is_standin = match *path {
+            RepoPath::FilePath(ref path) => path.to_vec().starts_with(STANDIN_DIR),
+            _ => false,
+        };
+        if is_standin {
+            let sha1 = str::from_utf8(content).ok()?.trim_right();
+            if is_hex(sha1, 40) {
+                return Some(Pointer::Largefile {
+                    sha1: sha1.to_string(),
+                });
+            }
+        }
+        None
+    }
+}
+
+fn is_hex(s: &str, len: usize) -> bool {
+    s.len() == len && s.bytes().all(|b| (b as char).is_digit(16))
+}
+
+/// Local stores the content of pointer files is read from.
+pub(crate) struct LargefileStores {
+    /// Largefiles store, with a file per largefile named after its sha1, like `.hg/largefiles`
+    pub largefiles: Option<PathBuf>,
+    /// LFS store, laid out like Mercurial's `lfs.usercache`
+    pub lfs: Option<PathBuf>,
+}
+
+impl LargefileStores {
+    /// If `content` is a pointer file, read the content it points to, returning the key it
+    /// should be imported under.
+    pub fn resolve(&self, path: &RepoPath, content: &[u8]) -> Result<Option<(String, Bytes)>> {
+        match Pointer::parse(path, content) {
+            Some(Pointer::Largefile { sha1 }) => {
+                let store = match self.largefiles {
+                    Some(ref store) => store,
+                    None => return Ok(None),
+                };
+                let content = read(store.join(&sha1))?;
+                let actual = BlobHash::from(content.as_ref()).sha1().to_string();
+                if actual != sha1 {
+                    bail_msg!("largefile {} of {} has the wrong hash {}", sha1, path, actual);
+                }
+                Ok(Some((format!("largefile-sha1-{}", sha1), content)))
+            }
+            Some(Pointer::Lfs { oid, size }) => {
+                let store = match self.lfs {
+                    Some(ref store) => store,
+                    None => return Ok(None),
+                };
+                let content = read(store.join(&oid[..2]).join(&oid[2..]))?;
+                if content.len() as u64 != size {
+                    bail_msg!(
+                        "LFS object {} of {} has size {}, expected {}",
+                        oid,
+                        path,
+                        content.len(),
+                        size
+                    );
+                }
+                let actual = get_sha256(&content);
+                if !actual.eq_ignore_ascii_case(&oid) {
+                    bail_msg!("LFS object {} of {} has the wrong hash {}", oid, path, actual);
+                }
+                Ok(Some((format!("lfs-sha256-{}", oid), content)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+fn read(path: PathBuf) -> Result<Bytes> {
+    let mut content = Vec::new();
+    File::open(&path)
+        .and_then(|mut file| file.read_to_end(&mut content))
+        .with_context(|_| format!("cannot read {}", path.display()))?;
+    Ok(Bytes::from(content))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::fs;
+    use std::io::Write;
+
+    use mercurial_types::MPath;
+    use tempdir::TempDir;
+
+    fn lfs_pointer(oid: &str, size: usize) -> Vec<u8> {
+        let mut pointer = LFS_VERSION.to_vec();
+        pointer.extend_from_slice(format!("oid sha256:{}\nsize {}\n", oid, size).as_bytes());
+        pointer
+    }
+
+    fn store_lfs_object(store: &PathBuf, oid: &str, content: &[u8]) {
+        let dir = store.join(&oid[..2]);
+        fs::create_dir_all(&dir).unwrap();
+        let mut file = File::create(dir.join(&oid[2..])).unwrap();
+        file.write_all(content).unwrap();
+    }
+
+    #[test]
+    fn lfs_wrong_content() {
+        let tmp = TempDir::new("largefiles_lfs_wrong_content").unwrap();
+        let store = tmp.path().to_path_buf();
+        let stores = LargefileStores {
+            largefiles: None,
+            lfs: Some(store.clone()),
+        };
+        let path = RepoPath::FilePath(MPath::new("big.bin").unwrap());
+        let content = b"the real content";
+        let oid = get_sha256(content);
+        let pointer = lfs_pointer(&oid, content.len());
+
+        // As long as the content the pointer names, but not it
+        store_lfs_object(&store, &oid, b"the fake content");
+        let err = stores.resolve(&path, &pointer).unwrap_err();
+        assert!(format!("{}", err).contains("big.bin"));
+
+        store_lfs_object(&store, &oid, content);
+        let (key, resolved) = stores.resolve(&path, &pointer).unwrap().unwrap();
+        assert_eq!(key, format!("lfs-sha256-{}", oid));
+        assert_eq!(resolved, Bytes::from(&content[..]));
+    }
+}
@@ -0,0 +1,108 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Backpressure on the converter based on blob sizes.
+//!
+//! The channels to the io threads only bound the number of entries in flight, which says little
+//! about memory use when blob sizes vary from bytes to hundreds of megabytes. These limits block
+//! the converting threads when sending an entry instead.
+
+use std::sync::{Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Result;
+
+struct BudgetState {
+    in_flight: usize,
+    // Set when the io threads stop, so that nobody waits for memory which will never be released
+    closed: bool,
+}
+
+/// Bound on the bytes of blobs sent to the io threads but not yet written.
+pub(crate) struct MemoryBudget {
+    limit: Option<usize>,
+    state: Mutex<BudgetState>,
+    released: Condvar,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: Option<usize>) -> Self {
+        Self {
+            limit,
+            state: Mutex::new(BudgetState {
+                in_flight: 0,
+                closed: false,
+            }),
+            released: Condvar::new(),
+        }
+    }
+
+    /// Wait until `bytes` more bytes fit in the budget. A blob bigger than the whole budget is let
+    /// through once nothing else is in flight.
+    pub fn acquire(&self, bytes: usize) -> Result<()> {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(()),
+        };
+        let mut state = self.state.lock().expect("lock poison");
+        while !state.closed && state.in_flight > 0 && state.in_flight + bytes > limit {
+            state = self.released.wait(state).expect("lock poison");
+        }
+        if state.closed {
+            bail_msg!("io threads stopped");
+        }
+        state.in_flight += bytes;
+        Ok(())
+    }
+
+    pub fn release(&self, bytes: usize) {
+        if self.limit.is_none() {
+            return;
+        }
+        let mut state = self.state.lock().expect("lock poison");
+        state.in_flight = state.in_flight.saturating_sub(bytes);
+        self.released.notify_all();
+    }
+
+    /// Fail all current and future waits.
+    pub fn close(&self) {
+        let mut state = self.state.lock().expect("lock poison");
+        state.closed = true;
+        self.released.notify_all();
+    }
+}
+
+/// Cap on the rate at which blob bytes are sent to the io threads, and so uploaded.
+pub(crate) struct RateLimiter {
+    bytes_per_sec: f64,
+    // When the bytes sent so far will have been uploaded at the capped rate
+    next_free: Mutex<Instant>,
+}
+
+impl RateLimiter {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Self {
+            bytes_per_sec: bytes_per_sec as f64,
+            next_free: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Wait until `bytes` more bytes can be sent without going over the cap.
+    pub fn throttle(&self, bytes: usize) {
+        let now = Instant::now();
+        let start = {
+            let mut next_free = self.next_free.lock().expect("lock poison");
+            let start = if *next_free > now { *next_free } else { now };
+            let secs = bytes as f64 / self.bytes_per_sec;
+            *next_free = start + Duration::new(secs as u64, (secs.fract() * 1e9) as u32);
+            start
+        };
+        if start > now {
+            thread::sleep(start - now);
+        }
+    }
+}
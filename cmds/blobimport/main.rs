@@ -16,6 +16,10 @@ extern crate futures;
 extern crate futures_cpupool;
 #[macro_use]
 extern crate lazy_static;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
 #[macro_use]
 extern crate slog;
 extern crate slog_glog_fmt;
@@ -24,8 +28,10 @@ extern crate tokio_core;
 
 extern crate blobrepo;
 extern crate blobstore;
+extern crate bookmarks;
 extern crate changesets;
 extern crate fileblob;
+extern crate filebookmarks;
 extern crate fileheads;
 extern crate filekv;
 extern crate filelinknodes;
@@ -36,19 +42,42 @@ extern crate manifoldblob;
 extern crate memheads;
 extern crate mercurial;
 extern crate mercurial_types;
+extern crate multiplexedblob;
+extern crate retryingblob;
 extern crate rocksblob;
 extern crate rocksdb;
 extern crate services;
+extern crate sqliteblob;
 #[macro_use]
 extern crate stats;
+extern crate statsblob;
+extern crate storage_types;
+#[cfg(test)]
+extern crate tempdir;
+extern crate throttledblob;
 
+mod checkpoint;
 mod convert;
+mod error_manifest;
+mod import_bookmarks;
+mod largefiles;
+mod limits;
 mod manifest;
+mod progress;
+mod revs;
+mod verify;
 
+use std::cmp;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::mem;
+use std::hash::{Hash, Hasher};
 use std::path::{Path, PathBuf};
-use std::sync::Arc;
-use std::sync::mpsc::sync_channel;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, sync_channel, SyncSender};
 use std::thread;
+use std::time::Duration;
 
 use bytes::Bytes;
 use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
@@ -62,15 +91,26 @@ use stats::Timeseries;
 use tokio_core::reactor::{Core, Remote};
 
 use blobrepo::BlobChangeset;
-use blobstore::Blobstore;
+use blobstore::{Blobstore, PrefixBlobstore};
+use checkpoint::{Checkpoint, SavedCheckpoint};
+use error_manifest::ErrorManifest;
 use fileblob::Fileblob;
+use largefiles::LargefileStores;
+use limits::{MemoryBudget, RateLimiter};
 use filelinknodes::FileLinknodes;
 use futures_ext::{BoxFuture, FutureExt};
 use linknodes::NoopLinknodes;
 use manifoldblob::ManifoldBlob;
+use memheads::MemHeads;
 use mercurial::{RevlogRepo, RevlogRepoOptions};
 use mercurial_types::{Changeset, ChangesetId, RepositoryId};
+use multiplexedblob::{MultiplexedBlobstore, WriteQuorum};
+use progress::Progress;
+use retryingblob::{RetryPolicy, RetryingBlobstore};
 use rocksblob::Rocksblob;
+use sqliteblob::Sqliteblob;
+use statsblob::StatsBlobstore;
+use throttledblob::{ThrottleLimits, ThrottledBlobstore};
 
 const DEFAULT_MANIFOLD_BUCKET: &str = "mononoke_prod";
 
@@ -81,12 +121,15 @@ define_stats! {
     duplicates: timeseries(RATE, SUM),
     failures: timeseries(RATE, SUM),
     successes: timeseries(RATE, SUM),
+    skipped: timeseries(RATE, SUM),
+    largefiles: timeseries(RATE, SUM),
 }
 
-#[derive(Debug, Eq, PartialEq)]
+#[derive(Clone, Debug, Eq, PartialEq)]
 enum BlobstoreType {
     Files,
     Rocksdb,
+    Sqlite,
     Manifold(String),
 }
 
@@ -102,18 +145,122 @@ pub(crate) enum BlobstoreEntry {
     Changeset(BlobChangeset),
 }
 
+impl BlobstoreEntry {
+    /// The blobstore key this entry will be saved under.
+    pub fn key(&self) -> String {
+        match *self {
+            BlobstoreEntry::ManifestEntry((ref key, _)) => key.clone(),
+            BlobstoreEntry::Changeset(ref bcs) => changeset_key(bcs),
+        }
+    }
+
+    /// Size of the blob data held by this entry. Changesets are small, so they aren't counted.
+    pub fn size(&self) -> usize {
+        match *self {
+            BlobstoreEntry::ManifestEntry((_, ref value)) => value.len(),
+            BlobstoreEntry::Changeset(_) => 0,
+        }
+    }
+}
+
+// Must match the key used by BlobChangeset::save
+fn changeset_key(bcs: &BlobChangeset) -> String {
+    format!("changeset-{}.bincode", bcs.get_changeset_id())
+}
+
+/// Sends entries to the io thread, keeping the checkpoint and progress counters up to date.
+#[derive(Clone)]
+pub(crate) struct EntrySender {
+    // One per io thread
+    senders: Vec<SyncSender<BlobstoreEntry>>,
+    checkpoint: Arc<Checkpoint>,
+    progress: Arc<Progress>,
+    memory: Arc<MemoryBudget>,
+    upload_rate: Option<Arc<RateLimiter>>,
+}
+
+impl EntrySender {
+    /// Send an entry produced by revision `rev`, waiting if that would go over the memory or
+    /// upload rate limits.
+    pub fn send(&self, entry: BlobstoreEntry, rev: u64) -> Result<()> {
+        let size = entry.size();
+        self.memory.acquire(size)?;
+        if let Some(ref upload_rate) = self.upload_rate {
+            upload_rate.throttle(size);
+        }
+        self.checkpoint.entry_sent(&entry, rev);
+        self.progress.entry_sent();
+        let shard = self.shard(&entry);
+        self.senders[shard].send(entry).map_err(Error::from)
+    }
+
+    // Entries with the same key always go to the same io thread, so that it can filter out
+    // duplicates.
+    fn shard(&self, entry: &BlobstoreEntry) -> usize {
+        if self.senders.len() == 1 {
+            return 0;
+        }
+        let mut hasher = DefaultHasher::new();
+        entry.key().hash(&mut hasher);
+        (hasher.finish() % self.senders.len() as u64) as usize
+    }
+
+    /// Record that all the entries of revision `rev` have been sent.
+    pub fn changeset_converted(&self, rev: u64) -> Result<()> {
+        self.progress.changeset_converted();
+        self.checkpoint.changeset_converted(rev)
+    }
+}
+
+/// Save a batch of changesets, and record them as written once they all are.
+fn save_changesets(
+    blobstore: BBlobstore,
+    changesets: Vec<BlobChangeset>,
+    checkpoint: Arc<Checkpoint>,
+    progress: Arc<Progress>,
+) -> BoxFuture<(), Error> {
+    let keys: Vec<_> = changesets.iter().map(changeset_key).collect();
+    let batch_size = cmp::max(1, changesets.len());
+    BlobChangeset::save_many(blobstore, changesets, batch_size)
+        .map(move |()| {
+            for key in keys {
+                checkpoint.entry_written(&key);
+                progress.entry_done(0);
+            }
+        })
+        .boxify()
+}
+
 fn run_blobimport<In, Out>(
     input: In,
     output: Out,
-    blobtype: BlobstoreType,
+    blobtypes: Vec<BlobstoreType>,
     write_linknodes: bool,
     logger: &Logger,
     postpone_compaction: bool,
     channel_size: usize,
+    io_threads: usize,
+    changeset_batch_size: usize,
+    convert_concurrency: usize,
     skip: Option<u64>,
     commits_limit: Option<u64>,
     max_blob_size: Option<usize>,
     inmemory_logs_capacity: Option<usize>,
+    blob_prefix: Option<String>,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
+    checkpoint_file: Option<PathBuf>,
+    resume: bool,
+    from_rev: Option<String>,
+    to_rev: Option<String>,
+    progress_interval: Option<Duration>,
+    dry_run: bool,
+    error_manifest: Option<PathBuf>,
+    import_bookmarks: bool,
+    largefiles: Option<LargefileStores>,
+    max_memory: Option<usize>,
+    max_upload_rate: Option<u64>,
+    flat_to_tree: bool,
 ) -> Result<()>
 where
     In: Into<PathBuf>,
@@ -123,77 +270,248 @@ where
     let core = Core::new()?;
     let cpupool = Arc::new(CpuPool::new_num_cpus());
 
-    info!(logger, "Opening headstore: {:?}", output);
-    let headstore = open_headstore(output.clone(), &cpupool)?;
-
-    if let BlobstoreType::Manifold(ref bucket) = blobtype {
-        info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
+    let headstore: Box<heads::Heads> = if dry_run {
+        info!(logger, "Dry run, nothing will be written");
+        Box::new(MemHeads::new())
     } else {
-        info!(logger, "Opening blobstore: {:?}", output);
+        info!(logger, "Opening headstore: {:?}", output);
+        open_headstore(output.clone(), &cpupool)?
+    };
+
+    if !dry_run {
+        for blobtype in &blobtypes {
+            if let BlobstoreType::Manifold(ref bucket) = *blobtype {
+                info!(logger, "Using ManifoldBlob with bucket: {:?}", bucket);
+            } else {
+                info!(logger, "Opening blobstore: {:?}", output);
+            }
+        }
     }
+    let dry_run_blobstore = DryRunBlobstore::default();
+
+    let saved_checkpoint = if resume {
+        let path = checkpoint_file
+            .as_ref()
+            .expect("--resume requires --checkpoint-file");
+        info!(logger, "Resuming from checkpoint: {}", path.display());
+        Some(SavedCheckpoint::load(path)?)
+    } else {
+        None
+    };
+    let checkpoint = Arc::new(match checkpoint_file {
+        Some(path) => Checkpoint::new(path),
+        None => Checkpoint::disabled(),
+    });
+
+    let progress = Arc::new(Progress::default());
+    let memory = Arc::new(MemoryBudget::new(max_memory));
+    let upload_rate = max_upload_rate.map(|rate| Arc::new(RateLimiter::new(rate)));
+    let error_manifest =
+        error_manifest.map(|path| Arc::new(ErrorManifest::new(path, logger.clone())));
 
-    let (sender, recv) = sync_channel::<BlobstoreEntry>(channel_size);
-    // Separate thread that does all blobstore operations. Other worker threads send parsed revlog
-    // data to this thread.
-    let iothread = thread::Builder::new()
-        .name("iothread".to_owned())
-        .spawn({
-            let output = output.clone();
-            move || {
-                let receiverstream = stream::iter_ok::<_, ()>(recv);
-                let mut core = Core::new().expect("cannot create core in iothread");
-                let blobstore = open_blobstore(
-                    output,
-                    blobtype,
-                    &core.remote(),
-                    postpone_compaction,
-                    max_blob_size,
-                )?;
-                // Filter only manifest entries, because changeset entries should be unique
-                let mut inserted_manifest_entries = std::collections::HashSet::new();
-                let stream = receiverstream
-                    .map(move |sender_helper| match sender_helper {
-                        BlobstoreEntry::Changeset(bcs) => {
-                            bcs.save(blobstore.clone()).from_err().boxify()
+    let shared_blobstores = if dry_run {
+        Vec::new()
+    } else {
+        open_shared_blobstores(
+            output.clone().into(),
+            &blobtypes,
+            &core.remote(),
+            postpone_compaction,
+            throttle_limits,
+            retry_policy.clone(),
+        )?
+    };
+    // Every io thread opens its own Manifold blobstores, so the limits are split between them
+    let shard_throttle_limits = ThrottleLimits {
+        max_inflight: throttle_limits
+            .max_inflight
+            .map(|limit| cmp::max(1, limit / io_threads)),
+        max_qps: throttle_limits
+            .max_qps
+            .map(|limit| cmp::max(1, limit / io_threads as u32)),
+    };
+
+    // The first io thread checks which blobs of a resumed import were actually written before we
+    // start converting.
+    let (resume_sender, resume_recv) = channel::<u64>();
+    let mut resume = Some((saved_checkpoint, resume_sender));
+    let mut senders = Vec::with_capacity(io_threads);
+    let mut iothreads = Vec::with_capacity(io_threads);
+    // Separate threads do all blobstore operations. Other worker threads send parsed revlog data
+    // to these threads, sharded by blobstore key.
+    for shard in 0..io_threads {
+        let (sender, recv) = sync_channel::<BlobstoreEntry>(channel_size);
+        senders.push(sender);
+
+        let iothread = thread::Builder::new()
+            .name(format!("iothread{}", shard))
+            .spawn({
+                let blobtypes = blobtypes.clone();
+                let shared_blobstores = shared_blobstores.clone();
+                let blob_prefix = blob_prefix.clone();
+                let retry_policy = retry_policy.clone();
+                let checkpoint = checkpoint.clone();
+                let progress = progress.clone();
+                let memory = memory.clone();
+                let dry_run_blobstore = dry_run_blobstore.clone();
+                let resume = resume.take();
+                move || {
+                    let receiverstream = stream::iter_ok::<_, ()>(recv);
+                    let mut core = Core::new().expect("cannot create core in iothread");
+                    let blobstore = if dry_run {
+                        limit_blob_size(Arc::new(dry_run_blobstore), max_blob_size)
+                    } else {
+                        compose_blobstore(
+                            blobtypes,
+                            shared_blobstores,
+                            &core.remote(),
+                            max_blob_size,
+                            blob_prefix,
+                            shard_throttle_limits,
+                            retry_policy,
+                        )?
+                    };
+                    if let Some((saved_checkpoint, resume_sender)) = resume {
+                        let resume_rev = match saved_checkpoint {
+                            Some(saved) => core.run(saved.resume_rev(&blobstore))?,
+                            None => 0,
+                        };
+                        resume_sender.send(resume_rev)?;
+                    }
+
+                    // Filter only manifest entries, because changeset entries should be unique.
+                    // Entries are sharded by key, so duplicates always end up in the same thread.
+                    let mut inserted_manifest_entries = std::collections::HashSet::new();
+                    // Changesets are saved in batches, the last one once every entry is received
+                    let pending_changesets = Arc::new(Mutex::new(Vec::new()));
+                    let flush_changesets = stream::once::<_, ()>(Ok(())).map({
+                        let blobstore = blobstore.clone();
+                        let checkpoint = checkpoint.clone();
+                        let progress = progress.clone();
+                        let pending_changesets = pending_changesets.clone();
+                        move |()| {
+                            let mut pending = pending_changesets.lock().expect("lock poison");
+                            save_changesets(
+                                blobstore.clone(),
+                                mem::replace(&mut *pending, Vec::new()),
+                                checkpoint.clone(),
+                                progress.clone(),
+                            )
                         }
-                        BlobstoreEntry::ManifestEntry((key, value)) => {
-                            if inserted_manifest_entries.insert(key.clone()) {
-                                blobstore.put(key.clone(), value).from_err().boxify()
+                    });
+                    let stream = receiverstream
+                        .map({
+                            let memory = memory.clone();
+                            move |sender_helper| {
+                                let key = sender_helper.key();
+                                let size = sender_helper.size();
+                                let (fut, bytes) = match sender_helper {
+                                    BlobstoreEntry::Changeset(bcs) => {
+                                        let mut pending =
+                                            pending_changesets.lock().expect("lock poison");
+                                        pending.push(bcs);
+                                        if pending.len() < changeset_batch_size {
+                                            return Ok(()).into_future().boxify();
+                                        }
+                                        return save_changesets(
+                                            blobstore.clone(),
+                                            mem::replace(&mut *pending, Vec::new()),
+                                            checkpoint.clone(),
+                                            progress.clone(),
+                                        );
+                                    }
+                                    BlobstoreEntry::ManifestEntry((key, value)) => {
+                                        if inserted_manifest_entries.insert(key.clone()) {
+                                            let bytes = value.len();
+                                            let put = blobstore.put(key.clone(), value);
+                                            (put.from_err().boxify(), bytes)
+                                        } else {
+                                            STATS::duplicates.add_value(1);
+                                            (Ok(()).into_future().boxify(), 0)
+                                        }
+                                    }
+                                };
+                                let checkpoint = checkpoint.clone();
+                                let progress = progress.clone();
+                                let memory = memory.clone();
+                                fut.map(move |()| {
+                                    checkpoint.entry_written(&key);
+                                    progress.entry_done(bytes);
+                                    memory.release(size);
+                                }).boxify()
+                            }
+                        })
+                        .chain(flush_changesets)
+                        .map_err(|_| failure::err_msg("failure happened").into())
+                        .buffer_unordered(channel_size)
+                        .then(move |res: Result<()>| {
+                            if res.is_err() {
+                                STATS::failures.add_value(1);
                             } else {
-                                STATS::duplicates.add_value(1);
-                                Ok(()).into_future().boxify()
+                                STATS::successes.add_value(1);
                             }
-                        }
-                    })
-                    .map_err(|_| failure::err_msg("failure happened").into())
-                    .buffer_unordered(channel_size)
-                    .then(move |res: Result<()>| {
-                        if res.is_err() {
-                            STATS::failures.add_value(1);
-                        } else {
-                            STATS::successes.add_value(1);
-                        }
-                        res
-                    });
-                core.run(stream.for_each(|_| Ok(())))
-            }
-        })
-        .expect("cannot start iothread");
+                            res
+                        });
+                    let res = core.run(stream.for_each(|_| Ok(())));
+                    memory.close();
+                    res
+                }
+            })
+            .expect("cannot start iothread");
+        iothreads.push(iothread);
+    }
 
     let repo = open_repo(&input, inmemory_logs_capacity)?;
 
+    let (skip, commits_limit) =
+        revs::select_revs(&repo, skip, commits_limit, from_rev, to_rev, logger)?;
+
+    // If the io thread failed to start, the error is reported when joining it below
+    let first_rev = skip.unwrap_or(0);
+    let (convert_skip, convert_commits_limit) = match resume_recv.recv() {
+        Ok(resume_rev) if resume_rev > first_rev => {
+            info!(logger, "Skipping {} already imported revisions", resume_rev - first_rev);
+            // --commits-limit still counts from the first revision of the original import
+            (
+                Some(resume_rev),
+                commits_limit.map(|limit| limit.saturating_sub(resume_rev - first_rev)),
+            )
+        }
+        _ => (skip, commits_limit),
+    };
+    checkpoint.start_from(convert_skip.unwrap_or(0));
+
+    let remaining = (repo.get_changelog().len() as u64).saturating_sub(convert_skip.unwrap_or(0));
+    progress.set_total(match convert_commits_limit {
+        Some(limit) => limit.min(remaining),
+        None => remaining,
+    });
+    if let Some(interval) = progress_interval {
+        progress::start_reporting(progress.clone(), logger.clone(), interval)?;
+    }
+
     info!(logger, "Converting: {}", input.display());
     let convert_context = convert::ConvertContext {
         repo: repo.clone(),
-        sender,
+        sender: EntrySender {
+            senders,
+            checkpoint: checkpoint.clone(),
+            progress: progress.clone(),
+            memory,
+            upload_rate,
+        },
         headstore,
         core,
         cpupool: cpupool.clone(),
         logger: logger.clone(),
-        skip: skip,
-        commits_limit: commits_limit,
+        skip: convert_skip,
+        commits_limit: convert_commits_limit,
+        concurrency: convert_concurrency,
+        error_manifest: error_manifest.clone(),
+        largefiles: largefiles.map(Arc::new),
+        flat_to_tree,
     };
-    let res = if write_linknodes {
+    let res = if write_linknodes && !dry_run {
         info!(logger, "Opening linknodes store: {:?}", output);
         let output = output.clone().into();
         let linknodes_store = open_linknodes_store(&output, &cpupool)?;
@@ -202,10 +520,34 @@ where
         info!(logger, "--linknodes not specified, not writing linknodes");
         convert_context.convert(NoopLinknodes::new())
     };
-    iothread.join().expect("failed to join io thread")?;
+    let iores = iothreads
+        .into_iter()
+        .map(|iothread| iothread.join().expect("failed to join io thread"))
+        .collect::<Result<Vec<()>>>();
+    progress.finish();
+    // Save even if the import failed, so that it can be resumed from as far as it got
+    checkpoint.save()?;
+    if let Some(error_manifest) = error_manifest {
+        error_manifest.save()?;
+    }
+    iores?;
     res?;
 
-    if !skip.is_none() && !commits_limit.is_none() {
+    if import_bookmarks && !dry_run {
+        info!(logger, "Importing bookmarks");
+        import_bookmarks::import_bookmarks(
+            &repo,
+            output.clone().into(),
+            &cpupool,
+            skip.unwrap_or(0),
+            commits_limit,
+            logger,
+        )?;
+    }
+
+    if dry_run {
+        dry_run_blobstore.report(logger);
+    } else if !skip.is_none() && !commits_limit.is_none() {
         warn!(
             logger,
             "skipping filling up changesets store because --skip or --commits-limit is set"
@@ -214,29 +556,74 @@ where
         warn!(logger, "filling up changesets changesets store");
         let changesets = open_changesets_store(output.into())?;
         let mut core = Core::new()?;
-        let fut = repo.changesets()
-            .and_then(|node| {
-                let node = ChangesetId::new(node);
-                repo.get_changeset_by_changesetid(&node)
-                    .map(move |cs| (cs, node))
-            })
-            .for_each(|(cs, node)| {
-                let parents = cs.parents()
-                    .into_iter()
-                    .map(|p| ChangesetId::new(p))
-                    .collect();
-                let insert = ChangesetInsert {
-                    repo_id: RepositoryId::new(0), // TODO(stash): real repo id
-                    cs_id: node,
-                    parents,
-                };
-                changesets.add(&insert)
-            });
+        // Only the parents are needed, which the changelog index has without reading changesets
+        let fut = repo.changelog_revisions().for_each(|info| {
+            let parents = info.parents
+                .into_iter()
+                .map(|p| ChangesetId::new(p))
+                .collect();
+            let insert = ChangesetInsert {
+                repo_id: RepositoryId::new(0), // TODO(stash): real repo id
+                cs_id: ChangesetId::new(info.nodeid),
+                parents,
+            };
+            changesets.add(&insert)
+        });
         core.run(fut)?;
     }
     Ok(())
 }
 
+/// Check the imported blobs against the revlog repo. Blobs are read back through the same stack of
+/// blobstores as they were written.
+fn run_verify<In, Out>(
+    input: In,
+    output: Out,
+    blobtypes: Vec<BlobstoreType>,
+    logger: &Logger,
+    concurrency: usize,
+    skip: Option<u64>,
+    commits_limit: Option<u64>,
+    inmemory_logs_capacity: Option<usize>,
+    blob_prefix: Option<String>,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
+    from_rev: Option<String>,
+    to_rev: Option<String>,
+) -> Result<()>
+where
+    In: Into<PathBuf>,
+    Out: Into<PathBuf>,
+{
+    let input = input.into();
+    let repo = open_repo(&input, inmemory_logs_capacity)?;
+    let (skip, commits_limit) =
+        revs::select_revs(&repo, skip, commits_limit, from_rev, to_rev, logger)?;
+
+    let core = Core::new()?;
+    let blobstore = open_blobstore(
+        output,
+        blobtypes,
+        &core.remote(),
+        false,
+        None,
+        blob_prefix,
+        throttle_limits,
+        retry_policy,
+    )?;
+
+    info!(logger, "Verifying: {}", input.display());
+    verify::VerifyContext {
+        repo,
+        blobstore,
+        core,
+        logger: logger.clone(),
+        skip,
+        commits_limit,
+        concurrency,
+    }.verify()
+}
+
 fn open_changesets_store(mut output: PathBuf) -> Result<Arc<Changesets>> {
     output.push("changesets");
     Ok(Arc::new(SqliteChangesets::create(
@@ -284,50 +671,215 @@ fn open_linknodes_store<P: Into<PathBuf>>(path: P, pool: &Arc<CpuPool>) -> Resul
 
 fn open_blobstore<P: Into<PathBuf>>(
     output: P,
-    ty: BlobstoreType,
+    types: Vec<BlobstoreType>,
     remote: &Remote,
     postpone_compaction: bool,
     max_blob_size: Option<usize>,
+    blob_prefix: Option<String>,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
+) -> Result<BBlobstore> {
+    let shared = open_shared_blobstores(
+        output.into(),
+        &types,
+        remote,
+        postpone_compaction,
+        throttle_limits,
+        retry_policy.clone(),
+    )?;
+    compose_blobstore(
+        types,
+        shared,
+        remote,
+        max_blob_size,
+        blob_prefix,
+        throttle_limits,
+        retry_policy,
+    )
+}
+
+/// Open the output blobstores which can be shared between io threads. Manifold blobstores are
+/// bound to the event loop of the thread which opens them, so they're left as None for each io
+/// thread to open its own.
+fn open_shared_blobstores(
+    output: PathBuf,
+    types: &[BlobstoreType],
+    remote: &Remote,
+    postpone_compaction: bool,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
+) -> Result<Vec<Option<BBlobstore>>> {
+    types
+        .iter()
+        .map(|ty| match *ty {
+            BlobstoreType::Manifold(_) => Ok(None),
+            _ => open_single_blobstore(
+                output.clone(),
+                ty.clone(),
+                remote,
+                postpone_compaction,
+                throttle_limits,
+                retry_policy.clone(),
+            ).map(Some),
+        })
+        .collect()
+}
+
+/// Put together the blobstore used by an io thread, opening the blobstores that weren't shared.
+fn compose_blobstore(
+    types: Vec<BlobstoreType>,
+    shared: Vec<Option<BBlobstore>>,
+    remote: &Remote,
+    max_blob_size: Option<usize>,
+    blob_prefix: Option<String>,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
+) -> Result<BBlobstore> {
+    let mut blobstores: Vec<BBlobstore> = types
+        .into_iter()
+        .zip(shared)
+        .map(|(ty, shared)| match shared {
+            Some(blobstore) => Ok(blobstore),
+            None => open_single_blobstore(
+                PathBuf::new(),
+                ty,
+                remote,
+                false,
+                throttle_limits,
+                retry_policy.clone(),
+            ),
+        })
+        .collect::<Result<_>>()?;
+
+    // Every blobstore must get every blob, otherwise the copies can't be used interchangeably
+    let blobstore: BBlobstore = if blobstores.len() == 1 {
+        blobstores.pop().unwrap()
+    } else {
+        Arc::new(MultiplexedBlobstore::new(blobstores, WriteQuorum::All)?)
+    };
+
+    let blobstore: BBlobstore = if let Some(blob_prefix) = blob_prefix {
+        Arc::new(PrefixBlobstore::new(blobstore, blob_prefix))
+    } else {
+        blobstore
+    };
+
+    let blobstore = limit_blob_size(blobstore, max_blob_size);
+
+    _assert_clone(&blobstore);
+    _assert_send(&blobstore);
+    _assert_static(&blobstore);
+    _assert_blobstore(&blobstore);
+
+    Ok(blobstore)
+}
+
+/// Open one of the output blobstores. Retries and throttling are applied to each blobstore
+/// separately, as they usually have very different limits.
+fn open_single_blobstore(
+    output: PathBuf,
+    ty: BlobstoreType,
+    remote: &Remote,
+    postpone_compaction: bool,
+    throttle_limits: ThrottleLimits,
+    retry_policy: Option<RetryPolicy>,
 ) -> Result<BBlobstore> {
     let blobstore: BBlobstore = match ty {
         BlobstoreType::Files => {
-            let mut output = output.into();
+            let mut output = output;
             output.push("blobs");
-            Arc::new(Fileblob::create(output)
+            let blobstore = Fileblob::create(output)
                 .map_err(Error::from)
-                .context("Failed to open file blob store")?)
+                .context("Failed to open file blob store")?;
+            Arc::new(StatsBlobstore::new(blobstore, "fileblob"))
         }
         BlobstoreType::Rocksdb => {
-            let mut output = output.into();
+            let mut output = output;
             output.push("blobs");
             let options = rocksdb::Options::new()
                 .create_if_missing(true)
                 .disable_auto_compaction(postpone_compaction);
-            Arc::new(Rocksblob::open_with_options(output, options)
+            let blobstore = Rocksblob::open_with_options(output, options)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?;
+            Arc::new(StatsBlobstore::new(blobstore, "rocksblob"))
+        }
+        BlobstoreType::Sqlite => {
+            fs::create_dir_all(&output)
                 .map_err(Error::from)
-                .context("Failed to open rocksdb blob store")?)
+                .context("Failed to create sqlite blob store directory")?;
+            let blobstore = Sqliteblob::create(output.join("blobs.sqlite"))
+                .context("Failed to open sqlite blob store")?;
+            Arc::new(StatsBlobstore::new(blobstore, "sqliteblob"))
         }
         BlobstoreType::Manifold(bucket) => {
             let mb: ManifoldBlob = ManifoldBlob::new_may_panic(bucket, remote);
-            Arc::new(mb)
+            Arc::new(StatsBlobstore::new(mb, "manifoldblob"))
         }
     };
 
-    let blobstore = if let Some(max_blob_size) = max_blob_size {
+    let blobstore: BBlobstore = if throttle_limits != ThrottleLimits::default() {
+        Arc::new(ThrottledBlobstore::new(blobstore, throttle_limits)?)
+    } else {
+        blobstore
+    };
+
+    // Retries go through the throttling, so that they can't push the blobstore over the limits
+    let blobstore: BBlobstore = if let Some(retry_policy) = retry_policy {
+        Arc::new(RetryingBlobstore::new(blobstore, retry_policy)?)
+    } else {
+        blobstore
+    };
+
+    Ok(blobstore)
+}
+
+fn limit_blob_size(blobstore: BBlobstore, max_blob_size: Option<usize>) -> BBlobstore {
+    if let Some(max_blob_size) = max_blob_size {
         Arc::new(LimitedBlobstore {
             blobstore,
             max_blob_size,
         })
     } else {
         blobstore
-    };
+    }
+}
 
-    _assert_clone(&blobstore);
-    _assert_send(&blobstore);
-    _assert_static(&blobstore);
-    _assert_blobstore(&blobstore);
+/// Blobstore for --dry-run, which doesn't store anything but counts what would have been stored
+#[derive(Clone, Default)]
+struct DryRunBlobstore {
+    changesets: Arc<AtomicUsize>,
+    entries: Arc<AtomicUsize>,
+    bytes: Arc<AtomicUsize>,
+}
 
-    Ok(blobstore)
+impl DryRunBlobstore {
+    fn report(&self, logger: &Logger) {
+        info!(
+            logger,
+            "dry run: would have written {} changesets and {} manifest entry blobs, \
+             {} bytes in total",
+            self.changesets.load(Ordering::Relaxed),
+            self.entries.load(Ordering::Relaxed),
+            self.bytes.load(Ordering::Relaxed)
+        );
+    }
+}
+
+impl Blobstore for DryRunBlobstore {
+    fn get(&self, _key: String) -> BoxFuture<Option<Bytes>, Error> {
+        Ok(None).into_future().boxify()
+    }
+
+    fn put(&self, key: String, value: Bytes) -> BoxFuture<(), Error> {
+        if key.starts_with("changeset-") {
+            self.changesets.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.entries.fetch_add(1, Ordering::Relaxed);
+        }
+        self.bytes.fetch_add(value.len(), Ordering::Relaxed);
+        Ok(()).into_future().boxify()
+    }
 }
 
 /// Blobstore that doesn't inserts blobs that are bigger than max_blob_size
@@ -365,21 +917,100 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
 
             -d, --debug              'print debug level output'
             --linknodes              'also generate linknodes'
-            --channel-size [SIZE]    'channel size between worker and io threads. Default: 1000'
+            --import-bookmarks       'also import the bookmarks of the revlog repo'
+            --flat-to-tree           'convert the manifests of a repo without tree manifests to tree manifests, whose roots --verify then reports as mismatched'
+            --channel-size [SIZE]    'channel size between worker threads and each io thread. Default: 1000'
+            --io-threads [COUNT]     'number of threads writing to the blobstore. Default: 1'
+            --changeset-batch-size [SIZE]  'changesets saved at once by each io thread. Default: 100'
+            --convert-concurrency [LIMIT]  'max number of changesets, and of entries per changeset, converted at once. Default: 100'
             --skip [SKIP]            'skips commits from the beginning'
             --commits-limit [LIMIT]  'import only LIMIT first commits from revlog repo'
             --max-blob-size [LIMIT]  'max size of the blob to be inserted'
             --inmemory-logs-capacity [CAPACITY]  'max number of filelogs and treelogs in memory'
+            --blob-prefix [PREFIX]   'prefix prepended to all blobstore keys'
+            --max-inflight [LIMIT]   'max number of blobstore requests in flight at once'
+            --max-qps [LIMIT]        'max number of blobstore requests started per second'
+            --max-memory-mb [LIMIT]  'max megabytes of blob data waiting to be written before conversion is paused'
+            --max-upload-mbps [LIMIT]  'max rate of blob data sent to the blobstore, in megabits per second'
+
+            --retry-attempts [ATTEMPTS]  'retry failed blobstore requests, up to ATTEMPTS attempts in total'
+            --retry-delay-ms [DELAY]     'delay before the first retry. Default: 100'
+            --retry-factor [FACTOR]      'multiplier applied to the delay after each retry. Default: 2'
+            --retry-jitter [JITTER]      'random fraction (0-1) by which delays are varied. Default: 0.1'
+
+            --checkpoint-file [FILE]  'periodically record import progress in FILE'
+            --skip-bad-filelogs [FILE]  'record entries which fail to import in the JSON error manifest FILE instead of failing'
+            --largefiles-store [DIR]  'also import the content of largefiles standins from the largefiles store DIR'
+            --lfs-store [DIR]        'also import the content of LFS pointers from the LFS usercache DIR'
+            --progress-interval [SECS]  'log import progress every SECS seconds, 0 to disable. Default: 60'
         "#,
         )
+        .arg(
+            Arg::with_name("from-rev")
+                .long("from-rev")
+                .takes_value(true)
+                .conflicts_with("skip")
+                .help(
+                    "first revision to import: a revision number, changeset hash, tip or \
+                     bookmark",
+                ),
+        )
+        .arg(
+            Arg::with_name("to-rev")
+                .long("to-rev")
+                .takes_value(true)
+                .conflicts_with("commits-limit")
+                .help(
+                    "last revision to import, inclusive: a revision number, changeset hash, tip \
+                     or bookmark",
+                ),
+        )
+        .arg(
+            Arg::with_name("verify")
+                .long("verify")
+                .conflicts_with("max-blob-size")
+                .help(
+                    "after importing, read everything back from the blobstore and check it \
+                     against the revlog repo",
+                ),
+        )
+        .arg(
+            Arg::with_name("verify-only")
+                .long("verify-only")
+                .conflicts_with_all(&["verify", "max-blob-size", "checkpoint-file"])
+                .help("check a previous import against the revlog repo, without importing"),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .conflicts_with_all(&["verify", "verify-only", "checkpoint-file"])
+                .help(
+                    "convert everything but don't write anything, only report how much would \
+                     have been written",
+                ),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .requires("checkpoint-file")
+                .help(
+                    "resume an interrupted import from --checkpoint-file, skipping revisions \
+                     which are already in the blobstore",
+                ),
+        )
         .arg(
             Arg::with_name("blobstore")
                 .long("blobstore")
                 .short("B")
                 .takes_value(true)
-                .possible_values(&["files", "rocksdb", "manifold"])
+                .possible_values(&["files", "rocksdb", "sqlite", "manifold"])
+                .multiple(true)
+                .number_of_values(1)
                 .required(true)
-                .help("blobstore type"),
+                .help(
+                    "blobstore type. Can be given several times to write to several blobstores \
+                     at once, but only one of them can be stored in OUTPUT",
+                ),
         )
         .arg(
             Arg::with_name("bucket")
@@ -398,6 +1029,65 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         )
 }
 
+fn get_retry_policy<'a>(matches: &ArgMatches<'a>) -> Result<Option<RetryPolicy>> {
+    let max_attempts = match matches.value_of("retry-attempts") {
+        Some(attempts) => attempts
+            .parse()
+            .expect("retry-attempts must be positive integer"),
+        None => return Ok(None),
+    };
+    if max_attempts == 0 {
+        bail_msg!("retry-attempts must be positive integer");
+    }
+    let default = RetryPolicy::default();
+
+    Ok(Some(RetryPolicy {
+        max_attempts,
+        base_delay: matches
+            .value_of("retry-delay-ms")
+            .map(|delay| {
+                Duration::from_millis(delay
+                    .parse()
+                    .expect("retry-delay-ms must be positive integer"))
+            })
+            .unwrap_or(default.base_delay),
+        factor: matches
+            .value_of("retry-factor")
+            .map(|factor| factor.parse().expect("retry-factor must be a number"))
+            .unwrap_or(default.factor),
+        jitter: matches
+            .value_of("retry-jitter")
+            .map(|jitter| jitter.parse().expect("retry-jitter must be a number"))
+            .unwrap_or(default.jitter),
+        retryable: default.retryable,
+    }))
+}
+
+fn get_largefile_stores<'a>(matches: &ArgMatches<'a>) -> Option<LargefileStores> {
+    let largefiles = matches.value_of("largefiles-store").map(PathBuf::from);
+    let lfs = matches.value_of("lfs-store").map(PathBuf::from);
+    if largefiles.is_none() && lfs.is_none() {
+        None
+    } else {
+        Some(LargefileStores { largefiles, lfs })
+    }
+}
+
+fn get_progress_interval<'a>(matches: &ArgMatches<'a>) -> Option<Duration> {
+    let secs = matches
+        .value_of("progress-interval")
+        .map(|secs| {
+            secs.parse()
+                .expect("progress-interval must be positive integer")
+        })
+        .unwrap_or(60);
+    if secs == 0 {
+        None
+    } else {
+        Some(Duration::from_secs(secs))
+    }
+}
+
 fn start_thrift_service<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<()> {
     let port = match matches.value_of("port") {
         None => return Ok(()),
@@ -457,12 +1147,30 @@ fn main() {
             .value_of("bucket")
             .unwrap_or(DEFAULT_MANIFOLD_BUCKET);
 
-        let blobtype = match matches.value_of("blobstore").unwrap() {
-            "files" => BlobstoreType::Files,
-            "rocksdb" => BlobstoreType::Rocksdb,
-            "manifold" => BlobstoreType::Manifold(bucket.to_string()),
-            bad => panic!("unexpected blobstore type {}", bad),
-        };
+        let mut blobtypes = Vec::new();
+        for blobtype in matches.values_of("blobstore").unwrap() {
+            let blobtype = match blobtype {
+                "files" => BlobstoreType::Files,
+                "rocksdb" => BlobstoreType::Rocksdb,
+                "sqlite" => BlobstoreType::Sqlite,
+                "manifold" => BlobstoreType::Manifold(bucket.to_string()),
+                bad => panic!("unexpected blobstore type {}", bad),
+            };
+            if blobtypes.contains(&blobtype) {
+                bail_msg!("blobstore {:?} specified more than once", blobtype);
+            }
+            blobtypes.push(blobtype);
+        }
+        let local_blobstores = blobtypes
+            .iter()
+            .filter(|ty| match **ty {
+                BlobstoreType::Manifold(_) => false,
+                _ => true,
+            })
+            .count();
+        if local_blobstores > 1 {
+            bail_msg!("at most one of the files, rocksdb and sqlite blobstores can be used");
+        }
 
         let postpone_compaction = matches.is_present("postpone-compaction");
 
@@ -471,37 +1179,166 @@ fn main() {
             .map(|size| size.parse().expect("channel-size must be positive integer"))
             .unwrap_or(1000);
 
-        let write_linknodes = matches.is_present("linknodes");
+        let io_threads: usize = matches
+            .value_of("io-threads")
+            .map(|count| count.parse().expect("io-threads must be positive integer"))
+            .unwrap_or(1);
+        if io_threads == 0 {
+            bail_msg!("io-threads must be positive integer");
+        }
 
-        run_blobimport(
-            input,
-            output.expect("output must be specified").to_string(),
-            blobtype,
-            write_linknodes,
-            &root_log,
-            postpone_compaction,
-            channel_size,
-            matches
-                .value_of("skip")
-                .map(|size| size.parse().expect("skip must be positive integer")),
-            matches.value_of("commits-limit").map(|size| {
+        let changeset_batch_size: usize = matches
+            .value_of("changeset-batch-size")
+            .map(|size| {
                 size.parse()
-                    .expect("commits-limit must be positive integer")
-            }),
-            matches.value_of("max-blob-size").map(|size| {
-                size.parse()
-                    .expect("max-blob-size must be positive integer")
-            }),
-            matches.value_of("inmemory-logs-capacity").map(|capacity| {
-                capacity
+                    .expect("changeset-batch-size must be positive integer")
+            })
+            .unwrap_or(100);
+        if changeset_batch_size == 0 {
+            bail_msg!("changeset-batch-size must be positive integer");
+        }
+
+        let convert_concurrency: usize = matches
+            .value_of("convert-concurrency")
+            .map(|limit| {
+                limit
+                    .parse()
+                    .expect("convert-concurrency must be positive integer")
+            })
+            .unwrap_or(100);
+        if convert_concurrency == 0 {
+            bail_msg!("convert-concurrency must be positive integer");
+        }
+
+        let write_linknodes = matches.is_present("linknodes");
+
+        let output = output.expect("output must be specified");
+        let skip = matches
+            .value_of("skip")
+            .map(|size| size.parse().expect("skip must be positive integer"));
+        let commits_limit = matches.value_of("commits-limit").map(|size| {
+            size.parse()
+                .expect("commits-limit must be positive integer")
+        });
+        let inmemory_logs_capacity = matches.value_of("inmemory-logs-capacity").map(|capacity| {
+            capacity
+                .parse()
+                .expect("inmemory_logs_capacity must be positive integer")
+        });
+        let blob_prefix = matches.value_of("blob-prefix").map(|prefix| prefix.to_string());
+        let throttle_limits = ThrottleLimits {
+            max_inflight: matches.value_of("max-inflight").map(|limit| {
+                limit
                     .parse()
-                    .expect("inmemory_logs_capacity must be positive integer")
+                    .expect("max-inflight must be positive integer")
             }),
-        )?;
+            max_qps: matches
+                .value_of("max-qps")
+                .map(|limit| limit.parse().expect("max-qps must be positive integer")),
+        };
+        if throttle_limits.max_inflight == Some(0) {
+            bail_msg!("max-inflight must be positive integer");
+        }
+        if throttle_limits.max_qps == Some(0) {
+            bail_msg!("max-qps must be positive integer");
+        }
+        let retry_policy = get_retry_policy(&matches)?;
+        let max_memory = match matches.value_of("max-memory-mb") {
+            Some(limit) => {
+                let limit: usize = limit
+                    .parse()
+                    .expect("max-memory-mb must be positive integer");
+                if limit == 0 {
+                    bail_msg!("max-memory-mb must be positive integer");
+                }
+                match limit.checked_mul(1024 * 1024) {
+                    Some(bytes) => Some(bytes),
+                    None => bail_msg!("max-memory-mb {} is too large", limit),
+                }
+            }
+            None => None,
+        };
+        let max_upload_rate = match matches.value_of("max-upload-mbps") {
+            Some(limit) => {
+                let limit: u64 = limit
+                    .parse()
+                    .expect("max-upload-mbps must be positive integer");
+                if limit == 0 {
+                    bail_msg!("max-upload-mbps must be positive integer");
+                }
+                // Megabits to bytes
+                match limit.checked_mul(1000 * 1000) {
+                    Some(bits) => Some(bits / 8),
+                    None => bail_msg!("max-upload-mbps {} is too large", limit),
+                }
+            }
+            None => None,
+        };
+        let from_rev = matches.value_of("from-rev").map(|rev| rev.to_string());
+        let to_rev = matches.value_of("to-rev").map(|rev| rev.to_string());
+
+        if !matches.is_present("verify-only") {
+            run_blobimport(
+                input,
+                output.to_string(),
+                blobtypes.clone(),
+                write_linknodes,
+                &root_log,
+                postpone_compaction,
+                channel_size,
+                io_threads,
+                changeset_batch_size,
+                convert_concurrency,
+                skip,
+                commits_limit,
+                matches.value_of("max-blob-size").map(|size| {
+                    size.parse()
+                        .expect("max-blob-size must be positive integer")
+                }),
+                inmemory_logs_capacity,
+                blob_prefix.clone(),
+                throttle_limits,
+                retry_policy.clone(),
+                matches.value_of("checkpoint-file").map(PathBuf::from),
+                matches.is_present("resume"),
+                from_rev.clone(),
+                to_rev.clone(),
+                get_progress_interval(&matches),
+                matches.is_present("dry-run"),
+                matches.value_of("skip-bad-filelogs").map(PathBuf::from),
+                matches.is_present("import-bookmarks"),
+                get_largefile_stores(&matches),
+                max_memory,
+                max_upload_rate,
+                matches.is_present("flat-to-tree"),
+            )?;
+        }
+
+        if matches.is_present("verify") || matches.is_present("verify-only") {
+            run_verify(
+                input,
+                output,
+                blobtypes,
+                &root_log,
+                convert_concurrency,
+                skip,
+                commits_limit,
+                inmemory_logs_capacity,
+                blob_prefix,
+                throttle_limits,
+                retry_policy,
+                from_rev,
+                to_rev,
+            )?;
+        }
 
-        if matches.value_of("blobstore").unwrap() == "rocksdb" && postpone_compaction {
+        let uses_rocksdb = matches
+            .values_of("blobstore")
+            .unwrap()
+            .any(|blobtype| blobtype == "rocksdb");
+        if uses_rocksdb && postpone_compaction && !matches.is_present("dry-run") {
             let options = rocksdb::Options::new().create_if_missing(false);
-            let rocksdb = rocksdb::Db::open(Path::new(output.unwrap()).join("blobs"), options)
+            let rocksdb = rocksdb::Db::open(Path::new(output).join("blobs"), options)
                 .expect("can't open rocksdb");
             info!(root_log, "compaction started");
             rocksdb.compact_range(&[], &[]);
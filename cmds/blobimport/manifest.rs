@@ -4,23 +4,31 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
-use std::sync::mpsc::SyncSender;
+use std::sync::Arc;
 
 use bincode;
 use bytes::Bytes;
-use failure::{self, Error};
+use failure::{self, Error, ResultExt};
 use futures::{self, Future, IntoFuture, Stream};
+use futures::future;
 
 use blobrepo::RawNodeBlob;
-use futures_ext::StreamExt;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use linknodes::{ErrorKind as LinknodeErrorKind, Linknodes};
 use mercurial::RevlogRepo;
+use mercurial::manifest::revlog::ManifestContent;
 use mercurial::revlog::RevIdx;
-use mercurial_types::{self, Blob, BlobHash, Entry, MPath, NodeHash, Parents, RepoPath, Type};
+use mercurial_types::{self, Blob, BlobHash, BlobNode, Entry, MPath, NodeHash, Parents, RepoPath,
+                      Type};
+use stats::Timeseries;
 
-use BlobstoreEntry;
+use {BlobstoreEntry, EntrySender};
+use STATS;
+use largefiles::LargefileStores;
 
 pub(crate) fn put_entry(
-    sender: SyncSender<BlobstoreEntry>,
+    sender: EntrySender,
+    rev: u64,
     entry_hash: NodeHash,
     blob: Blob,
     parents: Parents,
@@ -35,6 +43,7 @@ where
         let nodeblob = RawNodeBlob {
             parents: parents,
             blob: BlobHash::from(bytes.as_ref()),
+            copy_from: None,
         };
         // TODO: (jsgf) T21597565 Convert blobimport to use blobrepo methods to name and create
         // blobs.
@@ -43,21 +52,70 @@ where
         let nodeblob = bincode::serialize(&nodeblob)
             .expect("bincode serialize failed");
 
-        let res1 = sender.send(BlobstoreEntry::ManifestEntry((
-            nodekey,
-            Bytes::from(nodeblob),
-        )));
-        let res2 = sender.send(BlobstoreEntry::ManifestEntry((blobkey, bytes)));
+        let res1 = sender.send(
+            BlobstoreEntry::ManifestEntry((nodekey, Bytes::from(nodeblob))),
+            rev,
+        );
+        let res2 = sender.send(BlobstoreEntry::ManifestEntry((blobkey, bytes)), rev);
 
-        res1.and(res2).map_err(Error::from)
+        res1.and(res2)
     })
 }
 
-// Copy a single manifest entry into the blobstore
+/// Put the flat manifest `mfid` as the trees of its directories, as a repo with tree manifests
+/// has them, along with the linknodes of the trees other than the root.
+pub(crate) fn put_flat_as_trees<L: Linknodes>(
+    sender: EntrySender,
+    linknodes_store: &L,
+    rev: u64,
+    mfid: NodeHash,
+    blob: &BlobNode,
+    linknode: NodeHash,
+) -> BoxFuture<(), Error> {
+    let trees = blob.as_blob()
+        .as_slice()
+        .ok_or(failure::err_msg("missing blob data"))
+        .and_then(|data| ManifestContent::parse(data).map_err(Error::from))
+        .and_then(|content| content.flat_to_trees(mfid).map_err(Error::from));
+    let trees = match trees {
+        Ok(trees) => trees,
+        Err(err) => return future::err(err).boxify(),
+    };
+
+    let puts: Vec<_> = trees
+        .into_iter()
+        .map(|(path, node, text)| {
+            if path.is_empty() {
+                // The linknode of the root is added along with the files
+                return put_entry(sender.clone(), rev, node, Blob::from(text), *blob.parents())
+                    .boxify();
+            }
+
+            let put = put_entry(sender.clone(), rev, node, Blob::from(text), Parents::None);
+            let add_linknode = linknodes_store
+                .add(RepoPath::DirectoryPath(path), &node, &linknode)
+                .or_else(|err| match err.downcast::<LinknodeErrorKind>() {
+                    // Identical directories share a tree, whose linknode is that of whichever
+                    // changeset adds it first
+                    Ok(LinknodeErrorKind::AlreadyExists { .. }) => Ok(()),
+                    Ok(err) => Err(err.into()),
+                    Err(err) => Err(err),
+                });
+            put.join(add_linknode).map(|_| ()).boxify()
+        })
+        .collect();
+    future::join_all(puts).map(|_| ()).boxify()
+}
+
+// Copy a single manifest entry into the blobstore, along with the content it points to if it's
+// a largefiles or LFS pointer.
 // TODO: #[async]
 pub(crate) fn copy_entry(
     entry: Box<Entry>,
-    sender: SyncSender<BlobstoreEntry>,
+    repopath: RepoPath,
+    sender: EntrySender,
+    rev: u64,
+    largefiles: Option<Arc<LargefileStores>>,
 ) -> impl Future<Item = (), Error = Error> + Send + 'static {
     let hash = (*entry).get_hash().into_nodehash();
 
@@ -65,7 +123,20 @@ pub(crate) fn copy_entry(
 
     blobfuture
         .join(entry.get_parents().map_err(Error::from))
-        .and_then(move |(blob, parents)| put_entry(sender, hash, blob, parents))
+        .and_then(move |(blob, parents)| -> Result<_, Error> {
+            let resolved = match (largefiles, blob.as_slice()) {
+                (Some(largefiles), Some(content)) => largefiles
+                    .resolve(&repopath, content)
+                    .with_context(|_| format!("cannot resolve pointer {}", repopath))?,
+                _ => None,
+            };
+            if let Some(resolved) = resolved {
+                STATS::largefiles.add_value(1);
+                sender.send(BlobstoreEntry::ManifestEntry(resolved), rev)?;
+            }
+            Ok((sender, blob, parents))
+        })
+        .and_then(move |(sender, blob, parents)| put_entry(sender, rev, hash, blob, parents))
 }
 
 pub(crate) fn get_entry_stream(
@@ -87,8 +158,9 @@ pub(crate) fn get_entry_stream(
         .map(|e| e.linkrev)
         .map_err(|e| {
             e.context(format_err!(
-                "cannot get linkrev of {}",
-                entry.get_hash().into_nodehash()
+                "cannot get linkrev of {} at {}",
+                entry.get_hash().into_nodehash(),
+                repopath
             )).into()
         });
 
@@ -0,0 +1,111 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Periodic logging of blobimport progress.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use failure::Result;
+use slog::Logger;
+
+/// Counters updated by the converting and io threads, read by the reporting thread.
+#[derive(Default)]
+pub(crate) struct Progress {
+    // Number of changesets which will be imported, or 0 if not known yet
+    total: AtomicUsize,
+    changesets: AtomicUsize,
+    bytes: AtomicUsize,
+    // Entries sent to the io thread, and entries it's done with. The difference is the number of
+    // entries queued in the channel or in flight to the blobstore.
+    sent: AtomicUsize,
+    done: AtomicUsize,
+    finished: AtomicBool,
+}
+
+impl Progress {
+    pub fn set_total(&self, total: u64) {
+        self.total.store(total as usize, Ordering::Relaxed);
+    }
+
+    pub fn changeset_converted(&self) {
+        self.changesets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn entry_sent(&self) {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record that the io thread is done with an entry, having written `bytes` bytes of it.
+    pub fn entry_done(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.done.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Stop the reporting thread, if any.
+    pub fn finish(&self) {
+        self.finished.store(true, Ordering::Relaxed);
+    }
+
+    fn report(&self, logger: &Logger, elapsed: Duration) {
+        let changesets = self.changesets.load(Ordering::Relaxed) as u64;
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let done = self.done.load(Ordering::Relaxed);
+        let queued = self.sent.load(Ordering::Relaxed).saturating_sub(done);
+
+        let secs = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        let rate = if secs > 0.0 {
+            changesets as f64 / secs
+        } else {
+            0.0
+        };
+        let total = self.total.load(Ordering::Relaxed) as u64;
+        let eta = if total > 0 && rate > 0.0 {
+            let remaining = total.saturating_sub(changesets) as f64 / rate;
+            format!("{}s", remaining as u64)
+        } else {
+            "unknown".to_string()
+        };
+
+        info!(
+            logger,
+            "progress: {}/{} changesets, {:.1} changesets/sec, {} bytes written, \
+             {} entries queued, ETA {}",
+            changesets,
+            total,
+            rate,
+            bytes,
+            queued,
+            eta
+        );
+    }
+}
+
+/// Start a thread logging `progress` every `interval` until `Progress::finish` is called.
+pub(crate) fn start_reporting(
+    progress: Arc<Progress>,
+    logger: Logger,
+    interval: Duration,
+) -> Result<()> {
+    let start = Instant::now();
+    thread::Builder::new()
+        .name("progress".to_owned())
+        .spawn(move || {
+            // Sleep in short steps so that the thread stops soon after the import is finished
+            let step = Duration::from_millis(100).min(interval);
+            let mut last_report = Instant::now();
+            while !progress.finished.load(Ordering::Relaxed) {
+                thread::sleep(step);
+                if last_report.elapsed() >= interval {
+                    progress.report(&logger, start.elapsed());
+                    last_report = Instant::now();
+                }
+            }
+        })?; // thread detached
+    Ok(())
+}
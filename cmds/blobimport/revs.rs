@@ -0,0 +1,100 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Selection of the slice of history to import.
+//!
+//! Revisions are selected by revision number, so `--from-rev A --to-rev B` imports every
+//! changeset numbered from A to B inclusive, like Mercurial's `A:B`. This is what makes it easy to
+//! split a huge import between several machines, but note that the selected changesets' ancestors
+//! aren't imported unless they're also in the range.
+
+use std::str::FromStr;
+
+use failure::Result;
+use futures::Future;
+use slog::Logger;
+
+use mercurial::RevlogRepo;
+use mercurial_types::NodeHash;
+
+/// Resolve `spec` to a revision number. `spec` can be a revision number, a full changeset hash,
+/// `tip` or a bookmark name, tried in that order.
+pub(crate) fn resolve_rev(repo: &RevlogRepo, spec: &str) -> Result<u64> {
+    let changelog = repo.get_changelog();
+    if changelog.is_empty() {
+        bail_msg!("cannot resolve {}: repo is empty", spec);
+    }
+
+    if spec == "tip" {
+        return Ok(changelog.len() as u64 - 1);
+    }
+
+    if let Ok(rev) = u64::from_str(spec) {
+        if rev >= changelog.len() as u64 {
+            bail_msg!("revision {} out of range, repo has {} revisions", rev, changelog.len());
+        }
+        return Ok(rev);
+    }
+
+    if let Ok(nodeid) = NodeHash::from_str(spec) {
+        if let Ok(idx) = changelog.get_idx_by_nodeid(&nodeid) {
+            return Ok(idx.into());
+        }
+    }
+
+    match repo.get_bookmark_value(&spec).wait()? {
+        Some((csid, _)) => {
+            let idx = changelog.get_idx_by_nodeid(&csid.into_nodehash())?;
+            Ok(idx.into())
+        }
+        None => bail_msg!("unknown revision {}", spec),
+    }
+}
+
+/// Work out how many revisions to skip and to import, given either `--skip`/`--commits-limit`
+/// or `--from-rev`/`--to-rev`.
+pub(crate) fn select_revs(
+    repo: &RevlogRepo,
+    skip: Option<u64>,
+    commits_limit: Option<u64>,
+    from_rev: Option<String>,
+    to_rev: Option<String>,
+    logger: &Logger,
+) -> Result<(Option<u64>, Option<u64>)> {
+    if from_rev.is_none() && to_rev.is_none() {
+        return Ok((skip, commits_limit));
+    }
+
+    let from = match from_rev {
+        Some(spec) => Some(resolve_rev(repo, &spec)?),
+        None => skip,
+    };
+    let to = match to_rev {
+        Some(spec) => Some(resolve_rev(repo, &spec)?),
+        None => None,
+    };
+    let (skip, limit) = range_to_skip_limit(from, to)?;
+    info!(
+        logger,
+        "Selected revisions {}:{}",
+        skip.unwrap_or(0),
+        to.map(|to| to.to_string()).unwrap_or_default()
+    );
+    Ok((skip, limit.or(commits_limit)))
+}
+
+/// Turn a `[from, to]` revision range into the number of revisions to skip and to import.
+fn range_to_skip_limit(
+    from: Option<u64>,
+    to: Option<u64>,
+) -> Result<(Option<u64>, Option<u64>)> {
+    let from = from.unwrap_or(0);
+    match to {
+        Some(to) if to < from => bail_msg!("--to-rev {} is before --from-rev {}", to, from),
+        Some(to) => Ok((Some(from), Some(to - from + 1))),
+        None => Ok((Some(from), None)),
+    }
+}
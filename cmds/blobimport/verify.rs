@@ -0,0 +1,240 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Cross-checking of an imported blobstore against the revlog repo it was imported from.
+//!
+//! For every selected changeset, the changeset blob and the blobs of every manifest and file
+//! entry it introduced are read back from the blobstore, and their Mercurial node hashes are
+//! recomputed from the stored contents and parents.
+
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, Future, Stream};
+use slog::Logger;
+use tokio_core::reactor::Core;
+
+use blobrepo::{fetch_content, BlobChangeset, RawNodeBlob};
+use blobstore::Blobstore;
+use failure::{Error, Result};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use mercurial::{self, RevlogManifest, RevlogRepo};
+use mercurial::changeset::serialize_cs;
+use mercurial_types::{BlobHash, BlobNode, Changeset, MPath, NodeHash};
+use mercurial_types::nodehash::{ChangesetId, EntryId};
+
+use manifest;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "Verification found {} problems", _0)]
+    VerifyFailed(usize),
+}
+
+/// Something wrong with the blobstore. These are reported, but don't stop the verification.
+#[derive(Debug, Fail)]
+enum Problem {
+    #[fail(display = "{} is missing", _0)]
+    Missing(String),
+    #[fail(display = "{} failed to read: {}", _0, _1)]
+    ReadFailed(String, Error),
+    #[fail(display = "{} is corrupt: {}", _0, _1)]
+    Corrupt(String, Error),
+    #[fail(display = "{} has the wrong hash {:?}", _0, _1)]
+    HashMismatch(String, Option<NodeHash>),
+}
+
+pub(crate) struct VerifyContext {
+    pub repo: RevlogRepo,
+    pub blobstore: Arc<Blobstore>,
+    pub core: Core,
+    pub logger: Logger,
+    pub skip: Option<u64>,
+    pub commits_limit: Option<u64>,
+    /// Max number of changesets, and of entries per changeset, verified at once.
+    pub concurrency: usize,
+}
+
+impl VerifyContext {
+    pub fn verify(self) -> Result<()> {
+        let mut core = self.core;
+        let repo = self.repo;
+        let blobstore = self.blobstore;
+        let logger = self.logger;
+        let concurrency = self.concurrency;
+
+        let changesets: BoxStream<NodeHash, mercurial::Error> = if let Some(skip) = self.skip {
+            repo.changesets().skip(skip).boxify()
+        } else {
+            repo.changesets().boxify()
+        };
+        let changesets: BoxStream<NodeHash, mercurial::Error> =
+            if let Some(limit) = self.commits_limit {
+                changesets.take(limit).boxify()
+            } else {
+                changesets
+            };
+
+        let verify = changesets
+            .map(move |csid| {
+                verify_changeset(
+                    repo.clone(),
+                    blobstore.clone(),
+                    concurrency,
+                    ChangesetId::new(csid),
+                ).map(move |problems| (csid, problems))
+            })
+            .buffer_unordered(concurrency)
+            .fold((0, 0), |(checked, failed), (csid, problems)| {
+                for problem in &problems {
+                    error!(logger, "changeset {}: {}", csid, problem);
+                }
+                Ok::<_, Error>((checked + 1, failed + problems.len()))
+            });
+
+        let (checked, failed) = core.run(verify)?;
+        info!(
+            logger,
+            "verified {} changesets, found {} problems", checked, failed
+        );
+        if failed > 0 {
+            Err(ErrorKind::VerifyFailed(failed).into())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Verify a changeset and all the entries it introduced, returning the problems found.
+fn verify_changeset(
+    repo: RevlogRepo,
+    blobstore: Arc<Blobstore>,
+    concurrency: usize,
+    csid: ChangesetId,
+) -> BoxFuture<Vec<Problem>, Error> {
+    let key = format!("changeset {}", csid);
+    let changeset = BlobChangeset::load(&blobstore, &csid).then(move |res| {
+        let problem = match res {
+            Err(err) => Some(Problem::ReadFailed(key, err)),
+            Ok(None) => Some(Problem::Missing(key)),
+            Ok(Some(cs)) => {
+                let mut data = Vec::new();
+                match serialize_cs(&cs, &mut data) {
+                    Err(err) => Some(Problem::Corrupt(key, err)),
+                    Ok(()) => {
+                        let (p1, p2) = cs.parents().get_nodes();
+                        let node = BlobNode::new(Bytes::from(data), p1, p2);
+                        check_hash(key, csid.into_nodehash(), node)
+                    }
+                }
+            }
+        };
+        Ok::<_, Error>(problem)
+    });
+
+    let entryid = EntryId::new(csid.into_nodehash());
+    let entries = repo.get_changeset_by_changesetid(&csid)
+        .join(repo.get_changelog_revlog_entry_by_id(&entryid))
+        .from_err()
+        .and_then(move |(cs, entry)| {
+            let mfid = *cs.manifestid();
+            let mfid = mfid.into_nodehash();
+            let linkrev = entry.linkrev;
+            let root = verify_node(blobstore.clone(), mfid);
+
+            let entries = repo.get_manifest_blob_by_nodeid(&mfid)
+                .from_err()
+                .and_then({
+                    let repo = repo.clone();
+                    move |blob| RevlogManifest::new(repo, blob).map_err(Error::from)
+                })
+                .and_then(move |mf| {
+                    mf.list()
+                        .map_err(Error::from)
+                        .map(move |entry| {
+                            manifest::get_entry_stream(
+                                entry,
+                                repo.clone(),
+                                linkrev,
+                                MPath::empty(),
+                            )
+                        })
+                        .flatten()
+                        .map(move |(entry, _)| {
+                            verify_node(blobstore.clone(), entry.get_hash().into_nodehash())
+                        })
+                        .buffer_unordered(concurrency)
+                        .filter_map(|problem| problem)
+                        .collect()
+                });
+
+            root.join(entries).map(|(root, mut problems)| {
+                problems.extend(root);
+                problems
+            })
+        });
+
+    changeset
+        .join(entries)
+        .map(|(changeset, mut problems)| {
+            problems.extend(changeset);
+            problems
+        })
+        .boxify()
+}
+
+/// Verify the node blob of a manifest or file entry, and the content blob it refers to.
+fn verify_node(blobstore: Arc<Blobstore>, hash: NodeHash) -> BoxFuture<Option<Problem>, Error> {
+    let nodekey = format!("node-{}.bincode", hash);
+
+    blobstore
+        .get(nodekey.clone())
+        .then(move |res| -> BoxFuture<Option<Problem>, Error> {
+            let bytes = match res {
+                Err(err) => return future::ok(Some(Problem::ReadFailed(nodekey, err))).boxify(),
+                Ok(None) => return future::ok(Some(Problem::Missing(nodekey))).boxify(),
+                Ok(Some(bytes)) => bytes,
+            };
+            let nodeblob = match RawNodeBlob::parse(bytes.as_ref()) {
+                Err(err) => return future::ok(Some(Problem::Corrupt(nodekey, err))).boxify(),
+                Ok(nodeblob) => nodeblob,
+            };
+
+            // The content may be stored as a delta, which this reconstructs
+            let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
+            fetch_content(&blobstore, nodeblob.blob)
+                .then(move |res| {
+                    let problem = match res {
+                        Err(err) => Some(Problem::ReadFailed(blobkey, err)),
+                        Ok(None) => Some(Problem::Missing(blobkey)),
+                        Ok(Some(content)) => {
+                            if BlobHash::from(content.as_ref()) != nodeblob.blob {
+                                Some(Problem::Corrupt(
+                                    blobkey,
+                                    format_err!("content doesn't match its hash"),
+                                ))
+                            } else {
+                                let (p1, p2) = nodeblob.parents.get_nodes();
+                                let text = nodeblob.raw_text(content);
+                                check_hash(nodekey, hash, BlobNode::new(text, p1, p2))
+                            }
+                        }
+                    };
+                    Ok(problem)
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+fn check_hash(key: String, expected: NodeHash, node: BlobNode) -> Option<Problem> {
+    let actual = node.nodeid();
+    if actual == Some(expected) {
+        None
+    } else {
+        Some(Problem::HashMismatch(key, actual))
+    }
+}
@@ -0,0 +1,325 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Inspection of the raw contents of a blobstore, for debugging.
+//!
+//! Blobstores are named `files:PATH`, `rocksdb:PATH`, `sqlite:PATH` or `manifold:BUCKET`, where
+//! PATH is the directory of the blobs (the `blobs` directory of a RepoCtx, or the sqlite file).
+//! The subcommands are:
+//!
+//! - `cat KEY`: print a blob, decoded as a changeset, node, manifest or file with `--type`
+//! - `stat KEY`: print whether a blob is present, and its size
+//! - `ls [PREFIX]`: list the keys with a prefix, for the blobstores which can be enumerated
+//! - `cp --to STORE [KEY]...`: copy blobs to another blobstore, the keys read from stdin if
+//!   none are given, so that the output of `ls` can be copied
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate fileblob;
+extern crate futures_ext;
+extern crate manifoldblob;
+extern crate mercurial;
+extern crate mercurial_types;
+extern crate rocksblob;
+extern crate sqliteblob;
+
+use std::io::{self, BufRead, Write};
+use std::sync::Arc;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::{Error, Result, ResultExt};
+use futures::{stream, Future, Stream};
+use tokio_core::reactor::{Core, Remote};
+
+use blobrepo::{BlobChangeset, BlobEntry, BlobManifest, RawNodeBlob};
+use blobstore::{Blobstore, Enumerable};
+use fileblob::Fileblob;
+use futures_ext::{BoxStream, FutureExt};
+use manifoldblob::ManifoldBlob;
+use mercurial::changeset::serialize_cs;
+use mercurial_types::{ChangesetId, Content, Entry, Manifest, ManifestId, NodeHash, Type};
+use rocksblob::Rocksblob;
+use sqliteblob::Sqliteblob;
+
+/// A blobstore, and the same blobstore as `Enumerable` if it is.
+struct Store {
+    blobstore: Arc<Blobstore>,
+    enumerable: Option<Arc<Enumerable>>,
+}
+
+impl Store {
+    fn open(spec: &str, remote: &Remote) -> Result<Self> {
+        let (kind, location) = match spec.find(':') {
+            Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+            None => bail_msg!("blobstore {} should be TYPE:LOCATION", spec),
+        };
+        let store = match kind {
+            "files" => {
+                let blobstore = Fileblob::open(location)
+                    .map_err(Error::from)
+                    .context("Failed to open file blob store")?;
+                Self::enumerable(Arc::new(blobstore))
+            }
+            "rocksdb" => {
+                let blobstore = Rocksblob::open(location)
+                    .map_err(Error::from)
+                    .context("Failed to open rocksdb blob store")?;
+                Self::enumerable(Arc::new(blobstore))
+            }
+            "sqlite" => {
+                let blobstore =
+                    Sqliteblob::open(location).context("Failed to open sqlite blob store")?;
+                Self::enumerable(Arc::new(blobstore))
+            }
+            "manifold" => Store {
+                blobstore: Arc::new(ManifoldBlob::new_may_panic(location.to_string(), remote)),
+                enumerable: None,
+            },
+            bad => bail_msg!("unexpected blobstore type {}", bad),
+        };
+        Ok(store)
+    }
+
+    fn enumerable<B: Enumerable + 'static>(blobstore: Arc<B>) -> Self {
+        Store {
+            blobstore: blobstore.clone(),
+            enumerable: Some(blobstore),
+        }
+    }
+
+    fn enumerate(&self) -> Result<BoxStream<String, Error>> {
+        match self.enumerable {
+            Some(ref enumerable) => Ok(enumerable.enumerate()),
+            None => bail_msg!("this blobstore can't list its keys"),
+        }
+    }
+}
+
+/// The node a key such as `changeset-<hash>.bincode` or `node-<hash>.bincode` is the blob of.
+/// The hash alone works too.
+fn parse_node_key(key: &str, prefix: &str) -> Result<NodeHash> {
+    let hash = if key.starts_with(prefix) && key.ends_with(".bincode") {
+        &key[prefix.len()..key.len() - ".bincode".len()]
+    } else {
+        key
+    };
+    hash.parse()
+        .map_err(|_| format_err!("{} isn't the key of a node", key))
+}
+
+fn cat(core: &mut Core, store: &Store, key: &str, ty: &str) -> Result<()> {
+    let blobstore = &store.blobstore;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    match ty {
+        "raw" => {
+            let blob = core.run(blobstore.get(key.to_string()))?;
+            let blob = blob.ok_or_else(|| format_err!("{} is missing", key))?;
+            out.write_all(&blob)?;
+        }
+        "changeset" => {
+            let csid = ChangesetId::new(parse_node_key(key, "changeset-")?);
+            let cs = core.run(BlobChangeset::load(blobstore, &csid))?;
+            let cs = cs.ok_or_else(|| format_err!("changeset {} is missing", csid))?;
+            serialize_cs(&cs, &mut out)?;
+        }
+        "node" => {
+            let blob = core.run(blobstore.get(key.to_string()))?;
+            let blob = blob.ok_or_else(|| format_err!("{} is missing", key))?;
+            let node = RawNodeBlob::parse(blob.as_ref())?;
+            writeln!(out, "{:#?}", node)?;
+        }
+        "manifest" => {
+            let mfid = ManifestId::new(parse_node_key(key, "node-")?);
+            let manifest = core.run(BlobManifest::load(blobstore, &mfid))?;
+            let manifest = manifest.ok_or_else(|| format_err!("manifest {} is missing", mfid))?;
+            for entry in core.run(manifest.list().collect())? {
+                let name = entry
+                    .get_name()
+                    .as_ref()
+                    .map(|name| String::from_utf8_lossy(name.as_bytes()).into_owned())
+                    .unwrap_or_default();
+                writeln!(
+                    out,
+                    "{}{}\t{}",
+                    entry.get_hash().into_nodehash(),
+                    entry.get_type(),
+                    name
+                )?;
+            }
+        }
+        "file" => {
+            let node = parse_node_key(key, "node-")?;
+            let entry = BlobEntry::new(blobstore.clone(), None, node, Type::File)?;
+            match core.run(entry.get_content())? {
+                Content::File(blob) | Content::Executable(blob) => {
+                    out.write_all(blob.as_slice().unwrap_or_default())?
+                }
+                _ => bail_msg!("{} isn't a file", node),
+            }
+        }
+        bad => bail_msg!("unexpected blob type {}", bad),
+    }
+    Ok(())
+}
+
+fn stat(core: &mut Core, store: &Store, key: &str) -> Result<()> {
+    match core.run(store.blobstore.get(key.to_string()))? {
+        Some(blob) => println!("{}: {} bytes", key, blob.len()),
+        None => println!("{}: missing", key),
+    }
+    Ok(())
+}
+
+fn ls(core: &mut Core, store: &Store, prefix: &str) -> Result<()> {
+    let prefix = prefix.to_string();
+    let keys = store
+        .enumerate()?
+        .filter(move |key| key.starts_with(&prefix))
+        .for_each(|key| {
+            println!("{}", key);
+            Ok(())
+        });
+    core.run(keys)
+}
+
+/// Copy `keys` from `from` to `to`, up to `concurrency` at once. Keys missing from `from` are
+/// reported, but don't stop the copy.
+fn cp(
+    core: &mut Core,
+    from: &Store,
+    to: &Store,
+    keys: Vec<String>,
+    concurrency: usize,
+) -> Result<()> {
+    let from = from.blobstore.clone();
+    let to = to.blobstore.clone();
+    let copy = stream::iter_ok(keys)
+        .map(move |key| {
+            let to = to.clone();
+            from.get(key.clone()).and_then(move |blob| match blob {
+                Some(blob) => to.put(key, blob).map(|()| None).boxify(),
+                None => futures::future::ok(Some(key)).boxify(),
+            })
+        })
+        .buffer_unordered(concurrency)
+        .fold((0, 0), |(copied, missing), res| {
+            let counts = match res {
+                Some(key) => {
+                    eprintln!("{} is missing", key);
+                    (copied, missing + 1)
+                }
+                None => (copied + 1, missing),
+            };
+            Ok::<_, Error>(counts)
+        });
+
+    let (copied, missing) = core.run(copy)?;
+    println!("Copied {} blobs, {} missing", copied, missing);
+    if missing > 0 {
+        bail_msg!("{} blobs were missing", missing);
+    }
+    Ok(())
+}
+
+fn read_keys() -> Result<Vec<String>> {
+    let stdin = io::stdin();
+    let mut keys = Vec::new();
+    for line in stdin.lock().lines() {
+        let line = line?;
+        let key = line.trim();
+        if !key.is_empty() {
+            keys.push(key.to_string());
+        }
+    }
+    Ok(keys)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("blobstore admin")
+        .version("0.0.0")
+        .about("inspect the blobs of a blobstore")
+        .arg(Arg::from_usage(
+            "--blobstore <STORE> 'files:PATH, rocksdb:PATH, sqlite:PATH or manifold:BUCKET'",
+        ))
+        .subcommand(
+            SubCommand::with_name("cat")
+                .about("print a blob")
+                .arg(Arg::from_usage("<KEY> 'key of the blob'"))
+                .arg(
+                    Arg::from_usage("--type [TYPE] 'how to decode the blob'")
+                        .possible_values(&["raw", "changeset", "node", "manifest", "file"])
+                        .default_value("raw"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("stat")
+                .about("print whether a blob is present, and its size")
+                .arg(Arg::from_usage("<KEY> 'key of the blob'")),
+        )
+        .subcommand(
+            SubCommand::with_name("ls")
+                .about("list the keys of the blobstore")
+                .arg(Arg::from_usage("[PREFIX] 'only list the keys with this prefix'")),
+        )
+        .subcommand(
+            SubCommand::with_name("cp")
+                .about("copy blobs to another blobstore")
+                .arg(Arg::from_usage("--to <STORE> 'blobstore to copy to'"))
+                .arg(Arg::from_usage(
+                    "--concurrency [LIMIT] 'max number of blobs copied at once. Default: 100'",
+                ))
+                .arg(Arg::from_usage("[KEY]... 'keys to copy. Default: read from stdin'")),
+        )
+}
+
+fn run<'a>(matches: ArgMatches<'a>) -> Result<()> {
+    let mut core = Core::new()?;
+    let remote = core.remote();
+    let store = Store::open(matches.value_of("blobstore").unwrap(), &remote)?;
+
+    match matches.subcommand() {
+        ("cat", Some(sub)) => cat(
+            &mut core,
+            &store,
+            sub.value_of("KEY").unwrap(),
+            sub.value_of("type").unwrap(),
+        ),
+        ("stat", Some(sub)) => stat(&mut core, &store, sub.value_of("KEY").unwrap()),
+        ("ls", Some(sub)) => ls(&mut core, &store, sub.value_of("PREFIX").unwrap_or("")),
+        ("cp", Some(sub)) => {
+            let to = Store::open(sub.value_of("to").unwrap(), &remote)?;
+            let concurrency: usize = sub.value_of("concurrency")
+                .map(|limit| limit.parse().expect("concurrency must be positive integer"))
+                .unwrap_or(100);
+            let keys = match sub.values_of("KEY") {
+                Some(keys) => keys.map(ToOwned::to_owned).collect(),
+                None => read_keys()?,
+            };
+            cp(&mut core, &store, &to, keys, concurrency)
+        }
+        _ => bail_msg!("unexpected or missing subcommand"),
+    }
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+    if let Err(err) = run(matches) {
+        eprintln!("Blobstore admin failed: {}", err);
+        for cause in err.causes().skip(1) {
+            eprintln!("  caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,414 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Creation of clone bundles: a bundle of the whole repo written to static storage, and the
+//! clonebundles manifest pointing clients at it.
+//!
+//! The manifest is the file the repo's `clonebundles_manifest` config option refers to. Clients
+//! which support clone bundles download the bundle on their first clone, and only pull what was
+//! pushed since it was created from the server.
+
+#![deny(warnings)]
+
+extern crate async_compression;
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate futures_ext;
+extern crate mercurial;
+extern crate mercurial_bundles;
+extern crate mercurial_types;
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use async_compression::{CompressorType, FlateCompression};
+use bytes::Bytes;
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{future, stream, Future, Stream};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::{BlobChangeset, BlobRepo};
+use futures_ext::{BoxFuture, FutureExt};
+use futures_ext::io::ChannelWriter;
+use mercurial_bundles::{parts, Bundle2EncodeBuilder};
+use mercurial_bundles::changegroup::CgVersion;
+use mercurial_types::{BlobNode, Changeset, ChangesetId, MPath, NodeHash, RepositoryId, Type,
+                      NULL_HASH};
+use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
+
+/// The bundles are HG20 bundles with a version 02 changegroup, compressed with gzip.
+const BUNDLESPEC: &str = "gzip-v2";
+const CHUNKS_IN_FLIGHT: usize = 64;
+
+/// What a changegroup sends along with a changeset: its root manifest, and the files it changes
+/// relative to its first parent.
+type ChangesetContents = (BlobNode, Vec<(MPath, NodeHash, BlobNode)>);
+
+fn run_create_clonebundle(
+    repo: Arc<BlobRepo>,
+    output: PathBuf,
+    base_url: &str,
+    manifest: PathBuf,
+    logger: &Logger,
+    concurrency: usize,
+) -> Result<()> {
+    let mut core = Core::new()?;
+
+    let mut heads = core.run(repo.get_heads().collect())?;
+    heads.sort();
+    info!(logger, "Loading changesets reachable from {} heads", heads.len());
+    let changesets = core.run(load_changesets(repo.clone(), heads.clone(), concurrency))?;
+    let order = topological_order(&changesets, &heads);
+    let tip = match order.last() {
+        Some(tip) => *tip,
+        None => bail_msg!("repo is empty, there's nothing to bundle"),
+    };
+
+    // Bundles are named after the last changeset in them, so a new bundle never replaces one
+    // which clients may be downloading
+    let name = format!("full-{}.hg", tip);
+    let path = output.join(&name);
+    info!(logger, "Bundling {} changesets into {}", order.len(), path.display());
+    let contents = core.run(load_contents(repo, &order, &changesets, concurrency))?;
+    write_atomically(&path, |file| {
+        core.run(write_bundle(file, &order, &changesets, contents))
+    })?;
+
+    let url = format!("{}/{}", base_url.trim_right_matches('/'), name);
+    write_atomically(&manifest, |file| {
+        writeln!(file, "{} BUNDLESPEC={}", url, BUNDLESPEC)?;
+        Ok(())
+    })?;
+
+    info!(logger, "Clone bundle {} is listed in {}", url, manifest.display());
+    Ok(())
+}
+
+/// Load every changeset reachable from `heads`.
+fn load_changesets(
+    repo: Arc<BlobRepo>,
+    heads: Vec<NodeHash>,
+    concurrency: usize,
+) -> BoxFuture<HashMap<NodeHash, BlobChangeset>, Error> {
+    // Walk the history one generation at a time
+    future::loop_fn(
+        (heads, HashMap::new()),
+        move |(frontier, mut loaded): (Vec<NodeHash>, HashMap<_, _>)| {
+            stream::iter_ok(frontier)
+                .map({
+                    let repo = repo.clone();
+                    move |node| {
+                        repo.get_changeset_by_changesetid(&ChangesetId::new(node))
+                            .map(move |cs| (node, cs))
+                    }
+                })
+                .buffer_unordered(concurrency)
+                .collect()
+                .map(move |batch| {
+                    let mut frontier = HashSet::new();
+                    for (node, cs) in batch {
+                        for parent in cs.parents() {
+                            if !loaded.contains_key(&parent) {
+                                frontier.insert(parent);
+                            }
+                        }
+                        loaded.insert(node, cs);
+                    }
+                    let frontier: Vec<_> = frontier
+                        .into_iter()
+                        .filter(|node| !loaded.contains_key(node))
+                        .collect();
+                    if frontier.is_empty() {
+                        future::Loop::Break(loaded)
+                    } else {
+                        future::Loop::Continue((frontier, loaded))
+                    }
+                })
+        },
+    ).boxify()
+}
+
+/// Order changesets so that parents always come before their children.
+fn topological_order(
+    changesets: &HashMap<NodeHash, BlobChangeset>,
+    heads: &[NodeHash],
+) -> Vec<NodeHash> {
+    let mut order = Vec::with_capacity(changesets.len());
+    let mut visited = HashSet::new();
+    for head in heads {
+        // Depth first, emitting a changeset once all its parents have been emitted
+        let mut stack = vec![(*head, false)];
+        while let Some((node, expanded)) = stack.pop() {
+            if expanded {
+                order.push(node);
+                continue;
+            }
+            if !visited.insert(node) {
+                continue;
+            }
+            stack.push((node, true));
+            let parents: Vec<_> = changesets[&node].parents().into_iter().collect();
+            for parent in parents.into_iter().rev() {
+                if !visited.contains(&parent) {
+                    stack.push((parent, false));
+                }
+            }
+        }
+    }
+    order
+}
+
+/// Load the manifests and files of the changesets in `order`.
+fn load_contents(
+    repo: Arc<BlobRepo>,
+    order: &[NodeHash],
+    changesets: &HashMap<NodeHash, BlobChangeset>,
+    concurrency: usize,
+) -> BoxFuture<Vec<ChangesetContents>, Error> {
+    let changesets: Vec<_> = order.iter().map(|node| changesets[node].clone()).collect();
+    stream::iter_ok(changesets)
+        .map(move |cs| load_changeset_contents(repo.clone(), cs))
+        .buffered(concurrency)
+        .collect()
+        .boxify()
+}
+
+fn load_changeset_contents(
+    repo: Arc<BlobRepo>,
+    cs: BlobChangeset,
+) -> BoxFuture<ChangesetContents, Error> {
+    let mfid = *cs.manifestid();
+
+    let entry = repo.get_root_entry(&mfid);
+    let manifest = entry
+        .get_raw_content()
+        .join(entry.get_parents())
+        .map(|(blob, parents)| {
+            let (p1, p2) = parents.get_nodes();
+            BlobNode::new(blob, p1, p2)
+        });
+
+    let basemfid = match cs.parents().get_nodes() {
+        (Some(p1), _) => repo.get_changeset_by_changesetid(&ChangesetId::new(*p1))
+            .map(|p1| p1.manifestid().into_nodehash())
+            .boxify(),
+        (None, _) => future::ok(NULL_HASH).boxify(),
+    };
+    let files = basemfid
+        .and_then({
+            let repo = repo.clone();
+            move |basemfid| {
+                repo.get_manifest_by_nodeid(&mfid.into_nodehash())
+                    .join(repo.get_manifest_by_nodeid(&basemfid))
+            }
+        })
+        .map(|(mf, basemf)| changed_entry_stream(&mf, &basemf, MPath::empty()))
+        .flatten_stream()
+        .filter_map(|entry_status| match entry_status.status {
+            EntryStatus::Added(entry) | EntryStatus::Modified(entry, _) => {
+                if entry.get_type() == Type::Tree {
+                    None
+                } else {
+                    Some((entry_status.path.join_element(entry.get_name()), entry))
+                }
+            }
+            EntryStatus::Deleted(..) => None,
+        })
+        .and_then(|(path, entry)| {
+            let filenode = entry.get_hash().into_nodehash();
+            entry
+                .get_raw_content()
+                .join(entry.get_parents())
+                .map(move |(blob, parents)| {
+                    let (p1, p2) = parents.get_nodes();
+                    (path, filenode, BlobNode::new(blob, p1, p2))
+                })
+        })
+        .collect();
+
+    manifest.join(files).boxify()
+}
+
+/// Encode the bundle, writing it out as it's encoded.
+fn write_bundle<'a>(
+    file: &'a mut fs::File,
+    order: &[NodeHash],
+    changesets: &HashMap<NodeHash, BlobChangeset>,
+    contents: Vec<ChangesetContents>,
+) -> Box<Future<Item = (), Error = Error> + 'a> {
+    let mut changelogentries = Vec::with_capacity(order.len());
+    for node in order {
+        let cs = &changesets[node];
+        let mut v = Vec::new();
+        if let Err(err) = mercurial::changeset::serialize_cs(cs, &mut v) {
+            return Box::new(future::err(err.into()));
+        }
+        let parents = cs.parents().get_nodes();
+        changelogentries.push(BlobNode::new(Bytes::from(v), parents.0, parents.1));
+    }
+
+    // Filelogs are grouped by path. A filenode appears in several changesets after a merge, and
+    // it's sent with the first one, which introduced it.
+    let mut manifestentries = Vec::with_capacity(contents.len());
+    let mut filelogs: BTreeMap<MPath, Vec<(BlobNode, NodeHash)>> = BTreeMap::new();
+    let mut seen_filenodes = HashSet::new();
+    for (linknode, (manifest, files)) in order.iter().cloned().zip(contents) {
+        manifestentries.push((manifest, linknode));
+        for (path, filenode, blobnode) in files {
+            if seen_filenodes.insert((path.clone(), filenode)) {
+                filelogs
+                    .entry(path)
+                    .or_insert_with(Vec::new)
+                    .push((blobnode, linknode));
+            }
+        }
+    }
+
+    let part = match parts::changegroup_part(
+        CgVersion::Cg2Version,
+        stream::iter_ok(changelogentries),
+        stream::iter_ok(manifestentries),
+        stream::empty::<(MPath, Vec<(BlobNode, NodeHash)>), Error>(),
+        stream::iter_ok(filelogs),
+    ) {
+        Ok(part) => part,
+        Err(err) => return Box::new(future::err(err)),
+    };
+
+    let (writer, chunks) = ChannelWriter::new(CHUNKS_IN_FLIGHT);
+    let mut bundle = Bundle2EncodeBuilder::new(writer);
+    bundle.set_compressor_type(CompressorType::Gzip(FlateCompression::default()));
+    bundle.add_part(part);
+
+    // The writer is dropped once the bundle is encoded, which ends the chunks
+    let encode = bundle.build().map(|_writer| ());
+    let write = chunks
+        .map_err(|()| format_err!("bundle chunks channel failed"))
+        .for_each(move |chunk| file.write_all(&chunk).map_err(Error::from));
+    Box::new(encode.join(write).map(|_| ()))
+}
+
+/// Write `path` through a temporary file, so that readers never see it partially written.
+fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&mut fs::File) -> Result<()>,
+{
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+
+    let mut file =
+        fs::File::create(&tmp).with_context(|_| format!("cannot create {}", tmp.display()))?;
+    write(&mut file).with_context(|_| format!("cannot write {}", tmp.display()))?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+        .with_context(|_| format!("cannot rename {} to {}", tmp.display(), path.display()))?;
+    Ok(())
+}
+
+fn open_repo(
+    logger: &Logger,
+    input: &Path,
+    blobtype: &str,
+    repoid: RepositoryId,
+    blob_prefix: Option<String>,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!("repo" => format!("{}", input.display())));
+    let repo = match blobtype {
+        "files" => BlobRepo::new_files(logger, input, repoid, blob_prefix)?,
+        "rocksdb" => BlobRepo::new_rocksdb(logger, input, repoid, blob_prefix)?,
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(repo)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("clone bundle creator")
+        .version("0.0.0")
+        .about("bundle a repo for clones and list it in a clonebundles manifest")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input blobstore RepoCtx'
+            <OUTPUT>                 'directory of the static storage to write the bundle to'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'blobstore type: files or rocksdb'
+            --base-url <URL>         'URL the OUTPUT directory is served at'
+            --manifest <PATH>        'clonebundles manifest to write'
+            --repoid [ID]            'numerical id of the repo. Default: 0'
+            --blob-prefix [PREFIX]   'prefix of the blobstore keys of the repo'
+            --concurrency [LIMIT]    'max number of blobstore reads in flight. Default: 100'
+        "#,
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = PathBuf::from(matches.value_of("INPUT").unwrap());
+        let output = PathBuf::from(matches.value_of("OUTPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let base_url = matches.value_of("base-url").unwrap();
+        let manifest = PathBuf::from(matches.value_of("manifest").unwrap());
+        let repoid = matches
+            .value_of("repoid")
+            .map(|id| id.parse().expect("repoid must be an integer"))
+            .unwrap_or(0);
+        let blob_prefix = matches.value_of("blob-prefix").map(ToOwned::to_owned);
+        let concurrency: usize = matches
+            .value_of("concurrency")
+            .map(|limit| limit.parse().expect("concurrency must be positive integer"))
+            .unwrap_or(100);
+
+        let repo = open_repo(
+            root_log,
+            &input,
+            blobtype,
+            RepositoryId::new(repoid),
+            blob_prefix,
+        )?;
+        run_create_clonebundle(
+            Arc::new(repo),
+            output,
+            base_url,
+            manifest,
+            root_log,
+            concurrency,
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Creating clone bundle failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
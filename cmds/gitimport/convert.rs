@@ -0,0 +1,336 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Conversion of git commits into Mercurial changesets, manifests and filenodes.
+//!
+//! Every git commit becomes a changeset with a flat root manifest, in the same format blobimport
+//! copies out of revlogs. Filenode parents are the nodes of the same path in the parent commits,
+//! so that the history of each file is preserved. Git doesn't record copies, so none are recorded
+//! in the filenodes.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use bincode;
+use bytes::Bytes;
+use git2::{Commit, ObjectType, Oid, Repository, TreeWalkMode, TreeWalkResult};
+
+use blobrepo::{BlobChangeset, RawNodeBlob};
+use failure::{Error, Result, ResultExt};
+use mercurial::changeset::RevlogChangeset;
+use mercurial::manifest::Details;
+use mercurial::manifest::revlog::ManifestContent;
+use mercurial_types::{BlobHash, BlobNode, MPath, NodeHash, Parents, RepoPath, Time, Type};
+use mercurial_types::nodehash::{ChangesetId, EntryId, ManifestId};
+
+/// Filelog metadata marker. Content starting with it must be escaped with an empty metadata block.
+const META_MARKER: &[u8] = b"\x01\n";
+
+/// Git file modes of the tree entries which are converted.
+const MODE_FILE: i32 = 0o100644;
+const MODE_EXECUTABLE: i32 = 0o100755;
+const MODE_SYMLINK: i32 = 0o120000;
+
+pub(crate) enum BlobstoreEntry {
+    Blob((String, Bytes)),
+    Changeset(BlobChangeset),
+}
+
+impl BlobstoreEntry {
+    /// The blobstore key this entry will be saved under.
+    pub fn key(&self) -> String {
+        match *self {
+            BlobstoreEntry::Blob((ref key, _)) => key.clone(),
+            // Must match the key used by BlobChangeset::save
+            BlobstoreEntry::Changeset(ref bcs) => {
+                format!("changeset-{}.bincode", bcs.get_changeset_id())
+            }
+        }
+    }
+}
+
+/// What converting a commit produced.
+pub(crate) struct Conversion {
+    pub csid: ChangesetId,
+    pub parents: Vec<ChangesetId>,
+    /// Entries which need to be written to the blobstore
+    pub entries: Vec<BlobstoreEntry>,
+    /// The root manifest and the filenodes the changeset introduces, whose linknode it is
+    pub linknodes: Vec<(RepoPath, NodeHash)>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FileState {
+    git: Oid,
+    node: NodeHash,
+    ty: Type,
+}
+
+struct ConvertedCommit {
+    csid: ChangesetId,
+    mfid: NodeHash,
+    files: Arc<BTreeMap<MPath, FileState>>,
+}
+
+pub(crate) struct GitConverter<'a> {
+    repo: &'a Repository,
+    /// Every commit converted so far. Commits are converted parents first, so the parents of a
+    /// commit are always here by the time it's converted.
+    converted: HashMap<Oid, ConvertedCommit>,
+    skipped_submodules: usize,
+}
+
+impl<'a> GitConverter<'a> {
+    pub fn new(repo: &'a Repository) -> Self {
+        Self {
+            repo,
+            converted: HashMap::new(),
+            skipped_submodules: 0,
+        }
+    }
+
+    /// The changeset a commit was converted to, if it was converted.
+    pub fn changeset_id(&self, oid: &Oid) -> Option<ChangesetId> {
+        self.converted.get(oid).map(|converted| converted.csid)
+    }
+
+    /// Number of submodule entries left out of the manifests, as they can't be represented.
+    pub fn skipped_submodules(&self) -> usize {
+        self.skipped_submodules
+    }
+
+    /// Convert the commit `oid`.
+    pub fn convert(&mut self, oid: Oid) -> Result<Conversion> {
+        let commit = self.repo
+            .find_commit(oid)
+            .with_context(|_| format!("cannot read commit {}", oid))?;
+        if commit.parent_count() > 2 {
+            bail_msg!(
+                "commit {} has {} parents, octopus merges are not supported",
+                oid,
+                commit.parent_count()
+            );
+        }
+
+        let parents = commit
+            .parent_ids()
+            .map(|parent| match self.converted.get(&parent) {
+                Some(converted) => Ok(converted),
+                None => Err(format_err!("parent {} of {} wasn't converted", parent, oid)),
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let p1 = parents.get(0);
+        let p2 = parents.get(1);
+
+        let mut entries = Vec::new();
+        let mut linknodes = Vec::new();
+        let tree = self.tree_files(&commit)?;
+        let mut files = BTreeMap::new();
+        let mut manifest = ManifestContent::new_empty();
+        for (path, (git, ty)) in tree {
+            let p1file = p1.and_then(|p1| p1.files.get(&path));
+            let p2file = p2.and_then(|p2| p2.files.get(&path));
+            let node = match (p1file, p2file) {
+                (Some(file), _) if file.git == git => file.node,
+                (_, Some(file)) if file.git == git => file.node,
+                (p1file, p2file) => {
+                    let blob = self.repo
+                        .find_blob(git)
+                        .with_context(|_| format!("cannot read blob {} of {}", git, path))?;
+                    let content = filelog_content(blob.content());
+                    let node = put_node(
+                        &mut entries,
+                        content,
+                        p1file.map(|file| &file.node),
+                        p2file.map(|file| &file.node),
+                    )?;
+                    linknodes.push((RepoPath::FilePath(path.clone()), node));
+                    node
+                }
+            };
+            manifest
+                .files
+                .insert(path.clone(), Details::new(EntryId::new(node), ty));
+            files.insert(path, FileState { git, node, ty });
+        }
+
+        let mut manifest_data = Vec::new();
+        manifest.generate(&mut manifest_data)?;
+        let mfid = put_node(
+            &mut entries,
+            Bytes::from(manifest_data),
+            p1.map(|p1| &p1.mfid),
+            p2.map(|p2| &p2.mfid),
+        )?;
+        linknodes.push((RepoPath::root(), mfid));
+
+        let cs = RevlogChangeset::new_from_parts(
+            Parents::new(
+                p1.map(|p1| p1.csid.into_nodehash()).as_ref(),
+                p2.map(|p2| p2.csid.into_nodehash()).as_ref(),
+            ),
+            ManifestId::new(mfid),
+            user(&commit),
+            time(&commit).with_context(|_| format!("cannot convert the time of {}", oid))?,
+            extra(&commit),
+            changed_files(&files, p1.map(|p1| &*p1.files)),
+            commit.message_bytes().to_vec(),
+        );
+        let bcs = BlobChangeset::new(cs)?;
+        let csid = bcs.get_changeset_id();
+        entries.push(BlobstoreEntry::Changeset(bcs));
+        let parents = parents.iter().map(|parent| parent.csid).collect();
+
+        self.converted.insert(
+            oid,
+            ConvertedCommit {
+                csid,
+                mfid,
+                files: Arc::new(files),
+            },
+        );
+        Ok(Conversion {
+            csid,
+            parents,
+            entries,
+            linknodes,
+        })
+    }
+
+    /// List the files in the tree of `commit`, with their blob ids and types.
+    fn tree_files(&mut self, commit: &Commit) -> Result<BTreeMap<MPath, (Oid, Type)>> {
+        let tree = commit.tree()?;
+        let mut files = BTreeMap::new();
+        let mut submodules = 0;
+        let mut error = None;
+        tree.walk(TreeWalkMode::PreOrder, |root, entry| {
+            let ty = match (entry.kind(), entry.filemode()) {
+                (Some(ObjectType::Blob), MODE_FILE) => Type::File,
+                (Some(ObjectType::Blob), MODE_EXECUTABLE) => Type::Executable,
+                (Some(ObjectType::Blob), MODE_SYMLINK) => Type::Symlink,
+                (Some(ObjectType::Commit), _) => {
+                    submodules += 1;
+                    return TreeWalkResult::Skip;
+                }
+                _ => return TreeWalkResult::Ok,
+            };
+            let mut path = root.as_bytes().to_vec();
+            path.extend_from_slice(entry.name_bytes());
+            match MPath::new(&path) {
+                Ok(path) => {
+                    files.insert(path, (entry.id(), ty));
+                    TreeWalkResult::Ok
+                }
+                Err(err) => {
+                    error = Some(err.context(format!(
+                        "invalid path {}",
+                        String::from_utf8_lossy(&path)
+                    )));
+                    TreeWalkResult::Abort
+                }
+            }
+        })?;
+        if let Some(error) = error {
+            return Err(error.into());
+        }
+        self.skipped_submodules += submodules;
+        Ok(files)
+    }
+}
+
+/// Turn the content of a git blob into the content of a filelog entry.
+fn filelog_content(content: &[u8]) -> Bytes {
+    if content.starts_with(META_MARKER) {
+        let mut escaped = Vec::with_capacity(content.len() + META_MARKER.len() * 2);
+        escaped.extend_from_slice(META_MARKER);
+        escaped.extend_from_slice(META_MARKER);
+        escaped.extend_from_slice(content);
+        Bytes::from(escaped)
+    } else {
+        Bytes::from(content)
+    }
+}
+
+/// Add the node and content blobs of a file or manifest to `entries`, returning its node hash.
+fn put_node(
+    entries: &mut Vec<BlobstoreEntry>,
+    content: Bytes,
+    p1: Option<&NodeHash>,
+    p2: Option<&NodeHash>,
+) -> Result<NodeHash> {
+    let node = BlobNode::new(content.clone(), p1, p2)
+        .nodeid()
+        .expect("node has content");
+    let nodeblob = RawNodeBlob {
+        parents: Parents::new(p1, p2),
+        blob: BlobHash::from(content.as_ref()),
+        copy_from: None,
+    };
+    let nodekey = format!("node-{}.bincode", node);
+    let blobkey = format!("sha1-{}", nodeblob.blob.sha1());
+    let nodeblob = bincode::serialize(&nodeblob).map_err(Error::from)?;
+
+    entries.push(BlobstoreEntry::Blob((nodekey, Bytes::from(nodeblob))));
+    entries.push(BlobstoreEntry::Blob((blobkey, content)));
+    Ok(node)
+}
+
+fn user(commit: &Commit) -> Vec<u8> {
+    let author = commit.author();
+    let mut user = author.name_bytes().to_vec();
+    user.extend_from_slice(b" <");
+    user.extend_from_slice(author.email_bytes());
+    user.push(b'>');
+    user
+}
+
+fn time(commit: &Commit) -> Result<Time> {
+    let when = commit.author().when();
+    if when.seconds() < 0 {
+        bail_msg!(
+            "time {} is before 1970, which changesets can't represent",
+            when.seconds()
+        );
+    }
+    Ok(Time {
+        time: when.seconds() as u64,
+        // Mercurial timezones are seconds west of UTC
+        tz: -when.offset_minutes() * 60,
+    })
+}
+
+fn extra(commit: &Commit) -> BTreeMap<Vec<u8>, Vec<u8>> {
+    let mut extra = BTreeMap::new();
+    extra.insert(
+        b"convert_revision".to_vec(),
+        commit.id().to_string().into_bytes(),
+    );
+    extra
+}
+
+/// Paths added, modified or removed relative to the first parent, like Mercurial records them.
+fn changed_files(
+    files: &BTreeMap<MPath, FileState>,
+    p1: Option<&BTreeMap<MPath, FileState>>,
+) -> Vec<MPath> {
+    let empty = BTreeMap::new();
+    let p1 = p1.unwrap_or(&empty);
+    let mut changed: Vec<MPath> = files
+        .iter()
+        .filter(|&(path, file)| match p1.get(path) {
+            Some(p1file) => p1file.node != file.node || p1file.ty != file.ty,
+            None => true,
+        })
+        .map(|(path, _)| path.clone())
+        .chain(
+            p1.keys()
+                .filter(|path| !files.contains_key(*path))
+                .cloned(),
+        )
+        .collect();
+    changed.sort();
+    changed
+}
@@ -0,0 +1,310 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Import of a git repository into a blobstore, so that Mononoke repos can be bootstrapped from
+//! git mirrors. The history reachable from the local branches is converted into Mercurial
+//! changesets, along with their linknodes and changesets table, the heads of the history are
+//! recorded as heads and the branches as bookmarks.
+
+#![deny(warnings)]
+
+extern crate bincode;
+extern crate bytes;
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate git2;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate bookmarks;
+extern crate changesets;
+extern crate fileblob;
+extern crate filebookmarks;
+extern crate fileheads;
+extern crate filelinknodes;
+extern crate futures_ext;
+extern crate heads;
+extern crate linknodes;
+extern crate mercurial;
+extern crate mercurial_types;
+extern crate rocksblob;
+extern crate storage_types;
+
+mod convert;
+
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{stream, Future, Stream};
+use futures_cpupool::CpuPool;
+use git2::{BranchType, Repository, Sort};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobstore::Blobstore;
+use bookmarks::BookmarksMut;
+use changesets::{ChangesetInsert, Changesets, SqliteChangesets};
+use convert::{BlobstoreEntry, GitConverter};
+use fileblob::Fileblob;
+use filebookmarks::FileBookmarks;
+use fileheads::FileHeads;
+use filelinknodes::FileLinknodes;
+use futures_ext::{BoxFuture, FutureExt};
+use heads::Heads;
+use linknodes::Linknodes;
+use mercurial_types::RepositoryId;
+use rocksblob::Rocksblob;
+use storage_types::Version;
+
+type BBlobstore = Arc<Blobstore>;
+
+fn run_gitimport(
+    input: PathBuf,
+    output: PathBuf,
+    blobtype: &str,
+    logger: &Logger,
+    concurrency: usize,
+    commits_limit: Option<usize>,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let cpupool = Arc::new(CpuPool::new_num_cpus());
+
+    info!(logger, "Opening git repo: {}", input.display());
+    let repo = Repository::open(&input)
+        .with_context(|_| format!("cannot open git repo {}", input.display()))?;
+
+    info!(logger, "Opening blobstore: {}", output.display());
+    let blobstore = open_blobstore(output.clone(), blobtype)?;
+    let headstore = FileHeads::create_with_pool(output.join("heads"), cpupool.clone())?;
+    let bookmarks = FileBookmarks::create_with_pool(output.join("books"), cpupool.clone())?;
+    let linknodes = FileLinknodes::create_with_pool(output.join("linknodes"), cpupool.clone())?;
+    let changesets = SqliteChangesets::create(output.join("changesets").to_string_lossy())?;
+
+    let mut branches = Vec::new();
+    for branch in repo.branches(Some(BranchType::Local))? {
+        let (branch, _) = branch?;
+        let name = branch.name_bytes()?.to_vec();
+        match branch.get().target() {
+            Some(oid) => branches.push((name, oid)),
+            None => warn!(
+                logger,
+                "branch {} is symbolic, skipping",
+                String::from_utf8_lossy(&name)
+            ),
+        }
+    }
+
+    // Parents are always converted before their children
+    let mut revwalk = repo.revwalk()?;
+    revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE);
+    for &(_, oid) in &branches {
+        revwalk.push(oid)?;
+    }
+    let commits: Vec<_> = match commits_limit {
+        Some(limit) => revwalk.take(limit).collect::<::std::result::Result<_, _>>()?,
+        None => revwalk.collect::<::std::result::Result<_, _>>()?,
+    };
+
+    info!(logger, "Converting {} commits", commits.len());
+    let mut converter = GitConverter::new(&repo);
+    // Parents first, as the changesets table checks that they're in it already
+    let mut inserts = Vec::with_capacity(commits.len());
+    {
+        // Filter only blobs, because changesets should be unique. Filenodes which several
+        // commits introduce get the first of them as linknode.
+        let mut inserted_blobs = HashSet::new();
+        let mut inserted_linknodes = HashSet::new();
+        let converter = &mut converter;
+        let inserts = &mut inserts;
+        let writes = stream::iter_result(commits.into_iter().map(|oid| {
+            let conversion = converter
+                .convert(oid)
+                .with_context(|_| format!("cannot convert commit {}", oid))?;
+            let csid = conversion.csid;
+            debug!(logger, "commit {} -> changeset {}", oid, csid);
+            inserts.push(ChangesetInsert {
+                repo_id: RepositoryId::new(0),
+                cs_id: csid,
+                parents: conversion.parents,
+            });
+
+            let mut writes: Vec<BoxFuture<(), Error>> = Vec::new();
+            for entry in conversion.entries {
+                match entry {
+                    BlobstoreEntry::Changeset(bcs) => {
+                        writes.push(bcs.save(blobstore.clone()).boxify())
+                    }
+                    BlobstoreEntry::Blob((key, value)) => {
+                        if inserted_blobs.insert(key.clone()) {
+                            writes.push(blobstore.put(key, value).boxify())
+                        }
+                    }
+                }
+            }
+            for (path, node) in conversion.linknodes {
+                if inserted_linknodes.insert((path.clone(), node)) {
+                    writes.push(linknodes.add(path, &node, &csid.into_nodehash()));
+                }
+            }
+            Ok(stream::iter_ok::<_, Error>(writes))
+        })).flatten();
+
+        let write = writes.buffer_unordered(concurrency).for_each(|()| Ok(()));
+        core.run(write)?;
+    }
+    if converter.skipped_submodules() > 0 {
+        warn!(
+            logger,
+            "left out {} submodule entries, they can't be represented in manifests",
+            converter.skipped_submodules()
+        );
+    }
+
+    info!(logger, "Filling up the changesets table");
+    // The heads of the imported history are the changesets which aren't the parent of another
+    let parents: HashSet<_> = inserts
+        .iter()
+        .flat_map(|insert| insert.parents.iter().cloned())
+        .collect();
+    let heads: Vec<_> = inserts
+        .iter()
+        .map(|insert| insert.cs_id)
+        .filter(|csid| !parents.contains(csid))
+        .collect();
+    let fill = stream::iter_ok(inserts).for_each(|insert| changesets.add(&insert));
+    core.run(fill)?;
+
+    info!(logger, "Recording {} heads", heads.len());
+    let record = stream::iter_ok(heads)
+        .and_then(|csid| headstore.add(&csid.into_nodehash()))
+        .for_each(|()| Ok(()));
+    core.run(record)?;
+
+    info!(logger, "Recording {} branches", branches.len());
+    let record = stream::iter_ok(branches)
+        .filter_map(|(name, oid)| {
+            let csid = converter.changeset_id(&oid);
+            if csid.is_none() {
+                // Only possible when --commits-limit stopped before the branch
+                info!(
+                    logger,
+                    "branch {} wasn't imported, skipping",
+                    String::from_utf8_lossy(&name)
+                );
+            }
+            csid.map(|csid| (name, csid))
+        })
+        .and_then(|(name, csid)| {
+            bookmarks
+                .set(&name, &csid, &Version::absent())
+                .and_then(move |version| match version {
+                    Some(_) => Ok(()),
+                    None => Err(format_err!(
+                        "bookmark {} already exists",
+                        String::from_utf8_lossy(&name)
+                    )),
+                })
+        })
+        .for_each(|()| Ok(()));
+    core.run(record)?;
+
+    info!(logger, "Imported {}", input.display());
+    Ok(())
+}
+
+fn open_blobstore(output: PathBuf, blobtype: &str) -> Result<BBlobstore> {
+    let mut output = output;
+    output.push("blobs");
+    let blobstore: BBlobstore = match blobtype {
+        "files" => Arc::new(
+            Fileblob::create(output)
+                .map_err(Error::from)
+                .context("Failed to open file blob store")?,
+        ),
+        "rocksdb" => Arc::new(
+            Rocksblob::create(output)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?,
+        ),
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(blobstore)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("git to blob importer")
+        .version("0.0.0")
+        .about("make blobs out of a git repo")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'input git repo'
+            <OUTPUT>                 'output blobstore RepoCtx'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'blobstore type: files or rocksdb'
+            --concurrency [LIMIT]    'max number of blobstore writes in flight. Default: 100'
+            --commits-limit [LIMIT]  'import only LIMIT first commits from git repo'
+        "#,
+        )
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = PathBuf::from(matches.value_of("INPUT").unwrap());
+        let output = PathBuf::from(matches.value_of("OUTPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let concurrency: usize = matches
+            .value_of("concurrency")
+            .map(|limit| limit.parse().expect("concurrency must be positive integer"))
+            .unwrap_or(100);
+        if concurrency == 0 {
+            bail_msg!("concurrency must be positive integer");
+        }
+        let commits_limit = matches.value_of("commits-limit").map(|limit| {
+            limit
+                .parse()
+                .expect("commits-limit must be positive integer")
+        });
+
+        run_gitimport(
+            input,
+            output,
+            blobtype,
+            root_log,
+            concurrency,
+            commits_limit,
+        )
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Gitimport failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,275 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Audit and replay of the operation log a server writes for a repo, see the `oplog_path` of
+//! the repo config. The subcommands are:
+//!
+//! - `list`: print the operations of the log, who made them, when and how they ended. Those
+//!   without an outcome were in flight when the server stopped.
+//! - `replay`: apply the operations of the log which were applied, or are in flight with
+//!   `--include-pending`, to a repo again, in order. Bookmarks are moved as they were, and the
+//!   changesets of pushes are checked to be in the repo: the log doesn't have their content,
+//!   which has to be copied from another blobstore or pushed again if it's missing. Replaying
+//!   stops at the first operation which can't be applied, so that `--from` can carry on once
+//!   it's fixed, and `--to` stops before a botched push.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate mercurial_types;
+
+use std::path::Path;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use failure::Result;
+use futures::future;
+use slog::{Drain, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::Core;
+
+use blobrepo::{read_oplog, BlobRepo, LoggedOperation, Operation, Outcome};
+use mercurial_types::{ChangesetId, RepositoryId};
+
+fn describe_outcome(outcome: &Option<Outcome>) -> String {
+    match *outcome {
+        Some(Outcome::Applied) => "applied".to_string(),
+        Some(Outcome::Rejected) => "rejected".to_string(),
+        Some(Outcome::Failed(ref err)) => format!("failed: {}", err),
+        None => "in flight".to_string(),
+    }
+}
+
+fn describe_operation(operation: &Operation) -> String {
+    let describe_cs = |cs: &Option<ChangesetId>| match *cs {
+        Some(cs) => format!("{}", cs),
+        None => "none".to_string(),
+    };
+    match *operation {
+        Operation::Push { ref changesets, .. } => {
+            let changesets: Vec<_> = changesets.iter().map(|cs| format!("{}", cs)).collect();
+            format!("push {}", changesets.join(" "))
+        }
+        Operation::Bookmarks {
+            ref moves,
+            ref reason,
+        } => {
+            let moves: Vec<_> = moves
+                .iter()
+                .map(|mv| {
+                    format!(
+                        "{} {} -> {}",
+                        mv.name,
+                        describe_cs(&mv.old),
+                        describe_cs(&mv.new)
+                    )
+                })
+                .collect();
+            format!("{}: {}", reason, moves.join(", "))
+        }
+    }
+}
+
+fn list(operations: &[LoggedOperation], pending: bool) {
+    for op in operations {
+        if pending && op.outcome.is_some() {
+            continue;
+        }
+        println!(
+            "{}\t{}\t{}\t{}\t{}",
+            op.seq,
+            op.timestamp,
+            op.author,
+            describe_outcome(&op.outcome),
+            describe_operation(&op.operation)
+        );
+    }
+}
+
+/// Apply `op` to `repo` again. Moves of bookmarks which were already made are skipped, so that
+/// a log can be replayed over a repo which has part of it.
+fn replay_one(core: &mut Core, repo: &BlobRepo, op: LoggedOperation) -> Result<()> {
+    match op.operation {
+        Operation::Push { changesets, .. } => {
+            let changesets: Vec<_> = changesets.into_iter().map(ChangesetId::new).collect();
+            let exist = core.run(repo.changesets_exist(&changesets))?;
+            let missing: Vec<_> = changesets
+                .iter()
+                .zip(exist)
+                .filter(|&(_, exists)| !exists)
+                .map(|(cs, _)| format!("{}", cs))
+                .collect();
+            if !missing.is_empty() {
+                bail_msg!(
+                    "operation {}: pushed changesets are missing: {}",
+                    op.seq,
+                    missing.join(" ")
+                );
+            }
+        }
+        Operation::Bookmarks { moves, reason } => {
+            let updates = moves
+                .iter()
+                .map(|mv| (mv.name.clone().into_bytes(), mv.old, mv.new))
+                .collect();
+            let reason = format!("replay of operation {}: {}", op.seq, reason);
+            if !core.run(repo.update_bookmarks(updates, &op.author, &reason))? {
+                let current = future::join_all(
+                    moves
+                        .iter()
+                        .map(|mv| repo.get_bookmark_value(&mv.name))
+                        .collect::<Vec<_>>(),
+                );
+                let current = core.run(current)?;
+                let moved = moves
+                    .iter()
+                    .zip(current)
+                    .all(|(mv, current)| current.map(|(cs, _)| cs) == mv.new);
+                if !moved {
+                    bail_msg!(
+                        "operation {}: the bookmarks aren't where it moved them from",
+                        op.seq
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+fn replay(
+    core: &mut Core,
+    logger: &Logger,
+    repo: &BlobRepo,
+    operations: Vec<LoggedOperation>,
+    include_pending: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let mut replayed = 0;
+    for op in operations {
+        match op.outcome {
+            Some(Outcome::Applied) => {}
+            None if include_pending => {}
+            _ => continue,
+        }
+
+        info!(logger, "replaying operation {}: {}", op.seq, describe_operation(&op.operation));
+        if !dry_run {
+            replay_one(core, repo, op)?;
+        }
+        replayed += 1;
+    }
+    info!(logger, "replayed {} operations", replayed);
+    Ok(())
+}
+
+fn open_repo(
+    logger: &Logger,
+    input: &Path,
+    blobtype: &str,
+    repoid: RepositoryId,
+    blob_prefix: Option<String>,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!("repo" => format!("{}", input.display())));
+    let repo = match blobtype {
+        "files" => BlobRepo::new_files(logger, input, repoid, blob_prefix)?,
+        "rocksdb" => BlobRepo::new_rocksdb(logger, input, repoid, blob_prefix)?,
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(repo)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("oplog replay")
+        .version("0.0.0")
+        .about("audit and replay the operation log of a repo")
+        .arg(Arg::from_usage("--oplog <FILE> 'operation log of the repo'"))
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("print the operations of the log")
+                .arg(Arg::from_usage("--pending 'only print the operations in flight'")),
+        )
+        .subcommand(
+            SubCommand::with_name("replay")
+                .about("apply the operations of the log to a repo again")
+                .args_from_usage(
+                    r#"
+                    <REPO>                   'blobstore RepoCtx of the repo to replay to'
+                    --blobstore <TYPE>       'blobstore type: files or rocksdb'
+                    --repoid [ID]            'numerical id of the repo. Default: 0'
+                    --blob-prefix [PREFIX]   'prefix of the blobstore keys of the repo'
+                    --from [SEQ]             'first operation to replay. Default: 0'
+                    --to [SEQ]               'last operation to replay. Default: the last one'
+                    --include-pending        'replay the operations in flight too'
+                    -n, --dry-run            'only print what would be replayed'
+                "#,
+                ),
+        )
+}
+
+fn run<'a>(logger: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+    let operations = read_oplog(matches.value_of("oplog").unwrap())?;
+
+    match matches.subcommand() {
+        ("list", Some(sub)) => {
+            list(&operations, sub.is_present("pending"));
+            Ok(())
+        }
+        ("replay", Some(sub)) => {
+            let repoid = sub.value_of("repoid")
+                .map(|id| id.parse().expect("repoid must be an integer"))
+                .unwrap_or(0);
+            let from = sub.value_of("from")
+                .map(|seq| seq.parse().expect("from must be an operation number"))
+                .unwrap_or(0);
+            let to = sub.value_of("to")
+                .map(|seq| seq.parse().expect("to must be an operation number"));
+            let operations = operations
+                .into_iter()
+                .filter(|op| op.seq >= from && to.map_or(true, |to| op.seq <= to))
+                .collect();
+
+            let mut core = Core::new()?;
+            let repo = open_repo(
+                logger,
+                Path::new(sub.value_of("REPO").unwrap()),
+                sub.value_of("blobstore").unwrap(),
+                RepositoryId::new(repoid),
+                sub.value_of("blob-prefix").map(ToOwned::to_owned),
+            )?;
+            replay(
+                &mut core,
+                logger,
+                &repo,
+                operations,
+                sub.is_present("include-pending"),
+                sub.is_present("dry-run"),
+            )
+        }
+        _ => bail_msg!("unexpected or missing subcommand"),
+    }
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+    let logger = Logger::root(glog_drain().fuse(), o![]);
+
+    if let Err(err) = run(&logger, matches) {
+        eprintln!("Oplog replay failed: {}", err);
+        for cause in err.causes().skip(1) {
+            eprintln!("  caused by: {}", cause);
+        }
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,394 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Replication of a repo to another blobstore, such as a replica in another region.
+//!
+//! The tailer polls the heads and bookmarks of the source repo, and copies the changesets they
+//! point to which the destination doesn't have yet, with their manifests and files. The
+//! changesets whose blobs are all copied are recorded in the checkpoint file, so that a restarted
+//! tailer doesn't walk the history again to find out what's missing. Only blobs are replicated:
+//! the heads and bookmarks of the replica are left to whatever serves it.
+//!
+//! With `--catch-up`, the tailer copies many changesets at once until the destination has
+//! everything, and exits. That's much faster for a new replica, or one which fell far behind.
+
+#![deny(warnings)]
+
+extern crate clap;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[macro_use]
+extern crate slog;
+extern crate slog_glog_fmt;
+extern crate tokio_core;
+
+extern crate blobrepo;
+extern crate blobstore;
+extern crate fileblob;
+extern crate futures_ext;
+extern crate manifoldblob;
+extern crate mercurial_types;
+extern crate rocksblob;
+extern crate services;
+extern crate sqliteblob;
+#[macro_use]
+extern crate stats;
+
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{App, ArgMatches};
+use failure::{Error, Result, ResultExt, SlogKVError};
+use futures::{Future, Stream};
+use slog::{Drain, Level, Logger};
+use slog_glog_fmt::default_drain as glog_drain;
+use tokio_core::reactor::{Core, Remote};
+
+use blobrepo::{find_missing_changesets, replicate_changesets, BlobRepo};
+use blobstore::Blobstore;
+use fileblob::Fileblob;
+use futures_ext::{BoxFuture, FutureExt};
+use manifoldblob::ManifoldBlob;
+use mercurial_types::{NodeHash, RepositoryId};
+use rocksblob::Rocksblob;
+use sqliteblob::Sqliteblob;
+use stats::{Histogram, Timeseries};
+
+define_stats! {
+    prefix = "repotailer";
+    changesets: timeseries(RATE, SUM),
+    blobs: timeseries(RATE, SUM),
+    failures: timeseries(RATE, SUM),
+    pending_changesets: histogram(100, 0, 100_000, AVG; P 50; P 99),
+    lag_secs: histogram(60, 0, 86_400, AVG; P 50; P 99),
+}
+
+/// The changesets which, with everything they refer to, the destination has.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Checkpoint {
+    replicated: Vec<String>,
+    /// When the source was polled for these changesets, in seconds since the epoch
+    time: u64,
+}
+
+impl Checkpoint {
+    fn load(path: &Path) -> Result<Self> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let file =
+            fs::File::open(path).with_context(|_| format!("cannot open {}", path.display()))?;
+        let checkpoint = serde_json::from_reader(file)
+            .with_context(|_| format!("cannot parse {}", path.display()))?;
+        Ok(checkpoint)
+    }
+
+    // The checkpoint is written to a temporary file first, so that it's never left half written
+    fn save(&self, path: &Path) -> Result<()> {
+        let tmp = path.with_extension("tmp");
+        let file =
+            fs::File::create(&tmp).with_context(|_| format!("cannot create {}", tmp.display()))?;
+        serde_json::to_writer_pretty(file, self)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    fn replicated(&self) -> Result<HashSet<NodeHash>> {
+        self.replicated
+            .iter()
+            .map(|node| {
+                node.parse()
+                    .map_err(|_| format_err!("invalid changeset {} in checkpoint", node))
+            })
+            .collect()
+    }
+}
+
+struct Tailer {
+    repo: BlobRepo,
+    dest: Arc<Blobstore>,
+    checkpoint: PathBuf,
+    /// Number of changesets copied at once
+    batch_size: usize,
+    concurrency: usize,
+    logger: Logger,
+}
+
+impl Tailer {
+    /// Replicate what the source has and the destination hasn't. Returns the number of
+    /// changesets which were missing.
+    fn tail_once(&self, core: &mut Core) -> Result<usize> {
+        let now = now_secs()?;
+        let checkpoint = Checkpoint::load(&self.checkpoint)?;
+        let roots = core.run(load_roots(&self.repo))?;
+
+        let source = self.repo.get_blobstore();
+        let missing = core.run(find_missing_changesets(
+            source.clone(),
+            self.dest.clone(),
+            roots.clone(),
+            checkpoint.replicated()?,
+            self.concurrency,
+        ))?;
+        STATS::pending_changesets.add_value(missing.len() as i64);
+        // The destination had everything the source had when the checkpoint was taken, so it's
+        // at most that far behind
+        if checkpoint.time > 0 {
+            STATS::lag_secs.add_value(now.saturating_sub(checkpoint.time) as i64);
+        }
+
+        let pending = missing.len();
+        if pending > 0 {
+            info!(self.logger, "Replicating {} changesets", pending);
+            let logger = self.logger.clone();
+            let replicate = replicate_changesets(
+                source,
+                self.dest.clone(),
+                missing,
+                self.batch_size,
+                self.concurrency,
+            ).for_each(move |(node, blobs)| {
+                debug!(logger, "replicated changeset {} ({} blobs)", node, blobs);
+                STATS::changesets.add_value(1);
+                STATS::blobs.add_value(blobs as i64);
+                Ok(())
+            });
+            core.run(replicate)?;
+        }
+
+        let checkpoint = Checkpoint {
+            replicated: roots.iter().map(|node| node.to_string()).collect(),
+            time: now,
+        };
+        checkpoint.save(&self.checkpoint)?;
+        Ok(pending)
+    }
+
+    fn tail(&self, core: &mut Core, interval: Duration) -> ! {
+        loop {
+            if let Err(err) = self.tail_once(core) {
+                STATS::failures.add_value(1);
+                error!(self.logger, "Replication failed"; SlogKVError(err));
+            }
+            thread::sleep(interval);
+        }
+    }
+}
+
+/// The changesets the heads and bookmarks of the repo point to.
+fn load_roots(repo: &BlobRepo) -> BoxFuture<Vec<NodeHash>, Error> {
+    let bookmarks = repo.get_bookmark_keys()
+        .and_then({
+            let repo = repo.clone();
+            move |name| repo.get_bookmark_value(&name)
+        })
+        .filter_map(|value| value.map(|(csid, _)| csid.into_nodehash()))
+        .collect();
+
+    repo.get_heads()
+        .collect()
+        .join(bookmarks)
+        .map(|(mut roots, bookmarks)| {
+            roots.extend(bookmarks);
+            roots.sort();
+            roots.dedup();
+            roots
+        })
+        .boxify()
+}
+
+fn now_secs() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+fn open_repo(
+    logger: &Logger,
+    input: &Path,
+    blobtype: &str,
+    repoid: RepositoryId,
+    blob_prefix: Option<String>,
+) -> Result<BlobRepo> {
+    let logger = logger.new(o!("repo" => format!("{}", input.display())));
+    let repo = match blobtype {
+        "files" => BlobRepo::new_files(logger, input, repoid, blob_prefix)?,
+        "rocksdb" => BlobRepo::new_rocksdb(logger, input, repoid, blob_prefix)?,
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(repo)
+}
+
+/// Open the destination, named `files:PATH`, `rocksdb:PATH`, `sqlite:PATH` or
+/// `manifold:BUCKET`.
+fn open_dest(spec: &str, remote: &Remote) -> Result<Arc<Blobstore>> {
+    let (kind, location) = match spec.find(':') {
+        Some(pos) => (&spec[..pos], &spec[pos + 1..]),
+        None => bail_msg!("blobstore {} should be TYPE:LOCATION", spec),
+    };
+    let blobstore: Arc<Blobstore> = match kind {
+        "files" => Arc::new(
+            Fileblob::create(location)
+                .map_err(Error::from)
+                .context("Failed to open file blob store")?,
+        ),
+        "rocksdb" => Arc::new(
+            Rocksblob::create(location)
+                .map_err(Error::from)
+                .context("Failed to open rocksdb blob store")?,
+        ),
+        "sqlite" => Arc::new(
+            Sqliteblob::create(location).context("Failed to open sqlite blob store")?,
+        ),
+        "manifold" => Arc::new(ManifoldBlob::new_may_panic(location.to_string(), remote)),
+        bad => bail_msg!("unexpected blobstore type {}", bad),
+    };
+    Ok(blobstore)
+}
+
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    App::new("repo tailer")
+        .version("0.0.0")
+        .about("replicate the changesets of a repo to another blobstore as they're pushed")
+        .args_from_usage(
+            r#"
+            <INPUT>                  'source blobstore RepoCtx'
+
+            -d, --debug              'print debug level output'
+            --blobstore <TYPE>       'source blobstore type: files or rocksdb'
+            --repoid [ID]            'numerical id of the repo. Default: 0'
+            --blob-prefix [PREFIX]   'prefix of the blobstore keys of the repo'
+            --dest <STORE>           'files:PATH, rocksdb:PATH, sqlite:PATH or manifold:BUCKET'
+            --checkpoint <FILE>      'changesets known to be replicated, updated as they are'
+            --interval [SECS]        'how often to poll the source. Default: 10'
+            --once                   'replicate what the source has now and exit'
+            --catch-up               'like --once, copying --batch-size changesets at once'
+            --batch-size [COUNT]     'changesets copied at once by --catch-up. Default: 100'
+            --concurrency [LIMIT]    'max number of blobstore requests in flight. Default: 100'
+            -p, --port [PORT]        'if provided the thrift server will start on this port'
+        "#,
+        )
+}
+
+fn start_thrift_service<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<()> {
+    let port = match matches.value_of("port") {
+        None => return Ok(()),
+        Some(port) => port.parse().expect("Failed to parse port as number"),
+    };
+
+    info!(logger, "Initializing thrift server on port {}", port);
+
+    thread::Builder::new()
+        .name("thrift_service".to_owned())
+        .spawn(move || {
+            services::run_service_framework(
+                "mononoke_repotailer",
+                port,
+                0, // Disables separate status http server
+            ).expect("failure while running thrift service framework")
+        })
+        .map(|_| ()) // detaches the thread
+        .map_err(Error::from)
+}
+
+fn start_stats() -> Result<()> {
+    thread::Builder::new()
+        .name("stats_aggregation".to_owned())
+        .spawn(move || {
+            let mut core = Core::new().expect("failed to create tokio core");
+            let scheduler = stats::schedule_stats_aggregation(&core.handle())
+                .expect("failed to create stats aggregation scheduler");
+            core.run(scheduler).expect("stats scheduler failed");
+            // stats scheduler shouldn't finish successfully
+            unreachable!()
+        })?; // thread detached
+    Ok(())
+}
+
+fn main() {
+    let matches = setup_app().get_matches();
+
+    let root_log = {
+        let level = if matches.is_present("debug") {
+            Level::Debug
+        } else {
+            Level::Info
+        };
+
+        let drain = glog_drain().filter_level(level).fuse();
+        slog::Logger::root(drain, o![])
+    };
+
+    fn run<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<()> {
+        let input = Path::new(matches.value_of("INPUT").unwrap());
+        let blobtype = matches.value_of("blobstore").unwrap();
+        let repoid = matches
+            .value_of("repoid")
+            .map(|id| id.parse().expect("repoid must be an integer"))
+            .unwrap_or(0);
+        let blob_prefix = matches.value_of("blob-prefix").map(ToOwned::to_owned);
+        let interval = matches
+            .value_of("interval")
+            .map(|secs| secs.parse().expect("interval must be a number of seconds"))
+            .unwrap_or(10);
+        let catch_up = matches.is_present("catch-up");
+        let batch_size = if catch_up {
+            matches
+                .value_of("batch-size")
+                .map(|count| count.parse().expect("batch size must be positive integer"))
+                .unwrap_or(100)
+        } else {
+            1
+        };
+        let concurrency: usize = matches
+            .value_of("concurrency")
+            .map(|limit| limit.parse().expect("concurrency must be positive integer"))
+            .unwrap_or(100);
+        if concurrency == 0 {
+            bail_msg!("concurrency must be positive integer");
+        }
+
+        start_thrift_service(root_log, &matches)?;
+        start_stats()?;
+
+        let mut core = Core::new()?;
+        let repo = open_repo(
+            root_log,
+            input,
+            blobtype,
+            RepositoryId::new(repoid),
+            blob_prefix,
+        )?;
+        let tailer = Tailer {
+            repo,
+            dest: open_dest(matches.value_of("dest").unwrap(), &core.remote())?,
+            checkpoint: PathBuf::from(matches.value_of("checkpoint").unwrap()),
+            batch_size,
+            concurrency,
+            logger: root_log.clone(),
+        };
+
+        if catch_up || matches.is_present("once") {
+            let pending = tailer.tail_once(&mut core)?;
+            info!(root_log, "Replicated {} changesets", pending);
+            Ok(())
+        } else {
+            tailer.tail(&mut core, Duration::from_secs(interval))
+        }
+    }
+
+    if let Err(e) = run(&root_log, matches) {
+        error!(root_log, "Repo tailer failed"; SlogKVError(e));
+        std::process::exit(1);
+    }
+}
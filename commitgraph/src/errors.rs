@@ -0,0 +1,14 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+pub use failure::{Error, Result};
+
+use mercurial_types::NodeHash;
+
+#[derive(Debug, Fail)]
+pub enum ErrorKind {
+    #[fail(display = "changeset {} is not in the commit graph", _0)] NodeNotFound(NodeHash),
+}
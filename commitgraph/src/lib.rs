@@ -0,0 +1,458 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Index of the commit graph of a repo, for ancestry queries which don't fetch changesets.
+//!
+//! There is no persisted index of its own: the parents and generation number of every complete
+//! changeset are the rows of the changesets table. The index loads them as the graph is walked
+//! and keeps the most recently used ones in memory, so that the walks of later queries are
+//! served without a round trip to the store. Each node held takes in the order of 150 bytes, so
+//! the default bound of `DEFAULT_MAX_NODES` nodes is about 150MB.
+//!
+//! The walks visit changesets by decreasing generation number. As a changeset always has a
+//! larger generation number than its parents, all the children of a changeset which are
+//! reached by a walk are visited before it.
+
+#![deny(warnings)]
+
+extern crate blobrepo;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate linked_hash_map;
+extern crate mercurial_types;
+
+#[cfg(test)]
+extern crate linear;
+#[cfg(test)]
+extern crate merge_uneven;
+
+mod errors;
+
+use std::collections::{BinaryHeap, HashMap};
+use std::sync::{Arc, Mutex};
+
+use linked_hash_map::LinkedHashMap;
+
+use futures::{future, stream, Future};
+use futures::future::{loop_fn, Loop};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use blobrepo::BlobRepo;
+use mercurial_types::{ChangesetId, NodeHash, NULL_HASH};
+
+pub use errors::*;
+
+// What a walk reached a changeset from
+const FROM_A: u8 = 1;
+const FROM_B: u8 = 2;
+// Reached from a common ancestor already found
+const STALE: u8 = 4;
+
+/// How many nodes the index holds in memory by default
+pub const DEFAULT_MAX_NODES: usize = 1_000_000;
+
+/// What the index knows of a changeset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CommitNode {
+    /// 1 for root changesets, and 1 + the largest generation number of the parents otherwise
+    pub generation: u64,
+    pub parents: Vec<NodeHash>,
+}
+
+pub struct CommitGraph {
+    repo: Arc<BlobRepo>,
+    // The least recently used nodes are evicted first
+    nodes: Arc<Mutex<LinkedHashMap<NodeHash, CommitNode>>>,
+    max_nodes: usize,
+}
+
+impl CommitGraph {
+    pub fn new(repo: Arc<BlobRepo>) -> Self {
+        Self::with_max_nodes(repo, DEFAULT_MAX_NODES)
+    }
+
+    /// An index holding at most `max_nodes` nodes in memory.
+    pub fn with_max_nodes(repo: Arc<BlobRepo>, max_nodes: usize) -> Self {
+        CommitGraph {
+            repo,
+            nodes: Arc::new(Mutex::new(LinkedHashMap::new())),
+            max_nodes,
+        }
+    }
+
+    /// The parents and generation number of `node`, failing if it isn't a complete changeset.
+    pub fn get(&self, node: NodeHash) -> BoxFuture<CommitNode, Error> {
+        if let Some(commit) = self.nodes.lock().expect("lock poisoned").get_refresh(&node) {
+            return future::ok(commit.clone()).boxify();
+        }
+
+        let nodes = self.nodes.clone();
+        let max_nodes = self.max_nodes;
+        self.repo
+            .get_changeset_entry(&ChangesetId::new(node))
+            .and_then(move |entry| {
+                let entry = entry.ok_or(ErrorKind::NodeNotFound(node))?;
+                let commit = CommitNode {
+                    generation: entry.gen,
+                    parents: entry
+                        .parents
+                        .into_iter()
+                        .map(|parent| parent.into_nodehash())
+                        .collect(),
+                };
+                let mut nodes = nodes.lock().expect("lock poisoned");
+                nodes.insert(node, commit.clone());
+                while nodes.len() > max_nodes {
+                    nodes.pop_front();
+                }
+                Ok(commit)
+            })
+            .boxify()
+    }
+
+    /// Which of `nodes` are complete changesets, in the same order. Only the nodes which aren't
+    /// in the index yet are looked up in the store.
+    pub fn known(&self, nodes: Vec<NodeHash>) -> BoxFuture<Vec<bool>, Error> {
+        let indexed: Vec<bool> = {
+            let indexed = self.nodes.lock().expect("lock poisoned");
+            nodes.iter().map(|node| indexed.contains_key(node)).collect()
+        };
+        let unindexed: Vec<_> = nodes
+            .iter()
+            .zip(indexed.iter())
+            .filter(|&(_, indexed)| !indexed)
+            .map(|(node, _)| ChangesetId::new(*node))
+            .collect();
+
+        self.repo
+            .changesets_exist(&unindexed)
+            .map(move |exist| {
+                let mut exist = exist.into_iter();
+                indexed
+                    .into_iter()
+                    .map(|indexed| indexed || exist.next().unwrap_or(false))
+                    .collect()
+            })
+            .boxify()
+    }
+
+    /// Whether `ancestor` is `descendant` or one of its ancestors.
+    pub fn is_ancestor(
+        self: &Arc<Self>,
+        ancestor: NodeHash,
+        descendant: NodeHash,
+    ) -> BoxFuture<bool, Error> {
+        if ancestor == NULL_HASH {
+            return future::ok(true).boxify();
+        }
+
+        let graph = self.clone();
+        self.get(ancestor)
+            .and_then(move |commit| {
+                // Nothing below the generation of the ancestor can lead to it
+                graph.walk(
+                    vec![(descendant, FROM_A)],
+                    commit.generation,
+                    false,
+                    move |found, node, flags| {
+                        *found = *found || node == ancestor;
+                        flags
+                    },
+                    |found, _| *found,
+                )
+            })
+            .boxify()
+    }
+
+    /// The greatest common ancestors of `a` and `b`: the common ancestors which aren't
+    /// ancestors of other common ancestors. There are several of them when `a` and `b` are
+    /// merges of the same branches.
+    pub fn common_ancestors(
+        self: &Arc<Self>,
+        a: NodeHash,
+        b: NodeHash,
+    ) -> BoxFuture<Vec<NodeHash>, Error> {
+        self.walk(
+            vec![(a, FROM_A), (b, FROM_B)],
+            0,
+            Vec::new(),
+            |ancestors, node, flags| {
+                if flags == FROM_A | FROM_B {
+                    ancestors.push(node);
+                    // Its ancestors are common ancestors too, but not greatest ones
+                    flags | STALE
+                } else {
+                    flags
+                }
+            },
+            // Past this point, everything is an ancestor of a common ancestor already found
+            |_, frontier| frontier.all_have(STALE),
+        )
+    }
+
+    /// The ancestors of `heads`, including them, which aren't ancestors of `common`, in
+    /// increasing generation number order: parents come before their children.
+    pub fn range(
+        self: &Arc<Self>,
+        heads: Vec<NodeHash>,
+        common: Vec<NodeHash>,
+    ) -> BoxFuture<Vec<NodeHash>, Error> {
+        let starts = heads
+            .into_iter()
+            .map(|node| (node, FROM_A))
+            .chain(common.into_iter().map(|node| (node, FROM_B)))
+            .collect();
+        self.walk(
+            starts,
+            0,
+            Vec::new(),
+            |range, node, flags| {
+                if flags & FROM_B == 0 {
+                    range.push(node);
+                }
+                flags
+            },
+            // Past this point, everything is an ancestor of common
+            |_, frontier| frontier.all_have(FROM_B),
+        ).map(|mut range| {
+            range.reverse();
+            range
+        })
+            .boxify()
+    }
+
+    /// `top` and its first parent ancestors, down to `bottom` excluded.
+    pub fn first_parents(
+        self: &Arc<Self>,
+        top: NodeHash,
+        bottom: NodeHash,
+    ) -> BoxStream<NodeHash, Error> {
+        let graph = self.clone();
+        stream::unfold(top, move |node| {
+            if node == bottom || node == NULL_HASH {
+                return None;
+            }
+            Some(graph.get(node).map(move |commit| {
+                let p1 = commit.parents.first().cloned().unwrap_or(NULL_HASH);
+                (node, p1)
+            }))
+        }).boxify()
+    }
+
+    // Walks the ancestors of `starts` by decreasing generation number, skipping the ones below
+    // `min_generation`. `visit` is called on every changeset with the flags it was reached
+    // with, and returns the flags to pass on to its parents. The walk stops once `done`, or
+    // when there are no more changesets to visit.
+    fn walk<S, V, D>(
+        self: &Arc<Self>,
+        starts: Vec<(NodeHash, u8)>,
+        min_generation: u64,
+        state: S,
+        visit: V,
+        done: D,
+    ) -> BoxFuture<S, Error>
+    where
+        S: Send + 'static,
+        V: Fn(&mut S, NodeHash, u8) -> u8 + Send + 'static,
+        D: Fn(&S, &Frontier) -> bool + Send + 'static,
+    {
+        let starts: Vec<_> = starts
+            .into_iter()
+            .filter(|&(node, _)| node != NULL_HASH)
+            .collect();
+        let commits: Vec<_> = starts.iter().map(|&(node, _)| self.get(node)).collect();
+        let graph = self.clone();
+
+        future::join_all(commits)
+            .and_then(move |commits| {
+                let mut frontier = Frontier::new();
+                for ((node, flags), commit) in starts.into_iter().zip(commits) {
+                    frontier.add(node, commit, flags);
+                }
+
+                loop_fn((frontier, state), move |(mut frontier, mut state)| {
+                    if done(&state, &frontier) {
+                        return future::ok(Loop::Break(state)).boxify();
+                    }
+                    let (node, commit, flags) = match frontier.pop() {
+                        Some(next) => next,
+                        None => return future::ok(Loop::Break(state)).boxify(),
+                    };
+                    let parent_flags = visit(&mut state, node, flags);
+
+                    let parents: Vec<_> = commit
+                        .parents
+                        .into_iter()
+                        .filter(|parent| *parent != NULL_HASH)
+                        .map(|parent| graph.get(parent).map(move |commit| (parent, commit)))
+                        .collect();
+                    future::join_all(parents)
+                        .map(move |parents| {
+                            for (parent, commit) in parents {
+                                if commit.generation >= min_generation {
+                                    frontier.add(parent, commit, parent_flags);
+                                }
+                            }
+                            Loop::Continue((frontier, state))
+                        })
+                        .boxify()
+                })
+            })
+            .boxify()
+    }
+}
+
+// The changesets a walk has reached but not visited yet, with the flags they were reached with
+struct Frontier {
+    queue: BinaryHeap<(u64, NodeHash)>,
+    pending: HashMap<NodeHash, (CommitNode, u8)>,
+}
+
+impl Frontier {
+    fn new() -> Self {
+        Frontier {
+            queue: BinaryHeap::new(),
+            pending: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, node: NodeHash, commit: CommitNode, flags: u8) {
+        if let Some(&mut (_, ref mut pending_flags)) = self.pending.get_mut(&node) {
+            *pending_flags |= flags;
+            return;
+        }
+        self.queue.push((commit.generation, node));
+        self.pending.insert(node, (commit, flags));
+    }
+
+    // The reached changeset with the largest generation number
+    fn pop(&mut self) -> Option<(NodeHash, CommitNode, u8)> {
+        let pending = &mut self.pending;
+        self.queue.pop().map(|(_, node)| {
+            let (commit, flags) = pending.remove(&node).expect("queued node is pending");
+            (node, commit, flags)
+        })
+    }
+
+    fn all_have(&self, flag: u8) -> bool {
+        self.pending.values().all(|&(_, flags)| flags & flag != 0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::str::FromStr;
+
+    use futures::Stream;
+
+    fn node(hash: &str) -> NodeHash {
+        NodeHash::from_str(hash).expect("valid hash")
+    }
+
+    fn linear_graph() -> Arc<CommitGraph> {
+        Arc::new(CommitGraph::new(Arc::new(linear::getrepo(None))))
+    }
+
+    const LINEAR_HEAD: &str = "a9473beb2eb03ddb1cccc3fbaeb8a4820f9cd157";
+    const LINEAR_ROOT: &str = "2d7d4ba9ce0a6ffd222de7785b249ead9c51c536";
+    const LINEAR_MIDDLE: &str = "cb15ca4a43a59acff5388cea9648c162afde8372";
+
+    #[test]
+    fn get() {
+        let graph = linear_graph();
+        let root = graph.get(node(LINEAR_ROOT)).wait().unwrap();
+        assert_eq!(root.generation, 1);
+        assert!(root.parents.is_empty());
+
+        let head = graph.get(node(LINEAR_HEAD)).wait().unwrap();
+        assert_eq!(head.generation, 8);
+
+        let missing = graph.get(node("1111111111111111111111111111111111111111"));
+        assert!(missing.wait().is_err());
+    }
+
+    #[test]
+    fn known() {
+        let graph = linear_graph();
+        graph.get(node(LINEAR_ROOT)).wait().unwrap();
+        let nodes = vec![
+            node(LINEAR_HEAD),
+            node("1111111111111111111111111111111111111111"),
+            node(LINEAR_ROOT),
+        ];
+        assert_eq!(graph.known(nodes).wait().unwrap(), vec![true, false, true]);
+    }
+
+    #[test]
+    fn is_ancestor() {
+        let graph = linear_graph();
+        let head = node(LINEAR_HEAD);
+        let middle = node(LINEAR_MIDDLE);
+        assert!(graph.is_ancestor(middle, head).wait().unwrap());
+        assert!(graph.is_ancestor(head, head).wait().unwrap());
+        assert!(!graph.is_ancestor(head, middle).wait().unwrap());
+    }
+
+    #[test]
+    fn max_nodes() {
+        let graph = Arc::new(CommitGraph::with_max_nodes(
+            Arc::new(linear::getrepo(None)),
+            2,
+        ));
+        // Walking the whole history holds only the last nodes walked
+        let head = node(LINEAR_HEAD);
+        let root = node(LINEAR_ROOT);
+        assert!(graph.is_ancestor(root, head).wait().unwrap());
+        assert_eq!(graph.nodes.lock().unwrap().len(), 2);
+        assert!(graph.is_ancestor(root, head).wait().unwrap());
+    }
+
+    #[test]
+    fn common_ancestors() {
+        let graph = Arc::new(CommitGraph::new(Arc::new(merge_uneven::getrepo(None))));
+        let ancestors = graph
+            .common_ancestors(
+                node("4f7f3fd428bec1a48f9314414b063c706d9c1aed"),
+                node("3cda5c78aa35f0f5b09780d971197b51cad4613a"),
+            )
+            .wait()
+            .unwrap();
+        assert_eq!(
+            ancestors,
+            vec![node("15c40d0abc36d47fb51c8eaec51ac7aad31f669c")]
+        );
+    }
+
+    #[test]
+    fn range() {
+        let graph = linear_graph();
+        let range = graph
+            .range(vec![node(LINEAR_HEAD)], vec![node(LINEAR_MIDDLE)])
+            .wait()
+            .unwrap();
+        assert_eq!(
+            range,
+            vec![
+                node("eed3a8c0ec67b6a6fe2eb3543334df3f0b4f202b"),
+                node("0ed509bf086fadcb8a8a5384dc3b550729b0fc17"),
+                node(LINEAR_HEAD),
+            ]
+        );
+
+        let first_parents: Vec<_> = graph
+            .first_parents(node(LINEAR_HEAD), node(LINEAR_MIDDLE))
+            .collect()
+            .wait()
+            .unwrap();
+        let mut expected = range.clone();
+        expected.reverse();
+        assert_eq!(first_parents, expected);
+    }
+}
@@ -469,6 +469,7 @@ struct RawRepoConfig {
     path: Option<PathBuf>,
     manifold_bucket: Option<String>,
     manifold_prefix: Option<String>,
+    blob_prefix: Option<String>,
     repotype: RawRepoType,
     reponame: String,
     addr: String,
@@ -515,8 +516,12 @@ fn main() {
             start_server(
                 &config.addr,
                 config.reponame,
-                BlobRepo::new_files(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
+                BlobRepo::new_files(
+                    repo_logger,
+                    &path,
+                    RepositoryId::new(config.repoid),
+                    config.blob_prefix,
+                ).expect("couldn't open blob state"),
                 root_logger.clone(),
                 config.ssl,
             )
@@ -527,8 +532,12 @@ fn main() {
             start_server(
                 &config.addr,
                 config.reponame,
-                BlobRepo::new_rocksdb(repo_logger, &path, RepositoryId::new(config.repoid))
-                    .expect("couldn't open blob state"),
+                BlobRepo::new_rocksdb(
+                    repo_logger,
+                    &path,
+                    RepositoryId::new(config.repoid),
+                    config.blob_prefix,
+                ).expect("couldn't open blob state"),
                 root_logger.clone(),
                 config.ssl,
             )
@@ -6,7 +6,9 @@
 
 use std::io::{self, Read, Write};
 
-use futures::Poll;
+use bytes::Bytes;
+use futures::{Async, AsyncSink, Poll, Sink};
+use futures::sync::mpsc;
 use tokio_io::{AsyncRead, AsyncWrite};
 
 #[derive(Debug, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
@@ -57,3 +59,75 @@ impl<A: AsyncWrite, B: AsyncWrite> AsyncWrite for Either<A, B> {
         }
     }
 }
+
+/// A writer which sends what's written to it as chunks down a channel, so that whatever is
+/// encoded into a writer can be streamed out without being buffered in memory first.
+/// Writes fail with `WouldBlock` while the channel is full, and the receiving stream ends once
+/// the writer is dropped.
+pub struct ChannelWriter {
+    sender: mpsc::Sender<Bytes>,
+}
+
+impl ChannelWriter {
+    /// Create a writer and the stream of its chunks. At most `buffer` chunks are held in the
+    /// channel.
+    pub fn new(buffer: usize) -> (Self, mpsc::Receiver<Bytes>) {
+        let (sender, receiver) = mpsc::channel(buffer);
+        (ChannelWriter { sender }, receiver)
+    }
+}
+
+impl Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.sender.start_send(Bytes::from(buf)) {
+            Ok(AsyncSink::Ready) => Ok(buf.len()),
+            Ok(AsyncSink::NotReady(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(_) => Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "channel receiver dropped",
+            )),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl AsyncWrite for ChannelWriter {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        Ok(Async::Ready(()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{Future, Stream};
+    use tokio_core::reactor::Core;
+    use tokio_io::io::write_all;
+
+    #[test]
+    fn channel_writer() {
+        let mut core = Core::new().unwrap();
+        let (writer, chunks) = ChannelWriter::new(1);
+
+        let write = write_all(writer, b"abc")
+            .and_then(|(writer, _)| write_all(writer, b"def"))
+            .map(|_| None)
+            .into_stream();
+        let chunks = chunks
+            .map(Some)
+            .map_err(|()| io::Error::new(io::ErrorKind::Other, "channel failed"))
+            .select(write)
+            .filter_map(|chunk| chunk)
+            .collect();
+
+        let chunks = core.run(chunks).unwrap();
+        assert_eq!(chunks, vec![Bytes::from(&b"abc"[..]), Bytes::from(&b"def"[..])]);
+    }
+}
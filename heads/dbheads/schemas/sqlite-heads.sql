@@ -0,0 +1,5 @@
+CREATE TABLE heads (
+  repo_id INTEGER NOT NULL,
+  head BINARY(20) NOT NULL,
+  PRIMARY KEY (repo_id, head)
+);
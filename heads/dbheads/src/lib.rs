@@ -0,0 +1,246 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+#[macro_use]
+extern crate diesel;
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+
+extern crate db;
+extern crate futures_ext;
+extern crate heads;
+extern crate mercurial_types;
+
+use std::result;
+use std::sync::{Mutex, MutexGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use diesel::{delete, insert_into, Connection, MysqlConnection, SqliteConnection};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::result::{DatabaseErrorKind, Error as DieselError};
+use failure::{Error, Result};
+use futures::{future, stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use db::ConnectionParams;
+use heads::Heads;
+use mercurial_types::{ChangesetId, NodeHash, RepositoryId};
+
+mod schema;
+
+use schema::heads as heads_table;
+
+/// How many connections to MySQL a store keeps by default
+pub const DEFAULT_POOL_SIZE: usize = 4;
+
+/// Connections to a database, each used by one query at a time, so that queries from several
+/// threads don't all wait for a single connection.
+struct ConnectionPool<C> {
+    connections: Vec<Mutex<C>>,
+    next: AtomicUsize,
+}
+
+impl<C> ConnectionPool<C> {
+    fn new(connections: Vec<C>) -> Self {
+        assert!(!connections.is_empty(), "a pool needs at least one connection");
+        Self {
+            connections: connections.into_iter().map(Mutex::new).collect(),
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    /// A connection which isn't in use, or the next one in turn if they all are
+    fn get(&self) -> MutexGuard<C> {
+        let len = self.connections.len();
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        for i in 0..len {
+            if let Ok(connection) = self.connections[(start + i) % len].try_lock() {
+                return connection;
+            }
+        }
+        self.connections[start % len].lock().expect("lock poisoned")
+    }
+}
+
+/// Heads stored in SQLite, which is mostly useful for tests.
+///
+/// The heads of all repos can share a database, each store only seeing the heads of its own.
+pub struct SqliteHeads {
+    repo_id: RepositoryId,
+    // A single connection, as every connection to an in-memory database has a database of its own
+    connections: ConnectionPool<SqliteConnection>,
+}
+
+impl SqliteHeads {
+    /// Open a SQLite database. This is synchronous because the SQLite backend hits local
+    /// disk or memory.
+    pub fn open<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let path = path.as_ref();
+        let conn = SqliteConnection::establish(path)?;
+        Ok(Self {
+            repo_id,
+            connections: ConnectionPool::new(vec![conn]),
+        })
+    }
+
+    /// Create a new SQLite database.
+    pub fn create<P: AsRef<str>>(path: P, repo_id: RepositoryId) -> Result<Self> {
+        let heads = Self::open(path, repo_id)?;
+
+        let up_query = include_str!("../schemas/sqlite-heads.sql");
+        heads.connections.get().batch_execute(&up_query)?;
+
+        Ok(heads)
+    }
+
+    /// Create a new in-memory empty database. Great for tests.
+    pub fn in_memory(repo_id: RepositoryId) -> Result<Self> {
+        Self::create(":memory:", repo_id)
+    }
+}
+
+/// Heads stored in MySQL, so that several servers can share them.
+pub struct MysqlHeads {
+    repo_id: RepositoryId,
+    connections: ConnectionPool<MysqlConnection>,
+}
+
+impl MysqlHeads {
+    pub fn open(params: ConnectionParams, repo_id: RepositoryId) -> Result<Self> {
+        Self::open_with_pool_size(params, repo_id, DEFAULT_POOL_SIZE)
+    }
+
+    /// Open `pool_size` connections to the database, which must be at least one.
+    pub fn open_with_pool_size(
+        params: ConnectionParams,
+        repo_id: RepositoryId,
+        pool_size: usize,
+    ) -> Result<Self> {
+        ensure_msg!(pool_size > 0, "pool size must be positive");
+        let url = params.to_diesel_url()?;
+        let connections = (0..pool_size)
+            .map(|_| MysqlConnection::establish(&url))
+            .collect::<result::Result<Vec<_>, _>>()?;
+        Ok(Self {
+            repo_id,
+            connections: ConnectionPool::new(connections),
+        })
+    }
+
+    /// Create a new test database with an empty heads table, returning the parameters to open it
+    /// with, as many times as needed.
+    pub fn create_test_db<P: AsRef<str>>(prefix: P) -> Result<ConnectionParams> {
+        let params = db::create_test_db(prefix)?;
+        let url = params.to_diesel_url()?;
+        let conn = MysqlConnection::establish(&url)?;
+
+        let up_query = include_str!("../schemas/mysql-heads.sql");
+        conn.batch_execute(&up_query)?;
+
+        Ok(params)
+    }
+}
+
+/// Using a macro here is unfortunate, but it appears to be the only way to share this code
+/// between SQLite and MySQL.
+macro_rules! impl_heads {
+    ($struct: ty) => {
+        impl Heads for $struct {
+            fn add(&self, head: &NodeHash) -> BoxFuture<(), Error> {
+                // TODO: don't block -- send this to another thread
+                let connection = self.connections.get();
+                let result = insert_into(heads_table::table)
+                    .values((
+                        heads_table::repo_id.eq(self.repo_id),
+                        heads_table::head.eq(ChangesetId::new(*head)),
+                    ))
+                    .execute(&*connection);
+                future::result(map_add_result(result)).boxify()
+            }
+
+            fn remove(&self, head: &NodeHash) -> BoxFuture<(), Error> {
+                let connection = self.connections.get();
+                let result = delete(
+                    heads_table::table
+                        .filter(heads_table::repo_id.eq(self.repo_id))
+                        .filter(heads_table::head.eq(ChangesetId::new(*head))),
+                ).execute(&*connection);
+                // Removing a head which isn't one isn't an error.
+                future::result(result.map(|_rows| ()).map_err(Error::from)).boxify()
+            }
+
+            fn is_head(&self, head: &NodeHash) -> BoxFuture<bool, Error> {
+                let connection = self.connections.get();
+                let result = heads_table::table
+                    .filter(heads_table::repo_id.eq(self.repo_id))
+                    .filter(heads_table::head.eq(ChangesetId::new(*head)))
+                    .select(heads_table::head)
+                    .first::<ChangesetId>(&*connection)
+                    .optional();
+                future::result(result.map(|row| row.is_some()).map_err(Error::from)).boxify()
+            }
+
+            fn heads(&self) -> BoxStream<NodeHash, Error> {
+                let connection = self.connections.get();
+                let result = heads_table::table
+                    .filter(heads_table::repo_id.eq(self.repo_id))
+                    .select(heads_table::head)
+                    .load::<ChangesetId>(&*connection);
+                match result {
+                    Ok(rows) => {
+                        stream::iter_ok(rows.into_iter().map(ChangesetId::into_nodehash)).boxify()
+                    }
+                    Err(err) => stream::once(Err(err.into())).boxify(),
+                }
+            }
+
+            // Atomic, in a transaction.
+            fn replace(
+                &self,
+                removed: Vec<NodeHash>,
+                added: Vec<NodeHash>,
+            ) -> BoxFuture<(), Error> {
+                let connection = self.connections.get();
+                let removed: Vec<_> = removed.into_iter().map(ChangesetId::new).collect();
+                let result = connection.transaction::<_, Error, _>(|| {
+                    delete(
+                        heads_table::table
+                            .filter(heads_table::repo_id.eq(self.repo_id))
+                            .filter(heads_table::head.eq_any(&removed)),
+                    ).execute(&*connection)?;
+                    for head in added {
+                        let result = insert_into(heads_table::table)
+                            .values((
+                                heads_table::repo_id.eq(self.repo_id),
+                                heads_table::head.eq(ChangesetId::new(head)),
+                            ))
+                            .execute(&*connection);
+                        map_add_result(result)?;
+                    }
+                    Ok(())
+                });
+                future::result(result).boxify()
+            }
+        }
+    }
+}
+
+impl_heads!(MysqlHeads);
+impl_heads!(SqliteHeads);
+
+// Adding a head which already is one isn't an error.
+#[inline]
+fn map_add_result(result: result::Result<usize, DieselError>) -> Result<()> {
+    match result {
+        Ok(_rows) => Ok(()),
+        Err(DieselError::DatabaseError(DatabaseErrorKind::UniqueViolation, _)) => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
@@ -0,0 +1,20 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The `table!` macro in this module describes the schema for this table in SQL storage
+//! (MySQL or SQLite). This description is *not* the source of truth, so if the schema ever
+//! changes it will need to be updated here as well.
+
+table! {
+    use diesel::sql_types::Integer;
+
+    use mercurial_types::sql_types::NodeHashSql;
+
+    heads (repo_id, head) {
+        repo_id -> Integer,
+        head -> NodeHashSql,
+    }
+}
@@ -26,7 +26,7 @@ use std::sync::Arc;
 
 use failure::{Error, Result, ResultExt};
 use futures::Async;
-use futures::future::{poll_fn, Future, IntoFuture};
+use futures::future::{self, poll_fn, Future, IntoFuture};
 use futures::stream::{self, Stream};
 use futures_cpupool::CpuPool;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
@@ -150,6 +150,21 @@ impl Heads for FileHeads {
             Err(e) => stream::once(Err(e.into())).boxify(),
         }
     }
+
+    // Not atomic: a file per head can't be. All of `added` are made heads before any of `removed`
+    // stops being one, so a concurrent reader may see both, but never neither.
+    fn replace(&self, removed: Vec<NodeHash>, added: Vec<NodeHash>) -> BoxFuture<(), Error> {
+        let adds: Vec<_> = added.iter().map(|head| self.add(head)).collect();
+        let removes: Vec<_> = removed
+            .iter()
+            .filter(|head| !added.contains(head))
+            .map(|head| self.remove(head))
+            .collect();
+        future::join_all(adds)
+            .and_then(move |_| future::join_all(removes))
+            .map(|_| ())
+            .boxify()
+    }
 }
 
 
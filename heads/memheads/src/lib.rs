@@ -59,4 +59,14 @@ impl Heads for MemHeads {
         let heads = (*guard).clone();
         iter_ok(heads).boxify()
     }
+
+    // Atomic, under the lock.
+    fn replace(&self, removed: Vec<NodeHash>, added: Vec<NodeHash>) -> BoxFuture<(), Error> {
+        let mut heads = self.heads.lock().unwrap();
+        for head in removed {
+            heads.remove(&head);
+        }
+        heads.extend(added);
+        ok(()).boxify()
+    }
 }
@@ -25,6 +25,11 @@ pub trait Heads: Send + Sync + 'static {
     fn remove(&self, &NodeHash) -> BoxFuture<(), Error>;
     fn is_head(&self, &NodeHash) -> BoxFuture<bool, Error>;
     fn heads(&self) -> BoxStream<NodeHash, Error>;
+
+    // Stop `removed` being heads and make `added` heads, as a push landing changesets does. How
+    // atomic this is depends on the store, but no store ever leaves both out, so readers never
+    // miss a branch. A head both removed and added stays one.
+    fn replace(&self, removed: Vec<NodeHash>, added: Vec<NodeHash>) -> BoxFuture<(), Error>;
 }
 
 impl Heads for Box<Heads> {
@@ -43,4 +48,8 @@ impl Heads for Box<Heads> {
     fn heads(&self) -> BoxStream<NodeHash, Error> {
         self.as_ref().heads()
     }
+
+    fn replace(&self, removed: Vec<NodeHash>, added: Vec<NodeHash>) -> BoxFuture<(), Error> {
+        self.as_ref().replace(removed, added)
+    }
 }
@@ -11,6 +11,8 @@
 extern crate futures;
 extern crate tempdir;
 
+extern crate db;
+extern crate dbheads;
 extern crate fileheads;
 extern crate heads;
 extern crate memheads;
@@ -20,10 +22,12 @@ extern crate mercurial_types_mocks;
 use futures::{Future, Stream};
 use tempdir::TempDir;
 
+use dbheads::{MysqlHeads, SqliteHeads};
 use fileheads::FileHeads;
 use heads::Heads;
 use memheads::MemHeads;
 use mercurial_types::NodeHash;
+use mercurial_types_mocks::repo::REPO_ZERO;
 
 fn basic<H: Heads>(heads: H) {
     let empty: Vec<NodeHash> = Vec::new();
@@ -56,6 +60,26 @@ fn basic<H: Heads>(heads: H) {
     assert_eq!(heads.heads().collect().wait().unwrap(), empty);
 }
 
+fn replace<H: Heads>(heads: H) {
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+    let baz = mercurial_types_mocks::nodehash::THREES_HASH;
+
+    heads.add(&foo).wait().unwrap();
+    heads.add(&bar).wait().unwrap();
+
+    // Replacing a head which isn't one isn't an error, and a head both removed and added stays
+    // one.
+    heads
+        .replace(vec![foo, bar, baz], vec![bar, baz])
+        .wait()
+        .unwrap();
+
+    let mut result = heads.heads().collect().wait().unwrap();
+    result.sort();
+    assert_eq!(result, vec![bar, baz]);
+}
+
 fn persistence<F, H>(mut new_heads: F)
 where
     F: FnMut() -> H,
@@ -99,6 +123,12 @@ macro_rules! heads_test_impl {
                 basic($new_cb(&state));
             }
 
+            #[test]
+            fn test_replace() {
+                let state = $state;
+                replace($new_cb(&state));
+            }
+
             #[test]
             fn test_save_node_hash() {
                 let state = $state;
@@ -132,3 +162,27 @@ heads_test_impl! {
         persistent: true,
     }
 }
+
+heads_test_impl! {
+    sqliteheads_test => {
+        state: {
+            let dir = TempDir::new("sqliteheads_test").unwrap();
+            let path = dir.path().join("heads.db");
+            SqliteHeads::create(path.to_str().unwrap(), REPO_ZERO).unwrap();
+            dir
+        },
+        new: |dir: &TempDir| {
+            let path = dir.path().join("heads.db");
+            SqliteHeads::open(path.to_str().unwrap(), REPO_ZERO).unwrap()
+        },
+        persistent: true,
+    }
+}
+
+heads_test_impl! {
+    mysqlheads_test => {
+        state: MysqlHeads::create_test_db("mononoke_dbheads").unwrap(),
+        new: |params: &db::ConnectionParams| MysqlHeads::open(params.clone(), REPO_ZERO).unwrap(),
+        persistent: true,
+    }
+}
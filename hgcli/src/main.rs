@@ -38,6 +38,9 @@ fn main() {
     let matches = App::new("Mononoke CLI")
         .about("Provide minimally compatible CLI to Mononoke server")
         .arg(Arg::from_usage("-R, --repository=<REPO> 'repository name'"))
+        .arg(Arg::from_usage(
+            "--mononoke-addr [ADDR] 'address of a Mononoke server hosting several repos'",
+        ))
         .subcommand(
             SubCommand::with_name("serve")
                 .about("start server")
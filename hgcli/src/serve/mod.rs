@@ -4,13 +4,16 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 
 use failure::ResultExt;
-use futures::{future, Future, Sink, Stream};
+use bytes::Bytes;
+use futures::{future, stream, Future, Sink, Stream};
 
+use tokio_core::net::TcpStream;
 use tokio_core::reactor::Core;
-use tokio_io::AsyncRead;
+use tokio_io::{AsyncRead, AsyncWrite};
 use tokio_io::codec::{FramedRead, FramedWrite};
 
 use tokio_uds::UnixStream;
@@ -27,41 +30,68 @@ mod fdio;
 pub fn cmd(main: &ArgMatches, sub: &ArgMatches) -> Result<()> {
     if sub.is_present("stdio") {
         if let Some(repo) = main.value_of("repository") {
+            if let Some(addr) = main.value_of("mononoke-addr") {
+                return tcp_relay(addr, repo);
+            }
+
             let mut path = PathBuf::from(repo);
             path.push(".hg");
             path.push("mononoke.sock");
 
-            return ssh_relay(path);
+            return unix_relay(path);
         }
         bail_msg!("Missing repository");
     }
     bail_msg!("Only stdio server is supported");
 }
 
-fn ssh_relay<P: AsRef<Path>>(path: P) -> Result<()> {
+fn unix_relay<P: AsRef<Path>>(path: P) -> Result<()> {
     let path = path.as_ref();
 
+    let reactor = Core::new()?;
+
+    // Open socket
+    let socket = UnixStream::connect(&path, &reactor.handle()).with_context(|_| {
+        format_err!("connecting to Mononoke socket '{}' failed", path.display())
+    })?;
+
+    ssh_relay(reactor, socket, None)
+}
+
+// A server listening on TCP hosts several repos, so it's first told which one is wanted
+fn tcp_relay(addr: &str, repo: &str) -> Result<()> {
+    let addr: SocketAddr = addr.parse()?;
+
     let mut reactor = Core::new()?;
-    let handle = reactor.handle();
 
+    // Open socket
+    let connect = TcpStream::connect(&addr, &reactor.handle());
+    let socket = reactor
+        .run(connect)
+        .with_context(|_| format_err!("connecting to Mononoke at '{}' failed", addr))?;
+
+    ssh_relay(reactor, socket, Some(repo))
+}
+
+fn ssh_relay<S>(mut reactor: Core, socket: S, preamble: Option<&str>) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite,
+{
     // Get Streams for stdin/out/err
     let stdin = fdio::stdin();
     let stdout = fdio::stdout();
     let stderr = fdio::stderr();
 
-    // Open socket
-    let socket = UnixStream::connect(&path, &handle).with_context(|_| {
-        format_err!("connecting to Mononoke socket '{}' failed", path.display())
-    })?;
-
     // Wrap the socket with the ssh codec
     let (socket_read, socket_write) = socket.split();
     let rx = FramedRead::new(socket_read, SshDecoder::new());
     let tx = FramedWrite::new(socket_write, SshEncoder::new());
 
+    let preamble = preamble.map(|repo| SshMsg::new(SshStream::Preamble, Bytes::from(repo)));
+
     // Start a task to copy from stdin to the socket
-    let stdin_future = stdin
-        .map(|buf| SshMsg::new(SshStream::Stdin, buf))
+    let stdin_future = stream::iter_ok(preamble)
+        .chain(stdin.map(|buf| SshMsg::new(SshStream::Stdin, buf)))
         .forward(tx)
         .map_err(Error::from)
         .map(|_| ());
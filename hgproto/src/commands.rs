@@ -33,8 +33,10 @@ use tokio_io::codec::Decoder;
 use {BranchRes, GetbundleArgs, GettreepackArgs, SingleRequest, SingleResponse};
 
 use errors::*;
+use limits;
 
 const HASH_SIZE: usize = 40;
+const NODE_SIZE: usize = 20;
 
 pub struct HgCommandHandler<H> {
     commands: H,
@@ -73,6 +75,15 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .boxify(),
                 ok(instream).boxify(),
             ),
+            SingleRequest::Bookmarkhistory { key } => (
+                hgcmds
+                    .bookmarkhistory(key)
+                    .map(SingleResponse::Bookmarkhistory)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
             SingleRequest::Branches { nodes } => (
                 hgcmds
                     .branches(nodes)
@@ -140,7 +151,6 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     .getbundle(args)
                     .map(SingleResponse::Getbundle)
                     .map_err(self::Error::into)
-                    .into_stream()
                     .boxify(),
                 ok(instream).boxify(),
             ),
@@ -197,7 +207,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             } => (
                 hgcmds
                     .pushkey(namespace, key, old, new)
-                    .map(|_| SingleResponse::Pushkey)
+                    .map(SingleResponse::Pushkey)
                     .map_err(self::Error::into)
                     .into_stream()
                     .boxify(),
@@ -206,9 +216,8 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
             SingleRequest::Streamout => (
                 hgcmds
                     .stream_out()
-                    .map(|_| SingleResponse::Streamout)
+                    .map(SingleResponse::Streamout)
                     .map_err(self::Error::into)
-                    .into_stream()
                     .boxify(),
                 ok(instream).boxify(),
             ),
@@ -275,6 +284,26 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
                     instream,
                 )
             }
+            SingleRequest::Getfile { file, node } => (
+                hgcmds
+                    .getfile(file, node)
+                    .map(SingleResponse::Getfile)
+                    .map_err(self::Error::into)
+                    .into_stream()
+                    .boxify(),
+                ok(instream).boxify(),
+            ),
+            SingleRequest::Getpackv1 => {
+                let (reqs, instream) = decode_getpackv1_arg_stream(instream);
+                (
+                    hgcmds
+                        .getpackv1(reqs)
+                        .map(SingleResponse::Getpackv1)
+                        .map_err(self::Error::into)
+                        .boxify(),
+                    instream,
+                )
+            }
         }
     }
 
@@ -306,6 +335,7 @@ impl<H: HgCommands + Send + 'static> HgCommandHandler<H> {
 
 const NONE: &[u8] = b"None";
 
+#[derive(Clone)]
 struct GetfilesArgDecoder {}
 
 // Parses one (hash, path) pair
@@ -322,6 +352,10 @@ impl Decoder for GetfilesArgDecoder {
 
         let index = match maybeindex {
             Some(index) => index,
+            None if src.len() > HASH_SIZE + limits::MAX_PATH_LEN => {
+                let msg = format!("getfiles entry of more than {} bytes", src.len());
+                bail_err!(ErrorKind::LimitExceeded(msg));
+            }
             None => {
                 // Need more bytes
                 return Ok(None);
@@ -359,6 +393,57 @@ impl Decoder for GetfilesArgDecoder {
     }
 }
 
+#[derive(Clone)]
+struct Getpackv1ArgDecoder {}
+
+// Parses the nodes requested for one file
+impl Decoder for Getpackv1ArgDecoder {
+    // If None has been decoded, then that means that client has sent all the data
+    type Item = Option<(MPath, Vec<NodeHash>)>;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>> {
+        if src.len() < 2 {
+            // Need more bytes
+            return Ok(None);
+        }
+        let path_len = read_be(&src[..2]);
+        if path_len == 0 {
+            // Finished parsing the stream
+            let _ = src.split_to(2);
+            return Ok(Some(None));
+        }
+
+        let count_offset = 2 + path_len;
+        let nodes_offset = count_offset + 4;
+        if src.len() < nodes_offset {
+            return Ok(None);
+        }
+        let node_count = read_be(&src[count_offset..nodes_offset]);
+        if node_count > limits::MAX_NODES {
+            let msg = format!("getpackv1 request for {} nodes of a file", node_count);
+            bail_err!(ErrorKind::LimitExceeded(msg));
+        }
+        let end = nodes_offset + node_count * NODE_SIZE;
+        if src.len() < end {
+            return Ok(None);
+        }
+
+        let buf = src.split_to(end);
+        let path = MPath::new(&buf[2..count_offset])?;
+        let nodes = buf[nodes_offset..]
+            .chunks(NODE_SIZE)
+            .map(NodeHash::from_bytes)
+            .collect::<Result<_>>()?;
+        Ok(Some(Some((path, nodes))))
+    }
+}
+
+// Reads a big-endian unsigned integer
+fn read_be(buf: &[u8]) -> usize {
+    buf.iter().fold(0, |acc, byte| (acc << 8) | (*byte as usize))
+}
+
 // getfiles args format:
 // (nodepath\n)*\n
 // nodepath := node path
@@ -373,18 +458,49 @@ fn decode_getfiles_arg_stream<S>(
 )
 where
     S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, GetfilesArgDecoder {})
+}
+
+// getpackv1 args format:
+// (filerequest)*<0: u16>
+// filerequest := <path len: u16> path <node count: u32> (node)*
+// node = 20 byte binary hash
+// All integers are big-endian.
+fn decode_getpackv1_arg_stream<S>(
+    input: BytesStream<S>,
+) -> (
+    BoxStream<(MPath, Vec<NodeHash>), Error>,
+    BoxFuture<BytesStream<S>, Error>,
+)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+{
+    decode_arg_stream(input, Getpackv1ArgDecoder {})
+}
+
+// Decodes entries with `decoder` until it returns Some(None), which marks the end of the args,
+// and then hands the rest of the input back.
+fn decode_arg_stream<S, D, T>(
+    input: BytesStream<S>,
+    decoder: D,
+) -> (BoxStream<T, Error>, BoxFuture<BytesStream<S>, Error>)
+where
+    S: Stream<Item = Bytes, Error = io::Error> + Send + 'static,
+    D: Decoder<Item = Option<T>, Error = Error> + Clone + Send + 'static,
+    T: Send + 'static,
 {
     let (send, recv) = oneshot::channel();
 
     // stream::unfold() requires us to to return None if it's finished, or Some(Future) if not.
-    // We can't say if node file stream is finished before we parse the entry, that means that
+    // We can't say if the entry stream is finished before we parse the entry, that means that
     // we can't stop unfolding by returning None. Instead we return a "fake" error. This fake
     // error is a Result. If this fake error is Ok(...) then no real error happened.
     // Note that fake error also contains input stream that will be send back to the future that
     // waits for it.
     let entry_stream: BoxStream<_, ::std::result::Result<BytesStream<S>, (_, BytesStream<S>)>> =
         stream::unfold(input, move |input| {
-            let fut_decode = input.into_future_decode(GetfilesArgDecoder {});
+            let fut_decode = input.into_future_decode(decoder.clone());
             let fut = fut_decode
                 .map_err(|err| Err(err)) // Real error happened, wrap it in result
                 .and_then(|(maybe_item, instream)| match maybe_item {
@@ -394,17 +510,17 @@ where
                             .into_future()
                             .boxify()
                     }
-                    Some(maybe_nodehash) => {
-                        match maybe_nodehash {
+                    Some(maybe_entry) => {
+                        match maybe_entry {
                             None => {
-                                // None here means that we've read all the node-file pairs
+                                // None here means that we've read all the entries
                                 // that client has sent us. Return fake error that means that
                                 // we've successfully parsed the stream.
                                 Err(Ok(instream)).into_future().boxify()
                             }
-                            Some(nodehash) => {
+                            Some(entry) => {
                                 // Parsed one more entry - continue
-                                Ok((nodehash, instream)).into_future().boxify()
+                                Ok((entry, instream)).into_future().boxify()
                             }
                         }
                     }
@@ -515,6 +631,11 @@ pub trait HgCommands {
         unimplemented("between")
     }
 
+    // Not a Mercurial command: the moves of a bookmark, most recent first, one per line
+    fn bookmarkhistory(&self, _key: Bytes) -> HgCommandRes<Bytes> {
+        unimplemented("bookmarkhistory")
+    }
+
     // @wireprotocommand('branchmap')
     fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<NodeHash>>> {
         unimplemented("branchmap")
@@ -547,9 +668,9 @@ pub trait HgCommands {
     }
 
     // @wireprotocommand('getbundle', '*')
-    // TODO: make this streaming
-    fn getbundle(&self, _args: GetbundleArgs) -> HgCommandRes<Bytes> {
-        unimplemented("getbundle")
+    // The bundle is streamed out in chunks as it's encoded
+    fn getbundle(&self, _args: GetbundleArgs) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("getbundle".into()).into())).boxify()
     }
 
     // @wireprotocommand('heads')
@@ -581,17 +702,16 @@ pub trait HgCommands {
     fn pushkey(
         &self,
         _namespace: String,
-        _key: String,
-        _old: NodeHash,
-        _new: NodeHash,
-    ) -> HgCommandRes<()> {
+        _key: Bytes,
+        _old: Bytes,
+        _new: Bytes,
+    ) -> HgCommandRes<bool> {
         unimplemented("pushkey")
     }
 
     // @wireprotocommand('stream_out')
-    fn stream_out(&self) -> HgCommandRes<BoxStream<Vec<u8>, Error>> {
-        // XXX raw streaming?
-        unimplemented("stream_out")
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("stream_out".into()).into())).boxify()
     }
 
     // @wireprotocommand('unbundle', 'heads')
@@ -612,6 +732,20 @@ pub trait HgCommands {
     fn getfiles(&self, _params: BoxStream<(NodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
         once(Err(ErrorKind::Unimplemented("getfiles".into()).into())).boxify()
     }
+
+    // @wireprotocommand('getfile', 'file node')
+    fn getfile(&self, _file: MPath, _node: NodeHash) -> HgCommandRes<Bytes> {
+        unimplemented("getfile")
+    }
+
+    // @wireprotocommand('getpackv1')
+    // The files and nodes requested are read from the input after the command
+    fn getpackv1(
+        &self,
+        _params: BoxStream<(MPath, Vec<NodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        once(Err(ErrorKind::Unimplemented("getpackv1".into()).into())).boxify()
+    }
 }
 
 #[cfg(test)]
@@ -744,4 +878,97 @@ mod test {
         let (paramstream, _input) = decode_getfiles_arg_stream(BytesStream::new(stream::empty()));
         assert!(paramstream.collect().wait().is_err());
     }
+
+    fn getpackv1_request(path: &[u8], nodes: &[NodeHash]) -> Vec<u8> {
+        let mut out = vec![0, path.len() as u8];
+        out.extend_from_slice(path);
+        out.extend_from_slice(&[0, 0, 0, nodes.len() as u8]);
+        for node in nodes {
+            out.extend_from_slice(node.as_ref());
+        }
+        out
+    }
+
+    #[test]
+    fn getpackv1decoder() {
+        let mut decoder = Getpackv1ArgDecoder {};
+        let request = getpackv1_request(b"path", &[hash_ones(), hash_twos()]);
+
+        let mut input = BytesMut::from(request.clone());
+        let res = decoder
+            .decode(&mut input)
+            .expect("unexpected error")
+            .expect("empty result");
+        assert_eq!(
+            Some((MPath::new("path").unwrap(), vec![hash_ones(), hash_twos()])),
+            res
+        );
+        assert!(input.is_empty());
+
+        // Every truncated request needs more bytes
+        for len in 0..request.len() {
+            let mut input = BytesMut::from(&request[..len]);
+            assert!(
+                decoder
+                    .decode(&mut input)
+                    .expect("unexpected error")
+                    .is_none()
+            );
+        }
+
+        let mut input = BytesMut::from(&b"\0\0"[..]);
+        let res = decoder
+            .decode(&mut input)
+            .expect("unexpected error")
+            .expect("empty result");
+        assert_eq!(None, res);
+    }
+
+    #[test]
+    fn getpackv1args() {
+        let mut input = getpackv1_request(b"path", &[hash_ones()]);
+        input.extend(getpackv1_request(b"path2", &[hash_ones(), hash_twos()]));
+        input.extend_from_slice(b"\0\0");
+        let (paramstream, _input) =
+            decode_getpackv1_arg_stream(BytesStream::new(stream::once(Ok(Bytes::from(input)))));
+
+        let res = paramstream.collect().wait().unwrap();
+        assert_eq!(
+            res,
+            vec![
+                (MPath::new("path").unwrap(), vec![hash_ones()]),
+                (MPath::new("path2").unwrap(), vec![hash_ones(), hash_twos()]),
+            ]
+        );
+
+        // Unexpected end of file
+        let (paramstream, _input) =
+            decode_getpackv1_arg_stream(BytesStream::new(stream::empty()));
+        assert!(paramstream.collect().wait().is_err());
+    }
+
+    #[test]
+    fn argdecoders_limits() {
+        // Too many nodes are refused before they're sent
+        let mut input = BytesMut::from(&b"\0\x04path\xff\xff\xff\xff"[..]);
+        match Getpackv1ArgDecoder {}
+            .decode(&mut input)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::LimitExceeded(_)) => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+
+        // A path can't be buffered forever
+        let mut input = BytesMut::from(vec![b'a'; HASH_SIZE + limits::MAX_PATH_LEN + 1]);
+        match GetfilesArgDecoder {}
+            .decode(&mut input)
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::LimitExceeded(_)) => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
 }
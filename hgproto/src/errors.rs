@@ -10,6 +10,8 @@ pub use failure::{Error, Result};
 pub enum ErrorKind {
     #[fail(display = "Unimplemented operation '{}'", _0)] Unimplemented(String),
     #[fail(display = "command parse failed for '{}'", _0)] CommandParse(String),
+    #[fail(display = "unknown command '{}'", _0)] UnknownCommand(String),
+    #[fail(display = "request exceeds a limit: {}", _0)] LimitExceeded(String),
     #[fail(display = "unconsumed data left after parsing '{}'", _0)] UnconsumedData(String),
     #[fail(display = "malformed batch with command '{}'", _0)] BatchInvalid(String),
     #[fail(display = "malformed bundle2 '{}'", _0)] Bundle2Invalid(String),
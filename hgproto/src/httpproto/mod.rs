@@ -0,0 +1,41 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! HTTP protocol
+//!
+//! References are https://www.mercurial-scm.org/wiki/HttpCommandProtocol and
+//! https://www.mercurial-scm.org/repo/hg/file/@/mercurial/help/internals/wireprotocol.txt.
+//!
+//! Each command is a separate HTTP request, `?cmd=<command>`. Its arguments are urlencoded,
+//! either in the query string or split across `X-HgArg-<N>` headers (N counting from 1) if the
+//! server advertises the `httpheader` capability. `unbundle` is a POST whose body is the
+//! bundle.
+//!
+//! Responses are the same as the SSH ones, without the leading `<numbytes> '\n'` as HTTP does
+//! its own framing. Streaming responses are sent as they're produced, using chunked transfer
+//! encoding.
+//!
+//! Rather than duplicating the command parsers, requests are re-encoded the way the SSH protocol
+//! sends them and handled with `sshproto::HgSshCommandDecode`, alongside
+//! `httpproto::HgHttpCommandEncode` for the responses.
+
+use Response;
+use handler::{OutputStream, ResponseEncoder};
+
+pub mod request;
+pub mod response;
+
+/// How long a single `X-HgArg-<N>` header may be, advertised with the `httpheader` capability.
+pub const MAX_HEADER_ARG_LEN: usize = 1024;
+
+#[derive(Clone)]
+pub struct HgHttpCommandEncode;
+
+impl ResponseEncoder for HgHttpCommandEncode {
+    fn encode(&self, response: Response) -> OutputStream {
+        response::encode(response)
+    }
+}
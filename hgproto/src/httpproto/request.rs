@@ -0,0 +1,200 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::io::Write;
+use std::str;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use futures::{stream, Stream};
+use url::percent_encoding::percent_decode;
+
+use errors::*;
+use registry;
+
+/// Decode urlencoded arguments, from either the query string or the concatenated
+/// `X-HgArg-<N>` headers.
+pub fn parse_args(query: &[u8]) -> HashMap<Vec<u8>, Vec<u8>> {
+    fn decode(s: &[u8]) -> Vec<u8> {
+        let s: Vec<u8> = s.iter()
+            .map(|b| if *b == b'+' { b' ' } else { *b })
+            .collect();
+        percent_decode(&s).collect()
+    }
+
+    query
+        .split(|b| *b == b'&')
+        .filter(|arg| !arg.is_empty())
+        .map(|arg| {
+            let mut kv = arg.splitn(2, |b| *b == b'=');
+            let key = kv.next().expect("must have at least 1 element");
+            let val = kv.next().unwrap_or(b"");
+            (decode(key), decode(val))
+        })
+        .collect()
+}
+
+fn write_arg(out: &mut Vec<u8>, key: &[u8], val: &[u8]) {
+    out.extend_from_slice(key);
+    write!(out, " {}\n", val.len()).expect("write to vec failed");
+    out.extend_from_slice(val);
+}
+
+/// Encode a command and its arguments the way the SSH protocol sends them, so that the request
+/// can be parsed by `sshproto::HgSshCommandDecode`. Arguments which the command doesn't take are
+/// ignored, like Mercurial does.
+pub fn encode_request(cmd: &str, mut args: HashMap<Vec<u8>, Vec<u8>>) -> Result<Bytes> {
+    let spec = registry::command(cmd)?;
+
+    let mut out = Vec::new();
+    write!(out, "{}\n", cmd).expect("write to vec failed");
+    for key in spec.args {
+        match args.remove(key.as_bytes()) {
+            Some(val) => write_arg(&mut out, key.as_bytes(), &val),
+            None => bail_err!(ErrorKind::CommandParse(format!(
+                "{}: missing argument '{}'",
+                cmd, key
+            ))),
+        }
+    }
+    if spec.star {
+        write!(out, "* {}\n", args.len()).expect("write to vec failed");
+        for (key, val) in args {
+            write_arg(&mut out, &key, &val);
+        }
+    }
+
+    Ok(Bytes::from(out))
+}
+
+/// Chunk the body of an `unbundle` request, which is sent as is over HTTP, like the SSH
+/// protocol does. See `hgproto/dechunker.rs` for the format.
+pub fn chunk_stream<S>(body: S) -> impl Stream<Item = Bytes, Error = S::Error>
+where
+    S: Stream<Item = Bytes>,
+{
+    body.filter(|chunk| !chunk.is_empty())
+        .map(|chunk| {
+            let mut out = BytesMut::with_capacity(10 + chunk.len());
+            out.put_slice(format!("{}\n", chunk.len()).as_bytes());
+            out.put(chunk);
+            out.freeze()
+        })
+        .chain(stream::once(Ok(Bytes::from(&b"0\n"[..]))))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use bytes::BytesMut;
+    use futures::Future;
+    use futures::stream::iter_ok;
+
+    use mercurial_types::NodeHash;
+
+    use {GetbundleArgs, Request, SingleRequest};
+    use sshproto::request::parse_request;
+
+    fn parse(cmd: &str, query: &[u8]) -> Request {
+        let encoded = encode_request(cmd, parse_args(query)).unwrap();
+        let mut buf = BytesMut::from(encoded);
+        let req = parse_request(&mut buf).unwrap().unwrap();
+        assert!(buf.is_empty(), "unconsumed input {:?}", buf);
+        req
+    }
+
+    fn hash_ones() -> NodeHash {
+        "1111111111111111111111111111111111111111".parse().unwrap()
+    }
+
+    fn hash_twos() -> NodeHash {
+        "2222222222222222222222222222222222222222".parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(
+            parse_args(b"cmd=listkeys&namespace=book%2Dmarks&empty=&flag&space=a+b"),
+            hashmap! {
+                b"cmd".to_vec() => b"listkeys".to_vec(),
+                b"namespace".to_vec() => b"book-marks".to_vec(),
+                b"empty".to_vec() => b"".to_vec(),
+                b"flag".to_vec() => b"".to_vec(),
+                b"space".to_vec() => b"a b".to_vec(),
+            }
+        );
+        assert_eq!(parse_args(b""), hashmap!{});
+    }
+
+    #[test]
+    fn test_encode_request() {
+        assert_eq!(
+            parse("heads", b"unused=1"),
+            Request::Single(SingleRequest::Heads)
+        );
+
+        assert_eq!(
+            parse(
+                "known",
+                b"nodes=1111111111111111111111111111111111111111+\
+                  2222222222222222222222222222222222222222",
+            ),
+            Request::Single(SingleRequest::Known {
+                nodes: vec![hash_ones(), hash_twos()],
+            })
+        );
+
+        assert_eq!(
+            parse(
+                "getbundle",
+                b"heads=1111111111111111111111111111111111111111&\
+                  common=2222222222222222222222222222222222222222&\
+                  bundlecaps=HG20%2Cbundle2%3DHG20&phases=1",
+            ),
+            Request::Single(SingleRequest::Getbundle(GetbundleArgs {
+                heads: vec![hash_ones()],
+                common: vec![hash_twos()],
+                bundlecaps: vec![b"HG20".to_vec(), b"bundle2=HG20".to_vec()],
+                listkeys: vec![],
+                phases: true,
+                obsmarkers: false,
+                cg: true,
+            }))
+        );
+
+        assert_eq!(
+            parse("batch", b"cmds=heads+%3Bknown+nodes%3D"),
+            Request::Batch(vec![
+                SingleRequest::Heads,
+                SingleRequest::Known { nodes: vec![] },
+            ])
+        );
+    }
+
+    #[test]
+    fn test_encode_request_errors() {
+        match encode_request("frobnicate", hashmap!{})
+            .unwrap_err()
+            .downcast::<ErrorKind>()
+        {
+            Ok(ErrorKind::UnknownCommand(ref cmd)) if cmd == "frobnicate" => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+        assert!(encode_request("lookup", hashmap!{}).is_err());
+    }
+
+    #[test]
+    fn test_chunk_stream() {
+        let body = iter_ok::<_, ()>(vec![
+            Bytes::from(&b"abc"[..]),
+            Bytes::new(),
+            Bytes::from(&b"de"[..]),
+        ]);
+        let chunks = chunk_stream(body).concat2().wait().unwrap();
+        assert_eq!(chunks, Bytes::from(&b"3\nabc2\nde0\n"[..]));
+    }
+}
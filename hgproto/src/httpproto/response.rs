@@ -0,0 +1,74 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use bytes::Bytes;
+use futures::stream;
+use futures_ext::StreamExt;
+
+use {batch, Response, SingleResponse};
+use handler::OutputStream;
+use sshproto::response::encode_cmd;
+
+use super::MAX_HEADER_ARG_LEN;
+
+pub fn encode(response: Response) -> OutputStream {
+    let out = match response {
+        Response::Batch(ref resps) => {
+            let escaped_results: Vec<_> = resps
+                .iter()
+                .map(|resp| batch::escape(&encode_cmd(resp)))
+                .collect();
+            Bytes::from(escaped_results.join(&b';'))
+        }
+        // The request body is available as soon as the request is, so there's nothing to
+        // acknowledge.
+        Response::Single(SingleResponse::ReadyForStream) => return stream::empty().boxify(),
+        // Arguments can only be passed in headers over HTTP
+        Response::Single(SingleResponse::Capabilities(mut caps)) => {
+            caps.push(format!("httpheader={}", MAX_HEADER_ARG_LEN));
+            encode_cmd(&SingleResponse::Capabilities(caps))
+        }
+        Response::Single(ref resp) => encode_cmd(resp),
+    };
+    stream::once(Ok(out)).boxify()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::{Future, Stream};
+
+    fn encoded(response: Response) -> Bytes {
+        encode(response).concat2().wait().unwrap()
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encoded(Response::Single(SingleResponse::Known(vec![true, false]))),
+            Bytes::from(&b"10"[..])
+        );
+        assert_eq!(
+            encoded(Response::Single(SingleResponse::ReadyForStream)),
+            Bytes::new()
+        );
+        assert_eq!(
+            encoded(Response::Single(SingleResponse::Capabilities(vec![
+                "lookup".to_string(),
+                "known".to_string(),
+            ]))),
+            Bytes::from(&b"lookup known httpheader=1024"[..])
+        );
+        assert_eq!(
+            encoded(Response::Batch(vec![
+                SingleResponse::Known(vec![true]),
+                SingleResponse::Pushkey(false),
+            ])),
+            Bytes::from(&b"1;0\n"[..])
+        );
+    }
+}
@@ -37,6 +37,7 @@ extern crate mercurial;
 extern crate mercurial_bundles;
 extern crate mercurial_types;
 extern crate revset;
+extern crate url;
 
 // QuickCheck for randomized testing.
 #[cfg(test)]
@@ -48,13 +49,16 @@ use std::fmt::{self, Debug};
 
 use bytes::Bytes;
 
-use mercurial_types::NodeHash;
+use mercurial_types::{MPath, NodeHash};
 
 mod batch;
 mod dechunker;
 mod errors;
 mod handler;
 mod commands;
+pub mod httpproto;
+pub mod limits;
+pub mod registry;
 pub mod sshproto;
 
 // result from `branches()`
@@ -77,6 +81,9 @@ pub enum SingleRequest {
     Between {
         pairs: Vec<(NodeHash, NodeHash)>,
     },
+    Bookmarkhistory {
+        key: Bytes,
+    },
     Branchmap,
     Branches {
         nodes: Vec<NodeHash>,
@@ -109,9 +116,9 @@ pub enum SingleRequest {
     },
     Pushkey {
         namespace: String,
-        key: String,
-        old: NodeHash,
-        new: NodeHash,
+        key: Bytes,
+        old: Bytes,
+        new: Bytes,
     },
     Streamout,
     Unbundle {
@@ -119,6 +126,11 @@ pub enum SingleRequest {
     },
     Gettreepack(GettreepackArgs),
     Getfiles,
+    Getfile {
+        file: MPath,
+        node: NodeHash,
+    },
+    Getpackv1,
 }
 
 /// The arguments that `getbundle` accepts, in a separate struct for
@@ -129,6 +141,12 @@ pub struct GetbundleArgs {
     pub common: Vec<NodeHash>,
     pub bundlecaps: Vec<Vec<u8>>,
     pub listkeys: Vec<Vec<u8>>,
+    /// Whether to send the heads of each phase
+    pub phases: bool,
+    /// Whether to send obsolescence markers
+    pub obsmarkers: bool,
+    /// Whether to send the changegroup, which clients only wanting listkeys or phases don't
+    pub cg: bool,
 }
 
 impl Debug for GetbundleArgs {
@@ -146,6 +164,9 @@ impl Debug for GetbundleArgs {
             .field("common", &self.common)
             .field("bundlecaps", &bcaps)
             .field("listkeys", &listkeys)
+            .field("phases", &self.phases)
+            .field("obsmarkers", &self.obsmarkers)
+            .field("cg", &self.cg)
             .finish()
     }
 }
@@ -175,6 +196,7 @@ pub enum Response {
 #[derive(Debug)]
 pub enum SingleResponse {
     Between(Vec<Vec<NodeHash>>),
+    Bookmarkhistory(Bytes),
     Branchmap(HashMap<String, HashSet<NodeHash>>),
     Branches(Vec<BranchRes>),
     Clonebundles(String),
@@ -188,12 +210,14 @@ pub enum SingleResponse {
     Listkeys(HashMap<Vec<u8>, Vec<u8>>),
     Lookup(Bytes),
     Known(Vec<bool>),
-    Pushkey,
-    Streamout, /* (BoxStream<Vec<u8>, Error>) */
+    Pushkey(bool),
+    Streamout(Bytes),
     ReadyForStream,
     Unbundle(Bytes),
     Gettreepack(Bytes),
     Getfiles(Bytes),
+    Getfile(Bytes),
+    Getpackv1(Bytes),
 }
 
 impl SingleResponse {
@@ -206,6 +230,8 @@ impl SingleResponse {
             &ReadyForStream => true,
             &Unbundle(_) => true,
             &Gettreepack(_) => true,
+            &Getpackv1(_) => true,
+            &Streamout(_) => true,
             _ => false,
         }
     }
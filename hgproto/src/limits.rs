@@ -0,0 +1,34 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Limits on requests, so that a client can't make the server buffer or allocate unbounded
+//! amounts of memory with a crafted command.
+//!
+//! Requests are buffered until they're complete, so a request which isn't complete after
+//! `MAX_REQUEST_LEN` bytes fails with `ErrorKind::LimitExceeded`. The parser rejects the other
+//! limits as soon as it sees the count or length breaking them, before reading what follows,
+//! which fails the request with `ErrorKind::CommandParse`.
+
+/// Number of arguments of a "*" argument, and of commands in a batch
+pub const MAX_ARGS: usize = 1024;
+
+/// Length of the value of an argument
+pub const MAX_ARG_LEN: usize = 32 * 1024 * 1024;
+
+/// Length of a request, without the streamed arguments of commands like `unbundle`
+pub const MAX_REQUEST_LEN: usize = 64 * 1024 * 1024;
+
+/// Number of nodes, or pairs of nodes, in the argument of a command like `known` or `between`
+pub const MAX_NODES: usize = 100_000;
+
+/// Length of the name of a command or argument
+pub const MAX_NAME_LEN: usize = 256;
+
+/// Number of digits of a length or a count, which is enough for any `usize`
+pub const MAX_DIGITS: usize = 20;
+
+/// Length of a path in the streamed arguments of `getfiles` and `getpackv1`
+pub const MAX_PATH_LEN: usize = 0xffff;
@@ -0,0 +1,225 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Registry of the commands of the wire protocol
+//!
+//! Each command declares its arguments, like Mercurial's command table, and the capability a
+//! server advertises when it implements the command, if clients need one to use it. Requests
+//! for commands which aren't registered fail with `ErrorKind::UnknownCommand`.
+
+use errors::*;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CommandSpec {
+    pub name: &'static str,
+    /// The named arguments of the command
+    pub args: &'static [&'static str],
+    /// Whether the command accepts other arguments too ("*")
+    pub star: bool,
+    /// The capability advertising the command, with its value if it has one
+    pub capability: Option<&'static str>,
+}
+
+/// The commands, in alphabetical order. `stream_out` is advertised as `stream` or `streamreqs`
+/// depending on the store of the repo, so servers do it themselves.
+pub static COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "batch",
+        args: &["cmds"],
+        star: true,
+        capability: Some("batch"),
+    },
+    CommandSpec {
+        name: "between",
+        args: &["pairs"],
+        star: false,
+        capability: None,
+    },
+    // Not a Mercurial command: the moves of a bookmark, most recent first, one per line
+    CommandSpec {
+        name: "bookmarkhistory",
+        args: &["key"],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "branchmap",
+        args: &[],
+        star: false,
+        capability: Some("branchmap"),
+    },
+    CommandSpec {
+        name: "branches",
+        args: &["nodes"],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "capabilities",
+        args: &[],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "changegroup",
+        args: &["roots"],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "changegroupsubset",
+        args: &["bases", "heads"],
+        star: false,
+        capability: Some("changegroupsubset"),
+    },
+    CommandSpec {
+        name: "clonebundles",
+        args: &[],
+        star: false,
+        capability: Some("clonebundles"),
+    },
+    CommandSpec {
+        name: "debugwireargs",
+        args: &["one", "two"],
+        star: true,
+        capability: None,
+    },
+    CommandSpec {
+        name: "getbundle",
+        args: &[],
+        star: true,
+        capability: Some("getbundle"),
+    },
+    CommandSpec {
+        name: "getfile",
+        args: &["file", "node"],
+        star: false,
+        capability: Some("getfile"),
+    },
+    CommandSpec {
+        name: "getfiles",
+        args: &[],
+        star: false,
+        capability: Some("remotefilelog"),
+    },
+    CommandSpec {
+        name: "getpackv1",
+        args: &[],
+        star: false,
+        capability: Some("remotefilelog"),
+    },
+    CommandSpec {
+        name: "gettreepack",
+        args: &[],
+        star: true,
+        capability: Some("gettreepack"),
+    },
+    CommandSpec {
+        name: "heads",
+        args: &[],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "hello",
+        args: &[],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "known",
+        args: &["nodes"],
+        star: true,
+        capability: Some("known"),
+    },
+    CommandSpec {
+        name: "listkeys",
+        args: &["namespace"],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "lookup",
+        args: &["key"],
+        star: false,
+        capability: Some("lookup"),
+    },
+    CommandSpec {
+        name: "pushkey",
+        args: &["namespace", "key", "old", "new"],
+        star: false,
+        capability: Some("pushkey"),
+    },
+    CommandSpec {
+        name: "stream_out",
+        args: &[],
+        star: false,
+        capability: None,
+    },
+    CommandSpec {
+        name: "unbundle",
+        args: &["heads"],
+        star: false,
+        capability: Some("unbundle=HG10GZ,HG10BZ,HG10UN"),
+    },
+];
+
+/// Look up a command by name.
+pub fn command(name: &str) -> Result<&'static CommandSpec> {
+    match COMMANDS.iter().find(|spec| spec.name == name) {
+        Some(spec) => Ok(spec),
+        None => bail_err!(ErrorKind::UnknownCommand(name.into())),
+    }
+}
+
+/// The capabilities to advertise for the commands a server implements, in the order of the
+/// commands and without duplicates.
+pub fn capabilities<'a, I>(commands: I) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = &'a str>,
+{
+    let mut caps: Vec<String> = Vec::new();
+    for name in commands {
+        if let Some(cap) = command(name)?.capability {
+            if !caps.iter().any(|c| c == cap) {
+                caps.push(cap.to_string());
+            }
+        }
+    }
+    Ok(caps)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_command() {
+        assert_eq!(command("known").unwrap().args, &["nodes"]);
+        assert!(command("known").unwrap().star);
+        match command("frobnicate").unwrap_err().downcast::<ErrorKind>() {
+            Ok(ErrorKind::UnknownCommand(ref name)) if name == "frobnicate" => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    #[test]
+    fn test_commands_sorted() {
+        for pair in COMMANDS.windows(2) {
+            assert!(pair[0].name < pair[1].name, "{} is out of order", pair[1].name);
+        }
+    }
+
+    #[test]
+    fn test_capabilities() {
+        let caps = capabilities(vec!["heads", "getfiles", "unbundle", "getpackv1", "lookup"]);
+        assert_eq!(
+            caps.unwrap(),
+            vec!["remotefilelog", "unbundle=HG10GZ,HG10BZ,HG10UN", "lookup"]
+        );
+        assert!(capabilities(vec!["lookup", "frobnicate"]).is_err());
+    }
+}
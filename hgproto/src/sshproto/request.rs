@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use std::cmp;
 use std::collections::HashMap;
 use std::iter;
 use std::str::{self, FromStr};
@@ -11,17 +12,24 @@ use std::str::{self, FromStr};
 use bytes::{Bytes, BytesMut};
 use nom::{is_alphanumeric, is_digit, ErrorKind, FindSubstring, IResult, Needed, Slice};
 
-use mercurial_types::NodeHash;
+use mercurial_types::{MPath, NodeHash};
 
 use {GetbundleArgs, GettreepackArgs, Request, SingleRequest};
 use batch;
 use errors;
 use errors::*;
+use limits;
+use registry;
 
 const BAD_UTF8_ERR_CODE: u32 = 111;
+const BAD_PATH_ERR_CODE: u32 = 112;
+const LIMIT_ERR_CODE: u32 = 113;
+
+/// How much of a request that failed to parse goes in the error
+const MAX_ERROR_REQUEST_LEN: usize = 1024;
 
 /// Parse an unsigned decimal integer. If it reaches the end of input, it returns Incomplete,
-/// as there may be more digits following
+/// as there may be more digits following, unless there are already too many of them.
 fn digit<F: Fn(u8) -> bool>(input: &[u8], isdigit: F) -> IResult<&[u8], &[u8]> {
     for (idx, item) in input.iter().enumerate() {
         if !isdigit(*item) {
@@ -31,6 +39,9 @@ fn digit<F: Fn(u8) -> bool>(input: &[u8], isdigit: F) -> IResult<&[u8], &[u8]> {
                 return IResult::Done(&input[idx..], &input[0..idx]);
             }
         }
+        if idx >= limits::MAX_DIGITS {
+            return IResult::Error(ErrorKind::Custom(LIMIT_ERR_CODE));
+        }
     }
     IResult::Incomplete(Needed::Unknown)
 }
@@ -43,10 +54,22 @@ named!(
     )
 );
 
+/// Parse an integer which can't be more than `max`, e.g. a length that would otherwise make the
+/// parser wait for that many bytes.
+fn bounded_integer(input: &[u8], max: usize) -> IResult<&[u8], usize> {
+    match integer(input) {
+        IResult::Done(_, val) if val > max => IResult::Error(ErrorKind::Custom(LIMIT_ERR_CODE)),
+        other => other,
+    }
+}
+
 /// Return an identifier of the form [a-zA-Z_][a-zA-Z0-9_]*. Returns Incomplete
 /// if it manages to reach the end of input, as there may be more identifier coming.
 fn ident(input: &[u8]) -> IResult<&[u8], &[u8]> {
     for (idx, item) in input.iter().enumerate() {
+        if idx >= limits::MAX_NAME_LEN {
+            return IResult::Error(ErrorKind::Custom(LIMIT_ERR_CODE));
+        }
         match *item as char {
             'a'...'z' | 'A'...'Z' | '_' => continue,
             '0'...'9' if idx > 0 => continue,
@@ -86,7 +109,10 @@ named!(
 /// but I don't know if that ever happens in practice.)
 named!(
     param_star<HashMap<Vec<u8>, Vec<u8>>>,
-    do_parse!(tag!(b"* ") >> count: integer >> tag!(b"\n") >> res: apply!(params, count) >> (res))
+    do_parse!(
+        tag!(b"* ") >> count: apply!(bounded_integer, limits::MAX_ARGS) >> tag!(b"\n")
+            >> res: apply!(params, count) >> (res)
+    )
 );
 
 /// List of comma-separated values, each of which is encoded using batch param encoding.
@@ -102,7 +128,8 @@ named!(
 named!(
     param_kv<HashMap<Vec<u8>, Vec<u8>>>,
     do_parse!(
-        key: ident >> tag!(b" ") >> len: integer >> tag!(b"\n") >> val: take!(len)
+        key: ident >> tag!(b" ") >> len: apply!(bounded_integer, limits::MAX_ARG_LEN)
+            >> tag!(b"\n") >> val: take!(len)
             >> (iter::once((key.to_vec(), val.to_vec())).collect())
     )
 );
@@ -177,17 +204,37 @@ named!(
 );
 
 /// A space-separated list of pairs.
+fn pairlist(input: &[u8]) -> IResult<&[u8], Vec<(NodeHash, NodeHash)>> {
+    bounded_list(input, pairs)
+}
+
 named!(
-    pairlist<Vec<(NodeHash, NodeHash)>>,
+    pairs<Vec<(NodeHash, NodeHash)>>,
     separated_list_complete!(tag!(" "), pair)
 );
 
 /// A space-separated list of node hashes
+fn hashlist(input: &[u8]) -> IResult<&[u8], Vec<NodeHash>> {
+    bounded_list(input, nodehashes)
+}
+
 named!(
-    hashlist<Vec<NodeHash>>,
+    nodehashes<Vec<NodeHash>>,
     separated_list_complete!(tag!(" "), nodehash)
 );
 
+/// Apply a parser of a space-separated list, unless the list has more than `MAX_NODES` items.
+fn bounded_list<T>(
+    input: &[u8],
+    parser: fn(&[u8]) -> IResult<&[u8], Vec<T>>,
+) -> IResult<&[u8], Vec<T>> {
+    if input.iter().filter(|b| **b == b' ').count() >= limits::MAX_NODES {
+        IResult::Error(ErrorKind::Custom(LIMIT_ERR_CODE))
+    } else {
+        parser(input)
+    }
+}
+
 /// A space-separated list of strings
 named!(
     stringlist<Vec<String>>,
@@ -218,6 +265,12 @@ fn commavalues(input: &[u8]) -> IResult<&[u8], Vec<Vec<u8>>> {
     }
 }
 
+/// A boolean is encoded as "1" or "0". Like Mercurial, treat any value other than "0" and the
+/// empty string as true. The input is assumed to be complete and exact.
+fn boolean(input: &[u8]) -> IResult<&[u8], bool> {
+    IResult::Done(b"", !(input.is_empty() || input == b"0"))
+}
+
 fn notsemi(b: u8) -> bool {
     b != b';'
 }
@@ -340,6 +393,15 @@ fn bytes_complete(inp: &[u8]) -> IResult<&[u8], Bytes> {
     IResult::Done(b"", res)
 }
 
+/// Parse a non-empty repo path, assumes that input is complete
+fn mpath_complete(inp: &[u8]) -> IResult<&[u8], MPath> {
+    match MPath::new(inp) {
+        Ok(ref path) if path.is_empty() => IResult::Error(ErrorKind::Custom(BAD_PATH_ERR_CODE)),
+        Ok(path) => IResult::Done(b"", path),
+        Err(_) => IResult::Error(ErrorKind::Custom(BAD_PATH_ERR_CODE)),
+    }
+}
+
 macro_rules! replace_expr {
     ($_t:tt $sub:expr) => {$sub};
 }
@@ -402,6 +464,9 @@ fn parse_batchrequest(inp: &[u8]) -> IResult<&[u8], Vec<SingleRequest>> {
             cmds => cmdlist,
         })
     );
+    if batch.cmds.len() > limits::MAX_ARGS {
+        return IResult::Error(ErrorKind::Custom(LIMIT_ERR_CODE));
+    }
 
     let mut parsed_cmds = Vec::with_capacity(batch.cmds.len());
     for cmd in batch.cmds {
@@ -423,11 +488,22 @@ pub fn parse_request(buf: &mut BytesMut) -> Result<Option<Request>> {
 
         match parse_res {
             IResult::Done(rest, val) => Some((origlen - rest.len(), val)),
-            IResult::Incomplete(_) => None,
+            IResult::Incomplete(_) => {
+                if buf.len() > limits::MAX_REQUEST_LEN {
+                    let msg = format!("incomplete request after {} bytes", buf.len());
+                    bail_err!(errors::ErrorKind::LimitExceeded(msg));
+                }
+                None
+            }
             IResult::Error(err) => {
                 println!("{:?}", err);
+                // Tell unregistered commands apart from registered ones with bad arguments
+                let name = buf.split(|b| *b == b'\n').next().unwrap_or(b"");
+                registry::command(&String::from_utf8_lossy(name))?;
+                // The request may be up to MAX_REQUEST_LEN long
+                let request = &buf[..cmp::min(buf.len(), MAX_ERROR_REQUEST_LEN)];
                 Err(errors::ErrorKind::CommandParse(
-                    String::from_utf8_lossy(buf.as_ref()).into_owned(),
+                    String::from_utf8_lossy(request).into_owned(),
                 ))?
             }
         }
@@ -453,6 +529,9 @@ fn parse_with_params(
           command!("between", Between, parse_params, {
               pairs => pairlist,
           })
+        | command!("bookmarkhistory", Bookmarkhistory, parse_params, {
+              key => bytes_complete,
+          })
         | command!("branchmap", Branchmap, parse_params, {})
         | command!("branches", Branches, parse_params, {
               nodes => hashlist,
@@ -475,14 +554,16 @@ fn parse_with_params(
         | call!(parse_command, "getbundle", parse_params, 0+1,
             |kv| Ok(Getbundle(GetbundleArgs {
                 // Some params are currently ignored, like:
-                // - obsmarkers
-                // - cg
                 // - cbattempted
                 // If those params are needed, they should be parsed here.
                 heads: parseval_default(&kv, "heads", hashlist)?,
                 common: parseval_default(&kv, "common", hashlist)?,
                 bundlecaps: parseval_default(&kv, "bundlecaps", commavalues)?,
                 listkeys: parseval_default(&kv, "listkeys", commavalues)?,
+                phases: parseval_default(&kv, "phases", boolean)?,
+                obsmarkers: parseval_default(&kv, "obsmarkers", boolean)?,
+                // The changegroup is sent unless the client says otherwise
+                cg: !kv.contains_key(&b"cg"[..]) || parseval(&kv, "cg", boolean)?,
             })))
         | command!("heads", Heads, parse_params, {})
         | command!("hello", Hello, parse_params, {})
@@ -497,11 +578,11 @@ fn parse_with_params(
           })
         | command!("pushkey", Pushkey, parse_params, {
               namespace => ident_string,
-              key => ident_string,
-              old => nodehash,
-              new => nodehash,
+              key => bytes_complete,
+              old => bytes_complete,
+              new => bytes_complete,
           })
-        | command!("streamout", Streamout, parse_params, {})
+        | command!("stream_out", Streamout, parse_params, {})
         | command!("unbundle", Unbundle, parse_params, {
               heads => stringlist,
           })
@@ -513,6 +594,11 @@ fn parse_with_params(
                 directories: parseval(&kv, "directories", gettreepack_directories)?,
             })))
         | command!("getfiles", Getfiles, parse_params, {})
+        | command!("getfile", Getfile, parse_params, {
+              file => mpath_complete,
+              node => nodehash,
+          })
+        | command!("getpackv1", Getpackv1, parse_params, {})
     )
 }
 
@@ -1245,13 +1331,16 @@ mod test_parse {
                 common: vec![],
                 bundlecaps: vec![],
                 listkeys: vec![],
+                phases: false,
+                obsmarkers: false,
+                cg: true,
             })),
         );
 
         // with arguments
         let inp =
             "getbundle\n\
-             * 5\n\
+             * 8\n\
              heads 40\n\
              1111111111111111111111111111111111111111\
              common 81\n\
@@ -1260,6 +1349,12 @@ mod test_parse {
              cap1,CAP2,cap3\
              listkeys 9\n\
              key1,key2\
+             phases 1\n\
+             1\
+             obsmarkers 1\n\
+             0\
+             cg 1\n\
+             0\
              extra 5\n\
              extra";
         test_parse(
@@ -1269,6 +1364,9 @@ mod test_parse {
                 common: vec![hash_twos(), hash_threes()],
                 bundlecaps: vec![b"cap1".to_vec(), b"CAP2".to_vec(), b"cap3".to_vec()],
                 listkeys: vec![b"key1".to_vec(), b"key2".to_vec()],
+                phases: true,
+                obsmarkers: false,
+                cg: false,
             })),
         );
     }
@@ -1373,6 +1471,38 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_getfile() {
+        let inp = "getfile\n\
+                   file 7\n\
+                   foo/bar\
+                   node 40\n\
+                   1111111111111111111111111111111111111111";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Getfile {
+                file: MPath::new("foo/bar").unwrap(),
+                node: hash_ones(),
+            }),
+        );
+
+        let inp = "getfile\n\
+                   file 0\n\
+                   node 40\n\
+                   1111111111111111111111111111111111111111";
+
+        let mut buf = BytesMut::from(inp.as_bytes());
+        assert!(parse_request(&mut buf).is_err());
+    }
+
+    #[test]
+    fn test_parse_getpackv1() {
+        let inp = "getpackv1\n";
+
+        test_parse(inp, Request::Single(SingleRequest::Getpackv1));
+    }
+
     #[test]
     fn test_parse_known_1() {
         let inp = "known\n\
@@ -1397,6 +1527,20 @@ mod test_parse {
         test_parse(inp, Request::Single(SingleRequest::Known { nodes: vec![] }));
     }
 
+    #[test]
+    fn test_parse_bookmarkhistory() {
+        let inp = "bookmarkhistory\n\
+                   key 11\n\
+                   feature/foo";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Bookmarkhistory {
+                key: Bytes::from("feature/foo"),
+            }),
+        );
+    }
+
     #[test]
     fn test_parse_pushkey() {
         let inp = "pushkey\n\
@@ -1413,16 +1557,36 @@ mod test_parse {
             inp,
             Request::Single(SingleRequest::Pushkey {
                 namespace: "bookmarks".to_string(),
-                key: "foobar".to_string(),
-                old: hash_ones(),
-                new: hash_twos(),
+                key: Bytes::from("foobar"),
+                old: Bytes::from("1111111111111111111111111111111111111111"),
+                new: Bytes::from("2222222222222222222222222222222222222222"),
+            }),
+        );
+
+        // Creating a bookmark, whose name isn't an identifier
+        let inp = "pushkey\n\
+                   namespace 9\n\
+                   bookmarks\
+                   key 11\n\
+                   feature/foo\
+                   old 0\n\
+                   new 40\n\
+                   2222222222222222222222222222222222222222";
+
+        test_parse(
+            inp,
+            Request::Single(SingleRequest::Pushkey {
+                namespace: "bookmarks".to_string(),
+                key: Bytes::from("feature/foo"),
+                old: Bytes::new(),
+                new: Bytes::from("2222222222222222222222222222222222222222"),
             }),
         );
     }
 
     #[test]
     fn test_parse_streamout() {
-        let inp = "streamout\n";
+        let inp = "stream_out\n";
 
         test_parse(inp, Request::Single(SingleRequest::Streamout {}));
     }
@@ -1489,4 +1653,55 @@ mod test_parse {
         );
     }
 
+    #[test]
+    fn test_parse_unknown_command() {
+        let mut buf = BytesMut::from(&b"frobnicate\n"[..]);
+        match parse_request(&mut buf).unwrap_err().downcast::<errors::ErrorKind>() {
+            Ok(errors::ErrorKind::UnknownCommand(ref cmd)) if cmd == "frobnicate" => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+
+        let mut buf = BytesMut::from(&b"between\npairs 3\nabc"[..]);
+        match parse_request(&mut buf).unwrap_err().downcast::<errors::ErrorKind>() {
+            Ok(errors::ErrorKind::CommandParse(_)) => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    fn assert_parse_error(inp: &[u8]) {
+        let mut buf = BytesMut::from(inp);
+        match parse_request(&mut buf).map_err(|err| err.downcast::<errors::ErrorKind>()) {
+            Err(Ok(errors::ErrorKind::CommandParse(ref request))) => {
+                assert!(request.len() <= MAX_ERROR_REQUEST_LEN)
+            }
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
+
+    #[test]
+    fn test_parse_limits() {
+        // Lengths and counts beyond the limits fail before the data they announce is sent
+        assert_parse_error(format!("lookup\nkey {}\n", limits::MAX_ARG_LEN + 1).as_bytes());
+        assert_parse_error(format!("known\n* {}\n", limits::MAX_ARGS + 1).as_bytes());
+        assert_parse_error(b"lookup\nkey 123456789012345678901");
+        let name = "a".repeat(limits::MAX_NAME_LEN + 1);
+        assert_parse_error(format!("known\n* 1\n{}", name).as_bytes());
+
+        let nodes = vec!["1111111111111111111111111111111111111111"; limits::MAX_NODES + 1];
+        let nodes = nodes.join(" ");
+        assert_parse_error(format!("known\n* 0\nnodes {}\n{}", nodes.len(), nodes).as_bytes());
+
+        // Arguments within the limits are waited for, unless the request gets too long
+        let mut buf = BytesMut::from(&b"known\n* 3\n"[..]);
+        for key in &["a", "b"] {
+            buf.extend_from_slice(format!("{} {}\n", key, limits::MAX_ARG_LEN).as_bytes());
+            assert!(parse_request(&mut buf).unwrap().is_none());
+            buf.extend_from_slice(&vec![b'a'; limits::MAX_ARG_LEN]);
+        }
+        buf.extend_from_slice(format!("c {}\n", limits::MAX_ARG_LEN).as_bytes());
+        match parse_request(&mut buf).map_err(|err| err.downcast::<errors::ErrorKind>()) {
+            Err(Ok(errors::ErrorKind::LimitExceeded(_))) => {}
+            bad => panic!("unexpected result {:?}", bad),
+        }
+    }
 }
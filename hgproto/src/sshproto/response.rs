@@ -10,6 +10,7 @@ use std::io::{self, Write};
 use bytes::{BufMut, Bytes, BytesMut};
 use futures::stream;
 use futures_ext::StreamExt;
+use mercurial_types::percent_encode;
 
 use {batch, Response, SingleResponse};
 use handler::OutputStream;
@@ -63,8 +64,8 @@ fn encode_single(response: &SingleResponse, out: &mut BytesMut) {
 }
 
 /// Encode the result of an individual command completion. This is used by both
-/// single and batch responses encoding
-fn encode_cmd(response: &SingleResponse) -> Bytes {
+/// single and batch responses encoding, and by the HTTP protocol.
+pub fn encode_cmd(response: &SingleResponse) -> Bytes {
     use SingleResponse::*;
 
     match response {
@@ -88,8 +89,30 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Capabilities(ref caps) => Bytes::from(caps.join(" ")),
+
         &Debugwireargs(ref res) => res.clone(),
 
+        &Clonebundles(ref res) => Bytes::from(res.as_bytes()),
+
+        &Branchmap(ref map) => {
+            let mut branches: Vec<_> = map.iter().collect();
+            branches.sort();
+            let lines: Vec<_> = branches
+                .into_iter()
+                .map(|(branch, heads)| {
+                    let mut heads: Vec<_> = heads.iter().collect();
+                    heads.sort();
+                    let mut line = Vec::new();
+                    write!(line, "{} ", percent_encode(branch)).expect("write to vec failed");
+                    separated(&mut line, heads, " ").expect("write to vec failed");
+                    line
+                })
+                .collect();
+
+            Bytes::from(lines.concat())
+        }
+
         &Heads(ref set) => {
             let mut out = Vec::new();
 
@@ -98,6 +121,8 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
             Bytes::from(out)
         }
 
+        &Pushkey(res) => Bytes::from(if res { &b"1\n"[..] } else { &b"0\n"[..] }),
+
         &Known(ref knowns) => {
             let out: Vec<_> = knowns
                 .iter()
@@ -118,8 +143,32 @@ fn encode_cmd(response: &SingleResponse) -> Bytes {
 
         &Getfiles(ref res) => res.clone(),
 
+        &Getfile(ref res) => res.clone(),
+
+        &Getpackv1(ref res) => res.clone(),
+
+        &Streamout(ref res) => res.clone(),
+
         &Lookup(ref res) => res.clone(),
 
+        &Bookmarkhistory(ref res) => res.clone(),
+
+        &Listkeys(ref res) => {
+            let mut keys: Vec<_> = res.iter().collect();
+            keys.sort();
+            let lines: Vec<_> = keys
+                .into_iter()
+                .map(|(key, value)| {
+                    let mut line = key.clone();
+                    line.push(b'\t');
+                    line.extend_from_slice(value);
+                    line
+                })
+                .collect();
+
+            Bytes::from(lines.join(&b'\n'))
+        }
+
         r => panic!("Response for {:?} unimplemented", r),
     }
 }
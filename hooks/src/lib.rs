@@ -11,9 +11,11 @@ extern crate ascii;
 #[macro_use]
 extern crate failure_ext as failure;
 extern crate futures;
+extern crate futures_ext;
 extern crate hlua;
 #[cfg_attr(test, macro_use)]
 extern crate maplit;
+extern crate regex;
 #[cfg(test)]
 extern crate tempdir;
 
@@ -24,8 +26,11 @@ extern crate mercurial_types;
 
 #[cfg(test)]
 extern crate linear;
+#[cfg(test)]
+extern crate mercurial_types_mocks;
 
 mod errors;
+pub mod push;
 
 use std::collections::HashMap;
 use std::sync::Arc;
@@ -41,6 +46,8 @@ use mercurial_types::{Changeset, NodeHash};
 use mercurial_types::nodehash::ChangesetId;
 
 pub use errors::*;
+pub use push::{run_hooks, BannedPathsHook, CommitMessageHook, Hook, HookChangeset,
+               HookExecution, HookFile, HookRejection, MaxFileSizeHook};
 
 #[allow(dead_code)]
 pub struct HookInfo {
@@ -0,0 +1,263 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Checks run against the changesets of a push before they are added to the repo. The push is
+//! rejected if any hook rejects any of its changesets, with all the reasons reported at once.
+
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+use regex::Regex;
+
+use mercurial_types::{MPath, NodeHash};
+
+use errors::*;
+
+/// What hooks get to see of a pushed changeset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookChangeset {
+    pub node: NodeHash,
+    pub user: String,
+    pub message: String,
+    pub files: Vec<HookFile>,
+}
+
+/// A file changed by a pushed changeset.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookFile {
+    pub path: MPath,
+    /// Size of the new content of the file, or None if the changeset removes it
+    pub size: Option<usize>,
+}
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum HookExecution {
+    Accepted,
+    /// The changeset can't be pushed, for the given reason
+    Rejected(String),
+}
+
+pub trait Hook: Send + Sync + 'static {
+    fn name(&self) -> &str;
+    fn run(&self, changeset: Arc<HookChangeset>) -> BoxFuture<HookExecution, Error>;
+}
+
+/// A changeset rejected by a hook.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HookRejection {
+    pub hook: String,
+    pub node: NodeHash,
+    pub reason: String,
+}
+
+impl Display for HookRejection {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        write!(fmt, "{}: {}: {}", self.node, self.hook, self.reason)
+    }
+}
+
+/// Run every hook on every changeset concurrently, and resolve to all the rejections, in the
+/// order of the changesets and then of the hooks.
+pub fn run_hooks(
+    hooks: &[Arc<Hook>],
+    changesets: Vec<HookChangeset>,
+) -> BoxFuture<Vec<HookRejection>, Error> {
+    let runs = changesets.into_iter().flat_map(|changeset| {
+        let changeset = Arc::new(changeset);
+        hooks
+            .iter()
+            .map(move |hook| {
+                let name = hook.name().to_string();
+                let node = changeset.node;
+                hook.run(changeset.clone())
+                    .map(move |execution| match execution {
+                        HookExecution::Accepted => None,
+                        HookExecution::Rejected(reason) => Some(HookRejection {
+                            hook: name,
+                            node,
+                            reason,
+                        }),
+                    })
+            })
+            .collect::<Vec<_>>()
+    });
+
+    future::join_all(runs)
+        .map(|rejections| rejections.into_iter().filter_map(|r| r).collect())
+        .boxify()
+}
+
+/// Rejects changesets whose message doesn't match a regex.
+pub struct CommitMessageHook {
+    regex: Regex,
+}
+
+impl CommitMessageHook {
+    pub fn new(regex: &str) -> Result<Self> {
+        let regex = Regex::new(regex)
+            .map_err(|err| ErrorKind::HookDefinitionError(format!("invalid regex: {}", err)))?;
+        Ok(CommitMessageHook { regex })
+    }
+}
+
+impl Hook for CommitMessageHook {
+    fn name(&self) -> &str {
+        "commit_message_regex"
+    }
+
+    fn run(&self, changeset: Arc<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+        let execution = if self.regex.is_match(&changeset.message) {
+            HookExecution::Accepted
+        } else {
+            HookExecution::Rejected(format!(
+                "commit message doesn't match {}",
+                self.regex.as_str()
+            ))
+        };
+        future::ok(execution).boxify()
+    }
+}
+
+/// Rejects changesets adding or changing a file to be larger than a limit.
+pub struct MaxFileSizeHook {
+    max_size: usize,
+}
+
+impl MaxFileSizeHook {
+    pub fn new(max_size: usize) -> Self {
+        MaxFileSizeHook { max_size }
+    }
+}
+
+impl Hook for MaxFileSizeHook {
+    fn name(&self) -> &str {
+        "max_file_size"
+    }
+
+    fn run(&self, changeset: Arc<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+        let too_large: Vec<_> = changeset
+            .files
+            .iter()
+            .filter(|file| file.size.map_or(false, |size| size > self.max_size))
+            .map(|file| format!("{}", file.path))
+            .collect();
+        let execution = if too_large.is_empty() {
+            HookExecution::Accepted
+        } else {
+            HookExecution::Rejected(format!(
+                "files larger than {} bytes: {}",
+                self.max_size,
+                too_large.join(", ")
+            ))
+        };
+        future::ok(execution).boxify()
+    }
+}
+
+/// Rejects changesets touching files in banned directories, or banned files.
+pub struct BannedPathsHook {
+    banned: Vec<MPath>,
+}
+
+impl BannedPathsHook {
+    pub fn new(banned: Vec<MPath>) -> Self {
+        BannedPathsHook { banned }
+    }
+}
+
+impl Hook for BannedPathsHook {
+    fn name(&self) -> &str {
+        "banned_paths"
+    }
+
+    fn run(&self, changeset: Arc<HookChangeset>) -> BoxFuture<HookExecution, Error> {
+        let banned: Vec<_> = changeset
+            .files
+            .iter()
+            .filter(|file| self.banned.iter().any(|banned| banned.is_prefix_of(&file.path)))
+            .map(|file| format!("{}", file.path))
+            .collect();
+        let execution = if banned.is_empty() {
+            HookExecution::Accepted
+        } else {
+            HookExecution::Rejected(format!("banned paths: {}", banned.join(", ")))
+        };
+        future::ok(execution).boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+    fn changeset(
+        node: NodeHash,
+        message: &str,
+        files: Vec<(&str, Option<usize>)>,
+    ) -> HookChangeset {
+        HookChangeset {
+            node,
+            user: "test".to_string(),
+            message: message.to_string(),
+            files: files
+                .into_iter()
+                .map(|(path, size)| HookFile {
+                    path: MPath::new(path).unwrap(),
+                    size,
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn commit_message() {
+        let hook = CommitMessageHook::new(r"^\[\w+\] ").unwrap();
+        let run = |message| hook.run(Arc::new(changeset(ONES_HASH, message, vec![]))).wait();
+        assert_eq!(run("[server] Fix it").unwrap(), HookExecution::Accepted);
+        match run("Fix it").unwrap() {
+            HookExecution::Rejected(_) => (),
+            HookExecution::Accepted => panic!("message should be rejected"),
+        }
+        assert!(CommitMessageHook::new("(").is_err());
+    }
+
+    #[test]
+    fn aggregated_rejections() {
+        let hooks: Vec<Arc<Hook>> = vec![
+            Arc::new(MaxFileSizeHook::new(10)),
+            Arc::new(BannedPathsHook::new(vec![MPath::new("secrets").unwrap()])),
+        ];
+        let changesets = vec![
+            changeset(ONES_HASH, "ok", vec![("dir/small", Some(10)), ("removed", None)]),
+            changeset(
+                TWOS_HASH,
+                "bad",
+                vec![("dir/large", Some(11)), ("secrets/key", Some(1))],
+            ),
+        ];
+
+        let rejections = run_hooks(&hooks, changesets).wait().unwrap();
+        assert_eq!(
+            rejections,
+            vec![
+                HookRejection {
+                    hook: "max_file_size".to_string(),
+                    node: TWOS_HASH,
+                    reason: "files larger than 10 bytes: dir/large".to_string(),
+                },
+                HookRejection {
+                    hook: "banned_paths".to_string(),
+                    node: TWOS_HASH,
+                    reason: "banned paths: secrets/key".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,184 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reading and writing bundle files, as written by `hg bundle` or served as clone bundles.
+//!
+//! A bundle file is either a bundle2 stream, starting with `HG20`, or a v1 bundle: `HG10`, a
+//! compression marker (`UN`, `GZ` or `BZ`) and a version 1 changegroup. The `BZ` of a bzip2
+//! compressed v1 bundle is the start of the bzip2 stream, so it's both part of the header and of
+//! the compressed data. Bundle2 files are written with `Bundle2EncodeBuilder`.
+
+use std::io::{self, BufRead, Chain, Cursor, Read};
+
+use bytes::BytesMut;
+use futures::{future, Async, Future, Sink, Stream};
+use slog;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::codec::{Encoder, FramedRead, FramedWrite};
+use tokio_io::io::{read_exact, write_all};
+
+use async_compression::{Compressor, CompressorType, Decompressor, DecompressorType};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_ext::io::Either::{self, A as Uncompressed, B as Compressed};
+
+use bundle2::Bundle2Stream;
+use changegroup::{CgVersion, Part};
+use changegroup::packer::CgPacker;
+use changegroup::unpacker::CgUnpacker;
+use chunk::Chunk;
+use errors::*;
+
+pub enum BundleFile<R>
+where
+    R: AsyncRead + BufRead + 'static + Send,
+{
+    /// A bundle2 stream, which starts with its stream header
+    Bundle2(Bundle2Stream<Chain<Cursor<Vec<u8>>, R>>),
+    /// The changegroup of a v1 bundle
+    Bundle1(BoxStream<Part, Error>),
+}
+
+impl<R> BundleFile<R>
+where
+    R: AsyncRead + BufRead + 'static + Send,
+{
+    pub fn is_bundle2(&self) -> bool {
+        match self {
+            &BundleFile::Bundle2(_) => true,
+            &BundleFile::Bundle1(_) => false,
+        }
+    }
+}
+
+/// Read the header of a bundle file to find out its format.
+pub fn read_bundle<R>(read: R, logger: slog::Logger) -> BoxFuture<BundleFile<R>, Error>
+where
+    R: AsyncRead + BufRead + 'static + Send,
+{
+    read_exact(read, [0; 4])
+        .from_err()
+        .and_then(move |(read, magic)| match &magic {
+            b"HG20" => {
+                // The bundle2 decoder parses the magic string itself
+                let read = Cursor::new(magic.to_vec()).chain(read);
+                future::ok(BundleFile::Bundle2(Bundle2Stream::new(read, logger))).boxify()
+            }
+            b"HG10" => read_exact(read, [0; 2])
+                .from_err()
+                .and_then(move |(read, compression)| {
+                    let unpacker = CgUnpacker::new(
+                        logger.new(o!("stream" => "cg", "version" => "01")),
+                        CgVersion::Cg1Version,
+                    );
+                    let parts = match &compression {
+                        b"UN" => FramedRead::new(read, unpacker).boxify(),
+                        b"GZ" => {
+                            let read = Decompressor::new(read, DecompressorType::Gzip);
+                            FramedRead::new(read, unpacker).boxify()
+                        }
+                        b"BZ" => {
+                            let read = Cursor::new(compression.to_vec()).chain(read);
+                            let read = Decompressor::new(read, DecompressorType::Bzip2);
+                            FramedRead::new(read, unpacker).boxify()
+                        }
+                        _ => {
+                            let msg = format!(
+                                "unknown v1 bundle compression '{}'",
+                                String::from_utf8_lossy(&compression)
+                            );
+                            bail_err!(ErrorKind::BundleFileDecode(msg));
+                        }
+                    };
+                    Ok(BundleFile::Bundle1(parts))
+                })
+                .boxify(),
+            _ => {
+                let msg = format!("invalid bundle magic string {:?}", magic);
+                future::err(ErrorKind::BundleFileDecode(msg).into()).boxify()
+            }
+        })
+        .boxify()
+}
+
+/// Write a v1 bundle of a changegroup, whose deltas must all be against the previous revision of
+/// their section, or the first parent for the first one. Zstd isn't supported by v1 bundles.
+pub fn write_bundle1<W, S>(
+    writer: W,
+    parts: S,
+    compression: Option<CompressorType>,
+) -> BoxFuture<W, Error>
+where
+    W: AsyncWrite + Send + 'static,
+    S: Stream<Item = Part, Error = Error> + Send + 'static,
+{
+    let header: &'static [u8] = match compression {
+        None => b"HG10UN",
+        Some(CompressorType::Gzip(_)) => b"HG10GZ",
+        // The bzip2 stream starts with the rest of the header
+        Some(CompressorType::Bzip2(_)) => b"HG10",
+        Some(CompressorType::Zstd { .. }) => {
+            let msg = "v1 bundles can't be compressed with zstd".into();
+            return future::err(ErrorKind::BundleFileEncode(msg).into()).boxify();
+        }
+    };
+
+    write_all(writer, header)
+        .from_err()
+        .and_then(move |(writer, _)| {
+            let writer = match compression {
+                None => Uncompressed(writer),
+                Some(compression) => Compressed(Compressor::new(writer, compression)),
+            };
+            // Unlike forward, sending chunks one by one doesn't shut the compressor down
+            CgPacker::new(parts, CgVersion::Cg1Version)
+                .fold(FramedWrite::new(writer, RawChunkEncoder), |sink, chunk| {
+                    sink.send(chunk)
+                })
+        })
+        .and_then(|sink| finish_write(sink.into_inner()))
+        .boxify()
+}
+
+fn finish_write<W>(writer: Either<W, Compressor<W>>) -> BoxFuture<W, Error>
+where
+    W: AsyncWrite + Send + 'static,
+{
+    let mut compressor = match writer {
+        Uncompressed(writer) => return future::ok(writer).boxify(),
+        Compressed(compressor) => Some(compressor),
+    };
+
+    future::poll_fn(move || {
+        let inner = compressor
+            .take()
+            .expect("polled finish_write future after it is complete");
+        match inner.try_finish() {
+            Ok(writer) => Ok(Async::Ready(writer)),
+            Err((inner, err)) => if err.kind() == io::ErrorKind::WouldBlock {
+                compressor = Some(inner);
+                Ok(Async::NotReady)
+            } else {
+                let context = ErrorKind::BundleFileEncode("error while completing write".into());
+                Err(Error::from(err).context(context).into())
+            },
+        }
+    }).boxify()
+}
+
+/// Write changegroup chunks as they are, without the framing of bundle2 parts.
+#[derive(Debug)]
+struct RawChunkEncoder;
+
+impl Encoder for RawChunkEncoder {
+    type Item = Chunk;
+    type Error = Error;
+
+    fn encode(&mut self, item: Chunk, dst: &mut BytesMut) -> Result<()> {
+        let bytes = item.into_bytes()?;
+        dst.extend_from_slice(&bytes);
+        Ok(())
+    }
+}
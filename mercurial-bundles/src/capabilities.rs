@@ -9,12 +9,17 @@
 use std::borrow::Cow;
 use std::collections::HashMap;
 
+use async_compression::{CompressorType, FlateCompression};
 use bytes::BytesMut;
 use tokio_io::codec::Decoder;
 use url::percent_encoding::percent_decode;
 
 use errors::*;
 
+/// Level of zstd compression, which is fast enough at this level to beat gzip on both size and
+/// speed
+const ZSTD_LEVEL: i32 = 3;
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct Capabilities {
     caps: HashMap<String, Vec<String>>,
@@ -41,8 +46,17 @@ impl Decoder for CapabilitiesUnpacker {
     }
 
     fn decode_eof(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
+        let caps = Capabilities::decode(buf)?;
+        buf.clear(); // all buf was consumed
+        Ok(Some(caps))
+    }
+}
+
+impl Capabilities {
+    /// Decode capabilities in the format described in CapabilitiesUnpacker
+    pub fn decode(data: &[u8]) -> Result<Self> {
         let mut caps = HashMap::new();
-        for kv in buf.split(|b| b == &b'\n') {
+        for kv in data.split(|b| b == &b'\n') {
             let mut kv = kv.splitn(2, |b| b == &b'=');
             let key = percent_decode(kv.next().expect("must have at least 1 element"))
                 .decode_utf8()?
@@ -63,8 +77,89 @@ impl Decoder for CapabilitiesUnpacker {
             caps.insert(key, values);
         }
 
-        buf.clear(); // all buf was consumed
+        Ok(Capabilities { caps })
+    }
+
+    /// Decode the bundle2 capabilities of a client from the `bundle2=` entry of the bundlecaps
+    /// it sends with getbundle, where they are percent encoded once more
+    pub fn from_bundlecaps(bundlecaps: &[Vec<u8>]) -> Result<Option<Self>> {
+        let prefix = b"bundle2=";
+        match bundlecaps.iter().find(|cap| cap.starts_with(prefix)) {
+            Some(cap) => {
+                let caps: Vec<u8> = percent_decode(&cap[prefix.len()..]).collect();
+                Ok(Some(Capabilities::decode(&caps)?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Vec<String>> {
+        self.caps.get(key)
+    }
+
+    /// Pick the compression of a bundle2 for a peer from its `compression` capability, which
+    /// lists the engines it can decode in order of preference. Bzip2 is never picked, as it's
+    /// too slow to be worth it; `None` means the bundle2 isn't compressed.
+    pub fn compressor_type(&self) -> Option<CompressorType> {
+        self.get("compression")?
+            .iter()
+            .filter_map(|engine| match engine.as_str() {
+                "ZS" => Some(CompressorType::Zstd { level: ZSTD_LEVEL }),
+                "GZ" => Some(CompressorType::Gzip(FlateCompression::default())),
+                _ => None,
+            })
+            .next()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use async_compression::DecompressorType;
+
+    #[test]
+    fn test_from_bundlecaps() {
+        let bundlecaps = vec![
+            b"HG20".to_vec(),
+            b"bundle2=HG20%0Achangegroup%3D01%2C02%0Acompression%3DZS%2CGZ".to_vec(),
+        ];
+        let caps = Capabilities::from_bundlecaps(&bundlecaps)
+            .expect("bundlecaps should decode")
+            .expect("bundle2 caps should be found");
+        assert_eq!(caps.get("HG20"), Some(&vec![]));
+        assert_eq!(
+            caps.get("changegroup"),
+            Some(&vec!["01".to_string(), "02".to_string()])
+        );
+        assert_eq!(
+            caps.get("compression"),
+            Some(&vec!["ZS".to_string(), "GZ".to_string()])
+        );
+
+        assert_eq!(
+            Capabilities::from_bundlecaps(&[b"HG20".to_vec()]).expect("no bundle2 caps"),
+            None
+        );
+    }
 
-        Ok(Some(Capabilities { caps }))
+    #[test]
+    fn test_compressor_type() {
+        let compressor_type = |caps: &[u8]| {
+            Capabilities::decode(caps)
+                .expect("caps should decode")
+                .compressor_type()
+                .map(|ct| ct.decompressor_type())
+        };
+        assert_eq!(
+            compressor_type(b"compression=BZ,ZS,GZ"),
+            Some(DecompressorType::Zstd)
+        );
+        assert_eq!(
+            compressor_type(b"compression=GZ,ZS"),
+            Some(DecompressorType::Gzip)
+        );
+        assert_eq!(compressor_type(b"compression=BZ,UN"), None);
+        assert_eq!(compressor_type(b"HG20"), None);
     }
 }
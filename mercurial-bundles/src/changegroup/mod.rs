@@ -6,13 +6,50 @@
 
 use mercurial_types::{Delta, MPath, NodeHash};
 
+use errors::*;
+
 pub mod packer;
 pub mod unpacker;
 
+/// The versions of the changegroup format, as named by the `version` parameter of changegroup
+/// parts. Version 1, the only one in v1 bundles, has no base node in chunks: a delta is against
+/// the previous chunk of its section, or the first parent for the first one. Version 3 adds
+/// revlog flags to chunks, and the tree manifests of the changesets after their root manifests.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CgVersion {
+    Cg1Version,
+    Cg2Version,
+    Cg3Version,
+}
+
+impl CgVersion {
+    pub fn from_param(version: &[u8]) -> Result<Self> {
+        match version {
+            b"01" => Ok(CgVersion::Cg1Version),
+            b"02" => Ok(CgVersion::Cg2Version),
+            b"03" => Ok(CgVersion::Cg3Version),
+            _ => bail_err!(ErrorKind::CgDecode(format!(
+                "unsupported changegroup version {:?}",
+                String::from_utf8_lossy(version)
+            ))),
+        }
+    }
+
+    pub fn to_param(&self) -> &'static str {
+        match *self {
+            CgVersion::Cg1Version => "01",
+            CgVersion::Cg2Version => "02",
+            CgVersion::Cg3Version => "03",
+        }
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub enum Section {
     Changeset,
     Manifest,
+    /// The manifest of a directory, only in version 3
+    Treemanifest(MPath),
     Filelog(MPath),
 }
 
@@ -40,6 +77,33 @@ pub struct CgDeltaChunk {
     pub base: NodeHash,
     pub linknode: NodeHash,
     pub delta: Delta,
+    /// Always empty in versions 1 and 2
+    pub flags: RevFlags,
+}
+
+/// Flags of a revision in its revlog.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct RevFlags(pub u16);
+
+impl RevFlags {
+    /// The content of the revision was replaced with a tombstone, e.g. because it leaked a secret
+    pub const CENSORED: RevFlags = RevFlags(1 << 15);
+    /// The parents of the revision are its closest ancestors in a narrow changegroup
+    pub const ELLIPSIS: RevFlags = RevFlags(1 << 14);
+    /// The content of the revision is stored outside of the repo, e.g. by LFS
+    pub const EXTSTORED: RevFlags = RevFlags(1 << 13);
+
+    pub fn empty() -> Self {
+        RevFlags(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, other: RevFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
 }
 
 #[cfg(test)]
@@ -58,7 +122,7 @@ mod test {
     use partial_io::{GenWouldBlock, PartialAsyncRead, PartialAsyncWrite, PartialWithErrors};
 
     use chunk::{ChunkDecoder, ChunkEncoder};
-    use quickcheck_types::Cg2PartSequence;
+    use quickcheck_types::CgPartSequence;
 
     use super::*;
 
@@ -76,7 +140,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip
                 as fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -91,7 +155,7 @@ mod test {
         quickcheck.quickcheck(
             roundtrip
                 as fn(
-                    Cg2PartSequence,
+                    CgPartSequence,
                     PartialWithErrors<GenWouldBlock>,
                     PartialWithErrors<GenWouldBlock>,
                 ) -> TestResult,
@@ -99,14 +163,14 @@ mod test {
     }
 
     fn roundtrip(
-        seq: Cg2PartSequence,
+        seq: CgPartSequence,
         write_ops: PartialWithErrors<GenWouldBlock>,
         read_ops: PartialWithErrors<GenWouldBlock>,
     ) -> TestResult {
         // Encode this sequence.
         let cursor = Cursor::new(Vec::with_capacity(32 * 1024));
         let partial_write = PartialAsyncWrite::new(cursor, write_ops);
-        let packer = packer::Cg2Packer::new(seq.to_stream().and_then(|x| x));
+        let packer = packer::CgPacker::new(seq.to_stream().and_then(|x| x), seq.version());
         let sink = FramedWrite::new(partial_write, ChunkEncoder);
         let encode_fut = packer.forward(sink);
 
@@ -122,7 +186,7 @@ mod test {
             .map(|chunk| chunk.into_bytes().expect("expected normal chunk"));
 
         let logger = make_root_logger();
-        let unpacker = unpacker::Cg2Unpacker::new(logger);
+        let unpacker = unpacker::CgUnpacker::new(logger, seq.version());
         let part_stream = chunks.decode(unpacker);
 
         let parts = Vec::new();
@@ -11,27 +11,50 @@ use futures::{Async, Poll, Stream};
 use byteorder::ByteOrder;
 use bytes::{BigEndian, BufMut};
 
+use mercurial_types::NodeHash;
+
 use chunk::Chunk;
 use delta;
 use errors::*;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, Section};
 
-pub struct Cg2Packer<S> {
+pub struct CgPacker<S> {
     delta_stream: S,
+    version: CgVersion,
     last_seen: Section,
+    // The node of the previous chunk of the section, which version 1 deltas must be against
+    prev_node: Option<NodeHash>,
+    // In version 3, the tree manifests are ended by an empty chunk before the first filelog
+    treemanifests_ended: bool,
+    after_treemanifests: Option<Chunk>,
 }
 
-impl<S> Cg2Packer<S> {
-    pub fn new(delta_stream: S) -> Self {
-        Cg2Packer {
+impl<S> CgPacker<S> {
+    pub fn new(delta_stream: S, version: CgVersion) -> Self {
+        CgPacker {
             delta_stream: delta_stream,
+            version: version,
             last_seen: Section::Changeset,
+            prev_node: None,
+            treemanifests_ended: version != CgVersion::Cg3Version,
+            after_treemanifests: None,
+        }
+    }
+
+    fn ends_treemanifests(&mut self, part: &Part) -> bool {
+        if self.treemanifests_ended {
+            return false;
         }
+        self.treemanifests_ended = match *part {
+            Part::CgChunk(Section::Filelog(_), _) | Part::End => true,
+            _ => false,
+        };
+        self.treemanifests_ended
     }
 }
 
-impl<S> Stream for Cg2Packer<S>
+impl<S> Stream for CgPacker<S>
 where
     S: Stream<Item = Part>,
     Error: From<S::Error>,
@@ -42,19 +65,49 @@ where
     fn poll(&mut self) -> Poll<Option<Chunk>, Error> {
         use self::Part::*;
 
-        match try_ready!(self.delta_stream.poll()) {
-            None => Ok(Async::Ready(None)),
-            Some(CgChunk(section, delta_chunk)) => {
+        if let Some(chunk) = self.after_treemanifests.take() {
+            return Ok(Async::Ready(Some(chunk)));
+        }
+
+        let part = match try_ready!(self.delta_stream.poll()) {
+            None => return Ok(Async::Ready(None)),
+            Some(part) => part,
+        };
+        let ends_treemanifests = self.ends_treemanifests(&part);
+
+        let chunk = match part {
+            CgChunk(section, delta_chunk) => {
+                if self.version == CgVersion::Cg1Version {
+                    let base = self.prev_node.unwrap_or(delta_chunk.p1);
+                    if delta_chunk.base != base {
+                        let msg = format!(
+                            "version 01 can't encode a delta of {} against {}, only against {}",
+                            delta_chunk.node, delta_chunk.base, base
+                        );
+                        bail_err!(ErrorKind::CgEncode(msg));
+                    }
+                }
+                self.prev_node = Some(delta_chunk.node);
                 let mut builder = ChunkBuilder::new();
                 if self.last_seen != section {
                     builder.encode_section(&section)?;
                     self.last_seen = section;
                 }
-                builder.encode_delta_chunk(delta_chunk);
-                Ok(Async::Ready(Some(builder.build()?)))
+                builder.encode_delta_chunk(delta_chunk, self.version);
+                builder.build()?
+            }
+            SectionEnd(_section) => {
+                self.prev_node = None;
+                empty_cg_chunk()
             }
-            Some(SectionEnd(_section)) => Ok(Async::Ready(Some(empty_cg_chunk()))),
-            Some(End) => Ok(Async::Ready(Some(empty_cg_chunk()))),
+            End => empty_cg_chunk(),
+        };
+
+        if ends_treemanifests {
+            self.after_treemanifests = Some(chunk);
+            Ok(Async::Ready(Some(empty_cg_chunk())))
+        } else {
+            Ok(Async::Ready(Some(chunk)))
         }
     }
 }
@@ -92,11 +145,21 @@ impl ChunkBuilder {
         );
         // Changeset and manifest sections are implicitly encoded, so we don't
         // need to do anything there.
-        // TODO: will need to encode tree manifests here as well
-        if let &Section::Filelog(ref f) = section {
-            let f_vec = f.to_vec();
+        let name = match section {
+            &Section::Treemanifest(ref d) => {
+                // Directory names end with a slash
+                let mut d_vec = d.to_vec();
+                if !d_vec.is_empty() {
+                    d_vec.push(b'/');
+                }
+                Some(d_vec)
+            }
+            &Section::Filelog(ref f) => Some(f.to_vec()),
+            _ => None,
+        };
+        if let Some(f_vec) = name {
             if f_vec.len() == 0 {
-                bail_err!(ErrorKind::Cg2Encode(
+                bail_err!(ErrorKind::CgEncode(
                     "attempted to encode a zero-length path".into(),
                 ));
             }
@@ -110,12 +173,19 @@ impl ChunkBuilder {
         Ok(self)
     }
 
-    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk) -> &mut Self {
+    pub fn encode_delta_chunk(&mut self, chunk: CgDeltaChunk, version: CgVersion) -> &mut Self {
         self.inner.put_slice(chunk.node.as_ref());
         self.inner.put_slice(chunk.p1.as_ref());
         self.inner.put_slice(chunk.p2.as_ref());
-        self.inner.put_slice(chunk.base.as_ref());
+        // Version 1 has no base, the delta is against the previous chunk or the first parent
+        if version != CgVersion::Cg1Version {
+            self.inner.put_slice(chunk.base.as_ref());
+        }
         self.inner.put_slice(chunk.linknode.as_ref());
+        // Version 2 has no flags, so they're dropped
+        if version == CgVersion::Cg3Version {
+            self.inner.put_u16::<BigEndian>(chunk.flags.0);
+        }
 
         delta::encode_delta(&chunk.delta, &mut self.inner);
 
@@ -145,7 +215,7 @@ mod test {
                 .unwrap_err()
                 .downcast::<ErrorKind>()
                 .unwrap(),
-            ErrorKind::Cg2Encode(_)
+            ErrorKind::CgEncode(_)
         );
     }
 }
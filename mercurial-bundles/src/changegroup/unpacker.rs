@@ -14,18 +14,21 @@ use bytes::BytesMut;
 use slog;
 use tokio_io::codec::Decoder;
 
-use mercurial_types::MPath;
+use mercurial_types::{MPath, NodeHash};
 
 use delta;
 use errors::*;
 use utils::BytesExt;
 
-use super::{CgDeltaChunk, Part, Section};
+use super::{CgDeltaChunk, CgVersion, Part, RevFlags, Section};
 
 #[derive(Debug)]
-pub struct Cg2Unpacker {
+pub struct CgUnpacker {
     logger: slog::Logger,
+    version: CgVersion,
     state: State,
+    /// The node of the previous chunk of the section, which version 1 deltas are against
+    prev_node: Option<NodeHash>,
 }
 
 impl Part {
@@ -55,16 +58,19 @@ impl Part {
     }
 }
 
-// See the chunk header definition below for the first 100 bytes. The last 4 is
-// for the length field itself.
-const CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+// See the chunk header definition below for the first 100 bytes, without the base node in
+// version 1, and the 2 bytes of flags in version 3. The last 4 is for the length field itself.
+const CG1_CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 4;
+const CG2_CHUNK_HEADER_LEN: usize = 20 + 20 + 20 + 20 + 20 + 4;
+const CG3_CHUNK_HEADER_LEN: usize = CG2_CHUNK_HEADER_LEN + 2;
 
-impl Decoder for Cg2Unpacker {
+impl Decoder for CgUnpacker {
     type Item = Part;
     type Error = Error;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>> {
-        match Self::decode_next(buf, self.state.take()) {
+        let state = self.state.take();
+        match self.decode_next(buf, state) {
             Err(e) => {
                 self.state = State::Invalid;
                 Err(e)
@@ -90,14 +96,14 @@ impl Decoder for Cg2Unpacker {
                          buffer. State: {:?}, First 128 bytes: {:?}",
                         len, self.state, bytes,
                     );
-                    bail_err!(ErrorKind::Cg2Decode(msg));
+                    bail_err!(ErrorKind::CgDecode(msg));
                 }
                 if self.state != State::End {
                     let msg = format!(
                         "incomplete changegroup: expected state End, found {:?}",
                         self.state
                     );
-                    bail_err!(ErrorKind::Cg2Decode(msg));
+                    bail_err!(ErrorKind::CgDecode(msg));
                 }
                 Ok(None)
             }
@@ -106,17 +112,19 @@ impl Decoder for Cg2Unpacker {
     }
 }
 
-impl Cg2Unpacker {
-    pub fn new(logger: slog::Logger) -> Self {
-        Cg2Unpacker {
+impl CgUnpacker {
+    pub fn new(logger: slog::Logger, version: CgVersion) -> Self {
+        CgUnpacker {
             logger: logger,
+            version: version,
             state: State::Changeset,
+            prev_node: None,
         }
     }
 
-    fn decode_next(buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
+    fn decode_next(&mut self, buf: &mut BytesMut, state: State) -> Result<(Option<Part>, State)> {
         match state {
-            State::Changeset => match Self::decode_chunk(buf)? {
+            State::Changeset => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Changeset)),
                 Some(CgChunk::Empty) => {
                     Ok((Some(Part::SectionEnd(Section::Changeset)), State::Manifest))
@@ -126,32 +134,67 @@ impl Cg2Unpacker {
                     State::Changeset,
                 )),
             },
-            State::Manifest => match Self::decode_chunk(buf)? {
+            State::Manifest => match self.decode_chunk(buf)? {
                 None => Ok((None, State::Manifest)),
                 Some(CgChunk::Empty) => {
-                    Ok((Some(Part::SectionEnd(Section::Manifest)), State::Filename))
+                    let next = match self.version {
+                        CgVersion::Cg1Version | CgVersion::Cg2Version => State::Filename,
+                        CgVersion::Cg3Version => State::Dirname,
+                    };
+                    Ok((Some(Part::SectionEnd(Section::Manifest)), next))
                 }
                 Some(CgChunk::Delta(chunk)) => Ok((
                     Some(Part::CgChunk(Section::Manifest, chunk)),
                     State::Manifest,
                 )),
             },
+            State::Dirname => {
+                let dirname = Self::decode_filename(buf)?;
+                match dirname {
+                    DecodeRes::None => Ok((None, State::Dirname)),
+                    DecodeRes::Some(d) => self.decode_treemanifest_chunk(buf, d),
+                    // The directories are followed by the files, without a part in between
+                    DecodeRes::End => self.decode_next(buf, State::Filename),
+                }
+            }
+            State::Treemanifest(dirname) => self.decode_treemanifest_chunk(buf, dirname),
             State::Filename => {
                 let filename = Self::decode_filename(buf)?;
                 match filename {
                     DecodeRes::None => Ok((None, State::Filename)),
-                    DecodeRes::Some(f) => Self::decode_filelog_chunk(buf, f),
+                    DecodeRes::Some(f) => self.decode_filelog_chunk(buf, f),
                     DecodeRes::End => Ok((Some(Part::End), State::End)),
                 }
             }
-            State::Filelog(filename) => Self::decode_filelog_chunk(buf, filename),
+            State::Filelog(filename) => self.decode_filelog_chunk(buf, filename),
             State::End => Ok((None, State::End)),
-            State::Invalid => Err(ErrorKind::Cg2Decode("byte stream corrupt".into()).into()),
+            State::Invalid => Err(ErrorKind::CgDecode("byte stream corrupt".into()).into()),
+        }
+    }
+
+    fn decode_treemanifest_chunk(
+        &mut self,
+        buf: &mut BytesMut,
+        d: MPath,
+    ) -> Result<(Option<Part>, State)> {
+        match self.decode_chunk(buf)? {
+            None => Ok((None, State::Treemanifest(d))),
+            Some(CgChunk::Empty) => {
+                Ok((Some(Part::SectionEnd(Section::Treemanifest(d))), State::Dirname))
+            }
+            Some(CgChunk::Delta(chunk)) => Ok((
+                Some(Part::CgChunk(Section::Treemanifest(d.clone()), chunk)),
+                State::Treemanifest(d),
+            )),
         }
     }
 
-    fn decode_filelog_chunk(buf: &mut BytesMut, f: MPath) -> Result<(Option<Part>, State)> {
-        match Self::decode_chunk(buf)? {
+    fn decode_filelog_chunk(
+        &mut self,
+        buf: &mut BytesMut,
+        f: MPath,
+    ) -> Result<(Option<Part>, State)> {
+        match self.decode_chunk(buf)? {
             None => Ok((None, State::Filelog(f))),
             Some(CgChunk::Empty) => {
                 Ok((Some(Part::SectionEnd(Section::Filelog(f))), State::Filename))
@@ -163,7 +206,13 @@ impl Cg2Unpacker {
         }
     }
 
-    fn decode_chunk(buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+    fn decode_chunk(&mut self, buf: &mut BytesMut) -> Result<Option<CgChunk>> {
+        let header_len = match self.version {
+            CgVersion::Cg1Version => CG1_CHUNK_HEADER_LEN,
+            CgVersion::Cg2Version => CG2_CHUNK_HEADER_LEN,
+            CgVersion::Cg3Version => CG3_CHUNK_HEADER_LEN,
+        };
+
         if buf.len() < 4 {
             return Ok(None);
         }
@@ -174,14 +223,15 @@ impl Cg2Unpacker {
         let chunk_len = chunk_len as usize;
         if chunk_len == 0 {
             let _ = buf.drain_i32();
+            self.prev_node = None;
             return Ok(Some(CgChunk::Empty));
         }
-        if chunk_len < CHUNK_HEADER_LEN {
+        if chunk_len < header_len {
             let msg = format!(
                 "invalid chunk: length >= {} required, found {}",
-                CHUNK_HEADER_LEN, chunk_len
+                header_len, chunk_len
             );
-            bail_err!(ErrorKind::Cg2Decode(msg));
+            bail_err!(ErrorKind::CgDecode(msg));
         }
 
         if buf.len() < chunk_len {
@@ -196,15 +246,24 @@ impl Cg2Unpacker {
         // p2: NodeHash (20 bytes) -- NULL_HASH if only 1 parent
         // base node: NodeHash (20 bytes) (new in changegroup2)
         // link node: NodeHash (20 bytes)
+        // flags: u16 (2 bytes) (new in changegroup3)
         // ---
 
         let node = buf.drain_node();
         let p1 = buf.drain_node();
         let p2 = buf.drain_node();
-        let base = buf.drain_node();
+        let base = match self.version {
+            CgVersion::Cg1Version => self.prev_node.unwrap_or(p1),
+            _ => buf.drain_node(),
+        };
         let linknode = buf.drain_node();
+        let flags = match self.version {
+            CgVersion::Cg1Version | CgVersion::Cg2Version => RevFlags::empty(),
+            CgVersion::Cg3Version => RevFlags(buf.drain_u16()),
+        };
 
-        let delta = delta::decode_delta(buf.split_to(chunk_len - CHUNK_HEADER_LEN))?;
+        let delta = delta::decode_delta(buf.split_to(chunk_len - header_len))?;
+        self.prev_node = Some(node);
         return Ok(Some(CgChunk::Delta(CgDeltaChunk {
             node: node,
             p1: p1,
@@ -212,6 +271,7 @@ impl Cg2Unpacker {
             base: base,
             linknode: linknode,
             delta: delta,
+            flags: flags,
         })));
     }
 
@@ -233,7 +293,7 @@ impl Cg2Unpacker {
         let _ = buf.split_to(4);
         let filename = buf.drain_path(filename_len - 4).with_context(|_| {
             let msg = format!("invalid filename of length {}", filename_len);
-            ErrorKind::Cg2Decode(msg)
+            ErrorKind::CgDecode(msg)
         })?;
         Ok(DecodeRes::Some(filename))
     }
@@ -254,6 +314,8 @@ enum CgChunk {
 enum State {
     Changeset,
     Manifest,
+    Dirname,
+    Treemanifest(MPath),
     Filename,
     Filelog(MPath),
     End,
@@ -52,6 +52,7 @@ extern crate partial_io;
 
 pub mod bundle2;
 pub mod bundle2_encode;
+pub mod bundle_file;
 pub mod changegroup;
 pub mod infinitepush;
 mod capabilities;
@@ -76,8 +77,11 @@ mod utils;
 use std::fmt;
 
 use futures_ext::{BoxFuture, BoxStream};
+use mercurial_types::{NodeHash, ObsMarker};
 
 pub use bundle2_encode::Bundle2EncodeBuilder;
+pub use bundle_file::{read_bundle, write_bundle1, BundleFile};
+pub use capabilities::Capabilities;
 pub use part_header::{PartHeader, PartHeaderType};
 pub use types::StreamHeader;
 
@@ -86,9 +90,17 @@ pub enum Bundle2Item {
     Changegroup(PartHeader, BoxStream<changegroup::Part, Error>),
     B2xInfinitepush(PartHeader, BoxStream<changegroup::Part, Error>),
     B2xTreegroup2(PartHeader, BoxStream<wirepack::Part, Error>),
+    B2xRebase(PartHeader, BoxStream<changegroup::Part, Error>),
+    B2xRebasePack(PartHeader, BoxStream<wirepack::Part, Error>),
     // B2xInfinitepushBookmarks returns Bytes because this part is not going to be used.
     B2xInfinitepushBookmarks(PartHeader, BoxStream<bytes::Bytes, Error>),
     Replycaps(PartHeader, BoxFuture<capabilities::Capabilities, Error>),
+    CheckHeads(PartHeader, BoxFuture<Vec<NodeHash>, Error>),
+    // Bookmarks with the changesets the pusher saw them at, None if they didn't exist.
+    CheckBookmarks(PartHeader, BoxFuture<Vec<(bytes::Bytes, Option<NodeHash>)>, Error>),
+    // Pushkey has no payload, all its content is in the parameters.
+    Pushkey(PartHeader, BoxFuture<(), Error>),
+    Obsmarkers(PartHeader, BoxFuture<Vec<ObsMarker>, Error>),
 }
 
 impl Bundle2Item {
@@ -123,7 +135,17 @@ impl fmt::Debug for Bundle2Item {
             &B2xTreegroup2(ref header, _) => {
                 write!(f, "Bundle2Item::B2xTreegroup2({:?}, ...)", header)
             }
+            &B2xRebase(ref header, _) => write!(f, "Bundle2Item::B2xRebase({:?}, ...)", header),
+            &B2xRebasePack(ref header, _) => {
+                write!(f, "Bundle2Item::B2xRebasePack({:?}, ...)", header)
+            }
             &Replycaps(ref header, _) => write!(f, "Bundle2Item::Replycaps({:?}, ...)", header),
+            &CheckHeads(ref header, _) => write!(f, "Bundle2Item::CheckHeads({:?}, ...)", header),
+            &CheckBookmarks(ref header, _) => {
+                write!(f, "Bundle2Item::CheckBookmarks({:?}, ...)", header)
+            }
+            &Pushkey(ref header, _) => write!(f, "Bundle2Item::Pushkey({:?}, ...)", header),
+            &Obsmarkers(ref header, _) => write!(f, "Bundle2Item::Obsmarkers({:?}, ...)", header),
         }
     }
 }
@@ -30,31 +30,43 @@ pub enum PartHeaderType {
     Listkeys,
     /// Contains wirepacks that are encoded TreeManifests required in the push.
     B2xTreegroup2,
-    /// In Mercurial this is used to verify that during the push the heads did not change. In
-    /// Mononoke this parameter will be ignored, because it does not provide transaction during
-    /// push
+    /// Contains the heads the pusher saw, to verify that the heads did not change during the
+    /// push.
     CheckHeads,
+    /// Contains the bookmarks the pusher saw, to verify that they did not move during the push.
+    CheckBookmarks,
+    /// Updates a key, f.e. a bookmark, if it still has the value the pusher saw.
+    Pushkey,
+    /// When responding for bundle2 this part contains the result of the corresponding Pushkey.
+    ReplyPushkey,
     /// Contains changegroup for infinitepush commits
     B2xInfinitepush,
     /// Contains bookmarks for infinitepush backups (won't be used in Mononoke,
     /// but they needs to be parsed).
     B2xInfinitepushBookmarks,
+    /// Contains changegroup for commits to rebase onto the bookmark given in its parameters.
+    B2xRebase,
+    /// Contains wirepacks that are encoded TreeManifests of the commits to rebase.
+    B2xRebasePack,
+    /// Contains the heads of each phase among the changesets sent to a client.
+    PhaseHeads,
+    /// Contains obsolescence markers, which record changesets that were rewritten or pruned.
+    Obsmarkers,
+    /// When responding for bundle2 this part says how many of the corresponding Obsmarkers were
+    /// new.
+    ReplyObsmarkers,
+    /// When responding for bundle2 this part aborts the operation on the client, with a message
+    /// and possibly a hint.
+    ErrorAbort,
+    /// Contains output for the client to show to its user.
+    Output,
     // RemoteChangegroup,       // We don't wish to support this functionality
-    // CheckBookmarks,          // TODO Do we want to support this?
-    // CheckHeads,              // TODO Do we want to support this?
     // CheckUpdatedHeads,       // TODO Do we want to support this?
     // CheckPhases,             // TODO Do we want to support this?
-    // Output,                  // TODO Do we want to support this?
-    // ErrorAbort,              // TODO Do we want to support this?
     // ErrorPushkey,            // TODO Do we want to support this?
     // ErrorUnsupportedContent, // TODO Do we want to support this?
     // ErrorPushRaced,          // TODO Do we want to support this?
-    // Pushkey,                 // TODO Do we want to support this?
     // Bookmarks,               // TODO Do we want to support this?
-    // PhaseHeads,              // TODO Do we want to support this?
-    // ReplyPushkey,            // TODO Do we want to support this?
-    // Obsmarkers,              // TODO Do we want to support this?
-    // ReplyObsmarkers,         // TODO Do we want to support this?
     // HgtagsFnodes,            // TODO Do we want to support this?
     // Pushvars,                // TODO Do we want to support this?
 }
@@ -70,7 +82,17 @@ impl PartHeaderType {
             "b2x:treegroup2" => Ok(B2xTreegroup2),
             "b2x:infinitepush" => Ok(B2xInfinitepush),
             "b2x:infinitepushscratchbookmarks" => Ok(B2xInfinitepushBookmarks),
+            "b2x:rebase" => Ok(B2xRebase),
+            "b2x:rebasepackpart" => Ok(B2xRebasePack),
             "check:heads" => Ok(CheckHeads),
+            "check:bookmarks" => Ok(CheckBookmarks),
+            "pushkey" => Ok(Pushkey),
+            "reply:pushkey" => Ok(ReplyPushkey),
+            "phase-heads" => Ok(PhaseHeads),
+            "obsmarkers" => Ok(Obsmarkers),
+            "reply:obsmarkers" => Ok(ReplyObsmarkers),
+            "error:abort" => Ok(ErrorAbort),
+            "output" => Ok(Output),
             bad => bail_msg!("unknown header type {}", bad),
         }
     }
@@ -85,7 +107,17 @@ impl PartHeaderType {
             B2xTreegroup2 => "b2x:treegroup2",
             B2xInfinitepush => "b2x:infinitepush",
             B2xInfinitepushBookmarks => "b2x:infinitepushscratchbookmarks",
+            B2xRebase => "b2x:rebase",
+            B2xRebasePack => "b2x:rebasepackpart",
             CheckHeads => "check:heads",
+            CheckBookmarks => "check:bookmarks",
+            Pushkey => "pushkey",
+            ReplyPushkey => "reply:pushkey",
+            PhaseHeads => "phase-heads",
+            Obsmarkers => "obsmarkers",
+            ReplyObsmarkers => "reply:obsmarkers",
+            ErrorAbort => "error:abort",
+            Output => "output",
         }
     }
 }
@@ -392,6 +424,8 @@ impl Arbitrary for PartHeaderType {
             Listkeys,
             B2xTreegroup2,
             CheckHeads,
+            Pushkey,
+            ReplyPushkey,
         ]).expect("empty choice provided")
             .clone()
     }
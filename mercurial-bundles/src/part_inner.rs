@@ -13,8 +13,9 @@ use std::str;
 
 use slog;
 
+use byteorder::{BigEndian, ByteOrder};
 use bytes::Bytes;
-use futures::{future, Future, Stream};
+use futures::{future, stream, Future, Stream};
 use futures_ext::{BoxFuture, FutureExt, StreamWrapper};
 use tokio_io::AsyncRead;
 
@@ -24,6 +25,8 @@ use changegroup;
 use errors::*;
 use futures_ext::{StreamExt, StreamLayeredExt};
 use infinitepush;
+use mercurial_types::{NodeHash, NULL_HASH};
+use mercurial_types::obsmarker;
 use part_header::{PartHeader, PartHeaderType};
 use part_outer::{OuterFrame, OuterStream};
 use wirepack;
@@ -42,7 +45,16 @@ lazy_static! {
             "pushbackbookmarks", "cgversion", "bookmark", "bookprevnode", "create", "force"});
         m.insert(PartHeaderType::B2xInfinitepushBookmarks, hashset!{});
         m.insert(PartHeaderType::B2xTreegroup2, hashset!{"version", "cache", "category"});
+        m.insert(
+            PartHeaderType::B2xRebase,
+            hashset!{"onto", "newhead", "obsmarkerversions", "cgversion"},
+        );
+        m.insert(PartHeaderType::B2xRebasePack, hashset!{"version", "cache", "category"});
         m.insert(PartHeaderType::Replycaps, hashset!{});
+        m.insert(PartHeaderType::CheckHeads, hashset!{});
+        m.insert(PartHeaderType::CheckBookmarks, hashset!{});
+        m.insert(PartHeaderType::Pushkey, hashset!{"namespace", "key", "old", "new"});
+        m.insert(PartHeaderType::Obsmarkers, hashset!{});
         m
     };
 }
@@ -74,6 +86,14 @@ pub fn validate_header(header: PartHeader) -> Result<Option<PartHeader>> {
     }
 }
 
+// The changegroup version of a part, given by its `param` parameter, or 2 if it doesn't have it
+fn cg_version(header: &PartHeader, param: &str) -> Result<changegroup::CgVersion> {
+    match header.mparams().get(param).or(header.aparams().get(param)) {
+        Some(version) => changegroup::CgVersion::from_param(version),
+        None => Ok(changegroup::CgVersion::Cg2Version),
+    }
+}
+
 /// Convert an OuterStream into an InnerStream using the part header.
 pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
     header: PartHeader,
@@ -87,16 +107,28 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
 
     let bundle2item = match header.part_type() {
         &PartHeaderType::Changegroup => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::Changegroup(header, Box::new(cg2_stream))
+            let cg_stream = match cg_version(&header, "version") {
+                Ok(version) => wrapped_stream
+                    .decode(changegroup::unpacker::CgUnpacker::new(
+                        logger.new(o!("stream" => "cg", "version" => version.to_param())),
+                        version,
+                    ))
+                    .boxify(),
+                Err(err) => stream::once(Err(err)).boxify(),
+            };
+            Bundle2Item::Changegroup(header, cg_stream)
         }
         &PartHeaderType::B2xInfinitepush => {
-            let cg2_stream = wrapped_stream.decode(changegroup::unpacker::Cg2Unpacker::new(
-                logger.new(o!("stream" => "cg2")),
-            ));
-            Bundle2Item::B2xInfinitepush(header, Box::new(cg2_stream))
+            let cg_stream = match cg_version(&header, "cgversion") {
+                Ok(version) => wrapped_stream
+                    .decode(changegroup::unpacker::CgUnpacker::new(
+                        logger.new(o!("stream" => "cg", "version" => version.to_param())),
+                        version,
+                    ))
+                    .boxify(),
+                Err(err) => stream::once(Err(err)).boxify(),
+            };
+            Bundle2Item::B2xInfinitepush(header, cg_stream)
         }
         &PartHeaderType::B2xInfinitepushBookmarks => {
             let bookmarks_stream =
@@ -112,6 +144,25 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             ));
             Bundle2Item::B2xTreegroup2(header, Box::new(wirepack_stream))
         }
+        &PartHeaderType::B2xRebase => {
+            let cg_stream = match cg_version(&header, "cgversion") {
+                Ok(version) => wrapped_stream
+                    .decode(changegroup::unpacker::CgUnpacker::new(
+                        logger.new(o!("stream" => "cg", "version" => version.to_param())),
+                        version,
+                    ))
+                    .boxify(),
+                Err(err) => stream::once(Err(err)).boxify(),
+            };
+            Bundle2Item::B2xRebase(header, cg_stream)
+        }
+        &PartHeaderType::B2xRebasePack => {
+            let wirepack_stream = wrapped_stream.decode(wirepack::unpacker::new(
+                logger.new(o!("stream" => "wirepack")),
+                wirepack::Kind::Tree,
+            ));
+            Bundle2Item::B2xRebasePack(header, Box::new(wirepack_stream))
+        }
         &PartHeaderType::Replycaps => {
             let caps = wrapped_stream
                 .decode(capabilities::CapabilitiesUnpacker)
@@ -122,6 +173,44 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
                 });
             Bundle2Item::Replycaps(header, Box::new(caps))
         }
+        &PartHeaderType::CheckHeads => {
+            let heads = wrapped_stream
+                .fold(Vec::new(), |mut payload, chunk| {
+                    payload.extend_from_slice(&chunk);
+                    Ok::<_, Error>(payload)
+                })
+                .and_then(|payload| {
+                    ensure_msg!(
+                        payload.len() % 20 == 0,
+                        "Unexpected CheckHeads payload length: {}",
+                        payload.len()
+                    );
+                    payload.chunks(20).map(NodeHash::from_bytes).collect()
+                });
+            Bundle2Item::CheckHeads(header, Box::new(heads))
+        }
+        &PartHeaderType::CheckBookmarks => {
+            let bookmarks = wrapped_stream
+                .fold(Vec::new(), |mut payload, chunk| {
+                    payload.extend_from_slice(&chunk);
+                    Ok::<_, Error>(payload)
+                })
+                .and_then(|payload| decode_bookmarks(&payload));
+            Bundle2Item::CheckBookmarks(header, Box::new(bookmarks))
+        }
+        &PartHeaderType::Pushkey => {
+            let payload = wrapped_stream.for_each(|_| Ok(()));
+            Bundle2Item::Pushkey(header, Box::new(payload))
+        }
+        &PartHeaderType::Obsmarkers => {
+            let markers = wrapped_stream
+                .fold(Vec::new(), |mut payload, chunk| {
+                    payload.extend_from_slice(&chunk);
+                    Ok::<_, Error>(payload)
+                })
+                .and_then(|payload| obsmarker::decode_markers(&payload));
+            Bundle2Item::Obsmarkers(header, Box::new(markers))
+        }
         _ => panic!("TODO: make this an error"),
     };
 
@@ -133,3 +222,45 @@ pub fn inner_stream<R: AsyncRead + BufRead + 'static + Send>(
             .boxify(),
     )
 }
+
+/// Decode bookmarks in Mercurial's binary format: each is a changeset, or the null hash if the
+/// bookmark doesn't exist, then the length of its name as a big-endian u16 and the name.
+fn decode_bookmarks(mut payload: &[u8]) -> Result<Vec<(Bytes, Option<NodeHash>)>> {
+    let mut bookmarks = Vec::new();
+    while !payload.is_empty() {
+        ensure_msg!(payload.len() >= 22, "Truncated bookmark in payload");
+        let node = NodeHash::from_bytes(&payload[..20])?;
+        let len = BigEndian::read_u16(&payload[20..22]) as usize;
+        ensure_msg!(payload.len() >= 22 + len, "Truncated bookmark name in payload");
+        let name = Bytes::from(&payload[22..22 + len]);
+        let node = if node == NULL_HASH { None } else { Some(node) };
+        bookmarks.push((name, node));
+        payload = &payload[22 + len..];
+    }
+    Ok(bookmarks)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use mercurial_types_mocks::nodehash::ONES_HASH;
+
+    #[test]
+    fn bookmarks() {
+        let mut payload = Vec::new();
+        payload.extend_from_slice(ONES_HASH.as_ref());
+        payload.extend_from_slice(b"\x00\x06master");
+        payload.extend_from_slice(NULL_HASH.as_ref());
+        payload.extend_from_slice(b"\x00\x03new");
+
+        assert_eq!(
+            decode_bookmarks(&payload).unwrap(),
+            vec![
+                (Bytes::from("master"), Some(ONES_HASH)),
+                (Bytes::from("new"), None),
+            ]
+        );
+        assert!(decode_bookmarks(&payload[..30]).is_err());
+    }
+}
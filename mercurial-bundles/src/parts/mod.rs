@@ -6,19 +6,21 @@
 
 use std::fmt;
 
+use byteorder::{BigEndian, WriteBytesExt};
 use bytes::Bytes;
 use failure::err_msg;
 use futures::{Future, Stream};
 use futures::stream::{iter_ok, once};
 
-use super::changegroup::{CgDeltaChunk, Part, Section};
-use super::changegroup::packer::Cg2Packer;
+use super::changegroup::{CgDeltaChunk, CgVersion, Part, RevFlags, Section};
+use super::changegroup::packer::CgPacker;
 use super::wirepack;
 use super::wirepack::packer::WirePackPacker;
 
 use errors::*;
-use mercurial_types::{BlobNode, Delta, MPath, NodeHash, RepoPath, NULL_HASH};
+use mercurial_types::{BlobNode, Delta, MPath, NodeHash, ObsMarker, RepoPath, NULL_HASH};
 use mercurial_types::manifest::Entry;
+use mercurial_types::obsmarker;
 use part_encode::PartEncodeBuilder;
 use part_header::PartHeaderType;
 
@@ -48,48 +50,112 @@ where
     Ok(builder)
 }
 
-pub fn changegroup_part<S>(changelogentries: S) -> Result<PartEncodeBuilder>
+/// Build a changegroup part of `version`, 02 or 03. Every revision is sent as a full text.
+///
+/// Manifest and filelog entries come with the linknode of the changeset which introduced them.
+/// The manifests of directories other than the root, and the filelog entries, must be grouped by
+/// path, each group in topological order. Only version 03 can send the manifests of directories:
+/// the part fails to encode in version 02 if there are any.
+pub fn changegroup_part<CS, MS, TS, FS>(
+    version: CgVersion,
+    changelogentries: CS,
+    manifestentries: MS,
+    treemanifests: TS,
+    filelogs: FS,
+) -> Result<PartEncodeBuilder>
 where
-    S: Stream<Item = BlobNode, Error = Error> + Send + 'static,
+    CS: Stream<Item = BlobNode, Error = Error> + Send + 'static,
+    MS: Stream<Item = (BlobNode, NodeHash), Error = Error> + Send + 'static,
+    TS: Stream<Item = (MPath, Vec<(BlobNode, NodeHash)>), Error = Error> + Send + 'static,
+    FS: Stream<Item = (MPath, Vec<(BlobNode, NodeHash)>), Error = Error> + Send + 'static,
 {
     let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Changegroup)?;
-    builder.add_mparam("version", "02")?;
+    builder.add_mparam("version", version.to_param())?;
 
     let changelogentries = changelogentries.map(|blobnode| {
-        let node = blobnode.nodeid().expect("blobnode should store data");
-        let parents = blobnode.parents().get_nodes();
-        let p1 = *parents.0.unwrap_or(&NULL_HASH);
-        let p2 = *parents.1.unwrap_or(&NULL_HASH);
-        let base = NULL_HASH;
         // Linknode is the same as node
-        let linknode = node;
-        let text = blobnode.as_blob().as_inner().unwrap_or(&Bytes::new()).clone();
-        let delta = Delta::new_fulltext(text.to_vec());
-
-        let deltachunk = CgDeltaChunk {
-            node,
-            p1,
-            p2,
-            base,
-            linknode,
-            delta,
-        };
-        Part::CgChunk(Section::Changeset, deltachunk)
+        let linknode = blobnode.nodeid().expect("blobnode should store data");
+        Part::CgChunk(Section::Changeset, delta_chunk(blobnode, linknode))
     });
 
-    let changelogentries = changelogentries
+    let manifestentries = manifestentries.map(|(blobnode, linknode)| {
+        Part::CgChunk(Section::Manifest, delta_chunk(blobnode, linknode))
+    });
+
+    let treemanifests = treemanifests
+        .and_then(move |(path, entries)| {
+            if version != CgVersion::Cg3Version {
+                let msg = format!(
+                    "version {} can't encode the manifest of directory {}",
+                    version.to_param(),
+                    path
+                );
+                bail_err!(ErrorKind::CgEncode(msg));
+            }
+            Ok((path, entries))
+        })
+        .map(|(path, entries)| {
+            let chunks: Vec<_> = entries
+                .into_iter()
+                .map(|(blobnode, linknode)| {
+                    Part::CgChunk(
+                        Section::Treemanifest(path.clone()),
+                        delta_chunk(blobnode, linknode),
+                    )
+                })
+                .collect();
+            iter_ok(chunks).chain(once(Ok(Part::SectionEnd(Section::Treemanifest(path)))))
+        })
+        .flatten();
+
+    let filelogs = filelogs
+        .map(|(path, entries)| {
+            let chunks: Vec<_> = entries
+                .into_iter()
+                .map(|(blobnode, linknode)| {
+                    Part::CgChunk(
+                        Section::Filelog(path.clone()),
+                        delta_chunk(blobnode, linknode),
+                    )
+                })
+                .collect();
+            iter_ok(chunks).chain(once(Ok(Part::SectionEnd(Section::Filelog(path)))))
+        })
+        .flatten();
+
+    let parts = changelogentries
         .chain(once(Ok(Part::SectionEnd(Section::Changeset))))
-        // One more SectionEnd entry is necessary because hg client excepts filelog section
-        // even if it's empty. Add SectionEnd part with a fake file name
-        .chain(once(Ok(Part::SectionEnd(Section::Filelog(MPath::empty())))))
+        .chain(manifestentries)
+        .chain(once(Ok(Part::SectionEnd(Section::Manifest))))
+        .chain(treemanifests)
+        .chain(filelogs)
+        // The empty chunk after the last filelog ends the list of files
         .chain(once(Ok(Part::End)));
 
-    let cgdata = Cg2Packer::new(changelogentries);
+    let cgdata = CgPacker::new(parts, version);
     builder.set_data_generated(cgdata);
 
     Ok(builder)
 }
 
+fn delta_chunk(blobnode: BlobNode, linknode: NodeHash) -> CgDeltaChunk {
+    let node = blobnode.nodeid().expect("blobnode should store data");
+    let parents = blobnode.parents().get_nodes();
+    let p1 = *parents.0.unwrap_or(&NULL_HASH);
+    let p2 = *parents.1.unwrap_or(&NULL_HASH);
+    let text = blobnode.as_blob().as_inner().unwrap_or(&Bytes::new()).clone();
+
+    CgDeltaChunk {
+        node,
+        p1,
+        p2,
+        base: NULL_HASH,
+        linknode,
+        delta: Delta::new_fulltext(text.to_vec()),
+        flags: RevFlags::empty(),
+    }
+}
+
 pub fn treepack_part<S>(entries: S) -> Result<PartEncodeBuilder>
 where
     S: Stream<Item = (Box<Entry + Sync>, NodeHash, MPath), Error = Error> + Send + 'static,
@@ -212,3 +278,64 @@ pub fn replychangegroup_part(
 
     Ok(builder)
 }
+
+/// Build a phase-heads part from the heads of each phase, given as Mercurial's phase numbers.
+/// Clients move the heads and their ancestors to at least that phase.
+pub fn phase_heads_part<I>(heads: I) -> Result<PartEncodeBuilder>
+where
+    I: IntoIterator<Item = (u32, NodeHash)>,
+{
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::PhaseHeads)?;
+    let mut payload = Vec::new();
+    for (phase, node) in heads {
+        payload.write_u32::<BigEndian>(phase)?;
+        payload.extend_from_slice(node.as_ref());
+    }
+    builder.set_data_bytes(payload)?;
+
+    Ok(builder)
+}
+
+pub fn replypushkey_part(res: bool, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyPushkey)?;
+    builder.add_mparam("return", if res { "1" } else { "0" })?;
+    builder.add_mparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
+
+/// Build an obsmarkers part, with the markers in version 1 of the obsstore format.
+pub fn obsmarkers_part(markers: Vec<ObsMarker>) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::Obsmarkers)?;
+    builder.set_data_bytes(obsmarker::encode_markers(&markers)?)?;
+
+    Ok(builder)
+}
+
+pub fn replyobsmarkers_part(new: usize, in_reply_to: u32) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ReplyObsmarkers)?;
+    builder.add_aparam("new", format!("{}", new))?;
+    builder.add_aparam("in-reply-to", format!("{}", in_reply_to))?;
+
+    Ok(builder)
+}
+
+/// Build an output part, whose content the client shows to its user, prefixed with "remote: ".
+pub fn output_part<B: Into<Bytes>>(output: B) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::advisory(PartHeaderType::Output)?;
+    builder.set_data_bytes(output)?;
+
+    Ok(builder)
+}
+
+/// Build an error:abort part, which makes the client abort with the message, and the hint if any.
+/// Parameters are at most 255 bytes long, so longer explanations go in an output part.
+pub fn error_abort_part(message: String, hint: Option<String>) -> Result<PartEncodeBuilder> {
+    let mut builder = PartEncodeBuilder::mandatory(PartHeaderType::ErrorAbort)?;
+    builder.add_mparam("message", message)?;
+    if let Some(hint) = hint {
+        builder.add_aparam("hint", hint)?;
+    }
+
+    Ok(builder)
+}
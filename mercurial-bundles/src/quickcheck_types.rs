@@ -47,19 +47,39 @@ impl Arbitrary for QCBytes {
 }
 
 #[derive(Clone, Debug)]
-pub struct Cg2PartSequence {
+pub struct CgPartSequence {
+    version: changegroup::CgVersion,
     // Storing the ends in here bypasses a number of lifetime issues.
     changesets: Vec<changegroup::Part>,
     changesets_end: changegroup::Part,
     manifests: Vec<changegroup::Part>,
     manifests_end: changegroup::Part,
+    // Only in version 3
+    treemanifests: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     filelogs: Vec<(Vec<changegroup::Part>, changegroup::Part)>,
     end: changegroup::Part,
 }
 
-impl Cg2PartSequence {
+impl CgPartSequence {
+    pub fn version(&self) -> changegroup::CgVersion {
+        self.version
+    }
+
     /// Combine all the changesets, manifests and filelogs into a single iterator.
     pub fn as_iter<'a>(&'a self) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
+        // If there are no parts in a tree manifest or filelog, it isn't valid to return a
+        // SectionEnd since that won't be referring to anything. So just skip the whole section.
+        fn sections<'a>(
+            sections: &'a [(Vec<changegroup::Part>, changegroup::Part)],
+        ) -> Box<Iterator<Item = &'a changegroup::Part> + 'a> {
+            Box::new(
+                sections
+                    .iter()
+                    .filter(|&&(ref parts, _)| !parts.is_empty())
+                    .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
+            )
+        }
+
         // Trying to describe the type here is madness. Just box it.
         Box::new(
             self.changesets
@@ -67,17 +87,8 @@ impl Cg2PartSequence {
                 .chain(iter::once(&self.changesets_end))
                 .chain(self.manifests.iter())
                 .chain(iter::once(&self.manifests_end))
-                .chain(
-                    self.filelogs
-                        .iter()
-                        .filter(|&&(ref parts, _)| {
-                            // If there are no filelog parts, it isn't valid to return a
-                            // SectionEnd since that won't be referring to anything. So
-                            // just skip the whole filelog.
-                            !parts.is_empty()
-                        })
-                        .flat_map(|&(ref parts, ref end)| parts.iter().chain(iter::once(end))),
-                )
+                .chain(sections(&self.treemanifests))
+                .chain(sections(&self.filelogs))
                 .chain(iter::once(&self.end)),
         )
     }
@@ -95,42 +106,56 @@ impl Cg2PartSequence {
     }
 }
 
-impl PartialEq<[changegroup::Part]> for Cg2PartSequence {
+impl PartialEq<[changegroup::Part]> for CgPartSequence {
     fn eq(&self, other: &[changegroup::Part]) -> bool {
         self.as_iter().eq(other.iter())
     }
 }
 
-impl Arbitrary for Cg2PartSequence {
+impl Arbitrary for CgPartSequence {
     fn arbitrary<G: Gen>(g: &mut G) -> Self {
         use changegroup::*;
 
-        // Generate a valid part sequence (changegroup, then manifest, then filelogs).
+        // Generate a valid part sequence (changegroup, then manifest, then tree manifests in
+        // version 3, then filelogs).
         let size = g.size();
+        let version = if g.gen() {
+            CgVersion::Cg3Version
+        } else {
+            CgVersion::Cg2Version
+        };
 
-        let changesets = gen_parts(Section::Changeset, g);
-        let manifests = gen_parts(Section::Manifest, g);
+        let changesets = gen_parts(Section::Changeset, version, g);
+        let manifests = gen_parts(Section::Manifest, version, g);
+
+        let mut treemanifests = Vec::new();
+        if version == CgVersion::Cg3Version {
+            for _ in 0..g.gen_range(0, size) {
+                let dir = gen_nonempty_path(g);
+                let section_end = Part::SectionEnd(Section::Treemanifest(dir.clone()));
+                treemanifests.push((
+                    gen_parts(Section::Treemanifest(dir), version, g),
+                    section_end,
+                ));
+            }
+        }
 
         let nfilelogs = g.gen_range(0, size);
         let mut filelogs = Vec::with_capacity(nfilelogs);
 
         for _ in 0..nfilelogs {
-            // Changegroups can't support empty paths, so skip over those.
-            let path = loop {
-                let path = MPath::arbitrary(g);
-                if !path.is_empty() {
-                    break path;
-                }
-            };
+            let path = gen_nonempty_path(g);
             let section_end = Part::SectionEnd(Section::Filelog(path.clone()));
-            filelogs.push((gen_parts(Section::Filelog(path), g), section_end));
+            filelogs.push((gen_parts(Section::Filelog(path), version, g), section_end));
         }
 
-        Cg2PartSequence {
+        CgPartSequence {
+            version: version,
             changesets: changesets,
             changesets_end: Part::SectionEnd(Section::Changeset),
             manifests: manifests,
             manifests_end: Part::SectionEnd(Section::Manifest),
+            treemanifests: treemanifests,
             filelogs: filelogs,
             end: Part::End,
         }
@@ -142,17 +167,21 @@ impl Arbitrary for Cg2PartSequence {
         // All the parts can be shrinked independently as long as the section
         // remains the same (ensured in the impl of Arbitrary for
         // changegroup::Part).
+        let version = self.version;
         Box::new(
             (
                 self.changesets.clone(),
                 self.manifests.clone(),
+                self.treemanifests.clone(),
                 self.filelogs.clone(),
             ).shrink()
-                .map(|(c, m, f)| Cg2PartSequence {
+                .map(move |(c, m, t, f)| CgPartSequence {
+                    version: version,
                     changesets: c,
                     changesets_end: Part::SectionEnd(Section::Changeset),
                     manifests: m,
                     manifests_end: Part::SectionEnd(Section::Manifest),
+                    treemanifests: t,
                     filelogs: f,
                     end: Part::End,
                 }),
@@ -160,15 +189,33 @@ impl Arbitrary for Cg2PartSequence {
     }
 }
 
-fn gen_parts<G: Gen>(section: changegroup::Section, g: &mut G) -> Vec<changegroup::Part> {
+fn gen_parts<G: Gen>(
+    section: changegroup::Section,
+    version: changegroup::CgVersion,
+    g: &mut G,
+) -> Vec<changegroup::Part> {
     let size = g.size();
     (0..g.gen_range(0, size))
         .map(|_| {
-            changegroup::Part::CgChunk(section.clone(), changegroup::CgDeltaChunk::arbitrary(g))
+            let mut chunk = changegroup::CgDeltaChunk::arbitrary(g);
+            if version == changegroup::CgVersion::Cg3Version {
+                chunk.flags = changegroup::RevFlags(g.gen());
+            }
+            changegroup::Part::CgChunk(section.clone(), chunk)
         })
         .collect()
 }
 
+fn gen_nonempty_path<G: Gen>(g: &mut G) -> MPath {
+    // Changegroups can't support empty paths, so skip over those.
+    loop {
+        let path = MPath::arbitrary(g);
+        if !path.is_empty() {
+            return path;
+        }
+    }
+}
+
 impl Arbitrary for changegroup::Part {
     fn arbitrary<G: Gen>(_g: &mut G) -> Self {
         unimplemented!()
@@ -202,6 +249,8 @@ impl Arbitrary for changegroup::CgDeltaChunk {
             base: NodeHash::arbitrary(g),
             linknode: NodeHash::arbitrary(g),
             delta: Delta::arbitrary(g),
+            // Only version 3 has flags
+            flags: changegroup::RevFlags::empty(),
         }
     }
 
@@ -218,6 +267,7 @@ impl Arbitrary for changegroup::CgDeltaChunk {
                     base: clone.base.clone(),
                     linknode: clone.linknode.clone(),
                     delta: delta,
+                    flags: clone.flags,
                 }),
         )
     }
@@ -11,7 +11,7 @@ use std::io::{self, BufRead, BufReader, Cursor};
 use std::iter::Iterator;
 use std::str::FromStr;
 
-use futures::stream::Stream;
+use futures::stream::{self, Stream};
 use futures_ext::BoxStream;
 use slog::{Drain, Logger};
 use slog_term;
@@ -20,7 +20,7 @@ use tokio_io::AsyncRead;
 
 use async_compression::{Bzip2Compression, CompressorType, FlateCompression};
 use async_compression::membuf::MemBuf;
-use mercurial_types::{MPath, NodeHash, RepoPath, NULL_HASH};
+use mercurial_types::{Delta, MPath, NodeHash, RepoPath, NULL_HASH};
 use partial_io::{GenWouldBlock, PartialAsyncRead, PartialWithErrors};
 use quickcheck::{QuickCheck, StdGen};
 use rand;
@@ -28,6 +28,7 @@ use rand;
 use Bundle2Item;
 use bundle2::{Bundle2Stream, StreamEvent};
 use bundle2_encode::Bundle2EncodeBuilder;
+use bundle_file::{read_bundle, write_bundle1, BundleFile};
 use changegroup;
 use errors::*;
 use part_encode::PartEncodeBuilder;
@@ -89,6 +90,11 @@ fn test_empty_bundle_roundtrip_gzip() {
     empty_bundle_roundtrip(Some(CompressorType::Gzip(FlateCompression::best())));
 }
 
+#[test]
+fn test_empty_bundle_roundtrip_zstd() {
+    empty_bundle_roundtrip(Some(CompressorType::Zstd { level: 0 }));
+}
+
 #[test]
 fn test_empty_bundle_roundtrip_uncompressed() {
     empty_bundle_roundtrip(None);
@@ -148,6 +154,11 @@ fn test_unknown_part_gzip() {
     unknown_part(Some(CompressorType::Gzip(FlateCompression::best())));
 }
 
+#[test]
+fn test_unknown_part_zstd() {
+    unknown_part(Some(CompressorType::Zstd { level: 0 }));
+}
+
 #[test]
 fn test_unknown_part_uncompressed() {
     unknown_part(None);
@@ -522,6 +533,145 @@ fn parse_wirepack(read_ops: PartialWithErrors<GenWouldBlock>) {
     assert!(stream.app_errors().is_empty());
 }
 
+#[test]
+fn test_read_bundle2_file() {
+    let mut core = Core::new().unwrap();
+    let read = BufReader::new(MemBuf::from(Vec::from(UNCOMP_BUNDLE2)));
+    let bundle = core.run(read_bundle(read, make_root_logger())).unwrap();
+    let stream = match bundle {
+        BundleFile::Bundle2(stream) => stream,
+        BundleFile::Bundle1(_) => panic!("expected a bundle2"),
+    };
+
+    let (res, stream) = core.next_stream(stream);
+    let header = res.unwrap().into_next().unwrap().unwrap_start();
+    assert!(header.m_stream_params.is_empty());
+
+    let (res, _) = core.next_stream(stream);
+    assert_matches!(res, Some(StreamEvent::Next(Bundle2Item::Changegroup(..))));
+}
+
+#[test]
+fn test_bundle1_roundtrip_uncompressed() {
+    bundle1_roundtrip(None);
+}
+
+#[test]
+fn test_bundle1_roundtrip_gzip() {
+    bundle1_roundtrip(Some(CompressorType::Gzip(FlateCompression::best())));
+}
+
+#[test]
+fn test_bundle1_roundtrip_bzip2() {
+    bundle1_roundtrip(Some(CompressorType::Bzip2(Bzip2Compression::Default)));
+}
+
+fn bundle1_roundtrip(compression: Option<CompressorType>) {
+    let mut core = Core::new().unwrap();
+    let parts = bundle1_parts();
+
+    let write = write_bundle1(
+        Cursor::new(Vec::new()),
+        stream::iter_ok(parts.clone()),
+        compression,
+    );
+    let bundle = core.run(write).unwrap().into_inner();
+
+    let bundle = core.run(read_bundle(Cursor::new(bundle), make_root_logger()))
+        .unwrap();
+    let cg = match bundle {
+        BundleFile::Bundle1(cg) => cg,
+        BundleFile::Bundle2(_) => panic!("expected a v1 bundle"),
+    };
+    assert_eq!(core.run(cg.collect()).unwrap(), parts);
+}
+
+#[test]
+fn test_bundle1_delta_base() {
+    let mut core = Core::new().unwrap();
+    let mut parts = bundle1_parts();
+    // Version 1 can't encode a delta of the second changeset against something else than the
+    // first one.
+    if let changegroup::Part::CgChunk(_, ref mut chunk) = parts[1] {
+        chunk.base = NULL_HASH;
+    }
+
+    let write = write_bundle1(Cursor::new(Vec::new()), stream::iter_ok(parts), None);
+    assert_matches!(
+        core.run(write).unwrap_err().downcast::<ErrorKind>().unwrap(),
+        ErrorKind::CgEncode(_)
+    );
+}
+
+#[test]
+fn test_bundle1_zstd() {
+    let mut core = Core::new().unwrap();
+    let write = write_bundle1(
+        Cursor::new(Vec::new()),
+        stream::iter_ok(bundle1_parts()),
+        Some(CompressorType::Zstd { level: 3 }),
+    );
+    assert_matches!(
+        core.run(write).unwrap_err().downcast::<ErrorKind>().unwrap(),
+        ErrorKind::BundleFileEncode(_)
+    );
+}
+
+#[test]
+fn test_read_bundle_invalid() {
+    let mut core = Core::new().unwrap();
+    for input in &[&b"HG30"[..], &b"HG10XZ"[..]] {
+        let read = read_bundle(Cursor::new(input.to_vec()), make_root_logger());
+        assert_matches!(
+            core.run(read).err().unwrap().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::BundleFileDecode(_)
+        );
+    }
+}
+
+/// A changegroup whose deltas are all against the previous revision of their section, or the
+/// first parent for the first one, as a v1 bundle needs.
+fn bundle1_parts() -> Vec<changegroup::Part> {
+    use changegroup::{CgDeltaChunk, Part, RevFlags, Section};
+
+    let changeset1 = NodeHash::from_str(CHANGESET1_HASH_STR).unwrap();
+    let changeset2 = NodeHash::from_str(CHANGESET2_HASH_STR).unwrap();
+    let manifest1 = NodeHash::from_str(MANIFEST1_HASH_STR).unwrap();
+    let abc = NodeHash::from_str(ABC_HASH_STR).unwrap();
+    let chunk = |node, p1, linknode, text: &[u8]| CgDeltaChunk {
+        node,
+        p1,
+        p2: NULL_HASH,
+        base: p1,
+        linknode,
+        delta: Delta::new_fulltext(text),
+        flags: RevFlags::empty(),
+    };
+
+    vec![
+        Part::CgChunk(
+            Section::Changeset,
+            chunk(changeset1, NULL_HASH, changeset1, b"changeset1"),
+        ),
+        Part::CgChunk(
+            Section::Changeset,
+            chunk(changeset2, changeset1, changeset2, b"changeset2"),
+        ),
+        Part::SectionEnd(Section::Changeset),
+        Part::CgChunk(
+            Section::Manifest,
+            chunk(manifest1, NULL_HASH, changeset1, b"manifest1"),
+        ),
+        Part::SectionEnd(Section::Manifest),
+        Part::CgChunk(
+            Section::Filelog(path(b"abc")),
+            chunk(abc, NULL_HASH, changeset1, b"abc"),
+        ),
+        Part::SectionEnd(Section::Filelog(path(b"abc"))),
+        Part::End,
+    ]
+}
+
 fn path(bytes: &[u8]) -> MPath {
     MPath::new(bytes).unwrap()
 }
@@ -5,12 +5,36 @@
 // GNU General Public License version 2 or any later version.
 
 use std::collections::BTreeMap;
+use std::str;
 
 use mononoke_types::MPath;
 
 use blobnode::Parents;
-use nodehash::ManifestId;
+use errors::*;
+use nodehash::{ManifestId, NodeHash};
 
+/// The keys of the extra fields Mercurial and its extensions give a meaning to
+pub mod extra {
+    pub const BRANCH: &[u8] = b"branch";
+    pub const CLOSE: &[u8] = b"close";
+    /// Changeset a graft or transplant was copied from
+    pub const SOURCE: &[u8] = b"source";
+    pub const REBASE_SOURCE: &[u8] = b"rebase_source";
+    pub const AMEND_SOURCE: &[u8] = b"amend_source";
+    /// When the committer isn't the author given as the user
+    pub const COMMITTER: &[u8] = b"committer";
+    /// Comma separated predecessors recorded by mutation tracking
+    pub const MUTATION_PREDECESSORS: &[u8] = b"mutpred";
+    /// The operation which made the changeset from its mutation predecessors, e.g. "amend"
+    pub const MUTATION_OPERATION: &[u8] = b"mutop";
+    pub const MUTATION_USER: &[u8] = b"mutuser";
+    pub const MUTATION_DATE: &[u8] = b"mutdate";
+}
+
+const DEFAULT_BRANCH: &[u8] = b"default";
+
+/// A changeset. The extra fields are kept as they are, so that a changeset is stored with the
+/// same hash whatever they contain; the accessors below only interpret them.
 pub trait Changeset: Send + 'static {
     fn manifestid(&self) -> &ManifestId;
     fn user(&self) -> &[u8];
@@ -20,6 +44,56 @@ pub trait Changeset: Send + 'static {
     fn time(&self) -> &Time;
     fn parents(&self) -> &Parents;
 
+    fn extra_field(&self, key: &[u8]) -> Option<&[u8]> {
+        self.extra().get(key).map(Vec::as_slice)
+    }
+
+    /// The named branch, which Mercurial omits for the default branch.
+    fn branch(&self) -> &[u8] {
+        self.extra_field(extra::BRANCH).unwrap_or(DEFAULT_BRANCH)
+    }
+
+    fn closes_branch(&self) -> bool {
+        self.extra().contains_key(extra::CLOSE)
+    }
+
+    /// The changeset a graft or transplant copied this one from.
+    fn source(&self) -> Result<Option<NodeHash>> {
+        parse_extra_node(self.extra_field(extra::SOURCE))
+    }
+
+    fn rebase_source(&self) -> Result<Option<NodeHash>> {
+        parse_extra_node(self.extra_field(extra::REBASE_SOURCE))
+    }
+
+    fn amend_source(&self) -> Result<Option<NodeHash>> {
+        parse_extra_node(self.extra_field(extra::AMEND_SOURCE))
+    }
+
+    fn committer(&self) -> Option<&[u8]> {
+        self.extra_field(extra::COMMITTER)
+    }
+
+    /// The changesets this one replaces, as recorded by mutation tracking, or else by rebase or
+    /// amend.
+    fn predecessors(&self) -> Result<Vec<NodeHash>> {
+        if let Some(preds) = self.extra_field(extra::MUTATION_PREDECESSORS) {
+            return preds
+                .split(|c| *c == b',')
+                .map(|pred| parse_extra_node(Some(pred)).map(Option::unwrap))
+                .collect();
+        }
+        let source = match self.rebase_source()? {
+            Some(source) => Some(source),
+            None => self.amend_source()?,
+        };
+        Ok(source.into_iter().collect())
+    }
+
+    fn mutation_operation(&self) -> Option<&[u8]> {
+        self.extra_field(extra::MUTATION_OPERATION)
+    }
+
     fn boxed(self) -> Box<Changeset>
     where
         Self: Sized,
@@ -58,6 +132,19 @@ impl Changeset for Box<Changeset> {
     }
 }
 
+fn parse_extra_node(value: Option<&[u8]>) -> Result<Option<NodeHash>> {
+    let value = match value {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    let hex = str::from_utf8(value)
+        .map_err(|_| ErrorKind::InvalidChangesetExtra(format!("{:?}", value)))?;
+    if hex.len() != 40 {
+        return Err(ErrorKind::InvalidChangesetExtra(hex.into()).into());
+    }
+    hex.parse().map(Some)
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash)]
 pub struct Time {
     pub time: u64,
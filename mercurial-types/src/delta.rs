@@ -4,6 +4,7 @@
 // This software may be used and distributed according to the terms of the
 // GNU General Public License version 2 or any later version.
 
+use bytes::Bytes;
 use quickcheck::{Arbitrary, Gen};
 use rand::distributions::{IndependentSample, LogNormal};
 
@@ -236,6 +237,106 @@ pub fn apply_chain<I: IntoIterator<Item = Delta>>(text: &[u8], deltas: I) -> Vec
     res
 }
 
+/// Past this many segments, a `Rope` is flattened so that long delta chains don't fragment it.
+const MAX_ROPE_SEGMENTS: usize = 256;
+
+/// A text made of slices of the texts and fragments it was built from, so that applying a
+/// `Delta` to it shares the unchanged regions with the base instead of copying them.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Rope {
+    segments: Vec<Bytes>,
+    len: usize,
+}
+
+impl Rope {
+    pub fn new(text: Bytes) -> Self {
+        let len = text.len();
+        let segments = if text.is_empty() { vec![] } else { vec![text] };
+        Rope { segments, len }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Apply a Delta, whose fragment contents are moved into the result rather than copied.
+    pub fn apply(&self, delta: Delta) -> Rope {
+        let mut rope = Rope::default();
+        let mut off = 0;
+
+        for frag in delta.frags {
+            assert!(off <= frag.start);
+            self.slice_into(off, frag.start, &mut rope);
+            rope.push(Bytes::from(frag.content));
+            off = frag.end;
+        }
+        if off < self.len {
+            self.slice_into(off, self.len, &mut rope);
+        }
+
+        if rope.segments.len() > MAX_ROPE_SEGMENTS {
+            Rope::new(rope.into_bytes())
+        } else {
+            rope
+        }
+    }
+
+    /// The contiguous text, which is only copied if it's made of several segments.
+    pub fn into_bytes(mut self) -> Bytes {
+        match self.segments.len() {
+            0 => Bytes::new(),
+            1 => self.segments.pop().unwrap(),
+            _ => {
+                let mut output = Vec::with_capacity(self.len);
+                for segment in &self.segments {
+                    output.extend_from_slice(segment);
+                }
+                Bytes::from(output)
+            }
+        }
+    }
+
+    fn push(&mut self, segment: Bytes) {
+        if !segment.is_empty() {
+            self.len += segment.len();
+            self.segments.push(segment);
+        }
+    }
+
+    /// Push the segments of the text from `start` to `end` onto `rope`.
+    fn slice_into(&self, start: usize, end: usize, rope: &mut Rope) {
+        let mut seg_start = 0;
+        for segment in &self.segments {
+            let seg_end = seg_start + segment.len();
+            if seg_end > start && seg_start < end {
+                let from = start.max(seg_start) - seg_start;
+                let to = end.min(seg_end) - seg_start;
+                rope.push(segment.slice(from, to));
+            }
+            if seg_end >= end {
+                break;
+            }
+            seg_start = seg_end;
+        }
+    }
+}
+
+impl From<Bytes> for Rope {
+    fn from(text: Bytes) -> Self {
+        Rope::new(text)
+    }
+}
+
+/// Apply a Delta to an input text without copying the regions it leaves unchanged, unless the
+/// result has to be assembled from several pieces.
+pub fn apply_bytes(text: Bytes, delta: Delta) -> Bytes {
+    Rope::new(text).apply(delta).into_bytes()
+}
+
 /// XXX: Compatibility functions for the old bdiff module for testing purposes. The delta
 /// module will replace that one once all instances of Vec<bdiff::Delta> are replaced
 /// with delta::Delta, and this compatibility module will be removed at that time.
@@ -459,4 +560,76 @@ mod tests {
         let res = apply(text, &delta);
         assert_eq!(&res[..], b"aaaa\ncccc\n");
     }
+
+    #[test]
+    fn test_rope_apply_chain() {
+        let text = Bytes::from(&b"aaaa\nbbbb\ncccc\n"[..]);
+        let delta1 = Delta {
+            frags: vec![
+                Fragment {
+                    start: 5,
+                    end: 10,
+                    content: (&b"xxxx\n"[..]).into(),
+                },
+            ],
+        };
+        let delta2 = Delta {
+            frags: vec![
+                Fragment {
+                    start: 0,
+                    end: 2,
+                    content: (&b"zz"[..]).into(),
+                },
+                Fragment {
+                    start: 7,
+                    end: 12,
+                    content: (&b""[..]).into(),
+                },
+            ],
+        };
+
+        let rope = Rope::new(text.clone()).apply(delta1.clone());
+        assert_eq!(rope.len(), 15);
+        let rope = rope.apply(delta2.clone());
+        let expected = apply_chain(&text, vec![delta1, delta2]);
+        assert_eq!(rope.len(), expected.len());
+        assert_eq!(&rope.into_bytes()[..], &expected[..]);
+    }
+
+    #[test]
+    fn test_apply_bytes_shares_unchanged_text() {
+        // Big enough not to be stored inline in the Bytes, which would copy it
+        let mut text = vec![b'a'; 100];
+        text.extend_from_slice(&[b'b'; 100]);
+        let text = Bytes::from(text);
+
+        let res = apply_bytes(text.clone(), Delta::default());
+        assert_eq!(res, text);
+        assert_eq!(res.as_ptr(), text.as_ptr());
+
+        // Truncating the text only slices it
+        let delta = Delta {
+            frags: vec![
+                Fragment {
+                    start: 100,
+                    end: 200,
+                    content: vec![],
+                },
+            ],
+        };
+        let res = apply_bytes(text.clone(), delta);
+        assert_eq!(&res[..], &text[..100]);
+        assert_eq!(res.as_ptr(), text.as_ptr());
+    }
+
+    quickcheck! {
+        fn rope_matches_apply(text: Vec<u8>, delta: Delta) -> bool {
+            // Only deltas which fit the text can be applied
+            let fits = delta.fragments().last().map_or(true, |frag| frag.end <= text.len());
+            !fits || {
+                let expected = apply(&text, &delta);
+                &apply_bytes(Bytes::from(text), delta)[..] == &expected[..]
+            }
+        }
+    }
 }
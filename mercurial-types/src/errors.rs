@@ -10,6 +10,8 @@ pub use failure::{Error, ResultExt};
 pub enum ErrorKind {
     #[fail(display = "invalid sha-1 input: {}", _0)] InvalidSha1Input(String),
     #[fail(display = "invalid fragment list: {}", _0)] InvalidFragmentList(String),
+    #[fail(display = "invalid obsolescence marker: {}", _0)] InvalidObsMarker(String),
+    #[fail(display = "invalid changeset id in extra: {}", _0)] InvalidChangesetExtra(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -84,6 +84,7 @@ pub mod errors;
 pub mod fsencode;
 pub mod hash;
 pub mod nodehash;
+pub mod obsmarker;
 pub mod utils;
 pub mod manifest;
 pub mod manifest_utils;
@@ -102,6 +103,7 @@ pub use fsencode::{fncache_fsencode, simple_fsencode};
 pub use manifest::{Entry, Manifest, Type};
 pub use node::Node;
 pub use nodehash::{ChangesetId, EntryId, ManifestId, NodeHash, NULL_HASH};
+pub use obsmarker::ObsMarker;
 pub use repo::RepositoryId;
 pub use utils::percent_encode;
 
@@ -0,0 +1,390 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Obsolescence markers, which record that a changeset was rewritten into others or pruned, and
+//! the binary formats Mercurial stores and exchanges them in.
+
+use std::collections::BTreeMap;
+use std::io::Cursor;
+
+use bytes::{BigEndian, Buf, BufMut, Bytes, BytesMut};
+
+use errors::*;
+use nodehash::NodeHash;
+
+/// Flag of markers whose nodes are SHA-256 hashes, which aren't supported.
+const FLAG_USING_SHA256: u16 = 2;
+
+const FM0_VERSION: u8 = 0;
+const FM1_VERSION: u8 = 1;
+/// Size of the fields every version 1 marker starts with.
+const FM1_FIXED_SIZE: usize = 39;
+/// Number of parents of a version 1 marker which doesn't record them.
+const FM1_PARENT_NONE: u8 = 3;
+/// Size above which markers are split between several keys of the `obsolete` pushkey namespace.
+const PUSHKEY_MAX_PAYLOAD: usize = 5300;
+
+const B85_CHARS: &[u8] =
+    b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz!#$%&()*+-;<=>?@^_`{|}~";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ObsMarker {
+    /// The changeset which was rewritten.
+    pub precursor: NodeHash,
+    /// What the precursor was rewritten into. A marker without successors prunes it.
+    pub successors: Vec<NodeHash>,
+    pub flags: u16,
+    /// The parents of the precursor, if the marker records them.
+    pub parents: Option<Vec<NodeHash>>,
+    /// When the marker was created, as seconds since the epoch and an offset from UTC in seconds.
+    pub date: (f64, i32),
+    pub metadata: Vec<(Vec<u8>, Vec<u8>)>,
+}
+
+impl ObsMarker {
+    fn parents(&self) -> &[NodeHash] {
+        self.parents.as_ref().map_or(&[][..], |parents| parents.as_slice())
+    }
+
+    fn check_encodable(&self) -> Result<()> {
+        if self.successors.len() > 255 {
+            bail!(ErrorKind::InvalidObsMarker("too many successors".into()));
+        }
+        if self.parents().len() > 2 {
+            bail!(ErrorKind::InvalidObsMarker("too many parents".into()));
+        }
+        if self.metadata.len() > 255 {
+            bail!(ErrorKind::InvalidObsMarker("too many metadata entries".into()));
+        }
+        for &(ref key, ref value) in &self.metadata {
+            if key.len() > 255 || value.len() > 255 {
+                bail!(ErrorKind::InvalidObsMarker("metadata entry too long".into()));
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_v1(&self, buf: &mut BytesMut) -> Result<()> {
+        self.check_encodable()?;
+
+        let numpar = match self.parents {
+            Some(ref parents) => parents.len() as u8,
+            None => FM1_PARENT_NONE,
+        };
+        let nodes = self.successors.len() + self.parents().len();
+        let metadata_size: usize = self.metadata
+            .iter()
+            .map(|&(ref key, ref value)| 2 + key.len() + value.len())
+            .sum();
+        let size = FM1_FIXED_SIZE + 20 * nodes + metadata_size;
+
+        buf.reserve(size);
+        buf.put_u32::<BigEndian>(size as u32);
+        buf.put_f64::<BigEndian>(self.date.0);
+        // The offset is stored in minutes
+        buf.put_i16::<BigEndian>((self.date.1 / 60) as i16);
+        buf.put_u16::<BigEndian>(self.flags);
+        buf.put_u8(self.successors.len() as u8);
+        buf.put_u8(numpar);
+        buf.put_u8(self.metadata.len() as u8);
+        buf.put_slice(self.precursor.as_ref());
+        for node in self.successors.iter().chain(self.parents()) {
+            buf.put_slice(node.as_ref());
+        }
+        for &(ref key, ref value) in &self.metadata {
+            buf.put_u8(key.len() as u8);
+            buf.put_u8(value.len() as u8);
+        }
+        for &(ref key, ref value) in &self.metadata {
+            buf.put_slice(key);
+            buf.put_slice(value);
+        }
+        Ok(())
+    }
+
+    fn decode_v1(buf: &mut Cursor<&[u8]>) -> Result<Self> {
+        fn read_node(buf: &mut Cursor<&[u8]>) -> Result<NodeHash> {
+            if buf.remaining() < 20 {
+                bail!(ErrorKind::InvalidObsMarker("truncated marker".into()));
+            }
+            let mut node = [0; 20];
+            buf.copy_to_slice(&mut node);
+            NodeHash::from_bytes(&node)
+        }
+
+        let start = buf.position();
+        if buf.remaining() < FM1_FIXED_SIZE {
+            bail!(ErrorKind::InvalidObsMarker("truncated marker".into()));
+        }
+        let size = buf.get_u32::<BigEndian>() as usize;
+        let time = buf.get_f64::<BigEndian>();
+        let tz = buf.get_i16::<BigEndian>() as i32 * 60;
+        let flags = buf.get_u16::<BigEndian>();
+        let numsuc = buf.get_u8() as usize;
+        let numpar = buf.get_u8();
+        let nummeta = buf.get_u8() as usize;
+
+        if flags & FLAG_USING_SHA256 != 0 {
+            bail!(ErrorKind::InvalidObsMarker("SHA-256 nodes are not supported".into()));
+        }
+        if numpar > 2 && numpar != FM1_PARENT_NONE {
+            bail!(ErrorKind::InvalidObsMarker(format!("bad number of parents {}", numpar)));
+        }
+        if size < FM1_FIXED_SIZE || buf.remaining() < size - FM1_FIXED_SIZE + 20 {
+            bail!(ErrorKind::InvalidObsMarker("truncated marker".into()));
+        }
+
+        let precursor = read_node(buf)?;
+        let successors = (0..numsuc)
+            .map(|_| read_node(buf))
+            .collect::<Result<Vec<_>>>()?;
+        let parents = if numpar == FM1_PARENT_NONE {
+            None
+        } else {
+            Some((0..numpar)
+                .map(|_| read_node(buf))
+                .collect::<Result<Vec<_>>>()?)
+        };
+
+        if buf.remaining() < 2 * nummeta {
+            bail!(ErrorKind::InvalidObsMarker("truncated metadata".into()));
+        }
+        let lengths: Vec<_> = (0..nummeta)
+            .map(|_| (buf.get_u8() as usize, buf.get_u8() as usize))
+            .collect();
+        let mut metadata = Vec::with_capacity(nummeta);
+        for (key_len, value_len) in lengths {
+            if buf.remaining() < key_len + value_len {
+                bail!(ErrorKind::InvalidObsMarker("truncated metadata".into()));
+            }
+            let mut key = vec![0; key_len];
+            buf.copy_to_slice(&mut key);
+            let mut value = vec![0; value_len];
+            buf.copy_to_slice(&mut value);
+            metadata.push((key, value));
+        }
+        if buf.position() - start != size as u64 {
+            bail!(ErrorKind::InvalidObsMarker("marker size doesn't match its content".into()));
+        }
+
+        Ok(ObsMarker {
+            precursor,
+            successors,
+            flags,
+            parents,
+            date: (time, tz),
+            metadata,
+        })
+    }
+
+    /// Version 0 keeps the date and the parents in the metadata.
+    fn encode_v0(&self, buf: &mut BytesMut) -> Result<()> {
+        self.check_encodable()?;
+        if self.flags > 255 {
+            bail!(ErrorKind::InvalidObsMarker("flags don't fit in version 0".into()));
+        }
+
+        let mut metadata: BTreeMap<Vec<u8>, Vec<u8>> = self.metadata.iter().cloned().collect();
+        metadata.insert(
+            b"date".to_vec(),
+            format!("{:?} {}", self.date.0, self.date.1).into_bytes(),
+        );
+        if let Some(ref parents) = self.parents {
+            if parents.is_empty() {
+                // Record that there are no parents, rather than that they're unknown
+                metadata.insert(b"p0".to_vec(), vec![]);
+            }
+            for (idx, parent) in parents.iter().enumerate() {
+                metadata.insert(
+                    format!("p{}", idx + 1).into_bytes(),
+                    parent.to_hex().as_bytes().to_vec(),
+                );
+            }
+        }
+
+        let mut encoded_metadata = Vec::new();
+        for (key, value) in metadata {
+            if key.contains(&b':') || key.contains(&0) || value.contains(&0) {
+                bail!(ErrorKind::InvalidObsMarker("bad metadata entry".into()));
+            }
+            if !encoded_metadata.is_empty() {
+                encoded_metadata.push(0);
+            }
+            encoded_metadata.extend_from_slice(&key);
+            encoded_metadata.push(b':');
+            encoded_metadata.extend_from_slice(&value);
+        }
+
+        buf.reserve(26 + 20 * self.successors.len() + encoded_metadata.len());
+        buf.put_u8(self.successors.len() as u8);
+        buf.put_u32::<BigEndian>(encoded_metadata.len() as u32);
+        buf.put_u8(self.flags as u8);
+        buf.put_slice(self.precursor.as_ref());
+        for node in &self.successors {
+            buf.put_slice(node.as_ref());
+        }
+        buf.put_slice(&encoded_metadata);
+        Ok(())
+    }
+}
+
+/// Encode markers in version 1 of the obsstore format, used by obsmarkers bundle2 parts.
+pub fn encode_markers<'a, I>(markers: I) -> Result<Bytes>
+where
+    I: IntoIterator<Item = &'a ObsMarker>,
+{
+    let mut buf = BytesMut::with_capacity(1);
+    buf.put_u8(FM1_VERSION);
+    for marker in markers {
+        marker.encode_v1(&mut buf)?;
+    }
+    Ok(buf.freeze())
+}
+
+/// Decode markers in version 1 of the obsstore format.
+pub fn decode_markers(data: &[u8]) -> Result<Vec<ObsMarker>> {
+    let mut buf = Cursor::new(data);
+    if !buf.has_remaining() {
+        bail!(ErrorKind::InvalidObsMarker("missing version".into()));
+    }
+    let version = buf.get_u8();
+    if version != FM1_VERSION {
+        bail!(ErrorKind::InvalidObsMarker(format!("unsupported version {}", version)));
+    }
+
+    let mut markers = Vec::new();
+    while buf.has_remaining() {
+        markers.push(ObsMarker::decode_v1(&mut buf)?);
+    }
+    Ok(markers)
+}
+
+/// Encode markers as the keys of the `obsolete` pushkey namespace. Markers are sent in version 0
+/// of the obsstore format, base85 encoded, and split between as many `dump<n>` keys as needed to
+/// keep each of them small.
+pub fn encode_pushkey_markers<'a, I>(markers: I) -> Result<Vec<(String, String)>>
+where
+    I: IntoIterator<Item = &'a ObsMarker>,
+{
+    let mut parts: Vec<BytesMut> = Vec::new();
+    for marker in markers {
+        let mut data = BytesMut::new();
+        marker.encode_v0(&mut data)?;
+
+        let new_part = match parts.last() {
+            Some(part) => part.len() - 1 + data.len() > PUSHKEY_MAX_PAYLOAD,
+            None => true,
+        };
+        if new_part {
+            let mut part = BytesMut::with_capacity(1 + data.len());
+            part.put_u8(FM0_VERSION);
+            parts.push(part);
+        }
+        parts.last_mut().expect("just added").extend_from_slice(&data);
+    }
+
+    // Like Mercurial, number the keys from the last part
+    Ok(parts
+        .into_iter()
+        .rev()
+        .enumerate()
+        .map(|(idx, part)| (format!("dump{}", idx), b85encode(&part)))
+        .collect())
+}
+
+/// Base85 encoding as done by Mercurial, without padding.
+fn b85encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() + 3) / 4 * 5);
+    for chunk in data.chunks(4) {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let mut word = Cursor::new(&word[..]).get_u32::<BigEndian>();
+
+        let mut encoded = [0; 5];
+        for c in encoded.iter_mut().rev() {
+            *c = B85_CHARS[(word % 85) as usize];
+            word /= 85;
+        }
+        // A partial chunk of n bytes only needs n + 1 characters
+        out.extend(encoded[..chunk.len() + 1].iter().map(|&c| c as char));
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn node(byte: u8) -> NodeHash {
+        NodeHash::from_bytes(&[byte; 20]).unwrap()
+    }
+
+    fn marker() -> ObsMarker {
+        ObsMarker {
+            precursor: node(0x11),
+            successors: vec![node(0x22)],
+            flags: 0,
+            parents: Some(vec![node(0x33)]),
+            date: (1500000000.0, -3600),
+            metadata: vec![(b"user".to_vec(), b"test".to_vec())],
+        }
+    }
+
+    #[test]
+    fn encode_v1() {
+        let mut expected = vec![1, 0, 0, 0, 0x59];
+        expected.extend_from_slice(&[0x41, 0xd6, 0x5a, 0x0b, 0xc0, 0, 0, 0]);
+        expected.extend_from_slice(&[0xff, 0xc4, 0, 0, 1, 1, 1]);
+        expected.extend_from_slice(&[0x11; 20]);
+        expected.extend_from_slice(&[0x22; 20]);
+        expected.extend_from_slice(&[0x33; 20]);
+        expected.extend_from_slice(&[4, 4]);
+        expected.extend_from_slice(b"usertest");
+
+        assert_eq!(encode_markers(&[marker()]).unwrap().as_ref(), &expected[..]);
+    }
+
+    #[test]
+    fn roundtrip_v1() {
+        let prune = ObsMarker {
+            successors: vec![],
+            parents: None,
+            metadata: vec![],
+            ..marker()
+        };
+        let markers = vec![marker(), prune];
+
+        let encoded = encode_markers(&markers).unwrap();
+        assert_eq!(decode_markers(&encoded).unwrap(), markers);
+    }
+
+    #[test]
+    fn decode_truncated() {
+        let encoded = encode_markers(&[marker()]).unwrap();
+        assert!(decode_markers(&encoded[..encoded.len() - 1]).is_err());
+        assert!(decode_markers(&[]).is_err());
+        assert!(decode_markers(&[0]).is_err());
+    }
+
+    #[test]
+    fn pushkey() {
+        let keys = encode_pushkey_markers(&[marker()]).unwrap();
+        let expected = "0096108Ic95fKp)5fKp)5fKp)5fKp)5fKq0A|fIpA|fIpA|fIpA|fIpA|fJWVRU6WF*PtSFfc\
+                        GMFfcAKAT2XCFfagcF*-9dGcz+YGcz+YGcz+YGcz+YGcz+YGcz+YGcz+YGcz+YGcz+YGcy2n\
+                        b7gWmbY*jN";
+        assert_eq!(keys, vec![("dump0".to_string(), expected.to_string())]);
+
+        let no_markers: Vec<ObsMarker> = vec![];
+        assert_eq!(encode_pushkey_markers(&no_markers).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn base85() {
+        assert_eq!(b85encode(b"hello"), "Xk~0{Zv");
+        assert_eq!(b85encode(b"hell"), "Xk~0{");
+        assert_eq!(b85encode(b"\x00\x01"), "009");
+    }
+}
@@ -8,10 +8,11 @@ use std::collections::BTreeMap;
 
 use quickcheck::{QuickCheck, TestResult};
 
-use mercurial_types::{Blob, BlobNode, MPath, NodeHash};
+use mercurial_types::{Blob, BlobNode, Changeset, MPath, NodeHash, Parents};
+use mercurial_types::changeset::extra;
 use mercurial_types::nodehash::ManifestId;
 
-use changeset::{escape, serialize_extras, unescape, Extra, RevlogChangeset, Time};
+use changeset::{escape, serialize_cs, serialize_extras, unescape, Extra, RevlogChangeset, Time};
 
 use bytes::Bytes;
 
@@ -153,3 +154,90 @@ fn extras_roundtrip_long() {
         .tests(1000)
         .quickcheck(extras_roundtrip_prop as fn(BTreeMap<Vec<u8>, Vec<u8>>) -> TestResult);
 }
+
+#[test]
+fn typed_extras_roundtrip() {
+    let pred1: NodeHash = "0849d280663e46b3e247857f4a68fabd2ba503c3".parse().unwrap();
+    let pred2: NodeHash = "169cb9e47f8e86079ee9fd79972092f78fbf68b1".parse().unwrap();
+    let mut mutpred = pred1.to_hex().as_str().as_bytes().to_vec();
+    mutpred.push(b',');
+    mutpred.extend_from_slice(pred2.to_hex().as_str().as_bytes());
+
+    let extras: BTreeMap<Vec<u8>, Vec<u8>> = vec![
+        (extra::BRANCH, b"stable".to_vec()),
+        (extra::SOURCE, pred1.to_hex().as_str().as_bytes().to_vec()),
+        (extra::COMMITTER, b"Committer <c@example.com> 1000 0".to_vec()),
+        (extra::MUTATION_PREDECESSORS, mutpred),
+        (extra::MUTATION_OPERATION, b"amend".to_vec()),
+        (&b"custom\0key"[..], b"value\nwith\0escapes".to_vec()),
+    ].into_iter()
+        .map(|(k, v)| (k.to_vec(), v))
+        .collect();
+    let cs = RevlogChangeset::new_from_parts(
+        Parents::None,
+        ManifestId::new(pred2),
+        b"Author <a@example.com>".to_vec(),
+        Time { time: 1000, tz: 0 },
+        extras.clone(),
+        vec![MPath::new(b"file").unwrap()],
+        b"message".to_vec(),
+    );
+
+    let mut data = Vec::new();
+    serialize_cs(&cs, &mut data).expect("serialize failed");
+    let node = BlobNode::new(Blob::from(Bytes::from(data)), None, None);
+    let parsed = RevlogChangeset::new(node).expect("parse failed");
+
+    assert_eq!(parsed.extra(), &extras);
+    assert_eq!(parsed.branch(), b"stable");
+    assert!(!parsed.closes_branch());
+    assert_eq!(parsed.source().unwrap(), Some(pred1));
+    assert_eq!(parsed.rebase_source().unwrap(), None);
+    assert_eq!(
+        parsed.committer(),
+        Some(&b"Committer <c@example.com> 1000 0"[..])
+    );
+    assert_eq!(parsed.predecessors().unwrap(), vec![pred1, pred2]);
+    assert_eq!(parsed.mutation_operation(), Some(&b"amend"[..]));
+}
+
+#[test]
+fn typed_extras_defaults() {
+    let cs = RevlogChangeset::new_null();
+    assert_eq!(cs.branch(), b"default");
+    assert_eq!(cs.source().unwrap(), None);
+    assert_eq!(cs.committer(), None);
+    assert_eq!(cs.predecessors().unwrap(), vec![]);
+
+    let pred: NodeHash = "0849d280663e46b3e247857f4a68fabd2ba503c3".parse().unwrap();
+    let mut extras = BTreeMap::new();
+    extras.insert(
+        extra::AMEND_SOURCE.to_vec(),
+        pred.to_hex().as_str().as_bytes().to_vec(),
+    );
+    extras.insert(extra::CLOSE.to_vec(), b"1".to_vec());
+    let cs = RevlogChangeset::new_from_parts(
+        Parents::None,
+        ManifestId::new(pred),
+        vec![],
+        Time { time: 0, tz: 0 },
+        extras,
+        vec![],
+        vec![],
+    );
+    assert!(cs.closes_branch());
+    assert_eq!(cs.predecessors().unwrap(), vec![pred]);
+
+    let mut extras = BTreeMap::new();
+    extras.insert(extra::REBASE_SOURCE.to_vec(), b"not a hash".to_vec());
+    let cs = RevlogChangeset::new_from_parts(
+        Parents::None,
+        ManifestId::new(pred),
+        vec![],
+        Time { time: 0, tz: 0 },
+        extras,
+        vec![],
+        vec![],
+    );
+    assert!(cs.rebase_source().is_err());
+}
@@ -101,6 +101,40 @@ impl File {
         }
     }
 
+    /// The content of the file and where it was copied from, if its metadata records nothing
+    /// else, so that `generate_copied_from` gives back its text.
+    pub fn split_copied_from(&self) -> Result<Option<((MPath, NodeHash), &[u8])>> {
+        let copied_from = match self.copied_from()? {
+            Some(copied_from) => copied_from,
+            None => return Ok(None),
+        };
+        let text = self.node.as_blob().as_slice();
+        let content = self.content();
+        match (text, content) {
+            (Some(text), Some(content))
+                if Self::generate_copied_from(&copied_from, content) == text =>
+            {
+                Ok(Some((copied_from, content)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// The text of a file with this content, copied from `copied_from`.
+    pub fn generate_copied_from(copied_from: &(MPath, NodeHash), content: &[u8]) -> Vec<u8> {
+        let (ref path, ref node) = *copied_from;
+        let mut text = Vec::new();
+        text.extend_from_slice(META_MARKER);
+        text.extend_from_slice(b"copy: ");
+        text.extend_from_slice(&path.to_vec());
+        text.extend_from_slice(b"\ncopyrev: ");
+        text.extend_from_slice(node.to_hex().as_bytes());
+        text.push(b'\n');
+        text.extend_from_slice(META_MARKER);
+        text.extend_from_slice(content);
+        text
+    }
+
     pub fn content(&self) -> Option<&[u8]> {
         self.node.as_blob().as_slice().map(|s| {
             let (_, off) = Self::extract_meta(s);
@@ -123,6 +157,10 @@ impl File {
 mod test {
     use super::{File, META_MARKER, META_SZ};
 
+    use bytes::Bytes;
+
+    use mercurial_types::{BlobNode, MPath, NodeHash};
+
     #[test]
     fn extract_meta_sz() {
         assert_eq!(META_SZ, META_MARKER.len())
@@ -208,4 +246,28 @@ mod test {
             ]
         )
     }
+
+    #[test]
+    fn copied_from_roundtrip() {
+        let node: NodeHash = "1111111111111111111111111111111111111111".parse().unwrap();
+        let copied_from = (MPath::new(b"dir/old").unwrap(), node);
+        let text = File::generate_copied_from(&copied_from, b"foo - copied");
+        let file = File::new(BlobNode::new(Bytes::from(text), None, None));
+
+        assert_eq!(file.copied_from().unwrap(), Some(copied_from.clone()));
+        assert_eq!(
+            file.split_copied_from().unwrap(),
+            Some((copied_from, &b"foo - copied"[..]))
+        );
+    }
+
+    #[test]
+    fn split_copied_from_other_meta() {
+        const DATA: &[u8] = b"\x01\ncensored: yes\ncopy: old\n\
+                              copyrev: 1111111111111111111111111111111111111111\n\x01\nfoo";
+        let file = File::new(BlobNode::new(Bytes::from(DATA), None, None));
+
+        assert!(file.copied_from().unwrap().is_some());
+        assert_eq!(file.split_copied_from().unwrap(), None);
+    }
 }
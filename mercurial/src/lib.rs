@@ -32,6 +32,8 @@ extern crate time;
 #[cfg(test)]
 #[macro_use]
 extern crate quickcheck;
+#[cfg(test)]
+extern crate tempdir;
 
 extern crate asyncmemo;
 extern crate bookmarks;
@@ -9,6 +9,7 @@ use std::io::{self, Write};
 use std::str;
 use std::vec;
 
+use bytes::Bytes;
 use futures::{Async, Poll};
 use futures::future::{Future, IntoFuture};
 use futures::stream::Stream;
@@ -102,6 +103,71 @@ impl ManifestContent {
         })
     }
 
+    /// Split a flat manifest, which lists every file by its full path, into the trees of its
+    /// directories, with their paths, nodes and texts. Subdirectories come before the directories
+    /// containing them, and the root comes last.
+    ///
+    /// The root tree keeps `root`, the node of the flat manifest, which the changeset refers to,
+    /// as in repos with both flat and tree manifests. The other trees are named by the hash of
+    /// their text alone, so that identical directories share a tree.
+    pub fn flat_to_trees(&self, root: NodeHash) -> Result<Vec<(MPath, NodeHash, Bytes)>> {
+        let mut dirs: BTreeMap<MPath, ManifestContent> = BTreeMap::new();
+        dirs.insert(MPath::empty(), ManifestContent::new_empty());
+        for (path, details) in &self.files {
+            let elements: Vec<&MPathElement> = path.into_iter().collect();
+            let (name, dir) = match elements.split_last() {
+                Some(split) => split,
+                None => bail_msg!("Malformed entry: empty path"),
+            };
+            for len in 1..dir.len() + 1 {
+                dirs.entry(MPath::empty().join(dir[..len].iter().cloned()))
+                    .or_insert_with(ManifestContent::new_empty);
+            }
+            if let Some(content) = dirs.get_mut(&MPath::empty().join(dir.iter().cloned())) {
+                content.files.insert(MPath::empty().join(Some(*name)), *details);
+            }
+        }
+
+        // The longest paths first, so that directories get the nodes of their subdirectories
+        let mut paths: Vec<MPath> = dirs.keys().cloned().collect();
+        paths.sort_by(|a, b| b.len().cmp(&a.len()));
+
+        let mut trees = Vec::with_capacity(paths.len());
+        for path in paths {
+            let content = match dirs.remove(&path) {
+                Some(content) => content,
+                None => continue,
+            };
+            let mut text = Vec::new();
+            content.generate(&mut text)?;
+            let text = Bytes::from(text);
+
+            if path.is_empty() {
+                trees.push((path, root, text));
+                break;
+            }
+
+            let node = match BlobNode::new(text.clone(), None, None).nodeid() {
+                Some(node) => node,
+                None => bail_msg!("can't hash the tree of {}", path),
+            };
+            {
+                let elements: Vec<&MPathElement> = (&path).into_iter().collect();
+                if let Some((name, parent)) = elements.split_last() {
+                    let parent = MPath::empty().join(parent.iter().cloned());
+                    if let Some(content) = dirs.get_mut(&parent) {
+                        content.files.insert(
+                            MPath::empty().join(Some(*name)),
+                            Details::new(EntryId::new(node), Type::Tree),
+                        );
+                    }
+                }
+            }
+            trees.push((path, node, text));
+        }
+        Ok(trees)
+    }
+
     pub fn generate<W: Write>(&self, out: &mut W) -> io::Result<()> {
         for (ref k, ref v) in &self.files {
             k.generate(out)?;
@@ -475,6 +541,47 @@ mod test {
         }
     }
 
+    #[test]
+    fn flat_to_trees() {
+        const FLAT: &[u8] = b"a\0da39a3ee5e6b4b0d3255bfef95601890afd80709\n\
+            dir/b\0da39a3ee5e6b4b0d3255bfef95601890afd80709x\n\
+            dir/sub/c\0da39a3ee5e6b4b0d3255bfef95601890afd80709\n";
+        let root: NodeHash = "1111111111111111111111111111111111111111".parse().unwrap();
+        let trees = ManifestContent::parse(FLAT)
+            .unwrap()
+            .flat_to_trees(root)
+            .unwrap();
+
+        let paths: Vec<_> = trees.iter().map(|&(ref path, ..)| path.clone()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                MPath::new(b"dir/sub").unwrap(),
+                MPath::new(b"dir").unwrap(),
+                MPath::empty(),
+            ]
+        );
+
+        let (_, sub, ref sub_text) = trees[0];
+        assert_eq!(
+            &sub_text[..],
+            &b"c\0da39a3ee5e6b4b0d3255bfef95601890afd80709\n"[..]
+        );
+        let (_, dir, ref dir_text) = trees[1];
+        let expected_dir = format!(
+            "b\0da39a3ee5e6b4b0d3255bfef95601890afd80709x\nsub\0{}t\n",
+            sub
+        );
+        assert_eq!(&dir_text[..], expected_dir.as_bytes());
+        let (_, root_node, ref root_text) = trees[2];
+        assert_eq!(root_node, root);
+        let expected_root = format!(
+            "a\0da39a3ee5e6b4b0d3255bfef95601890afd80709\ndir\0{}t\n",
+            dir
+        );
+        assert_eq!(&root_text[..], expected_root.as_bytes());
+    }
+
     #[test]
     fn one_roundtrip() {
         // Only one flag because its unclear how multiple flags should be ordered
@@ -20,7 +20,7 @@ use failure;
 use memmap::Mmap;
 use nom::IResult;
 
-use mercurial_types::{Blob, BlobNode, NodeHash};
+use mercurial_types::{Blob, BlobNode, NodeHash, Parents};
 pub use mercurial_types::bdiff::{self, Delta};
 pub use mercurial_types::delta;
 use mercurial_types::nodehash::EntryId;
@@ -29,13 +29,15 @@ use mercurial_types::nodehash::EntryId;
 mod parser;
 mod revidx;
 mod lz4;
+mod writer;
 
 #[cfg(test)]
 mod test;
 
-use self::parser::{Header, Version};
+use self::parser::{CompMode, Header, Version};
 pub use self::parser::Entry;
 pub use self::revidx::RevIdx;
+pub use self::writer::RevlogWriter;
 
 #[derive(Debug)]
 enum Datafile {
@@ -205,6 +207,15 @@ impl Revlog {
         self.inner.header
     }
 
+    /// Return the number of entries in the `Revlog`.
+    pub fn len(&self) -> usize {
+        self.inner.idxoff.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.inner.idxoff.is_empty()
+    }
+
     /// Return an `Entry` entry from the `RevIdx`.
     pub fn get_entry(&self, idx: RevIdx) -> Result<Entry> {
         self.inner.get_entry(idx)
@@ -247,6 +258,16 @@ impl Revlog {
     pub fn get_heads(&self) -> Result<HashSet<NodeHash>> {
         self.inner.get_heads()
     }
+
+    /// Return a `RevisionInfo` for a revision at `RevIdx`, which only reads the index.
+    pub fn get_revision_info(&self, idx: RevIdx) -> Result<RevisionInfo> {
+        self.inner.get_revision_info(idx)
+    }
+
+    /// Iterate over the `RevisionInfo` of every revision, in order, without reading their data.
+    pub fn revision_infos(&self) -> RevisionInfoIter {
+        RevisionInfoIter(self.inner.clone(), RevIdx::zero())
+    }
 }
 
 impl RevlogInner {
@@ -256,6 +277,7 @@ impl RevlogInner {
         let res = match self.header.version {
             Version::Revlog0 => parser::index0(&self.idx.as_slice()[off..]),
             Version::RevlogNG => parser::indexng(&self.idx.as_slice()[off..]),
+            Version::RevlogV2 => parser::indexv2(&self.idx.as_slice()[off..]),
         };
 
         match res {
@@ -278,13 +300,16 @@ impl RevlogInner {
         match self.header.version {
             Version::Revlog0 => parser::index0_size(),
             Version::RevlogNG => parser::indexng_size(),
+            Version::RevlogV2 => parser::indexv2_size(),
         }
     }
 
     fn entry_size(&self, ent: Option<&Entry>) -> usize {
         let mut sz = self.fixed_entry_size();
         if self.header.features.contains(parser::Features::INLINE) {
-            sz += ent.expect("inline needs ent").compressed_len as usize;
+            // Inline sidedata follows the content of the revision
+            let ent = ent.expect("inline needs ent");
+            sz += ent.compressed_len as usize + ent.sidedata_len as usize;
         }
         sz
     }
@@ -370,8 +395,18 @@ impl RevlogInner {
         // If the entry has no baserev then the chunk is literal data, Otherwise
         // its 0 or more deltas against the baserev. If its general delta, then the
         // baserev itself might also be delta, otherwise its all the deltas from baserev..idx.
+        // A plain chunk has no marker of its compression, as it has none.
         if let Some(baserev) = entry.baserev {
-            let delta = match parser::deltachunk(chunkdata) {
+            // Sparse revlogs store a revision with the same text as its base as an empty delta
+            if chunkdata.len() == 0 {
+                return Ok(Chunk::Deltas(baserev, vec![]));
+            }
+            let deltas = if entry.comp_mode == CompMode::Plain {
+                parser::deltas(chunkdata)
+            } else {
+                parser::deltachunk(chunkdata)
+            };
+            let delta = match deltas {
                 IResult::Done(rest, _) if rest.len() != 0 => {
                     return Err(ErrorKind::Revlog(format!(
                         "Failed to unpack details: {} remains, {:?}",
@@ -389,6 +424,8 @@ impl RevlogInner {
             Ok(delta)
         } else if chunkdata.len() == 0 {
             Ok(Chunk::Literal(vec![]))
+        } else if entry.comp_mode == CompMode::Plain {
+            Ok(Chunk::Literal(chunkdata.to_vec()))
         } else {
             let literal = match parser::literal(chunkdata) {
                 IResult::Done(rest, _) if rest.len() != 0 => {
@@ -410,9 +447,11 @@ impl RevlogInner {
     }
 
     fn is_general_delta(&self) -> bool {
-        self.header
-            .features
-            .contains(parser::Features::GENERAL_DELTA)
+        // v2 revlogs are always general delta, and don't have the flag
+        self.header.version == Version::RevlogV2
+            || self.header
+                .features
+                .contains(parser::Features::GENERAL_DELTA)
     }
 
     fn construct_simple(&self, tgtidx: RevIdx) -> Result<Vec<u8>> {
@@ -543,6 +582,21 @@ impl RevlogInner {
         }
     }
 
+    fn get_revision_info(&self, idx: RevIdx) -> Result<RevisionInfo> {
+        let entry = self.get_entry(idx)?;
+        let mut pnodeid = |p| self.get_entry(p).map(|n| n.nodeid);
+        let p1 = map_io(entry.p1, &mut pnodeid)?;
+        let p2 = map_io(entry.p2, &mut pnodeid)?;
+
+        Ok(RevisionInfo {
+            idx,
+            nodeid: entry.nodeid,
+            parents: Parents::new(p1.as_ref(), p2.as_ref()),
+            linkrev: entry.linkrev,
+            len: entry.len,
+        })
+    }
+
     /// Return the set of head revisions in a revlog
     fn get_heads(&self) -> Result<HashSet<NodeHash>> {
         // Current set of candidate heads
@@ -567,6 +621,18 @@ impl RevlogInner {
     }
 }
 
+/// What the index records of a revision, for users which don't need its text.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RevisionInfo {
+    pub idx: RevIdx,
+    pub nodeid: NodeHash,
+    pub parents: Parents,
+    /// The changeset which introduced the revision
+    pub linkrev: RevIdx,
+    /// Size of the full text, which revlogs before RevlogNG don't record
+    pub len: Option<u32>,
+}
+
 /// Data associated with a revision.
 ///
 /// XXX internal detail?
@@ -639,3 +705,19 @@ impl Iterator for RevlogIter {
         ret.map(|r| (idx, r))
     }
 }
+
+#[derive(Debug)]
+pub struct RevisionInfoIter(Arc<RevlogInner>, RevIdx);
+
+impl Iterator for RevisionInfoIter {
+    type Item = Result<RevisionInfo>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let idx = self.1;
+        if !self.0.idxoff.contains_key(&idx) {
+            return None;
+        }
+        self.1 = idx.succ();
+        Some(self.0.get_revision_info(idx))
+    }
+}
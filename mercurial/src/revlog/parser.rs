@@ -10,7 +10,7 @@ use std::fmt::Debug;
 use std::io::Read;
 
 use flate2::read::ZlibDecoder;
-use nom::{ErrorKind, IResult, Needed, be_u16, be_u32};
+use nom::{ErrorKind, IResult, Needed, be_u16, be_u32, be_u64, be_u8};
 
 use mercurial_types::NodeHash;
 use mercurial_types::bdiff::Delta;
@@ -53,6 +53,9 @@ bitflags! {
 bitflags! {
     pub struct IdxFlags: u16 {
         const CENSORED      = 1 << 15;
+        const ELLIPSIS      = 1 << 14;
+        const EXTSTORED     = 1 << 13;
+        const HASCOPIESINFO = 1 << 12;
     }
 }
 
@@ -61,6 +64,19 @@ bitflags! {
 pub enum Version {
     Revlog0 = 0,
     RevlogNG = 1,
+    /// Always general delta, with sidedata stored apart from the revisions
+    RevlogV2 = 0xDEAD,
+}
+
+/// How the chunk of a revision is compressed, which revlogs before v2 always mark in the chunk
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CompMode {
+    /// Not compressed, and without any marker
+    Plain,
+    /// Compressed with the default engine of the revlog, zlib
+    Default,
+    /// Marked by the first byte of the chunk
+    Inline,
 }
 
 /// Revlog header
@@ -82,6 +98,9 @@ pub struct Entry {
     pub p1: Option<RevIdx>,  // parent p1
     pub p2: Option<RevIdx>,  // parent p2
     pub nodeid: NodeHash,    // nodeid
+    pub sidedata_offset: u64, // offset of sidedata in datafile (v2 only)
+    pub sidedata_len: u32,   // compressed sidedata size (v2 only)
+    pub comp_mode: CompMode, // compression of the content
 }
 
 impl Entry {
@@ -99,13 +118,12 @@ named!(pub header<Header>,
             let vers = match version {
                 0 => Version::Revlog0,
                 1 => Version::RevlogNG,
+                0xDEAD => Version::RevlogV2,
                 _ => panic!("bad version"),
             };
 
-            let features = match Features::from_bits(features) {
-                Some(f) => f,
-                None => panic!("bad features"),
-            };
+            // Features this parser doesn't know of don't change how revisions are read
+            let features = Features::from_bits_truncate(features);
 
             Header {
                 version: vers,
@@ -133,7 +151,7 @@ named!(pub indexng<Entry>,
         ({
             Entry {
                 offset: offset,
-                flags: IdxFlags::from_bits(flags).expect("bad rev idx flags"),
+                flags: IdxFlags::from_bits_truncate(flags),
                 compressed_len: compressed_length,
                 len: Some(uncompressed_length),
                 baserev: if baserev == !0 { None } else { Some(baserev.into()) },
@@ -141,6 +159,38 @@ named!(pub indexng<Entry>,
                 p1: if p1 == !0 { None } else { Some(p1.into()) },
                 p2: if p2 == !0 { None } else { Some(p2.into()) },
                 nodeid: NodeHash::from_bytes(&hash[..20]).expect("bad bytes for sha"),
+                sidedata_offset: 0,
+                sidedata_len: 0,
+                comp_mode: CompMode::Inline,
+            }
+        })
+    )
+);
+
+pub fn indexv2_size() -> usize {
+    indexng_size() + 8 + 4 + 1 + 19
+}
+
+/// Parse a v2 revlog entry, which is an "NG" entry followed by the location of the sidedata and
+/// the compression mode
+named!(pub indexv2<Entry>,
+    do_parse!(
+        entry: indexng >>
+        sidedata_offset: return_error!(ErrorKind::Custom(Badness::IO), be_u64) >>
+        sidedata_len: return_error!(ErrorKind::Custom(Badness::IO), be_u32) >>
+        comp_mode: return_error!(ErrorKind::Custom(Badness::IO), be_u8) >>
+        _padding: take!(19) >>
+        ({
+            let comp_mode = match comp_mode & 0x3 {
+                0 => CompMode::Plain,
+                1 => CompMode::Default,
+                _ => CompMode::Inline,
+            };
+            Entry {
+                sidedata_offset,
+                sidedata_len,
+                comp_mode,
+                ..entry
             }
         })
     )
@@ -172,6 +222,9 @@ named!(pub index0<Entry>,
                 p1: if p1 == !0 { None } else { Some(p1.into()) },
                 p2: if p2 == !0 { None } else { Some(p2.into()) },
                 nodeid: NodeHash::from_bytes(&hash[..20]).expect("bad bytes for sha"),
+                sidedata_offset: 0,
+                sidedata_len: 0,
+                comp_mode: CompMode::Inline,
             }
         })
     )
@@ -194,7 +247,7 @@ named!(pub delta<Delta>,
 );
 
 /// Parse 0 or more deltas
-named!(deltas<Vec<Delta>>, many0!(delta));
+named!(pub deltas<Vec<Delta>>, many0!(delta));
 
 // A chunk of data data that contains some Deltas; the caller defines the framing bytes
 // bounding the input.
@@ -337,6 +390,21 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_header_v2() {
+        let d = [0x00, 0x01, 0xde, 0xad];
+        assert_eq!(
+            header(&d[..]),
+            IResult::Done(
+                &b""[..],
+                Header {
+                    version: Version::RevlogV2,
+                    features: Features::INLINE,
+                }
+            )
+        )
+    }
+
     #[test]
     fn test_header_feat_3() {
         let d = [0x00, 0x03, 0x00, 0x01];
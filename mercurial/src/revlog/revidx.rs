@@ -59,6 +59,13 @@ impl From<usize> for RevIdx {
     }
 }
 
+// Convert a `RevIdx` back into its revision number
+impl From<RevIdx> for u64 {
+    fn from(v: RevIdx) -> Self {
+        v.0 as u64
+    }
+}
+
 // Construct a `RevIdx` from a string (which may fail)
 impl FromStr for RevIdx {
     type Err = <u32 as FromStr>::Err;
@@ -182,4 +189,9 @@ mod test {
             Err(err) => println!("ok {:?}", err),
         }
     }
+
+    #[test]
+    fn into_u64() {
+        assert_eq!(u64::from(RevIdx(555)), 555);
+    }
 }
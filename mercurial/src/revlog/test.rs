@@ -6,6 +6,8 @@
 
 use super::*;
 
+use tempdir::TempDir;
+
 static EMPTY: &[u8] = include_bytes!("empty.i.bin");
 
 #[test]
@@ -17,3 +19,164 @@ fn emptyrev() {
 
     assert_eq!(node.size(), Some(0));
 }
+
+fn write_revs(writer: &mut RevlogWriter, texts: &[&[u8]]) -> Vec<NodeHash> {
+    let mut nodes: Vec<NodeHash> = Vec::new();
+    for (linkrev, text) in texts.iter().enumerate() {
+        let p1 = nodes.last().cloned();
+        let node = BlobNode::new(Bytes::from(*text), p1.as_ref(), None)
+            .nodeid()
+            .unwrap();
+        writer
+            .add(node, text, linkrev, p1.as_ref(), None)
+            .expect("add failed");
+        nodes.push(node);
+    }
+    nodes
+}
+
+fn check_revs(revlog: &Revlog, texts: &[&[u8]], nodes: &[NodeHash]) {
+    assert_eq!(revlog.len(), texts.len());
+    for (idx, (text, node)) in texts.iter().zip(nodes).enumerate() {
+        let rev = revlog.get_rev(RevIdx::from(idx)).expect("failed to get rev");
+        assert_eq!(rev.as_blob().as_slice(), Some(*text));
+        assert_eq!(rev.nodeid(), Some(*node));
+    }
+}
+
+fn write_and_read(general_delta: bool) {
+    let dir = TempDir::new("revlog_writer").unwrap();
+    let (idx, data) = (dir.path().join("test.i"), dir.path().join("test.d"));
+    let texts: &[&[u8]] = &[
+        b"",
+        b"foo foo foo\nbar bar bar\nbaz baz baz\n",
+        b"foo foo foo\nbar bar bar\nqux\nbaz baz baz\n",
+        b"\0binary",
+        b"foo\n",
+    ];
+
+    let mut writer = RevlogWriter::create(&idx, &data, general_delta).unwrap();
+    let nodes = write_revs(&mut writer, texts);
+    let revlog = Revlog::from_idx_data(&idx, Some(&data)).unwrap();
+    assert_eq!(
+        revlog
+            .get_header()
+            .features
+            .contains(parser::Features::GENERAL_DELTA),
+        general_delta
+    );
+    check_revs(&revlog, texts, &nodes);
+    // The change adding a line is small enough to be stored as a delta
+    assert!(revlog.get_entry(RevIdx::from(2u32)).unwrap().baserev.is_some());
+}
+
+#[test]
+fn write_and_read_revlog() {
+    write_and_read(false);
+}
+
+#[test]
+fn write_and_read_general_delta_revlog() {
+    write_and_read(true);
+}
+
+#[test]
+fn append_to_revlog() {
+    let dir = TempDir::new("revlog_writer").unwrap();
+    let (idx, data) = (dir.path().join("test.i"), dir.path().join("test.d"));
+    let texts: &[&[u8]] = &[b"foo\n", b"foo\nbar\n", b"foo\nbar\nbaz\n"];
+
+    let mut writer = RevlogWriter::create(&idx, &data, true).unwrap();
+    let mut nodes = write_revs(&mut writer, &texts[..2]);
+
+    let mut writer = RevlogWriter::open(&idx, &data).unwrap();
+    assert_eq!(writer.len(), 2);
+    let node = BlobNode::new(Bytes::from(texts[2]), nodes.last(), None)
+        .nodeid()
+        .unwrap();
+    writer.add(node, texts[2], 2, nodes.last(), None).unwrap();
+    nodes.push(node);
+
+    let revlog = Revlog::from_idx_data(&idx, Some(&data)).unwrap();
+    check_revs(&revlog, texts, &nodes);
+}
+
+/// An inline v2 revlog entry, without any sidedata
+fn v2_entry(compressed_len: u32, len: u32, baserev: u32, p1: u32, node: &NodeHash) -> Vec<u8> {
+    let mut entry = vec![0; 8];
+    for field in &[compressed_len, len, baserev, 0, p1, !0] {
+        entry.extend_from_slice(&[
+            (field >> 24) as u8,
+            (field >> 16) as u8,
+            (field >> 8) as u8,
+            *field as u8,
+        ]);
+    }
+    entry.extend_from_slice(node.as_ref());
+    entry.extend_from_slice(&[0; 12]);
+    entry.extend_from_slice(&[0; 8 + 4]);
+    // Plain compression mode, then padding
+    entry.extend_from_slice(&[0; 1 + 19]);
+    entry
+}
+
+#[test]
+fn v2_plain_and_empty_delta() {
+    let text = b"\0plain\n";
+    let node0 = BlobNode::new(Bytes::from(&text[..]), None, None)
+        .nodeid()
+        .unwrap();
+    let node1 = BlobNode::new(Bytes::from(&text[..]), Some(&node0), None)
+        .nodeid()
+        .unwrap();
+
+    let mut idx = v2_entry(text.len() as u32, text.len() as u32, 0, !0, &node0);
+    // The header takes the place of the first revision's offset: inline, v2
+    idx[..4].copy_from_slice(&[0x00, 0x01, 0xde, 0xad]);
+    idx.extend_from_slice(text);
+    idx.extend(v2_entry(0, text.len() as u32, 0, 0, &node1));
+
+    let revlog = Revlog::new(idx, None).expect("construction failed");
+    assert_eq!(revlog.len(), 2);
+    for (idx, node) in [node0, node1].iter().enumerate() {
+        let rev = revlog.get_rev(RevIdx::from(idx)).expect("failed to get rev");
+        assert_eq!(rev.as_blob().as_slice(), Some(&text[..]));
+        assert_eq!(rev.nodeid(), Some(*node));
+    }
+}
+
+#[test]
+fn revision_infos() {
+    let dir = TempDir::new("revlog_writer").unwrap();
+    let (idx, data) = (dir.path().join("test.i"), dir.path().join("test.d"));
+    let texts: &[&[u8]] = &[b"foo\n", b"foo\nbar\n"];
+
+    let mut writer = RevlogWriter::create(&idx, &data, false).unwrap();
+    let nodes = write_revs(&mut writer, texts);
+    // Only the index is needed
+    let revlog = Revlog::from_idx(&idx).unwrap();
+
+    let infos: Vec<_> = revlog
+        .revision_infos()
+        .collect::<Result<_>>()
+        .expect("failed to get infos");
+    assert_eq!(
+        infos,
+        vec![
+            RevisionInfo {
+                idx: RevIdx::from(0u32),
+                nodeid: nodes[0],
+                parents: Parents::None,
+                linkrev: RevIdx::from(0u32),
+                len: Some(4),
+            },
+            RevisionInfo {
+                idx: RevIdx::from(1u32),
+                nodeid: nodes[1],
+                parents: Parents::new(Some(&nodes[0]), None),
+                linkrev: RevIdx::from(1u32),
+                len: Some(8),
+            },
+        ]
+    );
+}
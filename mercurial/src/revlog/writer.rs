@@ -0,0 +1,332 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Writer of RevlogNG revlogs, appending revisions to a new or existing revlog.
+//!
+//! Data is always kept in a separate data file. A revision is stored as a delta against the
+//! previous revision, or against its first parent in a general delta revlog, unless reading the
+//! chain of deltas would cost more than twice the text, in which case it's stored in full. Only
+//! the texts of the heads are kept to compute deltas, so that a revision whose first parent
+//! already has a child is stored in full in a general delta revlog.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use mercurial_types::NodeHash;
+
+use errors::*;
+use revlog::Revlog;
+use revlog::parser::{Features, Version};
+
+const NULL_REV: u32 = !0;
+
+/// The chain of deltas needed to rebuild a revision
+#[derive(Clone, Copy, Debug)]
+struct Chain {
+    /// The revision stored in full the chain starts from
+    base: u32,
+    /// Total size of the stored chunks of the chain
+    size: u64,
+}
+
+pub struct RevlogWriter {
+    index_path: PathBuf,
+    data_path: PathBuf,
+    general_delta: bool,
+    data_len: u64,
+    revs: HashMap<NodeHash, u32>,
+    chains: Vec<Chain>,
+    /// The texts of the heads, which the next revisions are likely to be deltas against
+    texts: HashMap<u32, Vec<u8>>,
+}
+
+impl RevlogWriter {
+    /// Create an empty revlog, replacing any existing one.
+    pub fn create<IP, DP>(index_path: IP, data_path: DP, general_delta: bool) -> Result<Self>
+    where
+        IP: Into<PathBuf>,
+        DP: Into<PathBuf>,
+    {
+        let index_path = index_path.into();
+        let data_path = data_path.into();
+        if let Some(dir) = index_path.parent() {
+            fs::create_dir_all(dir)
+                .with_context(|_| format!("cannot create directory {}", dir.display()))?;
+        }
+        for path in &[&index_path, &data_path] {
+            fs::File::create(path)
+                .with_context(|_| format!("cannot create revlog {}", path.display()))?;
+        }
+
+        Ok(Self {
+            index_path,
+            data_path,
+            general_delta,
+            data_len: 0,
+            revs: HashMap::new(),
+            chains: Vec::new(),
+            texts: HashMap::new(),
+        })
+    }
+
+    /// Open an existing revlog to append to it. Revlogs with inline data aren't supported.
+    pub fn open<IP, DP>(index_path: IP, data_path: DP) -> Result<Self>
+    where
+        IP: Into<PathBuf>,
+        DP: Into<PathBuf>,
+    {
+        let index_path = index_path.into();
+        let data_path = data_path.into();
+        let revlog = Revlog::from_idx_data(&index_path, Some(&data_path))?;
+        let header = revlog.get_header();
+        if header.version != Version::RevlogNG
+            || header.features.contains(Features::INLINE)
+        {
+            let msg = format!("cannot append to {}: {:?}", index_path.display(), header);
+            return Err(ErrorKind::Revlog(msg).into());
+        }
+        let general_delta = header.features.contains(Features::GENERAL_DELTA);
+
+        let mut revs = HashMap::new();
+        let mut chains: Vec<Chain> = Vec::new();
+        let mut data_len = 0;
+        for (_, entry) in &revlog {
+            let rev = revs.len() as u32;
+            let size = entry.compressed_len as u64;
+            let chain = match entry.baserev {
+                None => Chain { base: rev, size },
+                Some(baserev) => {
+                    // Without general delta, the delta is against the previous revision
+                    let parent = if general_delta {
+                        chains[u64::from(baserev) as usize]
+                    } else {
+                        chains[rev as usize - 1]
+                    };
+                    Chain {
+                        base: parent.base,
+                        size: parent.size + size,
+                    }
+                }
+            };
+            chains.push(chain);
+            revs.insert(entry.nodeid, rev);
+            data_len = entry.offset + size;
+        }
+
+        let mut texts = HashMap::new();
+        for head in revlog.get_heads()? {
+            let node = revlog.get_rev_by_nodeid(&head)?;
+            let text = node.as_blob()
+                .as_slice()
+                .map(<[u8]>::to_vec)
+                .unwrap_or_else(Vec::new);
+            texts.insert(revs[&head], text);
+        }
+
+        Ok(Self {
+            index_path,
+            data_path,
+            general_delta,
+            data_len,
+            revs,
+            chains,
+            texts,
+        })
+    }
+
+    pub fn contains(&self, node: &NodeHash) -> bool {
+        self.revs.contains_key(node)
+    }
+
+    /// Return the number of revisions in the revlog.
+    pub fn len(&self) -> usize {
+        self.revs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.revs.is_empty()
+    }
+
+    /// Append a revision, unless it's already there. Parents must already be in the revlog.
+    pub fn add(
+        &mut self,
+        node: NodeHash,
+        content: &[u8],
+        linkrev: usize,
+        p1: Option<&NodeHash>,
+        p2: Option<&NodeHash>,
+    ) -> Result<()> {
+        if self.contains(&node) {
+            return Ok(());
+        }
+        let p1 = self.parent_rev(&node, p1)?;
+        let p2 = self.parent_rev(&node, p2)?;
+        let rev = self.revs.len() as u32;
+
+        let deltaparent = if self.general_delta {
+            p1
+        } else if rev > 0 {
+            rev - 1
+        } else {
+            NULL_REV
+        };
+        let (chunk, chain, baserev) = match self.delta_against(deltaparent, content)? {
+            Some((chunk, chain)) => {
+                // Without general delta, the base is where the chain starts
+                let baserev = if self.general_delta {
+                    deltaparent
+                } else {
+                    chain.base
+                };
+                (chunk, chain, baserev)
+            }
+            None => {
+                let chunk = compress(content)?;
+                let size = chunk.len() as u64;
+                (chunk, Chain { base: rev, size }, rev)
+            }
+        };
+
+        let mut entry = Vec::with_capacity(64);
+        if rev == 0 {
+            // The header takes the place of the first revision's offset, which is always 0
+            let mut features = Features::empty();
+            if self.general_delta {
+                features |= Features::GENERAL_DELTA;
+            }
+            put_u16(&mut entry, features.bits());
+            put_u16(&mut entry, Version::RevlogNG as u16);
+            put_u16(&mut entry, 0);
+        } else {
+            put_u16(&mut entry, (self.data_len >> 32) as u16);
+            put_u32(&mut entry, self.data_len as u32);
+        }
+        put_u16(&mut entry, 0); // flags
+        put_u32(&mut entry, chunk.len() as u32);
+        put_u32(&mut entry, content.len() as u32);
+        put_u32(&mut entry, baserev);
+        put_u32(&mut entry, linkrev as u32);
+        put_u32(&mut entry, p1);
+        put_u32(&mut entry, p2);
+        entry.extend_from_slice(node.as_ref());
+        entry.extend_from_slice(&[0; 12]);
+
+        append(&self.data_path, &chunk)?;
+        append(&self.index_path, &entry)?;
+        self.data_len += chunk.len() as u64;
+        self.revs.insert(node, rev);
+        self.chains.push(chain);
+        self.texts.remove(&p1);
+        self.texts.remove(&p2);
+        self.texts.insert(rev, content.to_vec());
+        Ok(())
+    }
+
+    /// The chunk of a delta against `deltaparent` and the chain it ends, unless the text of
+    /// `deltaparent` isn't known or the chain would be too costly to read.
+    fn delta_against(&self, deltaparent: u32, content: &[u8]) -> Result<Option<(Vec<u8>, Chain)>> {
+        let base = match self.texts.get(&deltaparent) {
+            Some(base) => base,
+            None => return Ok(None),
+        };
+        let chunk = compress(&delta(base, content))?;
+        let parent = self.chains[deltaparent as usize];
+        let size = parent.size + chunk.len() as u64;
+        if size > 2 * content.len() as u64 {
+            Ok(None)
+        } else {
+            let chain = Chain {
+                base: parent.base,
+                size,
+            };
+            Ok(Some((chunk, chain)))
+        }
+    }
+
+    fn parent_rev(&self, node: &NodeHash, parent: Option<&NodeHash>) -> Result<u32> {
+        match parent {
+            None => Ok(NULL_REV),
+            Some(parent) => match self.revs.get(parent) {
+                Some(rev) => Ok(*rev),
+                None => {
+                    let msg = format!(
+                        "parent {} of {} isn't in {}",
+                        parent,
+                        node,
+                        self.index_path.display()
+                    );
+                    Err(ErrorKind::Revlog(msg).into())
+                }
+            },
+        }
+    }
+}
+
+/// A delta turning `base` into `text`, as a single fragment replacing what lies between their
+/// common prefix and suffix.
+fn delta(base: &[u8], text: &[u8]) -> Vec<u8> {
+    let prefix = base.iter()
+        .zip(text.iter())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let suffix = base[prefix..]
+        .iter()
+        .rev()
+        .zip(text[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let content = &text[prefix..text.len() - suffix];
+
+    let mut delta = Vec::with_capacity(12 + content.len());
+    put_u32(&mut delta, prefix as u32);
+    put_u32(&mut delta, (base.len() - suffix) as u32);
+    put_u32(&mut delta, content.len() as u32);
+    delta.extend_from_slice(content);
+    delta
+}
+
+/// Encode a chunk as Mercurial does: zlib compressed if that's smaller, otherwise stored with
+/// a 'u' marker, unless it's empty or starts with a NUL byte.
+fn compress(content: &[u8]) -> Result<Vec<u8>> {
+    if content.is_empty() {
+        return Ok(Vec::new());
+    }
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(content)?;
+    let compressed = encoder.finish()?;
+    if compressed.len() < content.len() {
+        Ok(compressed)
+    } else if content[0] == b'\0' {
+        Ok(content.to_vec())
+    } else {
+        let mut stored = Vec::with_capacity(content.len() + 1);
+        stored.push(b'u');
+        stored.extend_from_slice(content);
+        Ok(stored)
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, v: u16) {
+    buf.extend_from_slice(&[(v >> 8) as u8, v as u8]);
+}
+
+fn put_u32(buf: &mut Vec<u8>, v: u32) {
+    buf.extend_from_slice(&[(v >> 24) as u8, (v >> 16) as u8, (v >> 8) as u8, v as u8]);
+}
+
+fn append(path: &Path, data: &[u8]) -> Result<()> {
+    let mut file = OpenOptions::new()
+        .append(true)
+        .open(path)
+        .with_context(|_| format!("cannot open {}", path.display()))?;
+    file.write_all(data)?;
+    Ok(())
+}
@@ -10,7 +10,7 @@ use std::collections::HashSet;
 use std::collections::hash_map::{Entry, HashMap};
 use std::fmt::{self, Display};
 use std::fs;
-use std::io::{BufRead, BufReader};
+use std::io::{self, BufRead, BufReader};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::{Arc, RwLock};
@@ -32,7 +32,7 @@ use bytes::Bytes;
 pub use changeset::RevlogChangeset;
 use errors::*;
 pub use manifest::RevlogManifest;
-use revlog::{self, Revlog, RevlogIter};
+use revlog::{self, RevisionInfo, Revlog, RevlogIter};
 
 type FutureResult<T> = future::FutureResult<T, Error>;
 
@@ -53,6 +53,7 @@ pub enum Required {
     SqlDirstate,
     HgSql,
     TreeDirstate,
+    SparseRevlog,
 }
 
 impl Display for Required {
@@ -73,6 +74,7 @@ impl Display for Required {
             &SqlDirstate => "sqldirstate",
             &HgSql => "hgsql",
             &TreeDirstate => "treedirstate",
+            &SparseRevlog => "sparserevlog",
         };
         write!(fmt, "{}", s)
     }
@@ -98,6 +100,7 @@ impl FromStr for Required {
             "sqldirstate" => Ok(SqlDirstate),
             "hgsql" => Ok(HgSql),
             "treedirstate" => Ok(TreeDirstate),
+            "sparserevlog" => Ok(SparseRevlog),
             unk => Err(ErrorKind::UnknownReq(unk.into()).into()),
         }
     }
@@ -207,6 +210,14 @@ impl RevlogRepo {
         Ok(self.changelog.get_idx_by_nodeid(&nodeid).is_ok()).into_future()
     }
 
+    pub fn changesets_exist(&self, changesetids: &[ChangesetId]) -> FutureResult<Vec<bool>> {
+        let exist = changesetids
+            .iter()
+            .map(|csid| self.changelog.get_idx_by_nodeid(csid.as_nodehash()).is_ok())
+            .collect();
+        Ok(exist).into_future()
+    }
+
     pub fn get_changeset_blob_by_nodeid(&self, nodeid: &NodeHash) -> FutureResult<BlobNode> {
         self.changelog
             .get_idx_by_nodeid(nodeid)
@@ -396,6 +407,56 @@ impl RevlogRepo {
         }
     }
 
+    /// List the revlogs of the store in the order a stream clone sends them: filelogs and tree
+    /// manifests, then the manifest, then the changelog last so that a reader never sees a
+    /// changeset whose data is missing. Each entry is the unencoded name relative to the store,
+    /// as a client expects it, and the path of the file on disk.
+    pub fn get_store_files(&self) -> Result<Vec<(Vec<u8>, PathBuf)>> {
+        if !self.requirements.contains(&Required::Fncache) {
+            let msg = "listing the store needs the fncache requirement".into();
+            return Err(ErrorKind::Repo(msg).into());
+        }
+        let store = self.basepath.join("store");
+
+        let mut files = Vec::new();
+        let fncache = match fs::File::open(store.join("fncache")) {
+            // An empty repo has no fncache
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => None,
+            file => Some(file.context("Can't open `fncache`")?),
+        };
+        for line in fncache.into_iter().flat_map(|file| BufReader::new(file).split(b'\n')) {
+            let name = line.context("Can't read `fncache`")?;
+            if name.is_empty() {
+                continue;
+            }
+            let elements: Vec<_> = name.split(|c| *c == b'/')
+                .map(|element| MPathElement::new(element.to_vec()))
+                .collect();
+            let path = store.join(self.fsencode_path(&elements));
+            // fncache can list revlogs which were stripped since
+            if path.exists() {
+                files.push((name, path));
+            }
+        }
+        files.sort();
+
+        let toplevel = [
+            "00manifesttree.i",
+            "00manifesttree.d",
+            "00manifest.i",
+            "00manifest.d",
+            "00changelog.i",
+            "00changelog.d",
+        ];
+        for name in toplevel.iter() {
+            let path = store.join(name);
+            if path.exists() {
+                files.push((name.as_bytes().to_vec(), path));
+            }
+        }
+        Ok(files)
+    }
+
     pub fn bookmarks(&self) -> Result<StockBookmarks> {
         Ok(StockBookmarks::read(self.basepath.clone())?)
     }
@@ -413,6 +474,25 @@ impl RevlogRepo {
     pub fn changesets(&self) -> ChangesetStream {
         ChangesetStream::new(&self.changelog)
     }
+
+    /// Stream the parents, linkrev and length of every changeset, without reading them.
+    pub fn changelog_revisions(&self) -> BoxStream<RevisionInfo, Error> {
+        stream::iter_result(self.changelog.revision_infos()).boxify()
+    }
+
+    /// Stream the parents, linkrev and length of every manifest, without reading them.
+    pub fn manifest_revisions(&self) -> BoxStream<RevisionInfo, Error> {
+        stream::iter_result(self.manifest.revision_infos()).boxify()
+    }
+
+    /// Stream the parents, linkrev and length of every revision of the file or tree manifest at
+    /// `path`, without reading them.
+    pub fn path_revisions(&self, path: &RepoPath) -> BoxStream<RevisionInfo, Error> {
+        match self.get_path_revlog(path) {
+            Ok(revlog) => stream::iter_result(revlog.revision_infos()).boxify(),
+            Err(err) => stream::once(Err(err)).boxify(),
+        }
+    }
 }
 
 pub struct ChangesetBlobFiller(RevlogRepo);
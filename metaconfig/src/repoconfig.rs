@@ -31,10 +31,76 @@ pub struct RepoConfig {
     pub repotype: RepoType,
     /// How large a cache to use (in bytes) for RepoGenCache derived information
     pub generation_cache_size: usize,
+    /// How large an in-process cache to keep (in bytes) of the blobs of the repo, if any
+    pub blob_cache_size: Option<usize>,
+    /// Store pushed file contents as deltas, with chains of at most this many deltas, if set
+    pub max_delta_chain: Option<usize>,
     /// Numerical repo id of the repo.
     pub repoid: i32,
     /// Scuba table for logging performance of operations
     pub scuba_table: Option<String>,
+    /// Prefix prepended to all blobstore keys of this repo, so that several repos can share a
+    /// single blobstore
+    pub blob_prefix: Option<String>,
+    /// Clonebundles manifest listing pre-generated bundles that clients can clone from instead
+    /// of asking the server for the whole repo. Read on every request, so it can be updated
+    /// without a restart.
+    pub clonebundles_manifest: Option<PathBuf>,
+    /// Checkout of a revlog copy of this repo, whose store is sent as is to clients asking for a
+    /// streaming clone. Nothing on the server updates it: it has to be kept in sync with the
+    /// blobstore, e.g. by running blobexport after pushes. Clients get what the copy has, so a
+    /// stale copy leaves them more to pull, and a copy with changesets the blobstore doesn't have
+    /// gives them history the server doesn't know of.
+    pub streaming_clone_repo: Option<PathBuf>,
+    /// File every push and move of a bookmark is written to before it's applied, see the oplog
+    /// module of blobrepo
+    pub oplog_path: Option<PathBuf>,
+    /// MySQL database the heads and bookmarks of the repo are kept in, if they aren't kept with
+    /// its blobs, so that several servers can serve it. The repo needs a database of its own.
+    pub db_address: Option<String>,
+    /// Checks run against the pushed changesets
+    pub hooks: HooksConfig,
+}
+
+/// Checks run against the changesets of every push, which is rejected if any of them fails
+#[derive(Debug, Default, Clone, Eq, PartialEq, Deserialize)]
+#[serde(default)]
+pub struct HooksConfig {
+    /// Regex which the message of every pushed changeset has to match
+    pub commit_message_regex: Option<String>,
+    /// Maximum size in bytes of the files in the pushed changesets
+    pub max_file_size: Option<usize>,
+    /// Paths which pushed changesets can't touch, including everything under them
+    pub banned_paths: Vec<String>,
+}
+
+/// Keys which can be set in the TOML config of a single repository
+pub const REPO_CONFIG_KEYS: &[&str] = &[
+    "path",
+    "repotype",
+    "generation_cache_size",
+    "blob_cache_size",
+    "max_delta_chain",
+    "manifold_bucket",
+    "manifold_prefix",
+    "repoid",
+    "scuba_table",
+    "blob_prefix",
+    "clonebundles_manifest",
+    "streaming_clone_repo",
+    "oplog_path",
+    "db_address",
+    "hooks",
+];
+
+/// Keys which can be set in the hooks section of the TOML config of a repository
+pub const HOOKS_CONFIG_KEYS: &[&str] = &["commit_message_regex", "max_file_size", "banned_paths"];
+
+impl RepoConfig {
+    /// Parse the TOML config of a single repository, in the same format as in metaconfig repo
+    pub fn from_toml(value: toml::Value) -> Result<Self> {
+        RepoConfig::try_from(value.try_into::<RawRepoConfig>()?)
+    }
 }
 
 /// Types of repositories supported
@@ -183,10 +249,18 @@ struct RawRepoConfig {
     path: PathBuf,
     repotype: RawRepoType,
     generation_cache_size: Option<usize>,
+    blob_cache_size: Option<usize>,
+    max_delta_chain: Option<usize>,
     manifold_bucket: Option<String>,
     manifold_prefix: Option<String>,
     repoid: i32,
     scuba_table: Option<String>,
+    blob_prefix: Option<String>,
+    clonebundles_manifest: Option<PathBuf>,
+    streaming_clone_repo: Option<PathBuf>,
+    oplog_path: Option<PathBuf>,
+    db_address: Option<String>,
+    hooks: Option<HooksConfig>,
 }
 
 /// Types of repositories supported
@@ -221,14 +295,30 @@ impl TryFrom<RawRepoConfig> for RepoConfig {
         };
 
         let generation_cache_size = this.generation_cache_size.unwrap_or(10 * 1024 * 1024);
+        let blob_cache_size = this.blob_cache_size;
+        let max_delta_chain = this.max_delta_chain;
         let repoid = this.repoid;
         let scuba_table = this.scuba_table;
+        let blob_prefix = this.blob_prefix;
+        let clonebundles_manifest = this.clonebundles_manifest;
+        let streaming_clone_repo = this.streaming_clone_repo;
+        let oplog_path = this.oplog_path;
+        let db_address = this.db_address;
+        let hooks = this.hooks.unwrap_or_default();
 
         Ok(RepoConfig {
             repotype,
             generation_cache_size,
+            blob_cache_size,
+            max_delta_chain,
             repoid,
             scuba_table,
+            blob_prefix,
+            clonebundles_manifest,
+            streaming_clone_repo,
+            oplog_path,
+            db_address,
+            hooks,
         })
     }
 }
@@ -248,8 +338,18 @@ mod test {
             path="/tmp/fbsource"
             repotype="blob:files"
             generation_cache_size=1048576
+            blob_cache_size=104857600
+            max_delta_chain=20
             repoid=0
             scuba_table="scuba_table"
+            blob_prefix="fbsource."
+            clonebundles_manifest="/tmp/fbsource-clonebundles"
+            streaming_clone_repo="/tmp/fbsource-revlog"
+            oplog_path="/tmp/fbsource-oplog"
+            db_address="xdb.fbsource"
+            [hooks]
+            commit_message_regex="^\\[\\w+\\] "
+            banned_paths=["secrets"]
         "#;
         let www_content = r#"
             path="/tmp/www"
@@ -287,8 +387,20 @@ mod test {
             RepoConfig {
                 repotype: RepoType::BlobFiles("/tmp/fbsource".into()),
                 generation_cache_size: 1024 * 1024,
+                blob_cache_size: Some(100 * 1024 * 1024),
+                max_delta_chain: Some(20),
                 repoid: 0,
                 scuba_table: Some("scuba_table".to_string()),
+                blob_prefix: Some("fbsource.".to_string()),
+                clonebundles_manifest: Some("/tmp/fbsource-clonebundles".into()),
+                streaming_clone_repo: Some("/tmp/fbsource-revlog".into()),
+                oplog_path: Some("/tmp/fbsource-oplog".into()),
+                db_address: Some("xdb.fbsource".to_string()),
+                hooks: HooksConfig {
+                    commit_message_regex: Some(r"^\[\w+\] ".to_string()),
+                    max_file_size: None,
+                    banned_paths: vec!["secrets".to_string()],
+                },
             },
         );
         repos.insert(
@@ -296,8 +408,16 @@ mod test {
             RepoConfig {
                 repotype: RepoType::Revlog("/tmp/www".into()),
                 generation_cache_size: 10 * 1024 * 1024,
+                blob_cache_size: None,
+                max_delta_chain: None,
                 repoid: 1,
                 scuba_table: Some("scuba_table".to_string()),
+                blob_prefix: None,
+                clonebundles_manifest: None,
+                streaming_clone_repo: None,
+                oplog_path: None,
+                db_address: None,
+                hooks: HooksConfig::default(),
             },
         );
         assert_eq!(
@@ -308,4 +428,40 @@ mod test {
             }
         )
     }
+
+    #[test]
+    fn test_from_toml() {
+        let content = r#"
+            path="/tmp/www"
+            repotype="blob:rocks"
+            repoid=1
+            generation_cache_size=1024
+        "#;
+        let config = RepoConfig::from_toml(toml::from_str(content).unwrap())
+            .expect("failed to parse repo config");
+        assert_eq!(
+            config,
+            RepoConfig {
+                repotype: RepoType::BlobRocks("/tmp/www".into()),
+                generation_cache_size: 1024,
+                blob_cache_size: None,
+                max_delta_chain: None,
+                repoid: 1,
+                scuba_table: None,
+                blob_prefix: None,
+                clonebundles_manifest: None,
+                streaming_clone_repo: None,
+                oplog_path: None,
+                db_address: None,
+                hooks: HooksConfig::default(),
+            }
+        );
+
+        let content = r#"
+            path="/tmp/www"
+            repotype="blob:testmanifold"
+            repoid=1
+        "#;
+        assert!(RepoConfig::from_toml(toml::from_str(content).unwrap()).is_err());
+    }
 }
@@ -0,0 +1,274 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::{BTreeMap, HashSet};
+
+use bincode;
+use quickcheck::{Arbitrary, Gen};
+
+use datetime::DateTime;
+use errors::*;
+use file_change::{FileChange, FileType};
+use path::MPath;
+use typed_hash::{ChangesetId, ContentId};
+
+/// A changeset which can be edited, before `freeze` checks that it is valid.
+#[derive(Clone, Debug, Eq, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct BonsaiChangesetMut {
+    pub parents: Vec<ChangesetId>,
+    pub author: String,
+    pub author_date: DateTime,
+    pub committer: Option<String>,
+    pub committer_date: Option<DateTime>,
+    pub message: String,
+    pub extra: BTreeMap<String, Vec<u8>>,
+    /// The files added, modified (`Some`) or deleted (`None`) by the changeset
+    pub file_changes: BTreeMap<MPath, Option<FileChange>>,
+}
+
+impl BonsaiChangesetMut {
+    /// Check that the changeset is valid, and make it immutable.
+    pub fn freeze(self) -> Result<BonsaiChangeset> {
+        self.verify()?;
+        Ok(BonsaiChangeset { inner: self })
+    }
+
+    fn verify(&self) -> Result<()> {
+        let parents: HashSet<_> = self.parents.iter().collect();
+        if parents.len() != self.parents.len() {
+            bail_err!(ErrorKind::InvalidBonsaiChangeset(
+                "duplicate parents".into()
+            ));
+        }
+
+        let mut last_file: Option<&MPath> = None;
+        for (path, change) in &self.file_changes {
+            let change = match *change {
+                Some(ref change) => change,
+                None => continue,
+            };
+            if let Some(&(ref from_path, ref from_cs)) = change.copy_from() {
+                if !parents.contains(from_cs) {
+                    bail_err!(ErrorKind::InvalidBonsaiChangeset(format!(
+                        "{} is copied from {} in {}, which isn't a parent",
+                        path, from_path, from_cs
+                    )));
+                }
+            }
+            // Paths are sorted, so a file which is a directory of another file comes right before
+            // the first such file
+            if let Some(last_file) = last_file {
+                if last_file.is_prefix_of(path) {
+                    bail_err!(ErrorKind::InvalidBonsaiChangeset(format!(
+                        "{} is both a file and a directory of {}",
+                        last_file, path
+                    )));
+                }
+            }
+            last_file = Some(path);
+        }
+        Ok(())
+    }
+}
+
+/// A changeset as Mononoke stores it, whichever version control system it came from.
+///
+/// It records the files it changes explicitly, with their copy information, rather than through
+/// manifests, and its id is a hash of its own content only. So it doesn't depend on how Mercurial
+/// or another system hashes its commits.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BonsaiChangeset {
+    inner: BonsaiChangesetMut,
+}
+
+impl BonsaiChangeset {
+    /// Deserialize a changeset stored with `to_blob`.
+    pub fn from_blob<T: AsRef<[u8]>>(bytes: T) -> Result<Self> {
+        let inner: BonsaiChangesetMut = bincode::deserialize(bytes.as_ref())
+            .context(ErrorKind::BlobDeserializeError("bonsai changeset".into()))?;
+        inner.freeze()
+    }
+
+    /// Serialize the changeset, as it's stored and hashed.
+    pub fn to_blob(&self) -> Vec<u8> {
+        bincode::serialize(&self.inner).expect("serialize for BonsaiChangeset cannot fail")
+    }
+
+    pub fn get_changeset_id(&self) -> ChangesetId {
+        ChangesetId::from_data(self.to_blob())
+    }
+
+    pub fn parents(&self) -> &[ChangesetId] {
+        &self.inner.parents
+    }
+
+    pub fn author(&self) -> &str {
+        &self.inner.author
+    }
+
+    pub fn author_date(&self) -> &DateTime {
+        &self.inner.author_date
+    }
+
+    pub fn committer(&self) -> Option<&str> {
+        self.inner.committer.as_ref().map(String::as_str)
+    }
+
+    pub fn committer_date(&self) -> Option<&DateTime> {
+        self.inner.committer_date.as_ref()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.inner.message
+    }
+
+    pub fn extra(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.inner.extra
+    }
+
+    pub fn file_changes(&self) -> &BTreeMap<MPath, Option<FileChange>> {
+        &self.inner.file_changes
+    }
+
+    /// Make the changeset editable again, for instance to create a new changeset from it.
+    pub fn into_mut(self) -> BonsaiChangesetMut {
+        self.inner
+    }
+}
+
+impl Arbitrary for BonsaiChangesetMut {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let parents: Vec<_> = (0..g.gen_range(0, 3))
+            .map(|_| ChangesetId::arbitrary(g))
+            .collect();
+
+        let mut file_changes = BTreeMap::new();
+        for _ in 0..g.gen_range(0, 10) {
+            let path = MPath::arbitrary_params(g, false);
+            let change = if g.gen_weighted_bool(5) {
+                None
+            } else {
+                // Copies are only valid from a parent
+                let copy_from = if g.gen_weighted_bool(4) {
+                    g.choose(&parents)
+                        .cloned()
+                        .map(|parent| (MPath::arbitrary_params(g, false), parent))
+                } else {
+                    None
+                };
+                Some(FileChange::new(
+                    ContentId::arbitrary(g),
+                    FileType::arbitrary(g),
+                    u64::arbitrary(g),
+                    copy_from,
+                ))
+            };
+            file_changes.insert(path, change);
+        }
+
+        BonsaiChangesetMut {
+            parents,
+            author: String::arbitrary(g),
+            author_date: DateTime::arbitrary(g),
+            committer: Option::<String>::arbitrary(g),
+            committer_date: Option::<DateTime>::arbitrary(g),
+            message: String::arbitrary(g),
+            extra: BTreeMap::arbitrary(g),
+            file_changes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use quickcheck::TestResult;
+
+    quickcheck! {
+        fn blob_roundtrip(cs: BonsaiChangesetMut) -> TestResult {
+            // Arbitrary paths can be both a file and a directory
+            let cs = match cs.freeze() {
+                Ok(cs) => cs,
+                Err(_) => return TestResult::discard(),
+            };
+            let blob = cs.to_blob();
+            let decoded = BonsaiChangeset::from_blob(&blob).unwrap();
+            TestResult::from_bool(
+                decoded.get_changeset_id() == cs.get_changeset_id() && decoded == cs
+            )
+        }
+    }
+
+    fn file_change(copy_from: Option<(MPath, ChangesetId)>) -> Option<FileChange> {
+        Some(FileChange::new(
+            ContentId::from_data(b"content"),
+            FileType::Regular,
+            7,
+            copy_from,
+        ))
+    }
+
+    fn changeset(
+        parents: Vec<ChangesetId>,
+        file_changes: Vec<(&str, Option<FileChange>)>,
+    ) -> BonsaiChangesetMut {
+        BonsaiChangesetMut {
+            parents,
+            author: "author".into(),
+            author_date: DateTime::new(1000, 0).unwrap(),
+            committer: None,
+            committer_date: None,
+            message: "message".into(),
+            extra: BTreeMap::new(),
+            file_changes: file_changes
+                .into_iter()
+                .map(|(path, change)| (MPath::new(path).unwrap(), change))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn copy_from_parent() {
+        let p1 = ChangesetId::from_data(b"p1");
+        let p2 = ChangesetId::from_data(b"p2");
+        let copy = Some((MPath::new("a").unwrap(), p2));
+        changeset(vec![p1, p2], vec![("b", file_change(copy.clone()))])
+            .freeze()
+            .expect("copy from a parent should be valid");
+
+        let res = changeset(vec![p1], vec![("b", file_change(copy))]).freeze();
+        assert_matches!(
+            res.unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::InvalidBonsaiChangeset(_)
+        );
+    }
+
+    #[test]
+    fn file_and_directory() {
+        changeset(vec![], vec![("a", None), ("a/b", file_change(None))])
+            .freeze()
+            .expect("replacing a file with a directory should be valid");
+
+        let res = changeset(vec![], vec![("a", file_change(None)), ("a/b", file_change(None))])
+            .freeze();
+        assert_matches!(
+            res.unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::InvalidBonsaiChangeset(_)
+        );
+    }
+
+    #[test]
+    fn duplicate_parents() {
+        let p1 = ChangesetId::from_data(b"p1");
+        let res = changeset(vec![p1, p1], vec![]).freeze();
+        assert_matches!(
+            res.unwrap_err().downcast::<ErrorKind>().unwrap(),
+            ErrorKind::InvalidBonsaiChangeset(_)
+        );
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt::{self, Display};
+
+use quickcheck::{Arbitrary, Gen};
+
+use errors::*;
+
+/// The largest timezone offset, in seconds, either side of UTC.
+const MAX_TZ_OFFSET: i32 = 14 * 60 * 60;
+
+/// A point in time, with the timezone it was recorded in.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub struct DateTime {
+    /// Seconds since the Unix epoch, in UTC
+    timestamp_secs: i64,
+    /// Seconds east of UTC of the timezone
+    tz_offset_secs: i32,
+}
+
+impl DateTime {
+    pub fn new(timestamp_secs: i64, tz_offset_secs: i32) -> Result<Self> {
+        if tz_offset_secs.abs() > MAX_TZ_OFFSET {
+            bail_err!(ErrorKind::InvalidDateTime(format!(
+                "timezone offset {} is out of range",
+                tz_offset_secs
+            )));
+        }
+        Ok(DateTime {
+            timestamp_secs,
+            tz_offset_secs,
+        })
+    }
+
+    pub fn timestamp_secs(&self) -> i64 {
+        self.timestamp_secs
+    }
+
+    pub fn tz_offset_secs(&self) -> i32 {
+        self.tz_offset_secs
+    }
+}
+
+impl Display for DateTime {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let sign = if self.tz_offset_secs < 0 { '-' } else { '+' };
+        let offset = self.tz_offset_secs.abs();
+        write!(
+            fmt,
+            "{} {}{:02}{:02}",
+            self.timestamp_secs,
+            sign,
+            offset / 3600,
+            offset % 3600 / 60
+        )
+    }
+}
+
+impl Arbitrary for DateTime {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        DateTime {
+            timestamp_secs: g.gen_range(0, i64::from(u32::max_value())),
+            tz_offset_secs: g.gen_range(-MAX_TZ_OFFSET, MAX_TZ_OFFSET + 1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn display() {
+        assert_eq!(format!("{}", DateTime::new(1000, 0).unwrap()), "1000 +0000");
+        assert_eq!(format!("{}", DateTime::new(1000, -19800).unwrap()), "1000 -0530");
+        assert_eq!(format!("{}", DateTime::new(1000, 3600).unwrap()), "1000 +0100");
+    }
+
+    #[test]
+    fn bad_offset() {
+        DateTime::new(0, MAX_TZ_OFFSET + 1).expect_err("unexpected OK - offset too large");
+        DateTime::new(0, -MAX_TZ_OFFSET - 1).expect_err("unexpected OK - offset too small");
+    }
+}
@@ -13,6 +13,9 @@ pub enum ErrorKind {
     #[fail(display = "invalid blake2 input: {}", _0)] InvalidBlake2Input(String),
     #[fail(display = "invalid path '{}': {}", _0, _1)] InvalidPath(String, String),
     #[fail(display = "invalid Mononoke path '{}': {}", _0, _1)] InvalidMPath(MPath, String),
+    #[fail(display = "invalid date: {}", _0)] InvalidDateTime(String),
+    #[fail(display = "invalid bonsai changeset: {}", _0)] InvalidBonsaiChangeset(String),
+    #[fail(display = "failed to deserialize {} blob", _0)] BlobDeserializeError(String),
 }
 
 pub type Result<T> = ::std::result::Result<T, Error>;
@@ -0,0 +1,98 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt::{self, Display};
+
+use quickcheck::{Arbitrary, Gen};
+
+use path::MPath;
+use typed_hash::{ChangesetId, ContentId};
+
+/// A file added or modified by a changeset. Deletions are recorded as the absence of a
+/// `FileChange`.
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub struct FileChange {
+    content_id: ContentId,
+    file_type: FileType,
+    size: u64,
+    copy_from: Option<(MPath, ChangesetId)>,
+}
+
+impl FileChange {
+    pub fn new(
+        content_id: ContentId,
+        file_type: FileType,
+        size: u64,
+        copy_from: Option<(MPath, ChangesetId)>,
+    ) -> Self {
+        FileChange {
+            content_id,
+            file_type,
+            size,
+            copy_from,
+        }
+    }
+
+    pub fn content_id(&self) -> &ContentId {
+        &self.content_id
+    }
+
+    pub fn file_type(&self) -> FileType {
+        self.file_type
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// The path and the parent changeset this file was copied or moved from, if it was.
+    pub fn copy_from(&self) -> Option<&(MPath, ChangesetId)> {
+        self.copy_from.as_ref()
+    }
+}
+
+impl Arbitrary for FileChange {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        let copy_from = if g.gen_weighted_bool(5) {
+            Some((MPath::arbitrary_params(g, false), ChangesetId::arbitrary(g)))
+        } else {
+            None
+        };
+        FileChange {
+            content_id: ContentId::arbitrary(g),
+            file_type: FileType::arbitrary(g),
+            size: u64::arbitrary(g),
+            copy_from,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub enum FileType {
+    Regular,
+    Executable,
+    Symlink,
+}
+
+impl Display for FileType {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        let s = match *self {
+            FileType::Regular => "regular",
+            FileType::Executable => "executable",
+            FileType::Symlink => "symlink",
+        };
+        write!(fmt, "{}", s)
+    }
+}
+
+impl Arbitrary for FileType {
+    fn arbitrary<G: Gen>(g: &mut G) -> Self {
+        *g.choose(&[FileType::Regular, FileType::Executable, FileType::Symlink])
+            .expect("choosing from a non-empty slice")
+    }
+}
@@ -31,8 +31,16 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
+pub mod bonsai_changeset;
+pub mod datetime;
 pub mod errors;
+pub mod file_change;
 pub mod hash;
 pub mod path;
+pub mod typed_hash;
 
+pub use bonsai_changeset::{BonsaiChangeset, BonsaiChangesetMut};
+pub use datetime::DateTime;
+pub use file_change::{FileChange, FileType};
 pub use path::{MPath, MPathElement, RepoPath};
+pub use typed_hash::{ChangesetId, ContentId};
@@ -236,6 +236,13 @@ impl MPath {
     pub fn is_empty(&self) -> bool {
         self.elements.is_empty()
     }
+
+    /// Whether `other` is this path or under it, comparing whole elements: "a/b" is a prefix of
+    /// "a/b/c", but not of "a/bc".
+    pub fn is_prefix_of(&self, other: &MPath) -> bool {
+        self.elements.len() <= other.elements.len()
+            && self.elements.iter().zip(&other.elements).all(|(a, b)| a == b)
+    }
 }
 
 impl IntoIterator for MPath {
@@ -473,4 +480,15 @@ mod test {
         assert!(a <= a);
         assert!(a <= b);
     }
+
+    #[test]
+    fn path_is_prefix_of() {
+        let path = MPath::new("a/b/c").unwrap();
+        assert!(MPath::new("a/b").unwrap().is_prefix_of(&path));
+        assert!(MPath::new("a/b/c").unwrap().is_prefix_of(&path));
+        assert!(MPath::empty().is_prefix_of(&path));
+        assert!(!MPath::new("a/bc").unwrap().is_prefix_of(&MPath::new("a/b").unwrap()));
+        assert!(!MPath::new("a/b/c/d").unwrap().is_prefix_of(&path));
+        assert!(!MPath::new("b").unwrap().is_prefix_of(&path));
+    }
 }
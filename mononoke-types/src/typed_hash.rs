@@ -0,0 +1,104 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use quickcheck::{Arbitrary, Gen};
+
+use errors::*;
+use hash::{Blake2, Context};
+
+// There is no NULL_HASH for typed hashes. Any places that need a null hash should use an
+// Option type, or perhaps a list as desired.
+
+/// An identifier for a changeset in Mononoke.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub struct ChangesetId(Blake2);
+
+/// An identifier for file contents in Mononoke.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Ord, PartialOrd, Hash)]
+#[derive(Serialize, Deserialize, HeapSizeOf)]
+pub struct ContentId(Blake2);
+
+/// Implementations of typed hashes. `$key` is hashed in first, so that the same bytes hash
+/// differently as a changeset and as a content.
+macro_rules! impl_typed_hash {
+    ($typed: ident, $key: expr) => {
+        impl $typed {
+            pub const fn new(blake2: Blake2) -> Self {
+                $typed(blake2)
+            }
+
+            /// The hash of `data`, as an identifier of this type.
+            pub fn from_data<T: AsRef<[u8]>>(data: T) -> Self {
+                let mut context = Context::new();
+                context.update($key);
+                context.update(data);
+                $typed(context.finish())
+            }
+
+            pub fn from_bytes<B: AsRef<[u8]>>(bytes: B) -> Result<Self> {
+                Blake2::from_bytes(bytes).map($typed)
+            }
+
+            pub fn blake2(&self) -> &Blake2 {
+                &self.0
+            }
+        }
+
+        impl FromStr for $typed {
+            type Err = Error;
+
+            fn from_str(s: &str) -> Result<Self> {
+                Blake2::from_str(s).map($typed)
+            }
+        }
+
+        impl Display for $typed {
+            fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+                Display::fmt(&self.0, fmt)
+            }
+        }
+
+        impl Arbitrary for $typed {
+            fn arbitrary<G: Gen>(g: &mut G) -> Self {
+                $typed(Blake2::arbitrary(g))
+            }
+
+            fn shrink(&self) -> Box<Iterator<Item = Self>> {
+                Box::new(self.0.shrink().map($typed))
+            }
+        }
+    }
+}
+
+impl_typed_hash!(ChangesetId, b"changeset");
+impl_typed_hash!(ContentId, b"content");
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    quickcheck! {
+        fn changesetid_roundtrip(id: ChangesetId) -> bool {
+            let parsed: ChangesetId = format!("{}", id).parse().unwrap();
+            parsed == id
+        }
+    }
+
+    #[test]
+    fn keyed_hashes() {
+        // The same data hashes differently depending on what it is the id of
+        let data = b"some data";
+        assert_ne!(
+            ChangesetId::from_data(data).blake2(),
+            ContentId::from_data(data).blake2()
+        );
+        assert_ne!(*ContentId::from_data(data).blake2(), Blake2::from(&data[..]));
+    }
+}
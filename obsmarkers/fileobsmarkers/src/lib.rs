@@ -0,0 +1,146 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate mercurial_types;
+extern crate obsmarkers;
+
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate futures_ext;
+#[cfg(test)]
+extern crate tempdir;
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use failure::{Error, Result};
+use futures::Async;
+use futures::future::{poll_fn, Future};
+use futures::stream;
+use futures_cpupool::CpuPool;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use mercurial_types::ObsMarker;
+use mercurial_types::obsmarker::{decode_markers, encode_markers};
+use obsmarkers::{new_markers, ObsMarkers};
+
+static OBSSTORE: &'static str = "obsstore";
+
+/// A basic file-based persistent obsolescence markers store.
+///
+/// Stores the markers in a single file in the specified directory, in the same format as
+/// Mercurial's obsstore. File operations are dispatched to a thread pool to avoid blocking the
+/// main thread with IO. Adding markers has to read the file to skip those already stored, so
+/// additions are serialized with a lock.
+pub struct FileObsMarkers {
+    path: PathBuf,
+    pool: Arc<CpuPool>,
+    lock: Arc<Mutex<()>>,
+}
+
+impl FileObsMarkers {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            bail_msg!("'{}' is not a directory", path.to_string_lossy());
+        }
+
+        Ok(FileObsMarkers {
+            path: path.join(OBSSTORE),
+            pool: pool,
+            lock: Arc::new(Mutex::new(())),
+        })
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        Self::open_with_pool(path, pool)
+    }
+}
+
+/// Read all the markers of the store, which is empty if it doesn't exist yet.
+fn read_markers(path: &Path) -> Result<Vec<ObsMarker>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+    let mut data = Vec::new();
+    file.read_to_end(&mut data)?;
+    if data.is_empty() {
+        Ok(Vec::new())
+    } else {
+        decode_markers(&data)
+    }
+}
+
+impl ObsMarkers for FileObsMarkers {
+    fn add(&self, markers: Vec<ObsMarker>) -> BoxFuture<usize, Error> {
+        let path = self.path.clone();
+        let lock = self.lock.clone();
+        let mut markers = Some(markers);
+        let future = poll_fn(move || {
+            let _guard = lock.lock().expect("lock poisoned");
+            let existing = read_markers(&path)?;
+            let new = new_markers(&existing, markers.take().expect("polled after completion"));
+            if !new.is_empty() {
+                let data = encode_markers(&new)?;
+                // The version header is only written once, at the start of the file
+                let data = if existing.is_empty() {
+                    &data[..]
+                } else {
+                    &data[1..]
+                };
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)?
+                    .write_all(data)?;
+            }
+            Ok(Async::Ready(new.len()))
+        });
+        self.pool.spawn(future).boxify()
+    }
+
+    fn markers(&self) -> BoxStream<ObsMarker, Error> {
+        let path = self.path.clone();
+        let future = poll_fn(move || read_markers(&path).map(Async::Ready));
+        self.pool
+            .spawn(future)
+            .map(stream::iter_ok)
+            .flatten_stream()
+            .boxify()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn invalid_dir() {
+        let tmp = TempDir::new("fileobsmarkers_invalid_dir").unwrap();
+        let obsmarkers = FileObsMarkers::open(tmp.path().join("does_not_exist"));
+        assert!(obsmarkers.is_err());
+    }
+}
@@ -0,0 +1,52 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate obsmarkers;
+
+use std::sync::Mutex;
+
+use failure::Error;
+use futures::future::ok;
+use futures::stream::iter_ok;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use mercurial_types::ObsMarker;
+use obsmarkers::{new_markers, ObsMarkers};
+
+/// Generic, in-memory obsolescence markers store backed by a Vec, intended to be used in tests.
+pub struct MemObsMarkers {
+    markers: Mutex<Vec<ObsMarker>>,
+}
+
+impl MemObsMarkers {
+    pub fn new() -> Self {
+        MemObsMarkers {
+            markers: Mutex::new(Vec::new()),
+        }
+    }
+}
+
+impl ObsMarkers for MemObsMarkers {
+    fn add(&self, markers: Vec<ObsMarker>) -> BoxFuture<usize, Error> {
+        let mut stored = self.markers.lock().unwrap();
+        let new = new_markers(&stored, markers);
+        let count = new.len();
+        stored.extend(new);
+        ok(count).boxify()
+    }
+
+    fn markers(&self) -> BoxStream<ObsMarker, Error> {
+        let guard = self.markers.lock().unwrap();
+        let markers = (*guard).clone();
+        iter_ok(markers).boxify()
+    }
+}
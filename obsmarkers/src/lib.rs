@@ -0,0 +1,46 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+extern crate failure_ext as failure;
+extern crate futures_ext;
+
+extern crate mercurial_types;
+
+use failure::Error;
+use futures_ext::{BoxFuture, BoxStream};
+
+use mercurial_types::ObsMarker;
+
+/// Trait representing the interface to an obsolescence markers store. Markers are only ever
+/// added, like in Mercurial's obsstore, and a marker which is already stored isn't added again.
+pub trait ObsMarkers: Send + Sync + 'static {
+    // Markers are returned in the order they were added.
+
+    /// Add markers, resolving to how many of them weren't already stored.
+    fn add(&self, markers: Vec<ObsMarker>) -> BoxFuture<usize, Error>;
+    fn markers(&self) -> BoxStream<ObsMarker, Error>;
+}
+
+impl ObsMarkers for Box<ObsMarkers> {
+    fn add(&self, markers: Vec<ObsMarker>) -> BoxFuture<usize, Error> {
+        self.as_ref().add(markers)
+    }
+
+    fn markers(&self) -> BoxStream<ObsMarker, Error> {
+        self.as_ref().markers()
+    }
+}
+
+/// Keep the markers which aren't in `existing`, nor earlier in `markers`.
+pub fn new_markers(existing: &[ObsMarker], markers: Vec<ObsMarker>) -> Vec<ObsMarker> {
+    let mut new: Vec<ObsMarker> = Vec::with_capacity(markers.len());
+    for marker in markers {
+        if !existing.contains(&marker) && !new.contains(&marker) {
+            new.push(marker);
+        }
+    }
+    new
+}
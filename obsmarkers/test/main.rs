@@ -0,0 +1,143 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests run against all obsolescence markers implementations.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate tempdir;
+
+extern crate fileobsmarkers;
+extern crate memobsmarkers;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate obsmarkers;
+
+use futures::{Future, Stream};
+use tempdir::TempDir;
+
+use fileobsmarkers::FileObsMarkers;
+use memobsmarkers::MemObsMarkers;
+use mercurial_types::{NodeHash, ObsMarker};
+use obsmarkers::ObsMarkers;
+
+fn marker(precursor: NodeHash, successors: Vec<NodeHash>) -> ObsMarker {
+    ObsMarker {
+        precursor,
+        successors,
+        flags: 0,
+        parents: None,
+        date: (1500000000.0, 0),
+        metadata: vec![(b"user".to_vec(), b"test".to_vec())],
+    }
+}
+
+fn basic<O: ObsMarkers>(obsmarkers: O) {
+    let empty: Vec<ObsMarker> = Vec::new();
+    assert_eq!(obsmarkers.markers().collect().wait().unwrap(), empty);
+
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+    let baz = mercurial_types_mocks::nodehash::THREES_HASH;
+
+    let rewrite = marker(foo, vec![bar]);
+    let prune = marker(bar, vec![]);
+    let split = marker(baz, vec![foo, bar]);
+
+    assert_eq!(
+        obsmarkers
+            .add(vec![rewrite.clone(), prune.clone()])
+            .wait()
+            .unwrap(),
+        2
+    );
+    assert_eq!(
+        obsmarkers.markers().collect().wait().unwrap(),
+        vec![rewrite.clone(), prune.clone()]
+    );
+
+    // Markers which are already stored, or repeated, are only added once.
+    assert_eq!(
+        obsmarkers
+            .add(vec![prune.clone(), split.clone(), split.clone()])
+            .wait()
+            .unwrap(),
+        1
+    );
+    assert_eq!(
+        obsmarkers.markers().collect().wait().unwrap(),
+        vec![rewrite, prune, split]
+    );
+    assert_eq!(obsmarkers.add(vec![]).wait().unwrap(), 0);
+}
+
+fn persistence<F, O>(mut new_obsmarkers: F)
+where
+    F: FnMut() -> O,
+    O: ObsMarkers,
+{
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+    let rewrite = marker(foo, vec![bar]);
+    let prune = marker(bar, vec![]);
+
+    {
+        let obsmarkers = new_obsmarkers();
+        obsmarkers.add(vec![rewrite.clone()]).wait().unwrap();
+        obsmarkers.add(vec![prune.clone()]).wait().unwrap();
+    }
+
+    let obsmarkers = new_obsmarkers();
+    assert_eq!(
+        obsmarkers.markers().collect().wait().unwrap(),
+        vec![rewrite.clone(), prune.clone()]
+    );
+    assert_eq!(obsmarkers.add(vec![rewrite]).wait().unwrap(), 0);
+}
+
+macro_rules! obsmarkers_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+        persistent: $persistent: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_basic() {
+                let state = $state;
+                basic($new_cb(&state));
+            }
+
+            #[test]
+            fn test_persistence() {
+                // Not all obsolescence markers implementations support persistence.
+                if $persistent {
+                    let state = $state;
+                    persistence(|| $new_cb(&state));
+                }
+            }
+        }
+    }
+}
+
+obsmarkers_test_impl! {
+    memobsmarkers_test => {
+        state: (),
+        new: |_| MemObsMarkers::new(),
+        persistent: false,
+    }
+}
+
+obsmarkers_test_impl! {
+    fileobsmarkers_test => {
+        state: TempDir::new("fileobsmarkers_test").unwrap(),
+        new: |dir| FileObsMarkers::open(&dir).unwrap(),
+        persistent: true,
+    }
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate mercurial_types;
+extern crate phases;
+
+#[macro_use]
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate futures_ext;
+#[cfg(test)]
+extern crate tempdir;
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use failure::{Error, Result, ResultExt};
+use futures::Async;
+use futures::future::poll_fn;
+use futures::stream::{self, Stream};
+use futures_cpupool::CpuPool;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use mercurial_types::NodeHash;
+use phases::{Phase, Phases};
+
+static PREFIX: &'static str = "draft-";
+
+/// A basic file-based persistent phases store.
+///
+/// Stores draft changesets as empty files in the specified directory. File operations are
+/// dispatched to a thread pool to avoid blocking the main thread with IO. For simplicity, file
+/// accesses are unsynchronized since each operation performs just a single File IO syscall.
+pub struct FilePhases {
+    base: PathBuf,
+    pool: Arc<CpuPool>,
+}
+
+impl FilePhases {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::open_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn open_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+
+        if !path.is_dir() {
+            bail_msg!("'{}' is not a directory", path.to_string_lossy());
+        }
+
+        Ok(FilePhases {
+            base: path.to_path_buf(),
+            pool: pool,
+        })
+    }
+
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::create_with_pool(path, Arc::new(CpuPool::new_num_cpus()))
+    }
+
+    pub fn create_with_pool<P: AsRef<Path>>(path: P, pool: Arc<CpuPool>) -> Result<Self> {
+        let path = path.as_ref();
+        fs::create_dir_all(path)?;
+        Self::open_with_pool(path, pool)
+    }
+
+    fn get_path(&self, node: &NodeHash) -> PathBuf {
+        self.base.join(format!("{}{}", PREFIX, node))
+    }
+}
+
+impl Phases for FilePhases {
+    fn get(&self, node: &NodeHash) -> BoxFuture<Phase, Error> {
+        let path = self.get_path(node);
+        let future = poll_fn(move || {
+            let phase = if path.exists() {
+                Phase::Draft
+            } else {
+                Phase::Public
+            };
+            Ok(Async::Ready(phase))
+        });
+        self.pool.spawn(future).boxify()
+    }
+
+    fn set(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), Error> {
+        let path = self.get_path(node);
+        let future = poll_fn(move || {
+            match phase {
+                Phase::Draft => {
+                    File::create(&path)?;
+                }
+                Phase::Public => fs::remove_file(&path).or_else(|e| {
+                    // Don't report an error if the changeset wasn't draft.
+                    match e.kind() {
+                        io::ErrorKind::NotFound => Ok(()),
+                        _ => Err(e),
+                    }
+                })?,
+            }
+            Ok(Async::Ready(()))
+        });
+        self.pool.spawn(future).boxify()
+    }
+
+    fn drafts(&self) -> BoxStream<NodeHash, Error> {
+        let names = fs::read_dir(&self.base).map(|entries| {
+            entries
+                .map(|result| {
+                    result
+                        .map_err(From::from)
+                        .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                })
+                .filter_map(|result| match result {
+                    Ok(ref name) if name.starts_with(PREFIX) => {
+                        let name = &name[PREFIX.len()..];
+                        let name = NodeHash::from_str(name)
+                            .context("can't parse name")
+                            .map_err(Error::from);
+                        Some(name)
+                    }
+                    Ok(_) => None,
+                    Err(err) => Some(Err(err)),
+                })
+        });
+        match names {
+            Ok(v) => stream::iter_ok(v).and_then(|v| v).boxify(),
+            Err(e) => stream::once(Err(e.into())).boxify(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tempdir::TempDir;
+
+    #[test]
+    fn invalid_dir() {
+        let tmp = TempDir::new("filephases_invalid_dir").unwrap();
+        let phases = FilePhases::open(tmp.path().join("does_not_exist"));
+        assert!(phases.is_err());
+    }
+}
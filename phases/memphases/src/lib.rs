@@ -0,0 +1,63 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+#![deny(warnings)]
+
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+extern crate mercurial_types;
+extern crate phases;
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+use failure::Error;
+use futures::future::ok;
+use futures::stream::iter_ok;
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+
+use mercurial_types::NodeHash;
+use phases::{Phase, Phases};
+
+/// Generic, in-memory phases store backed by a HashSet of drafts, intended to be used in tests.
+pub struct MemPhases {
+    drafts: Mutex<HashSet<NodeHash>>,
+}
+
+impl MemPhases {
+    pub fn new() -> Self {
+        MemPhases {
+            drafts: Mutex::new(HashSet::new()),
+        }
+    }
+}
+
+impl Phases for MemPhases {
+    fn get(&self, node: &NodeHash) -> BoxFuture<Phase, Error> {
+        let phase = if self.drafts.lock().unwrap().contains(node) {
+            Phase::Draft
+        } else {
+            Phase::Public
+        };
+        ok(phase).boxify()
+    }
+
+    fn set(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), Error> {
+        let mut drafts = self.drafts.lock().unwrap();
+        match phase {
+            Phase::Draft => drafts.insert(*node),
+            Phase::Public => drafts.remove(node),
+        };
+        ok(()).boxify()
+    }
+
+    fn drafts(&self) -> BoxStream<NodeHash, Error> {
+        let guard = self.drafts.lock().unwrap();
+        let drafts = (*guard).clone();
+        iter_ok(drafts).boxify()
+    }
+}
@@ -0,0 +1,78 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+extern crate failure_ext as failure;
+extern crate futures;
+extern crate futures_ext;
+
+extern crate mercurial_types;
+
+use std::fmt::{self, Display};
+
+use failure::Error;
+use futures_ext::{BoxFuture, BoxStream};
+
+use mercurial_types::NodeHash;
+
+/// Phase of a changeset. Secret changesets are never sent to a server, so they aren't tracked.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub enum Phase {
+    Public,
+    Draft,
+}
+
+impl Phase {
+    /// The number Mercurial uses for this phase on the wire.
+    pub fn as_u32(&self) -> u32 {
+        match *self {
+            Phase::Public => 0,
+            Phase::Draft => 1,
+        }
+    }
+
+    pub fn from_u32(phase: u32) -> Option<Phase> {
+        match phase {
+            0 => Some(Phase::Public),
+            1 => Some(Phase::Draft),
+            _ => None,
+        }
+    }
+}
+
+impl Display for Phase {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Phase::Public => write!(fmt, "public"),
+            Phase::Draft => write!(fmt, "draft"),
+        }
+    }
+}
+
+/// Trait representing the interface to a phases store. Only draft changesets are recorded, so
+/// any changeset which was never made draft, like every changeset stored before phases were
+/// tracked, is public.
+pub trait Phases: Send + Sync + 'static {
+    // Drafts are not guaranteed to be returned in any particular order. Changesets which are
+    // draft for the entire duration of the traversal are guaranteed to appear at least once.
+
+    fn get(&self, &NodeHash) -> BoxFuture<Phase, Error>;
+    fn set(&self, &NodeHash, Phase) -> BoxFuture<(), Error>;
+    fn drafts(&self) -> BoxStream<NodeHash, Error>;
+}
+
+impl Phases for Box<Phases> {
+    fn get(&self, node: &NodeHash) -> BoxFuture<Phase, Error> {
+        self.as_ref().get(node)
+    }
+
+    fn set(&self, node: &NodeHash, phase: Phase) -> BoxFuture<(), Error> {
+        self.as_ref().set(node, phase)
+    }
+
+    fn drafts(&self) -> BoxStream<NodeHash, Error> {
+        self.as_ref().drafts()
+    }
+}
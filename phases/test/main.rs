@@ -0,0 +1,119 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Tests run against all phases implementations.
+
+#![deny(warnings)]
+
+extern crate futures;
+extern crate tempdir;
+
+extern crate filephases;
+extern crate memphases;
+extern crate mercurial_types;
+extern crate mercurial_types_mocks;
+extern crate phases;
+
+use futures::{Future, Stream};
+use tempdir::TempDir;
+
+use filephases::FilePhases;
+use memphases::MemPhases;
+use mercurial_types::NodeHash;
+use phases::{Phase, Phases};
+
+fn basic<P: Phases>(phases: P) {
+    let empty: Vec<NodeHash> = Vec::new();
+    assert_eq!(phases.drafts().collect().wait().unwrap(), empty);
+
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+    let baz = mercurial_types_mocks::nodehash::THREES_HASH;
+
+    // Changesets are public unless they were made draft
+    assert_eq!(phases.get(&foo).wait().unwrap(), Phase::Public);
+
+    phases.set(&foo, Phase::Draft).wait().unwrap();
+    phases.set(&bar, Phase::Draft).wait().unwrap();
+
+    assert_eq!(phases.get(&foo).wait().unwrap(), Phase::Draft);
+    assert_eq!(phases.get(&bar).wait().unwrap(), Phase::Draft);
+    assert_eq!(phases.get(&baz).wait().unwrap(), Phase::Public);
+
+    let mut result = phases.drafts().collect().wait().unwrap();
+    result.sort();
+    assert_eq!(result, vec![foo.clone(), bar.clone()]);
+
+    phases.set(&foo, Phase::Public).wait().unwrap();
+    // Publishing a public changeset should not panic.
+    phases.set(&baz, Phase::Public).wait().unwrap();
+
+    assert_eq!(phases.get(&foo).wait().unwrap(), Phase::Public);
+    assert_eq!(phases.drafts().collect().wait().unwrap(), vec![bar]);
+}
+
+fn persistence<F, P>(mut new_phases: F)
+where
+    F: FnMut() -> P,
+    P: Phases,
+{
+    let foo = mercurial_types_mocks::nodehash::ONES_HASH;
+    let bar = mercurial_types_mocks::nodehash::TWOS_HASH;
+
+    {
+        let phases = new_phases();
+        phases.set(&foo, Phase::Draft).wait().unwrap();
+        phases.set(&bar, Phase::Draft).wait().unwrap();
+    }
+
+    let phases = new_phases();
+    let mut result = phases.drafts().collect().wait().unwrap();
+    result.sort();
+    assert_eq!(result, vec![foo.clone(), bar.clone()]);
+}
+
+macro_rules! phases_test_impl {
+    ($mod_name: ident => {
+        state: $state: expr,
+        new: $new_cb: expr,
+        persistent: $persistent: expr,
+    }) => {
+        mod $mod_name {
+            use super::*;
+
+            #[test]
+            fn test_basic() {
+                let state = $state;
+                basic($new_cb(&state));
+            }
+
+            #[test]
+            fn test_persistence() {
+                // Not all phases implementations support persistence.
+                if $persistent {
+                    let state = $state;
+                    persistence(|| $new_cb(&state));
+                }
+            }
+        }
+    }
+}
+
+phases_test_impl! {
+    memphases_test => {
+        state: (),
+        new: |_| MemPhases::new(),
+        persistent: false,
+    }
+}
+
+phases_test_impl! {
+    filephases_test => {
+        state: TempDir::new("filephases_test").unwrap(),
+        new: |dir| FilePhases::open(&dir).unwrap(),
+        persistent: true,
+    }
+}
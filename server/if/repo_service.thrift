@@ -0,0 +1,82 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+// Queries of the data of a repo, for other backend services. They're the queries of the JSON API
+// of the server, see server/src/api.rs, whose structs these mirror.
+
+namespace rust repo_service
+namespace py mononoke.repo_service
+
+// 40 hex digits
+typedef string NodeHash
+
+enum EntryType {
+  FILE = 0,
+  SYMLINK = 1,
+  TREE = 2,
+  EXECUTABLE = 3,
+}
+
+struct Changeset {
+  1: NodeHash node,
+  2: list<NodeHash> parents,
+  3: NodeHash manifest,
+  4: string user,
+  // Seconds since the epoch, and the offset of the timezone in seconds
+  5: i64 time,
+  6: i32 tz,
+  7: string desc,
+  8: string branch,
+  9: list<string> files,
+}
+
+struct File {
+  1: NodeHash node,
+  2: string path,
+  3: EntryType type,
+  4: binary content,
+}
+
+struct TreeEntry {
+  1: string name,
+  2: EntryType type,
+  3: NodeHash node,
+}
+
+struct Directory {
+  1: NodeHash node,
+  2: string path,
+  3: list<TreeEntry> entries,
+}
+
+struct Bookmark {
+  1: string name,
+  2: NodeHash node,
+}
+
+exception NotFound {
+  1: string message,
+}
+
+exception InvalidRequest {
+  1: string message,
+}
+
+service RepoService {
+  Changeset getChangeset(1: string repo, 2: NodeHash node)
+    throws (1: NotFound notFound, 2: InvalidRequest invalid),
+
+  File getFileContent(1: string repo, 2: NodeHash node, 3: string path)
+    throws (1: NotFound notFound, 2: InvalidRequest invalid),
+
+  // The root directory if the path is empty
+  Directory listDirectory(1: string repo, 2: NodeHash node, 3: string path)
+    throws (1: NotFound notFound, 2: InvalidRequest invalid),
+
+  // Sorted by name
+  list<Bookmark> getBookmarks(1: string repo)
+    throws (1: NotFound notFound),
+}
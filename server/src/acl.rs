@@ -0,0 +1,193 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Authorization of the commands run by clients, checked by `RepoClient` before running them.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::{Arc, RwLock};
+
+use futures::future;
+use futures_ext::{BoxFuture, FutureExt};
+
+use config::{AclCheckerType, AclConfig};
+use errors::*;
+use identity::Identity;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Action {
+    /// Fetching data from the repo, e.g. getbundle
+    Read,
+    /// Changing the repo, e.g. unbundle or pushkey
+    Write,
+}
+
+impl Display for Action {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Action::Read => write!(fmt, "read"),
+            Action::Write => write!(fmt, "write"),
+        }
+    }
+}
+
+pub trait AclChecker: Send + Sync + 'static {
+    /// Resolves to whether `identity` can do `action` on the repo at `repopath`.
+    fn check(&self, identity: &Identity, repopath: &str, action: Action) -> BoxFuture<bool, Error>;
+}
+
+/// Everything is allowed to everyone.
+pub struct AllowAll;
+
+impl AclChecker for AllowAll {
+    fn check(&self, _: &Identity, _: &str, _: Action) -> BoxFuture<bool, Error> {
+        future::ok(true).boxify()
+    }
+}
+
+/// Reads are allowed to everyone, writes only to the users listed for the repo path, or for
+/// all repos under "*". A user is given by name, or by uid for the unix socket clients.
+pub struct StaticAcl {
+    writers: HashMap<String, Vec<String>>,
+}
+
+impl StaticAcl {
+    pub fn new(writers: HashMap<String, Vec<String>>) -> Self {
+        StaticAcl { writers }
+    }
+
+    fn is_writer(&self, identity: &Identity, repopath: &str) -> bool {
+        let user = match *identity {
+            Identity::Anonymous => return false,
+            Identity::User(ref name) => name.clone(),
+            Identity::Uid(uid) => uid.to_string(),
+        };
+        [repopath, "*"]
+            .iter()
+            .filter_map(|path| self.writers.get(*path))
+            .any(|writers| writers.contains(&user))
+    }
+}
+
+impl AclChecker for StaticAcl {
+    fn check(&self, identity: &Identity, repopath: &str, action: Action) -> BoxFuture<bool, Error> {
+        let allowed = match action {
+            Action::Read => true,
+            Action::Write => self.is_writer(identity, repopath),
+        };
+        future::ok(allowed).boxify()
+    }
+}
+
+/// Asks an external ACL service. Not implemented yet: reads are allowed, while writes fail, so
+/// that a misconfigured server doesn't accept pushes from everyone.
+pub struct ServiceAcl {
+    service: String,
+}
+
+impl ServiceAcl {
+    pub fn new(service: String) -> Self {
+        ServiceAcl { service }
+    }
+}
+
+impl AclChecker for ServiceAcl {
+    fn check(&self, _: &Identity, _: &str, action: Action) -> BoxFuture<bool, Error> {
+        match action {
+            Action::Read => future::ok(true).boxify(),
+            Action::Write => future::err(format_err!(
+                "checking writes with the ACL service {} is not supported yet",
+                self.service
+            )).boxify(),
+        }
+    }
+}
+
+/// Delegates to a checker which can be replaced while the server runs, when it reloads its
+/// config. Checks in progress finish with the checker they started with.
+pub struct ReloadableAcl {
+    inner: RwLock<Arc<AclChecker>>,
+}
+
+impl ReloadableAcl {
+    pub fn new(checker: Arc<AclChecker>) -> Self {
+        ReloadableAcl {
+            inner: RwLock::new(checker),
+        }
+    }
+
+    pub fn replace(&self, checker: Arc<AclChecker>) {
+        *self.inner.write().expect("lock poisoned") = checker;
+    }
+}
+
+impl AclChecker for ReloadableAcl {
+    fn check(&self, identity: &Identity, repopath: &str, action: Action) -> BoxFuture<bool, Error> {
+        let checker = self.inner.read().expect("lock poisoned").clone();
+        checker.check(identity, repopath, action)
+    }
+}
+
+pub fn acl_checker(config: &AclConfig) -> Result<Arc<AclChecker>> {
+    let checker: Arc<AclChecker> = match config.checker {
+        AclCheckerType::AllowAll => Arc::new(AllowAll),
+        AclCheckerType::Static => Arc::new(StaticAcl::new(config.writers.clone())),
+        AclCheckerType::Service => match config.service {
+            Some(ref service) => Arc::new(ServiceAcl::new(service.clone())),
+            None => bail_err!(ErrorKind::InvalidConfig(
+                "server.acl.service is required by the service checker".into()
+            )),
+        },
+    };
+    Ok(checker)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use futures::Future;
+
+    #[test]
+    fn static_acl() {
+        let acl = StaticAcl::new(hashmap! {
+            "/repos/www".to_string() => vec!["alice".to_string(), "1000".to_string()],
+            "*".to_string() => vec!["admin".to_string()],
+        });
+        let check = |identity, path, action| acl.check(&identity, path, action).wait().unwrap();
+
+        let alice = Identity::User("alice".to_string());
+        let admin = Identity::User("admin".to_string());
+        assert!(check(alice.clone(), "/repos/www", Action::Write));
+        assert!(check(Identity::Uid(1000), "/repos/www", Action::Write));
+        assert!(check(admin.clone(), "/repos/www", Action::Write));
+        assert!(check(admin, "/repos/fbsource", Action::Write));
+        assert!(!check(alice.clone(), "/repos/fbsource", Action::Write));
+        assert!(!check(Identity::Anonymous, "/repos/www", Action::Write));
+
+        assert!(check(alice, "/repos/fbsource", Action::Read));
+        assert!(check(Identity::Anonymous, "/repos/www", Action::Read));
+    }
+
+    #[test]
+    fn reloadable_acl() {
+        let acl = ReloadableAcl::new(Arc::new(AllowAll));
+        let alice = Identity::User("alice".to_string());
+        assert!(acl.check(&alice, "/repos/www", Action::Write).wait().unwrap());
+
+        acl.replace(Arc::new(StaticAcl::new(HashMap::new())));
+        assert!(!acl.check(&alice, "/repos/www", Action::Write).wait().unwrap());
+        assert!(acl.check(&alice, "/repos/www", Action::Read).wait().unwrap());
+    }
+
+    #[test]
+    fn service_acl() {
+        let acl = ServiceAcl::new("acl_service".to_string());
+        let alice = Identity::User("alice".to_string());
+        assert!(acl.check(&alice, "/repos/www", Action::Read).wait().unwrap());
+        assert!(acl.check(&alice, "/repos/www", Action::Write).wait().is_err());
+    }
+}
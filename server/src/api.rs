@@ -0,0 +1,390 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! A read-only JSON API to browse repos, like hgweb's json style, for the tools which don't speak
+//! the wire protocol. It's served over HTTP under `/<repo>/api/`:
+//!
+//! - `changeset/<hash>`: the metadata of a changeset
+//! - `file/<hash>/<path>`: the content of a file in a changeset, which is `null` for binary files
+//! - `tree/<hash>/<path>`: the entries of a directory in a changeset, the root one without path
+//! - `bookmarks`: the bookmarks and the changesets they point to
+//!
+//! The queries behind the endpoints return plain structs, so that other services can serve them
+//! with another protocol, like the one of `if/repo_service.thrift`.
+
+use std::iter;
+use std::str;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{future, stream, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use serde::{Serialize, Serializer};
+use serde_json;
+use url::percent_encoding::percent_decode;
+
+use blobrepo::{BlobChangeset, BlobRepo};
+use mercurial_types::{Changeset, ChangesetId, Entry, MPath, NodeHash, Type};
+use mercurial_types::manifest::Content;
+
+use errors::*;
+
+/// The prefix of the path of API requests, after the path of the repo
+const API_PREFIX: &str = "/api/";
+
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ApiRequest {
+    Changeset(NodeHash),
+    File(NodeHash, MPath),
+    Tree(NodeHash, MPath),
+    Bookmarks,
+}
+
+impl ApiRequest {
+    /// Parse the path of a URL after `/<repo>/api/`, whose elements are percent encoded.
+    pub fn parse(endpoint: &str) -> Result<Self> {
+        let invalid = || ErrorKind::InvalidApiRequest(endpoint.to_string());
+        let mut elements = endpoint.split('/');
+        let name = elements.next().unwrap_or("");
+        let request = match name {
+            "changeset" => ApiRequest::Changeset(parse_hash(elements.next()).ok_or_else(invalid)?),
+            "file" | "tree" => {
+                let hash = parse_hash(elements.next()).ok_or_else(invalid)?;
+                let path: Vec<u8> = elements
+                    .by_ref()
+                    .flat_map(|element| percent_decode(element.as_bytes()).chain(iter::once(b'/')))
+                    .collect();
+                let path = MPath::new(path).map_err(|_| invalid())?;
+                if name == "tree" {
+                    ApiRequest::Tree(hash, path)
+                } else if path.is_empty() {
+                    bail_err!(invalid());
+                } else {
+                    ApiRequest::File(hash, path)
+                }
+            }
+            "bookmarks" => ApiRequest::Bookmarks,
+            _ => bail_err!(invalid()),
+        };
+        // Only files and trees have paths, the other requests can only end with a slash
+        if elements.any(|element| !element.is_empty()) {
+            bail_err!(invalid());
+        }
+        Ok(request)
+    }
+}
+
+fn parse_hash(hash: Option<&str>) -> Option<NodeHash> {
+    hash.and_then(|hash| NodeHash::from_str(hash).ok())
+}
+
+/// Split the path of a URL into the path of the repo and the endpoint of the API, if it's an API
+/// request.
+pub fn split_path(path: &str) -> Option<(&str, &str)> {
+    path.find(API_PREFIX)
+        .map(|idx| (&path[..idx], &path[idx + API_PREFIX.len()..]))
+}
+
+/// Answer an API request with the JSON document it asks for.
+pub fn handle(repo: Arc<BlobRepo>, request: ApiRequest) -> BoxFuture<Bytes, Error> {
+    match request {
+        ApiRequest::Changeset(node) => get_changeset(repo, node)
+            .and_then(|info| to_json(&info))
+            .boxify(),
+        ApiRequest::File(node, path) => get_file_content(repo, node, path)
+            .and_then(|info| to_json(&info))
+            .boxify(),
+        ApiRequest::Tree(node, path) => list_directory(repo, node, path)
+            .and_then(|info| to_json(&info))
+            .boxify(),
+        ApiRequest::Bookmarks => get_bookmarks(repo)
+            .and_then(|bookmarks| to_json(&Bookmarks { bookmarks }))
+            .boxify(),
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct ChangesetInfo {
+    pub node: NodeHash,
+    pub parents: Vec<NodeHash>,
+    pub manifest: NodeHash,
+    pub user: String,
+    /// The time in seconds since the epoch and the offset of the timezone, as in hgweb
+    pub date: (u64, i32),
+    pub desc: String,
+    pub branch: String,
+    pub files: Vec<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct FileInfo {
+    pub node: NodeHash,
+    pub path: String,
+    #[serde(rename = "type")]
+    pub ty: Type,
+    pub size: usize,
+    /// The content of the file, or the target of a symlink. It's only serialized as text, so
+    /// it's `null` for binary files.
+    #[serde(serialize_with = "serialize_text")]
+    pub content: Bytes,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeInfo {
+    pub node: NodeHash,
+    pub path: String,
+    pub entries: Vec<TreeEntry>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct TreeEntry {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub ty: Type,
+    pub node: NodeHash,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct BookmarkInfo {
+    pub bookmark: String,
+    pub node: ChangesetId,
+}
+
+#[derive(Serialize)]
+struct Bookmarks {
+    bookmarks: Vec<BookmarkInfo>,
+}
+
+/// The metadata of the changeset `node`.
+pub fn get_changeset(repo: Arc<BlobRepo>, node: NodeHash) -> BoxFuture<ChangesetInfo, Error> {
+    load_changeset(&repo, node)
+        .map(move |cs| {
+            let (time, tz) = (cs.time().time, cs.time().tz);
+            ChangesetInfo {
+                node,
+                parents: cs.parents().into_iter().collect(),
+                manifest: cs.manifestid().into_nodehash(),
+                user: String::from_utf8_lossy(cs.user()).into_owned(),
+                date: (time, tz),
+                desc: String::from_utf8_lossy(cs.comments()).into_owned(),
+                branch: String::from_utf8_lossy(cs.branch()).into_owned(),
+                files: cs.files().iter().map(|path| path.to_string()).collect(),
+            }
+        })
+        .boxify()
+}
+
+/// The file `path` in the changeset `node`, with its content.
+pub fn get_file_content(
+    repo: Arc<BlobRepo>,
+    node: NodeHash,
+    path: MPath,
+) -> BoxFuture<FileInfo, Error> {
+    let not_found = ErrorKind::NotFound(format!("file {} in {}", path, node));
+    load_changeset(&repo, node)
+        .and_then(move |cs| {
+            let root = repo.get_root_entry(cs.manifestid());
+            find_entry(root, path.clone()).map(move |entry| (entry, path))
+        })
+        .and_then(move |(entry, path)| {
+            let entry = match entry {
+                Some(entry) => entry,
+                None => return future::err(not_found.into()).boxify(),
+            };
+            let (node, ty) = (entry.get_hash().into_nodehash(), entry.get_type());
+            entry
+                .get_content()
+                .and_then(move |content| {
+                    let data = match content {
+                        Content::File(blob) | Content::Executable(blob) => blob.into_inner(),
+                        Content::Symlink(target) => Some(Bytes::from(target.to_vec())),
+                        Content::Tree(_) => bail_err!(not_found),
+                    };
+                    let content = data.unwrap_or_default();
+                    Ok(FileInfo {
+                        node,
+                        path: path.to_string(),
+                        ty,
+                        size: content.len(),
+                        content,
+                    })
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// The entries of the directory `path` in the changeset `node`, which is the root directory if
+/// `path` is empty.
+pub fn list_directory(
+    repo: Arc<BlobRepo>,
+    node: NodeHash,
+    path: MPath,
+) -> BoxFuture<TreeInfo, Error> {
+    let not_found = ErrorKind::NotFound(format!("directory {} in {}", path, node));
+    load_changeset(&repo, node)
+        .and_then(move |cs| {
+            let root = repo.get_root_entry(cs.manifestid());
+            find_entry(root, path.clone()).map(move |entry| (entry, path))
+        })
+        .and_then(move |(entry, path)| {
+            let entry = match entry {
+                Some(ref entry) if entry.get_type() == Type::Tree => entry,
+                _ => return future::err(not_found.into()).boxify(),
+            };
+            let tree_node = entry.get_hash().into_nodehash();
+            entry
+                .get_content()
+                .and_then(move |content| match content {
+                    Content::Tree(manifest) => Ok(manifest.list()),
+                    _ => Err(not_found.into()),
+                })
+                .flatten_stream()
+                .map(|entry| TreeEntry {
+                    name: entry.get_name().as_ref().map_or(String::new(), |name| {
+                        String::from_utf8_lossy(name.as_bytes()).into_owned()
+                    }),
+                    ty: entry.get_type(),
+                    node: entry.get_hash().into_nodehash(),
+                })
+                .collect()
+                .map(move |entries| TreeInfo {
+                    node: tree_node,
+                    path: path.to_string(),
+                    entries,
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// The bookmarks of the repo, sorted by name.
+pub fn get_bookmarks(repo: Arc<BlobRepo>) -> BoxFuture<Vec<BookmarkInfo>, Error> {
+    repo.get_bookmark_keys()
+        .and_then(move |name| {
+            repo.get_bookmark_value(&name)
+                .map(move |value| value.map(|(node, _)| (name, node)))
+        })
+        // Skip bookmarks deleted while listing them
+        .filter_map(|bookmark| bookmark)
+        .map(|(name, node)| BookmarkInfo {
+            bookmark: String::from_utf8_lossy(&name).into_owned(),
+            node,
+        })
+        .collect()
+        .map(|mut bookmarks: Vec<BookmarkInfo>| {
+            bookmarks.sort_by(|a, b| a.bookmark.cmp(&b.bookmark));
+            bookmarks
+        })
+        .boxify()
+}
+
+/// The changeset `node`, failing with `ErrorKind::NotFound` if it isn't in the repo.
+fn load_changeset(repo: &Arc<BlobRepo>, node: NodeHash) -> BoxFuture<BlobChangeset, Error> {
+    let csid = ChangesetId::new(node);
+    let repo = repo.clone();
+    repo.changeset_exists(&csid)
+        .and_then(move |exists| {
+            if !exists {
+                bail_err!(ErrorKind::NotFound(format!("changeset {}", node)));
+            }
+            Ok(repo.get_changeset_by_changesetid(&csid))
+        })
+        .flatten()
+        .boxify()
+}
+
+/// Find the entry of `path` under `root`, looking it up one directory at a time.
+fn find_entry(
+    root: Box<Entry + Sync>,
+    path: MPath,
+) -> BoxFuture<Option<Box<Entry + Sync>>, Error> {
+    stream::iter_ok(path)
+        .fold(Some(root), |entry, element| match entry {
+            Some(entry) => entry
+                .get_content()
+                .and_then(move |content| match content {
+                    Content::Tree(manifest) => {
+                        manifest.lookup(&MPath::empty().join(iter::once(&element)))
+                    }
+                    _ => future::ok(None).boxify(),
+                })
+                .boxify(),
+            None => future::ok(None).boxify(),
+        })
+        .boxify()
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<Bytes> {
+    Ok(Bytes::from(serde_json::to_vec(value)?))
+}
+
+fn serialize_text<S>(content: &Bytes, serializer: S) -> ::std::result::Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    match str::from_utf8(content) {
+        Ok(text) => serializer.serialize_some(text),
+        Err(_) => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const HASH: &str = "1111111111111111111111111111111111111111";
+
+    fn node() -> NodeHash {
+        NodeHash::from_str(HASH).unwrap()
+    }
+
+    #[test]
+    fn test_split_path() {
+        assert_eq!(split_path("/repo/api/bookmarks"), Some(("/repo", "bookmarks")));
+        assert_eq!(split_path("/a/b/api/tree/"), Some(("/a/b", "tree/")));
+        assert_eq!(split_path("/repo"), None);
+        assert_eq!(split_path("/repo/apis/bookmarks"), None);
+    }
+
+    #[test]
+    fn test_parse() {
+        let parse = |endpoint: &str| ApiRequest::parse(endpoint).ok();
+        let path = |path: &str| MPath::new(path).unwrap();
+
+        assert_eq!(
+            parse(&format!("changeset/{}", HASH)),
+            Some(ApiRequest::Changeset(node()))
+        );
+        assert_eq!(
+            parse(&format!("changeset/{}/", HASH)),
+            Some(ApiRequest::Changeset(node()))
+        );
+        assert_eq!(
+            parse(&format!("file/{}/dir/some%20file", HASH)),
+            Some(ApiRequest::File(node(), path("dir/some file")))
+        );
+        assert_eq!(
+            parse(&format!("tree/{}/dir/", HASH)),
+            Some(ApiRequest::Tree(node(), path("dir")))
+        );
+        assert_eq!(
+            parse(&format!("tree/{}", HASH)),
+            Some(ApiRequest::Tree(node(), MPath::empty()))
+        );
+        assert_eq!(parse("bookmarks"), Some(ApiRequest::Bookmarks));
+
+        assert_eq!(parse(""), None);
+        assert_eq!(parse("changeset"), None);
+        assert_eq!(parse("changeset/nothex"), None);
+        assert_eq!(parse(&format!("changeset/{}/more", HASH)), None);
+        assert_eq!(parse(&format!("file/{}", HASH)), None);
+        assert_eq!(parse(&format!("file/{}/a%00b", HASH)), None);
+        assert_eq!(parse("bookmarks/more"), None);
+        assert_eq!(parse("frobnicate"), None);
+    }
+}
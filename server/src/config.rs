@@ -0,0 +1,444 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Configuration of the server, read from the TOML file given with `--config`. Every setting
+//! can also be given on the command line, which takes precedence over the file.
+//!
+//! The file is read again on SIGHUP, or when it changes if `config_poll_secs` is set, and the
+//! log level, throttle, ACL and protected bookmarks are updated without a restart, see the
+//! reload module. The other settings only take effect on restart.
+//!
+//! ```toml
+//! [server]
+//! listen_addr = "[::]:8000"
+//! http_addr = "[::]:8080"
+//! thrift_port = 9000
+//! readonly = false
+//! disable_bundle_compression = false
+//! disabled_bundle2_caps = ["obsmarkers"]
+//! tls_identity_header = "X-Client-Cert-Subject"
+//! request_log_path = "/var/log/mononoke/requests.json"
+//! heads_cache_ttl_secs = 60
+//! log_level = "info"
+//! config_poll_secs = 10
+//!
+//! # Bookmarks which can only be moved forward, and can't be deleted, in all repos
+//! protected_bookmarks = ["master"]
+//!
+//! # Limits of each command across all repos, past which clients are told the server is busy,
+//! # and how long each of them can run, or go without sending anything for streamed responses
+//! [server.throttle.getbundle]
+//! max_concurrent = 20
+//! max_qps = 50
+//! timeout_secs = 1800
+//!
+//! # Who can do what, "allow_all" by default. The "static" checker only lets the users listed
+//! # for a repo path, or for all repos under "*", push to it
+//! [server.acl]
+//! checker = "static"
+//! [server.acl.writers]
+//! "/data/www" = ["alice", "bob"]
+//! "*" = ["admin"]
+//!
+//! # Repos configured in a config repo, as with --configrepo_path
+//! [configrepo]
+//! path = "/data/configrepo"
+//! bookmark = "master"
+//!
+//! # Repos configured in this file, with the same keys as in a config repo
+//! [repos.www]
+//! path = "/data/www"
+//! repotype = "blob:rocks"
+//! repoid = 1
+//! generation_cache_size = 10485760
+//! blob_cache_size = 104857600
+//! max_delta_chain = 20
+//! oplog_path = "/var/log/mononoke/www.oplog"
+//! # Heads and bookmarks shared by all the servers of the repo
+//! db_address = "xdb.www"
+//!
+//! # Checks run against the changesets pushed to the repo
+//! [repos.www.hooks]
+//! commit_message_regex = "^\\[\\w+\\] "
+//! max_file_size = 10485760
+//! banned_paths = ["secrets"]
+//! ```
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use slog::Level;
+use toml::{self, Value};
+use toml::value::Table;
+
+use metaconfig::repoconfig::{RepoConfig, HOOKS_CONFIG_KEYS, REPO_CONFIG_KEYS};
+
+use errors::*;
+
+const TOP_LEVEL_KEYS: &[&str] = &["server", "configrepo", "repos"];
+const SERVER_KEYS: &[&str] = &[
+    "listen_addr",
+    "http_addr",
+    "thrift_port",
+    "readonly",
+    "disable_bundle_compression",
+    "disabled_bundle2_caps",
+    "acl",
+    "tls_identity_header",
+    "throttle",
+    "request_log_path",
+    "heads_cache_ttl_secs",
+    "log_level",
+    "protected_bookmarks",
+    "config_poll_secs",
+];
+const ACL_KEYS: &[&str] = &["checker", "writers", "service"];
+const COMMAND_LIMITS_KEYS: &[&str] = &["max_concurrent", "max_qps", "timeout_secs"];
+const CONFIGREPO_KEYS: &[&str] = &["path", "bookmark", "hash"];
+
+/// Settings shared by all the repos served.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// Serve all the repos over TCP on this address
+    pub listen_addr: Option<SocketAddr>,
+    /// Serve all the repos over HTTP on this address
+    pub http_addr: Option<SocketAddr>,
+    /// Start the thrift server on this port
+    pub thrift_port: Option<u16>,
+    /// Refuse pushes and every other command which writes to the repos, and reject all
+    /// blobstore writes
+    pub readonly: bool,
+    /// Never compress getbundle responses, for debugging
+    pub disable_bundle_compression: bool,
+    /// bundle2 capabilities which aren't advertised to clients, e.g. "obsmarkers"
+    pub disabled_bundle2_caps: Vec<String>,
+    pub acl: AclConfig,
+    /// Header in which the proxy terminating TLS in front of the HTTP listener passes the subject
+    /// of the client certificate it verified. Clients must not be able to reach the listener
+    /// directly, or they could claim to be anyone.
+    pub tls_identity_header: Option<String>,
+    /// Limits of the commands, by name, see the throttle module
+    pub throttle: HashMap<String, CommandLimits>,
+    /// File to append a JSON record of every command to, see the requestlog module
+    pub request_log_path: Option<PathBuf>,
+    /// How long the heads of a repo are cached for, in case other servers push to it, see the
+    /// headscache module. 60 seconds by default, and 0 to disable the cache.
+    pub heads_cache_ttl_secs: Option<u64>,
+    /// Least severe messages logged, "info" by default. `--debug` overrides it.
+    pub log_level: Option<LogLevel>,
+    /// Bookmarks of all the repos which can't be deleted, nor moved other than forward
+    pub protected_bookmarks: Vec<String>,
+    /// How often to check whether the config file changed, to reload it. It's only reloaded on
+    /// SIGHUP if unset.
+    pub config_poll_secs: Option<u64>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum LogLevel {
+    #[serde(rename = "critical")] Critical,
+    #[serde(rename = "error")] Error,
+    #[serde(rename = "warning")] Warning,
+    #[serde(rename = "info")] Info,
+    #[serde(rename = "debug")] Debug,
+    #[serde(rename = "trace")] Trace,
+}
+
+impl From<LogLevel> for Level {
+    fn from(level: LogLevel) -> Self {
+        match level {
+            LogLevel::Critical => Level::Critical,
+            LogLevel::Error => Level::Error,
+            LogLevel::Warning => Level::Warning,
+            LogLevel::Info => Level::Info,
+            LogLevel::Debug => Level::Debug,
+            LogLevel::Trace => Level::Trace,
+        }
+    }
+}
+
+/// Which `AclChecker` authorizes the commands of the clients, see the acl module.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default)]
+pub struct AclConfig {
+    pub checker: AclCheckerType,
+    /// For the static checker: the users which can push, by repo path
+    pub writers: HashMap<String, Vec<String>>,
+    /// For the service checker: the ACL service to ask
+    pub service: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq)]
+pub enum AclCheckerType {
+    #[serde(rename = "allow_all")] AllowAll,
+    #[serde(rename = "static")] Static,
+    #[serde(rename = "service")] Service,
+}
+
+impl Default for AclCheckerType {
+    fn default() -> Self {
+        AclCheckerType::AllowAll
+    }
+}
+
+/// How much of a command the server takes on, unlimited by default.
+#[derive(Clone, Debug, Default, Deserialize, Eq, PartialEq)]
+#[serde(default)]
+pub struct CommandLimits {
+    /// How many can run at once
+    pub max_concurrent: Option<usize>,
+    /// How many can be started each second
+    pub max_qps: Option<u32>,
+    /// How long one can run before it's cancelled. Commands which stream their response are
+    /// only cancelled once they've gone that long without sending anything
+    pub timeout_secs: Option<u64>,
+}
+
+/// Config repo to read more repo configs from, at either a bookmark or a commit.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct ConfigRepo {
+    pub path: PathBuf,
+    pub bookmark: Option<String>,
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub configrepo: Option<ConfigRepo>,
+    pub repos: HashMap<String, RepoConfig>,
+}
+
+#[derive(Deserialize)]
+struct RawConfig {
+    #[serde(default)]
+    server: ServerConfig,
+    configrepo: Option<ConfigRepo>,
+    #[serde(default)]
+    repos: HashMap<String, Value>,
+}
+
+impl Config {
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut content = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut content))
+            .with_context(|_| format!("failed to read config file {}", path.display()))?;
+        let config = Self::parse(&content)
+            .with_context(|_| format!("invalid config file {}", path.display()))?;
+        Ok(config)
+    }
+
+    pub fn parse(content: &str) -> Result<Self> {
+        let value: Value = toml::from_str(content)?;
+
+        let unknown = unknown_keys(&value);
+        if !unknown.is_empty() {
+            bail_err!(ErrorKind::InvalidConfig(format!(
+                "unknown keys: {}",
+                unknown.join(", ")
+            )));
+        }
+
+        let raw: RawConfig = value.try_into()?;
+        if let Some(ref configrepo) = raw.configrepo {
+            if configrepo.bookmark.is_some() == configrepo.hash.is_some() {
+                bail_err!(ErrorKind::InvalidConfig(
+                    "exactly one of configrepo.bookmark and configrepo.hash must be set".into()
+                ));
+            }
+        }
+
+        let mut repos = HashMap::new();
+        for (name, repo) in raw.repos {
+            let repo = RepoConfig::from_toml(repo)
+                .with_context(|_| format!("invalid config for repo {}", name))?;
+            repos.insert(name, repo);
+        }
+
+        Ok(Config {
+            server: raw.server,
+            configrepo: raw.configrepo,
+            repos,
+        })
+    }
+}
+
+// All the keys of the config which aren't known settings, with their full path, so that typos
+// are reported instead of silently ignored.
+fn unknown_keys(value: &Value) -> Vec<String> {
+    let mut unknown = Vec::new();
+    let table = match value.as_table() {
+        Some(table) => table,
+        None => return unknown,
+    };
+
+    check_keys(table, "", TOP_LEVEL_KEYS, &mut unknown);
+    if let Some(server) = table.get("server").and_then(Value::as_table) {
+        check_keys(server, "server.", SERVER_KEYS, &mut unknown);
+        if let Some(acl) = server.get("acl").and_then(Value::as_table) {
+            check_keys(acl, "server.acl.", ACL_KEYS, &mut unknown);
+        }
+        if let Some(throttle) = server.get("throttle").and_then(Value::as_table) {
+            for (command, limits) in throttle {
+                if let Some(limits) = limits.as_table() {
+                    let prefix = format!("server.throttle.{}.", command);
+                    check_keys(limits, &prefix, COMMAND_LIMITS_KEYS, &mut unknown);
+                }
+            }
+        }
+    }
+    if let Some(configrepo) = table.get("configrepo").and_then(Value::as_table) {
+        check_keys(configrepo, "configrepo.", CONFIGREPO_KEYS, &mut unknown);
+    }
+    if let Some(repos) = table.get("repos").and_then(Value::as_table) {
+        for (name, repo) in repos {
+            if let Some(repo) = repo.as_table() {
+                let prefix = format!("repos.{}.", name);
+                check_keys(repo, &prefix, REPO_CONFIG_KEYS, &mut unknown);
+                if let Some(hooks) = repo.get("hooks").and_then(Value::as_table) {
+                    let prefix = format!("{}hooks.", prefix);
+                    check_keys(hooks, &prefix, HOOKS_CONFIG_KEYS, &mut unknown);
+                }
+            }
+        }
+    }
+
+    unknown.sort();
+    unknown
+}
+
+fn check_keys(table: &Table, prefix: &str, known: &[&str], unknown: &mut Vec<String>) {
+    for key in table.keys() {
+        if !known.contains(&key.as_str()) {
+            unknown.push(format!("{}{}", prefix, key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use metaconfig::repoconfig::RepoType;
+
+    #[test]
+    fn parse() {
+        let content = r#"
+            [server]
+            listen_addr = "127.0.0.1:8000"
+            readonly = true
+            disabled_bundle2_caps = ["obsmarkers"]
+            log_level = "warning"
+            protected_bookmarks = ["master"]
+
+            [server.acl]
+            checker = "static"
+            [server.acl.writers]
+            "/tmp/www" = ["alice"]
+
+            [server.throttle.getbundle]
+            max_concurrent = 10
+
+            [configrepo]
+            path = "/tmp/configrepo"
+            bookmark = "master"
+
+            [repos.www]
+            path = "/tmp/www"
+            repotype = "revlog"
+            repoid = 1
+        "#;
+        let config = Config::parse(content).expect("failed to parse config");
+
+        assert_eq!(
+            config.server,
+            ServerConfig {
+                listen_addr: Some("127.0.0.1:8000".parse().unwrap()),
+                readonly: true,
+                disabled_bundle2_caps: vec!["obsmarkers".to_string()],
+                log_level: Some(LogLevel::Warning),
+                protected_bookmarks: vec!["master".to_string()],
+                acl: AclConfig {
+                    checker: AclCheckerType::Static,
+                    writers: hashmap! { "/tmp/www".to_string() => vec!["alice".to_string()] },
+                    service: None,
+                },
+                throttle: hashmap! {
+                    "getbundle".to_string() => CommandLimits {
+                        max_concurrent: Some(10),
+                        ..CommandLimits::default()
+                    },
+                },
+                ..ServerConfig::default()
+            }
+        );
+        assert_eq!(
+            config.configrepo,
+            Some(ConfigRepo {
+                path: "/tmp/configrepo".into(),
+                bookmark: Some("master".to_string()),
+                hash: None,
+            })
+        );
+        assert_eq!(config.repos.len(), 1);
+        assert_eq!(
+            config.repos["www"].repotype,
+            RepoType::Revlog("/tmp/www".into())
+        );
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(Config::parse("").unwrap(), Config::default());
+    }
+
+    #[test]
+    fn unknown_keys_listed() {
+        let content = r#"
+            verbose = true
+
+            [server]
+            listne_addr = "127.0.0.1:8000"
+
+            [repos.www]
+            path = "/tmp/www"
+            repotype = "revlog"
+            repoid = 1
+            cache_size = 1024
+
+            [repos.www.hooks]
+            max_size = 1024
+        "#;
+        let err = Config::parse(content).unwrap_err();
+        assert_eq!(
+            format!("{}", err),
+            "invalid config: unknown keys: repos.www.cache_size, repos.www.hooks.max_size, \
+             server.listne_addr, verbose"
+        );
+    }
+
+    #[test]
+    fn configrepo_revision() {
+        let content = r#"
+            [configrepo]
+            path = "/tmp/configrepo"
+        "#;
+        assert!(Config::parse(content).is_err());
+
+        let content = r#"
+            [configrepo]
+            path = "/tmp/configrepo"
+            bookmark = "master"
+            hash = "1111111111111111111111111111111111111111"
+        "#;
+        assert!(Config::parse(content).is_err());
+    }
+}
@@ -6,7 +6,22 @@
 
 pub use failure::{Error, Result, ResultExt};
 
+use acl::Action;
+use identity::Identity;
+
 #[derive(Debug, Fail)]
 pub enum ErrorKind {
     #[fail(display = "failed to initialize server: {}", _0)] Initialization(&'static str),
+    #[fail(display = "invalid config: {}", _0)] InvalidConfig(String),
+    #[fail(display = "invalid API request: {}", _0)] InvalidApiRequest(String),
+    #[fail(display = "{} not found", _0)] NotFound(String),
+    #[fail(display = "{} is not allowed to {} {}", _0, _1, _2)]
+    PermissionDenied(Identity, Action, String),
+    #[fail(display = "server busy: too many {} requests, try again later", _0)]
+    ServerBusy(String),
+    #[fail(display = "client can't decode changegroup version {}", _0)]
+    UnsupportedChangegroup(String),
+    #[fail(display = "{} timed out after {} seconds", _0, _1)] Timeout(String, u64),
+    #[fail(display = "{} can't be run on the read-only repo {}", _0, _1)]
+    ReadOnly(String, String),
 }
@@ -0,0 +1,134 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! The heads of a repo, as last read from its store. Pushes through this server invalidate them,
+//! and they expire after `server.heads_cache_ttl_secs` to pick up the changes made to the repo
+//! by other servers.
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future, Future};
+use futures_ext::{BoxFuture, FutureExt};
+
+use mercurial_types::NodeHash;
+
+use errors::*;
+
+pub struct HeadsCache {
+    ttl: Duration,
+    state: Arc<Mutex<CacheState>>,
+}
+
+struct CacheState {
+    heads: Option<(Instant, HashSet<NodeHash>)>,
+    // Bumped by every invalidation, so that heads read before one aren't cached after it
+    generation: u64,
+}
+
+impl HeadsCache {
+    /// Caches heads for `ttl` at most, which is never with a zero `ttl`.
+    pub fn new(ttl: Duration) -> Self {
+        HeadsCache {
+            ttl,
+            state: Arc::new(Mutex::new(CacheState {
+                heads: None,
+                generation: 0,
+            })),
+        }
+    }
+
+    /// The cached heads if they haven't expired, or the heads read with `fetch` otherwise.
+    pub fn get<F>(&self, fetch: F) -> BoxFuture<HashSet<NodeHash>, Error>
+    where
+        F: FnOnce() -> BoxFuture<HashSet<NodeHash>, Error>,
+    {
+        let now = Instant::now();
+        let generation = {
+            let state = self.state.lock().expect("lock poisoned");
+            if let Some((read_at, ref heads)) = state.heads {
+                if now < read_at + self.ttl {
+                    return future::ok(heads.clone()).boxify();
+                }
+            }
+            state.generation
+        };
+
+        let state = self.state.clone();
+        fetch()
+            .map(move |heads| {
+                let mut state = state.lock().expect("lock poisoned");
+                if state.generation == generation {
+                    state.heads = Some((now, heads.clone()));
+                }
+                heads
+            })
+            .boxify()
+    }
+
+    /// Drops the cached heads, after the repo was changed.
+    pub fn invalidate(&self) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.heads = None;
+        state.generation += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+    use mercurial_types_mocks::nodehash::{ONES_HASH, TWOS_HASH};
+
+    fn fetch(fetches: &AtomicUsize, head: NodeHash) -> BoxFuture<HashSet<NodeHash>, Error> {
+        fetches.fetch_add(1, Ordering::SeqCst);
+        future::ok(hashset!{head}).boxify()
+    }
+
+    #[test]
+    fn cached_until_invalidated() {
+        static FETCHES: AtomicUsize = ATOMIC_USIZE_INIT;
+        let cache = HeadsCache::new(Duration::from_secs(3600));
+
+        let heads = cache.get(|| fetch(&FETCHES, ONES_HASH)).wait().unwrap();
+        assert_eq!(heads, hashset!{ONES_HASH});
+        let heads = cache.get(|| fetch(&FETCHES, TWOS_HASH)).wait().unwrap();
+        assert_eq!(heads, hashset!{ONES_HASH});
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 1);
+
+        cache.invalidate();
+        let heads = cache.get(|| fetch(&FETCHES, TWOS_HASH)).wait().unwrap();
+        assert_eq!(heads, hashset!{TWOS_HASH});
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn no_ttl() {
+        static FETCHES: AtomicUsize = ATOMIC_USIZE_INIT;
+        let cache = HeadsCache::new(Duration::from_secs(0));
+
+        cache.get(|| fetch(&FETCHES, ONES_HASH)).wait().unwrap();
+        let heads = cache.get(|| fetch(&FETCHES, TWOS_HASH)).wait().unwrap();
+        assert_eq!(heads, hashset!{TWOS_HASH});
+        assert_eq!(FETCHES.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn invalidated_while_fetching() {
+        static FETCHES: AtomicUsize = ATOMIC_USIZE_INIT;
+        let cache = HeadsCache::new(Duration::from_secs(3600));
+
+        let stale = cache.get(|| fetch(&FETCHES, ONES_HASH));
+        cache.invalidate();
+        assert_eq!(stale.wait().unwrap(), hashset!{ONES_HASH});
+
+        let heads = cache.get(|| fetch(&FETCHES, TWOS_HASH)).wait().unwrap();
+        assert_eq!(heads, hashset!{TWOS_HASH});
+    }
+}
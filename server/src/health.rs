@@ -0,0 +1,85 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Health checks for load balancers, served on the HTTP address. `/healthz` succeeds as long as
+//! the server runs. `/readyz` only succeeds while the stores of every repo can be read: its
+//! blobstore, heads and bookmarks. Otherwise it fails with 503, listing the failed checks, so
+//! that requests stop being routed to a server whose blobstore credentials expired, say.
+
+use std::time::Duration;
+
+use futures::{future, Future, Stream};
+use futures_ext::{BoxFuture, FutureExt};
+use tokio_core::reactor::{Handle, Timeout};
+
+use errors::*;
+use registry::RepoRegistry;
+
+pub const HEALTHZ_PATH: &str = "/healthz";
+pub const READYZ_PATH: &str = "/readyz";
+
+/// How long a check can take before it fails, as a store which hangs isn't ready either
+const CHECK_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct CheckResult {
+    pub repo: String,
+    pub check: &'static str,
+    /// Why the check failed, if it did
+    pub error: Option<String>,
+}
+
+/// Run the checks of all the repos at once. The checks which fail are reported in the results,
+/// the future itself never fails.
+pub fn check_repos(
+    registry: &RepoRegistry,
+    handle: &Handle,
+) -> BoxFuture<Vec<CheckResult>, Error> {
+    let mut results = Vec::new();
+    for (name, repo) in registry.iter() {
+        let repo = repo.blobrepo();
+        // Reading the first head or bookmark is enough to know that the store can be read
+        let heads = repo.get_heads().take(1).for_each(|_| Ok(())).boxify();
+        let bookmarks = repo.get_bookmark_keys().take(1).for_each(|_| Ok(())).boxify();
+        let checks = vec![
+            ("blobstore", repo.check_blobstore()),
+            ("heads", heads),
+            ("bookmarks", bookmarks),
+        ];
+        for (check, fut) in checks {
+            results.push(run_check(name.clone(), check, fut, handle));
+        }
+    }
+    future::join_all(results).boxify()
+}
+
+fn run_check(
+    repo: String,
+    check: &'static str,
+    fut: BoxFuture<(), Error>,
+    handle: &Handle,
+) -> BoxFuture<CheckResult, Error> {
+    let timeout = Duration::from_secs(CHECK_TIMEOUT_SECS);
+    let deadline = match Timeout::new(timeout, handle) {
+        Ok(deadline) => deadline
+            .from_err()
+            .and_then(move |()| {
+                let what = format!("{} check", check);
+                Err(ErrorKind::Timeout(what, CHECK_TIMEOUT_SECS).into())
+            })
+            .boxify(),
+        Err(err) => future::err(err.into()).boxify(),
+    };
+    fut.select(deadline)
+        .then(move |res| {
+            Ok::<_, Error>(CheckResult {
+                repo,
+                check,
+                error: res.err().map(|(err, _)| err.to_string()),
+            })
+        })
+        .boxify()
+}
@@ -0,0 +1,278 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Mercurial's HTTP wire protocol, served with the same `RepoClient` as the SSH one. See
+//! `hgproto::httpproto` for the protocol itself. The repo is the path of the URL, like
+//! `http://server/<repo>?cmd=capabilities`. The JSON API of the `api` module is served too, under
+//! `http://server/<repo>/api/`, and so are the health checks of the `health` module.
+
+use std::io;
+use std::str;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{stream, Future, Sink, Stream};
+use futures::future::ok;
+use futures::sync::mpsc;
+use futures_ext::{BoxFuture, FutureExt, StreamExt};
+use hyper::{self, Body, Chunk, StatusCode};
+use hyper::server::{Request, Response, Service};
+use serde_json;
+use slog::Logger;
+use tokio_core::reactor::Handle;
+
+use failure::SlogKVError;
+use hgproto::{sshproto, HgProtoHandler};
+use hgproto::httpproto::{self, request};
+
+use api::{self, ApiRequest};
+use errors::*;
+use health::{self, CheckResult};
+use identity::{Identity, SessionContext};
+use registry::RepoRegistry;
+use repo::RepoClient;
+
+/// Serves the requests of a single HTTP connection.
+pub struct HttpService {
+    registry: Arc<RepoRegistry>,
+    logger: Logger,
+    handle: Handle,
+    identity_header: Option<String>,
+    // Held for as long as the connection is open or a response is being sent, so that shutdown
+    // can wait for them.
+    inflight: mpsc::Sender<()>,
+}
+
+impl HttpService {
+    pub fn new(
+        registry: Arc<RepoRegistry>,
+        logger: &Logger,
+        handle: &Handle,
+        identity_header: Option<String>,
+        inflight: &mpsc::Sender<()>,
+    ) -> Self {
+        HttpService {
+            registry,
+            logger: logger.clone(),
+            handle: handle.clone(),
+            identity_header,
+            inflight: inflight.clone(),
+        }
+    }
+
+    // Clients are only identified by their TLS certificate, whose subject the proxy terminating
+    // TLS passes in a header.
+    fn identity(&self, req: &Request) -> Identity {
+        let subject = self.identity_header.as_ref().and_then(|header| {
+            req.headers()
+                .get_raw(header)
+                .and_then(|raw| raw.one())
+                .and_then(|subject| str::from_utf8(subject).ok())
+        });
+        match subject {
+            Some(subject) => Identity::from_cert_subject(subject),
+            None => Identity::Anonymous,
+        }
+    }
+
+    // Succeeds if all the checks pass, with their results either way
+    fn call_readyz(&self) -> BoxFuture<Response, hyper::Error> {
+        let inflight = self.inflight.clone();
+        let logger = self.logger.clone();
+        health::check_repos(&self.registry, &self.handle)
+            .then(move |res| {
+                drop(inflight);
+                let resp = match res.and_then(|results| readyz_response(&results)) {
+                    Ok(resp) => resp,
+                    Err(err) => {
+                        error!(logger, "Health checks failed"; SlogKVError(err));
+                        Response::new().with_status(StatusCode::InternalServerError)
+                    }
+                };
+                Ok(resp)
+            })
+            .boxify()
+    }
+
+    // The whole JSON document is built before it's sent, so that errors get their own status.
+    fn call_api(
+        &self,
+        client: RepoClient,
+        endpoint: &str,
+        logger: Logger,
+    ) -> BoxFuture<Response, hyper::Error> {
+        let request = match ApiRequest::parse(endpoint) {
+            Ok(request) => request,
+            Err(err) => return ok(error_response(&err)).boxify(),
+        };
+        let inflight = self.inflight.clone();
+        client
+            .api(request)
+            .then(move |res| {
+                drop(inflight);
+                let resp = match res {
+                    Ok(json) => {
+                        let mut resp = Response::new().with_body(json.to_vec());
+                        resp.headers_mut().set_raw("Content-Type", "application/json");
+                        resp
+                    }
+                    Err(err) => {
+                        let resp = error_response(&err);
+                        if resp.status() == StatusCode::InternalServerError {
+                            error!(logger, "API request failed"; SlogKVError(err));
+                        }
+                        resp
+                    }
+                };
+                Ok(resp)
+            })
+            .boxify()
+    }
+}
+
+fn readyz_response(results: &[CheckResult]) -> Result<Response> {
+    let status = if results.iter().all(|result| result.error.is_none()) {
+        StatusCode::Ok
+    } else {
+        StatusCode::ServiceUnavailable
+    };
+    let mut resp = Response::new()
+        .with_status(status)
+        .with_body(serde_json::to_vec(results)?);
+    resp.headers_mut().set_raw("Content-Type", "application/json");
+    Ok(resp)
+}
+
+/// The response to a failed API request, whose status depends on the error.
+fn error_response(err: &Error) -> Response {
+    let status = match err.downcast_ref::<ErrorKind>() {
+        Some(&ErrorKind::InvalidApiRequest(_)) => StatusCode::BadRequest,
+        Some(&ErrorKind::NotFound(_)) => StatusCode::NotFound,
+        Some(&ErrorKind::PermissionDenied(..)) => StatusCode::Forbidden,
+        Some(&ErrorKind::ServerBusy(_)) => StatusCode::ServiceUnavailable,
+        _ => StatusCode::InternalServerError,
+    };
+    Response::new().with_status(status).with_body(err.to_string())
+}
+
+/// Extract the command of a request, and its arguments from both the query string and the
+/// `X-HgArg-<N>` headers, encoded as the SSH protocol would send them.
+fn parse_request(req: &Request) -> Result<(String, Bytes)> {
+    let mut args = request::parse_args(req.query().unwrap_or("").as_bytes());
+
+    // A single argument can be split across several headers, so they're joined before decoding
+    let mut header_args = Vec::new();
+    for n in 1.. {
+        match req.headers()
+            .get_raw(&format!("X-HgArg-{}", n))
+            .and_then(|raw| raw.one())
+        {
+            Some(arg) => header_args.extend_from_slice(arg),
+            None => break,
+        }
+    }
+    args.extend(request::parse_args(&header_args));
+
+    let cmd = match args.remove(&b"cmd"[..]) {
+        Some(cmd) => String::from_utf8(cmd)?,
+        None => bail_msg!("no command in request"),
+    };
+    let input = request::encode_request(&cmd, args)?;
+    Ok((cmd, input))
+}
+
+impl Service for HttpService {
+    type Request = Request;
+    type Response = Response;
+    type Error = hyper::Error;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn call(&self, req: Request) -> Self::Future {
+        debug!(self.logger, "request: {} {}", req.method(), req.uri());
+
+        match req.path() {
+            health::HEALTHZ_PATH => return ok(Response::new().with_body("ok")).boxify(),
+            health::READYZ_PATH => return self.call_readyz(),
+            _ => {}
+        }
+
+        let (path, endpoint) = match api::split_path(req.path()) {
+            Some((path, endpoint)) => (path.to_string(), Some(endpoint.to_string())),
+            None => (req.path().to_string(), None),
+        };
+        let repo = match self.registry.route(&path) {
+            Some(repo) => repo.clone(),
+            None => {
+                let resp = Response::new()
+                    .with_status(StatusCode::NotFound)
+                    .with_body(format!("repository {} not found", path));
+                return ok(resp).boxify();
+            }
+        };
+        let session = SessionContext::new(self.identity(&req));
+        let logger = session.logger(&self.logger.new(o!("repo" => repo.path().clone())));
+
+        if let Some(endpoint) = endpoint {
+            let client = RepoClient::new(repo, &logger, session);
+            return self.call_api(client, &endpoint, logger);
+        }
+
+        let (cmd, input) = match parse_request(&req) {
+            Ok(parsed) => parsed,
+            Err(err) => {
+                let resp = Response::new()
+                    .with_status(StatusCode::BadRequest)
+                    .with_body(err.to_string());
+                error!(logger, "Bad request"; SlogKVError(err));
+                return ok(resp).boxify();
+            }
+        };
+
+        let input = stream::once(Ok(input));
+        let input = if cmd == "unbundle" {
+            let body = req.body()
+                .map(|chunk| Bytes::from(chunk.to_vec()))
+                .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+            input.chain(request::chunk_stream(body)).boxify()
+        } else {
+            input.boxify()
+        };
+
+        let output = HgProtoHandler::new(
+            input,
+            RepoClient::new(repo, &logger, session),
+            sshproto::HgSshCommandDecode,
+            httpproto::HgHttpCommandEncode,
+            &logger,
+        );
+
+        // Stream the response as it's produced. The status is already sent by the time an error
+        // happens, so the response is cut short instead for the client to notice.
+        let (sender, body) = Body::pair();
+        let inflight = self.inflight.clone();
+        let forward = output
+            .then(move |res| {
+                let res = res.map(|bytes| Chunk::from(bytes.to_vec()))
+                    .map_err(|err| {
+                        let msg = err.to_string();
+                        error!(logger, "Command failed"; SlogKVError(err));
+                        hyper::Error::Io(io::Error::new(io::ErrorKind::Other, msg))
+                    });
+                Ok::<_, ()>(res)
+            })
+            .forward(sender.sink_map_err(|_| ()))
+            .then(move |_| {
+                drop(inflight);
+                Ok(())
+            });
+        self.handle.spawn(forward);
+
+        let mut resp = Response::new().with_body(body);
+        resp.headers_mut()
+            .set_raw("Content-Type", "application/mercurial-0.1");
+        ok(resp).boxify()
+    }
+}
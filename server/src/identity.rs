@@ -0,0 +1,124 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Who the clients are, so that their load and failures can be attributed to them in logs and
+//! stats, and their commands authorized.
+
+use std::env;
+use std::fmt::{self, Display};
+use std::sync::atomic::{AtomicUsize, Ordering, ATOMIC_USIZE_INIT};
+
+use slog::Logger;
+
+static NEXT_SESSION_ID: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Who the client is, as far as the transport it connected over can tell. Clients connecting
+/// over TCP, or over HTTP without a TLS-terminating proxy vouching for them, are anonymous.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Identity {
+    Anonymous,
+    /// Name of the user, e.g. of the ssh user running the server over stdio
+    User(String),
+    /// Uid of the process connected to the unix socket
+    Uid(u32),
+}
+
+impl Identity {
+    /// The user who logged in over ssh, when the server is run by sshd for a single client.
+    pub fn from_ssh_env() -> Self {
+        if env::var_os("SSH_CONNECTION").is_none() {
+            return Identity::Anonymous;
+        }
+        match env::var("USER").or_else(|_| env::var("LOGNAME")) {
+            Ok(ref user) if !user.is_empty() => Identity::User(user.clone()),
+            _ => Identity::Anonymous,
+        }
+    }
+
+    /// The common name of a TLS client certificate, from its subject in either the
+    /// `CN=alice,O=Org` or the `/O=Org/CN=alice` form.
+    pub fn from_cert_subject(subject: &str) -> Self {
+        let common_name = subject
+            .split(|c| c == ',' || c == '/')
+            .filter_map(|field| {
+                let mut parts = field.trim().splitn(2, '=');
+                match (parts.next(), parts.next()) {
+                    (Some("CN"), Some(name)) if !name.is_empty() => Some(name),
+                    _ => None,
+                }
+            })
+            .next();
+        match common_name {
+            Some(name) => Identity::User(name.to_string()),
+            None => Identity::Anonymous,
+        }
+    }
+}
+
+impl Display for Identity {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Identity::Anonymous => write!(fmt, "anonymous client"),
+            Identity::User(ref name) => write!(fmt, "user {}", name),
+            Identity::Uid(uid) => write!(fmt, "uid {}", uid),
+        }
+    }
+}
+
+/// What's known about a client connection, for as long as it lasts.
+#[derive(Clone, Debug)]
+pub struct SessionContext {
+    id: usize,
+    identity: Identity,
+}
+
+impl SessionContext {
+    pub fn new(identity: Identity) -> Self {
+        SessionContext {
+            id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            identity,
+        }
+    }
+
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    pub fn identity(&self) -> &Identity {
+        &self.identity
+    }
+
+    /// A logger whose records all carry the session and the identity of the client.
+    pub fn logger(&self, parent_logger: &Logger) -> Logger {
+        parent_logger.new(o!(
+            "session" => self.id,
+            "identity" => format!("{}", self.identity),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cert_subject() {
+        let alice = Identity::User("alice".to_string());
+        assert_eq!(Identity::from_cert_subject("CN=alice,O=Org"), alice);
+        assert_eq!(Identity::from_cert_subject("O=Org, CN=alice"), alice);
+        assert_eq!(Identity::from_cert_subject("/O=Org/CN=alice"), alice);
+        assert_eq!(Identity::from_cert_subject("/O=Org"), Identity::Anonymous);
+        assert_eq!(Identity::from_cert_subject("CN="), Identity::Anonymous);
+        assert_eq!(Identity::from_cert_subject(""), Identity::Anonymous);
+    }
+
+    #[test]
+    fn session_ids() {
+        let first = SessionContext::new(Identity::Anonymous);
+        let second = SessionContext::new(Identity::Anonymous);
+        assert!(second.id() > first.id());
+    }
+}
@@ -5,21 +5,37 @@
 // GNU General Public License version 2 or any later version.
 
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
+use std::net::SocketAddr;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
+use std::thread;
 
-use futures::Stream;
+use futures::{Future, Sink, Stream};
 use futures::sync::mpsc;
-use futures_ext::{BoxStream, FutureExt, StreamExt};
+use futures_ext::{BoxFutureNonSend, BoxStream, FutureExt, StreamExt};
 
 use bytes::Bytes;
+use tokio_core::net::{TcpListener, TcpStream};
 use tokio_core::reactor::Handle;
 use tokio_io::{AsyncRead, AsyncWrite, IoStream};
 use tokio_io::codec::{FramedRead, FramedWrite};
 use tokio_uds::{UnixListener, UnixStream};
 
+use nix::sys::socket::{getsockopt, sockopt};
 use sshrelay::{SshDecoder, SshEncoder, SshMsg, SshStream};
 
+use identity::Identity;
+
+// The process on the other end of a unix socket is identified by its uid, which the kernel
+// vouches for.
+pub fn peer_identity(sock: &UnixStream) -> Identity {
+    match getsockopt(sock.as_raw_fd(), sockopt::PeerCredentials) {
+        Ok(creds) => Identity::Uid(creds.uid()),
+        Err(_) => Identity::Anonymous,
+    }
+}
+
 pub fn listener<P>(sockname: P, handle: &Handle) -> io::Result<IoStream<UnixStream>>
 where
     P: AsRef<Path>,
@@ -57,6 +73,12 @@ where
     Ok(listener.incoming().map(|(socket, _)| socket).boxify())
 }
 
+pub fn tcp_listener(addr: &SocketAddr, handle: &Handle) -> io::Result<IoStream<TcpStream>> {
+    let listener = TcpListener::bind(addr, handle)?;
+
+    Ok(listener.incoming().map(|(socket, _)| socket).boxify())
+}
+
 pub struct Stdio {
     pub stdin: BoxStream<Bytes, io::Error>,
     pub stdout: mpsc::Sender<Bytes>,
@@ -73,6 +95,51 @@ where
     let wr = FramedWrite::new(tx, SshEncoder::new());
     let rd = FramedRead::new(rx, SshDecoder::new());
 
+    mux(rd, wr, handle)
+}
+
+// As above, for a server hosting several repos: the client first sends the path of the repo it
+// wants in a preamble, which this resolves to along with the Io pair.
+pub fn ssh_server_mux_preamble<S>(
+    s: S,
+    handle: &Handle,
+) -> BoxFutureNonSend<(String, Stdio), io::Error>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let (rx, tx) = s.split();
+    let rd = FramedRead::new(rx, SshDecoder::new());
+    let handle = handle.clone();
+
+    rd.into_future()
+        .map_err(|(err, _)| err)
+        .and_then(move |(msg, rd)| {
+            let path = match msg {
+                Some(ref msg) if msg.stream() == SshStream::Preamble => {
+                    String::from_utf8_lossy(msg.as_ref()).into_owned()
+                }
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "expected a preamble with the repo path",
+                    ))
+                }
+            };
+            let wr = FramedWrite::new(tx, SshEncoder::new());
+            Ok((path, mux(rd, wr, &handle)))
+        })
+        .boxify_nonsend()
+}
+
+fn mux<R, W>(
+    rd: FramedRead<R, SshDecoder>,
+    wr: FramedWrite<W, SshEncoder>,
+    handle: &Handle,
+) -> Stdio
+where
+    R: AsyncRead + Send + 'static,
+    W: AsyncWrite + Send + 'static,
+{
     let stdin = rd.filter_map(|s| {
         if s.stream() == SshStream::Stdin {
             Some(s.data())
@@ -105,3 +172,70 @@ where
         stderr: stderr,
     }
 }
+
+// As a server speaking directly to a single client over the process' own stdin/stdout, like
+// `hg serve --stdio` does as the target of an ssh command. The protocol is unframed there, so
+// unlike `ssh_server_mux` stderr goes straight to the process' stderr.
+pub fn stdio() -> Stdio {
+    Stdio {
+        stdin: read_stream(io::stdin()),
+        stdout: write_sink(io::stdout()),
+        stderr: write_sink(io::stderr()),
+    }
+}
+
+// Stdio is blocking, so it's read on its own thread, which stops at EOF or on the first error.
+fn read_stream<R>(mut reader: R) -> BoxStream<Bytes, io::Error>
+where
+    R: Read + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel(1);
+
+    thread::spawn(move || {
+        let mut tx = tx;
+        loop {
+            let mut buf = vec![0; 8192];
+            let res = match reader.read(&mut buf[..]) {
+                Ok(0) => break,
+                Ok(size) => {
+                    buf.truncate(size);
+                    Ok(Bytes::from(buf))
+                }
+                Err(err) => Err(err),
+            };
+            let is_err = res.is_err();
+            tx = match tx.send(res).wait() {
+                Ok(tx) => tx,
+                Err(_) => break,
+            };
+            if is_err {
+                break;
+            }
+        }
+    });
+
+    rx.then(|res| res.expect("mpsc::Receiver never fails")).boxify()
+}
+
+// Likewise written on its own thread, which stops once all the senders are dropped or a write
+// fails.
+fn write_sink<W>(mut writer: W) -> mpsc::Sender<Bytes>
+where
+    W: Write + Send + 'static,
+{
+    let (tx, rx) = mpsc::channel::<Bytes>(1);
+
+    thread::spawn(move || {
+        for buf in rx.wait() {
+            let buf = match buf {
+                Ok(buf) => buf,
+                Err(()) => break,
+            };
+            if writer.write_all(&buf).and_then(|()| writer.flush()).is_err() {
+                break;
+            }
+        }
+    });
+
+    tx
+}
@@ -10,12 +10,15 @@
 
 #[macro_use]
 extern crate failure_ext as failure;
-#[macro_use]
 extern crate futures;
+extern crate futures_cpupool;
 extern crate futures_ext;
 extern crate futures_stats;
+extern crate hyper;
 extern crate tokio_core;
 extern crate tokio_io;
+extern crate tokio_signal;
+extern crate tokio_timer;
 extern crate tokio_uds;
 
 extern crate clap;
@@ -31,12 +34,25 @@ extern crate slog_term;
 extern crate lz4;
 #[macro_use]
 extern crate maplit;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate serde_json;
+#[cfg(test)]
+extern crate tempdir;
+extern crate toml;
+extern crate url;
 
 extern crate async_compression;
 extern crate blobrepo;
 extern crate bundle2_resolver;
 extern crate bytes;
+extern crate commitgraph;
+extern crate db;
+extern crate dbbookmarks;
+extern crate dbheads;
 extern crate hgproto;
+extern crate hooks;
 #[cfg(test)]
 extern crate many_files_dirs;
 extern crate mercurial;
@@ -45,29 +61,45 @@ extern crate mercurial_types;
 #[cfg(test)]
 extern crate mercurial_types_mocks;
 extern crate metaconfig;
+extern crate nix;
+extern crate phases;
 extern crate pylz4;
-extern crate repoinfo;
-extern crate revset;
 extern crate scuba;
 extern crate services;
 extern crate sshrelay;
 extern crate stats;
 
+mod acl;
+mod api;
+mod config;
 mod errors;
+mod headscache;
+mod health;
+mod http;
+mod identity;
+mod registry;
+mod reload;
 mod repo;
 mod listener;
+mod requestlog;
+mod throttle;
 
+use std::collections::HashMap;
+use std::fmt::Debug;
 use std::io;
 use std::panic;
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::mpsc as std_mpsc;
 use std::thread::{self, JoinHandle};
 
 use failure::SlogKVError;
-use futures::{Future, Sink, Stream};
+use futures::{stream, Future, Sink, Stream};
 use futures::sink::Wait;
 use futures::sync::mpsc;
+use tokio_core::reactor::{Core, Handle};
+use tokio_signal::unix::{Signal, SIGTERM};
 
 use clap::{App, ArgGroup, ArgMatches};
 
@@ -78,14 +110,20 @@ use slog_logview::LogViewDrain;
 
 use bytes::Bytes;
 use hgproto::{sshproto, HgProtoHandler};
+use hyper::server::Http;
 use mercurial::RevlogRepo;
-use mercurial_types::RepositoryId;
 use metaconfig::RepoConfigs;
-use metaconfig::repoconfig::RepoType;
+use metaconfig::repoconfig::RepoConfig;
 
+use config::{Config, ConfigRepo, ServerConfig};
 use errors::*;
 
-use listener::{ssh_server_mux, Stdio};
+use http::HttpService;
+use identity::{Identity, SessionContext};
+use listener::{peer_identity, ssh_server_mux, ssh_server_mux_preamble, stdio, Stdio};
+use registry::RepoRegistry;
+use reload::{LevelSwitch, LevelSwitchFilter, LiveConfig};
+use repo::HgRepo;
 
 struct SenderBytesWrite {
     chan: Wait<mpsc::Sender<Bytes>>,
@@ -122,46 +160,57 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
         .about("serve repos")
         .args_from_usage(
             r#"
-            <crpath>      -P, --configrepo_path [PATH]           'path to the config repo'
+            --config [FILE]      'TOML file with the server settings, which flags override'
+
+            [crpath]      -P, --configrepo_path [PATH]           'path to the config repo'
 
             [crbookmark]  -B, --configrepo_bookmark [BOOKMARK]   'config repo bookmark'
             [crhash]      -C, --configrepo_hash [HASH]           'config repo commit hash'
 
             -p, --thrift_port [PORT] 'if provided the thrift server will start on this port'
+            --listen-addr [ADDR] 'if provided all repos are also served over TCP on this address'
+            --http-addr [ADDR]   'if provided all repos are also served over HTTP on this address'
+            --stdio [REPONAME]   'serve a single client of this repo on stdin/stdout and exit'
 
             -d, --debug                                          'print debug level output'
+            --readonly                                           'refuse pushes and reject all blobstore writes'
+            --disable-bundle-compression                         'never compress getbundle responses, for debugging'
         "#,
         )
-        .group(
-            ArgGroup::default()
-                .args(&["crbookmark", "crhash"])
-                .required(true),
-        )
+        .group(ArgGroup::default().args(&["crbookmark", "crhash"]))
 }
 
-fn setup_logger<'a>(matches: &ArgMatches<'a>) -> Logger {
-    let level = if matches.is_present("debug") {
+// The level of the logger is set from the config once it's read, and when it's reloaded
+fn setup_logger<'a>(matches: &ArgMatches<'a>) -> (Logger, LevelSwitch) {
+    let level = LevelSwitch::new(if matches.is_present("debug") {
         Level::Debug
     } else {
         Level::Info
-    };
+    });
 
     let drain = {
         let drain = {
+            // stdout carries the protocol when serving over stdio
+            let output: Box<io::Write + Send> = if matches.is_present("stdio") {
+                Box::new(io::stderr())
+            } else {
+                Box::new(io::stdout())
+            };
             // TODO: switch to TermDecorator, which supports color
-            let decorator = slog_term::PlainSyncDecorator::new(io::stdout());
+            let decorator = slog_term::PlainSyncDecorator::new(output);
             let stderr_drain = GlogFormat::new(decorator, kv_categorizer::FacebookCategorizer);
             let logview_drain = LogViewDrain::new("errorlog_mononoke");
             slog::Duplicate::new(stderr_drain, logview_drain)
         };
         let drain = slog_stats::StatsDrain::new(drain);
-        drain.filter_level(level)
+        LevelSwitchFilter::new(drain, level.clone())
     };
 
-    Logger::root(
+    let logger = Logger::root(
         drain.fuse(),
         o!(kv_defaults::FacebookKV::new().expect("Failed to initialize logging")),
-    )
+    );
+    (logger, level)
 }
 
 fn start_stats() -> Result<JoinHandle<!>> {
@@ -177,12 +226,8 @@ fn start_stats() -> Result<JoinHandle<!>> {
         })?)
 }
 
-fn start_thrift_service<'a>(
-    logger: &Logger,
-    matches: &ArgMatches<'a>,
-) -> Option<Result<JoinHandle<!>>> {
-    matches.value_of("thrift_port").map(|port| {
-        let port = port.parse().expect("Failed to parse thrift_port as number");
+fn start_thrift_service(logger: &Logger, port: Option<u16>) -> Option<Result<JoinHandle<!>>> {
+    port.map(|port| {
         info!(logger, "Initializing thrift server on port {}", port);
 
         thread::Builder::new()
@@ -198,20 +243,70 @@ fn start_thrift_service<'a>(
     })
 }
 
-fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfigs> {
+// Read the config file if one was given, and override its settings with the command line ones.
+fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<Config> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => Config::read(path)?,
+        None => Config::default(),
+    };
+
+    if let Some(port) = matches.value_of("thrift_port") {
+        config.server.thrift_port = Some(port.parse()?);
+    }
+    if let Some(addr) = matches.value_of("listen-addr") {
+        config.server.listen_addr = Some(addr.parse()?);
+    }
+    if let Some(addr) = matches.value_of("http-addr") {
+        config.server.http_addr = Some(addr.parse()?);
+    }
+    if matches.is_present("readonly") {
+        config.server.readonly = true;
+    }
+    if matches.is_present("disable-bundle-compression") {
+        config.server.disable_bundle_compression = true;
+    }
+    if let Some(path) = matches.value_of("crpath") {
+        config.configrepo = Some(ConfigRepo {
+            path: PathBuf::from(path),
+            bookmark: matches.value_of("crbookmark").map(ToOwned::to_owned),
+            hash: matches.value_of("crhash").map(ToOwned::to_owned),
+        });
+    }
+
+    if let Some(ref configrepo) = config.configrepo {
+        for (name, repo) in read_config_repo(logger, configrepo)?.repos {
+            if config.repos.contains_key(&name) {
+                bail_err!(ErrorKind::InvalidConfig(format!(
+                    "repo {} is configured both in the config file and the config repo",
+                    name
+                )));
+            }
+            config.repos.insert(name, repo);
+        }
+    }
+
+    if config.repos.is_empty() {
+        bail_err!(ErrorKind::InvalidConfig("no repos configured".into()));
+    }
+    Ok(config)
+}
+
+fn read_config_repo(logger: &Logger, configrepo: &ConfigRepo) -> Result<RepoConfigs> {
     // TODO: This needs to cope with blob repos, too
-    let mut crpath = PathBuf::from(matches.value_of("crpath").unwrap());
-    crpath.push(".hg");
-    let config_repo = RevlogRepo::open(crpath)?;
-
-    let changesetid = if let Some(bookmark) = matches.value_of("crbookmark") {
-        config_repo
-            .get_bookmark_value(&bookmark)
-            .wait()?
-            .ok_or_else(|| failure::err_msg("bookmark for config repo not found"))?
-            .0
-    } else {
-        mercurial_types::nodehash::ChangesetId::from_str(matches.value_of("crhash").unwrap())?
+    let config_repo = RevlogRepo::open(configrepo.path.join(".hg"))?;
+
+    let changesetid = match (&configrepo.bookmark, &configrepo.hash) {
+        (&Some(ref bookmark), _) => {
+            config_repo
+                .get_bookmark_value(bookmark)
+                .wait()?
+                .ok_or_else(|| failure::err_msg("bookmark for config repo not found"))?
+                .0
+        }
+        (&None, &Some(ref hash)) => mercurial_types::nodehash::ChangesetId::from_str(hash)?,
+        (&None, &None) => bail_err!(ErrorKind::InvalidConfig(
+            "a bookmark or a hash of the config repo is required".into()
+        )),
     };
 
     info!(
@@ -224,10 +319,13 @@ fn get_config<'a>(logger: &Logger, matches: &ArgMatches<'a>) -> Result<RepoConfi
         .wait()
 }
 
-fn start_repo_listeners<I>(repos: I, root_log: &Logger) -> Result<Vec<JoinHandle<!>>>
-where
-    I: IntoIterator<Item = (RepoType, usize, i32, Option<String>)>,
-{
+fn start_repo_listeners(
+    repos: HashMap<String, RepoConfig>,
+    server_config: &ServerConfig,
+    live: &Arc<LiveConfig>,
+    registered: std_mpsc::Sender<(String, Arc<HgRepo>)>,
+    root_log: &Logger,
+) -> Result<Vec<JoinHandle<()>>> {
     // Given the list of paths to repos:
     // - create a thread for it
     // - initialize the repo
@@ -235,22 +333,17 @@ where
 
     let handles: Vec<_> = repos
         .into_iter()
-        .map(move |(repotype, cache_size, repoid, scuba_table)| {
+        .map(move |(reponame, config)| {
             // start a thread for each repo to own the reactor and start listening for
             // connections and detach it
             thread::Builder::new()
-                .name(format!("listener_{:?}", repotype))
+                .name(format!("listener_{:?}", config.repotype))
                 .spawn({
                     let root_log = root_log.clone();
-                    move || {
-                        repo_listen(
-                            repotype,
-                            cache_size,
-                            root_log.clone(),
-                            RepositoryId::new(repoid),
-                            scuba_table,
-                        )
-                    }
+                    let server_config = server_config.clone();
+                    let live = live.clone();
+                    let registered = registered.clone();
+                    move || repo_listen(reponame, config, server_config, live, root_log, registered)
                 })
                 .map_err(Error::from)
         })
@@ -268,136 +361,366 @@ where
     Ok(handles.into_iter().filter_map(Result::ok).collect())
 }
 
-// Listener thread for a specific repo
+// Once all the repos are initialized, start listening for the TCP and HTTP connections, which
+// are routed to any of them, if any address to listen on was given.
+fn start_shared_listener(
+    repo_count: usize,
+    registrations: std_mpsc::Receiver<(String, Arc<HgRepo>)>,
+    server_config: &ServerConfig,
+    root_log: &Logger,
+) -> Result<Option<JoinHandle<()>>> {
+    if server_config.listen_addr.is_none() && server_config.http_addr.is_none() {
+        return Ok(None);
+    }
+
+    let mut registry = RepoRegistry::new();
+    for _ in 0..repo_count {
+        let (name, repo) = registrations.recv().map_err(|_| {
+            ErrorKind::Initialization("a listener thread exited before registering its repo")
+        })?;
+        registry.insert(name, repo);
+    }
+    let registry = Arc::new(registry);
+
+    let handle = thread::Builder::new()
+        .name("shared_listener".to_owned())
+        .spawn({
+            let server_config = server_config.clone();
+            let root_log = root_log.clone();
+            move || shared_listen(registry, server_config, root_log)
+        })?;
+    Ok(Some(handle))
+}
+
+fn log_peer_addr<A: Debug>(listen_log: &Logger, peer_addr: io::Result<A>) {
+    match peer_addr {
+        Ok(addr) => info!(listen_log, "New connection from {:?}", addr),
+        Err(err) => error!(listen_log, "Failed to get peer addr"; SlogKVError(Error::from(err))),
+    };
+}
+
+// Serve a single connection on its own task, until the client closes it. The connection holds
+// on to `inflight` for as long as it runs, so that shutdown can wait for it.
+fn serve_connection(
+    stdio: Stdio,
+    session: SessionContext,
+    repo: &Arc<HgRepo>,
+    listen_log: &Logger,
+    handle: &Handle,
+    inflight: &mpsc::Sender<()>,
+) {
+    let Stdio {
+        stdin,
+        stdout,
+        stderr,
+    } = stdio;
+
+    let stderr_write = SenderBytesWrite {
+        chan: stderr.clone().wait(),
+    };
+    let drain = slog_term::PlainSyncDecorator::new(stderr_write);
+    let drain = slog_term::FullFormat::new(drain).build();
+    let drain = KVFilter::new(drain, Level::Critical).only_pass_any_on_all_keys(hashmap! {
+        "remote".into() => hashset!["true".into()],
+    });
+    let drain = slog::Duplicate::new(drain, listen_log.clone()).fuse();
+    let conn_log = session.logger(&Logger::root(drain, o![]));
+
+    // Construct a hg protocol handler
+    let proto_handler = HgProtoHandler::new(
+        stdin,
+        repo::RepoClient::new(repo.clone(), &conn_log, session),
+        sshproto::HgSshCommandDecode,
+        sshproto::HgSshCommandEncode,
+        &conn_log,
+    );
+
+    // send responses back
+    let endres = proto_handler
+        .map_err(Error::from)
+        .forward(stdout)
+        .map(|_| ());
+
+    // If we got an error at this point, then catch it, print a message and return
+    // Ok (if we allow the Error to propagate further it will shutdown the listener
+    // rather than just the connection). Unfortunately there's no way to print what the
+    // actual failing command was.
+    // TODO: seems to leave the client hanging?
+    let conn_log = conn_log.clone();
+    let inflight = inflight.clone();
+    let endres = endres.or_else(move |err| {
+        error!(conn_log, "Command failed"; SlogKVError(err), "remote" => "true");
+        Ok(())
+    });
+
+    // Run the whole future asynchronously to allow new connections
+    handle.spawn(endres.then(move |res| {
+        drop(inflight);
+        res
+    }));
+}
+
+// Run the listeners until SIGTERM is received, which drops them, and then wait for the
+// connections in flight to finish: each of them holds a sender of `drained`.
+fn serve_until_sigterm<S>(
+    mut core: Core,
+    connections: S,
+    drained: mpsc::Receiver<()>,
+    listen_log: &Logger,
+) where
+    S: Stream<Item = (), Error = io::Error>,
+{
+    let server = connections.map_err(Error::from).for_each(|()| Ok(()));
+
+    let sigterm = Signal::new(SIGTERM, &core.handle())
+        .flatten_stream()
+        .into_future()
+        .map(|_| ())
+        .map_err(|(err, _)| Error::from(err));
+
+    core.run(server.select(sigterm).map(|_| ()).map_err(|(err, _)| err))
+        .expect("failure while running listener on tokio core");
+    info!(listen_log, "Shutting down, waiting for connections in flight");
+
+    core.run(drained.for_each(|()| Ok(())))
+        .expect("failure while waiting for connections to finish");
+}
+
+// Listener thread for a specific repo. Returns once SIGTERM was received and all the connections
+// in flight at that point are finished.
 fn repo_listen(
-    repotype: RepoType,
-    cache_size: usize,
+    reponame: String,
+    config: RepoConfig,
+    server_config: ServerConfig,
+    live: Arc<LiveConfig>,
     root_log: Logger,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
-) -> ! {
-    let mut core = tokio_core::reactor::Core::new().expect("failed to create tokio core");
-    let (sockname, repo) = repo::init_repo(
-        &root_log,
-        &repotype,
-        cache_size,
-        &core.remote(),
-        repoid,
-        scuba_table,
-    ).expect("failed to initialize repo");
+    registered: std_mpsc::Sender<(String, Arc<HgRepo>)>,
+) {
+    let core = Core::new().expect("failed to create tokio core");
+    let (sockname, repo) =
+        repo::init_repo(&root_log, config, &core.remote(), &server_config, &live)
+            .expect("failed to initialize repo");
 
     let listen_log = root_log.new(o!("repo" => repo.path().clone()));
 
     let handle = core.handle();
     let repo = Arc::new(repo);
+    // Nothing receives this if no shared listener is started
+    let _ = registered.send((reponame, repo.clone()));
 
-    let server = listener::listener(sockname, &handle)
-        .expect("failed to create listener")
-        .map_err(Error::from)
-        .for_each(move |sock| {
-            match sock.peer_addr() {
-                Ok(addr) => info!(listen_log, "New connection from {:?}", addr),
-                Err(err) => {
-                    error!(listen_log, "Failed to get peer addr"; SlogKVError(Error::from(err)))
-                }
-            };
+    // Every connection holds a clone of the sender, so the receiver ends once they're all done.
+    let (inflight, drained) = mpsc::channel::<()>(0);
 
+    let unix_connections = listener::listener(sockname, &handle)
+        .expect("failed to create listener")
+        .map(move |sock| {
+            log_peer_addr(&listen_log, sock.peer_addr());
+            let session = SessionContext::new(peer_identity(&sock));
             // Have a connection. Extract std{in,out,err} streams for socket
-            let Stdio {
-                stdin,
-                stdout,
-                stderr,
-            } = ssh_server_mux(sock, &handle);
-
-            let stderr_write = SenderBytesWrite {
-                chan: stderr.clone().wait(),
-            };
-            let drain = slog_term::PlainSyncDecorator::new(stderr_write);
-            let drain = slog_term::FullFormat::new(drain).build();
-            let drain = KVFilter::new(drain, Level::Critical).only_pass_any_on_all_keys(hashmap! {
-                "remote".into() => hashset!["true".into()],
-            });
-            let drain = slog::Duplicate::new(drain, listen_log.clone()).fuse();
-            let conn_log = Logger::root(drain, o![]);
-
-            // Construct a hg protocol handler
-            let proto_handler = HgProtoHandler::new(
-                stdin,
-                repo::RepoClient::new(repo.clone(), &conn_log),
-                sshproto::HgSshCommandDecode,
-                sshproto::HgSshCommandEncode,
-                &conn_log,
-            );
-
-            // send responses back
-            let endres = proto_handler
-                .map_err(Error::from)
-                .forward(stdout)
-                .map(|_| ());
-
-            // If we got an error at this point, then catch it, print a message and return
-            // Ok (if we allow the Error to propagate further it will shutdown the listener
-            // rather than just the connection). Unfortunately there's no way to print what the
-            // actual failing command was.
-            // TODO: seems to leave the client hanging?
-            let conn_log = conn_log.clone();
-            let endres = endres.or_else(move |err| {
-                error!(conn_log, "Command failed"; SlogKVError(err), "remote" => "true");
-                Ok(())
-            });
-
-            // Run the whole future asynchronously to allow new connections
-            handle.spawn(endres);
-
-            Ok(())
+            let stdio = ssh_server_mux(sock, &handle);
+            serve_connection(stdio, session, &repo, &listen_log, &handle, &inflight)
         });
 
-    core.run(server)
-        .expect("failure while running listener on tokio core");
+    serve_until_sigterm(core, unix_connections, drained, &root_log);
+}
+
+// Listener thread for the TCP and HTTP connections, which can be for any of the repos. Returns
+// once SIGTERM was received and all the connections in flight at that point are finished.
+fn shared_listen(
+    registry: Arc<RepoRegistry>,
+    server_config: ServerConfig,
+    root_log: Logger,
+) {
+    let core = Core::new().expect("failed to create tokio core");
+    let handle = core.handle();
+
+    let (inflight, drained) = mpsc::channel::<()>(0);
+
+    let tcp_connections = match server_config.listen_addr {
+        Some(addr) => {
+            info!(root_log, "Listening for TCP connections on {}", addr);
+            let tcp_connections = listener::tcp_listener(&addr, &handle)
+                .expect("failed to create TCP listener")
+                .map({
+                    let registry = registry.clone();
+                    let root_log = root_log.clone();
+                    let handle = handle.clone();
+                    let inflight = inflight.clone();
+                    move |sock| {
+                        log_peer_addr(&root_log, sock.peer_addr());
+                        let routed = ssh_server_mux_preamble(sock, &handle).then({
+                            let registry = registry.clone();
+                            let root_log = root_log.clone();
+                            let handle = handle.clone();
+                            let inflight = inflight.clone();
+                            move |res| {
+                                match res {
+                                    Ok((path, stdio)) => match registry.route(&path) {
+                                        Some(repo) => {
+                                            let listen_log =
+                                                root_log.new(o!("repo" => repo.path().clone()));
+                                            serve_connection(
+                                                stdio,
+                                                SessionContext::new(Identity::Anonymous),
+                                                repo,
+                                                &listen_log,
+                                                &handle,
+                                                &inflight,
+                                            )
+                                        }
+                                        None => {
+                                            error!(root_log, "Unknown repo {}", path);
+                                            let msg = format!("repository {} not found\n", path);
+                                            let send = stdio.stderr.send(Bytes::from(msg));
+                                            handle.spawn(send.then(|_| Ok(())));
+                                        }
+                                    },
+                                    Err(err) => {
+                                        error!(root_log, "Failed to route connection";
+                                               SlogKVError(err.into()))
+                                    }
+                                }
+                                Ok::<_, ()>(())
+                            }
+                        });
+                        handle.spawn(routed);
+                    }
+                });
+            Box::new(tcp_connections) as Box<Stream<Item = (), Error = io::Error>>
+        }
+        None => Box::new(stream::empty()),
+    };
+
+    let http_connections = match server_config.http_addr {
+        Some(addr) => {
+            info!(root_log, "Listening for HTTP connections on {}", addr);
+            let http_connections = listener::tcp_listener(&addr, &handle)
+                .expect("failed to create HTTP listener")
+                .map({
+                    let root_log = root_log.clone();
+                    let handle = handle.clone();
+                    let inflight = inflight.clone();
+                    move |sock| match sock.peer_addr() {
+                        Ok(peer_addr) => {
+                            info!(root_log, "New HTTP connection from {:?}", peer_addr);
+                            let service = HttpService::new(
+                                registry.clone(),
+                                &root_log,
+                                &handle,
+                                server_config.tls_identity_header.clone(),
+                                &inflight,
+                            );
+                            Http::new().bind_connection(&handle, sock, peer_addr, service)
+                        }
+                        Err(err) => {
+                            error!(root_log, "Failed to get peer addr"; SlogKVError(err.into()))
+                        }
+                    }
+                });
+            Box::new(http_connections) as Box<Stream<Item = (), Error = io::Error>>
+        }
+        None => Box::new(stream::empty()),
+    };
+    drop(inflight);
+
+    serve_until_sigterm(
+        core,
+        tcp_connections.select(http_connections),
+        drained,
+        &root_log,
+    );
+}
 
-    // The server is an infinite stream of connections
-    unreachable!();
+// Serve a single client over stdin/stdout, and return once it's done.
+fn serve_stdio(
+    config: RepoConfig,
+    server_config: &ServerConfig,
+    live: &LiveConfig,
+    root_log: &Logger,
+) -> Result<()> {
+    let mut core = Core::new()?;
+    let (_, repo) = repo::init_repo(root_log, config, &core.remote(), server_config, live)?;
+
+    let listen_log = root_log.new(o!("repo" => repo.path().clone()));
+    info!(listen_log, "Serving over stdio");
+
+    let handle = core.handle();
+    let (inflight, drained) = mpsc::channel::<()>(0);
+    let session = SessionContext::new(Identity::from_ssh_env());
+    let repo = Arc::new(repo);
+    serve_connection(stdio(), session, &repo, &listen_log, &handle, &inflight);
+    drop(inflight);
+
+    core.run(drained.for_each(|()| Ok(())))
+        .map_err(|()| format_err!("failure while serving over stdio"))
 }
 
 fn main() {
     setup_panic_hook();
     let matches = setup_app().get_matches();
-    let root_log = setup_logger(&matches);
+    let (root_log, log_level) = setup_logger(&matches);
 
-    fn run_server<'a>(root_log: &Logger, matches: ArgMatches<'a>) -> Result<!> {
+    fn run_server(
+        root_log: &Logger,
+        log_level: LevelSwitch,
+        matches: ArgMatches<'static>,
+    ) -> Result<!> {
         info!(root_log, "Starting up");
 
-        let stats_aggregation = start_stats()?;
-        let maybe_thrift = match start_thrift_service(&root_log, &matches) {
+        let config = get_config(root_log, &matches)?;
+        let server_config = config.server.clone();
+        let mut repos = config.repos.clone();
+        // Shared by all the repos, as they share the resources of the process
+        let live = Arc::new(LiveConfig::new(config, log_level, matches.is_present("debug"))?);
+
+        let _stats_aggregation = start_stats()?;
+        let _maybe_thrift = match start_thrift_service(&root_log, server_config.thrift_port) {
             None => None,
             Some(handle) => Some(handle?),
         };
 
-        let config = get_config(root_log, &matches)?;
-        let repo_listeners = start_repo_listeners(
-            config
-                .repos
-                .into_iter()
-                .map(|(_, c)| (c.repotype, c.generation_cache_size, c.repoid, c.scuba_table)),
+        if let Some(reponame) = matches.value_of("stdio") {
+            let repo_config = repos
+                .remove(reponame)
+                .ok_or_else(|| format_err!("repo '{}' not found in config", reponame))?;
+            serve_stdio(repo_config, &server_config, &live, root_log)?;
+            std::process::exit(0);
+        }
+
+        let _reloader = reload::start_reloader(
+            live.clone(),
+            {
+                let root_log = root_log.clone();
+                let matches = matches.clone();
+                move || get_config(&root_log, &matches)
+            },
+            matches.value_of("config").map(PathBuf::from),
+            server_config.config_poll_secs,
             root_log,
         )?;
 
-        for handle in vec![stats_aggregation]
-            .into_iter()
-            .chain(maybe_thrift.into_iter())
-            .chain(repo_listeners.into_iter())
-        {
+        let repo_count = repos.len();
+        let (registered, registrations) = std_mpsc::channel();
+        let repo_listeners =
+            start_repo_listeners(repos, &server_config, &live, registered, root_log)?;
+        let shared_listener =
+            start_shared_listener(repo_count, registrations, &server_config, root_log)?;
+
+        // The stats, thrift and config reloading threads never finish, and a panic in any
+        // thread exits the process, so only the listeners are waited for: they return on
+        // SIGTERM once their connections are drained.
+        for handle in repo_listeners.into_iter().chain(shared_listener) {
             let thread_name = handle.thread().name().unwrap_or("unknown").to_owned();
-            match handle.join() {
-                Err(panic) => crit!(root_log, "Thread {} paniced with: {:?}", thread_name, panic),
+            if let Err(panic) = handle.join() {
+                crit!(root_log, "Thread {} paniced with: {:?}", thread_name, panic);
             }
         }
 
-        info!(root_log, "No service to run, shutting down");
+        info!(root_log, "All listeners are shut down, exiting");
         std::process::exit(0);
     }
 
-    match run_server(&root_log, matches) {
+    match run_server(&root_log, log_level, matches) {
         Err(e) => {
             crit!(root_log, "Server fatal error"; SlogKVError(e));
             std::process::exit(1);
@@ -0,0 +1,64 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::sync::Arc;
+
+use repo::HgRepo;
+
+/// The repos hosted by a server, by their name in the config, used to route the connections
+/// which can be for any of them.
+#[derive(Clone, Debug, Default)]
+pub struct RepoRegistry {
+    repos: HashMap<String, Arc<HgRepo>>,
+}
+
+impl RepoRegistry {
+    pub fn new() -> Self {
+        RepoRegistry {
+            repos: HashMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, name: String, repo: Arc<HgRepo>) {
+        self.repos.insert(name, repo);
+    }
+
+    pub fn iter(&self) -> hash_map::Iter<String, Arc<HgRepo>> {
+        self.repos.iter()
+    }
+
+    /// Find the repo a client asked for, by the path in the URL it was given.
+    pub fn route(&self, path: &str) -> Option<&Arc<HgRepo>> {
+        self.repos
+            .iter()
+            .find(|&(name, repo)| path_matches(path, name, repo.path()))
+            .map(|(_, repo)| repo)
+    }
+}
+
+/// Paths are matched against the names of the repos, ignoring slashes around them, as in
+/// `http://server/<name>`, or against where they're stored, as in `ssh://server//<path>`.
+fn path_matches(path: &str, name: &str, repo_path: &str) -> bool {
+    path.trim_matches('/') == name || path.trim_right_matches('/') == repo_path
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("repo", "repo", "/data/repo"));
+        assert!(path_matches("/repo/", "repo", "/data/repo"));
+        assert!(path_matches("/data/repo", "repo", "/data/repo"));
+        assert!(path_matches("/data/repo/", "repo", "/data/repo"));
+        assert!(!path_matches("/data/other", "repo", "/data/repo"));
+        assert!(!path_matches("data/repo", "repo", "/data/repo"));
+        assert!(!path_matches("", "repo", "/data/repo"));
+    }
+}
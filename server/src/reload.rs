@@ -0,0 +1,297 @@
+// Copyright (c) 2018-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Reloading of the server config while the server runs, on SIGHUP or when the config file
+//! changes, without dropping connections.
+//!
+//! The log level, the throttle, the ACL and the protected bookmarks are shared by all the repos
+//! and connections, which pick up the new settings with their next command. A config which is
+//! invalid is rejected as a whole, and the settings in use are kept. Changes to the other
+//! settings, such as the repos or the addresses listened on, are only warned about: they take
+//! effect on restart.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::result;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime};
+
+use futures::{stream, Stream};
+use tokio_core::reactor::Core;
+use tokio_signal::unix::{Signal, SIGHUP};
+use tokio_timer::wheel;
+
+use failure::SlogKVError;
+use slog::{Drain, Level, Logger, OwnedKVList, Record};
+
+use blobrepo::ProtectedBookmarks;
+
+use acl::{acl_checker, ReloadableAcl};
+use config::{Config, ServerConfig};
+use errors::*;
+use repo;
+use throttle::Throttle;
+
+// Enough for the config file to be polled up to every 18 hours
+const TIMER_SLOTS: usize = 1 << 16;
+
+/// Least severe level of the messages logged, which can be changed while the logger is in use.
+#[derive(Clone)]
+pub struct LevelSwitch(Arc<AtomicUsize>);
+
+impl LevelSwitch {
+    pub fn new(level: Level) -> Self {
+        LevelSwitch(Arc::new(AtomicUsize::new(level.as_usize())))
+    }
+
+    pub fn set(&self, level: Level) {
+        self.0.store(level.as_usize(), Ordering::Relaxed);
+    }
+
+    fn get(&self) -> Level {
+        Level::from_usize(self.0.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+    }
+}
+
+/// Drops the messages less severe than the level of `switch`.
+pub struct LevelSwitchFilter<D> {
+    drain: D,
+    switch: LevelSwitch,
+}
+
+impl<D> LevelSwitchFilter<D> {
+    pub fn new(drain: D, switch: LevelSwitch) -> Self {
+        LevelSwitchFilter { drain, switch }
+    }
+}
+
+impl<D: Drain> Drain for LevelSwitchFilter<D> {
+    type Ok = Option<D::Ok>;
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> result::Result<Self::Ok, Self::Err> {
+        if record.level().is_at_least(self.switch.get()) {
+            self.drain.log(record, values).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+}
+
+/// The settings which can be reloaded, shared by all the repos.
+pub struct LiveConfig {
+    pub throttle: Arc<Throttle>,
+    pub acl: Arc<ReloadableAcl>,
+    pub protected_bookmarks: Arc<ProtectedBookmarks>,
+    log_level: LevelSwitch,
+    // Whether --debug was given, which overrides the log level of the config
+    debug: bool,
+    current: Mutex<Config>,
+}
+
+impl LiveConfig {
+    pub fn new(config: Config, log_level: LevelSwitch, debug: bool) -> Result<Self> {
+        let throttle = Throttle::new(&config.server.throttle, repo::ops::ALL)?;
+        let acl = acl_checker(&config.server.acl)?;
+        let protected_bookmarks = ProtectedBookmarks::new(&config.server.protected_bookmarks);
+        log_level.set(level(&config.server, debug));
+        Ok(LiveConfig {
+            throttle: Arc::new(throttle),
+            acl: Arc::new(ReloadableAcl::new(acl)),
+            protected_bookmarks: Arc::new(protected_bookmarks),
+            log_level,
+            debug,
+            current: Mutex::new(config),
+        })
+    }
+
+    /// Apply the reloadable settings of `config`, or nothing if any of them is invalid.
+    pub fn reload(&self, logger: &Logger, config: Config) -> Result<()> {
+        let server = &config.server;
+        let acl = acl_checker(&server.acl)?;
+        // The last fallible step, which changes nothing if it fails
+        self.throttle.reload(&server.throttle)?;
+        self.acl.replace(acl);
+        self.protected_bookmarks.replace(&server.protected_bookmarks);
+        self.log_level.set(level(server, self.debug));
+
+        let mut current = self.current.lock().expect("lock poisoned");
+        let restart_needed = restart_needed(&current, &config);
+        if !restart_needed.is_empty() {
+            warn!(
+                logger,
+                "Settings which only take effect on restart changed: {}",
+                restart_needed.join(", ")
+            );
+        }
+        *current = config;
+        Ok(())
+    }
+}
+
+fn level(server: &ServerConfig, debug: bool) -> Level {
+    if debug {
+        Level::Debug
+    } else {
+        server.log_level.map_or(Level::Info, Level::from)
+    }
+}
+
+// The parts of the config, out of the server settings, the config repo and the repos, which
+// differ in settings that can't be reloaded
+fn restart_needed(old: &Config, new: &Config) -> Vec<&'static str> {
+    let fixed = |server: &ServerConfig| ServerConfig {
+        throttle: Default::default(),
+        acl: Default::default(),
+        protected_bookmarks: Vec::new(),
+        log_level: None,
+        ..server.clone()
+    };
+
+    let mut changed = Vec::new();
+    if fixed(&old.server) != fixed(&new.server) {
+        changed.push("server");
+    }
+    if old.configrepo != new.configrepo {
+        changed.push("configrepo");
+    }
+    if old.repos != new.repos {
+        changed.push("repos");
+    }
+    changed
+}
+
+/// Start a thread which reloads `live` with the config `load` reads, on SIGHUP and, if
+/// `poll_secs` is set, whenever the modification time of `path` changes.
+pub fn start_reloader<F>(
+    live: Arc<LiveConfig>,
+    load: F,
+    path: Option<PathBuf>,
+    poll_secs: Option<u64>,
+    logger: &Logger,
+) -> Result<JoinHandle<()>>
+where
+    F: Fn() -> Result<Config> + Send + 'static,
+{
+    let logger = logger.clone();
+    let handle = thread::Builder::new()
+        .name("config_reloader".to_owned())
+        .spawn(move || {
+            let mut core = Core::new().expect("failed to create tokio core");
+            let sighups = Signal::new(SIGHUP, &core.handle())
+                .flatten_stream()
+                .map(|_| ())
+                .map_err(Error::from);
+
+            let changes = match (path, poll_secs) {
+                (Some(path), Some(secs)) => {
+                    let mut last_modified = modified(&path);
+                    let timer = wheel()
+                        .tick_duration(Duration::from_secs(1))
+                        .num_slots(TIMER_SLOTS)
+                        .build();
+                    let changes = timer
+                        .interval(Duration::from_secs(secs))
+                        .map_err(Error::from)
+                        .filter(move |_| {
+                            let modified = modified(&path);
+                            let changed = modified != last_modified;
+                            last_modified = modified;
+                            changed
+                        });
+                    Box::new(changes) as Box<Stream<Item = (), Error = Error>>
+                }
+                _ => Box::new(stream::empty()),
+            };
+
+            let reloads = sighups.select(changes).for_each(|()| {
+                info!(logger, "Reloading the config");
+                match load().and_then(|config| live.reload(&logger, config)) {
+                    Ok(()) => info!(logger, "Config reloaded"),
+                    Err(err) => error!(
+                        logger,
+                        "Invalid config, keeping the one in use";
+                        SlogKVError(err)
+                    ),
+                }
+                Ok(())
+            });
+            if let Err(err) = core.run(reloads) {
+                error!(logger, "Config reloading stopped"; SlogKVError(err));
+            }
+        })?;
+    Ok(handle)
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use slog::Discard;
+
+    fn logger() -> Logger {
+        Logger::root(Discard, o![])
+    }
+
+    #[test]
+    fn reload() {
+        let switch = LevelSwitch::new(Level::Info);
+        let live = LiveConfig::new(Config::default(), switch.clone(), false).unwrap();
+        assert!(!live.protected_bookmarks.is_protected(b"master"));
+
+        let reloaded = Config::parse(
+            r#"
+            [server]
+            log_level = "error"
+            protected_bookmarks = ["master"]
+            [server.throttle.getbundle]
+            max_concurrent = 0
+        "#,
+        ).unwrap();
+        live.reload(&logger(), reloaded).unwrap();
+        assert!(live.protected_bookmarks.is_protected(b"master"));
+        assert_eq!(switch.get(), Level::Error);
+        assert!(live.throttle.acquire(repo::ops::GETBUNDLE).is_err());
+
+        // Nothing changes if any of the settings is invalid
+        for content in &[
+            "[server.acl]\nchecker = \"service\"",
+            "[server.throttle.getbundel]\nmax_concurrent = 1",
+        ] {
+            let invalid = Config::parse(&format!("[server]\nlog_level = \"debug\"\n{}", content));
+            assert!(live.reload(&logger(), invalid.unwrap()).is_err());
+        }
+        assert!(live.protected_bookmarks.is_protected(b"master"));
+        assert_eq!(switch.get(), Level::Error);
+        assert!(live.throttle.acquire(repo::ops::GETBUNDLE).is_err());
+    }
+
+    #[test]
+    fn debug_overrides_log_level() {
+        let switch = LevelSwitch::new(Level::Debug);
+        let live = LiveConfig::new(Config::default(), switch.clone(), true).unwrap();
+        let config = Config::parse("[server]\nlog_level = \"error\"").unwrap();
+        live.reload(&logger(), config).unwrap();
+        assert_eq!(switch.get(), Level::Debug);
+    }
+
+    #[test]
+    fn restart_needed_settings() {
+        let old = Config::parse("[server]\nreadonly = false").unwrap();
+        let new = Config::parse("[server]\nreadonly = true\nprotected_bookmarks = [\"master\"]");
+        assert_eq!(restart_needed(&old, &new.unwrap()), vec!["server"]);
+        let new = Config::parse("[server]\nlog_level = \"debug\"").unwrap();
+        assert!(restart_needed(&old, &new).is_empty());
+    }
+}
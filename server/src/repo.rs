@@ -6,79 +6,166 @@
 
 //! State for a single source control Repo
 
-use std::collections::{HashMap, HashSet, VecDeque};
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fmt::{self, Debug};
-use std::io::{Cursor, Write};
+use std::fs::{self, File};
+use std::io::{Cursor, Read, Write};
 use std::mem;
 use std::path::{Path, PathBuf};
 use std::str::FromStr;
 use std::sync::Arc;
+use std::sync::mpsc as std_mpsc;
+use std::thread;
+use std::time::Duration;
 
+use async_compression::CompressorType;
 use bytes::{BufMut, Bytes, BytesMut};
 use failure::err_msg;
-use futures::{future, stream, Async, Future, IntoFuture, Poll, Stream};
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures::future::{loop_fn, Loop};
+use futures_cpupool::CpuPool;
 use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use futures_ext::io::ChannelWriter;
 use futures_stats::{Stats, Timed};
 use pylz4;
 use scuba::{ScubaClient, ScubaSample};
-use tokio_core::reactor::Remote;
+use tokio_core::reactor::{Core, Remote};
+use tokio_io::AsyncWrite;
 
 use slog::Logger;
 
-use blobrepo::BlobChangeset;
+use blobrepo::OpLog;
 use bundle2_resolver;
 use mercurial;
-use mercurial_bundles::{parts, Bundle2EncodeBuilder, Bundle2Item};
-use mercurial_types::{percent_encode, BlobNode, Changeset, ChangesetId, Entry, MPath, ManifestId,
-                      NodeHash, Parents, RepoPath, RepositoryId, Type, NULL_HASH};
+use mercurial::RevlogRepo;
+use mercurial::revlogrepo::Required;
+use mercurial_bundles::{parts, wirepack, Bundle2EncodeBuilder, Bundle2Item, Capabilities};
+use mercurial_bundles::changegroup::CgVersion;
+use mercurial_bundles::part_encode::PartEncodeBuilder;
+use mercurial_bundles::wirepack::packer::WirePackPacker;
+use mercurial_types::{percent_encode, BlobNode, Changeset, ChangesetId, Delta, Entry, MPath,
+                      ManifestId, NodeHash, Parents, RepoPath, RepositoryId, Type, NULL_HASH};
 use mercurial_types::manifest_utils::{changed_entry_stream, EntryStatus};
-use metaconfig::repoconfig::RepoType;
+use mercurial_types::obsmarker;
+use metaconfig::repoconfig::{HooksConfig, RepoConfig, RepoType};
+use phases::Phase;
 
 use hgproto::{self, GetbundleArgs, GettreepackArgs, HgCommandRes, HgCommands};
+use hooks::{BannedPathsHook, CommitMessageHook, Hook, MaxFileSizeHook};
 
 use blobrepo::BlobRepo;
-
+use commitgraph::CommitGraph;
+use db;
+use dbbookmarks::DbBookmarks;
+use dbheads::MysqlHeads;
+
+use acl::{AclChecker, Action};
+use api::{self, ApiRequest};
+use config::ServerConfig;
 use errors::*;
+use headscache::HeadsCache;
+use identity::SessionContext;
+use reload::LiveConfig;
+use requestlog::{JsonSink, RequestLog};
+use throttle::Throttle;
 
-use repoinfo::RepoGenCache;
-use revset::{AncestorsNodeStream, IntersectNodeStream, NodeStream, SetDifferenceNodeStream,
-             SingleNodeHash, UnionNodeStream};
 
 const METAKEYFLAG: &str = "f";
 const METAKEYSIZE: &str = "s";
 
-mod ops {
+/// How many chunks of a getbundle response can be encoded ahead of the client reading them.
+const GETBUNDLE_CHUNKS_IN_FLIGHT: usize = 64;
+
+/// How many of the pairs of a between command are walked at once.
+const BETWEEN_PAIRS_IN_FLIGHT: usize = 16;
+
+/// How long the heads of a repo are cached for when the config doesn't say.
+const DEFAULT_HEADS_CACHE_TTL_SECS: u64 = 60;
+
+/// Bundle capabilities of clients which don't keep flat manifests or full filelogs.
+const BUNDLECAP_TREEONLY: &[u8] = b"treeonly";
+const BUNDLECAP_REMOTEFILELOG: &[u8] = b"remotefilelog";
+
+/// Size of the chunks the store files of a streaming clone are read and sent in.
+const STREAM_OUT_CHUNK_SIZE: u64 = 64 * 1024;
+// Threads the files of a streaming clone are read on, as reading files blocks
+const STREAM_OUT_THREADS: usize = 4;
+
+/// How many files or trees are prefetched at once before being sent to a client.
+const PREFETCH_BATCH_SIZE: usize = 256;
+/// How many changesets, manifests or files of a changegroup are loaded at once.
+const CHANGEGROUP_NODES_IN_FLIGHT: usize = 64;
+
+/// How many connections to the database of a repo its heads are read and written through.
+const DB_POOL_SIZE: usize = 8;
+
+pub mod ops {
     pub const HELLO: &str = "hello";
+    pub const CAPABILITIES: &str = "capabilities";
     pub const UNBUNDLE: &str = "unbundle";
     pub const HEADS: &str = "heads";
+    pub const BRANCHMAP: &str = "branchmap";
     pub const LOOKUP: &str = "lookup";
+    pub const LISTKEYS: &str = "listkeys";
+    pub const PUSHKEY: &str = "pushkey";
     pub const KNOWN: &str = "known";
     pub const BETWEEN: &str = "between";
     pub const GETBUNDLE: &str = "getbundle";
     pub const GETTREEPACK: &str = "gettreepack";
     pub const GETFILES: &str = "getfiles";
+    pub const GETFILE: &str = "getfile";
+    pub const GETPACKV1: &str = "getpackv1";
+    pub const CLONEBUNDLES: &str = "clonebundles";
+    pub const STREAMOUT: &str = "stream_out";
+    pub const BOOKMARKHISTORY: &str = "bookmarkhistory";
+    pub const API: &str = "api";
+
+    /// All the commands, which can be throttled
+    pub const ALL: &[&str] = &[
+        HELLO,
+        CAPABILITIES,
+        UNBUNDLE,
+        HEADS,
+        BRANCHMAP,
+        LOOKUP,
+        LISTKEYS,
+        PUSHKEY,
+        KNOWN,
+        BETWEEN,
+        GETBUNDLE,
+        GETTREEPACK,
+        GETFILES,
+        GETFILE,
+        GETPACKV1,
+        CLONEBUNDLES,
+        STREAMOUT,
+        BOOKMARKHISTORY,
+        API,
+    ];
+
+    /// What a client must be allowed to do on the repo to run the command `op`
+    pub fn action(op: &str) -> super::Action {
+        match op {
+            UNBUNDLE | PUSHKEY => super::Action::Write,
+            _ => super::Action::Read,
+        }
+    }
 }
 
 pub fn init_repo(
     parent_logger: &Logger,
-    repotype: &RepoType,
-    cache_size: usize,
+    config: RepoConfig,
     remote: &Remote,
-    repoid: RepositoryId,
-    scuba_table: Option<String>,
+    server_config: &ServerConfig,
+    live: &LiveConfig,
 ) -> Result<(PathBuf, HgRepo)> {
-    let repopath = repotype.path();
+    let repopath = config.repotype.path().to_owned();
 
     let mut sock = repopath.join(".hg");
 
-    let repo = HgRepo::new(
-        parent_logger,
-        repotype,
-        cache_size,
-        remote,
-        repoid,
-        scuba_table,
-    ).with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
+    let repo = HgRepo::new(parent_logger, config, remote, server_config, live)
+        .with_context(|_| format!("Failed to initialize repo {:?}", repopath))?;
 
     sock.push("mononoke.sock");
 
@@ -86,20 +173,34 @@ pub fn init_repo(
 }
 
 pub trait OpenableRepoType {
-    fn open(&self, logger: Logger, remote: &Remote, repoid: RepositoryId) -> Result<BlobRepo>;
+    fn open(
+        &self,
+        logger: Logger,
+        remote: &Remote,
+        repoid: RepositoryId,
+        blob_prefix: Option<String>,
+    ) -> Result<BlobRepo>;
     fn path(&self) -> &Path;
 }
 
 impl OpenableRepoType for RepoType {
-    fn open(&self, logger: Logger, remote: &Remote, repoid: RepositoryId) -> Result<BlobRepo> {
+    fn open(
+        &self,
+        logger: Logger,
+        remote: &Remote,
+        repoid: RepositoryId,
+        blob_prefix: Option<String>,
+    ) -> Result<BlobRepo> {
         use hgproto::ErrorKind;
         use metaconfig::repoconfig::RepoType::*;
 
         let ret = match *self {
             Revlog(_) => Err(ErrorKind::CantServeRevlogRepo)?,
-            BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid)?,
-            BlobRocks(ref path) => BlobRepo::new_rocksdb(logger, &path, repoid)?,
+            BlobFiles(ref path) => BlobRepo::new_files(logger, &path, repoid, blob_prefix)?,
+            BlobRocks(ref path) => BlobRepo::new_rocksdb(logger, &path, repoid, blob_prefix)?,
             TestBlobManifold(ref bucket, ref prefix, _) => {
+                // Manifold repos already namespace their keys with the manifold prefix
+                let prefix = format!("{}{}", prefix, blob_prefix.unwrap_or_default());
                 BlobRepo::new_test_manifold(logger, bucket, &prefix, remote, repoid)?
             }
         };
@@ -117,6 +218,30 @@ impl OpenableRepoType for RepoType {
     }
 }
 
+/// Keep the heads and bookmarks of `hgrepo` in the database `db_address`, so that all the servers
+/// sharing its blobstore see the same ones.
+fn with_db_state(hgrepo: BlobRepo, db_address: &str, repoid: RepositoryId) -> Result<BlobRepo> {
+    let params =
+        db::get_connection_params(db_address, db::InstanceRequirement::Master, None, None)?;
+    let heads = MysqlHeads::open_with_pool_size(params.clone(), repoid, DB_POOL_SIZE)?;
+    let bookmarks = DbBookmarks::new_async(params, &spawn_db_core()?).wait()?;
+    Ok(hgrepo.with_heads_and_bookmarks(Arc::new(heads), Arc::new(bookmarks)))
+}
+
+// The connection pool of DbBookmarks lives on a core, which the core of the repo can't be as it
+// doesn't run yet. This one runs for as long as the server does.
+fn spawn_db_core() -> Result<Remote> {
+    let (sender, receiver) = std_mpsc::channel();
+    thread::Builder::new()
+        .name("db".to_string())
+        .spawn(move || {
+            let mut core = Core::new().expect("failed to create tokio core");
+            let _ = sender.send(core.remote());
+            core.run(future::empty::<(), ()>()).expect("db core failed");
+        })?;
+    Ok(receiver.recv()?)
+}
+
 fn add_common_stats_and_send_to_scuba(
     scuba: Option<Arc<ScubaClient>>,
     sample: &mut ScubaSample,
@@ -135,33 +260,257 @@ fn add_common_stats_and_send_to_scuba(
 pub struct HgRepo {
     path: String,
     hgrepo: Arc<BlobRepo>,
-    repo_generation: RepoGenCache,
+    commit_graph: Arc<CommitGraph>,
+    heads_cache: Arc<HeadsCache>,
     scuba: Option<Arc<ScubaClient>>,
+    clonebundles_manifest: Option<PathBuf>,
+    // The revlog copy of the repo, and the threads its files are read on
+    streaming_clone: Option<(RevlogRepo, CpuPool)>,
+    // Commands which write to the repo are refused, see `ops::action`
+    readonly: bool,
+    disable_bundle_compression: bool,
+    disabled_bundle2_caps: Vec<String>,
+    acl: Arc<AclChecker>,
+    hooks: Vec<Arc<Hook>>,
+    throttle: Arc<Throttle>,
+    request_log: Option<Arc<JsonSink>>,
+}
+
+/// The commands implemented for every repo, whose capabilities come from `hgproto::registry`
+const COMMANDS: &[&str] = &[
+    "lookup",
+    "branchmap",
+    "known",
+    "pushkey",
+    "getbundle",
+    "batch",
+    "unbundle",
+    "gettreepack",
+    "getfiles",
+    "getpackv1",
+    "getfile",
+];
+
+/// Pick the compression of a getbundle response from the bundle2 capabilities of the client.
+fn getbundle_compression(bundlecaps: &[Vec<u8>]) -> Result<Option<CompressorType>> {
+    let caps = Capabilities::from_bundlecaps(bundlecaps)?;
+    Ok(caps.and_then(|caps| caps.compressor_type()))
+}
+
+/// The newest version of changegroup that a client can decode, 02 or 03, if its bundle2
+/// capabilities list the versions it can. Older clients don't list them, and only pull version 02
+/// anyway.
+fn max_changegroup_version(bundlecaps: &[Vec<u8>]) -> Result<CgVersion> {
+    if let Some(caps) = Capabilities::from_bundlecaps(bundlecaps)? {
+        if let Some(versions) = caps.get("changegroup") {
+            let supports = |version: CgVersion| versions.iter().any(|v| v == version.to_param());
+            if supports(CgVersion::Cg3Version) {
+                return Ok(CgVersion::Cg3Version);
+            }
+            if !supports(CgVersion::Cg2Version) {
+                let version = CgVersion::Cg2Version.to_param();
+                bail_err!(ErrorKind::UnsupportedChangegroup(version.to_string()));
+            }
+        }
+    }
+    Ok(CgVersion::Cg2Version)
+}
+
+/// Capability telling clients that they can ask for a streaming clone of `repo`, listing the
+/// formats they must support to use its store unless that's only revlogv1.
+fn streaming_clone_cap(repo: &RevlogRepo) -> String {
+    let formats: BTreeSet<_> = repo.get_requirements()
+        .iter()
+        .filter(|req| match **req {
+            Required::Revlogv1
+            | Required::Generaldelta
+            | Required::Treemanifest
+            | Required::Manifestv2 => true,
+            _ => false,
+        })
+        .map(|req| req.to_string())
+        .collect();
+
+    if formats.iter().all(|format| format == "revlogv1") {
+        "stream".to_string()
+    } else {
+        let formats: Vec<_> = formats.into_iter().collect();
+        format!("streamreqs={}", formats.join(","))
+    }
+}
+
+/// Encode the store of `repo` as a `stream_out` response: a status line, the number of files and
+/// their total size, then for each file its name, a NUL, its size, a newline and its content.
+/// The store is listed and its files are read on `pool`, off the threads serving requests.
+fn stream_store_files(
+    repo: RevlogRepo,
+    pool: CpuPool,
+) -> BoxFuture<BoxStream<Bytes, Error>, Error> {
+    pool.clone()
+        .spawn_fn(move || list_store_files(&repo, pool))
+        .boxify()
+}
+
+fn list_store_files(repo: &RevlogRepo, pool: CpuPool) -> Result<BoxStream<Bytes, Error>> {
+    let files = repo.get_store_files()?;
+
+    // Revlogs are only ever appended to, filelogs and manifests before the changelog. Sizing the
+    // changelog first and the filelogs last keeps the snapshot consistent while it's written to.
+    let mut sized = Vec::with_capacity(files.len());
+    for (name, path) in files.into_iter().rev() {
+        let size = fs::metadata(&path)
+            .with_context(|_| format!("cannot stat {}", path.display()))?
+            .len();
+        sized.push((name, path, size));
+    }
+    sized.reverse();
+
+    let total: u64 = sized.iter().map(|&(_, _, size)| size).sum();
+    let header = Bytes::from(format!("0\n{} {}\n", sized.len(), total));
+    let files = stream::iter_ok::<_, Error>(sized)
+        .map(move |(mut name, path, size)| {
+            name.push(b'\0');
+            name.extend_from_slice(format!("{}\n", size).as_bytes());
+            stream::once(Ok(Bytes::from(name))).chain(read_file_chunks(pool.clone(), path, size))
+        })
+        .flatten();
+
+    Ok(stream::once(Ok(header)).chain(files).boxify())
+}
+
+/// Read the first `size` bytes of the file at `path` in chunks, on `pool`. A chunk is only read
+/// once the previous one is consumed.
+fn read_file_chunks(pool: CpuPool, path: PathBuf, size: u64) -> BoxStream<Bytes, Error> {
+    let open = {
+        let path = path.clone();
+        pool.spawn_fn(move || -> Result<File> {
+            let file =
+                File::open(&path).with_context(|_| format!("cannot open {}", path.display()))?;
+            Ok(file)
+        })
+    };
+
+    open.map(move |file| {
+        stream::unfold((file, size), move |(mut file, remaining)| {
+            if remaining == 0 {
+                return None;
+            }
+            let path = path.clone();
+            Some(pool.spawn_fn(move || -> Result<_> {
+                let mut chunk = vec![0; cmp::min(remaining, STREAM_OUT_CHUNK_SIZE) as usize];
+                file.read_exact(&mut chunk)
+                    .with_context(|_| format!("cannot read {}", path.display()))?;
+                let remaining = remaining - chunk.len() as u64;
+                Ok((Bytes::from(chunk), (file, remaining)))
+            }))
+        })
+    }).flatten_stream()
+        .boxify()
+}
+
+/// Heads of each phase among `heads` and their ancestors, or the repo's heads if none are given,
+/// public first. The public changesets draft heads are based on are included, so that clients
+/// know where the public part of the history they pull ends.
+fn get_phase_heads(
+    repo: Arc<BlobRepo>,
+    heads: Vec<NodeHash>,
+) -> BoxFuture<BTreeSet<(Phase, NodeHash)>, Error> {
+    let heads = if heads.is_empty() {
+        repo.get_heads().collect().boxify()
+    } else {
+        future::ok(heads).boxify()
+    };
+
+    heads
+        .and_then(move |heads| {
+            let requested: HashSet<_> = heads.iter().cloned().collect();
+            loop_fn(
+                (heads, HashSet::new(), BTreeSet::new()),
+                move |(mut queue, mut seen, mut phase_heads)| {
+                    let node = match queue.pop() {
+                        Some(node) => node,
+                        None => return future::ok(Loop::Break(phase_heads)).boxify(),
+                    };
+                    if !seen.insert(node) {
+                        return future::ok(Loop::Continue((queue, seen, phase_heads))).boxify();
+                    }
+
+                    let is_head = requested.contains(&node);
+                    get_phase_and_draft_parents(&repo, node)
+                        .map(move |(phase, parents)| {
+                            if phase == Phase::Public || is_head {
+                                phase_heads.insert((phase, node));
+                            }
+                            queue.extend(parents);
+                            Loop::Continue((queue, seen, phase_heads))
+                        })
+                        .boxify()
+                },
+            )
+        })
+        .boxify()
 }
 
-fn wireprotocaps() -> Vec<String> {
-    vec![
-        "lookup".to_string(),
-        "known".to_string(),
-        "getbundle".to_string(),
-        "unbundle=HG10GZ,HG10BZ,HG10UN".to_string(),
-        "gettreepack".to_string(),
-        "remotefilelog".to_string(),
-    ]
+/// Draft changesets whose parents are all public.
+fn get_draft_roots(repo: Arc<BlobRepo>) -> BoxFuture<Vec<NodeHash>, Error> {
+    repo.get_draft_changesets()
+        .collect()
+        .and_then(move |drafts| {
+            let draft_set: HashSet<_> = drafts.iter().cloned().collect();
+            stream::iter_ok(drafts)
+                .and_then(move |node| {
+                    repo.get_changeset_by_changesetid(&ChangesetId::new(node))
+                        .map(move |cs| (node, cs))
+                })
+                .filter(move |&(_, ref cs)| {
+                    let (p1, p2) = cs.parents().get_nodes();
+                    !p1.into_iter().chain(p2).any(|p| draft_set.contains(p))
+                })
+                .map(|(node, _)| node)
+                .collect()
+        })
+        .boxify()
 }
 
-fn bundle2caps() -> String {
+/// The phase of `node`, and its parents if it's a draft. Public changesets only have public
+/// ancestors, so there's no need to look further.
+fn get_phase_and_draft_parents(
+    repo: &Arc<BlobRepo>,
+    node: NodeHash,
+) -> BoxFuture<(Phase, Vec<NodeHash>), Error> {
+    let repo = repo.clone();
+    repo.get_phase(&node)
+        .and_then(move |phase| match phase {
+            Phase::Public => future::ok((phase, vec![])).boxify(),
+            Phase::Draft => repo.get_changeset_by_changesetid(&ChangesetId::new(node))
+                .map(move |cs| {
+                    let (p1, p2) = cs.parents().get_nodes();
+                    (phase, p1.into_iter().chain(p2).cloned().collect())
+                })
+                .boxify(),
+        })
+        .boxify()
+}
+
+fn bundle2caps(disabled: &[String]) -> String {
     let caps = vec![
         ("HG20", vec![]),
         ("listkeys", vec![]),
-        ("changegroup", vec!["02"]),
+        ("pushkey", vec![]),
+        ("changegroup", vec!["02", "03"]),
         ("b2x:infinitepush", vec![]),
         ("b2x:infinitepushscratchbookmarks", vec![]),
+        ("b2x:rebase", vec![]),
+        ("phases", vec!["heads"]),
+        ("obsmarkers", vec!["V1"]),
     ];
 
     let mut encodedcaps = vec![];
 
     for &(ref key, ref value) in &caps {
+        if disabled.iter().any(|cap| cap == key) {
+            continue;
+        }
         let encodedkey = key.to_string();
         if value.len() > 0 {
             let encodedvalue = value.join(",");
@@ -174,26 +523,98 @@ fn bundle2caps() -> String {
     percent_encode(&encodedcaps.join("\n"))
 }
 
+/// The hooks run against every push, as configured for the repo
+fn push_hooks(config: &HooksConfig) -> Result<Vec<Arc<Hook>>> {
+    let mut hooks: Vec<Arc<Hook>> = Vec::new();
+    if let Some(ref regex) = config.commit_message_regex {
+        hooks.push(Arc::new(CommitMessageHook::new(regex)?));
+    }
+    if let Some(max_size) = config.max_file_size {
+        hooks.push(Arc::new(MaxFileSizeHook::new(max_size)));
+    }
+    if !config.banned_paths.is_empty() {
+        let banned = config
+            .banned_paths
+            .iter()
+            .map(MPath::new)
+            .collect::<Result<_>>()?;
+        hooks.push(Arc::new(BannedPathsHook::new(banned)));
+    }
+    Ok(hooks)
+}
+
 impl HgRepo {
     pub fn new(
         parent_logger: &Logger,
-        repo: &RepoType,
-        cache_size: usize,
+        config: RepoConfig,
         remote: &Remote,
-        repoid: RepositoryId,
-        scuba_table: Option<String>,
+        server_config: &ServerConfig,
+        live: &LiveConfig,
     ) -> Result<Self> {
-        let path = repo.path().to_owned();
+        let path = config.repotype.path().to_owned();
         let logger = parent_logger.new(o!("repo" => format!("{}", path.display())));
 
+        let repoid = RepositoryId::new(config.repoid);
+        let hgrepo = config
+            .repotype
+            .open(logger, remote, repoid, config.blob_prefix)?;
+        let hgrepo = if server_config.readonly {
+            hgrepo.into_readonly()
+        } else {
+            hgrepo
+        };
+        let hgrepo = match config.db_address {
+            Some(ref db_address) => with_db_state(hgrepo, db_address, repoid)?,
+            None => hgrepo,
+        };
+        let hgrepo = match config.blob_cache_size {
+            Some(size) => hgrepo.with_blob_cache(size),
+            None => hgrepo,
+        };
+        let hgrepo = match config.max_delta_chain {
+            Some(max_chain_len) => hgrepo.with_delta_storage(max_chain_len),
+            None => hgrepo,
+        };
+        let hgrepo = match config.oplog_path {
+            Some(ref path) => hgrepo.with_oplog(Arc::new(OpLog::open(path)?)),
+            None => hgrepo,
+        };
+        let hgrepo = hgrepo.with_protected_bookmarks(live.protected_bookmarks.clone());
+        let streaming_clone = match config.streaming_clone_repo {
+            Some(path) => Some((
+                RevlogRepo::open(path.join(".hg"))?,
+                CpuPool::new(STREAM_OUT_THREADS),
+            )),
+            None => None,
+        };
+
+        let hgrepo = Arc::new(hgrepo);
+
         Ok(HgRepo {
             path: format!("{}", path.display()),
-            hgrepo: Arc::new(repo.open(logger, remote, repoid)?),
-            repo_generation: RepoGenCache::new(cache_size),
-            scuba: match scuba_table {
+            commit_graph: Arc::new(CommitGraph::new(hgrepo.clone())),
+            heads_cache: Arc::new(HeadsCache::new(Duration::from_secs(
+                server_config
+                    .heads_cache_ttl_secs
+                    .unwrap_or(DEFAULT_HEADS_CACHE_TTL_SECS),
+            ))),
+            hgrepo,
+            scuba: match config.scuba_table {
                 Some(name) => Some(Arc::new(ScubaClient::new(name))),
                 None => None,
             },
+            clonebundles_manifest: config.clonebundles_manifest,
+            streaming_clone,
+            readonly: server_config.readonly,
+            disable_bundle_compression: server_config.disable_bundle_compression,
+            disabled_bundle2_caps: server_config.disabled_bundle2_caps.clone(),
+            acl: live.acl.clone(),
+            hooks: push_hooks(&config.hooks).with_context(|_| "invalid hooks config")?,
+            throttle: live.throttle.clone(),
+            request_log: match server_config.request_log_path {
+                Some(ref path) => Some(Arc::new(JsonSink::open(path)?)),
+                None => None,
+            },
         })
     }
 
@@ -201,11 +622,32 @@ impl HgRepo {
         &self.path
     }
 
+    pub fn blobrepo(&self) -> &Arc<BlobRepo> {
+        &self.hgrepo
+    }
+
     fn scuba_sample(&self, op: &str) -> ScubaSample {
         let mut sample = ScubaSample::new();
         sample.add("operation", op);
         sample
     }
+
+    fn capabilities(&self) -> Vec<String> {
+        let mut commands = COMMANDS.to_vec();
+        if self.readonly {
+            commands.retain(|command| ops::action(command) != Action::Write);
+        }
+        if self.clonebundles_manifest.is_some() {
+            commands.push("clonebundles");
+        }
+        let mut caps =
+            hgproto::registry::capabilities(commands).expect("all the commands are registered");
+        if let Some((ref repo, _)) = self.streaming_clone {
+            caps.push(streaming_clone_cap(repo));
+        }
+        caps.push(format!("bundle2={}", bundle2caps(&self.disabled_bundle2_caps)));
+        caps
+    }
 }
 
 impl Debug for HgRepo {
@@ -217,14 +659,128 @@ impl Debug for HgRepo {
 pub struct RepoClient {
     repo: Arc<HgRepo>,
     logger: Logger,
+    session: SessionContext,
 }
 
 impl RepoClient {
-    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger) -> Self {
+    // The logger is expected to carry the session already, see `SessionContext::logger`.
+    pub fn new(repo: Arc<HgRepo>, parent_logger: &Logger, session: SessionContext) -> Self {
         RepoClient {
             repo: repo,
-            logger: parent_logger.new(o!()), // connection details?
+            logger: parent_logger.new(o!()),
+            session,
+        }
+    }
+
+    // Stats of the client's commands are attributed to it
+    fn scuba_sample(&self, op: &str) -> ScubaSample {
+        let mut sample = self.repo.scuba_sample(op);
+        sample.add("identity", format!("{}", self.session.identity()));
+        sample
+    }
+
+    // Resolves once the client is allowed to do `action` on the repo, and fails otherwise.
+    fn authorize(&self, action: Action) -> BoxFuture<(), Error> {
+        let identity = self.session.identity().clone();
+        let path = self.repo.path.clone();
+        self.repo
+            .acl
+            .check(&identity, &path, action)
+            .and_then(move |allowed| {
+                if allowed {
+                    Ok(())
+                } else {
+                    Err(ErrorKind::PermissionDenied(identity, action, path).into())
+                }
+            })
+            .boxify()
+    }
+
+    // Resolves once the client is allowed to run the command `op`. Commands which write to the
+    // repo are refused right away if it's read-only, the ACL decides for the others.
+    fn authorize_op(&self, op: &'static str) -> BoxFuture<(), Error> {
+        let action = ops::action(op);
+        if self.repo.readonly && action == Action::Write {
+            let err = ErrorKind::ReadOnly(op.to_string(), self.repo.path.clone());
+            return future::err(err.into()).boxify();
         }
+        self.authorize(action)
+    }
+
+    // Runs the command `op` once the client is allowed to, see `authorize_op`, within the limits
+    // of the throttle, and logs its record once it's done, see the requestlog module. Every
+    // command goes through here or one of the functions below, so that none of them skips the
+    // ACL check. The ACL is checked before the command counts against the throttle, so that
+    // clients which aren't allowed can't use up the limits of those which are.
+    fn run_future<F>(&self, op: &'static str, summary: String, fut: F) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let throttle = self.repo.throttle.clone();
+        let fut = self.authorize_op(op)
+            .and_then(move |()| throttle.future(op, fut));
+        self.request_log(op, summary).future(fut)
+    }
+
+    // Like `run_future`, but the size of the response is logged too
+    fn run_bytes_future<F>(&self, op: &'static str, summary: String, fut: F) -> HgCommandRes<Bytes>
+    where
+        F: Future<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        let throttle = self.repo.throttle.clone();
+        let fut = self.authorize_op(op)
+            .and_then(move |()| throttle.future(op, fut));
+        self.request_log(op, summary).bytes_future(fut)
+    }
+
+    // Like `run_future`, for commands streaming their response
+    fn run_stream<S>(&self, op: &'static str, summary: String, s: S) -> BoxStream<Bytes, Error>
+    where
+        S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        let throttle = self.repo.throttle.clone();
+        let s = self.authorize_op(op)
+            .map(move |()| throttle.stream(op, s))
+            .flatten_stream();
+        self.request_log(op, summary).stream(s)
+    }
+
+    fn request_log(&self, op: &'static str, summary: String) -> RequestLog {
+        RequestLog::new(
+            &self.logger,
+            self.repo.request_log.clone(),
+            self.repo.path.clone(),
+            self.session.clone(),
+            op,
+            summary,
+        )
+    }
+
+    // The heads of the repo, which are cached as clients ask for them all the time
+    fn repo_heads(&self) -> BoxFuture<HashSet<NodeHash>, Error> {
+        let hgrepo = self.repo.hgrepo.clone();
+        self.repo.heads_cache.get(move || {
+            hgrepo
+                .get_heads()
+                .collect()
+                .map(|v| v.into_iter().collect())
+                .boxify()
+        })
+    }
+
+    /// Answer a request of the JSON API, which is throttled and logged like the commands.
+    pub fn api(&self, request: ApiRequest) -> BoxFuture<Bytes, Error> {
+        let summary = format!("{:?}", request);
+        let hgrepo = self.repo.hgrepo.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::API);
+
+        let res = api::handle(hgrepo, request)
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_bytes_future(ops::API, summary, res)
     }
 
     #[allow(dead_code)]
@@ -232,61 +788,67 @@ impl RepoClient {
         &self.logger
     }
 
-    fn create_bundle(&self, args: GetbundleArgs) -> hgproto::Result<HgCommandRes<Bytes>> {
-        let writer = Cursor::new(Vec::new());
+    fn create_bundle<W>(&self, args: GetbundleArgs, writer: W) -> hgproto::Result<HgCommandRes<W>>
+    where
+        W: AsyncWrite + Send + 'static,
+    {
         let mut bundle = Bundle2EncodeBuilder::new(writer);
         // Mercurial currently hangs while trying to read compressed bundles over the wire:
         // https://bz.mercurial-scm.org/show_bug.cgi?id=5646
-        // TODO: possibly enable compression support once this is fixed.
-        bundle.set_compressor_type(None);
+        // so only compress for clients which say they can decode the result.
+        let compression = if self.repo.disable_bundle_compression {
+            None
+        } else {
+            getbundle_compression(&args.bundlecaps)?
+        };
+        debug!(self.logger, "getbundle compression: {:?}", compression);
+        bundle.set_compressor_type(compression);
+        let cg_version = if args.cg {
+            max_changegroup_version(&args.bundlecaps)?
+        } else {
+            CgVersion::Cg2Version
+        };
 
-        let repo_generation = &self.repo.repo_generation;
         let hgrepo = &self.repo.hgrepo;
-
-        let ancestors_stream = |nodes: &Vec<NodeHash>| -> Box<NodeStream> {
-            let heads_ancestors = nodes.iter().map(|head| {
-                AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), *head).boxed()
-            });
-            Box::new(UnionNodeStream::new(
-                &hgrepo,
-                repo_generation.clone(),
-                heads_ancestors,
-            ))
+        let graph = self.repo.commit_graph.clone();
+        // No heads means all of them, like a clone. The common nodes are the heads of what the
+        // client has, whose discovery can end on changesets which this server doesn't have.
+        let heads = if args.heads.is_empty() {
+            self.repo_heads()
+                .map(|heads| heads.into_iter().collect())
+                .boxify()
+        } else {
+            future::ok(args.heads.clone()).boxify()
         };
+        let cg = args.cg;
+        let common = args.common.clone();
+        let revs = heads.and_then(move |heads| {
+            if !cg {
+                return future::ok((heads, Vec::new())).boxify();
+            }
+            graph
+                .known(common.clone())
+                .and_then(move |known| {
+                    let common = common
+                        .into_iter()
+                        .zip(known)
+                        .filter_map(|(node, known)| if known { Some(node) } else { None })
+                        .collect();
+                    graph
+                        .range(heads.clone(), common)
+                        .map(move |nodestosend| (heads, nodestosend))
+                })
+                .boxify()
+        });
 
-        let heads_ancestors = ancestors_stream(&args.heads);
-        let common_ancestors = ancestors_stream(&args.common);
-
-        let nodestosend = Box::new(SetDifferenceNodeStream::new(
-            hgrepo,
-            repo_generation.clone(),
-            heads_ancestors,
-            common_ancestors,
-        ));
-
-        // TODO(stash): avoid collecting all the changelogs in the vector - T25767311
-        let nodestosend = nodestosend
-            .collect()
-            .map(|nodes| stream::iter_ok(nodes.into_iter().rev()))
-            .flatten_stream();
-
-        let changelogentries = nodestosend
-            .and_then({
-                let hgrepo = hgrepo.clone();
-                move |node| hgrepo.get_changeset_by_changesetid(&ChangesetId::new(node))
-            })
-            .and_then(|cs| {
-                let mut v = Vec::new();
-                mercurial::changeset::serialize_cs(&cs, &mut v)?;
-                let parents = cs.parents().get_nodes();
-                Ok(BlobNode::new(Bytes::from(v), parents.0, parents.1))
-            });
-
-        bundle.add_part(parts::changegroup_part(changelogentries)?);
+        // Shallow clients fetch trees and file contents separately, with gettreepack and
+        // getfiles, so only send them to full clients
+        let send_manifests = !args.bundlecaps.contains(&BUNDLECAP_TREEONLY.to_vec());
+        let send_files = !args.bundlecaps.contains(&BUNDLECAP_REMOTEFILELOG.to_vec());
 
         // TODO: generalize this to other listkey types
         // (note: just calling &b"bookmarks"[..] doesn't work because https://fburl.com/0p0sq6kp)
-        if args.listkeys.contains(&b"bookmarks".to_vec()) {
+        let listkeys = if args.listkeys.contains(&b"bookmarks".to_vec()) {
             let hgrepo = self.repo.hgrepo.clone();
             let bookmark_names = hgrepo.get_bookmark_keys();
             let items = bookmark_names.and_then(move |name| {
@@ -306,38 +868,99 @@ impl RepoClient {
                     }
                 })
             });
-            bundle.add_part(parts::listkey_part("bookmarks", items)?);
-        }
+            Some(parts::listkey_part("bookmarks", items)?)
+        } else {
+            None
+        };
+        // Mercurial only sends the markers relevant to the changesets which are pulled, but
+        // finding those means walking their whole history, so all of them are sent instead.
+        // Clients keep markers for changesets they don't have.
+        let obsmarkers = if args.obsmarkers {
+            hgrepo
+                .get_obsmarkers()
+                .collect()
+                .and_then(|markers| {
+                    if markers.is_empty() {
+                        Ok(None)
+                    } else {
+                        parts::obsmarkers_part(markers).map(Some)
+                    }
+                })
+                .boxify()
+        } else {
+            future::ok(None).boxify()
+        };
         // TODO(stash): handle includepattern= and excludepattern=
 
-        let encode_fut = bundle.build();
+        let phases = args.phases;
+        let hgrepo = hgrepo.clone();
+        let cg_and_phases = revs.and_then(move |(heads, nodestosend)| {
+            // Clients which only want listkeys or phases say so with cg=0
+            let changegroup = if cg {
+                create_changegroup(
+                    hgrepo.clone(),
+                    cg_version,
+                    nodestosend,
+                    send_manifests,
+                    send_files,
+                ).map(Some)
+                    .boxify()
+            } else {
+                future::ok(None).boxify()
+            };
+            let phase_heads = if phases {
+                get_phase_heads(hgrepo, heads)
+                    .and_then(|heads| {
+                        let heads = heads.into_iter().map(|(phase, node)| (phase.as_u32(), node));
+                        parts::phase_heads_part(heads)
+                    })
+                    .map(Some)
+                    .boxify()
+            } else {
+                future::ok(None).boxify()
+            };
+            changegroup.join(phase_heads)
+        });
 
-        Ok(encode_fut
-            .map(|cursor| Bytes::from(cursor.into_inner()))
+        Ok(cg_and_phases
+            .join(obsmarkers)
+            .and_then(move |((changegroup, phase_heads), obsmarkers)| {
+                if let Some(changegroup) = changegroup {
+                    bundle.add_part(changegroup);
+                }
+                if let Some(listkeys) = listkeys {
+                    bundle.add_part(listkeys);
+                }
+                if let Some(phase_heads) = phase_heads {
+                    bundle.add_part(phase_heads);
+                }
+                if let Some(obsmarkers) = obsmarkers {
+                    bundle.add_part(obsmarkers);
+                }
+                bundle.build()
+            })
             .from_err()
             .boxify())
     }
 
     fn gettreepack_untimed(&self, params: GettreepackArgs) -> HgCommandRes<Bytes> {
-        info!(self.logger, "gettreepack {:?}", params);
-
-        if !params.directories.is_empty() {
-            // This param is not used by core hg, don't worry about implementing it now
-            return Err(err_msg("directories param is not supported"))
-                .into_future()
-                .boxify();
-        }
+        debug!(self.logger, "gettreepack {:?}", params);
 
         // TODO(stash): T25850889 only one basemfnodes is used. That means that trees that client
         // already has can be sent to the client.
         let basemfnode = params.basemfnodes.get(0).unwrap_or(&NULL_HASH);
 
-        if params.rootdir.len() != 0 {
-            // For now, only root repo
-            return Err(err_msg("only empty rootdir is supported"))
-                .into_future()
-                .boxify();
-        }
+        // mfnodes and basemfnodes are the nodes of the trees at rootdir
+        let rootpath = match MPath::new(&params.rootdir) {
+            Ok(rootpath) => rootpath,
+            Err(err) => return future::err(err).boxify(),
+        };
+        // If directories are given, only the trees at those paths are sent
+        let directories: HashSet<Vec<u8>> = params
+            .directories
+            .iter()
+            .map(|dir| dir.to_vec())
+            .collect();
 
         let writer = Cursor::new(Vec::new());
         let mut bundle = Bundle2EncodeBuilder::new(writer);
@@ -351,16 +974,29 @@ impl RepoClient {
         let changed_entries = params.mfnodes.iter().fold(
             stream::empty().boxify(),
             |cur_stream, manifest_id| {
-                let new_stream =
-                    get_changed_entry_stream(self.repo.hgrepo.clone(), manifest_id, basemfnode);
+                let new_stream = get_changed_entry_stream(
+                    self.repo.hgrepo.clone(),
+                    manifest_id,
+                    basemfnode,
+                    rootpath.clone(),
+                );
                 cur_stream.select(new_stream).boxify()
             },
         );
 
         let changed_entries = changed_entries.filter({
             let mut used_hashes = HashSet::new();
-            move |entry| used_hashes.insert(*entry.0.get_hash())
+            move |entry| {
+                let path = entry.2.join_element(entry.0.get_name());
+                (directories.is_empty() || directories.contains(&path.to_vec()))
+                    && used_hashes.insert(*entry.0.get_hash())
+            }
         });
+        let changed_entries = prefetch_batches(
+            self.repo.hgrepo.clone(),
+            changed_entries,
+            |entry| entry.0.get_hash().into_nodehash(),
+        );
 
         parts::treepack_part(changed_entries)
             .into_future()
@@ -377,67 +1013,20 @@ impl RepoClient {
 impl HgCommands for RepoClient {
     // @wireprotocommand('between', 'pairs')
     fn between(&self, pairs: Vec<(NodeHash, NodeHash)>) -> HgCommandRes<Vec<Vec<NodeHash>>> {
-        info!(self.logger, "between pairs {:?}", pairs);
-
-        struct ParentStream<CS> {
-            repo: Arc<HgRepo>,
-            n: NodeHash,
-            bottom: NodeHash,
-            wait_cs: Option<CS>,
-        };
-
-        impl<CS> ParentStream<CS> {
-            fn new(repo: &Arc<HgRepo>, top: NodeHash, bottom: NodeHash) -> Self {
-                ParentStream {
-                    repo: repo.clone(),
-                    n: top,
-                    bottom: bottom,
-                    wait_cs: None,
-                }
-            }
-        }
-
-        impl Stream for ParentStream<BoxFuture<BlobChangeset, hgproto::Error>> {
-            type Item = NodeHash;
-            type Error = hgproto::Error;
-
-            fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-                if self.n == self.bottom || self.n == NULL_HASH {
-                    return Ok(Async::Ready(None));
-                }
-
-                self.wait_cs = self.wait_cs.take().or_else(|| {
-                    Some(
-                        self.repo
-                            .hgrepo
-                            .get_changeset_by_changesetid(&ChangesetId::new(self.n)),
-                    )
-                });
-                let cs = try_ready!(self.wait_cs.as_mut().unwrap().poll());
-                self.wait_cs = None; // got it
-
-                let p = match cs.parents() {
-                    &Parents::None => NULL_HASH,
-                    &Parents::One(ref p) => *p,
-                    &Parents::Two(ref p, _) => *p,
-                };
-
-                let prev_n = mem::replace(&mut self.n, p);
-
-                Ok(Async::Ready(Some(prev_n)))
-            }
-        }
+        let summary = format!("{} pairs", pairs.len());
 
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::BETWEEN);
+        let mut sample = self.scuba_sample(ops::BETWEEN);
 
-        // TODO(jsgf): do pairs in parallel?
+        // The pairs often have common ancestors, whose parents are only fetched once by the
+        // commit graph
         // TODO: directly return stream of streams
-        let repo = self.repo.clone();
-        stream::iter_ok(pairs.into_iter())
-            .and_then(move |(top, bottom)| {
+        let graph = self.repo.commit_graph.clone();
+        let res = stream::iter_ok(pairs.into_iter().enumerate())
+            .map(move |(idx, (top, bottom))| {
                 let mut f = 1;
-                ParentStream::new(&repo, top, bottom)
+                graph
+                    .first_parents(top, bottom)
                     .enumerate()
                     .filter(move |&(i, _)| {
                         if i == f {
@@ -449,12 +1038,63 @@ impl HgCommands for RepoClient {
                     })
                     .map(|(_, v)| v)
                     .collect()
+                    .map(move |nodes| (idx, nodes))
             })
+            .buffer_unordered(BETWEEN_PAIRS_IN_FLIGHT)
             .collect()
+            // The answers are expected in the order of the pairs
+            .map(|mut answers: Vec<(usize, Vec<NodeHash>)>| {
+                answers.sort_by_key(|&(idx, _)| idx);
+                answers.into_iter().map(|(_, nodes)| nodes).collect()
+            })
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_future(ops::BETWEEN, summary, res)
+    }
+
+    // @wireprotocommand('clonebundles', '')
+    fn clonebundles(&self) -> HgCommandRes<String> {
+        // No manifest means no clone bundles, and clients do a normal clone. It's only read once
+        // the client is authorized.
+        let path = self.repo.clonebundles_manifest.clone();
+        let manifest = future::lazy(move || -> Result<String> {
+            let mut manifest = String::new();
+            if let Some(path) = path {
+                File::open(&path)
+                    .and_then(|mut file| file.read_to_string(&mut manifest))
+                    .with_context(|_| format!("cannot read {}", path.display()))?;
+            }
+            Ok(manifest)
+        });
+
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::CLONEBUNDLES);
+        let res = manifest
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_future(ops::CLONEBUNDLES, String::new(), res)
+    }
+
+    // @wireprotocommand('stream_out')
+    fn stream_out(&self) -> BoxStream<Bytes, Error> {
+        let (repo, pool) = match self.repo.streaming_clone {
+            Some((ref repo, ref pool)) => (repo.clone(), pool.clone()),
+            // Only a client ignoring the capabilities gets here. 1 means the operation is
+            // forbidden.
+            None => return stream::once(Ok(Bytes::from(&b"1\n"[..]))).boxify(),
+        };
+
+        // Only listing the store is timed, the files are read as the client consumes them
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::STREAMOUT);
+        let res = stream_store_files(repo, pool)
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
             })
-            .boxify()
+            .flatten_stream();
+        self.run_stream(ops::STREAMOUT, String::new(), res)
     }
 
     // @wireprotocommand('changegroup', 'roots')
@@ -462,7 +1102,46 @@ impl HgCommands for RepoClient {
         // TODO: streaming something
         info!(self.logger, "changegroup roots {:?}", roots);
 
-        future::ok(()).boxify()
+        // Not run like the other commands, but still only answered to readers
+        self.authorize(Action::Read)
+    }
+
+    // @wireprotocommand('branchmap')
+    fn branchmap(&self) -> HgCommandRes<HashMap<String, HashSet<NodeHash>>> {
+        // Only the repo heads are grouped by branch. A branch head with children on other
+        // branches is left out, which saves walking the whole history.
+        let hgrepo = self.repo.hgrepo.clone();
+        let logger = self.logger.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::BRANCHMAP);
+        let res = hgrepo
+            .get_heads()
+            .and_then({
+                let hgrepo = hgrepo.clone();
+                move |head| {
+                    hgrepo
+                        .get_changeset_by_changesetid(&ChangesetId::new(head))
+                        .map(move |cs| {
+                            let branch = match cs.extra().get(&b"branch"[..]) {
+                                Some(branch) => String::from_utf8_lossy(branch).into_owned(),
+                                None => "default".to_string(),
+                            };
+                            (branch, head)
+                        })
+                }
+            })
+            .fold(HashMap::new(), |mut branchmap, (branch, head)| {
+                branchmap
+                    .entry(branch)
+                    .or_insert_with(HashSet::new)
+                    .insert(head);
+                Ok::<_, Error>(branchmap)
+            })
+            .inspect(move |resp| debug!(logger, "branchmap response: {:?}", resp))
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_future(ops::BRANCHMAP, String::new(), res)
     }
 
     // @wireprotocommand('heads')
@@ -471,161 +1150,271 @@ impl HgCommands for RepoClient {
         // TODO: directly return stream of heads
         let logger = self.logger.clone();
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::HEADS);
-        self.repo
-            .hgrepo
-            .get_heads()
-            .collect()
-            .from_err()
-            .and_then(|v| Ok(v.into_iter().collect()))
+        let mut sample = self.scuba_sample(ops::HEADS);
+        let res = self.repo_heads()
             .inspect(move |resp| debug!(logger, "heads response: {:?}", resp))
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-            })
-            .boxify()
+            });
+        self.run_future(ops::HEADS, String::new(), res)
     }
 
     // @wireprotocommand('lookup', 'key')
     fn lookup(&self, key: String) -> HgCommandRes<Bytes> {
-        // TODO(stash): T25928839 lookup should support bookmarks and prefixes too
+        let summary = key.clone();
+        // Like Mercurial, try the key as a full hash, then as a bookmark, then as a hash prefix
         let repo = self.repo.hgrepo.clone();
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::LOOKUP);
-        NodeHash::from_str(&key)
-            .into_future()
-            .and_then(move |node| {
-                let csid = ChangesetId::new(node);
-                repo.changeset_exists(&csid)
-                    .map(move |exists| (node, exists))
+        let mut sample = self.scuba_sample(ops::LOOKUP);
+
+        let full_hash = match NodeHash::from_str(&key) {
+            Ok(node) => repo.changeset_exists(&ChangesetId::new(node))
+                .map(move |exists| if exists { Some(node) } else { None })
+                .boxify(),
+            Err(_) => future::ok(None).boxify(),
+        };
+
+        let res = full_hash
+            .and_then({
+                let repo = repo.clone();
+                let key = key.clone();
+                move |found| match found {
+                    Some(node) => future::ok(Some(node)).boxify(),
+                    None => repo.get_bookmark_value(&key)
+                        .map(|value| value.map(|(csid, _)| csid.into_nodehash()))
+                        .boxify(),
+                }
             })
-            .and_then(|(node, exists)| {
-                if exists {
-                    let mut buf = BytesMut::with_capacity(node.to_hex().len() + 3);
-                    buf.put(b'1');
-                    buf.put(b' ');
-                    buf.extend_from_slice(node.to_hex().as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
-                } else {
-                    let err_msg = format!("{} not found", node);
-                    let mut buf = BytesMut::with_capacity(err_msg.len() + 3);
-                    buf.put(b'0');
-                    buf.put(b' ');
-                    buf.extend_from_slice(err_msg.as_bytes());
-                    buf.put(b'\n');
-                    Ok(buf.freeze())
+            .and_then({
+                let key = key.clone();
+                move |found| -> BoxFuture<LookupResult, Error> {
+                    match found {
+                        Some(node) => future::ok(LookupResult::Found(node)).boxify(),
+                        None => lookup_prefix(&repo, &key),
+                    }
                 }
             })
+            .map(move |res| {
+                let (success, msg) = match res {
+                    LookupResult::Found(node) => ('1', node.to_hex().to_string()),
+                    LookupResult::Ambiguous => ('0', format!("{}: ambiguous identifier", key)),
+                    LookupResult::Unknown => ('0', format!("unknown revision '{}'", key)),
+                };
+                let mut buf = BytesMut::with_capacity(msg.len() + 3);
+                buf.put(success as u8);
+                buf.put(b' ');
+                buf.extend_from_slice(msg.as_bytes());
+                buf.put(b'\n');
+                buf.freeze()
+            })
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-            })
-            .boxify()
+            });
+        self.run_bytes_future(ops::LOOKUP, summary, res)
     }
 
-    // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
-    fn known(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<bool>> {
-        info!(self.logger, "known: {:?}", nodes);
-        let repo_generation = &self.repo.repo_generation;
-        let hgrepo = &self.repo.hgrepo;
+    // Not a Mercurial command. Each move of the bookmark `key` is a line of tab-separated fields:
+    // the time it was made at, in seconds since the epoch, where it moved from and to (empty when
+    // the bookmark didn't or doesn't exist any more), who moved it and why.
+    fn bookmarkhistory(&self, key: Bytes) -> HgCommandRes<Bytes> {
+        let summary = String::from_utf8_lossy(&key).into_owned();
+        let hgrepo = self.repo.hgrepo.clone();
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::KNOWN);
+        let mut sample = self.scuba_sample(ops::BOOKMARKHISTORY);
 
-        // Ultimately, this block takes all ancestors of heads in this repo intersected with
-        // the nodes passed in by the client, and then returns a Vec<bool>, true if the
-        // intersection contains the matching node in nodes, false if it does not.
-        // Note that revsets are lazy, and will not generate unnecessary nodes.
-        hgrepo
-            .get_heads()
-            // Convert Stream<Heads> into Stream<Ancestors<Heads>>
-            .map({
-                let repo_generation = repo_generation.clone();
-                let hgrepo = hgrepo.clone();
-                move |hash| AncestorsNodeStream::new(&hgrepo, repo_generation.clone(), hash).boxed()
-            })
-            // Convert Stream<Ancestors<Heads>>> into Future<Vec<Ancestors<Heads>>>
+        let res = hgrepo
+            .get_bookmark_history(&key)
             .collect()
-            // Do the next few steps inside the Future; the parameter to the closure is
-            // Vec<Ancestors<Heads>>
-            .map({
-                let repo_generation = repo_generation.clone();
-                let hgrepo = hgrepo.clone();
-                let nodes = nodes.clone();
-                move |vec| {
-                    // Intersect the union of the Vec<Ancestors<Heads>> that's passed in, with
-                    // a union of the known nodes the client asked about.
-                    IntersectNodeStream::new(
-                        &hgrepo,
-                        repo_generation.clone(),
-                        vec![
-                            // This is the union of all ancestors of heads
-                            UnionNodeStream::new(&hgrepo, repo_generation.clone(), vec).boxed(),
-                            // This is the union of all passed in nodes.
-                            UnionNodeStream::new(
-                                &hgrepo,
-                                repo_generation,
-                                nodes.into_iter().map({
-                                    let hgrepo = hgrepo.clone();
-                                    move |node| SingleNodeHash::new(node, &hgrepo).boxed()
-                                }),
-                            ).boxed(),
-                        ],
-                        // collect() below will result in a Future<Vec<NodeHash>> which is all
-                        // nodes that are both an ancestor of a get_heads() head and were
-                        // passed in by the client
-                    ).collect()
-                         .from_err::<hgproto::Error>()
+            .map(|entries| {
+                let hex = |value: Option<ChangesetId>| {
+                    value.map_or(String::new(), |value| value.to_hex().to_string())
+                };
+                let mut out = String::new();
+                for entry in entries {
+                    out.push_str(&format!(
+                        "{}\t{}\t{}\t{}\t{}\n",
+                        entry.timestamp,
+                        hex(entry.from),
+                        hex(entry.to),
+                        entry.author,
+                        entry.reason,
+                    ));
                 }
+                Bytes::from(out)
             })
-            // We have a Future<Future<Vec<NodeHash>>> - collapse one layer of Future.
-            .flatten()
-            // Finally, within the Future, use the Vec<NodeHash> that's only nodes that were
-            // passed in by the client and that are ancestors of a get_heads() head to convert
-            // the Vec of client known nodes to a Vec<bool> telling the client if we also
-            // know of the nodes it asked us about.
-            .map(move |known| {
-                nodes
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_bytes_future(ops::BOOKMARKHISTORY, summary, res)
+    }
+
+    // @wireprotocommand('listkeys', 'namespace')
+    fn listkeys(&self, namespace: String) -> HgCommandRes<HashMap<Vec<u8>, Vec<u8>>> {
+        let summary = namespace.clone();
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::LISTKEYS);
+
+        let keys = match namespace.as_str() {
+            "bookmarks" => {
+                let hgrepo = self.repo.hgrepo.clone();
+                hgrepo
+                    .get_bookmark_keys()
+                    .and_then(move |name| {
+                        hgrepo
+                            .get_bookmark_value(&name)
+                            .map(move |value| value.map(|(csid, _)| (name, csid)))
+                    })
+                    // Skip bookmarks deleted while listing them
+                    .filter_map(|bookmark| bookmark)
+                    .fold(HashMap::new(), |mut bookmarks, (name, csid)| {
+                        bookmarks.insert(name, csid.to_hex().into());
+                        Ok::<_, Error>(bookmarks)
+                    })
+                    .boxify()
+            }
+            // Clients of a publishing server ignore the draft roots and make everything they
+            // pull public, so only claim to be one while there are no drafts
+            "phases" => get_draft_roots(self.repo.hgrepo.clone())
+                .map(|roots| {
+                    let mut phases: HashMap<Vec<u8>, Vec<u8>> = roots
+                        .into_iter()
+                        .map(|root| (root.to_hex().into(), b"1".to_vec()))
+                        .collect();
+                    if phases.is_empty() {
+                        phases.insert(b"publishing".to_vec(), b"True".to_vec());
+                    }
+                    phases
+                })
+                .boxify(),
+            "obsolete" => self.repo
+                .hgrepo
+                .get_obsmarkers()
+                .collect()
+                .and_then(|markers| {
+                    let keys: HashMap<Vec<u8>, Vec<u8>> =
+                        obsmarker::encode_pushkey_markers(&markers)?
+                            .into_iter()
+                            .map(|(key, value)| (key.into_bytes(), value.into_bytes()))
+                            .collect();
+                    Ok(keys)
+                })
+                .boxify(),
+            "namespaces" => {
+                let namespaces = ["bookmarks", "namespaces", "obsolete", "phases"];
+                let namespaces: HashMap<Vec<u8>, Vec<u8>> = namespaces
                     .iter()
-                    .map(|node| known.contains(node))
-                    .collect::<Vec<bool>>()
-            })
+                    .map(|namespace| (namespace.as_bytes().to_vec(), vec![]))
+                    .collect();
+                future::ok(namespaces).boxify()
+            }
+            // Like Mercurial, unknown namespaces are empty
+            _ => future::ok(HashMap::new()).boxify(),
+        };
+
+        let res = keys.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        });
+        self.run_future(ops::LISTKEYS, summary, res)
+    }
+
+    // @wireprotocommand('pushkey', 'namespace key old new')
+    fn pushkey(
+        &self,
+        namespace: String,
+        key: Bytes,
+        old: Bytes,
+        new: Bytes,
+    ) -> HgCommandRes<bool> {
+        let summary = format!("{} {:?} {:?} -> {:?}", namespace, key, old, new);
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::PUSHKEY);
+        let hgrepo = self.repo.hgrepo.clone();
+        let heads_cache = self.repo.heads_cache.clone();
+        let author = format!("{}", self.session.identity());
+        // Nothing is written before the client is authorized
+        let moves_bookmark = namespace == "bookmarks";
+        let res = future::lazy(move || {
+            bundle2_resolver::apply_pushkey(hgrepo, namespace.as_bytes(), key, &old, &new, &author)
+        }).then(move |res| {
+            if moves_bookmark {
+                heads_cache.invalidate();
+            }
+            res
+        });
+
+        let res = res.timed(move |stats, _| {
+            add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+        });
+        self.run_future(ops::PUSHKEY, summary, res)
+    }
+
+    // @wireprotocommand('known', 'nodes *'), but the '*' is ignored
+    fn known(&self, nodes: Vec<NodeHash>) -> HgCommandRes<Vec<bool>> {
+        let summary = format!("{} nodes", nodes.len());
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::KNOWN);
+
+        // Like Mercurial, a node is known if its changeset is in the repo. Discovery asks about
+        // many nodes at once, so the ones not in the commit graph yet are looked up together.
+        let res = self.repo
+            .commit_graph
+            .known(nodes)
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-            })
-            .boxify()
+            });
+        self.run_future(ops::KNOWN, summary, res)
     }
 
     // @wireprotocommand('getbundle', '*')
-    fn getbundle(&self, args: GetbundleArgs) -> HgCommandRes<Bytes> {
-        info!(self.logger, "Getbundle: {:?}", args);
+    fn getbundle(&self, args: GetbundleArgs) -> BoxStream<Bytes, Error> {
+        debug!(self.logger, "Getbundle: {:?}", args);
+        let summary = format!("{} heads, {} common", args.heads.len(), args.common.len());
 
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::GETBUNDLE);
+        let mut sample = self.scuba_sample(ops::GETBUNDLE);
 
-        match self.create_bundle(args) {
+        // The bundle is sent as it's encoded instead of being built in memory first
+        let (writer, chunks) = ChannelWriter::new(GETBUNDLE_CHUNKS_IN_FLIGHT);
+        let encode = match self.create_bundle(args, writer) {
             Ok(res) => res,
             Err(err) => Err(err).into_future().boxify(),
         }.timed(move |stats, _| {
             add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
         })
-            .boxify()
+            // Dropping the writer ends the stream of chunks
+            .map(|_writer| None);
+
+        let chunks = chunks
+            .map(Some)
+            .map_err(|()| err_msg("getbundle chunks channel failed"))
+            .select(encode.into_stream())
+            .filter_map(|chunk| chunk);
+        self.run_stream(ops::GETBUNDLE, summary, chunks)
     }
 
     // @wireprotocommand('hello')
     fn hello(&self) -> HgCommandRes<HashMap<String, Vec<String>>> {
-        info!(self.logger, "Hello -> capabilities");
-
         let mut res = HashMap::new();
-        let mut caps = wireprotocaps();
-        caps.push(format!("bundle2={}", bundle2caps()));
-        res.insert("capabilities".to_string(), caps);
+        res.insert("capabilities".to_string(), self.repo.capabilities());
 
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::HELLO);
-        future::ok(res)
+        let mut sample = self.scuba_sample(ops::HELLO);
+        let res = future::ok(res)
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-            })
-            .boxify()
+            });
+        self.run_future(ops::HELLO, String::new(), res)
+    }
+
+    // @wireprotocommand('capabilities')
+    fn capabilities(&self) -> HgCommandRes<Vec<String>> {
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::CAPABILITIES);
+        let res = future::ok(self.repo.capabilities())
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_future(ops::CAPABILITIES, String::new(), res)
     }
 
     // @wireprotocommand('unbundle')
@@ -634,60 +1423,463 @@ impl HgCommands for RepoClient {
         heads: Vec<String>,
         stream: BoxStream<Bundle2Item, Error>,
     ) -> HgCommandRes<Bytes> {
-        let res = bundle2_resolver::resolve(
-            self.repo.hgrepo.clone(),
-            self.logger.new(o!("command" => "unbundle")),
-            heads,
-            stream,
-        );
+        let summary = format!("{} heads", heads.len());
+        let hgrepo = self.repo.hgrepo.clone();
+        let hooks = self.repo.hooks.clone();
+        let logger = self.logger.new(o!("command" => "unbundle"));
+        let heads_cache = self.repo.heads_cache.clone();
+        let author = format!("{}", self.session.identity());
+        // Nothing is read from the bundle before the client is authorized
+        let res = future::lazy(move || {
+            bundle2_resolver::resolve(hgrepo, logger, heads, stream, hooks, author)
+        })
+            // Even a failed push may have landed some of its changesets
+            .then(move |res| {
+                heads_cache.invalidate();
+                res
+            });
 
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::UNBUNDLE);
+        let mut sample = self.scuba_sample(ops::UNBUNDLE);
 
-        res.timed(move |stats, _| {
+        let res = res.timed(move |stats, _| {
             add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-        }).boxify()
+        });
+        self.run_bytes_future(ops::UNBUNDLE, summary, res)
     }
 
     // @wireprotocommand('gettreepack', 'rootdir mfnodes basemfnodes directories')
     fn gettreepack(&self, params: GettreepackArgs) -> HgCommandRes<Bytes> {
+        let summary = format!(
+            "{} mfnodes, {} basemfnodes, {} directories",
+            params.mfnodes.len(),
+            params.basemfnodes.len(),
+            params.directories.len()
+        );
         let scuba = self.repo.scuba.clone();
-        let mut sample = self.repo.scuba_sample(ops::GETTREEPACK);
+        let mut sample = self.scuba_sample(ops::GETTREEPACK);
 
-        return self.gettreepack_untimed(params)
+        let res = self.gettreepack_untimed(params)
             .timed(move |stats, _| {
                 add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
-            })
-            .boxify();
+            });
+        self.run_bytes_future(ops::GETTREEPACK, summary, res)
     }
 
     // @wireprotocommand('getfiles', 'files*')
     fn getfiles(&self, params: BoxStream<(NodeHash, MPath), Error>) -> BoxStream<Bytes, Error> {
-        info!(self.logger, "getfiles");
         let repo = self.repo.clone();
-        params
-            .and_then(move |(node, path)| {
+        let identity = format!("{}", self.session.identity());
+        let blobs = params.and_then(move |(node, path)| {
+            let repo = repo.clone();
+            let identity = identity.clone();
+            create_remotefilelog_blob(repo.hgrepo.clone(), node, path).timed(move |stats, _| {
+                let mut sample = repo.scuba_sample(ops::GETFILES);
+                sample.add("identity", identity);
+                add_common_stats_and_send_to_scuba(repo.scuba.clone(), &mut sample, &stats);
+            })
+        });
+        self.run_stream(ops::GETFILES, String::new(), blobs)
+    }
+
+    // @wireprotocommand('getfile', 'file node')
+    fn getfile(&self, file: MPath, node: NodeHash) -> HgCommandRes<Bytes> {
+        let summary = format!("{} {}", file, node);
+
+        // The response is an error code, then NUL, then the blob. Errors fail the whole command
+        // here instead of getting a non-zero code.
+        let blob = if node == NULL_HASH {
+            future::ok(Bytes::new()).boxify()
+        } else {
+            create_remotefilelog_blob(self.repo.hgrepo.clone(), node, file)
+        };
+
+        let scuba = self.repo.scuba.clone();
+        let mut sample = self.scuba_sample(ops::GETFILE);
+        let res = blob
+            .map(|blob| {
+                let mut res = BytesMut::with_capacity(blob.len() + 2);
+                res.put_slice(b"0\0");
+                res.put(blob);
+                res.freeze()
+            })
+            .timed(move |stats, _| {
+                add_common_stats_and_send_to_scuba(scuba, &mut sample, &stats);
+            });
+        self.run_bytes_future(ops::GETFILE, summary, res)
+    }
+
+    // @wireprotocommand('getpackv1')
+    fn getpackv1(
+        &self,
+        params: BoxStream<(MPath, Vec<NodeHash>), Error>,
+    ) -> BoxStream<Bytes, Error> {
+        let repo = self.repo.clone();
+        let identity = format!("{}", self.session.identity());
+        let parts = params
+            .and_then(move |(path, nodes)| {
                 let repo = repo.clone();
-                create_remotefilelog_blob(repo.hgrepo.clone(), node, path).timed(move |stats, _| {
-                    let mut sample = repo.scuba_sample(ops::GETFILES);
-                    add_common_stats_and_send_to_scuba(repo.scuba.clone(), &mut sample, &stats);
-                })
+                let identity = identity.clone();
+                create_file_wirepack_parts(repo.hgrepo.clone(), path, nodes).timed(
+                    move |stats, _| {
+                        let mut sample = repo.scuba_sample(ops::GETPACKV1);
+                        sample.add("identity", identity);
+                        add_common_stats_and_send_to_scuba(
+                            repo.scuba.clone(),
+                            &mut sample,
+                            &stats,
+                        );
+                    },
+                )
             })
-            .boxify()
+            .map(stream::iter_ok)
+            .flatten()
+            .chain(stream::once(Ok(wirepack::Part::End)));
+
+        let chunks = WirePackPacker::new(parts, wirepack::Kind::File)
+            .and_then(|chunk| chunk.into_bytes());
+        self.run_stream(ops::GETPACKV1, String::new(), chunks)
+    }
+}
+
+enum LookupResult {
+    Found(NodeHash),
+    Ambiguous,
+    Unknown,
+}
+
+/// Find the changeset whose hash starts with `prefix`, if there's exactly one.
+fn lookup_prefix(repo: &BlobRepo, prefix: &str) -> BoxFuture<LookupResult, Error> {
+    let is_prefix = !prefix.is_empty() && prefix.len() < 40
+        && prefix.bytes().all(|b| (b as char).is_digit(16));
+    if !is_prefix {
+        return future::ok(LookupResult::Unknown).boxify();
+    }
+
+    // Two are enough to tell that the prefix is ambiguous
+    repo.get_changesets_by_prefix(prefix, 2)
+        .map(|mut csids| match csids.len() {
+            0 => LookupResult::Unknown,
+            1 => LookupResult::Found(csids.pop().unwrap().into_nodehash()),
+            _ => LookupResult::Ambiguous,
+        })
+        .boxify()
+}
+
+/// Nodes of the manifests and files a changegroup sends, grouped the way it sends them, each with
+/// the linknode of the first changeset which introduces it.
+#[derive(Default)]
+struct ChangegroupIndex {
+    manifests: Vec<(NodeHash, NodeHash)>,
+    treemanifests: BTreeMap<MPath, Vec<(NodeHash, NodeHash)>>,
+    filelogs: BTreeMap<MPath, Vec<(NodeHash, NodeHash)>>,
+    seen_manifests: HashSet<NodeHash>,
+    seen_trees: HashSet<(MPath, NodeHash)>,
+    seen_files: HashSet<(MPath, NodeHash)>,
+}
+
+impl ChangegroupIndex {
+    /// Add what the changeset `linknode` introduces. A node appears in several changesets after
+    /// a merge, and it's sent with the first one.
+    fn add_changeset(
+        &mut self,
+        linknode: NodeHash,
+        manifest: Option<NodeHash>,
+        trees: Vec<(MPath, NodeHash)>,
+        files: Vec<(MPath, NodeHash)>,
+    ) {
+        if let Some(manifest) = manifest {
+            if self.seen_manifests.insert(manifest) {
+                self.manifests.push((manifest, linknode));
+            }
+        }
+        add_to_groups(&mut self.treemanifests, &mut self.seen_trees, linknode, trees);
+        add_to_groups(&mut self.filelogs, &mut self.seen_files, linknode, files);
     }
 }
 
+fn add_to_groups(
+    groups: &mut BTreeMap<MPath, Vec<(NodeHash, NodeHash)>>,
+    seen: &mut HashSet<(MPath, NodeHash)>,
+    linknode: NodeHash,
+    entries: Vec<(MPath, NodeHash)>,
+) {
+    for (path, node) in entries {
+        if seen.insert((path.clone(), node)) {
+            groups
+                .entry(path)
+                .or_insert_with(Vec::new)
+                .push((node, linknode));
+        }
+    }
+}
+
+/// The changegroup part of a getbundle response: the changesets of `nodestosend`, in which
+/// parents come before their children, and the manifests and files they introduce. It's of
+/// version 02 unless there are manifests of directories to send, which need 03, the newest
+/// version the client can decode being `max_version`.
+///
+/// Only the nodes of what's sent are collected up front: the contents are loaded as the part is
+/// encoded.
+fn create_changegroup(
+    hgrepo: Arc<BlobRepo>,
+    max_version: CgVersion,
+    nodestosend: Vec<NodeHash>,
+    send_manifests: bool,
+    send_files: bool,
+) -> BoxFuture<PartEncodeBuilder, Error> {
+    let index = stream::iter_ok(nodestosend.clone())
+        .map({
+            let hgrepo = hgrepo.clone();
+            move |node| get_changeset_contents(hgrepo.clone(), node, send_manifests, send_files)
+        })
+        .buffered(CHANGEGROUP_NODES_IN_FLIGHT)
+        .fold(
+            ChangegroupIndex::default(),
+            |mut index, (linknode, manifest, trees, files)| {
+                index.add_changeset(linknode, manifest, trees, files);
+                Ok::<_, Error>(index)
+            },
+        );
+
+    index
+        .and_then(move |index| {
+            let version = if index.treemanifests.is_empty() {
+                CgVersion::Cg2Version
+            } else if max_version == CgVersion::Cg3Version {
+                CgVersion::Cg3Version
+            } else {
+                let version = CgVersion::Cg3Version.to_param();
+                bail_err!(ErrorKind::UnsupportedChangegroup(version.to_string()));
+            };
+
+            let changelogentries = stream::iter_ok(nodestosend)
+                .map({
+                    let hgrepo = hgrepo.clone();
+                    move |node| {
+                        hgrepo
+                            .get_changeset_by_changesetid(&ChangesetId::new(node))
+                            .and_then(|cs| {
+                                let mut v = Vec::new();
+                                mercurial::changeset::serialize_cs(&cs, &mut v)?;
+                                let parents = cs.parents().get_nodes();
+                                Ok(BlobNode::new(Bytes::from(v), parents.0, parents.1))
+                            })
+                    }
+                })
+                .buffered(CHANGEGROUP_NODES_IN_FLIGHT);
+
+            let manifestentries = stream::iter_ok(index.manifests)
+                .map({
+                    let hgrepo = hgrepo.clone();
+                    move |(node, linknode)| {
+                        load_blobnode(&hgrepo, node).map(move |blobnode| (blobnode, linknode))
+                    }
+                })
+                .buffered(CHANGEGROUP_NODES_IN_FLIGHT);
+
+            parts::changegroup_part(
+                version,
+                changelogentries,
+                manifestentries,
+                load_groups(hgrepo.clone(), index.treemanifests),
+                load_groups(hgrepo, index.filelogs),
+            )
+        })
+        .boxify()
+}
+
+/// The nodes a changegroup sends along with the changeset `node`: its root manifest, and the
+/// manifests of directories and the files which are new relative to all of its parents.
+fn get_changeset_contents(
+    repo: Arc<BlobRepo>,
+    node: NodeHash,
+    send_manifests: bool,
+    send_files: bool,
+) -> BoxFuture<
+    (
+        NodeHash,
+        Option<NodeHash>,
+        Vec<(MPath, NodeHash)>,
+        Vec<(MPath, NodeHash)>,
+    ),
+    Error,
+> {
+    repo.get_changeset_by_changesetid(&ChangesetId::new(node))
+        .and_then(move |cs| {
+            let mfid = cs.manifestid().into_nodehash();
+            let manifest = if send_manifests { Some(mfid) } else { None };
+            if !send_manifests && !send_files {
+                return future::ok((node, manifest, Vec::new(), Vec::new())).boxify();
+            }
+
+            let (p1, p2) = cs.parents().get_nodes();
+            let (p1, p2) = (p1.cloned(), p2.cloned());
+            let changed = get_changed_nodes(repo.clone(), mfid, p1);
+            // A merge only introduces what's in neither of its parents
+            let changed = match p2 {
+                Some(p2) => changed
+                    .join(get_changed_nodes(repo, mfid, Some(p2)))
+                    .map(|((trees, files), (p2trees, p2files))| {
+                        (
+                            retain_changed(trees, p2trees),
+                            retain_changed(files, p2files),
+                        )
+                    })
+                    .boxify(),
+                None => changed,
+            };
+
+            changed
+                .map(move |(trees, files)| {
+                    let trees = if send_manifests { trees } else { Vec::new() };
+                    let files = if send_files { files } else { Vec::new() };
+                    (node, manifest, trees, files)
+                })
+                .boxify()
+        })
+        .boxify()
+}
+
+/// The manifests of directories and the files in the manifest `mfid` which aren't in the one of
+/// the changeset `parent`, if there's a parent.
+fn get_changed_nodes(
+    repo: Arc<BlobRepo>,
+    mfid: NodeHash,
+    parent: Option<NodeHash>,
+) -> BoxFuture<(Vec<(MPath, NodeHash)>, Vec<(MPath, NodeHash)>), Error> {
+    let basemfid = match parent {
+        Some(parent) => repo.get_changeset_by_changesetid(&ChangesetId::new(parent))
+            .map(|parent| parent.manifestid().into_nodehash())
+            .boxify(),
+        None => future::ok(NULL_HASH).boxify(),
+    };
+
+    basemfid
+        .and_then({
+            let repo = repo.clone();
+            move |basemfid| {
+                repo.get_manifest_by_nodeid(&mfid)
+                    .join(repo.get_manifest_by_nodeid(&basemfid))
+            }
+        })
+        .map(|(mf, basemf)| changed_entry_stream(&mf, &basemf, MPath::empty()))
+        .flatten_stream()
+        .filter_map(|entry_status| match entry_status.status {
+            EntryStatus::Added(entry) | EntryStatus::Modified(entry, _) => {
+                let path = entry_status.path.join_element(entry.get_name());
+                let node = entry.get_hash().into_nodehash();
+                Some((entry.get_type() == Type::Tree, path, node))
+            }
+            EntryStatus::Deleted(..) => None,
+        })
+        .fold(
+            (Vec::new(), Vec::new()),
+            |(mut trees, mut files), (is_tree, path, node)| {
+                if is_tree {
+                    trees.push((path, node));
+                } else {
+                    files.push((path, node));
+                }
+                Ok::<_, Error>((trees, files))
+            },
+        )
+        .boxify()
+}
+
+/// Keep the `entries` which are also in `other`.
+fn retain_changed(
+    entries: Vec<(MPath, NodeHash)>,
+    other: Vec<(MPath, NodeHash)>,
+) -> Vec<(MPath, NodeHash)> {
+    let other: HashSet<_> = other.into_iter().collect();
+    entries
+        .into_iter()
+        .filter(|entry| other.contains(entry))
+        .collect()
+}
+
+/// Load `node`, a manifest or a file, as a changegroup sends it.
+fn load_blobnode(repo: &BlobRepo, node: NodeHash) -> BoxFuture<BlobNode, Error> {
+    repo.get_raw_content(&node)
+        .join(repo.get_parents(&node))
+        .map(|(blob, parents)| {
+            let (p1, p2) = parents.get_nodes();
+            BlobNode::new(blob, p1, p2)
+        })
+        .boxify()
+}
+
+/// Load the manifests or files of `groups`, keeping them grouped by path.
+fn load_groups(
+    repo: Arc<BlobRepo>,
+    groups: BTreeMap<MPath, Vec<(NodeHash, NodeHash)>>,
+) -> BoxStream<(MPath, Vec<(BlobNode, NodeHash)>), Error> {
+    let entries = stream::iter_ok(groups.into_iter().flat_map(|(path, nodes)| {
+        nodes
+            .into_iter()
+            .map(move |(node, linknode)| (path.clone(), node, linknode))
+    }));
+
+    // The entries of a path are next to each other: gather them until the path changes
+    let mut group: Option<(MPath, Vec<(BlobNode, NodeHash)>)> = None;
+    prefetch_batches(repo.clone(), entries, |&(_, node, _)| node)
+        .map(move |(path, node, linknode)| {
+            load_blobnode(&repo, node).map(move |blobnode| (path, blobnode, linknode))
+        })
+        .buffered(CHANGEGROUP_NODES_IN_FLIGHT)
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .filter_map(move |entry| match entry {
+            Some((path, blobnode, linknode)) => {
+                if let Some((ref current, ref mut entries)) = group {
+                    if *current == path {
+                        entries.push((blobnode, linknode));
+                        return None;
+                    }
+                }
+                mem::replace(&mut group, Some((path, vec![(blobnode, linknode)])))
+            }
+            None => group.take(),
+        })
+        .boxify()
+}
+
+/// Prefetch the files or trees of `entries`, whose nodes are given by `node`, a batch at a time
+/// ahead of them being read.
+fn prefetch_batches<S, F>(repo: Arc<BlobRepo>, entries: S, node: F) -> BoxStream<S::Item, Error>
+where
+    S: Stream<Error = Error> + Send + 'static,
+    S::Item: Send + 'static,
+    F: Fn(&S::Item) -> NodeHash + Send + 'static,
+{
+    entries
+        .chunks(PREFETCH_BATCH_SIZE)
+        .and_then(move |batch| {
+            let nodes = batch.iter().map(&node).collect();
+            repo.prefetch_nodes(nodes).map(move |()| stream::iter_ok(batch))
+        })
+        .flatten()
+        .boxify()
+}
+
+/// Trees which changed between the trees `basemfid` and `mfid` at `rootpath`, along with their
+/// linknodes and the paths of their parent directories.
 fn get_changed_entry_stream(
     repo: Arc<BlobRepo>,
     mfid: &NodeHash,
     basemfid: &NodeHash,
+    rootpath: MPath,
 ) -> BoxStream<(Box<Entry + Sync>, NodeHash, MPath), Error> {
     let manifest = repo.get_manifest_by_nodeid(mfid);
     let basemanifest = repo.get_manifest_by_nodeid(basemfid);
 
     let changed_entries = manifest
         .join(basemanifest)
-        .map(|(mf, basemf)| changed_entry_stream(&mf, &basemf, MPath::empty()))
+        .map({
+            let rootpath = rootpath.clone();
+            move |(mf, basemf)| changed_entry_stream(&mf, &basemf, rootpath)
+        })
         .flatten_stream();
 
     let changed_entries = changed_entries
@@ -719,7 +1911,7 @@ fn get_changed_entry_stream(
         .into_future()
         .and_then({
             let hgrepo = repo.clone();
-            move |entry| fetch_linknode(hgrepo.clone(), entry, MPath::empty())
+            move |entry| fetch_linknode(hgrepo.clone(), entry, rootpath)
         })
         .map(|(entry, linknode, basepath)| stream::once(Ok((entry, linknode, basepath))))
         .flatten_stream();
@@ -732,16 +1924,14 @@ fn fetch_linknode(
     entry: Box<Entry + Sync>,
     basepath: MPath,
 ) -> BoxFuture<(Box<Entry + Sync>, NodeHash, MPath), Error> {
-    let path = match entry.get_name() {
-        &Some(ref name) => {
-            let path = basepath.clone().join(name.clone().into_iter());
-            if entry.get_type() == Type::Tree {
-                RepoPath::DirectoryPath(path)
-            } else {
-                RepoPath::FilePath(path)
-            }
-        }
-        &None => RepoPath::RootPath,
+    // Root entries have no name, but they're only the root of the repo if basepath is empty
+    let path = basepath.join_element(entry.get_name());
+    let path = if path.is_empty() {
+        RepoPath::RootPath
+    } else if entry.get_type() == Type::Tree {
+        RepoPath::DirectoryPath(path)
+    } else {
+        RepoPath::FilePath(path)
     };
 
     let linknode_fut = repo.get_linknode(path, &entry.get_hash().into_nodehash());
@@ -750,43 +1940,26 @@ fn fetch_linknode(
         .boxify()
 }
 
-fn get_file_history(
-    repo: Arc<BlobRepo>,
-    startnode: NodeHash,
-    path: MPath,
-) -> BoxStream<(NodeHash, Parents, NodeHash, Option<(MPath, NodeHash)>), Error> {
-    if startnode == NULL_HASH {
-        return stream::empty().boxify();
-    }
-    let mut startstate = VecDeque::new();
-    startstate.push_back(startnode);
-    let seen_nodes: HashSet<_> = [startnode].iter().cloned().collect();
-
-    stream::unfold(
-        (startstate, seen_nodes),
-        move |cur_data: (VecDeque<NodeHash>, HashSet<NodeHash>)| {
-            let (mut nodes, mut seen_nodes) = cur_data;
-            let node = nodes.pop_front()?;
-
-            let parents = repo.get_parents(&node);
-            let copy = repo.get_file_copy(&node);
-
-            let linknode = RepoPath::file(path.clone()).into_future().and_then({
-                let repo = repo.clone();
-                move |path| repo.get_linknode(path, &node)
-            });
-
-            let joined = parents
-                .join(linknode)
-                .join(copy)
-                .map(|(pl, c)| (pl.0, pl.1, c));
+/// The parents and copy source of a file revision, as remotefilelog represents them.
+fn remotefilelog_parents(
+    parents: Parents,
+    copy: Option<(MPath, NodeHash)>,
+) -> (NodeHash, NodeHash, Option<MPath>) {
+    let (p1, p2) = match parents {
+        Parents::None => (NULL_HASH, NULL_HASH),
+        Parents::One(p) => (p, NULL_HASH),
+        Parents::Two(p1, p2) => (p1, p2),
+    };
 
-            Some(joined.map(move |(parents, linknode, copy)| {
-                nodes.extend(parents.into_iter().filter(|p| seen_nodes.insert(*p)));
-                ((node, parents, linknode, copy), (nodes, seen_nodes))
-            }))
-        },
-    ).boxify()
+    if let Some((copied_from, copied_rev)) = copy {
+        // Mercurial has a complicated copy/renames logic.
+        // If (path1, filenode1) is copied/renamed from (path2, filenode2),
+        // filenode1's p1 is set to filenode2, and copy_from path is set to path2
+        // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
+        (copied_rev, p1, Some(copied_from))
+    } else {
+        (p1, p2, None)
+    }
 }
 
 fn create_remotefilelog_blob(
@@ -794,8 +1967,8 @@ fn create_remotefilelog_blob(
     node: NodeHash,
     path: MPath,
 ) -> BoxFuture<Bytes, Error> {
-    // raw_content includes copy information
-    let raw_content_bytes = repo.get_file_content(&node).and_then(move |raw_content| {
+    // raw_content includes copy information, which remotefilelog hashes along with the content
+    let raw_content_bytes = repo.get_raw_content(&node).and_then(move |raw_content| {
         // requires digit counting to know for sure, use reasonable approximation
         let approximate_header_size = 12;
         let mut writer = Cursor::new(Vec::with_capacity(
@@ -818,7 +1991,7 @@ fn create_remotefilelog_blob(
             .map(|_| writer.into_inner())
     });
 
-    let file_history_bytes = get_file_history(repo, node, path)
+    let file_history_bytes = repo.get_file_history(path, &node)
         .collect()
         .and_then(|history| {
             let approximate_history_entry_size = 81;
@@ -826,27 +1999,13 @@ fn create_remotefilelog_blob(
                 history.len() * approximate_history_entry_size,
             ));
 
-            for (node, parents, linknode, copy) in history {
-                let (p1, p2) = match parents {
-                    Parents::None => (NULL_HASH, NULL_HASH),
-                    Parents::One(p) => (p, NULL_HASH),
-                    Parents::Two(p1, p2) => (p1, p2),
-                };
-
-                let (p1, p2, copied_from) = if let Some((copied_from, copied_rev)) = copy {
-                    // Mercurial has a complicated copy/renames logic.
-                    // If (path1, filenode1) is copied/renamed from (path2, filenode2),
-                    // filenode1's p1 is set to filenode2, and copy_from path is set to path2
-                    // filenode1's p2 is null for non-merge commits. It might be non-null for merges.
-                    (copied_rev, p1, Some(copied_from))
-                } else {
-                    (p1, p2, None)
-                };
+            for info in history {
+                let (p1, p2, copied_from) = remotefilelog_parents(info.parents, info.copy_from);
 
-                writer.write_all(node.sha1().as_ref())?;
+                writer.write_all(info.node.sha1().as_ref())?;
                 writer.write_all(p1.sha1().as_ref())?;
                 writer.write_all(p2.sha1().as_ref())?;
-                writer.write_all(linknode.sha1().as_ref())?;
+                writer.write_all(info.linknode.sha1().as_ref())?;
                 if let Some(copied_from) = copied_from {
                     writer.write_all(&copied_from.to_vec())?;
                 }
@@ -866,3 +2025,75 @@ fn create_remotefilelog_blob(
         .map(|bytes| Bytes::from(bytes))
         .boxify()
 }
+
+/// Build the wirepack parts which getpackv1 sends for one file: the history of all the requested
+/// nodes, followed by their full texts.
+fn create_file_wirepack_parts(
+    repo: Arc<BlobRepo>,
+    path: MPath,
+    nodes: Vec<NodeHash>,
+) -> BoxFuture<Vec<wirepack::Part>, Error> {
+    let repo_path = match RepoPath::file(path.clone()) {
+        Ok(repo_path) => repo_path,
+        Err(err) => return future::err(err).boxify(),
+    };
+    let nodes: Vec<_> = {
+        let mut seen = HashSet::new();
+        nodes
+            .into_iter()
+            .filter(|node| *node != NULL_HASH && seen.insert(*node))
+            .collect()
+    };
+
+    let history = stream::iter_ok(nodes.clone())
+        .map({
+            let repo = repo.clone();
+            move |node| repo.get_file_history(path.clone(), &node)
+        })
+        .flatten()
+        .filter({
+            let mut seen = HashSet::new();
+            move |info| seen.insert(info.node)
+        })
+        .map(|info| {
+            let (p1, p2, copied_from) = remotefilelog_parents(info.parents, info.copy_from);
+            wirepack::HistoryEntry {
+                node: info.node,
+                p1,
+                p2,
+                linknode: info.linknode,
+                copy_from: copied_from.map(RepoPath::FilePath),
+            }
+        })
+        .collect();
+
+    // raw_content includes copy information, which remotefilelog hashes along with the content
+    let data = stream::iter_ok(nodes)
+        .and_then(move |node| {
+            repo.get_raw_content(&node)
+                .map(move |raw_content| wirepack::DataEntry {
+                    node,
+                    delta_base: NULL_HASH,
+                    delta: Delta::new_fulltext(raw_content.to_vec()),
+                })
+        })
+        .collect();
+
+    history
+        .join(data)
+        .map(move |(history, data)| {
+            let mut parts = Vec::with_capacity(history.len() + data.len() + 2);
+            parts.push(wirepack::Part::HistoryMeta {
+                path: repo_path.clone(),
+                entry_count: history.len() as u32,
+            });
+            parts.extend(history.into_iter().map(wirepack::Part::History));
+            parts.push(wirepack::Part::DataMeta {
+                path: repo_path,
+                entry_count: data.len() as u32,
+            });
+            parts.extend(data.into_iter().map(wirepack::Part::Data));
+            parts
+        })
+        .boxify()
+}
@@ -0,0 +1,288 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! One record per command run by a client, with its arguments, how long it took, how much was
+//! sent back and how it ended. The record is logged to slog, and appended as a line of JSON to
+//! the file given with `server.request_log_path`, once the command is done or dropped.
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use bytes::Bytes;
+use futures::{Async, Future, Poll, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use serde_json;
+use slog::Logger;
+
+use errors::*;
+use identity::SessionContext;
+
+/// File the records are appended to, one per line.
+pub struct JsonSink {
+    file: Mutex<File>,
+}
+
+impl JsonSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|_| format!("failed to open request log {}", path.display()))?;
+        Ok(JsonSink {
+            file: Mutex::new(file),
+        })
+    }
+
+    fn write(&self, record: &RequestRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+        // A single write, so that the records of concurrent commands don't interleave
+        let mut file = self.file.lock().expect("lock poisoned");
+        file.write_all(&line)?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RequestRecord<'a> {
+    repo: &'a str,
+    session: usize,
+    identity: String,
+    command: &'a str,
+    args: &'a str,
+    duration_ms: u64,
+    bytes_sent: Option<usize>,
+    status: &'static str,
+    error: Option<&'a str>,
+}
+
+enum Outcome {
+    // Dropped before completing, e.g. because the client went away
+    Cancelled,
+    Success,
+    Failure(String),
+}
+
+/// The record of a command being run, logged when it's dropped.
+pub struct RequestLog {
+    logger: Logger,
+    sink: Option<Arc<JsonSink>>,
+    repo: String,
+    session: SessionContext,
+    command: &'static str,
+    args: String,
+    start: Instant,
+    bytes_sent: Option<usize>,
+    outcome: Outcome,
+}
+
+impl RequestLog {
+    pub fn new(
+        logger: &Logger,
+        sink: Option<Arc<JsonSink>>,
+        repo: String,
+        session: SessionContext,
+        command: &'static str,
+        args: String,
+    ) -> Self {
+        RequestLog {
+            logger: logger.clone(),
+            sink,
+            repo,
+            session,
+            command,
+            args,
+            start: Instant::now(),
+            bytes_sent: None,
+            outcome: Outcome::Cancelled,
+        }
+    }
+
+    /// Logs the command run by `fut` once it's done.
+    pub fn future<F>(self, fut: F) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let mut log = self;
+        fut.then(move |res| {
+            log.set_outcome(&res);
+            res
+        }).boxify()
+    }
+
+    /// Logs the command run by `fut` once it's done, along with the size of its response.
+    pub fn bytes_future<F>(self, fut: F) -> BoxFuture<Bytes, Error>
+    where
+        F: Future<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        let mut log = self;
+        fut.then(move |res| {
+            if let Ok(ref bytes) = res {
+                log.bytes_sent = Some(bytes.len());
+            }
+            log.set_outcome(&res);
+            res
+        }).boxify()
+    }
+
+    /// Logs the command streaming its response with `s` once the stream is finished or dropped.
+    pub fn stream<S>(self, s: S) -> BoxStream<Bytes, Error>
+    where
+        S: Stream<Item = Bytes, Error = Error> + Send + 'static,
+    {
+        LoggedStream {
+            inner: s,
+            log: self,
+        }.boxify()
+    }
+
+    fn set_outcome<T>(&mut self, res: &Result<T>) {
+        self.outcome = match *res {
+            Ok(_) => Outcome::Success,
+            Err(ref err) => Outcome::Failure(format!("{}", err)),
+        };
+    }
+
+    fn log(&self) {
+        let elapsed = self.start.elapsed();
+        let duration_ms = elapsed.as_secs() * 1000 + u64::from(elapsed.subsec_nanos() / 1_000_000);
+        let (status, error) = match self.outcome {
+            Outcome::Cancelled => ("cancelled", None),
+            Outcome::Success => ("ok", None),
+            Outcome::Failure(ref err) => ("error", Some(err.as_str())),
+        };
+
+        info!(self.logger, "request {} {}", self.command, status;
+            "command" => self.command,
+            "args" => self.args.clone(),
+            "duration_ms" => duration_ms,
+            "bytes_sent" => self.bytes_sent,
+            "status" => status,
+            "error" => error.map(String::from)
+        );
+
+        if let Some(ref sink) = self.sink {
+            let record = RequestRecord {
+                repo: &self.repo,
+                session: self.session.id(),
+                identity: format!("{}", self.session.identity()),
+                command: self.command,
+                args: &self.args,
+                duration_ms,
+                bytes_sent: self.bytes_sent,
+                status,
+                error,
+            };
+            if let Err(err) = sink.write(&record) {
+                warn!(self.logger, "failed to write request log: {}", err);
+            }
+        }
+    }
+}
+
+impl Drop for RequestLog {
+    fn drop(&mut self) {
+        self.log();
+    }
+}
+
+struct LoggedStream<S> {
+    inner: S,
+    log: RequestLog,
+}
+
+impl<S> Stream for LoggedStream<S>
+where
+    S: Stream<Item = Bytes, Error = Error>,
+{
+    type Item = Bytes;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<Bytes>, Error> {
+        match self.inner.poll() {
+            Ok(Async::Ready(Some(bytes))) => {
+                *self.log.bytes_sent.get_or_insert(0) += bytes.len();
+                Ok(Async::Ready(Some(bytes)))
+            }
+            Ok(Async::Ready(None)) => {
+                self.log.outcome = Outcome::Success;
+                Ok(Async::Ready(None))
+            }
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => {
+                self.log.outcome = Outcome::Failure(format!("{}", err));
+                Err(err)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Read;
+
+    use futures::{future, stream};
+    use slog::Discard;
+    use tempdir::TempDir;
+
+    use identity::Identity;
+
+    fn request_log(sink: &Arc<JsonSink>, command: &'static str) -> RequestLog {
+        RequestLog::new(
+            &Logger::root(Discard, o!()),
+            Some(sink.clone()),
+            "/repos/www".to_string(),
+            SessionContext::new(Identity::User("alice".to_string())),
+            command,
+            "args".to_string(),
+        )
+    }
+
+    #[test]
+    fn records() {
+        let dir = TempDir::new("requestlog").unwrap();
+        let path = dir.path().join("requests.json");
+        let sink = Arc::new(JsonSink::open(&path).unwrap());
+
+        let chunks = stream::iter_ok(vec![Bytes::from("abc"), Bytes::from("de")]);
+        let sent = request_log(&sink, "getbundle").stream(chunks).collect().wait();
+        assert_eq!(sent.unwrap().len(), 2);
+
+        let failed = future::err::<bool, _>(format_err!("no such key"));
+        assert!(request_log(&sink, "pushkey").future(failed).wait().is_err());
+
+        let cancelled = future::empty::<Bytes, Error>();
+        drop(request_log(&sink, "lookup").bytes_future(cancelled));
+
+        let mut content = String::new();
+        File::open(&path)
+            .unwrap()
+            .read_to_string(&mut content)
+            .unwrap();
+        let records: Vec<serde_json::Value> = content
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(records.len(), 3);
+
+        assert_eq!(records[0]["command"], "getbundle");
+        assert_eq!(records[0]["identity"], "user alice");
+        assert_eq!(records[0]["bytes_sent"], 5);
+        assert_eq!(records[0]["status"], "ok");
+        assert_eq!(records[1]["status"], "error");
+        assert_eq!(records[1]["error"], "no such key");
+        assert_eq!(records[2]["status"], "cancelled");
+        assert_eq!(records[2]["bytes_sent"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,437 @@
+// Copyright (c) 2004-present, Facebook, Inc.
+// All Rights Reserved.
+//
+// This software may be used and distributed according to the terms of the
+// GNU General Public License version 2 or any later version.
+
+//! Load shedding: limits on how many of each command can run at once and be started each
+//! second, shared by all the repos of the server. Commands over a limit fail right away with
+//! `ErrorKind::ServerBusy` instead of queueing, so that a stampede of clients can't exhaust the
+//! memory of the server.
+//!
+//! Commands can also be given a timeout, past which they fail with `ErrorKind::Timeout`. The
+//! commands which stream their response only time out once they haven't produced anything for
+//! that long, so that a long response to a slow client isn't cut short while it keeps flowing.
+//! A command is cancelled by dropping its future or stream, along with the blobstore fetches
+//! in flight for it, which also happens when the client disconnects and the response can't be
+//! sent anymore.
+//!
+//! The limits can be changed while the server runs, see `Throttle::reload`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use futures::{future, stream, Async, Future, Poll, Stream};
+use futures_ext::{BoxFuture, BoxStream, FutureExt, StreamExt};
+use tokio_timer::{wheel, Sleep, Timer};
+
+use config::CommandLimits;
+use errors::*;
+
+// The timer can't sleep for longer than its number of slots times its tick
+const TIMER_TICK_SECS: u64 = 1;
+const TIMER_SLOTS: usize = 1 << 16;
+
+pub struct Throttle {
+    commands: RwLock<HashMap<String, Arc<CommandThrottle>>>,
+    known_commands: &'static [&'static str],
+    timer: Timer,
+}
+
+impl Throttle {
+    /// Limits the commands named in `limits`, any other command is unlimited.
+    pub fn new(
+        limits: &HashMap<String, CommandLimits>,
+        known_commands: &'static [&'static str],
+    ) -> Result<Self> {
+        validate(limits, known_commands)?;
+        let commands = limits
+            .iter()
+            .map(|(command, limits)| {
+                (command.clone(), Arc::new(CommandThrottle::new(limits.clone())))
+            })
+            .collect();
+
+        let timer = wheel()
+            .tick_duration(Duration::from_secs(TIMER_TICK_SECS))
+            .num_slots(TIMER_SLOTS)
+            .build();
+        Ok(Throttle {
+            commands: RwLock::new(commands),
+            known_commands,
+            timer,
+        })
+    }
+
+    /// Limits the commands as `limits` says from now on, or changes nothing if they're invalid.
+    /// The runs in progress still count against the new limits, and keep their timeouts.
+    pub fn reload(&self, limits: &HashMap<String, CommandLimits>) -> Result<()> {
+        validate(limits, self.known_commands)?;
+        let mut commands = self.commands.write().expect("lock poisoned");
+        commands.retain(|command, _| limits.contains_key(command));
+        for (command, limits) in limits {
+            if let Some(throttle) = commands.get(command) {
+                throttle.set_limits(limits.clone());
+                continue;
+            }
+            commands.insert(command.clone(), Arc::new(CommandThrottle::new(limits.clone())));
+        }
+        Ok(())
+    }
+
+    /// Counts a new run of `command`, failing if it's over a limit. The run lasts until the
+    /// returned permit is dropped.
+    pub fn acquire(&self, command: &str) -> Result<Permit> {
+        let throttle = self.commands
+            .read()
+            .expect("lock poisoned")
+            .get(command)
+            .cloned();
+        match throttle {
+            Some(throttle) => {
+                if throttle.try_start(Instant::now()) {
+                    Ok(Permit(Some(throttle)))
+                } else {
+                    Err(ErrorKind::ServerBusy(command.to_string()).into())
+                }
+            }
+            None => Ok(Permit(None)),
+        }
+    }
+
+    /// Runs `fut` as a `command`, which fails instead if it's over a limit, and is cancelled if
+    /// it doesn't complete before the timeout of the command.
+    pub fn future<F>(&self, command: &str, fut: F) -> BoxFuture<F::Item, Error>
+    where
+        F: Future<Error = Error> + Send + 'static,
+        F::Item: Send + 'static,
+    {
+        let permit = match self.acquire(command) {
+            Ok(permit) => permit,
+            Err(err) => return future::err(err).boxify(),
+        };
+        let fut = fut.then(move |res| {
+            drop(permit);
+            res
+        });
+
+        match self.deadline(command) {
+            Some(deadline) => fut.select(deadline)
+                .map(|(item, _)| item)
+                .map_err(|(err, _)| err)
+                .boxify(),
+            None => fut.boxify(),
+        }
+    }
+
+    /// Runs `s` as a `command`, which fails instead if it's over a limit, and is cancelled if
+    /// it goes for longer than the timeout of the command without yielding anything. The run
+    /// lasts until the stream is finished or dropped.
+    pub fn stream<S>(&self, command: &str, s: S) -> BoxStream<S::Item, Error>
+    where
+        S: Stream<Error = Error> + Send + 'static,
+        S::Item: Send + 'static,
+    {
+        let permit = match self.acquire(command) {
+            Ok(permit) => permit,
+            Err(err) => return stream::once(Err(err)).boxify(),
+        };
+        let s = s.then(move |res| {
+            let _ = &permit;
+            res
+        });
+
+        match self.timeout_secs(command) {
+            Some(timeout_secs) => IdleTimeout {
+                inner: s,
+                sleep: self.timer.sleep(Duration::from_secs(timeout_secs)),
+                timer: self.timer.clone(),
+                command: command.to_string(),
+                timeout_secs,
+            }.boxify(),
+            None => s.boxify(),
+        }
+    }
+
+    // Fails once the timeout of `command` has passed, if it has one
+    fn deadline<T: Send + 'static>(&self, command: &str) -> Option<BoxFuture<T, Error>> {
+        self.timeout_secs(command).map(|secs| {
+            let command = command.to_string();
+            self.timer
+                .sleep(Duration::from_secs(secs))
+                .from_err()
+                .and_then(move |()| Err(ErrorKind::Timeout(command, secs).into()))
+                .boxify()
+        })
+    }
+
+    fn timeout_secs(&self, command: &str) -> Option<u64> {
+        match self.commands.read().expect("lock poisoned").get(command) {
+            Some(throttle) => throttle.state.lock().expect("lock poisoned").limits.timeout_secs,
+            None => None,
+        }
+    }
+}
+
+/// Fails with `ErrorKind::Timeout` once `inner` has gone for `timeout_secs` without yielding
+/// anything.
+struct IdleTimeout<S> {
+    inner: S,
+    sleep: Sleep,
+    timer: Timer,
+    command: String,
+    timeout_secs: u64,
+}
+
+impl<S> Stream for IdleTimeout<S>
+where
+    S: Stream<Error = Error>,
+{
+    type Item = S::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Option<S::Item>, Error> {
+        match self.inner.poll()? {
+            Async::Ready(item) => {
+                self.sleep = self.timer.sleep(Duration::from_secs(self.timeout_secs));
+                Ok(Async::Ready(item))
+            }
+            Async::NotReady => match self.sleep.poll()? {
+                Async::Ready(()) => {
+                    let command = self.command.clone();
+                    Err(ErrorKind::Timeout(command, self.timeout_secs).into())
+                }
+                Async::NotReady => Ok(Async::NotReady),
+            },
+        }
+    }
+}
+
+fn validate(
+    limits: &HashMap<String, CommandLimits>,
+    known_commands: &[&str],
+) -> Result<()> {
+    for (command, limits) in limits {
+        if !known_commands.contains(&command.as_str()) {
+            bail_err!(ErrorKind::InvalidConfig(format!(
+                "cannot throttle unknown command {}",
+                command
+            )));
+        }
+        let max_timeout_secs = TIMER_TICK_SECS * TIMER_SLOTS as u64;
+        if limits.timeout_secs.map_or(false, |secs| secs > max_timeout_secs) {
+            bail_err!(ErrorKind::InvalidConfig(format!(
+                "timeout of {} is longer than {} seconds",
+                command, max_timeout_secs
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Keeps a command counted as running while it's alive.
+pub struct Permit(Option<Arc<CommandThrottle>>);
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        if let Some(ref throttle) = self.0 {
+            throttle.finish();
+        }
+    }
+}
+
+struct CommandThrottle {
+    state: Mutex<ThrottleState>,
+}
+
+struct ThrottleState {
+    limits: CommandLimits,
+    running: usize,
+    // The runs started since the beginning of the current one second window
+    window_start: Instant,
+    window_started: u32,
+}
+
+impl CommandThrottle {
+    fn new(limits: CommandLimits) -> Self {
+        CommandThrottle {
+            state: Mutex::new(ThrottleState {
+                limits,
+                running: 0,
+                window_start: Instant::now(),
+                window_started: 0,
+            }),
+        }
+    }
+
+    fn try_start(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().expect("lock poisoned");
+        if now >= state.window_start + Duration::from_secs(1) {
+            state.window_start = now;
+            state.window_started = 0;
+        }
+
+        let too_many_running = state
+            .limits
+            .max_concurrent
+            .map_or(false, |max| state.running >= max);
+        let too_many_started = state
+            .limits
+            .max_qps
+            .map_or(false, |max| state.window_started >= max);
+        if too_many_running || too_many_started {
+            return false;
+        }
+
+        state.running += 1;
+        state.window_started += 1;
+        true
+    }
+
+    fn set_limits(&self, limits: CommandLimits) {
+        self.state.lock().expect("lock poisoned").limits = limits;
+    }
+
+    fn finish(&self) {
+        let mut state = self.state.lock().expect("lock poisoned");
+        state.running -= 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn throttle(max_concurrent: Option<usize>, max_qps: Option<u32>) -> Throttle {
+        let limits = hashmap! {
+            "getbundle".to_string() => CommandLimits {
+                max_concurrent,
+                max_qps,
+                timeout_secs: None,
+            },
+        };
+        Throttle::new(&limits, &["getbundle", "heads"]).unwrap()
+    }
+
+    #[test]
+    fn max_concurrent() {
+        let throttle = throttle(Some(2), None);
+        let first = throttle.acquire("getbundle").unwrap();
+        let _second = throttle.acquire("getbundle").unwrap();
+        assert!(throttle.acquire("getbundle").is_err());
+        // Other commands aren't limited
+        assert!(throttle.acquire("heads").is_ok());
+
+        drop(first);
+        assert!(throttle.acquire("getbundle").is_ok());
+    }
+
+    #[test]
+    fn max_qps() {
+        let command = CommandThrottle::new(CommandLimits {
+            max_qps: Some(2),
+            ..CommandLimits::default()
+        });
+        let start = Instant::now();
+        assert!(command.try_start(start));
+        assert!(command.try_start(start));
+        assert!(!command.try_start(start + Duration::from_millis(500)));
+        assert!(command.try_start(start + Duration::from_secs(1)));
+    }
+
+    #[test]
+    fn busy_stream() {
+        let throttle = throttle(Some(1), None);
+        let first = throttle.stream("getbundle", stream::iter_ok(vec![1, 2]));
+        let second = throttle.stream("getbundle", stream::iter_ok(vec![3]));
+        assert!(second.collect().wait().is_err());
+        assert_eq!(first.collect().wait().unwrap(), vec![1, 2]);
+        assert!(throttle.stream("getbundle", stream::iter_ok(vec![3])).collect().wait().is_ok());
+    }
+
+    #[test]
+    fn timeout() {
+        let limits = hashmap! {
+            "getbundle".to_string() => CommandLimits {
+                timeout_secs: Some(1),
+                ..CommandLimits::default()
+            },
+        };
+        let throttle = Throttle::new(&limits, &["getbundle"]).unwrap();
+
+        let never = future::empty::<(), Error>();
+        let err = throttle.future("getbundle", never).wait().unwrap_err();
+        match err.downcast::<ErrorKind>() {
+            Ok(ErrorKind::Timeout(..)) => (),
+            other => panic!("unexpected result {:?}", other),
+        }
+
+        let finished = stream::iter_ok::<_, Error>(vec![1, 2]);
+        let items = throttle.stream("getbundle", finished).collect().wait();
+        assert_eq!(items.unwrap(), vec![1, 2]);
+
+        let unfinished = stream::iter_ok(vec![1]).chain(future::empty().into_stream());
+        let items = throttle.stream("getbundle", unfinished).collect().wait();
+        assert!(items.is_err());
+    }
+
+    #[test]
+    fn stream_timeout_between_items() {
+        let limits = hashmap! {
+            "getbundle".to_string() => CommandLimits {
+                timeout_secs: Some(1),
+                ..CommandLimits::default()
+            },
+        };
+        let throttle = Throttle::new(&limits, &["getbundle"]).unwrap();
+
+        // Longer than the timeout overall, but never idle for that long
+        let slow = wheel()
+            .build()
+            .interval(Duration::from_millis(400))
+            .take(5)
+            .from_err::<Error>();
+        let items = throttle.stream("getbundle", slow).collect().wait();
+        assert_eq!(items.unwrap().len(), 5);
+    }
+
+    #[test]
+    fn reload() {
+        let throttle = throttle(Some(1), None);
+        let first = throttle.acquire("getbundle").unwrap();
+        assert!(throttle.acquire("getbundle").is_err());
+
+        // The run in progress counts against the new limit
+        let limits = hashmap! {
+            "getbundle".to_string() => CommandLimits {
+                max_concurrent: Some(2),
+                ..CommandLimits::default()
+            },
+            "heads".to_string() => CommandLimits {
+                max_concurrent: Some(0),
+                ..CommandLimits::default()
+            },
+        };
+        throttle.reload(&limits).unwrap();
+        let _second = throttle.acquire("getbundle").unwrap();
+        assert!(throttle.acquire("getbundle").is_err());
+        assert!(throttle.acquire("heads").is_err());
+
+        // Invalid limits change nothing
+        let invalid = hashmap! { "getbundel".to_string() => CommandLimits::default() };
+        assert!(throttle.reload(&invalid).is_err());
+        assert!(throttle.acquire("heads").is_err());
+
+        throttle.reload(&HashMap::new()).unwrap();
+        assert!(throttle.acquire("heads").is_ok());
+        drop(first);
+        assert!(throttle.acquire("getbundle").is_ok());
+    }
+
+    #[test]
+    fn unknown_command() {
+        let limits = hashmap! { "getbundel".to_string() => CommandLimits::default() };
+        assert!(Throttle::new(&limits, &["getbundle"]).is_err());
+    }
+}
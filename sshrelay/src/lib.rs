@@ -27,6 +27,9 @@ pub enum SshStream {
     Stdin,
     Stdout,
     Stderr,
+    /// Sent once by the client before anything else, to a server which hosts several repos:
+    /// the path of the repo it wants to talk to.
+    Preamble,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
@@ -77,6 +80,7 @@ impl Decoder for SshDecoder {
                 0 => SshStream::Stdin,
                 1 => SshStream::Stdout,
                 2 => SshStream::Stderr,
+                3 => SshStream::Preamble,
                 _ => {
                     return Err(io::Error::new(
                         io::ErrorKind::InvalidInput,
@@ -107,6 +111,7 @@ impl Encoder for SshEncoder {
             SshStream::Stdin => v.put_u8(0),
             SshStream::Stdout => v.put_u8(1),
             SshStream::Stderr => v.put_u8(2),
+            SshStream::Preamble => v.put_u8(3),
         };
         v.put_slice(&msg.1);
         Ok(self.0.encode(v.freeze(), buf)?)
@@ -248,10 +253,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn encode_preamble() {
+        let mut buf = BytesMut::with_capacity(1024);
+        let mut encoder = SshEncoder::new();
+
+        encoder
+            .encode(SshMsg::new(Preamble, b"repo".bytes()), &mut buf)
+            .expect("encode failed");
+
+        assert_eq!(buf.as_ref(), b"5:\x03repo,");
+    }
+
+    #[test]
+    fn decode_preamble() {
+        let mut buf = BytesMut::with_capacity(1024);
+        buf.put_slice(b"5:\x03repo,");
+
+        let mut decoder = SshDecoder::new();
+
+        match decoder.decode(&mut buf) {
+            Ok(Some(ref res)) if res == &SshMsg::new(Preamble, b"repo".bytes()) => (),
+            bad => panic!("decode failed: {:?}", bad.as_ref()),
+        }
+    }
+
     #[test]
     fn decode_bad() {
         let mut buf = BytesMut::with_capacity(1024);
-        buf.put_slice(b"2:\x03X,");
+        buf.put_slice(b"2:\x04X,");
 
         let mut decoder = SshDecoder::new();
 
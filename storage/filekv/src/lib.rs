@@ -32,7 +32,7 @@ use std::io::prelude::*;
 use std::marker::PhantomData;
 use std::os::unix::io::AsRawFd;
 use std::path::PathBuf;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Mutex, MutexGuard};
 
 use bincode::{deserialize, serialize};
 use futures::{Async, Poll};
@@ -215,6 +215,47 @@ where
                 pool.spawn(future)
             })
     }
+
+    /// Make all of `changes` at once, each setting its key to a value (or deleting it if None) if
+    /// the key is at the version given. Resolves to the values the keys had, in the order of the
+    /// keys, or to None, changing nothing, if any key isn't at its version.
+    pub fn transaction<Q: Into<String>>(
+        &self,
+        changes: Vec<(Q, Option<V>, Version)>,
+    ) -> impl Future<Item = Option<Vec<(String, Option<V>)>>, Error = Error> {
+        let pool = self.pool.clone();
+        self.get_path_mutexes(changes)
+            .into_future()
+            .and_then(move |(keys, changes)| {
+                let future = poll_fn(move || poll_transaction(&changes));
+                pool.spawn(future)
+                    .map(move |old| old.map(|old| keys.into_iter().zip(old).collect()))
+            })
+    }
+
+    /// The mutexes of the keys of `changes`, in the order of the keys, so that concurrent
+    /// transactions always lock them in the same order.
+    fn get_path_mutexes<Q: Into<String>>(
+        &self,
+        changes: Vec<(Q, Option<V>, Version)>,
+    ) -> Result<(Vec<String>, Vec<(Arc<Mutex<PathBuf>>, Option<V>, Version)>)> {
+        let mut changes: Vec<(String, _, _)> = changes
+            .into_iter()
+            .map(|(key, value, version)| (key.into(), value, version))
+            .collect();
+        changes.sort_by(|a, b| a.0.cmp(&b.0));
+        for pair in changes.windows(2) {
+            if pair[0].0 == pair[1].0 {
+                bail_msg!("key '{}' is changed more than once in a transaction", pair[0].0);
+            }
+        }
+        let keys = changes.iter().map(|&(ref key, _, _)| key.clone()).collect();
+        let changes = changes
+            .into_iter()
+            .map(|(key, value, version)| Ok((self.get_path_mutex(key)?, value, version)))
+            .collect::<Result<_>>()?;
+        Ok((keys, changes))
+    }
 }
 
 /// Synchronous implementation of the get operation for the bookmark store. Intended to
@@ -362,6 +403,126 @@ fn poll_delete(
     result.map(Async::Ready)
 }
 
+/// Synchronous implementation of the transaction operation for the bookmark store. Intended to
+/// be used in conjunction with poll_fn() and a CpuPool to dispatch it onto a thread pool.
+///
+/// Every key is locked before any is checked, so that none can change before all the changes are
+/// made. Keys are in separate files though, so an error while writing them can leave only some of
+/// the changes made.
+fn poll_transaction<V>(
+    changes: &[(Arc<Mutex<PathBuf>>, Option<V>, Version)],
+) -> Poll<Option<Vec<Option<V>>>, Error>
+where
+    V: Serialize + DeserializeOwned,
+{
+    let paths: Vec<_> = changes
+        .iter()
+        .map(|&(ref mutex, _, _)| mutex.lock().expect("Lock poisoned"))
+        .collect();
+
+    // Files created in order to lock keys which didn't exist are removed again if the changes
+    // aren't made.
+    let mut created = Vec::new();
+    let result = lock_matching(&paths, changes, &mut created).and_then(|locked| {
+        let (files, old): (Vec<_>, Vec<_>) = match locked {
+            Some(locked) => locked.into_iter().unzip(),
+            None => return Ok(None),
+        };
+        for ((path, file), &(_, ref value, _)) in paths.iter().zip(files).zip(changes) {
+            match (file, value) {
+                (Some(mut file), &Some(ref value)) => {
+                    let out = serialize(&(value, version_random()))?;
+                    file.seek(SeekFrom::Start(0))?;
+                    file.set_len(0)?;
+                    file.write_all(&out)?;
+                }
+                (Some(_), &None) => fs::remove_file(&**path).or_else(|e| match e.kind() {
+                    io::ErrorKind::NotFound => Ok(()),
+                    _ => Err(e),
+                })?,
+                // Deleting a key which doesn't exist
+                (None, _) => {}
+            }
+        }
+        Ok(Some(old))
+    });
+
+    if result.as_ref().map_or(true, |old| old.is_none()) {
+        for path in created {
+            let _ = fs::remove_file(path);
+        }
+    }
+    result.map(Async::Ready)
+}
+
+/// Open and lock the file of each key of a transaction, checking that the key is at the version
+/// expected, and read the value it has. Returns None if one isn't.
+fn lock_matching<V>(
+    paths: &[MutexGuard<PathBuf>],
+    changes: &[(Arc<Mutex<PathBuf>>, Option<V>, Version)],
+    created: &mut Vec<PathBuf>,
+) -> Result<Option<Vec<(Option<File>, Option<V>)>>>
+where
+    V: DeserializeOwned,
+{
+    let mut files = Vec::new();
+    for (path, &(_, ref value, ref version)) in paths.iter().zip(changes) {
+        let path: &PathBuf = &**path;
+
+        if *version == Version::absent() {
+            // Deleting a key which mustn't exist only checks that it doesn't.
+            if value.is_none() {
+                if path.exists() {
+                    return Ok(None);
+                }
+                files.push((None, None));
+                continue;
+            }
+            match OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create_new(true)
+                .open(path)
+            {
+                Ok(file) => {
+                    created.push(path.clone());
+                    fcntl::flock(file.as_raw_fd(), FlockArg::LockExclusive)?;
+                    files.push((Some(file), None));
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::AlreadyExists => return Ok(None),
+                    _ => return Err(e.into()),
+                },
+            }
+        } else {
+            match OpenOptions::new().read(true).write(true).open(path) {
+                Ok(mut file) => {
+                    // Block until we get an advisory lock on this file.
+                    let fd = file.as_raw_fd();
+                    fcntl::flock(fd, FlockArg::LockExclusive)?;
+
+                    // Ensure file wasn't deleted between opening and locking.
+                    if stat::fstat(fd)?.st_nlink == 0 {
+                        return Ok(None);
+                    }
+                    let mut buf = Vec::new();
+                    let _ = file.read_to_end(&mut buf)?;
+                    let (value, file_version) = deserialize::<(V, Version)>(&buf)?;
+                    if file_version != *version {
+                        return Ok(None);
+                    }
+                    files.push((Some(file), Some(value)));
+                }
+                Err(e) => match e.kind() {
+                    io::ErrorKind::NotFound => return Ok(None),
+                    _ => return Err(e.into()),
+                },
+            }
+        }
+    }
+    Ok(Some(files))
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -415,6 +576,48 @@ mod test {
         assert_eq!(kv.get(foo).wait().unwrap(), Some((bar, version)));
     }
 
+    #[test]
+    fn transaction() {
+        let tmp = TempDir::new("filekv_transaction").unwrap();
+        let kv = FileKV::open(tmp.path(), "kv:").unwrap();
+
+        let absent = Version::absent();
+        let foo_v1 = kv.set_new("foo", &"1".to_string(), None)
+            .wait()
+            .unwrap()
+            .unwrap();
+
+        // "bar" doesn't exist, so nothing changes
+        let changes = vec![
+            ("foo", Some("2".to_string()), foo_v1),
+            ("bar", None, foo_v1),
+            ("baz", Some("3".to_string()), absent),
+        ];
+        assert_eq!(kv.transaction(changes).wait().unwrap(), None);
+        assert_eq!(kv.get("foo").wait().unwrap(), Some(("1".to_string(), foo_v1)));
+        assert_eq!(kv.get("baz").wait().unwrap(), None);
+
+        let changes = vec![
+            ("foo", None, foo_v1),
+            ("bar", None, absent),
+            ("baz", Some("3".to_string()), absent),
+        ];
+        let old = kv.transaction(changes).wait().unwrap();
+        assert_eq!(
+            old,
+            Some(vec![
+                ("bar".to_string(), None),
+                ("baz".to_string(), None),
+                ("foo".to_string(), Some("1".to_string())),
+            ])
+        );
+        assert_eq!(kv.get("foo").wait().unwrap(), None);
+        assert_eq!(kv.get("baz").wait().unwrap().unwrap().0, "3".to_string());
+
+        let changes = vec![("baz", None, absent), ("baz", None, absent)];
+        assert!(kv.transaction(changes).wait().is_err());
+    }
+
     #[test]
     fn list() {
         let tmp = TempDir::new("filebookmarks_heads_basic").unwrap();
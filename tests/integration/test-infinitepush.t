@@ -43,8 +43,8 @@ Do infinitepush (aka commit cloud) push
   running * (glob)
   sending hello command
   sending between command
-  remote: 194
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=* (glob)
+  remote: 291
+  remote: capabilities: lookup branchmap known pushkey getbundle batch unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog getfile bundle2=* (glob)
   remote: 1
   query 1; heads
   sending batch command
@@ -86,8 +86,8 @@ Pushbackup also works
   running * (glob)
   sending hello command
   sending between command
-  remote: 194
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=* (glob)
+  remote: 291
+  remote: capabilities: lookup branchmap known pushkey getbundle batch unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog getfile bundle2=* (glob)
   remote: 1
   query 1; heads
   sending batch command
@@ -126,8 +126,8 @@ Pushbackup that pushes only bookmarks doesn't work (T26428992)
   running * (glob)
   sending hello command
   sending between command
-  remote: 194
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=HG20%0Alistkeys%0Achangegroup%3D02%0Ab2x%3Ainfinitepush%0Ab2x%3Ainfinitepushscratchbookmarks
+  remote: 291
+  remote: capabilities: lookup branchmap known pushkey getbundle batch unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog getfile bundle2=HG20%0Alistkeys%0Apushkey%0Achangegroup%3D02%2C03%0Ab2x%3Ainfinitepush%0Ab2x%3Ainfinitepushscratchbookmarks%0Ab2x%3Arebase%0Aphases%3Dheads%0Aobsmarkers%3DV1
   remote: 1
   sending unbundle command
   bundle2-output-bundle: "HG20", (1 params) 2 parts total
@@ -117,6 +117,8 @@ start mononoke
   $ hg up -q 0
 Test a pull of one specific revision
   $ hgmn pull -r 3e19bf519e9af6c66edf28380101a92122cbea50 -q
+Test a pull of a revision by hash prefix
+  $ hgmn pull -r a42a44555d7c -q
 Pull the rest
   $ hgmn pull -q
 
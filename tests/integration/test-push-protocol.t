@@ -208,8 +208,8 @@ push to Mononoke
   running *scm/mononoke/tests/integration/dummyssh.par 'user@dummy' ''\''*scm/mononoke/hgcli/hgcli#binary/hgcli'\'' -R repo serve --stdio' (glob)
   sending hello command
   sending between command
-  remote: 194
-  remote: capabilities: lookup known getbundle unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog bundle2=HG20%0Alistkeys%0Achangegroup%3D02%0Ab2x%3Ainfinitepush%0Ab2x%3Ainfinitepushscratchbookmarks
+  remote: 291
+  remote: capabilities: lookup branchmap known pushkey getbundle batch unbundle=HG10GZ,HG10BZ,HG10UN gettreepack remotefilelog getfile bundle2=HG20%0Alistkeys%0Apushkey%0Achangegroup%3D02%2C03%0Ab2x%3Ainfinitepush%0Ab2x%3Ainfinitepushscratchbookmarks%0Ab2x%3Arebase%0Aphases%3Dheads%0Aobsmarkers%3DV1
   remote: 1
   query 1; heads
   sending batch command
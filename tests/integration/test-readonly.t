@@ -0,0 +1,40 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+
+  $ setup_common_config
+  $ cd $TESTTMP
+
+setup repo
+
+  $ hginit_treemanifest repo-hg
+  $ cd repo-hg
+  $ echo "a file content" > a
+  $ hg add a
+  $ hg ci -ma
+  $ cd $TESTTMP
+  $ blobimport --blobstore files --linknodes repo-hg repo
+
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo2
+  $ cd repo2
+  $ echo "b file content" > b
+  $ hg add b
+  $ hg ci -mb
+
+start a read-only mononoke
+
+  $ mononoke -P $TESTTMP/mononoke-config -B test-config --readonly
+  $ wait_for_mononoke $TESTTMP/repo
+
+The server doesn't advertise unbundle and pushkey, and refuses the push
+
+  $ hgmn push --force --config treemanifest.treeonly=True --debug ssh://user@dummy/repo > push.out 2>&1
+  [255]
+  $ grep "remote: capabilities:" push.out
+  remote: capabilities: lookup branchmap known getbundle batch gettreepack remotefilelog getfile bundle2=* (glob)
+  $ grep -q "unbundle can't be run on the read-only repo" $TESTTMP/mononoke.out
+
+Nothing was pushed
+
+  $ hgmn outgoing -q ssh://user@dummy/repo
+  1:* (glob)
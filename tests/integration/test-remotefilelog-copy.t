@@ -0,0 +1,63 @@
+  $ . $TESTDIR/library.sh
+
+setup configuration
+  $ setup_common_config
+  $ cd $TESTTMP
+
+setup repo with a copied and a renamed file
+
+  $ hg init repo-hg
+  $ cd repo-hg
+  $ setup_hg_server
+  $ echo content > a
+  $ echo other > b
+  $ hg add a b
+  $ hg ci -ma
+  $ hg cp a copied
+  $ hg mv b renamed
+  $ hg ci -m 'copy and rename'
+  $ cd $TESTTMP
+
+blobimport them into Mononoke storage and start Mononoke
+  $ blobimport --blobstore files --linknodes repo-hg repo
+  $ mononoke -P $TESTTMP/mononoke-config -B test-config
+  $ wait_for_mononoke $TESTTMP/repo
+
+Fetch the files with getfiles. The client checks the node of every file it receives against
+its text, which includes the copy metadata.
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo2 --noupdate -q
+  $ cd repo2
+  $ setup_hg_client
+  $ hgmn pull -q
+  $ hgmn up -q tip
+  $ cat copied renamed
+  content
+  other
+  $ hg st --change tip -C
+  A copied
+    a
+  A renamed
+    b
+  R b
+  $ cd $TESTTMP
+
+Fetch the files again with getpackv1
+  $ rm -rf $TESTTMP/cachepath
+  $ hgclone_treemanifest ssh://user@dummy/repo-hg repo3 --noupdate -q
+  $ cd repo3
+  $ setup_hg_client
+  $ cat >> .hg/hgrc <<EOF
+  > [remotefilelog]
+  > fetchpacks=True
+  > EOF
+  $ hgmn pull -q
+  $ hgmn up -q tip
+  $ cat copied renamed
+  content
+  other
+  $ hg st --change tip -C
+  A copied
+    a
+  A renamed
+    b
+  R b